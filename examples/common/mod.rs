@@ -0,0 +1,41 @@
+//! What the examples share: opening the stack on a tun interface and reading the command
+//! line. Futures are run with `async_stack::block_on`, so the examples don't need a runtime.
+
+use std::time::Instant;
+
+use anyhow::Result;
+use tcp_rs::{
+    async_stack::AsyncStack,
+    cli::DEFAULT_IFACE,
+    device::{self, TunDevice},
+    isn::IsnGenerator,
+    listener::Listeners,
+    stack::Stack,
+};
+
+/// Start the stack on the tun interface `iface`. Listeners are added with
+/// [`AsyncStack::listen`].
+pub fn open_stack(iface: &str) -> Result<AsyncStack<TunDevice>> {
+    let nic: TunDevice = device::open_tun(iface)?;
+    nic.set_non_blocking()?;
+
+    let stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::from_os_random()?,
+        Instant::now(),
+    );
+    Ok(AsyncStack::spawn(nic, stack)?)
+}
+
+/// The interface and port from the command line, `[iface] [port]`. The interface is the
+/// daemon's default unless given.
+pub fn args(default_port: u16) -> Result<(String, u16)> {
+    let mut args = std::env::args().skip(1);
+    let iface: String = args.next().unwrap_or_else(|| DEFAULT_IFACE.to_string());
+    let port: u16 = match args.next() {
+        Some(port) => port.parse()?,
+        None => default_port,
+    };
+
+    Ok((iface, port))
+}
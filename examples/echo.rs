@@ -0,0 +1,76 @@
+//! Echo server, RFC 862. Everything each client sends is written back to it until the
+//! client closes, then the connection is closed from our side too.
+//!
+//! ```shell
+//! cargo run --example echo -- tun0 7
+//! sudo ip addr add 192.168.0.1/24 dev tun0
+//! sudo ip link set up dev tun0
+//! nc 192.168.0.2 7
+//! ```
+
+#[cfg(unix)]
+mod common;
+
+#[cfg(unix)]
+use std::{io, thread};
+
+#[cfg(unix)]
+use anyhow::Result;
+#[cfg(unix)]
+use tcp_rs::{
+    async_stack::{block_on, AsyncTcpListener, AsyncTcpStream},
+    device::TunDevice,
+    listener::ListenerLimits,
+};
+
+#[cfg(unix)]
+const ECHO_PORT: u16 = 7;
+
+#[cfg(unix)]
+fn main() -> Result<()> {
+    let (iface, port) = common::args(ECHO_PORT)?;
+    let stack = common::open_stack(&iface)?;
+    let listener: AsyncTcpListener<TunDevice> = stack.listen(port, ListenerLimits::default());
+    println!("Echoing on port {port} of {iface}");
+
+    // Each connection gets a thread of its own, blocked on its futures
+    loop {
+        let stream: AsyncTcpStream<TunDevice> = block_on(listener.accept())?;
+        thread::spawn(move || {
+            let info = stream.info();
+            match block_on(echo(&stream)) {
+                Ok(n_echoed) => println!("{info}: echoed {n_echoed}b"),
+                Err(err) => println!("{info}: {err}"),
+            }
+        });
+    }
+}
+
+/// Write back everything read from `stream`, returning how many bytes were echoed
+#[cfg(unix)]
+async fn echo(stream: &AsyncTcpStream<TunDevice>) -> io::Result<usize> {
+    let mut buf: [u8; 4096] = [0; 4096];
+    let mut n_echoed: usize = 0;
+
+    loop {
+        let n_read: usize = stream.read(&mut buf).await?;
+        if n_read == 0 {
+            break;
+        }
+
+        // Nothing more is read until the send buffer has room for all of this. A client
+        // sending faster than it reads the echo fills our receive window and has to wait,
+        // rather than us buffering without bound.
+        stream.write_all(&buf[..n_read]).await?;
+        n_echoed += n_read;
+    }
+
+    // The client has finished, so close our side once the echo has been sent
+    stream.shutdown().await?;
+    Ok(n_echoed)
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The examples use the async front end, which needs Unix");
+}
@@ -0,0 +1,168 @@
+//! A tiny HTTP/1.0 server, answering each connection's one GET request then closing it.
+//!
+//! `/` says hello and `/bytes/<n>` sends `n` bytes, which shows writes waiting for the
+//! client to acknowledge what was sent once the send buffer is full.
+//!
+//! ```shell
+//! cargo run --example http -- tun0 80
+//! sudo ip addr add 192.168.0.1/24 dev tun0
+//! sudo ip link set up dev tun0
+//! curl http://192.168.0.2/
+//! curl -o /dev/null http://192.168.0.2/bytes/10000000
+//! ```
+
+#[cfg(unix)]
+mod common;
+
+#[cfg(unix)]
+use std::{io, thread};
+
+#[cfg(unix)]
+use anyhow::Result;
+#[cfg(unix)]
+use tcp_rs::{
+    async_stack::{block_on, AsyncTcpListener, AsyncTcpStream},
+    device::TunDevice,
+    listener::ListenerLimits,
+};
+
+#[cfg(unix)]
+const HTTP_PORT: u16 = 80;
+
+/// Longest request head read before giving up on the request
+#[cfg(unix)]
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Size of each write of a `/bytes/<n>` body
+#[cfg(unix)]
+const BODY_CHUNK_LEN: usize = 16 * 1024;
+
+#[cfg(unix)]
+fn main() -> Result<()> {
+    let (iface, port) = common::args(HTTP_PORT)?;
+    let stack = common::open_stack(&iface)?;
+    let listener: AsyncTcpListener<TunDevice> = stack.listen(port, ListenerLimits::default());
+    println!("Serving HTTP on port {port} of {iface}");
+
+    // Each connection gets a thread of its own, blocked on its futures
+    loop {
+        let stream: AsyncTcpStream<TunDevice> = block_on(listener.accept())?;
+        thread::spawn(move || {
+            let info = stream.info();
+            match block_on(serve(&stream)) {
+                Ok(status) => println!("{info}: {status}"),
+                Err(err) => println!("{info}: {err}"),
+            }
+        });
+    }
+}
+
+/// Answer one request, then close the connection. Returns the status line sent.
+#[cfg(unix)]
+async fn serve(stream: &AsyncTcpStream<TunDevice>) -> io::Result<String> {
+    let request: Option<Vec<u8>> = read_request(stream).await?;
+    let request_line: Option<&str> = request
+        .as_deref()
+        .and_then(|request| std::str::from_utf8(request).ok())
+        .and_then(|request| request.lines().next());
+
+    let status: &str = match request_line.and_then(respond) {
+        Some(Response::Text(status, body)) => {
+            write_head(stream, status, body.len()).await?;
+            stream.write_all(body.as_bytes()).await?;
+            status
+        }
+        Some(Response::Bytes(n_bytes)) => {
+            write_head(stream, "200 OK", n_bytes).await?;
+            // Each write waits for the client to acknowledge enough to make room for it
+            let chunk: [u8; BODY_CHUNK_LEN] = [b'x'; BODY_CHUNK_LEN];
+            let mut n_left: usize = n_bytes;
+            while n_left > 0 {
+                let n_written: usize = stream.write(&chunk[..n_left.min(BODY_CHUNK_LEN)]).await?;
+                n_left -= n_written;
+            }
+            "200 OK"
+        }
+        None => {
+            let body: &str = "bad request\n";
+            write_head(stream, "400 Bad Request", body.len()).await?;
+            stream.write_all(body.as_bytes()).await?;
+            "400 Bad Request"
+        }
+    };
+
+    stream.shutdown().await?;
+    Ok(status.to_string())
+}
+
+#[cfg(unix)]
+enum Response {
+    Text(&'static str, String),
+    /// `n` bytes of filler
+    Bytes(usize),
+}
+
+/// What to answer `request_line` with, or `None` if it isn't a request
+#[cfg(unix)]
+fn respond(request_line: &str) -> Option<Response> {
+    let mut parts = request_line.split_whitespace();
+    let (method, path, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    if method != "GET" {
+        return Some(Response::Text(
+            "405 Method Not Allowed",
+            "only GET is supported\n".to_string(),
+        ));
+    }
+
+    let response: Response = match path {
+        "/" => Response::Text("200 OK", "hello from tcp_rs\n".to_string()),
+        path => match path.strip_prefix("/bytes/").map(str::parse::<usize>) {
+            Some(Ok(n_bytes)) => Response::Bytes(n_bytes),
+            _ => Response::Text("404 Not Found", format!("nothing at {path}\n")),
+        },
+    };
+    Some(response)
+}
+
+/// Read up to the blank line ending the request head. Returns `None` if the client closed
+/// first or the head is longer than [`MAX_REQUEST_LEN`].
+#[cfg(unix)]
+async fn read_request(stream: &AsyncTcpStream<TunDevice>) -> io::Result<Option<Vec<u8>>> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut buf: [u8; 1024] = [0; 1024];
+
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+
+        let n_read: usize = stream.read(&mut buf).await?;
+        if n_read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n_read]);
+    }
+
+    Ok(Some(request))
+}
+
+#[cfg(unix)]
+async fn write_head(
+    stream: &AsyncTcpStream<TunDevice>,
+    status: &str,
+    content_len: usize,
+) -> io::Result<()> {
+    let head: String = format!(
+        "HTTP/1.0 {status}\r\nContent-Length: {content_len}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(head.as_bytes()).await
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The examples use the async front end, which needs Unix");
+}
@@ -0,0 +1,11 @@
+//! Arbitrary bytes read from the tun device, exercising IP, TCP and ICMP parsing
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp_rs::fuzz;
+
+fuzz_target!(|packet: &[u8]| {
+    let mut stack = fuzz::stack();
+    let _ = fuzz::process_packet(&mut stack, packet);
+});
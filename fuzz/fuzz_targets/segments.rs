@@ -0,0 +1,11 @@
+//! Arbitrary sequences of well formed segments on one connection, exercising the state
+//! machine and its sequence number arithmetic
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp_rs::fuzz;
+
+fuzz_target!(|input: &[u8]| {
+    let _ = fuzz::run_segments(input);
+});
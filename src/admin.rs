@@ -0,0 +1,172 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::Duration,
+};
+use std::{net::SocketAddrV4, path::PathBuf};
+
+use crate::tcp::ConnectInfo;
+#[cfg(unix)]
+use crate::{error::Result, span::log};
+
+/// How long a client has to send its command before it's dropped, so a stuck client
+/// can't stall the stack
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A command sent by an operator
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Report the stack and per-connection counters, resetting them to zero in the same
+    /// step if `reset` is set
+    Stats {
+        reset: bool,
+    },
+    /// Write every packet through the device to a pcap file at `path`
+    StartCapture {
+        path: PathBuf,
+    },
+    StopCapture,
+    /// Report resource usage against the stack's limits
+    Health,
+    /// List every connection with its state, sequence variables, queues and counters
+    Connections,
+    /// Reset a connection and delete it, see [`Tcb::abort`](crate::tcp::Tcb::abort)
+    Abort {
+        connection: ConnectInfo,
+    },
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Option<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["stats"] => Some(AdminCommand::Stats { reset: false }),
+            ["stats", "reset"] => Some(AdminCommand::Stats { reset: true }),
+            ["capture", "start", path] => Some(AdminCommand::StartCapture { path: path.into() }),
+            ["capture", "stop"] => Some(AdminCommand::StopCapture),
+            ["health"] => Some(AdminCommand::Health),
+            ["connections"] => Some(AdminCommand::Connections),
+            // Connections are given as they're listed, with or without the arrow
+            ["abort", remote, local] | ["abort", remote, "->", local] => {
+                let remote: SocketAddrV4 = remote.parse().ok()?;
+                let local: SocketAddrV4 = local.parse().ok()?;
+                Some(AdminCommand::Abort {
+                    connection: ConnectInfo {
+                        src_addr: *remote.ip(),
+                        src_port: remote.port(),
+                        dst_addr: *local.ip(),
+                        dst_port: local.port(),
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Unix domain socket the daemon answers operator commands on.
+///
+/// Each client sends a single line command and gets the response back before the
+/// connection is closed, so it can be driven with `socat - UNIX-CONNECT:<path>`.
+/// Commands:
+/// - `stats` prints the stack and per-connection counters
+/// - `stats reset` prints the counters and resets them to zero in the same step
+/// - `capture start <path>` writes every packet through the device to a pcap file
+/// - `capture stop` stops writing it
+/// - `health` prints `ok`, `degraded` or `overloaded`, followed by the resource usage it
+///   was judged on
+/// - `connections` lists every connection, one a line, with its state, sequence
+///   variables, queues and counters
+/// - `abort <remote> <local>` resets the connection between the two `addr:port`s
+///
+/// Anything else gets an error message back without reaching the stack.
+#[cfg(unix)]
+pub struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl AdminSocket {
+    /// Listen at `path`, replacing any socket left behind by a previous run
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path: PathBuf = path.as_ref().to_path_buf();
+
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(AdminSocket { listener, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answer every client waiting to connect, passing each command to `handler`
+    /// and sending back what it returns
+    pub fn serve(&self, mut handler: impl FnMut(AdminCommand) -> String) -> Result<()> {
+        loop {
+            let stream: UnixStream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+
+            if let Err(err) = serve_client(stream, &mut handler) {
+                log!("Admin client failed: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_client(
+    stream: UnixStream,
+    handler: &mut impl FnMut(AdminCommand) -> String,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+
+    let mut response: String = match AdminCommand::parse(&command) {
+        Some(command) => handler(command),
+        None => format!(
+            "unknown command {:?}, expected `stats`, `stats reset`, `capture start <path>`, `capture stop`, `health`, `connections` or `abort <remote> <local>`",
+            command.trim()
+        ),
+    };
+    response.push('\n');
+
+    (&stream).write_all(response.as_bytes())
+}
+
+#[cfg(unix)]
+impl AsRawFd for AdminSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
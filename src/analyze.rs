@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{
+    challenge::ChallengeAckLimiter,
+    clock::{Clock, SimulatedClock},
+    device::CaptureDevice,
+    error::Result,
+    isn::IsnGenerator,
+    pcap::PcapReader,
+    tcp::{self, ConnectInfo, State, Tcb},
+};
+
+/// How the stack handled one of the peer's segments from a capture
+pub struct AnalysisStep {
+    /// Position of the segment in the capture, starting from 1
+    pub packet_number: usize,
+    /// Time since the first packet in the capture
+    pub elapsed: Duration,
+    /// The peer's IPv4 packet
+    pub inbound: Vec<u8>,
+    /// Connection state before the segment, `None` if there was no connection
+    pub state_before: Option<State>,
+    /// Connection state after the segment, `None` if no connection was created or kept
+    pub state_after: Option<State>,
+    /// Packets the stack would have sent, including any sent by timers which expired
+    /// since the previous segment
+    pub sent: Vec<Vec<u8>>,
+}
+
+/// Result of replaying a peer's side of a capture against the stack
+pub struct Analysis {
+    /// The local address the peer was talking to, taken from the first SYN
+    pub local_addr: Option<Ipv4Addr>,
+    pub steps: Vec<AnalysisStep>,
+    /// Packets which weren't IPv4 TCP segments sent to `local_addr`,
+    /// including the original endpoint's own responses
+    pub skipped: usize,
+}
+
+/// Feed every segment sent to the local endpoint in a capture through the stack, recording
+/// how it would respond. Nothing is sent on the network.
+///
+/// The local endpoint is the destination of the first SYN without an ACK, or of the first
+/// TCP segment if the capture has no SYN. Segments in the other direction, from the endpoint
+/// which was originally captured, are skipped. Timers run on the capture's clock, so
+/// keep-alive and TIME-WAIT expiry between segments show up in the results.
+pub fn analyze(reader: impl Read) -> Result<Analysis> {
+    let mut pcap = PcapReader::new(reader)?;
+    let link_type = pcap.link_type();
+
+    let mut packets: Vec<(Duration, Vec<u8>)> = Vec::new();
+    let mut skipped: usize = 0;
+
+    while let Some(record) = pcap.next_record()? {
+        match link_type.ipv4_packet(&record.data) {
+            Some(packet) => packets.push((record.timestamp, packet.to_vec())),
+            None => {
+                skipped += 1;
+                packets.push((record.timestamp, Vec::new()));
+            }
+        }
+    }
+
+    let local_addr: Option<Ipv4Addr> = find_local_addr(&packets);
+
+    let device = CaptureDevice::default();
+    let mut connections = HashMap::<ConnectInfo, Tcb>::default();
+    let mut challenge_acks = ChallengeAckLimiter::default();
+    let isn = IsnGenerator::from_os_random()?;
+    let mut steps: Vec<AnalysisStep> = Vec::new();
+
+    // Connections run on the capture's clock
+    let clock = Arc::new(SimulatedClock::new());
+    let connection_clock: Arc<dyn Clock> = clock.clone();
+    let start: Instant = clock.now();
+    let first_timestamp: Duration = packets.first().map_or(Duration::ZERO, |(ts, _)| *ts);
+
+    for (i, (timestamp, packet)) in packets.iter().enumerate() {
+        if packet.is_empty() {
+            continue;
+        }
+
+        let Some((ip_header, tcp_header, data)) = decode(packet) else {
+            skipped += 1;
+            continue;
+        };
+
+        if Some(ip_header.destination_addr()) != local_addr {
+            skipped += 1;
+            continue;
+        }
+
+        let elapsed: Duration = timestamp.saturating_sub(first_timestamp);
+        let now: Instant = start + elapsed;
+        clock.advance_to(now);
+
+        for tcb in connections.values_mut() {
+            if tcb.next_deadline().is_some_and(|deadline| deadline <= now) {
+                tcb.on_tick(&device, now)?;
+            }
+        }
+        connections.retain(|_, tcb| tcb.state() != State::Closed);
+
+        let info = ConnectInfo {
+            src_addr: ip_header.source_addr(),
+            src_port: tcp_header.source_port(),
+            dst_addr: ip_header.destination_addr(),
+            dst_port: tcp_header.destination_port(),
+        };
+
+        let state_before: Option<State> = connections.get(&info).map(Tcb::state);
+
+        match connections.get_mut(&info) {
+            Some(tcb) => {
+                tcb.on_packet(&device, ip_header, tcp_header, data, &mut challenge_acks)?;
+
+                // Matches the daemon, which reads everything it receives straight away
+                // and closes as soon as the peer has finished
+                let mut received: Vec<u8> = vec![0; tcb.unread_len()];
+                tcb.read(&mut received);
+
+                if tcb.state() == State::CloseWait {
+                    tcb.close(&device)?;
+                }
+            }
+            None => {
+                if let Some(tcb) = Tcb::accept_connection(
+                    &device,
+                    ip_header,
+                    tcp_header,
+                    data,
+                    &isn,
+                    &connection_clock,
+                )? {
+                    connections.insert(info, tcb);
+                }
+            }
+        }
+
+        let state_after: Option<State> = connections.get(&info).map(Tcb::state);
+
+        steps.push(AnalysisStep {
+            packet_number: i + 1,
+            elapsed,
+            inbound: packet.clone(),
+            state_before,
+            state_after,
+            sent: device.take_sent(),
+        });
+    }
+
+    Ok(Analysis {
+        local_addr,
+        steps,
+        skipped,
+    })
+}
+
+/// Analyze the capture at `path` and print how the stack would have responded
+pub fn run_and_report(path: &str) -> Result<()> {
+    let file = File::open(path)?;
+    let analysis = analyze(BufReader::new(file))?;
+
+    println!("{analysis}");
+
+    Ok(())
+}
+
+pub(crate) fn find_local_addr(packets: &[(Duration, Vec<u8>)]) -> Option<Ipv4Addr> {
+    let segments = || packets.iter().filter_map(|(_, packet)| decode(packet));
+
+    segments()
+        .find(|(_, tcp_header, _)| tcp_header.syn() && !tcp_header.ack())
+        .or_else(|| segments().next())
+        .map(|(ip_header, _, _)| ip_header.destination_addr())
+}
+
+pub(crate) fn decode(packet: &[u8]) -> Option<(Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8])> {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).ok()?;
+    if ip_header.protocol() != IpNumber::TCP {
+        return None;
+    }
+
+    let tcp_header_offset: usize = ip_header.slice().len();
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[tcp_header_offset..]).ok()?;
+
+    let data_offset: usize = tcp_header_offset + tcp_header.slice().len();
+    let data_end: usize = (ip_header.total_len() as usize).clamp(data_offset, packet.len());
+
+    Some((ip_header, tcp_header, &packet[data_offset..data_end]))
+}
+
+/// One line summary of a segment, in the style of tcpdump
+fn describe(packet: &[u8]) -> String {
+    let Some((ip_header, tcp_header, data)) = decode(packet) else {
+        return format!("{} bytes, not a TCP segment", packet.len());
+    };
+
+    let flags: String = [
+        (tcp_header.syn(), 'S'),
+        (tcp_header.fin(), 'F'),
+        (tcp_header.rst(), 'R'),
+        (tcp_header.psh(), 'P'),
+        (tcp_header.urg(), 'U'),
+        (tcp_header.ack(), '.'),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+
+    format!(
+        "{}:{} > {}:{} [{flags}] seq={} ack={} win={} len={}",
+        ip_header.source_addr(),
+        tcp_header.source_port(),
+        ip_header.destination_addr(),
+        tcp_header.destination_port(),
+        tcp_header.sequence_number(),
+        tcp_header.acknowledgment_number(),
+        tcp_header.window_size(),
+        tcp::segment_len(&tcp_header, data),
+    )
+}
+
+fn describe_state(state: Option<State>) -> String {
+    match state {
+        Some(state) => format!("{state:?}"),
+        None => "Listen".to_string(),
+    }
+}
+
+impl fmt::Display for AnalysisStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "#{} +{:.6}s {}",
+            self.packet_number,
+            self.elapsed.as_secs_f64(),
+            describe(&self.inbound)
+        )?;
+        writeln!(
+            f,
+            "    {} -> {}",
+            describe_state(self.state_before),
+            describe_state(self.state_after)
+        )?;
+
+        if self.sent.is_empty() {
+            write!(f, "    would send nothing")
+        } else {
+            let sent: Vec<String> = self
+                .sent
+                .iter()
+                .map(|packet| format!("    would send {}", describe(packet)))
+                .collect();
+            write!(f, "{}", sent.join("\n"))
+        }
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.local_addr {
+            Some(local_addr) => writeln!(f, "Responding as {local_addr}")?,
+            None => writeln!(f, "No TCP segments found")?,
+        }
+
+        for step in &self.steps {
+            writeln!(f, "{step}")?;
+        }
+
+        write!(
+            f,
+            "{} segments analyzed, {} packets skipped",
+            self.steps.len(),
+            self.skipped
+        )
+    }
+}
@@ -0,0 +1,1049 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::{poll_fn, Future},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddrV4},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, JoinHandle, Thread},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use std::{os::fd::RawFd, pin::Pin};
+
+#[cfg(feature = "tokio")]
+use mio::{unix::EventedFd, Evented, PollOpt, Ready, Token};
+#[cfg(feature = "tokio")]
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    prelude::{task::Task, Async, Future as TokioFuture, Poll as TokioPoll},
+    reactor::PollEvented2,
+    timer::Delay,
+};
+
+use crate::{
+    device::{NetworkDevice, RecvBuffer, TunDevice},
+    error::Result,
+    listener::ListenerLimits,
+    span::log,
+    stack::{self, Stack},
+    tcp::{ConnectInfo, SocketOption, SoftError, State, Tcb},
+};
+
+/// Runs a [`Stack`] on a background thread and exposes its connections as futures.
+///
+/// The futures don't depend on any particular runtime, so they can be awaited from tokio
+/// or anything else. The packet pump waits on the device and the stack's next timer
+/// itself, and wakes any task whose connection may have made progress after every
+/// packet or timer. With the `tokio` feature the pump can be a task on a tokio runtime
+/// instead, see [`AsyncStack::spawn_tokio`].
+///
+/// Dropping a future part way through leaves nothing half done. Every operation takes
+/// effect under a single lock, so a read or write either happens in full or not at all,
+/// a connect which is dropped before the handshake completes aborts its connection, and
+/// `accept` only removes a connection from the queue when it returns it.
+pub struct AsyncStack<D = TunDevice>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    shared: Arc<Shared<D>>,
+    pump: Option<JoinHandle<Result<()>>>,
+}
+
+struct Shared<D> {
+    nic: D,
+    inner: Mutex<Inner>,
+    /// Written to interrupt the pump's wait, so it notices a new timer or shuts down
+    wake_tx: UnixStream,
+}
+
+struct Inner {
+    stack: Stack,
+    /// Established connections waiting to be accepted, for each port being listened on
+    accept_queues: HashMap<u16, VecDeque<ConnectInfo>>,
+    /// Connections which have been queued, accepted or connected, so they're only queued
+    /// once
+    handed_out: HashSet<ConnectInfo>,
+    /// Connections being opened by [`AsyncStack::connect`], and how far they've got
+    connecting: HashMap<ConnectInfo, Connect>,
+    /// Connections handed out which the peer reset, so reads and writes fail with
+    /// [`io::ErrorKind::ConnectionReset`] once they're deleted
+    reset: HashSet<ConnectInfo>,
+    /// Tasks waiting for something to happen on any connection or listener
+    wakers: Vec<Waker>,
+    /// Tasks waiting with a timeout, woken once it's passed even if nothing happens
+    timeouts: HashMap<u64, (Instant, Waker)>,
+    next_timeout_id: u64,
+    stopping: bool,
+}
+
+/// How far a connection being opened by [`AsyncStack::connect`] has got
+#[derive(Clone, Copy)]
+enum Connect {
+    Handshake,
+    Established,
+    Failed(io::ErrorKind),
+}
+
+impl<D> AsyncStack<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Start pumping packets between `nic` and `stack` on a background thread.
+    /// `nic` should be non-blocking.
+    pub fn spawn(nic: D, stack: Stack) -> Result<Self> {
+        let (shared, wake_rx): (Arc<Shared<D>>, UnixStream) = Shared::new(nic, stack)?;
+
+        let pump_shared: Arc<Shared<D>> = Arc::clone(&shared);
+        let pump: JoinHandle<Result<()>> = thread::spawn(move || pump(&pump_shared, wake_rx));
+
+        Ok(AsyncStack {
+            shared,
+            pump: Some(pump),
+        })
+    }
+
+    /// Start pumping packets between `nic` and `stack` as a task on the tokio runtime this
+    /// is called from, rather than on a thread of its own. The device is waited on through
+    /// the reactor, like tokio's own sockets, so `nic` has to be non-blocking. The task
+    /// finishes once the stack is dropped.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_tokio(nic: D, stack: Stack) -> Result<Self> {
+        let (shared, wake_rx): (Arc<Shared<D>>, UnixStream) = Shared::new(nic, stack)?;
+
+        let pump = TokioPump {
+            nic: PollEvented2::new(Fd(shared.nic.as_raw_fd())),
+            wake: PollEvented2::new(Fd(wake_rx.as_raw_fd())),
+            wake_rx,
+            buf: RecvBuffer::for_device(&shared.nic),
+            delay: None,
+            shared: Arc::clone(&shared),
+        };
+        tokio::spawn(pump);
+
+        Ok(AsyncStack { shared, pump: None })
+    }
+
+    /// Listen on `port`, with connections to it waiting to be accepted once established
+    pub fn listen(&self, port: u16, limits: ListenerLimits) -> AsyncTcpListener<D> {
+        let mut inner = self.shared.lock();
+        inner.stack.listeners_mut().insert(port, limits);
+        inner.accept_queues.entry(port).or_default();
+
+        AsyncTcpListener {
+            shared: Arc::clone(&self.shared),
+            port,
+        }
+    }
+
+    /// Open a connection from `local` to `remote`, waiting for the handshake to complete.
+    /// A local port of 0 is replaced with a free ephemeral port, see [`Stack::connect`].
+    ///
+    /// Fails with [`io::ErrorKind::ConnectionRefused`] if the peer answers the SYN with a
+    /// reset, or [`io::ErrorKind::TimedOut`] if the handshake fails any other way. Once
+    /// established the stream is returned even if the peer has since reset it, and reading
+    /// or writing it fails with [`io::ErrorKind::ConnectionReset`]. Dropping the future
+    /// before then aborts the connection.
+    pub async fn connect(
+        &self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> io::Result<AsyncTcpStream<D>> {
+        self.connect_until(local, remote, None).await
+    }
+
+    /// [`AsyncStack::connect`], failing with [`io::ErrorKind::TimedOut`] if the handshake
+    /// hasn't completed within `timeout`, in which case the connection is aborted
+    pub async fn connect_timeout(
+        &self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        timeout: Duration,
+    ) -> io::Result<AsyncTcpStream<D>> {
+        self.connect_until(local, remote, Some(timeout)).await
+    }
+
+    async fn connect_until(
+        &self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        timeout: Option<Duration>,
+    ) -> io::Result<AsyncTcpStream<D>> {
+        let info: ConnectInfo = {
+            let mut inner = self.shared.lock();
+            let info: ConnectInfo = inner.stack.connect(&self.shared.nic, local, remote)?;
+            inner.handed_out.insert(info);
+            inner.connecting.insert(info, Connect::Handshake);
+            info
+        };
+        // The SYN's retransmission timer has started
+        self.shared.wake_pump();
+
+        let mut connecting = Connecting {
+            shared: &self.shared,
+            info,
+            done: false,
+        };
+        let mut deadline = Deadline::after(&self.shared, timeout);
+        poll_fn(|cx| deadline.poll(cx, |cx| connecting.poll(cx))).await
+    }
+}
+
+/// A connection being opened by [`AsyncStack::connect`], which is aborted if the future is
+/// dropped before it's established
+struct Connecting<'a, D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    shared: &'a Arc<Shared<D>>,
+    info: ConnectInfo,
+    done: bool,
+}
+
+impl<D> Connecting<'_, D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncTcpStream<D>>> {
+        let mut inner = self.shared.lock();
+
+        if inner.stopping {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+
+        let established: bool = inner
+            .stack
+            .connection(&self.info)
+            .is_some_and(|tcb| tcb.state().is_synchronised());
+
+        let result: io::Result<AsyncTcpStream<D>> = match inner.connecting.get(&self.info) {
+            Some(Connect::Failed(kind)) => Err((*kind).into()),
+            Some(Connect::Established) => Ok(AsyncTcpStream {
+                shared: Arc::clone(self.shared),
+                info: self.info,
+            }),
+            _ if established => Ok(AsyncTcpStream {
+                shared: Arc::clone(self.shared),
+                info: self.info,
+            }),
+            _ => {
+                inner.wakers.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+
+        inner.connecting.remove(&self.info);
+        self.done = true;
+        Poll::Ready(result)
+    }
+}
+
+impl<D> Drop for Connecting<'_, D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut inner = self.shared.lock();
+        inner.connecting.remove(&self.info);
+        if inner.stack.connection(&self.info).is_some() {
+            if let Err(err) = inner.stack.abort(&self.shared.nic, &self.info) {
+                log!("Failed to abort connection {}: {err}", self.info);
+            }
+        }
+    }
+}
+
+impl<D> Drop for AsyncStack<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Stop the packet pump. Connections which are still open go with it.
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.wake_pump();
+
+        if let Some(pump) = self.pump.take() {
+            if let Ok(Err(err)) = pump.join() {
+                log!("Packet pump failed: {err}");
+            }
+        }
+    }
+}
+
+/// Accepts connections to one port, see [`AsyncStack::listen`]
+pub struct AsyncTcpListener<D = TunDevice>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    shared: Arc<Shared<D>>,
+    port: u16,
+}
+
+impl<D> AsyncTcpListener<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next connection to complete its handshake
+    pub async fn accept(&self) -> io::Result<AsyncTcpStream<D>> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncTcpStream<D>>> {
+        let mut inner = self.shared.lock();
+
+        if inner.stopping {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+
+        match inner
+            .accept_queues
+            .get_mut(&self.port)
+            .and_then(VecDeque::pop_front)
+        {
+            Some(info) => Poll::Ready(Ok(AsyncTcpStream {
+                shared: Arc::clone(&self.shared),
+                info,
+            })),
+            None => {
+                inner.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<D> Drop for AsyncTcpListener<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Stop listening. Connections which were never accepted are closed.
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        let queued: VecDeque<ConnectInfo> =
+            inner.accept_queues.remove(&self.port).unwrap_or_default();
+
+        for info in queued {
+            inner.reset.remove(&info);
+            if let Err(err) = inner.stack.close(&self.shared.nic, &info) {
+                log!("Failed to close unaccepted connection {info}: {err}");
+            }
+        }
+    }
+}
+
+/// An established connection, read and written through futures.
+/// Dropping it closes our side of the connection.
+pub struct AsyncTcpStream<D = TunDevice>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    shared: Arc<Shared<D>>,
+    info: ConnectInfo,
+}
+
+impl<D> AsyncTcpStream<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// The connection's 4-tuple as seen on segments from the peer
+    pub fn info(&self) -> ConnectInfo {
+        self.info
+    }
+
+    /// Take the soft errors reported since the last call, see [`Tcb::take_soft_errors`].
+    /// They're advisory, the connection carries on.
+    pub fn take_soft_errors(&self) -> Vec<SoftError> {
+        self.shared
+            .lock()
+            .stack
+            .connection_mut(&self.info)
+            .map(Tcb::take_soft_errors)
+            .unwrap_or_default()
+    }
+
+    /// Change a setting on the connection, see [`Tcb::set_option`]
+    pub fn set_option(&self, option: SocketOption) -> io::Result<()> {
+        let mut inner = self.shared.lock();
+        let Some(tcb) = inner.stack.connection_mut(&self.info) else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+
+        tcb.set_option(option)?;
+
+        // A timer may have started, or a window update be due
+        self.shared.wake_pump();
+        Ok(())
+    }
+
+    /// Read a setting off the connection with one of its getters, such as
+    /// `stream.option(Tcb::nodelay)`
+    pub fn option<T>(&self, get: impl FnOnce(&Tcb) -> T) -> io::Result<T> {
+        self.shared
+            .lock()
+            .stack
+            .connection(&self.info)
+            .map(get)
+            .ok_or(io::ErrorKind::NotConnected.into())
+    }
+
+    /// Wait for data from the peer and read as much as fits in `buf`.
+    /// Returns zero once the peer has closed and everything it sent has been read.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// [`AsyncTcpStream::read`], failing with [`io::ErrorKind::TimedOut`] if nothing
+    /// arrives within `timeout`. Nothing is read if it times out.
+    pub async fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let mut deadline = Deadline::after(&self.shared, Some(timeout));
+        poll_fn(|cx| deadline.poll(cx, |cx| self.poll_read(cx, buf))).await
+    }
+
+    /// Wait for room in the send buffer and queue as much of `data` as fits
+    pub async fn write(&self, data: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, data)).await
+    }
+
+    /// [`AsyncTcpStream::write`], failing with [`io::ErrorKind::TimedOut`] if there's no
+    /// room within `timeout`. Nothing is queued if it times out.
+    pub async fn write_timeout(&self, data: &[u8], timeout: Duration) -> io::Result<usize> {
+        let mut deadline = Deadline::after(&self.shared, Some(timeout));
+        poll_fn(|cx| deadline.poll(cx, |cx| self.poll_write(cx, data))).await
+    }
+
+    /// Queue all of `data`, waiting for room as the peer acknowledges what was sent.
+    /// If the future is dropped part way through, a prefix of `data` has been queued.
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let n_written: usize = self.write(data).await?;
+            data = &data[n_written..];
+        }
+
+        Ok(())
+    }
+
+    /// Close our side of the connection. Data from the peer can still be read.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_shutdown(cx)).await
+    }
+
+    /// Reset the connection straight away, see [`Tcb::abort`]. Anything not yet sent or
+    /// read is lost, and the peer sees the connection reset.
+    pub fn abort(&self) -> io::Result<()> {
+        let mut inner = self.shared.lock();
+        if inner.stack.connection(&self.info).is_none() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        inner.stack.abort(&self.shared.nic, &self.info)?;
+
+        // Tasks waiting on the connection find it gone
+        for waker in inner.wakers.drain(..) {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.lock();
+        let Inner { stack, reset, .. } = &mut *inner;
+
+        let Some(tcb) = stack.connection_mut(&self.info) else {
+            return Poll::Ready(match reset.contains(&self.info) {
+                true => Err(io::ErrorKind::ConnectionReset.into()),
+                false => Ok(0),
+            });
+        };
+
+        let n_read: usize = tcb.read(buf);
+        if n_read == 0 && tcb.was_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        let peer_closed: bool = matches!(
+            tcb.state(),
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        );
+
+        if n_read > 0 || peer_closed || buf.is_empty() {
+            // Reading may have opened the receive window enough to tell the peer
+            self.shared.wake_pump();
+            return Poll::Ready(Ok(n_read));
+        }
+
+        inner.wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Queue as much of `data` as fits in the send buffer without waiting, failing with
+    /// [`io::ErrorKind::WouldBlock`] if it's full. It stays full until the peer
+    /// acknowledges what was sent, which takes longer when its window is closed.
+    pub fn try_write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut inner = self.shared.lock();
+        let Inner { stack, reset, .. } = &mut *inner;
+        self.write_now(stack, reset, data)
+    }
+
+    pub fn poll_write(&self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.lock();
+        let Inner {
+            stack,
+            reset,
+            wakers,
+            ..
+        } = &mut *inner;
+
+        match self.write_now(stack, reset, data) {
+            // The pump wakes us after the next packet, which may be the ACK making room
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn write_now(
+        &self,
+        stack: &mut Stack,
+        reset: &HashSet<ConnectInfo>,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        let tcb: Option<&mut Tcb> = stack.connection_mut(&self.info);
+        if reset.contains(&self.info) || tcb.as_ref().is_some_and(|tcb| tcb.was_reset()) {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        let Some(tcb) = tcb else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+
+        if !matches!(tcb.state(), State::Estab | State::CloseWait) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let n_taken: usize = tcb.send(&self.shared.nic, data)?;
+
+        if n_taken == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        // A retransmission timer may have started
+        self.shared.wake_pump();
+        Ok(n_taken)
+    }
+
+    pub fn poll_shutdown(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+
+        let open: bool = inner
+            .stack
+            .connection(&self.info)
+            .is_some_and(|tcb| matches!(tcb.state(), State::Estab | State::CloseWait));
+
+        if open {
+            inner
+                .stack
+                .shutdown(&self.shared.nic, &self.info, Shutdown::Write)?;
+            self.shared.wake_pump();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D> Drop for AsyncTcpStream<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        let waker = Waker::noop();
+        if let Poll::Ready(Err(err)) = self.poll_shutdown(&mut Context::from_waker(waker)) {
+            log!("Failed to close {}: {err}", self.info);
+        }
+        self.shared.lock().reset.remove(&self.info);
+    }
+}
+
+/// When an operation with a timeout gives up. While the operation waits the pump wakes
+/// its task at the deadline, and the deadline is forgotten once the operation finishes
+/// or its future is dropped.
+struct Deadline<'a, D> {
+    shared: &'a Shared<D>,
+    at: Option<Instant>,
+    /// Where the waiting task is registered with the pump, once it has been
+    id: Option<u64>,
+}
+
+impl<'a, D> Deadline<'a, D> {
+    /// The deadline `timeout` from now, or none at all
+    fn after(shared: &'a Shared<D>, timeout: Option<Duration>) -> Self {
+        Deadline {
+            shared,
+            at: timeout.map(|timeout| Instant::now() + timeout),
+            id: None,
+        }
+    }
+
+    /// Poll an operation, failing with [`io::ErrorKind::TimedOut`] if it's still waiting
+    /// once the deadline has passed
+    fn poll<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: impl FnOnce(&mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let result: Poll<io::Result<T>> = poll(cx);
+        let Some(at) = self.at.filter(|_| result.is_pending()) else {
+            self.forget();
+            return result;
+        };
+
+        if Instant::now() >= at {
+            self.forget();
+            return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+        }
+
+        let mut inner = self.shared.lock();
+        let id: u64 = *self.id.get_or_insert_with(|| {
+            inner.next_timeout_id += 1;
+            inner.next_timeout_id
+        });
+        if inner
+            .timeouts
+            .insert(id, (at, cx.waker().clone()))
+            .is_none()
+        {
+            // The pump may be waiting on a later deadline, or none
+            self.shared.wake_pump();
+        }
+        Poll::Pending
+    }
+
+    fn forget(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.shared.lock().timeouts.remove(&id);
+        }
+    }
+}
+
+impl<D> Drop for Deadline<'_, D> {
+    fn drop(&mut self) {
+        self.forget();
+    }
+}
+
+impl<D> Shared<D> {
+    /// The state shared with the pump, and the pump's end of the pipe which wakes it
+    fn new(nic: D, stack: Stack) -> Result<(Arc<Self>, UnixStream)> {
+        let (wake_tx, wake_rx): (UnixStream, UnixStream) = UnixStream::pair()?;
+        wake_tx.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+
+        let shared = Arc::new(Shared {
+            nic,
+            inner: Mutex::new(Inner {
+                stack,
+                accept_queues: HashMap::new(),
+                handed_out: HashSet::new(),
+                connecting: HashMap::new(),
+                reset: HashSet::new(),
+                wakers: Vec::new(),
+                timeouts: HashMap::new(),
+                next_timeout_id: 0,
+                stopping: false,
+            }),
+            wake_tx,
+        });
+
+        Ok((shared, wake_rx))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wake_pump(&self) {
+        // A full pipe already has a wake up pending
+        let _ = (&self.wake_tx).write(&[1]);
+    }
+}
+
+/// Move packets from the device into the stack and run its timers until the stack is dropped
+fn pump<D>(shared: &Shared<D>, mut wake_rx: UnixStream) -> Result<()>
+where
+    D: NetworkDevice + AsRawFd,
+{
+    let mut buf = RecvBuffer::for_device(&shared.nic);
+
+    loop {
+        let deadline: Option<Instant> = shared.lock().next_deadline();
+
+        let [packet_ready, woken] = stack::wait_for_input(
+            [Some(shared.nic.as_raw_fd()), Some(wake_rx.as_raw_fd())],
+            deadline,
+        )?;
+
+        if woken {
+            drain(&mut wake_rx);
+        }
+
+        if shared.lock().stopping {
+            return Ok(());
+        }
+
+        shared.turn(&mut buf, packet_ready)?;
+    }
+}
+
+/// Empty the pump's end of the wake up pipe
+fn drain(wake_rx: &mut UnixStream) {
+    let mut drained: [u8; 64] = [0; 64];
+    while matches!(wake_rx.read(&mut drained), Ok(n) if n > 0) {}
+}
+
+impl<D> Shared<D>
+where
+    D: NetworkDevice,
+{
+    /// Receive a packet if the device has one, run the stack's timers, then wake every task
+    /// waiting on the stack. Returns whether a packet was received, as there may be more.
+    fn turn(&self, buf: &mut RecvBuffer, packet_ready: bool) -> Result<bool> {
+        let mut inner = self.lock();
+
+        let mut received: bool = false;
+        if packet_ready {
+            match buf.recv(&self.nic) {
+                Ok(Some(packet)) => {
+                    received = true;
+                    inner.stack.on_packet(&self.nic, packet, Instant::now())?;
+                }
+                Ok(None) => received = true,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // Connections which closed are only removed on the tick, so whether they were
+        // reset has to be seen before it
+        inner.check_connecting();
+        let now = Instant::now();
+        inner.stack.on_tick(&self.nic, now)?;
+        inner.check_connecting();
+        inner.queue_established();
+
+        for waker in inner.wakers.drain(..) {
+            waker.wake();
+        }
+        inner.expire_timeouts(now);
+        Ok(received)
+    }
+}
+
+impl Inner {
+    /// When the pump next has work to do, the stack's next timer or a task's timeout
+    fn next_deadline(&self) -> Option<Instant> {
+        let timeouts = self.timeouts.values().map(|(deadline, _)| *deadline);
+        self.stack.next_deadline().into_iter().chain(timeouts).min()
+    }
+
+    /// Record which connections being opened by [`AsyncStack::connect`] have been
+    /// established or failed, and which handed out connections the peer reset. Only a
+    /// reset answering the SYN refuses the connection, a later one is for the stream.
+    fn check_connecting(&mut self) {
+        let Inner {
+            stack,
+            connecting,
+            handed_out,
+            reset,
+            ..
+        } = self;
+
+        for (info, progress) in connecting.iter_mut() {
+            if !matches!(progress, Connect::Handshake) {
+                continue;
+            }
+
+            *progress = match stack.connection(info) {
+                Some(tcb) if tcb.was_refused() => Connect::Failed(io::ErrorKind::ConnectionRefused),
+                Some(tcb) if tcb.state().is_synchronised() || tcb.was_reset() => {
+                    Connect::Established
+                }
+                Some(tcb) if tcb.state() == State::Closed => {
+                    Connect::Failed(io::ErrorKind::TimedOut)
+                }
+                Some(_) => Connect::Handshake,
+                None => Connect::Failed(io::ErrorKind::TimedOut),
+            };
+        }
+
+        for info in handed_out.iter() {
+            if stack.connection(info).is_some_and(Tcb::was_reset) {
+                reset.insert(*info);
+            }
+        }
+    }
+
+    /// Queue newly established connections on their listener to be accepted
+    fn queue_established(&mut self) {
+        let Inner {
+            stack,
+            accept_queues,
+            handed_out,
+            ..
+        } = self;
+
+        handed_out.retain(|info| stack.connection(info).is_some());
+
+        for (info, tcb) in stack.connections() {
+            if !tcb.state().is_synchronised() || handed_out.contains(info) {
+                continue;
+            }
+
+            if let Some(queue) = accept_queues.get_mut(&info.dst_port) {
+                queue.push_back(*info);
+                handed_out.insert(*info);
+            }
+        }
+    }
+
+    /// Wake the tasks whose timeouts have passed by `now`
+    fn expire_timeouts(&mut self, now: Instant) {
+        self.timeouts.retain(|_, (deadline, waker)| {
+            if *deadline > now {
+                return true;
+            }
+            waker.wake_by_ref();
+            false
+        });
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` on this thread, parking it until the stack wakes the future again. For
+/// callers without an executor of their own, such as [`proxy`](crate::proxy) and the
+/// examples.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// A file descriptor for the tokio reactor to wait on, which something else owns
+#[cfg(feature = "tokio")]
+struct Fd(RawFd);
+
+#[cfg(feature = "tokio")]
+impl Evented for Fd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+/// The packet pump as a tokio task, see [`AsyncStack::spawn_tokio`]. The reactor tells it
+/// when the device has packets or it's been woken, and a [`Delay`] when the stack's next
+/// timer is due.
+#[cfg(feature = "tokio")]
+struct TokioPump<D> {
+    shared: Arc<Shared<D>>,
+    nic: PollEvented2<Fd>,
+    wake: PollEvented2<Fd>,
+    wake_rx: UnixStream,
+    buf: RecvBuffer,
+    delay: Option<Delay>,
+}
+
+#[cfg(feature = "tokio")]
+impl<D> TokioPump<D>
+where
+    D: NetworkDevice + AsRawFd,
+{
+    fn pump(&mut self) -> Result<Async<()>> {
+        loop {
+            if self.wake.poll_read_ready(Ready::readable())?.is_ready() {
+                drain(&mut self.wake_rx);
+                self.wake.clear_read_ready(Ready::readable())?;
+            }
+
+            if self.shared.lock().stopping {
+                return Ok(Async::Ready(()));
+            }
+
+            // The reactor only reports the device again once it's been read until it
+            // would block
+            let packet_ready: bool = self.nic.poll_read_ready(Ready::readable())?.is_ready();
+            if self.shared.turn(&mut self.buf, packet_ready)? {
+                continue;
+            }
+            if packet_ready {
+                self.nic.clear_read_ready(Ready::readable())?;
+            }
+
+            let Some(deadline) = self.shared.lock().next_deadline() else {
+                self.delay = None;
+                return Ok(Async::NotReady);
+            };
+            let delay: &mut Delay = self.delay.get_or_insert_with(|| Delay::new(deadline));
+            delay.reset(deadline);
+            match delay.poll() {
+                Ok(Async::Ready(())) => continue,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => return Err(io::Error::other(err).into()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<D> TokioFuture for TokioPump<D>
+where
+    D: NetworkDevice + AsRawFd,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> TokioPoll<(), ()> {
+        self.pump().map_err(|err| log!("Packet pump failed: {err}"))
+    }
+}
+
+/// Wakes the tokio task which was being polled when it was made
+#[cfg(feature = "tokio")]
+struct TaskWaker(Task);
+
+#[cfg(feature = "tokio")]
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.notify();
+    }
+}
+
+/// Poll one of the front end's operations from a tokio task, failing with
+/// [`io::ErrorKind::WouldBlock`] if it isn't ready, in which case the task is notified
+/// once it may be. Panics outside of a task.
+#[cfg(feature = "tokio")]
+fn poll_in_task<T>(poll: impl FnOnce(&mut Context<'_>) -> Poll<io::Result<T>>) -> io::Result<T> {
+    let waker = Waker::from(Arc::new(TaskWaker(tokio::prelude::task::current())));
+    match poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+/// Reads as tokio's [`AsyncRead`] expects, without waiting. Only call it from a tokio task.
+#[cfg(feature = "tokio")]
+impl<D> Read for AsyncTcpStream<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_in_task(|cx| AsyncTcpStream::poll_read(self, cx, buf))
+    }
+}
+
+/// Writes as tokio's [`AsyncWrite`] expects, without waiting. Only call it from a tokio
+/// task.
+#[cfg(feature = "tokio")]
+impl<D> Write for AsyncTcpStream<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        poll_in_task(|cx| AsyncTcpStream::poll_write(self, cx, data))
+    }
+
+    /// Written data goes straight to the connection, so there's nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<D> AsyncRead for AsyncTcpStream<D> where D: NetworkDevice + AsRawFd + Send + Sync + 'static {}
+
+#[cfg(feature = "tokio")]
+impl<D> AsyncWrite for AsyncTcpStream<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Close our side of the connection, see [`AsyncTcpStream::shutdown`]
+    fn shutdown(&mut self) -> TokioPoll<(), io::Error> {
+        poll_in_task(|cx| AsyncTcpStream::poll_shutdown(self, cx)).map(Async::Ready)
+    }
+}
+
+/// A `std` future run as a tokio task, see [`compat`]
+#[cfg(feature = "tokio")]
+pub struct Compat<T>(Pin<Box<dyn Future<Output = T> + Send>>);
+
+/// Run `future` on a tokio runtime, which only runs its own kind of futures. Any of the
+/// front end's can be awaited inside, such as
+/// `tokio::run(compat(async move { listener.accept().await; ... }))`.
+#[cfg(feature = "tokio")]
+pub fn compat<F>(future: F) -> Compat<F::Output>
+where
+    F: Future + Send + 'static,
+{
+    Compat(Box::pin(future))
+}
+
+#[cfg(feature = "tokio")]
+impl<T> TokioFuture for Compat<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> TokioPoll<T, ()> {
+        let waker = Waker::from(Arc::new(TaskWaker(tokio::prelude::task::current())));
+        match self.0.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => Ok(Async::Ready(output)),
+            Poll::Pending => Ok(Async::NotReady),
+        }
+    }
+}
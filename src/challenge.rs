@@ -0,0 +1,48 @@
+use core::time::Duration;
+
+use crate::clock::{self, Instant};
+
+/// Default number of challenge ACKs allowed per second across all connections, matching Linux
+pub const DEFAULT_CHALLENGE_ACK_LIMIT: u32 = 1000;
+
+/// Limits how many challenge ACKs are sent, shared between every connection.
+/// RFC 5961 Section 7
+///
+/// Without a limit, an attacker spraying RSTs or SYNs could use us to amplify traffic
+/// towards the peer.
+pub struct ChallengeAckLimiter {
+    limit_per_sec: u32,
+    window_start: Instant,
+    sent_in_window: u32,
+}
+
+impl ChallengeAckLimiter {
+    pub fn new(limit_per_sec: u32) -> Self {
+        ChallengeAckLimiter {
+            limit_per_sec,
+            window_start: clock::start(),
+            sent_in_window: 0,
+        }
+    }
+
+    /// Whether another challenge ACK may be sent at `now`. Counts it as sent if so.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.sent_in_window = 0;
+        }
+
+        if self.sent_in_window >= self.limit_per_sec {
+            return false;
+        }
+
+        self.sent_in_window += 1;
+        true
+    }
+}
+
+impl Default for ChallengeAckLimiter {
+    fn default() -> Self {
+        ChallengeAckLimiter::new(DEFAULT_CHALLENGE_ACK_LIMIT)
+    }
+}
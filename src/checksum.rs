@@ -0,0 +1,93 @@
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+
+/// Which checksum of an incoming packet didn't match its contents
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumError {
+    /// RFC 791 Section 3.1, header checksum
+    Ipv4Header,
+    /// RFC 9293 Section 3.1, checksum over the pseudo-header, TCP header and payload
+    Tcp,
+}
+
+/// Check the IPv4 header checksum and the TCP checksum of a received segment.
+/// `payload` must be exactly the segment's data, without any link layer padding.
+pub fn verify(
+    ip_header: &Ipv4HeaderSlice,
+    tcp_header: &TcpHeaderSlice,
+    payload: &[u8],
+) -> Result<(), ChecksumError> {
+    verify_ipv4_header(ip_header)?;
+
+    match tcp_header.calc_checksum_ipv4(ip_header, payload) {
+        Ok(checksum) if checksum == tcp_header.checksum() => Ok(()),
+        _ => Err(ChecksumError::Tcp),
+    }
+}
+
+/// Check just the IPv4 header checksum, for fragments which can't be checked any further
+/// until the datagram is reassembled
+pub fn verify_ipv4_header(ip_header: &Ipv4HeaderSlice) -> Result<(), ChecksumError> {
+    if ip_header.to_header().calc_header_checksum() != ip_header.header_checksum() {
+        return Err(ChecksumError::Ipv4Header);
+    }
+
+    Ok(())
+}
+
+/// RFC 9293 Section 3.1, the TCP checksum of a segment from `source` to `destination`
+/// whose header and payload are split across `parts`, as they are when the payload is
+/// sent straight from the send buffer. The header's checksum field must be zero.
+pub fn tcp_ipv4(source: [u8; 4], destination: [u8; 4], parts: &[&[u8]]) -> u16 {
+    let tcp_len: usize = parts.iter().map(|part| part.len()).sum();
+
+    let mut sum = OnesComplementSum::default();
+    sum.add(&source);
+    sum.add(&destination);
+    sum.add(&[0, IpNumber::TCP.0]);
+    sum.add(&(tcp_len as u16).to_be_bytes());
+    for part in parts {
+        sum.add(part);
+    }
+
+    sum.finish()
+}
+
+/// RFC 1071, the sum of 16 bit words over data which may be split at any byte
+#[derive(Default)]
+struct OnesComplementSum {
+    sum: u64,
+    /// The first byte of a word whose second byte is at the start of the next part
+    odd_byte: Option<u8>,
+}
+
+impl OnesComplementSum {
+    fn add(&mut self, mut bytes: &[u8]) {
+        if let Some(high) = self.odd_byte.take() {
+            let Some((&low, rest)) = bytes.split_first() else {
+                self.odd_byte = Some(high);
+                return;
+            };
+            self.sum += u16::from_be_bytes([high, low]) as u64;
+            bytes = rest;
+        }
+
+        let mut words = bytes.chunks_exact(2);
+        for word in &mut words {
+            self.sum += u16::from_be_bytes([word[0], word[1]]) as u64;
+        }
+        if let [last] = words.remainder() {
+            self.odd_byte = Some(*last);
+        }
+    }
+
+    fn finish(mut self) -> u16 {
+        if let Some(high) = self.odd_byte {
+            self.sum += u16::from_be_bytes([high, 0]) as u64;
+        }
+        while self.sum >> 16 != 0 {
+            self.sum = (self.sum & 0xffff) + (self.sum >> 16);
+        }
+
+        !(self.sum as u16)
+    }
+}
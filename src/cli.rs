@@ -0,0 +1,419 @@
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    config::StackConfig,
+    error::{Result, TcpError},
+    span::LogLevel,
+    tcat::{TcatMode, LOCAL_ADDR},
+    tcp::KeepaliveConfig,
+};
+
+/// Interface opened when none is given
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_IFACE: &str = "tun0";
+/// Interface opened when none is given, the next free utun interface
+#[cfg(target_os = "macos")]
+pub const DEFAULT_IFACE: &str = "utun";
+
+/// Number of synthetic connections opened by `--isn-audit` when no count is given
+pub const DEFAULT_ISN_AUDIT_CONNECTIONS: usize = 1000;
+
+pub const USAGE: &str = "Usage: tcp_rs [options] [--help | --isn-audit [connections] | --analyze <file.pcap> | --replay <file.pcap> [expected.txt] | --workload <flows.txt> | tcat [-l] ...]
+
+Options, which are also the keys of a --config file:
+  --config <file>        read `key = value` lines, one per option, before the command line
+  --profile <name>       start from a preset: default, embedded, server or interactive
+  --iface <name>         tun interface to open, tun0 by default, or utun<n> on macOS
+  --tap <ip>             open a tap interface instead, answering ARP for this address
+  --raw <ip>             use an existing interface through a packet socket, as this address
+  --address <ip/prefix>  give the interface this host side address and bring it up
+  --listen <port,...>    only accept connections to these ports, rather than every port
+  --forward <port=host:port,...>
+                         relay connections to these ports to host sockets
+  --reverse <host:port=ip:port,...>
+                         relay host connections to these addresses through the stack
+  --workers <n>          spread connections over n worker threads
+  --queues <n>           open the tun interface with n queues, one thread each (Linux)
+  --log <level>          off, info, debug or trace, info by default
+  --log-port <port>      only log connections to or from this port
+  --pcap <file>          capture every packet to a pcap file from the start
+
+Connection settings:
+  --ttl <hops>  --recv-window <bytes>  --mss <bytes>  --send-buffer <bytes>
+  --timestamps <on|off>  --sack <on|off>  --pacing <on|off>
+  --initial-rto <ms>  --max-rto <ms>
+  --keepalive <idle s|off>  --idle-timeout <s|off>  --time-wait <ms>
+  --max-connections <n|off>";
+
+/// What the binary was asked to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Serve connections on the interface
+    Daemon(Box<DaemonOptions>),
+    /// Print [`USAGE`]
+    Help,
+    IsnAudit(usize),
+    Analyze(String),
+    Replay {
+        pcap: String,
+        expected: Option<String>,
+    },
+    Workload(String),
+    Tcat {
+        iface: String,
+        mode: TcatMode,
+    },
+}
+
+/// How the daemon reaches the network through its interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkMode {
+    /// A tun interface carrying IP packets
+    Tun,
+    /// A tap interface carrying Ethernet frames, with the stack at this address on the link
+    Tap(Ipv4Addr),
+    /// An existing interface, such as a physical NIC, through an `AF_PACKET` socket, with
+    /// the stack at this address. See [`PacketSocket`](crate::packet_socket::PacketSocket).
+    Packet(Ipv4Addr),
+}
+
+/// Bridge connections the stack accepts on `port` to new host connections to `upstream`,
+/// see [`Proxy`](crate::proxy::Proxy). Written `port=upstream`, such as
+/// `80=127.0.0.1:8080`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Forward {
+    pub port: u16,
+    pub upstream: SocketAddr,
+}
+
+/// Bridge connections a host listener accepts on `listen` to new connections the stack
+/// opens from `local` to `remote`. Written `listen=remote`, such as
+/// `127.0.0.1:8080=192.168.0.1:80`, with the stack at [`LOCAL_ADDR`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reverse {
+    pub listen: SocketAddr,
+    pub local: Ipv4Addr,
+    pub remote: SocketAddrV4,
+}
+
+impl FromStr for Forward {
+    type Err = TcpError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (port, upstream) = value
+            .split_once('=')
+            .ok_or_else(|| TcpError::Parse(format!("Expected <port>=<host:port>, got {value}")))?;
+
+        Ok(Forward {
+            port: port.trim().parse()?,
+            upstream: upstream.trim().parse()?,
+        })
+    }
+}
+
+impl FromStr for Reverse {
+    type Err = TcpError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (listen, remote) = value.split_once('=').ok_or_else(|| {
+            TcpError::Parse(format!("Expected <host:port>=<ip:port>, got {value}"))
+        })?;
+
+        Ok(Reverse {
+            listen: listen.trim().parse()?,
+            local: LOCAL_ADDR,
+            remote: remote.trim().parse()?,
+        })
+    }
+}
+
+/// How the daemon is set up, from options on the command line or in a config file
+#[derive(Clone, Debug, PartialEq)]
+pub struct DaemonOptions {
+    pub iface: String,
+    pub link: LinkMode,
+    /// Host side address and prefix length to configure on the interface, if any
+    pub address: Option<(Ipv4Addr, u8)>,
+    /// Ports to listen on, or every port if empty
+    pub listen: Vec<u16>,
+    /// Proxy rules, which turn the daemon into a relay between the stack and host sockets
+    pub forward: Vec<Forward>,
+    pub reverse: Vec<Reverse>,
+    /// Worker threads, or `None` to handle every connection on the main thread
+    pub workers: Option<usize>,
+    /// Queues to open the tun interface with, each served by a thread of its own, or `None`
+    /// for a single queue
+    pub queues: Option<usize>,
+    pub log_level: LogLevel,
+    /// Only log connections using this port, see [`set_port_filter`](crate::span::set_port_filter)
+    pub log_port: Option<u16>,
+    /// Where to capture packets to from the start, if anywhere
+    pub pcap: Option<PathBuf>,
+    pub config: StackConfig,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        DaemonOptions {
+            iface: DEFAULT_IFACE.to_string(),
+            link: LinkMode::Tun,
+            address: None,
+            listen: Vec::new(),
+            forward: Vec::new(),
+            reverse: Vec::new(),
+            workers: None,
+            queues: None,
+            log_level: LogLevel::Info,
+            log_port: None,
+            pcap: None,
+            config: StackConfig::DEFAULT,
+        }
+    }
+}
+
+impl DaemonOptions {
+    /// Set the option called `key`, as named on the command line without the leading `--`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let tcb = &mut self.config.tcb;
+
+        match key {
+            "profile" => self.config = StackConfig::profile(value)?,
+            "iface" => self.iface = value.to_string(),
+            "tap" => self.link = LinkMode::Tap(value.parse()?),
+            "raw" => self.link = LinkMode::Packet(value.parse()?),
+            "address" => self.address = Some(parse_address(value)?),
+            "listen" => {
+                self.listen = value
+                    .split(',')
+                    .map(|port| port.trim().parse::<u16>())
+                    .collect::<Result<Vec<u16>, _>>()?
+            }
+            "forward" => self.forward = parse_list(value)?,
+            "reverse" => self.reverse = parse_list(value)?,
+            "workers" => self.workers = Some(value.parse()?),
+            "queues" => self.queues = Some(value.parse()?),
+            "log" => self.log_level = LogLevel::parse(value)?,
+            "log-port" => self.log_port = Some(value.parse()?),
+            "pcap" => self.pcap = Some(value.into()),
+            "ttl" => tcb.ttl = value.parse()?,
+            "recv-window" => tcb.recv_window = value.parse()?,
+            "mss" => tcb.mss = Some(value.parse()?),
+            "send-buffer" => tcb.send_buffer_size = value.parse()?,
+            "timestamps" => tcb.timestamps = parse_switch(value)?,
+            "sack" => tcb.sack = parse_switch(value)?,
+            "pacing" => tcb.pacing = parse_switch(value)?,
+            "initial-rto" => tcb.initial_rto = Duration::from_millis(value.parse()?),
+            "max-rto" => tcb.max_rto = Duration::from_millis(value.parse()?),
+            "keepalive" => {
+                tcb.keepalive = parse_optional(value)?.map(|idle| KeepaliveConfig {
+                    idle: Duration::from_secs(idle),
+                    ..KeepaliveConfig::DEFAULT
+                })
+            }
+            "idle-timeout" => tcb.idle_timeout = parse_optional(value)?.map(Duration::from_secs),
+            "time-wait" => tcb.time_wait = Duration::from_millis(value.parse()?),
+            "max-connections" => {
+                self.config.max_connections = parse_optional(value)?.map(|n| n as usize)
+            }
+            _ => return Err(TcpError::Parse(format!("Unknown option {key}"))),
+        }
+
+        Ok(())
+    }
+}
+
+/// The options in a config file of `key = value` lines, keys named as on the command line
+/// without the leading `--`. Everything after a `#` is a comment.
+/// ```text
+/// profile = server
+/// listen = 80, 443
+/// idle-timeout = 300  # seconds
+/// ```
+pub fn parse_config_file(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut settings: Vec<(String, String)> = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line: &str = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(TcpError::Parse(format!(
+                "line {}: expected key = value, got {line}",
+                i + 1
+            )));
+        };
+        settings.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(settings)
+}
+
+impl Command {
+    /// Parse the arguments after the program name.
+    ///
+    /// The profile is applied first, whether it's given in a `--config` file or on the
+    /// command line, then everything in the file, then the rest of the command line. So
+    /// the command line overrides the file, and either one tunes the profile.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter().peekable();
+        let mut settings: Vec<(String, String)> = Vec::new();
+
+        while let Some(key) = args.next_if(|arg| arg.starts_with("--")) {
+            let key: &str = &key[2..];
+            if is_command(key) {
+                return Command::parse_command(key, args, settings);
+            }
+
+            let Some(value) = args.next() else {
+                return Err(TcpError::Parse(format!("--{key} needs a value\n{USAGE}")));
+            };
+            settings.push((key.to_string(), value));
+        }
+
+        match args.next() {
+            None => Ok(Command::Daemon(Box::new(daemon_options(settings)?))),
+            Some(arg) if arg == "tcat" => {
+                let options: DaemonOptions = daemon_options(settings)?;
+                Ok(Command::Tcat {
+                    iface: options.iface,
+                    mode: TcatMode::from_args(args)?,
+                })
+            }
+            Some(arg) => Err(TcpError::Parse(format!("Unknown argument {arg}\n{USAGE}"))),
+        }
+    }
+
+    fn parse_command(
+        name: &str,
+        mut args: impl Iterator<Item = String>,
+        settings: Vec<(String, String)>,
+    ) -> Result<Self> {
+        if let Some((key, _)) = settings.first() {
+            return Err(TcpError::Parse(format!(
+                "--{key} can't be used with --{name}"
+            )));
+        }
+
+        let command = match name {
+            "help" => Command::Help,
+            "isn-audit" => Command::IsnAudit(match args.next() {
+                Some(n) => n.parse()?,
+                None => DEFAULT_ISN_AUDIT_CONNECTIONS,
+            }),
+            "analyze" => {
+                let Some(path) = args.next() else {
+                    return Err(TcpError::Parse("--analyze needs a pcap file".into()));
+                };
+                Command::Analyze(path)
+            }
+            "replay" => {
+                let Some(pcap) = args.next() else {
+                    return Err(TcpError::Parse("--replay needs a pcap file".into()));
+                };
+                Command::Replay {
+                    pcap,
+                    expected: args.next(),
+                }
+            }
+            "workload" => {
+                let Some(path) = args.next() else {
+                    return Err(TcpError::Parse(
+                        "--workload needs a flow description file".into(),
+                    ));
+                };
+                Command::Workload(path)
+            }
+            _ => {
+                return Err(TcpError::Parse(format!(
+                    "Unknown argument --{name}\n{USAGE}"
+                )))
+            }
+        };
+
+        if let Some(arg) = args.next() {
+            return Err(TcpError::Parse(format!(
+                "Unexpected argument {arg} after --{name}"
+            )));
+        }
+        Ok(command)
+    }
+}
+
+/// Whether `--key` is a command rather than an option
+fn is_command(key: &str) -> bool {
+    matches!(
+        key,
+        "help" | "isn-audit" | "analyze" | "replay" | "workload"
+    )
+}
+
+/// Options from the command line `settings` and any config files they name, applied in
+/// the order described on [`Command::parse`]
+fn daemon_options(settings: Vec<(String, String)>) -> Result<DaemonOptions> {
+    let mut all: Vec<(String, String)> = Vec::new();
+    for (_, path) in settings.iter().filter(|(key, _)| key == "config") {
+        let contents: String = fs::read_to_string(path)
+            .map_err(|err| TcpError::Failed(format!("reading config file {path}: {err}")))?;
+        all.extend(
+            parse_config_file(&contents)
+                .map_err(|err| TcpError::Parse(format!("in {path}: {err}")))?,
+        );
+    }
+    all.extend(settings.into_iter().filter(|(key, _)| key != "config"));
+
+    let mut options = DaemonOptions::default();
+    let (profiles, rest): (Vec<_>, Vec<_>) = all.into_iter().partition(|(key, _)| key == "profile");
+    for (key, value) in profiles.iter().chain(&rest) {
+        options
+            .set(key, value)
+            .map_err(|err| TcpError::Parse(format!("--{key}: {err}")))?;
+    }
+
+    Ok(options)
+}
+
+/// A comma separated list, like `80=127.0.0.1:8080, 443=127.0.0.1:8443`
+fn parse_list<T: FromStr<Err = TcpError>>(value: &str) -> Result<Vec<T>> {
+    value.split(',').map(|item| item.trim().parse()).collect()
+}
+
+/// An address with its prefix length, like `192.168.0.1/24`
+fn parse_address(value: &str) -> Result<(Ipv4Addr, u8)> {
+    let Some((addr, prefix_len)) = value.split_once('/') else {
+        return Err(TcpError::Parse(format!(
+            "expected <ip>/<prefix length>, got {value}"
+        )));
+    };
+
+    let prefix_len: u8 = prefix_len.parse()?;
+    if prefix_len > 32 {
+        return Err(TcpError::Parse(format!(
+            "prefix length must be at most 32, got {prefix_len}"
+        )));
+    }
+    Ok((addr.parse()?, prefix_len))
+}
+
+fn parse_switch(value: &str) -> Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(TcpError::Parse(format!("expected on or off, got {value}"))),
+    }
+}
+
+/// A number, or `off` for none
+fn parse_optional(value: &str) -> Result<Option<u64>> {
+    match value {
+        "off" => Ok(None),
+        _ => Ok(Some(value.parse()?)),
+    }
+}
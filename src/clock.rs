@@ -0,0 +1,190 @@
+#[cfg(feature = "std")]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(not(feature = "std"))]
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+/// A point on a [`Clock`], std's own with the `std` feature
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+/// A point on a [`Clock`]: nanoseconds since whenever the clock started counting from,
+/// such as the board booting. It has the parts of std's `Instant` the stack uses.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant { nanos }
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos: u64 = duration.as_nanos().try_into().ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos: u64 = duration.as_nanos().try_into().ok()?;
+        self.nanos.checked_sub(nanos).map(Instant::from_nanos)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Where timers kept outside a connection start from, before they've been given a time
+/// from a [`Clock`]
+#[cfg(feature = "std")]
+pub(crate) fn start() -> Instant {
+    Instant::now()
+}
+
+/// Without std there's no time to read, so the start of the clock
+#[cfg(not(feature = "std"))]
+pub(crate) fn start() -> Instant {
+    Instant::from_nanos(0)
+}
+
+/// Where connections read the time from for their timers: retransmission, TIME-WAIT,
+/// keep-alive and timestamps
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(feature = "std")]
+/// The clock connections use unless told otherwise
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(feature = "std")]
+/// A clock which only moves when told to.
+///
+/// Driving a stack with one, ticking it at each [`Stack::next_deadline`] and advancing the
+/// clock to match, runs timeouts deterministically and without waiting for them.
+///
+/// [`Stack::next_deadline`]: crate::stack::Stack::next_deadline
+pub struct SimulatedClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(feature = "std")]
+impl SimulatedClock {
+    /// A clock starting from the real time now
+    pub fn new() -> Self {
+        SimulatedClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    /// Move the clock forward to `instant`. It never goes backwards, so an instant
+    /// already passed leaves it where it is.
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.lock();
+        *now = (*now).max(instant);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Instant> {
+        self.now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        SimulatedClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        *self.lock()
+    }
+}
@@ -0,0 +1,179 @@
+use alloc::format;
+use core::time::Duration;
+
+use crate::{
+    error::{Result, TcpError},
+    health::HealthLimits,
+    rto::{DEFAULT_INITIAL_RTO, MAX_RTO},
+    tcp::{KeepaliveConfig, MSL, SEND_BUFFER_SIZE},
+    window::DEFAULT_RECV_WINDOW,
+};
+
+/// Settings for one connection, given to [`Tcb::connect_with_config`] and
+/// [`Tcb::accept_connection_with_config`]. A [`Stack`](crate::stack::Stack) opens every
+/// connection with the one in its [`StackConfig`].
+///
+/// [`Tcb::connect_with_config`]: crate::tcp::Tcb::connect_with_config
+/// [`Tcb::accept_connection_with_config`]: crate::tcp::Tcb::accept_connection_with_config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcbConfig {
+    /// Time to live of every IP packet sent
+    pub ttl: u8,
+    /// Receive window advertised, in bytes. Without window scaling at most 65535 can be
+    /// advertised.
+    pub recv_window: u32,
+    /// MSS option sent on our SYN, or `None` to send none so the peer assumes 536 bytes.
+    /// RFC 9293 Section 3.7.1
+    pub mss: Option<u16>,
+    /// Most unacknowledged data `send` will hold
+    pub send_buffer_size: usize,
+    /// Whether timestamps are offered and accepted, RFC 7323 Section 3
+    pub timestamps: bool,
+    /// Whether duplicate data is reported with D-SACK blocks to peers which accept SACK,
+    /// RFC 2883. No SACK scoreboard is kept either way.
+    pub sack: bool,
+    /// Retransmission timeout until a round trip has been measured, see
+    /// [`RtoEstimator::with_max`]
+    pub initial_rto: Duration,
+    /// Longest the retransmission timeout backs off to, at least [`MAX_RTO`]
+    pub max_rto: Duration,
+    /// Keep-alive settings, or `None` for no probes
+    pub keepalive: Option<KeepaliveConfig>,
+    /// How long the connection can go without an acceptable segment from the peer before
+    /// it's reset and deleted, or `None` to keep it forever. Unlike keep-alives nothing
+    /// is sent to check whether the peer is still there.
+    pub idle_timeout: Option<Duration>,
+    /// Time spent in TIME-WAIT before the connection is deleted
+    pub time_wait: Duration,
+    /// Whether segments are paced across the round trip, see [`Pacer`](crate::pacing::Pacer)
+    pub pacing: bool,
+}
+
+impl TcbConfig {
+    pub const DEFAULT: TcbConfig = TcbConfig {
+        ttl: 64,
+        recv_window: DEFAULT_RECV_WINDOW,
+        mss: None,
+        send_buffer_size: SEND_BUFFER_SIZE,
+        timestamps: true,
+        sack: true,
+        initial_rto: DEFAULT_INITIAL_RTO,
+        max_rto: MAX_RTO,
+        keepalive: None,
+        idle_timeout: None,
+        time_wait: MSL.saturating_mul(2),
+        pacing: false,
+    };
+}
+
+impl Default for TcbConfig {
+    fn default() -> Self {
+        TcbConfig::DEFAULT
+    }
+}
+
+/// Settings for a [`Stack`](crate::stack::Stack) and the connections it opens, see
+/// [`Stack::set_config`](crate::stack::Stack::set_config).
+///
+/// Profiles for common uses are provided as constants, so a build can pick one without
+/// anything being read at runtime, or one can be chosen by name with
+/// [`StackConfig::profile`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackConfig {
+    /// Settings for each new connection
+    pub tcb: TcbConfig,
+    pub health_limits: HealthLimits,
+    /// Most connections held at once, including those in TIME-WAIT, or `None` for no
+    /// limit. SYNs arriving at the limit are dropped and `connect` fails.
+    pub max_connections: Option<usize>,
+}
+
+impl StackConfig {
+    /// How the stack behaves when nothing is configured
+    pub const DEFAULT: StackConfig = StackConfig {
+        tcb: TcbConfig::DEFAULT,
+        health_limits: HealthLimits::DEFAULT,
+        max_connections: None,
+    };
+
+    /// Small buffers and a handful of connections, for devices short on memory. Options
+    /// which only help fast paths are left off, saving 12 bytes of timestamps a segment,
+    /// and connections which have gone quiet are freed after 5 minutes.
+    pub const EMBEDDED: StackConfig = StackConfig {
+        tcb: TcbConfig {
+            recv_window: 1024,
+            send_buffer_size: 2 * 1024,
+            timestamps: false,
+            sack: false,
+            idle_timeout: Some(Duration::from_secs(5 * 60)),
+            ..TcbConfig::DEFAULT
+        },
+        health_limits: HealthLimits {
+            max_buffered_bytes: 64 * 1024,
+            max_connections: 16,
+            max_timers: 3 * 16,
+            max_device_queue: 32,
+        },
+        max_connections: Some(16),
+    };
+
+    /// Large buffers and room for many connections, with keep-alives so connections to
+    /// peers which have gone away are eventually freed. Connections past the limit are
+    /// refused, so a flood of SYNs can't grow the table without bound.
+    pub const SERVER: StackConfig = StackConfig {
+        tcb: TcbConfig {
+            recv_window: u16::MAX as u32,
+            send_buffer_size: 256 * 1024,
+            keepalive: Some(KeepaliveConfig::DEFAULT),
+            ..TcbConfig::DEFAULT
+        },
+        health_limits: HealthLimits {
+            max_buffered_bytes: 1024 * 1024 * 1024,
+            max_connections: 65536,
+            max_timers: 3 * 65536,
+            max_device_queue: 4096,
+        },
+        max_connections: Some(65536),
+    };
+
+    /// Small exchanges where latency matters more than throughput, with keep-alives
+    /// quick to notice a dead peer. Segments and ACKs already go out as soon as they can,
+    /// the stack never holds them back.
+    pub const INTERACTIVE: StackConfig = StackConfig {
+        tcb: TcbConfig {
+            recv_window: 16 * 1024,
+            send_buffer_size: 16 * 1024,
+            keepalive: Some(KeepaliveConfig {
+                idle: Duration::from_secs(60),
+                interval: Duration::from_secs(10),
+                probes: 6,
+            }),
+            ..TcbConfig::DEFAULT
+        },
+        health_limits: HealthLimits::DEFAULT,
+        max_connections: None,
+    };
+
+    /// Names accepted by [`StackConfig::profile`]
+    pub const PROFILES: [&str; 4] = ["default", "embedded", "server", "interactive"];
+
+    /// The profile called `name`, one of [`StackConfig::PROFILES`]
+    pub fn profile(name: &str) -> Result<StackConfig> {
+        match name {
+            "default" => Ok(StackConfig::DEFAULT),
+            "embedded" => Ok(StackConfig::EMBEDDED),
+            "server" => Ok(StackConfig::SERVER),
+            "interactive" => Ok(StackConfig::INTERACTIVE),
+            _ => Err(TcpError::Parse(format!(
+                "Unknown profile {name}, expected one of {}",
+                StackConfig::PROFILES.join(", ")
+            ))),
+        }
+    }
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        StackConfig::DEFAULT
+    }
+}
@@ -0,0 +1,232 @@
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+use crate::{clock::Instant, seq::SeqNum, span::log};
+
+/// How long the window can go without being validated before it's reduced.
+/// RFC 7661 Section 4.3
+pub const NON_VALIDATED_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// Shortest time pipeACK samples are kept for. RFC 7661 Section 4.2 only asks for at least
+/// three round trips, which on a sub-millisecond path would keep little more than the
+/// latest sample.
+pub const MIN_SAMPLING_PERIOD: Duration = Duration::from_secs(1);
+
+/// Initial window for a sender with an MSS of `mss` bytes.
+/// RFC 6928 Section 2, `min(10*MSS, max(2*MSS, 14600))`
+pub fn initial_window(mss: u32) -> u32 {
+    (10 * mss).min((2 * mss).max(14600))
+}
+
+/// The congestion window, which limits how much can be in flight alongside the peer's
+/// window. Slow start and congestion avoidance as in RFC 5681 Section 3.1, with the window
+/// growing by one MSS each time a window's worth of data is acknowledged once past
+/// ssthresh.
+///
+/// The window is only grown while it's validated, RFC 7661. A sender limited by the
+/// application rather than the window learns nothing about whether the path can carry
+/// the window, so it isn't grown, and it's halved for every [`NON_VALIDATED_PERIOD`] it
+/// goes unused, idle connections included.
+#[derive(Clone, Debug)]
+pub struct CongestionWindow {
+    cwnd: u32,
+    ssthresh: u32,
+    /// Bytes acknowledged in congestion avoidance since the window last grew
+    bytes_acked: u32,
+    /// The SND.NXT which ends the flight being sampled, see [`CongestionWindow::pipe_ack`]
+    sample_end: Option<SeqNum>,
+    /// Bytes acknowledged so far in the flight being sampled
+    sample_acked: u32,
+    /// cwnd when the flight being sampled started
+    sample_cwnd: u32,
+    /// pipeACK samples within the sampling period, and when each was taken
+    samples: VecDeque<(Instant, u32)>,
+    /// Whether a sample has ever been taken, before which pipeACK is undefined
+    sampled: bool,
+    validated: bool,
+    /// When the window was last seen validated, or last reduced for not being
+    validated_at: Instant,
+}
+
+impl CongestionWindow {
+    /// The window before the handshake, which is replaced once it completes, see
+    /// [`CongestionWindow::on_established`]
+    pub fn new(mss: u32, now: Instant) -> Self {
+        CongestionWindow {
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            bytes_acked: 0,
+            sample_end: None,
+            sample_acked: 0,
+            sample_cwnd: 0,
+            samples: VecDeque::new(),
+            sampled: false,
+            validated: true,
+            validated_at: now,
+        }
+    }
+
+    /// cwnd, in bytes
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    /// ssthresh, in bytes, `u32::MAX` until the first loss
+    pub fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    /// The most data acknowledged in one flight within the sampling period, or `None` until
+    /// a whole flight has been acknowledged. RFC 7661 Section 4.2
+    pub fn pipe_ack(&self) -> Option<u32> {
+        if !self.sampled {
+            return None;
+        }
+
+        Some(self.samples.iter().map(|&(_, n)| n).max().unwrap_or(0))
+    }
+
+    /// Whether the window has been used recently enough to be trusted, pipeACK >= cwnd/2
+    /// as in RFC 7661 Section 4.3. pipeACK is compared with the window its flight was sent
+    /// with, as in slow start the window doubles before the flight is all acknowledged.
+    /// True until pipeACK is defined, false once every sample has aged out.
+    pub fn is_validated(&self) -> bool {
+        self.validated
+    }
+
+    /// Start from the initial window once the handshake completes. RFC 5681 Section 3.1,
+    /// if our SYN or SYN,ACK had to be retransmitted the window starts at one segment.
+    pub fn on_established(&mut self, mss: u32, syn_retransmitted: bool, now: Instant) {
+        self.cwnd = if syn_retransmitted {
+            mss
+        } else {
+            initial_window(mss)
+        };
+        self.validated_at = now;
+    }
+
+    /// Carry on with a window from elsewhere, such as the process a connection was handed
+    /// over from. It's trusted until the flights sent with it say otherwise.
+    pub fn resume(&mut self, cwnd: u32, ssthresh: u32, now: Instant) {
+        self.cwnd = cwnd;
+        self.ssthresh = ssthresh;
+        self.validated_at = now;
+    }
+
+    /// Take an ACK of `n_acked` new bytes up to `ackn`, with `snd_nxt` being what's been
+    /// sent. Grows the window if it's validated.
+    pub fn on_ack(
+        &mut self,
+        n_acked: u32,
+        ackn: SeqNum,
+        snd_nxt: SeqNum,
+        mss: u32,
+        srtt: Option<Duration>,
+        now: Instant,
+    ) {
+        // A sample is everything acknowledged from one flight, the data sent by the time
+        // its first ACK arrives, which is as near as the ACKs get to the data acknowledged
+        // over one round trip
+        if n_acked > 0 {
+            self.sample_acked = self.sample_acked.saturating_add(n_acked);
+            let sample_end: SeqNum = match self.sample_end {
+                Some(sample_end) => sample_end,
+                None => {
+                    self.sample_cwnd = self.cwnd;
+                    snd_nxt
+                }
+            };
+
+            if ackn.ge(sample_end) {
+                self.samples.push_back((now, self.sample_acked));
+                self.sampled = true;
+                self.validated = self.pipe_ack().unwrap_or(0) >= self.sample_cwnd / 2;
+                self.sample_acked = 0;
+                self.sample_end = None;
+            } else {
+                self.sample_end = Some(sample_end);
+            }
+        }
+
+        self.update_phase(mss, srtt, now);
+        if !self.is_validated() || n_acked == 0 {
+            return;
+        }
+
+        if self.in_slow_start() {
+            self.cwnd = self.cwnd.saturating_add(n_acked.min(mss));
+            return;
+        }
+
+        self.bytes_acked = self.bytes_acked.saturating_add(n_acked);
+        if self.bytes_acked >= self.cwnd {
+            self.bytes_acked -= self.cwnd;
+            self.cwnd = self.cwnd.saturating_add(mss);
+        }
+    }
+
+    /// Check whether the window is still validated before sending, reducing it for every
+    /// [`NON_VALIDATED_PERIOD`] it hasn't been
+    pub fn on_send(&mut self, mss: u32, srtt: Option<Duration>, now: Instant) {
+        self.update_phase(mss, srtt, now);
+    }
+
+    /// Shrink the window after the retransmission timer expires with `flight_size` bytes
+    /// outstanding. RFC 5681 Section 3.1, equation 4 and the loss window.
+    /// ```text
+    /// ssthresh = max (FlightSize / 2, 2*SMSS)
+    /// cwnd = 1 SMSS
+    /// ```
+    /// ssthresh is only lowered on the first expiry for the same data, later ones back
+    /// off from the window it was already cut to.
+    pub fn on_timeout(&mut self, flight_size: u32, mss: u32, first: bool) {
+        if first {
+            self.ssthresh = (flight_size / 2).max(2 * mss);
+        }
+        self.cwnd = mss;
+        self.bytes_acked = 0;
+        self.sample_end = None;
+        self.sample_acked = 0;
+    }
+
+    /// Drop samples older than the sampling period, then note whether the window is
+    /// validated. RFC 7661 Section 4.4.3, at the end of each non-validated period
+    /// ```text
+    /// ssthresh = max(ssthresh, 3*cwnd/4)
+    /// cwnd = max(cwnd/2, IW)
+    /// ```
+    fn update_phase(&mut self, mss: u32, srtt: Option<Duration>, now: Instant) {
+        if let Some(srtt) = srtt {
+            let period: Duration = (srtt * 3).max(MIN_SAMPLING_PERIOD);
+            while self
+                .samples
+                .front()
+                .is_some_and(|&(taken, _)| now.saturating_duration_since(taken) > period)
+            {
+                self.samples.pop_front();
+            }
+        }
+        if self.sampled && self.samples.is_empty() {
+            self.validated = false;
+        }
+
+        if self.validated {
+            self.validated_at = now;
+            return;
+        }
+
+        while now.saturating_duration_since(self.validated_at) >= NON_VALIDATED_PERIOD {
+            let iw: u32 = initial_window(mss);
+            if self.cwnd > iw {
+                self.ssthresh = self.ssthresh.max(self.cwnd / 4 * 3);
+                self.cwnd = (self.cwnd / 2).max(iw);
+                log!("Congestion window unvalidated, reduced to {}b", self.cwnd);
+            }
+            self.validated_at += NON_VALIDATED_PERIOD;
+        }
+    }
+}
@@ -0,0 +1,534 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::cell::RefCell;
+#[cfg(feature = "std")]
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+#[cfg(all(unix, feature = "std"))]
+use std::os::fd::{AsRawFd, RawFd};
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+use tun_tap::{Iface, Mode};
+
+use crate::{
+    io::{self, IoSlice},
+    span::log,
+    ETH_HEADER_SIZE, ETH_MTU,
+};
+#[cfg(feature = "std")]
+use crate::{
+    pcap::PcapWriter,
+    pool::{PacketBuf, PacketPool},
+};
+
+/// The platform's tun device, opened by the daemon and used by [`AsyncStack`] by default:
+/// a Linux tun device, a macOS [`Utun`](crate::utun::Utun) or a Windows
+/// [`Wintun`](crate::wintun::Wintun) adapter
+///
+/// [`AsyncStack`]: crate::async_stack::AsyncStack
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub type TunDevice = Iface;
+#[cfg(all(target_os = "macos", feature = "std"))]
+pub type TunDevice = crate::utun::Utun;
+#[cfg(all(windows, feature = "std"))]
+pub type TunDevice = crate::wintun::Wintun;
+
+/// Open the tun interface `name`, or on macOS the utun interface, creating it if needed.
+/// Linux and macOS tun devices start out blocking, see their `set_non_blocking`.
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub fn open_tun(name: &str) -> io::Result<TunDevice> {
+    Iface::without_packet_info(name, Mode::Tun)
+}
+
+#[cfg(all(target_os = "macos", feature = "std"))]
+pub fn open_tun(name: &str) -> io::Result<TunDevice> {
+    crate::utun::Utun::open(name)
+}
+
+#[cfg(all(windows, feature = "std"))]
+pub fn open_tun(name: &str) -> io::Result<TunDevice> {
+    crate::wintun::Wintun::open(name)
+}
+
+/// netdevice(7), read an interface's MTU
+#[cfg(all(target_os = "linux", feature = "std"))]
+const SIOCGIFMTU: libc::c_ulong = libc::SIOCGIFMTU;
+/// `_IOWR('i', 51, struct ifreq)` from sys/sockio.h, which `libc` doesn't have for macOS
+#[cfg(all(target_os = "macos", feature = "std"))]
+const SIOCGIFMTU: libc::c_ulong = 0xc020_6933;
+
+/// A network interface which moves raw IP packets in and out of the stack
+pub trait NetworkDevice {
+    /// Receive a single packet into `buf`, returning its length
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Send a single packet, returning the number of bytes written
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Send a single packet made of `bufs` one after another, returning the number of bytes
+    /// written. Devices which can send straight from the pieces, as `writev` does, save
+    /// copying them into one buffer first.
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match bufs {
+            [buf] => self.send(buf),
+            _ => self.send(&gather(bufs)),
+        }
+    }
+
+    /// Largest IP packet the device can send, which connections start path MTU discovery
+    /// from. Packets are never built larger than [`ETH_MTU`], whatever the device allows.
+    fn mtu(&self) -> usize {
+        ETH_MTU
+    }
+
+    /// Packets waiting to be received, or `None` if the device can't tell
+    fn queued_packets(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<D: NetworkDevice + ?Sized> NetworkDevice for Box<D> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        (**self).send_vectored(bufs)
+    }
+
+    fn mtu(&self) -> usize {
+        (**self).mtu()
+    }
+
+    fn queued_packets(&self) -> Option<usize> {
+        (**self).queued_packets()
+    }
+}
+
+/// Largest IPv4 packet, RFC 791 Section 3.1. Segmentation offloads such as virtio GRO
+/// can hand over aggregates this large whatever the MTU.
+pub const MAX_PACKET_LEN: usize = u16::MAX as usize;
+
+/// Somewhere to receive packets from a device, large enough for any packet it can
+/// deliver plus a packet information prefix.
+///
+/// Devices cut packets short to fit the buffer without saying so. The buffer is one byte
+/// larger than the largest packet expected, so a read which fills it must have been
+/// truncated and is dropped rather than parsed.
+pub struct RecvBuffer {
+    buf: Vec<u8>,
+}
+
+impl RecvBuffer {
+    /// A buffer for packets up to `max_packet_len` bytes, at most [`MAX_PACKET_LEN`]
+    pub fn new(max_packet_len: usize) -> Self {
+        let len: usize = max_packet_len.min(MAX_PACKET_LEN) + ETH_HEADER_SIZE + 1;
+        RecvBuffer { buf: vec![0; len] }
+    }
+
+    /// A buffer sized from the device's MTU, and never smaller than Ethernet's
+    pub fn for_device(nic: &impl NetworkDevice) -> Self {
+        RecvBuffer::new(nic.mtu().max(ETH_MTU))
+    }
+
+    /// Largest packet which can be received without being truncated
+    pub fn max_packet_len(&self) -> usize {
+        self.buf.len() - 1
+    }
+
+    /// Receive a single packet. `None` means it was larger than the buffer, so was dropped.
+    pub fn recv(&mut self, nic: &impl NetworkDevice) -> io::Result<Option<&[u8]>> {
+        let n_bytes: Option<usize> = self.recv_len(nic)?;
+        Ok(n_bytes.map(|n_bytes| &self.buf[..n_bytes]))
+    }
+
+    fn recv_len(&mut self, nic: &impl NetworkDevice) -> io::Result<Option<usize>> {
+        let n_bytes: usize = nic.recv(&mut self.buf)?;
+
+        if n_bytes >= self.buf.len() {
+            log!(
+                "Skipping packet. Larger than the {} byte receive buffer",
+                self.max_packet_len()
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(n_bytes))
+    }
+}
+
+/// Packets a [`RecvBatch`] takes from the device in one go, by default
+pub const RECV_BATCH_SIZE: usize = 32;
+
+/// Somewhere to receive several packets at once, so each wakeup drains what the device
+/// has queued before any of it is processed, see [`Stack::on_packets`].
+///
+/// [`Stack::on_packets`]: crate::stack::Stack::on_packets
+pub struct RecvBatch {
+    buffers: Vec<RecvBuffer>,
+    /// Length of the packet in each buffer, up to the number received
+    lens: Vec<usize>,
+}
+
+impl RecvBatch {
+    /// Room for `capacity` packets of up to `max_packet_len` bytes
+    pub fn new(max_packet_len: usize, capacity: usize) -> Self {
+        RecvBatch {
+            buffers: (0..capacity.max(1))
+                .map(|_| RecvBuffer::new(max_packet_len))
+                .collect(),
+            lens: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Room for [`RECV_BATCH_SIZE`] packets, sized as [`RecvBuffer::for_device`]
+    pub fn for_device(nic: &impl NetworkDevice) -> Self {
+        RecvBatch::new(nic.mtu().max(ETH_MTU), RECV_BATCH_SIZE)
+    }
+
+    /// Receive packets until the device has none left or the batch is full, returning how
+    /// many were received. The device must be non-blocking, as reading stops when it
+    /// would block. Packets too large for the buffers are dropped, as with
+    /// [`RecvBuffer::recv`]. An error is only returned if nothing was received before it.
+    pub fn recv(&mut self, nic: &impl NetworkDevice) -> io::Result<usize> {
+        self.lens.clear();
+        let mut n_reads: usize = 0;
+
+        while n_reads < self.buffers.len() {
+            let buffer: &mut RecvBuffer = &mut self.buffers[self.lens.len()];
+            match buffer.recv_len(nic) {
+                Ok(Some(n_bytes)) => self.lens.push(n_bytes),
+                Ok(None) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if n_reads == 0 => return Err(err),
+                Err(_) => break,
+            }
+            n_reads += 1;
+        }
+
+        Ok(self.lens.len())
+    }
+
+    /// The packets from the last `recv`, in the order they were received
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.buffers
+            .iter()
+            .zip(&self.lens)
+            .map(|(buffer, &n_bytes)| &buffer.buf[..n_bytes])
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+impl NetworkDevice for Iface {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Iface::recv(self, buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Iface::send(self, buf)
+    }
+
+    /// Each write to a tun device is one packet, so the pieces go to the kernel as they are
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n_bufs: libc::c_int = bufs
+            .len()
+            .try_into()
+            .map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on Unix, and every
+        // slice outlives the call
+        let n_written: isize = unsafe {
+            libc::writev(
+                self.as_raw_fd(),
+                bufs.as_ptr().cast::<libc::iovec>(),
+                n_bufs,
+            )
+        };
+        if n_written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n_written as usize)
+    }
+
+    /// The interface's configured MTU, or [`ETH_MTU`] if it can't be read
+    fn mtu(&self) -> usize {
+        match interface_mtu(self.name()) {
+            Ok(mtu) => mtu,
+            Err(err) => {
+                log!(
+                    "Couldn't read the MTU of {}, assuming {ETH_MTU}: {err}",
+                    self.name()
+                );
+                ETH_MTU
+            }
+        }
+    }
+}
+
+/// Ask the kernel for an interface's MTU. netdevice(7), SIOCGIFMTU
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "std"))]
+pub(crate) fn interface_mtu(name: &str) -> io::Result<usize> {
+    let request: libc::ifreq = interface_ioctl(name, SIOCGIFMTU)?;
+
+    // SAFETY: SIOCGIFMTU fills in the MTU member
+    Ok(unsafe { request.ifr_ifru.ifru_mtu } as usize)
+}
+
+/// Make one of the netdevice(7) `SIOCGIF*` requests about the interface `name`, returning
+/// the filled in `ifreq`
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "std"))]
+pub(crate) fn interface_ioctl(name: &str, command: libc::c_ulong) -> io::Result<libc::ifreq> {
+    // SAFETY: an all zero ifreq is valid, the name is copied in below
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    if name.len() >= request.ifr_name.len() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    // Any socket will do, the request is about the interface
+    // SAFETY: no pointers are passed
+    let fd: libc::c_int = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: `request` is a valid ifreq which outlives the call
+    let result: libc::c_int = unsafe { libc::ioctl(fd, command, &mut request) };
+    let err = io::Error::last_os_error();
+    // SAFETY: `fd` was opened above and isn't used again
+    unsafe { libc::close(fd) };
+
+    if result < 0 {
+        return Err(err);
+    }
+    Ok(request)
+}
+
+/// A device which never receives anything and keeps every packet sent to it,
+/// for running the stack without touching the network
+#[derive(Default)]
+pub struct CaptureDevice {
+    sent: RefCell<Vec<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    /// Remove and return the packets sent so far
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        self.sent.take()
+    }
+}
+
+impl NetworkDevice for CaptureDevice {
+    fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "std")]
+/// One end of an in-memory link. Packets sent on one end are received, in order, on the
+/// other, so two stacks can talk without touching the network. Packets in flight are
+/// held in buffers from the shared [`PacketPool`].
+pub struct LoopbackDevice {
+    inbox: Arc<Mutex<VecDeque<PacketBuf>>>,
+    peer_inbox: Arc<Mutex<VecDeque<PacketBuf>>>,
+}
+
+#[cfg(feature = "std")]
+impl LoopbackDevice {
+    /// Both ends of a new link
+    pub fn pair() -> (LoopbackDevice, LoopbackDevice) {
+        let a: Arc<Mutex<VecDeque<PacketBuf>>> = Arc::default();
+        let b: Arc<Mutex<VecDeque<PacketBuf>>> = Arc::default();
+
+        (
+            LoopbackDevice {
+                inbox: Arc::clone(&a),
+                peer_inbox: Arc::clone(&b),
+            },
+            LoopbackDevice {
+                inbox: b,
+                peer_inbox: a,
+            },
+        )
+    }
+
+    /// Number of packets waiting to be received on this end
+    pub fn pending(&self) -> usize {
+        lock(&self.inbox).len()
+    }
+
+    /// Remove and return the packets waiting on this end, as if they were lost
+    pub fn take_pending(&self) -> Vec<Vec<u8>> {
+        lock(&self.inbox)
+            .drain(..)
+            .map(|packet| packet.to_vec())
+            .collect()
+    }
+}
+
+#[cfg(feature = "std")]
+impl NetworkDevice for LoopbackDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(packet) = lock(&self.inbox).pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        let n_bytes: usize = packet.len().min(buf.len());
+        buf[..n_bytes].copy_from_slice(&packet[..n_bytes]);
+        Ok(n_bytes)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let packet: PacketBuf = PacketPool::shared().copy(buf);
+        lock(&self.peer_inbox).push_back(packet);
+        Ok(buf.len())
+    }
+
+    fn queued_packets(&self) -> Option<usize> {
+        Some(self.pending())
+    }
+}
+
+#[cfg(feature = "std")]
+/// Wraps a device, optionally writing every packet received from or sent to it into a pcap
+/// file. Capturing can be started and stopped at any time, including from another thread.
+pub struct PcapTap<D: NetworkDevice> {
+    inner: D,
+    sink: Mutex<Option<PcapWriter<Box<dyn Write + Send>>>>,
+}
+
+#[cfg(feature = "std")]
+impl<D: NetworkDevice> PcapTap<D> {
+    /// Wrap `inner`, not capturing anything yet
+    pub fn new(inner: D) -> Self {
+        PcapTap {
+            inner,
+            sink: Mutex::new(None),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Start writing packets to `writer` as a new pcap file, replacing any capture already
+    /// running. Each packet is flushed as it's written, so the file can be followed live.
+    pub fn start_capture(&self, writer: impl Write + Send + 'static) -> io::Result<()> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let pcap = PcapWriter::new(writer)?;
+
+        if let Some(mut previous) = self.lock_sink().replace(pcap) {
+            previous.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop capturing, flushing what's been written. Does nothing if not capturing.
+    pub fn stop_capture(&self) -> io::Result<()> {
+        match self.lock_sink().take() {
+            Some(mut pcap) => pcap.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.lock_sink().is_some()
+    }
+
+    /// Write a packet to the capture, if there is one. The capture is stopped if it
+    /// fails, the device carries on either way.
+    fn capture(&self, packet: &[u8]) {
+        let mut sink = self.lock_sink();
+        let Some(pcap) = sink.as_mut() else {
+            return;
+        };
+
+        let written: io::Result<()> = pcap
+            .write_packet(SystemTime::now(), packet)
+            .and_then(|()| pcap.flush());
+        if let Err(err) = written {
+            log!("Stopping packet capture. Failed to write: {err}");
+            *sink = None;
+        }
+    }
+
+    fn lock_sink(&self) -> MutexGuard<'_, Option<PcapWriter<Box<dyn Write + Send>>>> {
+        self.sink
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: NetworkDevice> NetworkDevice for PcapTap<D> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_bytes: usize = self.inner.recv(buf)?;
+        self.capture(&buf[..n_bytes]);
+        Ok(n_bytes)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let n_bytes: usize = self.inner.send(buf)?;
+        self.capture(&buf[..n_bytes]);
+        Ok(n_bytes)
+    }
+
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n_bytes: usize = self.inner.send_vectored(bufs)?;
+        if self.is_capturing() {
+            self.capture(&gather(bufs)[..n_bytes]);
+        }
+        Ok(n_bytes)
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn queued_packets(&self) -> Option<usize> {
+        self.inner.queued_packets()
+    }
+}
+
+#[cfg(all(unix, feature = "std"))]
+impl<D: NetworkDevice + AsRawFd> AsRawFd for PcapTap<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Copy the pieces of a packet into one buffer from the shared pool
+#[cfg(feature = "std")]
+fn gather(bufs: &[IoSlice<'_>]) -> PacketBuf {
+    PacketPool::shared().gather(bufs)
+}
+
+/// Copy the pieces of a packet into one buffer
+#[cfg(not(feature = "std"))]
+fn gather(bufs: &[IoSlice<'_>]) -> Vec<u8> {
+    bufs.iter().flat_map(|buf| buf.iter().copied()).collect()
+}
+
+#[cfg(feature = "std")]
+fn lock(queue: &Mutex<VecDeque<PacketBuf>>) -> MutexGuard<'_, VecDeque<PacketBuf>> {
+    queue
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,181 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+};
+use core::{
+    error, fmt,
+    hash::Hash,
+    net::AddrParseError,
+    num::{ParseFloatError, ParseIntError},
+};
+
+use etherparse::{
+    err::{ipv4, tcp, ValueTooBigError},
+    TcpOptionWriteError,
+};
+
+use crate::{io, tcp::State};
+
+/// What the library's calls fail with. The connection errors are the ones RFC 9293
+/// Section 3.10 gives the user calls, so a caller can tell a connection which is closing
+/// from one which was reset, or a bad packet from a failing device, without matching on
+/// the message.
+#[derive(Debug)]
+pub enum TcpError {
+    /// A packet, capture or setting which isn't well formed
+    Parse(String),
+    /// Reading from or writing to the device failed, or a capture it's recording to or
+    /// being replayed from
+    Device(io::Error),
+    /// The call isn't allowed in the state the connection is in, such as sending after
+    /// closing, RFC 9293's "connection closing"
+    InvalidState(State),
+    /// There's no such connection, or it's closed, RFC 9293's "connection does not exist"
+    NotConnected,
+    /// The peer reset the connection, RFC 9293's "connection reset"
+    ConnectionReset,
+    /// Nothing can be done without waiting, such as writing while the device is full
+    WouldBlock,
+    /// The local address and port are taken, by a connection or a listener, RFC 9293's
+    /// "connection already exists"
+    AddrInUse(u16),
+    /// Out of connections or ephemeral ports, RFC 9293's "insufficient resources"
+    InsufficientResources(String),
+    /// An argument the call can't take, such as port 0
+    InvalidInput(&'static str),
+    /// A shard or queue thread stopped, with the error it failed with if it returned one
+    WorkerStopped {
+        index: usize,
+        error: Option<Box<TcpError>>,
+    },
+    /// A command or the test harness found something other than expected, such as a
+    /// replay not matching its expected output or a simulated link never going quiet
+    Failed(String),
+}
+
+pub type Result<T, E = TcpError> = core::result::Result<T, E>;
+
+impl TcpError {
+    /// The closest [`io::ErrorKind`], for callers which work in `io::Result`
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TcpError::Parse(_) => io::ErrorKind::InvalidData,
+            TcpError::Device(err) => err.kind(),
+            TcpError::InvalidState(_) => io::ErrorKind::BrokenPipe,
+            TcpError::NotConnected => io::ErrorKind::NotConnected,
+            TcpError::ConnectionReset => io::ErrorKind::ConnectionReset,
+            TcpError::WouldBlock => io::ErrorKind::WouldBlock,
+            TcpError::AddrInUse(_) => io::ErrorKind::AddrInUse,
+            TcpError::InsufficientResources(_) => io::ErrorKind::AddrNotAvailable,
+            TcpError::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            TcpError::WorkerStopped { .. } => io::ErrorKind::Other,
+            TcpError::Failed(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for TcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpError::Parse(message) => write!(f, "{message}"),
+            TcpError::Device(err) => write!(f, "{err}"),
+            TcpError::InvalidState(State::Closed) | TcpError::NotConnected => {
+                write!(f, "connection does not exist")
+            }
+            TcpError::InvalidState(_) => write!(f, "connection closing"),
+            TcpError::ConnectionReset => write!(f, "connection reset"),
+            TcpError::WouldBlock => write!(f, "operation would block"),
+            TcpError::AddrInUse(port) => write!(f, "port {port} already in use"),
+            TcpError::InsufficientResources(message) => write!(f, "{message}"),
+            TcpError::InvalidInput(message) => write!(f, "{message}"),
+            TcpError::WorkerStopped {
+                index,
+                error: Some(err),
+            } => write!(f, "worker {index} failed: {err}"),
+            TcpError::WorkerStopped { index, error: None } => write!(f, "worker {index} stopped"),
+            TcpError::Failed(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl error::Error for TcpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TcpError::Device(err) => Some(err),
+            TcpError::WorkerStopped {
+                error: Some(err), ..
+            } => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Device errors which only mean trying again later are [`TcpError::WouldBlock`]
+impl From<io::Error> for TcpError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock => TcpError::WouldBlock,
+            _ => TcpError::Device(err),
+        }
+    }
+}
+
+impl From<TcpError> for io::Error {
+    fn from(err: TcpError) -> Self {
+        match err {
+            TcpError::Device(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+/// A header field out of range while building a packet
+impl<T> From<ValueTooBigError<T>> for TcpError
+where
+    T: Clone + fmt::Display + fmt::Debug + Eq + Hash,
+{
+    fn from(err: ValueTooBigError<T>) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+impl From<TcpOptionWriteError> for TcpError {
+    fn from(err: TcpOptionWriteError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+/// A packet too short or malformed to take an IPv4 header from
+impl From<ipv4::HeaderSliceError> for TcpError {
+    fn from(err: ipv4::HeaderSliceError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+/// A packet too short or malformed to take a TCP header from
+impl From<tcp::HeaderSliceError> for TcpError {
+    fn from(err: tcp::HeaderSliceError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+/// A setting which should be a whole number
+impl From<ParseIntError> for TcpError {
+    fn from(err: ParseIntError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+/// A setting which should be a number
+impl From<ParseFloatError> for TcpError {
+    fn from(err: ParseFloatError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
+
+/// A setting which should be an IP address or socket address
+impl From<AddrParseError> for TcpError {
+    fn from(err: AddrParseError) -> Self {
+        TcpError::Parse(err.to_string())
+    }
+}
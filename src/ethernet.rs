@@ -0,0 +1,442 @@
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, IoSlice},
+    net::Ipv4Addr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{self, Clock},
+    device::{NetworkDevice, RecvBuffer},
+    pool::{PacketBuf, PacketPool},
+    span::log,
+    EtherType, ETH_MTU,
+};
+
+/// Ethernet II header: destination, source and EtherType
+pub const ETH_FRAME_HEADER_SIZE: usize = 14;
+
+/// ARP packet for IPv4 over Ethernet, RFC 826
+pub const ARP_PACKET_SIZE: usize = 28;
+
+/// Packets held for each address being resolved. Past this the oldest are dropped, and
+/// TCP sends them again once the address is known.
+pub const MAX_UNRESOLVED_PACKETS: usize = 8;
+
+/// How long to wait for an ARP reply before asking again
+pub const ARP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A 48 bit Ethernet hardware address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+    /// A locally administered address made from `addr`, `02:00` followed by its 4 bytes,
+    /// so a stack gets the same address every time it runs
+    pub fn for_ipv4(addr: Ipv4Addr) -> Self {
+        let [a, b, c, d] = addr.octets();
+        MacAddr([0x02, 0x00, a, b, c, d])
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArpOperation {
+    Request = 1,
+    Reply = 2,
+}
+
+/// An ARP packet mapping IPv4 addresses to Ethernet addresses, RFC 826
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddr,
+    pub sender_addr: Ipv4Addr,
+    /// Unknown, and all zeros, in a request
+    pub target_mac: MacAddr,
+    pub target_addr: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Hardware type for Ethernet
+    const HTYPE_ETHERNET: u16 = 1;
+
+    /// Parse an ARP packet, or `None` if it isn't a request or reply for IPv4 over Ethernet
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < ARP_PACKET_SIZE {
+            return None;
+        }
+
+        let htype = u16::from_be_bytes([packet[0], packet[1]]);
+        let ptype = u16::from_be_bytes([packet[2], packet[3]]);
+        if htype != Self::HTYPE_ETHERNET || ptype != EtherType::Ipv4 as u16 {
+            return None;
+        }
+        if packet[4] != 6 || packet[5] != 4 {
+            return None;
+        }
+
+        let operation: ArpOperation = match u16::from_be_bytes([packet[6], packet[7]]) {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => return None,
+        };
+
+        Some(ArpPacket {
+            operation,
+            sender_mac: MacAddr(packet[8..14].try_into().ok()?),
+            sender_addr: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[14..18]).ok()?),
+            target_mac: MacAddr(packet[18..24].try_into().ok()?),
+            target_addr: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[24..28]).ok()?),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_PACKET_SIZE] {
+        let mut packet: [u8; ARP_PACKET_SIZE] = [0; ARP_PACKET_SIZE];
+        packet[0..2].copy_from_slice(&Self::HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&(EtherType::Ipv4 as u16).to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&(self.operation as u16).to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender_addr.octets());
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target_addr.octets());
+        packet
+    }
+}
+
+/// Recently learned Ethernet addresses of IPv4 neighbours. Entries expire after a while so
+/// a neighbour whose address changes is asked again, and the oldest is replaced once the
+/// cache is full.
+pub struct ArpCache {
+    entries: HashMap<Ipv4Addr, (MacAddr, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl ArpCache {
+    pub const DEFAULT_CAPACITY: usize = 64;
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ArpCache {
+            entries: HashMap::new(),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    /// The address of `addr`, unless it's unknown or has expired
+    pub fn get(&self, addr: Ipv4Addr, now: Instant) -> Option<MacAddr> {
+        let (mac, learned) = self.entries.get(&addr)?;
+        (now.saturating_duration_since(*learned) < self.ttl).then_some(*mac)
+    }
+
+    /// Learn or refresh the address of `addr`, replacing the oldest entry if the cache is full
+    pub fn insert(&mut self, addr: Ipv4Addr, mac: MacAddr, now: Instant) {
+        if !self.entries.contains_key(&addr) && self.entries.len() >= self.capacity {
+            let oldest: Option<Ipv4Addr> = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, learned))| *learned)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(addr, (mac, now));
+    }
+
+    /// Refresh `addr` if it's already known. Returns whether it was.
+    pub fn update(&mut self, addr: Ipv4Addr, mac: MacAddr, now: Instant) -> bool {
+        match self.entries.get_mut(&addr) {
+            Some(entry) => {
+                *entry = (mac, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for ArpCache {
+    fn default() -> Self {
+        ArpCache::new(ArpCache::DEFAULT_CAPACITY, ArpCache::DEFAULT_TTL)
+    }
+}
+
+/// Wraps a device carrying Ethernet frames, such as a tap interface, so the stack sees IP
+/// packets as it would from a tun interface.
+///
+/// Frames for other hosts and anything other than IPv4 and ARP are dropped. ARP requests
+/// for `addr` are answered, and outgoing packets wait while their destination's address is
+/// asked for. Every destination is taken to be on the local link, there's no gateway.
+pub struct EthernetDevice<D: NetworkDevice> {
+    inner: D,
+    mac: MacAddr,
+    addr: Ipv4Addr,
+    clock: Arc<dyn Clock>,
+    state: Mutex<ArpState>,
+}
+
+struct ArpState {
+    cache: ArpCache,
+    /// Packets waiting for their destination's address, and when it was last asked for
+    unresolved: HashMap<Ipv4Addr, (Instant, VecDeque<PacketBuf>)>,
+    frame: RecvBuffer,
+}
+
+impl<D: NetworkDevice> EthernetDevice<D> {
+    /// Answer for `addr` at `mac` on `inner`
+    pub fn new(inner: D, mac: MacAddr, addr: Ipv4Addr) -> Self {
+        EthernetDevice::with_clock(inner, mac, addr, clock::system())
+    }
+
+    /// As [`EthernetDevice::new`], timing cache entries and retries with `clock`
+    pub fn with_clock(inner: D, mac: MacAddr, addr: Ipv4Addr, clock: Arc<dyn Clock>) -> Self {
+        let frame = RecvBuffer::new(inner.mtu().max(ETH_MTU) + ETH_FRAME_HEADER_SIZE);
+
+        EthernetDevice {
+            inner,
+            mac,
+            addr,
+            clock,
+            state: Mutex::new(ArpState {
+                cache: ArpCache::default(),
+                unresolved: HashMap::new(),
+                frame,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    /// The cached address of the neighbour `addr`, if it's known
+    pub fn neighbour(&self, addr: Ipv4Addr) -> Option<MacAddr> {
+        self.lock_state().cache.get(addr, self.clock.now())
+    }
+
+    /// RFC 826, merge the sender into the cache if it's already there, or add it if the
+    /// packet is for us, then answer requests for our address. Anything waiting for the
+    /// sender's address goes out now it's known.
+    fn on_arp(&self, state: &mut ArpState, arp: ArpPacket) -> io::Result<()> {
+        let now: Instant = self.clock.now();
+
+        // Probes, RFC 5227 Section 2.1.1, come from 0.0.0.0 which is nobody's address
+        let known: bool = !arp.sender_addr.is_unspecified()
+            && state.cache.update(arp.sender_addr, arp.sender_mac, now);
+
+        if arp.target_addr != self.addr {
+            return Ok(());
+        }
+
+        if !known && !arp.sender_addr.is_unspecified() {
+            state.cache.insert(arp.sender_addr, arp.sender_mac, now);
+        }
+
+        if arp.operation == ArpOperation::Request {
+            let reply = ArpPacket {
+                operation: ArpOperation::Reply,
+                sender_mac: self.mac,
+                sender_addr: self.addr,
+                target_mac: arp.sender_mac,
+                target_addr: arp.sender_addr,
+            };
+            self.send_frame(
+                arp.sender_mac,
+                EtherType::Arp,
+                &[IoSlice::new(&reply.to_bytes())],
+            )?;
+        }
+
+        if let Some((_, packets)) = state.unresolved.remove(&arp.sender_addr) {
+            for packet in packets {
+                self.send_frame(arp.sender_mac, EtherType::Ipv4, &[IoSlice::new(&packet)])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hold a packet until the address of `dst` is known, asking for it unless a request
+    /// went out within the last [`ARP_RETRY_INTERVAL`]
+    fn resolve(&self, state: &mut ArpState, dst: Ipv4Addr, packet: PacketBuf) -> io::Result<()> {
+        let now: Instant = self.clock.now();
+
+        let ask: bool = match state.unresolved.get(&dst) {
+            Some((requested, _)) => now.saturating_duration_since(*requested) >= ARP_RETRY_INTERVAL,
+            None => true,
+        };
+
+        let (requested, packets) = state
+            .unresolved
+            .entry(dst)
+            .or_insert_with(|| (now, VecDeque::new()));
+        if packets.len() >= MAX_UNRESOLVED_PACKETS {
+            packets.pop_front();
+        }
+        packets.push_back(packet);
+
+        if ask {
+            *requested = now;
+            let request = ArpPacket {
+                operation: ArpOperation::Request,
+                sender_mac: self.mac,
+                sender_addr: self.addr,
+                target_mac: MacAddr([0; 6]),
+                target_addr: dst,
+            };
+            self.send_frame(
+                MacAddr::BROADCAST,
+                EtherType::Arp,
+                &[IoSlice::new(&request.to_bytes())],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Send `payload` to `dst` behind an Ethernet header, returning the payload bytes written
+    fn send_frame(
+        &self,
+        dst: MacAddr,
+        ether_type: EtherType,
+        payload: &[IoSlice<'_>],
+    ) -> io::Result<usize> {
+        let mut header: [u8; ETH_FRAME_HEADER_SIZE] = [0; ETH_FRAME_HEADER_SIZE];
+        header[0..6].copy_from_slice(&dst.0);
+        header[6..12].copy_from_slice(&self.mac.0);
+        header[12..14].copy_from_slice(&(ether_type as u16).to_be_bytes());
+
+        let mut bufs: Vec<IoSlice<'_>> = Vec::with_capacity(payload.len() + 1);
+        bufs.push(IoSlice::new(&header));
+        bufs.extend_from_slice(payload);
+
+        let n_written: usize = self.inner.send_vectored(&bufs)?;
+        Ok(n_written.saturating_sub(ETH_FRAME_HEADER_SIZE))
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ArpState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<D: NetworkDevice> NetworkDevice for EthernetDevice<D> {
+    /// Receive the next IPv4 packet addressed to us, handling any ARP on the way
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock_state();
+
+        loop {
+            let Some(frame) = state.frame.recv(&self.inner)? else {
+                continue;
+            };
+
+            if frame.len() < ETH_FRAME_HEADER_SIZE {
+                log!("Skipping frame. Shorter than an Ethernet header");
+                continue;
+            }
+
+            let dst = MacAddr(frame[0..6].try_into().unwrap());
+            if dst != self.mac && dst != MacAddr::BROADCAST {
+                continue;
+            }
+
+            let payload: &[u8] = &frame[ETH_FRAME_HEADER_SIZE..];
+            match u16::from_be_bytes([frame[12], frame[13]]) {
+                ether_type if ether_type == EtherType::Ipv4 as u16 => {
+                    let n_bytes: usize = payload.len().min(buf.len());
+                    buf[..n_bytes].copy_from_slice(&payload[..n_bytes]);
+                    return Ok(n_bytes);
+                }
+                ether_type if ether_type == EtherType::Arp as u16 => {
+                    if let Some(arp) = ArpPacket::parse(payload) {
+                        self.on_arp(&mut state, arp)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// Reports packets waiting for an address to be resolved as sent
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        // The destination is at bytes 16 to 20 of the IPv4 header
+        let header: &[u8] = bufs.first().map_or(&[], |buf| &buf[..]);
+        let Some(dst) = header.get(16..20) else {
+            let packet: PacketBuf = PacketPool::shared().gather(bufs);
+            if packet.len() < 20 {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+            return self.send(&packet);
+        };
+        let dst = Ipv4Addr::from(<[u8; 4]>::try_from(dst).unwrap());
+
+        if dst.is_broadcast() {
+            return self.send_frame(MacAddr::BROADCAST, EtherType::Ipv4, bufs);
+        }
+
+        let mut state = self.lock_state();
+        match state.cache.get(dst, self.clock.now()) {
+            Some(mac) => self.send_frame(mac, EtherType::Ipv4, bufs),
+            None => {
+                let packet: PacketBuf = PacketPool::shared().gather(bufs);
+                let len: usize = packet.len();
+                self.resolve(&mut state, dst, packet)?;
+                Ok(len)
+            }
+        }
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn queued_packets(&self) -> Option<usize> {
+        self.inner.queued_packets()
+    }
+}
+
+#[cfg(unix)]
+impl<D: NetworkDevice + AsRawFd> AsRawFd for EthernetDevice<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4Header, TcpHeader, TcpHeaderSlice, TcpOptionElement};
+
+use crate::{
+    clock::{Clock, SimulatedClock},
+    device::CaptureDevice,
+    error::Result,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::ConnectInfo,
+};
+
+/// Peer the segments from [`run_segments`] come from
+const PEER: ([u8; 4], u16) = ([192, 168, 0, 1], 40000);
+/// Local endpoint the segments from [`run_segments`] are sent to
+const LOCAL: ([u8; 4], u16) = ([192, 168, 0, 2], 443);
+
+/// Bytes of [`run_segments`] input describing one segment, before its payload
+const SEGMENT_HEADER_LEN: usize = 14;
+
+/// A stack listening on every port, with a fixed ISN secret so runs can be reproduced
+pub fn stack() -> Stack {
+    Stack::new(
+        Listeners::accept_any(ListenerLimits::default()),
+        IsnGenerator::new([0; 16]),
+        Instant::now(),
+    )
+}
+
+/// Feed `packet` through the stack as if it had been read from the tun device, returning
+/// whatever the stack sent in response
+pub fn process_packet(stack: &mut Stack, packet: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let device = CaptureDevice::default();
+    stack.on_packet(&device, packet, Instant::now())?;
+
+    Ok(device.take_sent())
+}
+
+/// Drive one connection through an arbitrary sequence of segments from the peer, on a
+/// [`SimulatedClock`] so timers fire without waiting.
+///
+/// Arbitrary bytes rarely get past the checksum, so `input` only describes the segments,
+/// which are built with valid headers and checksums. The peer opens with a SYN, then each
+/// segment takes [`SEGMENT_HEADER_LEN`] bytes followed by its payload:
+/// - flags, the low six bits being FIN, SYN, RST, PSH, ACK and URG
+/// - actions, bit 0 runs any timers due first, bit 1 reads everything received,
+///   bit 2 sends the payload from our end too and bit 3 closes our end
+/// - sequence number, a big endian `i32` relative to the peer's next sequence number
+/// - acknowledgment number, a big endian `i32` relative to our ISS
+/// - window, big endian
+/// - options, bit 0 adds an MSS, bit 1 window scale, bit 2 SACK permitted and bit 3
+///   a timestamp
+/// - payload length
+///
+/// Returns the stack once every segment has been processed.
+pub fn run_segments(input: &[u8]) -> Result<Stack> {
+    let device = CaptureDevice::default();
+    let clock = Arc::new(SimulatedClock::new());
+    let mut stack: Stack = stack();
+    stack.set_clock(clock.clone());
+    let info = ConnectInfo {
+        src_addr: PEER.0.into(),
+        src_port: PEER.1,
+        dst_addr: LOCAL.0.into(),
+        dst_port: LOCAL.1,
+    };
+
+    let mut syn = TcpHeader::new(PEER.1, LOCAL.1, 0, u16::MAX);
+    syn.syn = true;
+    stack.on_packet(&device, &build(syn, &[])?, clock.now())?;
+
+    let Some(iss) = device
+        .take_sent()
+        .first()
+        .and_then(|packet| TcpHeaderSlice::from_slice(packet.get(20..)?).ok())
+        .map(|syn_ack| syn_ack.sequence_number())
+    else {
+        return Ok(stack);
+    };
+
+    let mut peer_nxt: u32 = 1;
+    let mut ts_val: u32 = 1;
+    let mut rest: &[u8] = input;
+
+    while let Some((fields, after)) = rest.split_first_chunk::<SEGMENT_HEADER_LEN>() {
+        let payload_len: usize = (fields[13] as usize).min(after.len());
+        let (payload, after) = after.split_at(payload_len);
+        rest = after;
+
+        let [flags, actions, s0, s1, s2, s3, a0, a1, a2, a3, w0, w1, options, _] = *fields;
+        let seq: u32 = peer_nxt.wrapping_add(i32::from_be_bytes([s0, s1, s2, s3]) as u32);
+        let ack: u32 = iss.wrapping_add(i32::from_be_bytes([a0, a1, a2, a3]) as u32);
+
+        if actions & 1 != 0 {
+            if let Some(deadline) = stack.next_deadline() {
+                clock.advance_to(deadline);
+            }
+            clock.advance(Duration::from_millis(1));
+            stack.on_tick(&device, clock.now())?;
+        }
+
+        let mut header = TcpHeader::new(PEER.1, LOCAL.1, seq, u16::from_be_bytes([w0, w1]));
+        header.acknowledgment_number = ack;
+        header.fin = flags & 0x01 != 0;
+        header.syn = flags & 0x02 != 0;
+        header.rst = flags & 0x04 != 0;
+        header.psh = flags & 0x08 != 0;
+        header.ack = flags & 0x10 != 0;
+        header.urg = flags & 0x20 != 0;
+
+        let mut elements: Vec<TcpOptionElement> = Vec::new();
+        if options & 0x01 != 0 {
+            elements.push(TcpOptionElement::MaximumSegmentSize(u16::from_be_bytes([
+                w0, w1,
+            ])));
+        }
+        if options & 0x02 != 0 {
+            elements.push(TcpOptionElement::WindowScale(s3));
+        }
+        if options & 0x04 != 0 {
+            elements.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+        }
+        if options & 0x08 != 0 {
+            elements.push(TcpOptionElement::Timestamp(ts_val, 0));
+            ts_val = ts_val.wrapping_add(1);
+        }
+        header.set_options(&elements)?;
+
+        let rst: bool = header.rst;
+        let n_flags: u32 = header.syn as u32 + header.fin as u32;
+        stack.on_packet(&device, &build(header, payload)?, clock.now())?;
+        if !rst {
+            peer_nxt = seq.wrapping_add(payload.len() as u32 + n_flags);
+        }
+
+        if actions & 0b1110 != 0 {
+            if let Some(tcb) = stack.connection_mut(&info) {
+                if actions & 2 != 0 {
+                    let mut received: Vec<u8> = vec![0; tcb.unread_len()];
+                    tcb.read(&mut received);
+                }
+                if actions & 4 != 0 {
+                    // Sending fails once the connection is closing, like it would for
+                    // an application
+                    let _ = tcb.send(&device, payload);
+                }
+            }
+            if actions & 8 != 0 && stack.connection(&info).is_some() {
+                let _ = stack.close(&device, &info);
+            }
+        }
+
+        device.take_sent();
+    }
+
+    Ok(stack)
+}
+
+/// An IPv4 packet from the peer to the local endpoint carrying `header` and `payload`
+fn build(mut header: TcpHeader, payload: &[u8]) -> Result<Vec<u8>> {
+    let ip_header = Ipv4Header::new(
+        (header.header_len() + payload.len()) as u16,
+        64,
+        IpNumber::TCP,
+        PEER.0,
+        LOCAL.0,
+    )?;
+    header.checksum = header.calc_checksum_ipv4(&ip_header, payload)?;
+
+    let mut packet: Vec<u8> = Vec::with_capacity(ip_header.total_len as usize);
+    ip_header.write(&mut packet)?;
+    header.write(&mut packet)?;
+    packet.extend_from_slice(payload);
+
+    Ok(packet)
+}
@@ -0,0 +1,136 @@
+use core::fmt;
+
+/// Share of a limit at which the stack reports itself [`HealthStatus::Degraded`]
+pub const DEGRADED_PERCENT: usize = 80;
+
+/// What the stack is expected to hold at most, which its [`Health`] is judged against.
+/// Nothing is refused on reaching a limit, they're only reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthLimits {
+    /// Bytes held in send and receive buffers across every connection
+    pub max_buffered_bytes: usize,
+    pub max_connections: usize,
+    /// Timers running across every connection and fragment reassembly
+    pub max_timers: usize,
+    /// Packets waiting to be read from the device
+    pub max_device_queue: usize,
+}
+
+impl HealthLimits {
+    pub const DEFAULT: HealthLimits = HealthLimits {
+        max_buffered_bytes: 64 * 1024 * 1024,
+        max_connections: 4096,
+        max_timers: 3 * 4096,
+        max_device_queue: 500,
+    };
+}
+
+impl Default for HealthLimits {
+    fn default() -> Self {
+        HealthLimits::DEFAULT
+    }
+}
+
+/// Resources the stack is holding right now
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HealthUsage {
+    pub buffered_bytes: usize,
+    pub connections: usize,
+    pub timers: usize,
+    /// `None` if the device can't tell
+    pub device_queue: Option<usize>,
+}
+
+impl HealthUsage {
+    /// Add the usage of another part of the same stack, such as another shard
+    pub fn merge(&mut self, other: HealthUsage) {
+        self.buffered_bytes += other.buffered_bytes;
+        self.connections += other.connections;
+        self.timers += other.timers;
+        self.device_queue = match (self.device_queue, other.device_queue) {
+            (Some(ours), Some(theirs)) => Some(ours + theirs),
+            (ours, theirs) => ours.or(theirs),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    /// Everything is well within its limit
+    Ok,
+    /// Something is past [`DEGRADED_PERCENT`] of its limit
+    Degraded,
+    /// Something has reached its limit
+    Overloaded,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = match self {
+            HealthStatus::Ok => "ok",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Overloaded => "overloaded",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The stack's resource usage against its limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Health {
+    pub usage: HealthUsage,
+    pub limits: HealthLimits,
+}
+
+impl Health {
+    pub fn new(usage: HealthUsage, limits: HealthLimits) -> Self {
+        Health { usage, limits }
+    }
+
+    /// The worst status of any one resource
+    pub fn status(&self) -> HealthStatus {
+        [
+            status_of(self.usage.buffered_bytes, self.limits.max_buffered_bytes),
+            status_of(self.usage.connections, self.limits.max_connections),
+            status_of(self.usage.timers, self.limits.max_timers),
+            status_of(
+                self.usage.device_queue.unwrap_or(0),
+                self.limits.max_device_queue,
+            ),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(HealthStatus::Ok)
+    }
+}
+
+fn status_of(used: usize, limit: usize) -> HealthStatus {
+    if used >= limit {
+        HealthStatus::Overloaded
+    } else if used * 100 >= limit * DEGRADED_PERCENT {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "health {} buffered_bytes={}/{} connections={}/{} timers={}/{} device_queue=",
+            self.status(),
+            self.usage.buffered_bytes,
+            self.limits.max_buffered_bytes,
+            self.usage.connections,
+            self.limits.max_connections,
+            self.usage.timers,
+            self.limits.max_timers,
+        )?;
+
+        match self.usage.device_queue {
+            Some(queued) => write!(f, "{queued}/{}", self.limits.max_device_queue),
+            None => write!(f, "unknown/{}", self.limits.max_device_queue),
+        }
+    }
+}
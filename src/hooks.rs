@@ -0,0 +1,37 @@
+use etherparse::TcpHeader;
+
+use crate::tcp::State;
+
+/// What to do with a segment after a [`SegmentHook`] has inspected it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Carry on processing the segment as normal
+    Accept,
+    /// Discard the segment as if it had been lost on the wire
+    Drop,
+}
+
+/// A segment as seen by a [`SegmentHook`]
+pub struct SegmentInfo<'a> {
+    /// State of the connection when the segment was received or sent
+    pub state: State,
+    pub header: &'a TcpHeader,
+    pub payload: &'a [u8],
+}
+
+/// Per-connection hook to inspect, and optionally drop, segments at the TCP layer.
+///
+/// Segments dropped on receive are never processed. Segments dropped on transmit are
+/// never handed to the device but still consume sequence space, so to the connection
+/// they look like they were lost.
+pub trait SegmentHook: Send {
+    /// Called for every segment received on the connection, before any processing
+    fn on_receive(&mut self, _segment: &SegmentInfo) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Called for every segment the connection is about to send
+    fn on_transmit(&mut self, _segment: &SegmentInfo) -> Verdict {
+        Verdict::Accept
+    }
+}
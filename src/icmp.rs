@@ -0,0 +1,174 @@
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+use etherparse::{
+    icmpv4::DestUnreachableHeader, Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4Header,
+    Ipv4HeaderSlice,
+};
+
+use crate::{device::NetworkDevice, error::Result, span::log, tcp::ConnectInfo};
+
+/// Bytes of the original datagram's payload quoted after its IP header.
+/// RFC 792, enough for the TCP ports and sequence number.
+const QUOTED_PAYLOAD_LEN: usize = 8;
+
+/// RFC 792, the type of a Source Quench message, which etherparse doesn't decode
+const SOURCE_QUENCH: u8 = 4;
+
+/// An ICMP error message about a segment we sent.
+/// RFC 1122 Section 4.2.3.9
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpError {
+    /// Code 2, the peer doesn't run TCP. A hard error.
+    ProtocolUnreachable,
+    /// Code 3, nothing is bound to the peer's port. A hard error.
+    PortUnreachable,
+    /// Code 4, a router needed to fragment a segment with DF set.
+    /// `next_hop_mtu` is from RFC 1191 and is zero from routers which predate it.
+    FragmentationNeeded { next_hop_mtu: u16 },
+    /// Any other code, a soft error which may go away by itself
+    Unreachable { code: u8 },
+    /// A router asked us to slow down. A soft error, and otherwise ignored as RFC 6633
+    /// deprecates it.
+    SourceQuench,
+    /// The segment's TTL ran out or its fragments weren't reassembled in time.
+    /// A soft error.
+    TimeExceeded { code: u8 },
+    /// A router or the peer couldn't parse the segment's headers. A soft error.
+    ParameterProblem,
+}
+
+impl IcmpError {
+    /// Whether RFC 1122 treats the error as a sign the connection can't succeed
+    pub fn is_hard(&self) -> bool {
+        matches!(
+            self,
+            IcmpError::ProtocolUnreachable | IcmpError::PortUnreachable
+        )
+    }
+}
+
+/// An ICMP error matched back to the connection whose segment caused it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcmpErrorMessage {
+    /// The affected connection, keyed as its incoming segments are
+    pub connection: ConnectInfo,
+    /// Sequence number of the quoted segment
+    pub seq: u32,
+    pub error: IcmpError,
+}
+
+/// Decode an ICMP message carried in `payload` of `ip_header`.
+/// Returns `None` for anything other than a Destination Unreachable, Source Quench,
+/// Time Exceeded or Parameter Problem quoting a TCP segment, or if the checksum doesn't match.
+pub fn parse_error(ip_header: &Ipv4HeaderSlice, payload: &[u8]) -> Option<IcmpErrorMessage> {
+    if ip_header.protocol() != IpNumber::ICMP {
+        return None;
+    }
+
+    let icmp = Icmpv4Slice::from_slice(payload).ok()?;
+    let checksum: u16 = Icmpv4Header::with_checksum(icmp.icmp_type(), icmp.payload()).checksum;
+    if checksum != icmp.checksum() {
+        return None;
+    }
+
+    let error: IcmpError = match icmp.icmp_type() {
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Protocol) => {
+            IcmpError::ProtocolUnreachable
+        }
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port) => {
+            IcmpError::PortUnreachable
+        }
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded {
+            next_hop_mtu,
+        }) => IcmpError::FragmentationNeeded { next_hop_mtu },
+        Icmpv4Type::DestinationUnreachable(_) => IcmpError::Unreachable {
+            code: icmp.code_u8(),
+        },
+        Icmpv4Type::Unknown {
+            type_u8: SOURCE_QUENCH,
+            ..
+        } => IcmpError::SourceQuench,
+        Icmpv4Type::TimeExceeded(_) => IcmpError::TimeExceeded {
+            code: icmp.code_u8(),
+        },
+        Icmpv4Type::ParameterProblem(_) => IcmpError::ParameterProblem,
+        _ => return None,
+    };
+
+    // The quoted segment is one we sent, so its source is our end of the connection
+    let quoted_ip = Ipv4HeaderSlice::from_slice(icmp.payload()).ok()?;
+    if quoted_ip.protocol() != IpNumber::TCP {
+        return None;
+    }
+
+    let quoted_tcp: &[u8] = &icmp.payload()[quoted_ip.slice().len()..];
+    if quoted_tcp.len() < QUOTED_PAYLOAD_LEN {
+        return None;
+    }
+
+    let connection = ConnectInfo {
+        src_addr: Ipv4Addr::from(quoted_ip.destination()),
+        src_port: u16::from_be_bytes([quoted_tcp[2], quoted_tcp[3]]),
+        dst_addr: Ipv4Addr::from(quoted_ip.source()),
+        dst_port: u16::from_be_bytes([quoted_tcp[0], quoted_tcp[1]]),
+    };
+    let seq = u32::from_be_bytes([quoted_tcp[4], quoted_tcp[5], quoted_tcp[6], quoted_tcp[7]]);
+
+    Some(IcmpErrorMessage {
+        connection,
+        seq,
+        error,
+    })
+}
+
+/// Tell the sender of `ip_header` that nothing is bound to the port its datagram was for,
+/// quoting the IP header and first 8 bytes of `payload`.
+/// Nothing is sent where RFC 1122 Section 3.2.2 forbids an ICMP error: for any fragment
+/// but the first, or for datagrams to or from broadcast and multicast addresses.
+/// Returns whether a message was sent.
+pub fn send_port_unreachable(
+    nic: &impl NetworkDevice,
+    ip_header: &Ipv4HeaderSlice,
+    payload: &[u8],
+) -> Result<bool> {
+    let src = Ipv4Addr::from(ip_header.source());
+    let dst = Ipv4Addr::from(ip_header.destination());
+
+    if ip_header.fragments_offset().value() != 0
+        || src.is_unspecified()
+        || src.is_broadcast()
+        || src.is_multicast()
+        || dst.is_broadcast()
+        || dst.is_multicast()
+    {
+        return Ok(false);
+    }
+
+    let mut quoted: Vec<u8> = ip_header.slice().to_vec();
+    quoted.extend_from_slice(&payload[..payload.len().min(QUOTED_PAYLOAD_LEN)]);
+
+    let icmp_header = Icmpv4Header::with_checksum(
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port),
+        &quoted,
+    );
+
+    let mut reply_ip_header = Ipv4Header::new(
+        (icmp_header.header_len() + quoted.len()) as u16,
+        64,
+        IpNumber::ICMP,
+        ip_header.destination(),
+        ip_header.source(),
+    )?;
+
+    reply_ip_header.header_checksum = reply_ip_header.calc_header_checksum();
+    let mut packet: Vec<u8> = Vec::with_capacity(reply_ip_header.total_len as usize);
+    packet.extend_from_slice(&reply_ip_header.to_bytes());
+    packet.extend_from_slice(&icmp_header.to_bytes());
+    packet.extend_from_slice(&quoted);
+
+    log!("Port unreachable {src} -> {dst}");
+    nic.send(&packet)?;
+
+    Ok(true)
+}
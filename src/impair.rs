@@ -0,0 +1,238 @@
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{self, Clock},
+    device::NetworkDevice,
+    pool::{PacketBuf, PacketPool},
+};
+
+/// How an [`ImpairedDevice`] mistreats the packets sent through it, much like `tc netem`.
+/// Probabilities are from 0 to 1 and apply to each packet independently.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Impairment {
+    pub loss: f64,
+    /// Chance a packet is sent twice
+    pub duplicate: f64,
+    /// Chance a packet is held back and sent after the one following it
+    pub reorder: f64,
+    /// Added to every packet
+    pub delay: Duration,
+    /// Up to this much more delay, chosen uniformly for each packet
+    pub jitter: Duration,
+}
+
+/// What's been done to the packets sent through an [`ImpairedDevice`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImpairmentStats {
+    /// Packets handed to the device, before any impairment
+    pub packets: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// Wraps a device, dropping, delaying, duplicating and reordering the packets sent through
+/// it according to an [`Impairment`]. Received packets pass through untouched, so both
+/// ends of a [`LoopbackDevice`](crate::device::LoopbackDevice) pair are wrapped to impair
+/// both directions.
+///
+/// Choices are drawn from a generator seeded on creation, so a seed replays the same
+/// impairments for the same packets. Packets waiting to go out are copied into buffers
+/// from the shared [`PacketPool`]. Delayed packets go out on the first call to `send`,
+/// `recv` or [`ImpairedDevice::release_due`] once they're due, and a packet held back for
+/// reordering waits for the next packet or [`ImpairedDevice::flush`].
+pub struct ImpairedDevice<D: NetworkDevice> {
+    inner: D,
+    impairment: Impairment,
+    clock: Arc<dyn Clock>,
+    state: Mutex<ImpairmentState>,
+}
+
+struct ImpairmentState {
+    rng: SplitMix64,
+    /// Packets waiting to go out, by when they're due and then the order they were sent in
+    delayed: BTreeMap<(Instant, u64), PacketBuf>,
+    n_scheduled: u64,
+    held_back: Option<PacketBuf>,
+    stats: ImpairmentStats,
+}
+
+impl<D: NetworkDevice> ImpairedDevice<D> {
+    pub fn new(inner: D, impairment: Impairment, seed: u64) -> Self {
+        ImpairedDevice {
+            inner,
+            impairment,
+            clock: clock::system(),
+            state: Mutex::new(ImpairmentState {
+                rng: SplitMix64(seed),
+                delayed: BTreeMap::new(),
+                n_scheduled: 0,
+                held_back: None,
+                stats: ImpairmentStats::default(),
+            }),
+        }
+    }
+
+    /// Time delays with `clock`, such as the [`SimulatedClock`](crate::clock::SimulatedClock)
+    /// driving the stack on this device
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn impairment(&self) -> Impairment {
+        self.impairment
+    }
+
+    pub fn stats(&self) -> ImpairmentStats {
+        self.lock_state().stats
+    }
+
+    /// When the next delayed packet is due, if there is one
+    pub fn next_release(&self) -> Option<Instant> {
+        self.lock_state()
+            .delayed
+            .first_key_value()
+            .map(|((due, _), _)| *due)
+    }
+
+    /// Send every delayed packet which is due, returning how many there were
+    pub fn release_due(&self) -> io::Result<usize> {
+        let mut state = self.lock_state();
+        self.release(&mut state)
+    }
+
+    /// Send the packet held back for reordering and every delayed packet, whether or not
+    /// they're due
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.lock_state();
+
+        if let Some(packet) = state.held_back.take() {
+            self.schedule(&mut state, packet);
+        }
+        while let Some((_, packet)) = state.delayed.pop_first() {
+            self.inner.send(&packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue a packet to go out after its delay
+    fn schedule(&self, state: &mut ImpairmentState, packet: PacketBuf) {
+        let jitter: Duration = self.impairment.jitter.mul_f64(state.rng.next_f64());
+        let due: Instant = self.clock.now() + self.impairment.delay + jitter;
+
+        state.delayed.insert((due, state.n_scheduled), packet);
+        state.n_scheduled += 1;
+    }
+
+    fn release(&self, state: &mut ImpairmentState) -> io::Result<usize> {
+        let now: Instant = self.clock.now();
+        let mut n_released: usize = 0;
+
+        while let Some(entry) = state.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            self.inner.send(&entry.remove())?;
+            n_released += 1;
+        }
+
+        Ok(n_released)
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ImpairmentState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<D: NetworkDevice> NetworkDevice for ImpairedDevice<D> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.release_due()?;
+        self.inner.recv(buf)
+    }
+
+    /// Reports every packet as sent, including those lost or still waiting to go out
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock_state();
+        state.stats.packets += 1;
+
+        if state.rng.chance(self.impairment.loss) {
+            state.stats.lost += 1;
+            self.release(&mut state)?;
+            return Ok(buf.len());
+        }
+
+        let mut n_copies: usize = 1;
+        if state.rng.chance(self.impairment.duplicate) {
+            state.stats.duplicated += 1;
+            n_copies = 2;
+        }
+
+        for _ in 0..n_copies {
+            if state.held_back.is_none() && state.rng.chance(self.impairment.reorder) {
+                state.stats.reordered += 1;
+                state.held_back = Some(PacketPool::shared().copy(buf));
+                continue;
+            }
+
+            self.schedule(&mut state, PacketPool::shared().copy(buf));
+            if let Some(packet) = state.held_back.take() {
+                self.schedule(&mut state, packet);
+            }
+        }
+
+        self.release(&mut state)?;
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn queued_packets(&self) -> Option<usize> {
+        self.inner.queued_packets()
+    }
+}
+
+#[cfg(unix)]
+impl<D: NetworkDevice + AsRawFd> AsRawFd for ImpairedDevice<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Small, fast and seedable, which is all impairments need from their randomness.
+/// <https://prng.di.unimi.it/splitmix64.c>
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z: u64 = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
@@ -0,0 +1,125 @@
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, IoSlice, Result};
+
+#[cfg(not(feature = "std"))]
+pub use self::bare::{Error, ErrorKind, IoSlice, Result};
+
+/// The parts of `std::io` devices and connections are written against, for builds without
+/// std. With it they're std's own, so any [`NetworkDevice`](crate::device::NetworkDevice)
+/// returning `std::io::Result` fits, and without it these are enough for a driver to say
+/// why a packet couldn't be sent or that it has nothing to receive.
+#[cfg(not(feature = "std"))]
+mod bare {
+    use alloc::boxed::Box;
+    use core::{error, fmt, ops::Deref};
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// The kinds of `std::io::ErrorKind` the stack produces or looks for
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        NotConnected,
+        ConnectionReset,
+        AddrInUse,
+        AddrNotAvailable,
+        BrokenPipe,
+        WouldBlock,
+        InvalidInput,
+        InvalidData,
+        TimedOut,
+        Unsupported,
+        OutOfMemory,
+        Other,
+    }
+
+    impl fmt::Display for ErrorKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let description: &str = match self {
+                ErrorKind::NotConnected => "not connected",
+                ErrorKind::ConnectionReset => "connection reset",
+                ErrorKind::AddrInUse => "address in use",
+                ErrorKind::AddrNotAvailable => "address not available",
+                ErrorKind::BrokenPipe => "broken pipe",
+                ErrorKind::WouldBlock => "operation would block",
+                ErrorKind::InvalidInput => "invalid input parameter",
+                ErrorKind::InvalidData => "invalid data",
+                ErrorKind::TimedOut => "timed out",
+                ErrorKind::Unsupported => "unsupported",
+                ErrorKind::OutOfMemory => "out of memory",
+                ErrorKind::Other => "other error",
+            };
+            f.write_str(description)
+        }
+    }
+
+    /// A device or connection error, a kind and optionally what caused it
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        error: Option<Box<dyn error::Error + Send + Sync>>,
+    }
+
+    impl Error {
+        pub fn new<E>(kind: ErrorKind, error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+        {
+            Error {
+                kind,
+                error: Some(error.into()),
+            }
+        }
+
+        pub fn other<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+        {
+            Error::new(ErrorKind::Other, error)
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Error { kind, error: None }
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.error {
+                Some(error) => write!(f, "{error}"),
+                None => write!(f, "{}", self.kind),
+            }
+        }
+    }
+
+    impl error::Error for Error {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            self.error.as_deref().map(|error| error as _)
+        }
+    }
+
+    /// One piece of a packet sent with
+    /// [`NetworkDevice::send_vectored`](crate::device::NetworkDevice::send_vectored)
+    #[derive(Clone, Copy, Debug)]
+    pub struct IoSlice<'a>(&'a [u8]);
+
+    impl<'a> IoSlice<'a> {
+        pub fn new(buf: &'a [u8]) -> Self {
+            IoSlice(buf)
+        }
+    }
+
+    impl Deref for IoSlice<'_> {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            self.0
+        }
+    }
+}
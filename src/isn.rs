@@ -0,0 +1,161 @@
+use core::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(all(unix, feature = "std"))]
+use std::{fs::File, io::Read};
+
+#[cfg(feature = "std")]
+use crate::error::Result;
+use crate::{
+    clock::{self, Instant},
+    options::FAST_OPEN_COOKIE_LEN,
+};
+
+/// Length of the ISN clock's tick in nanoseconds.
+/// RFC 6528 Section 3, M is a timer incremented every 4 microseconds.
+const TICK_NANOS: u128 = 4_000;
+
+/// Chooses initial send sequence numbers.
+/// RFC 6528 Section 3
+/// ```text
+/// ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+/// ```
+/// F is SipHash-2-4 keyed with the secret, so every 4-tuple gets its own unpredictable
+/// offset, while M keeps the ISNs for any one 4-tuple moving forward with time.
+pub struct IsnGenerator {
+    key: [u64; 2],
+    /// Start of the clock M
+    epoch: Instant,
+}
+
+impl IsnGenerator {
+    pub fn new(secret: [u8; 16]) -> Self {
+        let (k0, k1) = secret.split_at(8);
+
+        IsnGenerator {
+            key: [
+                u64::from_le_bytes(k0.try_into().unwrap()),
+                u64::from_le_bytes(k1.try_into().unwrap()),
+            ],
+            epoch: clock::start(),
+        }
+    }
+
+    /// A generator with a secret read from the operating system's random number generator
+    #[cfg(all(unix, feature = "std"))]
+    pub fn from_os_random() -> Result<Self> {
+        let mut secret: [u8; 16] = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut secret)?;
+
+        Ok(IsnGenerator::new(secret))
+    }
+
+    /// A generator with a secret read from the operating system's random number generator
+    #[cfg(all(windows, feature = "std"))]
+    pub fn from_os_random() -> Result<Self> {
+        /// Use the system's preferred generator rather than one opened by the caller
+        const BCRYPT_USE_SYSTEM_PREFERRED_RNG: u32 = 0x2;
+
+        #[link(name = "bcrypt")]
+        extern "system" {
+            fn BCryptGenRandom(
+                algorithm: *mut std::ffi::c_void,
+                buf: *mut u8,
+                len: u32,
+                flags: u32,
+            ) -> i32;
+        }
+
+        let mut secret: [u8; 16] = [0; 16];
+        // SAFETY: `secret` is valid for writes of its length
+        let status: i32 = unsafe {
+            BCryptGenRandom(
+                std::ptr::null_mut(),
+                secret.as_mut_ptr(),
+                secret.len() as u32,
+                BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+            )
+        };
+        if status < 0 {
+            return Err(std::io::Error::other(format!(
+                "BCryptGenRandom failed with NTSTATUS {status:#x}"
+            ))
+            .into());
+        }
+
+        Ok(IsnGenerator::new(secret))
+    }
+
+    /// The ISN for a connection between `local` and `remote` opened at `now`
+    pub fn generate(&self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+        let mut quad: [u8; 12] = [0; 12];
+        quad[0..4].copy_from_slice(&local.ip().octets());
+        quad[4..6].copy_from_slice(&local.port().to_be_bytes());
+        quad[6..10].copy_from_slice(&remote.ip().octets());
+        quad[10..12].copy_from_slice(&remote.port().to_be_bytes());
+
+        let m: u32 = (now.saturating_duration_since(self.epoch).as_nanos() / TICK_NANOS) as u32;
+        let f: u32 = siphash24(self.key, &quad) as u32;
+
+        m.wrapping_add(f)
+    }
+
+    /// The TCP Fast Open cookie for `client`, keyed with the same secret as the ISNs.
+    /// RFC 7413 Section 4.1.2, the cookie is a MAC of the client's address, so it can be
+    /// checked without keeping any state and is no use to anyone else. The address is
+    /// shorter than an ISN's 4-tuple, and SipHash covers the length, so the two never meet.
+    pub fn fast_open_cookie(&self, client: Ipv4Addr) -> [u8; FAST_OPEN_COOKIE_LEN] {
+        siphash24(self.key, &client.octets()).to_be_bytes()
+    }
+}
+
+/// SipHash-2-4 of `msg`.
+/// <https://www.aumasson.jp/siphash/siphash.pdf>
+fn siphash24([k0, k1]: [u64; 2], msg: &[u8]) -> u64 {
+    let mut v: [u64; 4] = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let chunks = msg.chunks_exact(8);
+    let remainder: &[u8] = chunks.remainder();
+
+    for chunk in chunks {
+        let m: u64 = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    // The final block holds the leftover bytes and the message length in its top byte
+    let mut last: u64 = (msg.len() as u64) << 56;
+    for (i, &byte) in remainder.iter().enumerate() {
+        last |= (byte as u64) << (8 * i);
+    }
+
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    net::Ipv4Addr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+
+use crate::{
+    clock,
+    device::CaptureDevice,
+    error::{Result, TcpError},
+    isn::IsnGenerator,
+    tcp::Tcb,
+};
+
+/// Local address and port the synthetic connections are opened to
+const AUDIT_LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 2), 443);
+
+/// Number of quads which are opened a second time to check their ISNs advance with the clock
+const REOPENED_QUADS: usize = 16;
+
+/// Pause before reopening quads, long enough for a 4 microsecond ISN clock to tick many times
+const REOPEN_DELAY: Duration = Duration::from_millis(5);
+
+/// Results of opening a batch of synthetic connections and inspecting the initial
+/// sequence numbers chosen for them.
+/// RFC 6528 expects ISN = M + F(localip, localport, remoteip, remoteport, secretkey),
+/// where M is a 4 microsecond timer, so ISNs should look random across quads while
+/// still advancing with time for any one quad.
+#[derive(Debug)]
+pub struct IsnAuditReport {
+    /// Number of distinct quads opened
+    pub connections: usize,
+    /// Number of distinct ISNs across those quads
+    pub distinct_isns: usize,
+    /// Adjacent pairs (in the order the quads were opened) where the ISN increased
+    pub ascending_pairs: usize,
+    /// Fraction of ISNs with each bit set, least significant bit first
+    pub bit_frequencies: Vec<f64>,
+    /// ISN increase when a quad was reopened, in the order they were reopened
+    pub reopen_deltas: Vec<i64>,
+    /// Properties which didn't hold
+    pub failures: Vec<String>,
+}
+
+impl IsnAuditReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for IsnAuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ISN audit over {} connections", self.connections)?;
+        writeln!(f, "  distinct ISNs:   {}", self.distinct_isns)?;
+        writeln!(
+            f,
+            "  ascending pairs: {}/{}",
+            self.ascending_pairs,
+            self.connections.saturating_sub(1)
+        )?;
+
+        let (min_bit, max_bit) = self
+            .bit_frequencies
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), &freq| {
+                (min.min(freq), max.max(freq))
+            });
+        writeln!(f, "  bit frequencies: {min_bit:.2}..{max_bit:.2}")?;
+        writeln!(f, "  reopen deltas:   {:?}", self.reopen_deltas)?;
+
+        if self.passed() {
+            write!(f, "PASS")
+        } else {
+            writeln!(f, "FAIL")?;
+            for failure in &self.failures {
+                writeln!(f, "  - {failure}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Open `n_connections` synthetic connections from distinct remote quads and check the
+/// distribution of the ISNs chosen for them. No packets leave the process.
+pub fn run(n_connections: usize) -> Result<IsnAuditReport> {
+    if n_connections < 2 * REOPENED_QUADS {
+        return Err(TcpError::Failed(format!(
+            "ISN audit needs at least {} connections",
+            2 * REOPENED_QUADS
+        )));
+    }
+
+    let isn = IsnGenerator::from_os_random()?;
+    let quads: Vec<(Ipv4Addr, u16)> = (0..n_connections).map(remote_quad).collect();
+
+    let isns: Vec<u32> = quads
+        .iter()
+        .map(|&remote| open_synthetic(&isn, remote))
+        .collect::<Result<_>>()?;
+
+    thread::sleep(REOPEN_DELAY);
+
+    let reopen_deltas: Vec<i64> = quads
+        .iter()
+        .zip(&isns)
+        .take(REOPENED_QUADS)
+        .map(|(&remote, &first_isn)| {
+            let second_isn: u32 = open_synthetic(&isn, remote)?;
+            Ok(second_isn.wrapping_sub(first_isn) as i32 as i64)
+        })
+        .collect::<Result<_>>()?;
+
+    let distinct_isns: usize = isns.iter().collect::<HashSet<_>>().len();
+
+    let ascending_pairs: usize = isns
+        .windows(2)
+        .filter(|pair| (pair[1].wrapping_sub(pair[0]) as i32) > 0)
+        .count();
+
+    let bit_frequencies: Vec<f64> = (0..u32::BITS)
+        .map(|bit| {
+            let n_set = isns.iter().filter(|&&isn| isn & (1 << bit) != 0).count();
+            n_set as f64 / isns.len() as f64
+        })
+        .collect();
+
+    let mut failures: Vec<String> = Vec::new();
+
+    // A good generator has ~no collisions between 32 bit values for a few thousand quads
+    if distinct_isns < n_connections * 99 / 100 {
+        failures.push(format!(
+            "only {distinct_isns} distinct ISNs across {n_connections} quads"
+        ));
+    }
+
+    // Random ISNs increase for about half of the adjacent pairs. A clock alone increases for all.
+    let n_pairs: f64 = (n_connections - 1) as f64;
+    let ascending_ratio: f64 = ascending_pairs as f64 / n_pairs;
+    if !(0.3..=0.7).contains(&ascending_ratio) {
+        failures.push(format!(
+            "ISNs increase across {:.0}% of adjacent quads, which is predictable",
+            ascending_ratio * 100.0
+        ));
+    }
+
+    if let Some((bit, freq)) = bit_frequencies
+        .iter()
+        .enumerate()
+        .find(|(_, &freq)| !(0.25..=0.75).contains(&freq))
+    {
+        failures.push(format!("bit {bit} is set in {:.0}% of ISNs", freq * 100.0));
+    }
+
+    // Reopening a quad keeps the same offset, so the ISN only moves forward with the clock,
+    // by far less than half the sequence space
+    if let Some(delta) = reopen_deltas
+        .iter()
+        .find(|&&delta| delta <= 0 || delta >= 1 << 24)
+    {
+        failures.push(format!(
+            "reopening a quad moved its ISN by {delta}, expected a small increase"
+        ));
+    }
+
+    Ok(IsnAuditReport {
+        connections: n_connections,
+        distinct_isns,
+        ascending_pairs,
+        bit_frequencies,
+        reopen_deltas,
+        failures,
+    })
+}
+
+/// A distinct remote address and port for the `i`th synthetic connection
+fn remote_quad(i: usize) -> (Ipv4Addr, u16) {
+    let host: u32 = i as u32 / 50_000;
+    let port: u16 = 10_000 + (i % 50_000) as u16;
+    (Ipv4Addr::from(0x0a00_0001 + host), port)
+}
+
+/// Feed a SYN from `remote` into a fresh TCB and return the ISN of its SYN-ACK
+fn open_synthetic(isn: &IsnGenerator, (remote_addr, remote_port): (Ipv4Addr, u16)) -> Result<u32> {
+    let (local_addr, local_port) = AUDIT_LOCAL;
+
+    let mut syn = TcpHeader::new(remote_port, local_port, 0x1234_5678, 64240);
+    syn.syn = true;
+
+    let ip_header = Ipv4Header::new(
+        syn.header_len_u16(),
+        64,
+        IpNumber::TCP,
+        remote_addr.octets(),
+        local_addr.octets(),
+    )?;
+    syn.checksum = syn.calc_checksum_ipv4(&ip_header, &[])?;
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet)?;
+    syn.write(&mut packet)?;
+
+    let ip_slice = Ipv4HeaderSlice::from_slice(&packet)?;
+    let tcp_slice = TcpHeaderSlice::from_slice(&packet[ip_slice.slice().len()..])?;
+
+    let device = CaptureDevice::default();
+    Tcb::accept_connection(&device, ip_slice, tcp_slice, &[], isn, &clock::system())?
+        .ok_or_else(|| TcpError::Failed("synthetic SYN was not accepted".into()))?;
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    let syn_ack: &Vec<u8> = sent
+        .first()
+        .ok_or_else(|| TcpError::Failed("no SYN-ACK sent for synthetic connection".into()))?;
+
+    let syn_ack_ip = Ipv4HeaderSlice::from_slice(syn_ack)?;
+    let syn_ack_tcp = TcpHeaderSlice::from_slice(&syn_ack[syn_ack_ip.slice().len()..])?;
+
+    Ok(syn_ack_tcp.sequence_number())
+}
+
+/// Print a report for `n_connections` and fail if any property doesn't hold
+pub fn run_and_report(n_connections: usize) -> Result<()> {
+    let start = Instant::now();
+    let report = run(n_connections)?;
+
+    println!("{report}");
+    println!("  took {:?}", start.elapsed());
+
+    if !report.passed() {
+        return Err(TcpError::Failed("ISN audit failed".into()));
+    }
+
+    Ok(())
+}
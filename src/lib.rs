@@ -1,4 +1,82 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod admin;
+#[cfg(feature = "std")]
+pub mod analyze;
+#[cfg(all(unix, feature = "std"))]
+pub mod async_stack;
+pub mod challenge;
+pub mod checksum;
+#[cfg(feature = "std")]
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod congestion;
+pub mod device;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod ethernet;
+#[cfg(feature = "std")]
+pub mod fuzz;
+pub mod health;
+pub mod hooks;
+pub mod icmp;
+#[cfg(feature = "std")]
+pub mod impair;
+pub mod io;
+pub mod isn;
+#[cfg(feature = "std")]
+pub mod isn_audit;
+#[cfg(feature = "std")]
+pub mod listener;
+#[cfg(all(unix, feature = "std"))]
+pub mod multiqueue;
+pub mod options;
+pub mod pacing;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod packet_socket;
+#[cfg(feature = "std")]
+pub mod pcap;
+pub mod pmtu;
+#[cfg(feature = "std")]
+pub mod poll;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod ports;
+#[cfg(all(unix, feature = "std"))]
+pub mod proxy;
+#[cfg(feature = "std")]
+pub mod reassembly;
+#[cfg(feature = "std")]
+pub mod replay;
+pub mod rto;
+pub mod seq;
+#[cfg(feature = "std")]
+pub mod sharded;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+pub mod span;
+#[cfg(feature = "std")]
+pub mod stack;
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod tcat;
 pub mod tcp;
+#[cfg(feature = "std")]
+pub mod testing;
+pub mod timer;
+pub mod timestamps;
+#[cfg(all(target_os = "macos", feature = "std"))]
+pub mod utun;
+pub mod window;
+#[cfg(all(windows, feature = "std"))]
+pub mod wintun;
+#[cfg(feature = "std")]
+pub mod workload;
 
 /// Buffer size to store a packet and its header in bytes
 pub const PACKET_BUF_SIZE: usize = ETH_MTU + ETH_HEADER_SIZE;
@@ -12,6 +90,7 @@ pub const ETH_HEADER_SIZE: usize = 4;
 #[repr(u16)]
 pub enum EtherType {
     Ipv4 = 0x0800,
+    Arp = 0x0806,
     Ipv6 = 0x086DD,
 }
 
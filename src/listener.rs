@@ -0,0 +1,264 @@
+use std::{collections::HashMap, time::Instant};
+
+/// Optional caps enforced on every connection accepted by a listener, and the handshake
+/// features it opts in to
+#[derive(Clone, Copy, Default)]
+pub struct ListenerLimits {
+    /// Maximum number of connections open at the same time
+    pub max_concurrent: Option<usize>,
+    /// Sustained number of payload bytes per second accepted across all connections
+    pub byte_rate: Option<u64>,
+    /// Number of bytes which may be accepted above `byte_rate` in a single burst
+    pub burst: u64,
+    /// Take part in TCP Fast Open, see [`Tcb::accept_fast_open_connection`]. Off by
+    /// default, as data on a SYN may be a duplicate the application sees twice.
+    /// RFC 7413 Section 6.2
+    ///
+    /// [`Tcb::accept_fast_open_connection`]: crate::tcp::Tcb::accept_fast_open_connection
+    pub fast_open: bool,
+}
+
+/// Running totals for a listener
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ListenerStats {
+    /// Connections accepted since the listener was created
+    pub accepted: u64,
+    /// SYNs turned away because `max_concurrent` was reached
+    pub rejected: u64,
+    /// Connections currently open
+    pub concurrent: usize,
+    /// Highest value `concurrent` has reached
+    pub peak_concurrent: usize,
+    /// Payload bytes received
+    pub bytes_in: u64,
+    /// Payload bytes sent
+    pub bytes_out: u64,
+    /// Payload bytes dropped because `byte_rate` was exceeded
+    pub bytes_throttled: u64,
+}
+
+/// Token bucket refilled at `rate` bytes per second, holding at most `rate + burst` bytes.
+/// It starts full the first time bytes are taken, so it runs on whichever clock the
+/// caller's `now` comes from.
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        let capacity = rate.saturating_add(burst);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: None,
+        }
+    }
+
+    fn try_take(&mut self, n: u64, now: Instant) -> bool {
+        let last_refill: Instant = *self.last_refill.get_or_insert(now);
+        let elapsed_micros: u128 = now.saturating_duration_since(last_refill).as_micros();
+        let refill: u128 = elapsed_micros * self.rate as u128 / 1_000_000;
+
+        if refill > 0 {
+            self.tokens = std::cmp::min(self.capacity as u128, self.tokens as u128 + refill) as u64;
+            self.last_refill = Some(now);
+        }
+
+        if n > self.tokens {
+            return false;
+        }
+
+        self.tokens -= n;
+        true
+    }
+}
+
+/// Accounting for all connections accepted on one local port
+pub struct Listener {
+    port: u16,
+    limits: ListenerLimits,
+    stats: ListenerStats,
+    bucket: Option<TokenBucket>,
+}
+
+impl Listener {
+    pub fn new(port: u16, limits: ListenerLimits) -> Self {
+        let bucket = limits
+            .byte_rate
+            .map(|rate| TokenBucket::new(rate, limits.burst));
+
+        Listener {
+            port,
+            limits,
+            stats: ListenerStats::default(),
+            bucket,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn limits(&self) -> ListenerLimits {
+        self.limits
+    }
+
+    pub fn stats(&self) -> ListenerStats {
+        self.stats
+    }
+
+    /// Whether another connection can be accepted without exceeding `max_concurrent`.
+    /// Counts the SYN as rejected if not.
+    pub fn try_reserve(&mut self) -> bool {
+        match self.limits.max_concurrent {
+            Some(max) if self.stats.concurrent >= max => {
+                self.stats.rejected += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Record a connection which has been accepted
+    pub fn on_accept(&mut self) {
+        self.stats.accepted += 1;
+        self.stats.concurrent += 1;
+        self.stats.peak_concurrent =
+            std::cmp::max(self.stats.peak_concurrent, self.stats.concurrent);
+    }
+
+    /// Record a connection which is no longer open
+    pub fn on_close(&mut self) {
+        self.stats.concurrent = self.stats.concurrent.saturating_sub(1);
+    }
+
+    /// Account for an incoming payload, returning false if it would exceed the byte rate
+    /// and should be dropped. The rest of its segment is still processed.
+    pub fn admit_bytes_in(&mut self, n_bytes: usize, now: Instant) -> bool {
+        if n_bytes == 0 {
+            return true;
+        }
+
+        if let Some(bucket) = &mut self.bucket {
+            if !bucket.try_take(n_bytes as u64, now) {
+                self.stats.bytes_throttled += n_bytes as u64;
+                return false;
+            }
+        }
+
+        self.stats.bytes_in += n_bytes as u64;
+        true
+    }
+
+    /// Account for an outgoing payload
+    pub fn on_bytes_out(&mut self, n_bytes: usize) {
+        self.stats.bytes_out += n_bytes as u64;
+    }
+}
+
+/// How to answer segments for ports nothing is listening on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClosedPortPolicy {
+    /// Send a reset, as RFC 9293 Section 3.10.7.1 requires
+    #[default]
+    Reset,
+    /// Drop the segment without a reply, so scans can't tell closed ports from filtered ones
+    Silent,
+    /// Send an ICMP Port Unreachable, as a firewall rejecting the segment would
+    PortUnreachable,
+}
+
+/// What to do with a new connection on the 4-tuple of one which is still in TIME-WAIT,
+/// whether the peer's SYN opens it or [`Stack::connect`](crate::stack::Stack::connect)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeWaitPolicy {
+    /// Handle the SYN like any other segment on the old connection, and fail to connect,
+    /// so the 4-tuple can't be used again until TIME-WAIT ends
+    Refuse,
+    /// Replace the old connection with a new one if it's clearly a new incarnation, see
+    /// [`Tcb::is_new_incarnation`](crate::tcp::Tcb::is_new_incarnation) and
+    /// [`Tcb::can_reconnect_with`](crate::tcp::Tcb::can_reconnect_with).
+    /// Lets either end reconnect straight away, as RFC 1122 Section 4.2.2.13 permits.
+    /// A peer's SYN only reopens a connection its listener accepted, and only while the
+    /// port is still listened on.
+    #[default]
+    Reopen,
+}
+
+/// Listeners indexed by local port.
+/// Segments for ports without a listener are treated as arriving at a closed port,
+/// unless `default_limits` is set in which case a listener is created on first use.
+#[derive(Default)]
+pub struct Listeners {
+    listeners: HashMap<u16, Listener>,
+    default_limits: Option<ListenerLimits>,
+    closed_port_policy: ClosedPortPolicy,
+    time_wait_policy: TimeWaitPolicy,
+}
+
+impl Listeners {
+    /// Listen on every port, creating listeners with `default_limits` as they are needed
+    pub fn accept_any(default_limits: ListenerLimits) -> Self {
+        Listeners {
+            listeners: HashMap::new(),
+            default_limits: Some(default_limits),
+            closed_port_policy: ClosedPortPolicy::default(),
+            time_wait_policy: TimeWaitPolicy::default(),
+        }
+    }
+
+    /// Whether every port is listened on, see [`Listeners::accept_any`]
+    pub fn accepts_any(&self) -> bool {
+        self.default_limits.is_some()
+    }
+
+    pub fn closed_port_policy(&self) -> ClosedPortPolicy {
+        self.closed_port_policy
+    }
+
+    pub fn set_closed_port_policy(&mut self, policy: ClosedPortPolicy) {
+        self.closed_port_policy = policy;
+    }
+
+    pub fn time_wait_policy(&self) -> TimeWaitPolicy {
+        self.time_wait_policy
+    }
+
+    pub fn set_time_wait_policy(&mut self, policy: TimeWaitPolicy) {
+        self.time_wait_policy = policy;
+    }
+
+    /// Add or replace the listener on `port`
+    pub fn insert(&mut self, port: u16, limits: ListenerLimits) {
+        self.listeners.insert(port, Listener::new(port, limits));
+    }
+
+    /// Stop listening on `port`. Connections it already accepted carry on.
+    pub fn remove(&mut self, port: u16) -> Option<Listener> {
+        self.listeners.remove(&port)
+    }
+
+    pub fn get(&self, port: u16) -> Option<&Listener> {
+        self.listeners.get(&port)
+    }
+
+    /// The listener on `port`, or `None` if the port is closed
+    pub fn get_mut(&mut self, port: u16) -> Option<&mut Listener> {
+        match self.default_limits {
+            Some(default_limits) => Some(
+                self.listeners
+                    .entry(port)
+                    .or_insert_with(|| Listener::new(port, default_limits)),
+            ),
+            None => self.listeners.get_mut(&port),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Listener> {
+        self.listeners.values()
+    }
+}
@@ -1,70 +1,547 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{self, BufWriter},
     net::Ipv4Addr,
+    process,
+    time::Instant,
+};
+#[cfg(unix)]
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    path::Path,
+    sync::Arc,
 };
 
-use anyhow::Result;
-use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+use anyhow::{bail, Result};
+#[cfg(target_os = "linux")]
 use tun_tap::{Iface, Mode};
 
+#[cfg(unix)]
+use tcp_rs::{
+    admin::{AdminCommand, AdminSocket},
+    async_stack::AsyncStack,
+    proxy::Proxy,
+    sharded::ShardedStack,
+    stack,
+    stats::ConnectionSummary,
+    tcat,
+};
 use tcp_rs::{
-    tcp::{ConnectInfo, Tcb},
-    PACKET_BUF_SIZE,
+    analyze,
+    cli::{self, Command, DaemonOptions, LinkMode},
+    device::{open_tun, NetworkDevice, PcapTap, RecvBatch, TunDevice},
+    error::TcpError,
+    isn::IsnGenerator,
+    isn_audit,
+    listener::{ListenerLimits, Listeners},
+    replay,
+    span::{self, LogLevel},
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    workload, PACKET_BUF_SIZE,
 };
+#[cfg(target_os = "linux")]
+use tcp_rs::{
+    ethernet::{EthernetDevice, MacAddr},
+    multiqueue::{MultiQueueStack, TunQueue},
+    packet_socket::{PacketFilter, PacketSocket},
+};
+
+/// The tun or tap device, which packets can be captured from at runtime over the admin socket
+#[cfg(unix)]
+type Device = PcapTap<Box<dyn Link>>;
+/// The Wintun adapter, captured from the start with `--pcap`
+#[cfg(windows)]
+type Device = PcapTap<TunDevice>;
+
+/// What the daemon needs from the interface, in either mode
+#[cfg(unix)]
+trait Link: NetworkDevice + AsRawFd + Send + Sync {}
+
+#[cfg(unix)]
+impl<D: NetworkDevice + AsRawFd + Send + Sync> Link for D {}
+
+#[cfg(unix)]
+impl AsRawFd for Box<dyn Link> {
+    fn as_raw_fd(&self) -> RawFd {
+        (**self).as_raw_fd()
+    }
+}
+
+/// Where the admin socket is served, see [`AdminSocket`]
+#[cfg(unix)]
+const ADMIN_SOCKET_PATH: &str = "/tmp/tcp_rs.sock";
+
+/// How often the admin thread checks on the queue threads with `--queues`
+#[cfg(target_os = "linux")]
+const QUEUE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 fn main() -> Result<()> {
-    let mut connections = HashMap::<ConnectInfo, Tcb>::default();
+    // The library logs nothing unless asked, but the binary reports what happens to
+    // connections. The daemon sets its own level from --log.
+    span::set_level(LogLevel::Info);
 
-    let nic = Iface::without_packet_info("tun0", Mode::Tun)?;
+    match Command::parse(std::env::args().skip(1))? {
+        Command::Daemon(options) => run_daemon(*options),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
+        Command::IsnAudit(n_connections) => Ok(isn_audit::run_and_report(n_connections)?),
+        Command::Analyze(path) => Ok(analyze::run_and_report(&path)?),
+        Command::Replay { pcap, expected } => {
+            Ok(replay::run_and_report(&pcap, expected.as_deref())?)
+        }
+        Command::Workload(path) => Ok(workload::run_and_report(&path)?),
+        #[cfg(unix)]
+        Command::Tcat { iface, mode } => {
+            let nic = open_tun(&iface)?;
+            Ok(tcat::run(&nic, mode)?)
+        }
+        #[cfg(windows)]
+        Command::Tcat { .. } => bail!("tcat polls stdin, which Windows can't do"),
+    }
+}
 
-    let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+#[cfg(unix)]
+fn run_daemon(options: DaemonOptions) -> Result<()> {
+    span::set_level(options.log_level);
+    span::set_port_filter(options.log_port);
+
+    let proxy: bool = !options.forward.is_empty() || !options.reverse.is_empty();
+    if let Some(n_queues) = options.queues {
+        if proxy {
+            bail!("--forward and --reverse can't be used with --queues");
+        }
+        return run_multiqueue(n_queues, &options);
+    }
+
+    // The kernel picks a utun interface's name, so it can differ from the one asked for
+    let (link, iface): (Box<dyn Link>, String) = match options.link {
+        LinkMode::Tun => {
+            let tun: TunDevice = open_tun(&options.iface)?;
+            tun.set_non_blocking()?;
+            let name: String = tun.name().to_string();
+            (Box::new(tun), name)
+        }
+        #[cfg(target_os = "linux")]
+        LinkMode::Tap(addr) => {
+            let iface = Iface::without_packet_info(&options.iface, Mode::Tap)?;
+            iface.set_non_blocking()?;
+            let link = EthernetDevice::new(iface, MacAddr::for_ipv4(addr), addr);
+            (Box::new(link), options.iface.clone())
+        }
+        #[cfg(target_os = "linux")]
+        LinkMode::Packet(addr) => {
+            let filter = PacketFilter {
+                addr,
+                ports: options.listen.clone(),
+            };
+            let socket = PacketSocket::open(&options.iface, &filter)?;
+            socket.set_non_blocking()?;
+            let mac: MacAddr = socket.mac();
+            let link = EthernetDevice::new(socket, mac, addr);
+            (Box::new(link), options.iface.clone())
+        }
+        #[cfg(not(target_os = "linux"))]
+        LinkMode::Tap(_) => bail!("--tap needs tap devices, which only Linux has"),
+        #[cfg(not(target_os = "linux"))]
+        LinkMode::Packet(_) => bail!("--raw needs AF_PACKET sockets, which only Linux has"),
+    };
+    let nic = PcapTap::new(link);
+    if let Some((addr, prefix_len)) = options.address {
+        configure_address(&iface, addr, prefix_len)?;
+    }
+    if let Some(path) = &options.pcap {
+        nic.start_capture(BufWriter::new(File::create(path)?))?;
+    }
+
+    match options.workers {
+        _ if proxy => run_proxy(nic, &options),
+        Some(n_workers) => run_sharded(nic, n_workers, &options),
+        None => run_single(nic, &options),
+    }
+}
+
+/// Serve every connection from this thread. There are no file descriptors to poll on
+/// Windows, so there's no admin socket, and the thread sleeps on the adapter's read event.
+#[cfg(windows)]
+fn run_daemon(options: DaemonOptions) -> Result<()> {
+    span::set_level(options.log_level);
+    span::set_port_filter(options.log_port);
+
+    if options.link != LinkMode::Tun {
+        bail!("--tap and --raw need Linux");
+    }
+    if options.workers.is_some() {
+        bail!("--workers needs Linux or macOS");
+    }
+    if options.queues.is_some() {
+        bail!("--queues needs Linux");
+    }
+    if !options.forward.is_empty() || !options.reverse.is_empty() {
+        bail!("--forward and --reverse need Linux or macOS");
+    }
+
+    let nic: Device = PcapTap::new(open_tun(&options.iface)?);
+    if let Some((addr, prefix_len)) = options.address {
+        configure_address(&options.iface, addr, prefix_len)?;
+    }
+    if let Some(path) = &options.pcap {
+        nic.start_capture(BufWriter::new(File::create(path)?))?;
+    }
+
+    let mut stack = Stack::with_config(
+        listeners(&options.listen),
+        IsnGenerator::from_os_random()?,
+        Instant::now(),
+        options.config,
+    );
+
+    let mut batch = RecvBatch::for_device(&nic);
 
     loop {
-        let n_bytes: usize = nic.recv(&mut buf[..])?;
+        if nic.inner().wait_readable(stack.next_deadline())? {
+            // Wintun only signals the event again once the ring has been emptied
+            while batch.recv(&nic)? > 0 {
+                stack.on_packets(&nic, batch.packets(), Instant::now())?;
+            }
+            serve_connections(&nic, &mut stack)?;
+        }
 
-        match Ipv4HeaderSlice::from_slice(&buf[..n_bytes]) {
-            Ok(ipv4_header) => {
-                let src: Ipv4Addr = ipv4_header.source_addr();
-                let dst: Ipv4Addr = ipv4_header.destination_addr();
+        stack.on_tick(&nic, Instant::now())?;
+    }
+}
 
-                if ipv4_header.protocol() != IpNumber::TCP {
-                    continue;
-                }
+/// Give `iface` the host side address `addr`/`prefix_len` and bring it up, as
+/// `scripts/run.sh` does by hand
+#[cfg(target_os = "linux")]
+fn configure_address(iface: &str, addr: Ipv4Addr, prefix_len: u8) -> Result<()> {
+    let cidr: String = format!("{addr}/{prefix_len}");
+    run("ip", &["addr", "add", &cidr, "dev", iface])?;
+    run("ip", &["link", "set", "up", "dev", iface])
+}
+
+/// utun interfaces are point to point, so the address is given as its own destination and
+/// the rest of the prefix is routed to the interface
+#[cfg(target_os = "macos")]
+fn configure_address(iface: &str, addr: Ipv4Addr, prefix_len: u8) -> Result<()> {
+    let addr: String = addr.to_string();
+    let cidr: String = format!("{addr}/{prefix_len}");
+    run("ifconfig", &[iface, "inet", &cidr, &addr, "up"])?;
+    run(
+        "route",
+        &["-q", "-n", "add", "-inet", &cidr, "-interface", iface],
+    )
+}
+
+/// Windows keeps an adapter's address in its IP settings rather than on the device
+#[cfg(windows)]
+fn configure_address(iface: &str, addr: Ipv4Addr, prefix_len: u8) -> Result<()> {
+    let mask = Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0));
+    let name: String = format!("name={iface}");
+    let addr: String = addr.to_string();
+    let mask: String = mask.to_string();
+    run(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "set",
+            "address",
+            &name,
+            "static",
+            &addr,
+            &mask,
+        ],
+    )
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = process::Command::new(program).args(args).status()?;
+    if !status.success() {
+        bail!("{program} {} failed: {status}", args.join(" "));
+    }
 
-                let tcp_header_offset: usize = ipv4_header.slice().len();
-
-                match TcpHeaderSlice::from_slice(&buf[tcp_header_offset..n_bytes]) {
-                    Ok(tcp_header) => {
-                        let data_offset: usize = tcp_header_offset + tcp_header.slice().len();
-
-                        match connections.entry(ConnectInfo {
-                            src_addr: src,
-                            src_port: tcp_header.source_port(),
-                            dst_addr: dst,
-                            dst_port: tcp_header.destination_port(),
-                        }) {
-                            Entry::Occupied(mut entry) => entry.get_mut().on_packet(
-                                &nic,
-                                ipv4_header,
-                                tcp_header,
-                                &buf[data_offset..n_bytes],
-                            )?,
-                            Entry::Vacant(entry) => {
-                                if let Some(tcb) = Tcb::accept_connection(
-                                    &nic,
-                                    ipv4_header,
-                                    tcp_header,
-                                    &buf[data_offset..n_bytes],
-                                )? {
-                                    entry.insert(tcb);
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => eprintln!("Skipping packet. Failed to decode TCP packet: {err}"),
+    Ok(())
+}
+
+/// Listeners on `ports`, or on every port if there are none
+fn listeners(ports: &[u16]) -> Listeners {
+    if ports.is_empty() {
+        return Listeners::accept_any(ListenerLimits::default());
+    }
+
+    let mut listeners = Listeners::default();
+    for &port in ports {
+        listeners.insert(port, ListenerLimits::default());
+    }
+    listeners
+}
+
+/// Serve every connection from this thread
+#[cfg(unix)]
+fn run_single(nic: Device, options: &DaemonOptions) -> Result<()> {
+    let mut stack = Stack::with_config(
+        listeners(&options.listen),
+        IsnGenerator::from_os_random()?,
+        Instant::now(),
+        options.config,
+    );
+
+    let admin = AdminSocket::bind(ADMIN_SOCKET_PATH)?;
+
+    let mut batch = RecvBatch::for_device(&nic);
+
+    loop {
+        let [packet_ready, admin_ready] = stack::wait_for_input(
+            [Some(nic.as_raw_fd()), Some(admin.as_raw_fd())],
+            stack.next_deadline(),
+        )?;
+
+        if packet_ready {
+            batch.recv(&nic)?;
+            stack.on_packets(&nic, batch.packets(), Instant::now())?;
+            serve_connections(&nic, &mut stack)?;
+        }
+
+        if admin_ready {
+            admin.serve(|command| match command {
+                AdminCommand::Stats { reset } => {
+                    let stats = stack.snapshot_stats(Instant::now(), reset);
+                    format!("{stats}\n{}", stack.health(&nic))
+                }
+                AdminCommand::StartCapture { path } => start_capture(&nic, &path),
+                AdminCommand::StopCapture => stop_capture(&nic),
+                AdminCommand::Health => stack.health(&nic).to_string(),
+                AdminCommand::Connections => list_connections(&stack.connection_summaries()),
+                AdminCommand::Abort { connection } => {
+                    aborted(connection, stack.abort(&nic, &connection))
                 }
+            })?;
+        }
+
+        stack.on_tick(&nic, Instant::now())?;
+    }
+}
+
+/// Relay connections between the stack and host sockets for the `--forward` and
+/// `--reverse` rules. Only the forwarded ports are listened on, and there's no admin
+/// socket, as the stack is driven by [`AsyncStack`]'s own thread.
+#[cfg(unix)]
+fn run_proxy(nic: Device, options: &DaemonOptions) -> Result<()> {
+    if options.workers.is_some() {
+        bail!("--forward and --reverse can't be used with --workers");
+    }
+    if !options.listen.is_empty() {
+        bail!("--listen can't be used with --forward or --reverse, which listen themselves");
+    }
+
+    let stack = Stack::with_config(
+        Listeners::default(),
+        IsnGenerator::from_os_random()?,
+        Instant::now(),
+        options.config,
+    );
+    let stack = AsyncStack::spawn(nic, stack)?;
+
+    Ok(Proxy::spawn(stack, &options.forward, &options.reverse)?.join()?)
+}
+
+/// Serve connections from `n_workers` threads, each owning the connections whose 4-tuple
+/// hashes to it. This thread only reads packets and hands them out.
+#[cfg(unix)]
+fn run_sharded(nic: Device, n_workers: usize, options: &DaemonOptions) -> Result<()> {
+    let nic = Arc::new(nic);
+    let admin = AdminSocket::bind(ADMIN_SOCKET_PATH)?;
+
+    let mut stack = ShardedStack::spawn(
+        Arc::clone(&nic),
+        n_workers,
+        || {
+            Ok(Stack::with_config(
+                listeners(&options.listen),
+                IsnGenerator::from_os_random()?,
+                Instant::now(),
+                options.config,
+            ))
+        },
+        serve_connections,
+    )?;
+    stack.set_health_limits(options.config.health_limits);
+
+    let mut batch = RecvBatch::for_device(&*nic);
+
+    loop {
+        let [packet_ready, admin_ready] = stack::wait_for_input(
+            [Some(nic.as_raw_fd()), Some(admin.as_raw_fd())],
+            stack.next_deadline(),
+        )?;
+
+        if packet_ready {
+            batch.recv(&*nic)?;
+            for packet in batch.packets() {
+                stack.on_packet(packet, Instant::now())?;
             }
-            Err(err) => eprintln!("Skipping packet. Failed to decode Ipv4 packet: {err}"),
-        };
+        }
+
+        if admin_ready {
+            admin.serve(|command| match command {
+                AdminCommand::Stats { reset } => {
+                    let stats = stack.snapshot_stats(Instant::now(), reset);
+                    format!("{stats}\n{}", stack.health())
+                }
+                AdminCommand::StartCapture { path } => start_capture(&nic, &path),
+                AdminCommand::StopCapture => stop_capture(&nic),
+                AdminCommand::Health => stack.health().to_string(),
+                AdminCommand::Connections => list_connections(&stack.connection_summaries()),
+                AdminCommand::Abort { connection } => aborted(connection, stack.abort(&connection)),
+            })?;
+        }
+
+        stack.on_tick(Instant::now());
+    }
+}
+
+/// Serve connections from a thread for each of `n_queues` queues of the tun interface,
+/// each owning the connections the kernel steers to its queue. This thread only answers
+/// the admin socket.
+#[cfg(target_os = "linux")]
+fn run_multiqueue(n_queues: usize, options: &DaemonOptions) -> Result<()> {
+    if options.link != LinkMode::Tun {
+        bail!("--queues needs a tun interface");
+    }
+    if options.workers.is_some() {
+        bail!("--queues and --workers can't be used together");
+    }
+    if options.pcap.is_some() {
+        bail!("--pcap can't capture from more than one queue");
+    }
+
+    let mut queues: Vec<Device> = Vec::with_capacity(n_queues);
+    for queue in TunQueue::open_all(&options.iface, n_queues)? {
+        queue.set_non_blocking()?;
+        queues.push(PcapTap::new(Box::new(queue)));
+    }
+    if let Some((addr, prefix_len)) = options.address {
+        configure_address(&options.iface, addr, prefix_len)?;
+    }
+
+    let admin = AdminSocket::bind(ADMIN_SOCKET_PATH)?;
+
+    let mut stack = MultiQueueStack::spawn(
+        queues,
+        || {
+            Ok(Stack::with_config(
+                listeners(&options.listen),
+                IsnGenerator::from_os_random()?,
+                Instant::now(),
+                options.config,
+            ))
+        },
+        serve_connections,
+    )?;
+    stack.set_health_limits(options.config.health_limits);
+
+    loop {
+        // Wake up now and then to notice a queue's thread failing
+        let [admin_ready] = stack::wait_for_input(
+            [Some(admin.as_raw_fd())],
+            Some(Instant::now() + QUEUE_CHECK_INTERVAL),
+        )?;
+
+        if admin_ready {
+            admin.serve(|command| match command {
+                AdminCommand::Stats { reset } => {
+                    let stats = stack.snapshot_stats(Instant::now(), reset);
+                    format!("{stats}\n{}", stack.health())
+                }
+                AdminCommand::StartCapture { .. } | AdminCommand::StopCapture => {
+                    "capture isn't available with --queues".to_string()
+                }
+                AdminCommand::Health => stack.health().to_string(),
+                AdminCommand::Connections => list_connections(&stack.connection_summaries()),
+                AdminCommand::Abort { connection } => aborted(connection, stack.abort(&connection)),
+            })?;
+        }
+
+        stack.check()?;
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn run_multiqueue(_n_queues: usize, _options: &DaemonOptions) -> Result<()> {
+    bail!("--queues needs Linux")
+}
+
+/// One line for each connection, for the `connections` admin command
+#[cfg(unix)]
+fn list_connections(summaries: &[ConnectionSummary]) -> String {
+    if summaries.is_empty() {
+        return "no connections".to_string();
+    }
+
+    summaries
+        .iter()
+        .map(ConnectionSummary::to_string)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The answer to the `abort` admin command
+#[cfg(unix)]
+fn aborted(connection: ConnectInfo, result: Result<(), TcpError>) -> String {
+    match result {
+        Ok(()) => format!("aborted {connection}"),
+        Err(err) => format!("failed to abort {connection}: {err}"),
+    }
+}
+
+/// Start writing every packet through `nic` to a new pcap file at `path`
+#[cfg(unix)]
+fn start_capture(nic: &Device, path: &Path) -> String {
+    let started: io::Result<()> =
+        File::create(path).and_then(|file| nic.start_capture(BufWriter::new(file)));
+
+    match started {
+        Ok(()) => format!("capturing to {}", path.display()),
+        Err(err) => format!("failed to capture to {}: {err}", path.display()),
+    }
+}
+
+#[cfg(unix)]
+fn stop_capture(nic: &Device) -> String {
+    if !nic.is_capturing() {
+        return "not capturing".to_string();
+    }
+
+    match nic.stop_capture() {
+        Ok(()) => "capture stopped".to_string(),
+        Err(err) => format!("capture stopped, failed to flush: {err}"),
     }
 }
+
+/// There's no application yet, so received data is logged and dropped.
+/// Nothing is ever sent either, so connections close as soon as the peer has finished.
+fn serve_connections(nic: &Device, stack: &mut Stack) -> Result<(), TcpError> {
+    let mut finished: Vec<ConnectInfo> = Vec::new();
+
+    for (info, tcb) in stack.connections_mut() {
+        let mut received: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+        let n_read: usize = tcb.read(&mut received);
+        let _span = span::enter(tcb.id());
+        if n_read > 0 && span::enabled(LogLevel::Trace) {
+            span::write_log(format_args!("Read {n_read}b: {:02x?}", &received[..n_read]));
+        }
+
+        if tcb.state() == State::CloseWait {
+            finished.push(*info);
+        }
+    }
+
+    for info in finished {
+        stack.close(nic, &info)?;
+    }
+
+    Ok(())
+}
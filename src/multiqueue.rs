@@ -0,0 +1,513 @@
+#[cfg(target_os = "linux")]
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Read},
+    os::{fd::RawFd, unix::fs::OpenOptionsExt},
+};
+use std::{
+    ops::{Deref, DerefMut},
+    os::{fd::AsRawFd, unix::net::UnixDatagram},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use etherparse::Ipv4HeaderSlice;
+
+#[cfg(target_os = "linux")]
+use crate::{device, ETH_MTU};
+use crate::{
+    device::{NetworkDevice, RecvBatch},
+    error::{Result, TcpError},
+    health::{Health, HealthLimits, HealthUsage},
+    sharded::{connection_of, lock},
+    span::log,
+    stack::{self, Stack},
+    stats::{ConnectionSummary, StatsSnapshot},
+    tcp::ConnectInfo,
+};
+
+/// `_IOW('T', 202, int)`, attach a file descriptor to a tun or tap interface.
+/// See Documentation/networking/tuntap.rst in the kernel tree.
+#[cfg(target_os = "linux")]
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+/// One queue of a tun interface opened with `IFF_MULTI_QUEUE`.
+///
+/// Each queue is a file descriptor of its own, and the kernel steers every flow to one of
+/// them, so they can be read from separate threads without sharing anything.
+#[cfg(target_os = "linux")]
+pub struct TunQueue {
+    file: File,
+    name: String,
+}
+
+#[cfg(target_os = "linux")]
+impl TunQueue {
+    /// Open `n_queues` queues of the tun interface `name`, creating it if it doesn't exist.
+    /// An interface created without `IFF_MULTI_QUEUE` can't be opened this way.
+    pub fn open_all(name: &str, n_queues: usize) -> io::Result<Vec<TunQueue>> {
+        if n_queues == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a tun interface needs at least one queue",
+            ));
+        }
+
+        (0..n_queues).map(|_| TunQueue::open(name)).collect()
+    }
+
+    fn open(name: &str) -> io::Result<TunQueue> {
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        // SAFETY: an all zero ifreq is valid, the name and flags are filled in below
+        let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+        if name.len() >= request.ifr_name.len() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags =
+            (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE) as libc::c_short;
+
+        // SAFETY: `request` is a valid ifreq which outlives the call
+        let result: libc::c_int = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &request) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The kernel fills in the name when it picks one
+        let name: String = request
+            .ifr_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8 as char)
+            .collect();
+
+        Ok(TunQueue { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_non_blocking(&self) -> io::Result<()> {
+        // SAFETY: no pointers are passed
+        let flags: libc::c_int = unsafe { libc::fcntl(self.file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: no pointers are passed
+        let result: libc::c_int = unsafe {
+            libc::fcntl(
+                self.file.as_raw_fd(),
+                libc::F_SETFL,
+                flags | libc::O_NONBLOCK,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkDevice for TunQueue {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// Each write to a tun queue is one packet, so the pieces go to the kernel as they are
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n_bufs: libc::c_int = bufs
+            .len()
+            .try_into()
+            .map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on Unix, and every
+        // slice outlives the call
+        let n_written: isize = unsafe {
+            libc::writev(
+                self.file.as_raw_fd(),
+                bufs.as_ptr().cast::<libc::iovec>(),
+                n_bufs,
+            )
+        };
+        if n_written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n_written as usize)
+    }
+
+    /// The interface's configured MTU, or [`ETH_MTU`] if it can't be read
+    fn mtu(&self) -> usize {
+        match device::interface_mtu(&self.name) {
+            Ok(mtu) => mtu,
+            Err(err) => {
+                log!(
+                    "Couldn't read the MTU of {}, assuming {ETH_MTU}: {err}",
+                    self.name
+                );
+                ETH_MTU
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for TunQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Serves a [`Stack`] for each queue of a multi-queue device, each from a thread of its own.
+///
+/// A connection lives in the stack of the queue its first packet arrived on, which is
+/// normally where the kernel steers the rest of its flow. Anything for a connection which
+/// arrives on another queue, such as the SYN,ACK of one opened from that queue's thread,
+/// is handed to the stack which owns it. Queue threads never hold two stacks at once.
+/// Fragments are reassembled by the queue they arrive on.
+///
+/// Listener limits and the challenge ACK limit apply to each queue separately.
+pub struct MultiQueueStack<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    shared: Arc<Shared<D>>,
+    workers: Vec<Option<JoinHandle<Result<()>>>>,
+    /// Limits for the queues together, rather than each queue's own
+    health_limits: HealthLimits,
+}
+
+struct Shared<D> {
+    queues: Vec<Queue<D>>,
+    stopping: AtomicBool,
+}
+
+struct Queue<D> {
+    nic: D,
+    stack: Mutex<Stack>,
+    /// Wakes the queue's thread, after a packet has been handed to its stack or to stop it
+    wake: UnixDatagram,
+}
+
+impl<D> MultiQueueStack<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Start a thread for each of `queues`, each with a stack from `new_stack`.
+    /// The queues must be non-blocking. After every batch of packets or timer a thread
+    /// calls `serve` on its stack, which is where the application reads and writes the
+    /// queue's connections.
+    pub fn spawn<F>(
+        queues: Vec<D>,
+        mut new_stack: impl FnMut() -> Result<Stack>,
+        serve: F,
+    ) -> Result<Self>
+    where
+        F: Fn(&D, &mut Stack) -> Result<()> + Clone + Send + 'static,
+    {
+        if queues.is_empty() {
+            return Err(TcpError::InvalidInput(
+                "a multi-queue stack needs at least one queue",
+            ));
+        }
+
+        let mut wake_rxs: Vec<UnixDatagram> = Vec::with_capacity(queues.len());
+        let mut shared_queues: Vec<Queue<D>> = Vec::with_capacity(queues.len());
+        for nic in queues {
+            let (wake, wake_rx) = UnixDatagram::pair()?;
+            wake.set_nonblocking(true)?;
+            wake_rx.set_nonblocking(true)?;
+            wake_rxs.push(wake_rx);
+
+            shared_queues.push(Queue {
+                nic,
+                stack: Mutex::new(new_stack()?),
+                wake,
+            });
+        }
+
+        let shared = Arc::new(Shared {
+            queues: shared_queues,
+            stopping: AtomicBool::new(false),
+        });
+
+        let mut workers: Vec<Option<JoinHandle<Result<()>>>> = Vec::with_capacity(wake_rxs.len());
+        for (index, wake_rx) in wake_rxs.into_iter().enumerate() {
+            let worker_shared: Arc<Shared<D>> = Arc::clone(&shared);
+            let serve: F = serve.clone();
+            let worker: Result<JoinHandle<Result<()>>> = thread::Builder::new()
+                .name(format!("queue-{index}"))
+                .spawn(move || run_queue(&worker_shared, index, wake_rx, serve))
+                .map_err(Into::into);
+
+            match worker {
+                Ok(worker) => workers.push(Some(worker)),
+                Err(err) => {
+                    // Stop the threads already started before giving up
+                    drop(MultiQueueStack {
+                        shared,
+                        workers,
+                        health_limits: HealthLimits::default(),
+                    });
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(MultiQueueStack {
+            shared,
+            workers,
+            health_limits: HealthLimits::default(),
+        })
+    }
+
+    pub fn n_queues(&self) -> usize {
+        self.shared.queues.len()
+    }
+
+    /// Lock the stack of one queue. Its thread waits until the guard is dropped, and is
+    /// woken once it has been, as anything done through it may have changed its timers.
+    pub fn queue(&self, index: usize) -> QueueGuard<'_, D> {
+        QueueGuard {
+            stack: lock(&self.shared.queues[index].stack),
+            queue: &self.shared.queues[index],
+        }
+    }
+
+    /// Index of the queue whose stack owns the connection, if any does
+    pub fn queue_of(&self, info: &ConnectInfo) -> Option<usize> {
+        self.shared.owner_of(info)
+    }
+
+    /// Read every counter across the queues, resetting them to zero if `reset` is set.
+    /// Queues are read one after another, so the intervals only roughly line up.
+    pub fn snapshot_stats(&self, now: Instant, reset: bool) -> StatsSnapshot {
+        let mut snapshots = self
+            .shared
+            .queues
+            .iter()
+            .map(|queue| lock(&queue.stack).snapshot_stats(now, reset));
+
+        // There's always at least one queue
+        let mut snapshot: StatsSnapshot = snapshots.next().expect("no queues");
+        for other in snapshots {
+            snapshot.merge(other);
+        }
+        snapshot
+    }
+
+    /// Every connection across the queues, see [`Stack::connection_summaries`]
+    pub fn connection_summaries(&self) -> Vec<ConnectionSummary> {
+        let mut summaries: Vec<ConnectionSummary> = self
+            .shared
+            .queues
+            .iter()
+            .flat_map(|queue| lock(&queue.stack).connection_summaries())
+            .collect();
+        summaries.sort_by_key(|summary| (summary.connection.src_addr, summary.connection.src_port));
+        summaries
+    }
+
+    /// Abort a connection in whichever queue owns it, see [`Stack::abort`]
+    pub fn abort(&self, info: &ConnectInfo) -> Result<()> {
+        let Some(index) = self.queue_of(info) else {
+            return Err(TcpError::NotConnected);
+        };
+        let nic: &D = &self.shared.queues[index].nic;
+        self.queue(index).abort(nic, info)
+    }
+
+    pub fn set_health_limits(&mut self, limits: HealthLimits) {
+        self.health_limits = limits;
+    }
+
+    /// Resource usage summed across the queues, against the limits for all of them
+    pub fn health(&self) -> Health {
+        let mut usage = HealthUsage::default();
+        for queue in &self.shared.queues {
+            usage.merge(HealthUsage {
+                device_queue: queue.nic.queued_packets(),
+                ..lock(&queue.stack).health_usage()
+            });
+        }
+
+        Health::new(usage, self.health_limits)
+    }
+
+    /// Whether every queue's thread is still running. One which has stopped has failed,
+    /// and its error is returned here.
+    pub fn check(&mut self) -> Result<()> {
+        for (index, worker) in self.workers.iter_mut().enumerate() {
+            if !worker.as_ref().is_some_and(JoinHandle::is_finished) {
+                continue;
+            }
+
+            match worker.take().map(JoinHandle::join) {
+                Some(Ok(Err(err))) => {
+                    return Err(TcpError::WorkerStopped {
+                        index,
+                        error: Some(Box::new(err)),
+                    })
+                }
+                _ => return Err(TcpError::WorkerStopped { index, error: None }),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<D> Drop for MultiQueueStack<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Stop every queue's thread. Connections which are still open go with them.
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Release);
+        for queue in &self.shared.queues {
+            queue.wake();
+        }
+
+        for (index, worker) in self.workers.iter_mut().enumerate() {
+            if let Some(Ok(Err(err))) = worker.take().map(JoinHandle::join) {
+                log!("Queue {index} failed: {err}");
+            }
+        }
+    }
+}
+
+/// The stack of one queue, see [`MultiQueueStack::queue`]
+pub struct QueueGuard<'a, D> {
+    stack: MutexGuard<'a, Stack>,
+    queue: &'a Queue<D>,
+}
+
+impl<D> QueueGuard<'_, D> {
+    /// The queue's device, which is what the stack should send on
+    pub fn nic(&self) -> &D {
+        &self.queue.nic
+    }
+}
+
+impl<D> Deref for QueueGuard<'_, D> {
+    type Target = Stack;
+
+    fn deref(&self) -> &Stack {
+        &self.stack
+    }
+}
+
+impl<D> DerefMut for QueueGuard<'_, D> {
+    fn deref_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+}
+
+impl<D> Drop for QueueGuard<'_, D> {
+    fn drop(&mut self) {
+        self.queue.wake();
+    }
+}
+
+impl<D> Shared<D> {
+    fn owner_of(&self, info: &ConnectInfo) -> Option<usize> {
+        self.queues
+            .iter()
+            .position(|queue| lock(&queue.stack).connection(info).is_some())
+    }
+}
+
+impl<D> Queue<D> {
+    fn wake(&self) {
+        // A full socket means a wake up is already pending
+        let _ = self.wake.send(&[0]);
+    }
+}
+
+/// Serve one queue until the stack is dropped: receive a batch, hand each packet to the
+/// stack owning its connection, then run the timers
+fn run_queue<D, F>(shared: &Shared<D>, index: usize, wake_rx: UnixDatagram, serve: F) -> Result<()>
+where
+    D: NetworkDevice + AsRawFd,
+    F: Fn(&D, &mut Stack) -> Result<()>,
+{
+    let queue: &Queue<D> = &shared.queues[index];
+    let mut batch = RecvBatch::for_device(&queue.nic);
+
+    loop {
+        let deadline: Option<Instant> = lock(&queue.stack).next_deadline();
+        let [packet_ready, woken] = stack::wait_for_input(
+            [Some(queue.nic.as_raw_fd()), Some(wake_rx.as_raw_fd())],
+            deadline,
+        )?;
+
+        if woken {
+            let mut drained: [u8; 16] = [0; 16];
+            while matches!(wake_rx.recv(&mut drained), Ok(n) if n > 0) {}
+        }
+        if shared.stopping.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        if packet_ready {
+            batch.recv(&queue.nic)?;
+
+            let mut local: Vec<&[u8]> = Vec::new();
+            for packet in batch.packets() {
+                match foreign_owner(shared, index, packet) {
+                    Some(owner) => {
+                        let owner: &Queue<D> = &shared.queues[owner];
+                        lock(&owner.stack).on_packet(&owner.nic, packet, Instant::now())?;
+                        owner.wake();
+                    }
+                    None => local.push(packet),
+                }
+            }
+
+            lock(&queue.stack).on_packets(&queue.nic, local, Instant::now())?;
+        }
+
+        let mut stack = lock(&queue.stack);
+        serve(&queue.nic, &mut stack)?;
+        stack.on_tick(&queue.nic, Instant::now())?;
+    }
+}
+
+/// The queue other than `index` whose stack owns the connection `packet` is about, if
+/// the packet arrived on the wrong queue. Fragments can't be told apart until they're
+/// reassembled, so they stay where they are.
+fn foreign_owner<D>(shared: &Shared<D>, index: usize, packet: &[u8]) -> Option<usize> {
+    let is_fragment: bool = Ipv4HeaderSlice::from_slice(packet)
+        .is_ok_and(|ip_header| ip_header.is_fragmenting_payload());
+    if is_fragment {
+        return None;
+    }
+
+    let info: ConnectInfo = connection_of(packet)?;
+    if lock(&shared.queues[index].stack)
+        .connection(&info)
+        .is_some()
+    {
+        return None;
+    }
+
+    shared.owner_of(&info).filter(|&owner| owner != index)
+}
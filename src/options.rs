@@ -0,0 +1,301 @@
+use alloc::{vec, vec::Vec};
+
+use etherparse::TcpHeader;
+
+use crate::span::log;
+
+/// Maximum number of bytes available for options in a TCP header
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+/// End of option list
+pub const KIND_END: u8 = 0;
+/// No-operation, used for padding
+pub const KIND_NOOP: u8 = 1;
+/// Maximum segment size, RFC 9293 Section 3.7.1
+pub const KIND_MSS: u8 = 2;
+/// Window scale, RFC 7323 Section 2
+pub const KIND_WINDOW_SCALE: u8 = 3;
+/// SACK permitted, RFC 2018
+pub const KIND_SACK_PERMITTED: u8 = 4;
+/// SACK blocks, RFC 2018
+pub const KIND_SACK: u8 = 5;
+/// Timestamps, RFC 7323 Section 3
+pub const KIND_TIMESTAMPS: u8 = 8;
+/// Bytes the timestamps option takes on every segment, with the two NOPs aligning it
+pub const TIMESTAMPS_LEN: usize = 12;
+/// TCP Fast Open cookie, or a request for one when empty, RFC 7413 Section 4.1.1
+pub const KIND_FAST_OPEN: u8 = 34;
+/// Length of the Fast Open cookies the stack issues, within the 4 to 16 bytes allowed
+pub const FAST_OPEN_COOKIE_LEN: usize = 8;
+/// Option kind reserved for experiments, RFC 4727
+pub const KIND_EXPERIMENT_1: u8 = 253;
+/// Option kind reserved for experiments, RFC 4727
+pub const KIND_EXPERIMENT_2: u8 = 254;
+
+/// Option kinds the stack itself understands, which are never passed to an [`OptionHook`]
+/// (EOL, NOP, MSS, window scale, SACK permitted, SACK, timestamps, Fast Open).
+const KNOWN_KINDS: [u8; 8] = [
+    KIND_END,
+    KIND_NOOP,
+    KIND_MSS,
+    KIND_WINDOW_SCALE,
+    KIND_SACK_PERMITTED,
+    KIND_SACK,
+    KIND_TIMESTAMPS,
+    KIND_FAST_OPEN,
+];
+
+/// A single option as found on the wire
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawOption<'a> {
+    pub kind: u8,
+    /// Option data, excluding the kind and length bytes
+    pub data: &'a [u8],
+}
+
+/// Iterate the options of a TCP header.
+/// Stops at the end of list option or at the first malformed option.
+pub fn parse_options(options: &[u8]) -> impl Iterator<Item = RawOption<'_>> {
+    let mut rest: &[u8] = options;
+
+    core::iter::from_fn(move || loop {
+        let (&kind, after_kind) = rest.split_first()?;
+
+        match kind {
+            KIND_END => {
+                rest = &[];
+                return None;
+            }
+            KIND_NOOP => {
+                rest = after_kind;
+                continue;
+            }
+            _ => {
+                let (&len, _) = after_kind.split_first()?;
+                let len: usize = len as usize;
+
+                if len < 2 || len > rest.len() {
+                    rest = &[];
+                    return None;
+                }
+
+                let option = RawOption {
+                    kind,
+                    data: &rest[2..len],
+                };
+                rest = &rest[len..];
+                return Some(option);
+            }
+        }
+    })
+}
+
+/// The peer's maximum segment size from the options of its SYN.
+/// RFC 9293 Section 3.7.1
+pub fn mss(options: &[u8]) -> Option<u16> {
+    let option: RawOption = parse_options(options).find(|option| option.kind == KIND_MSS)?;
+    let mss: [u8; 2] = option.data.try_into().ok()?;
+
+    Some(u16::from_be_bytes(mss))
+}
+
+/// TSval and TSecr from a timestamps option.
+/// RFC 7323 Section 3.2
+pub fn timestamps(options: &[u8]) -> Option<(u32, u32)> {
+    let option: RawOption = parse_options(options).find(|option| option.kind == KIND_TIMESTAMPS)?;
+    let timestamps: [u8; 8] = option.data.try_into().ok()?;
+    let (ts_val, ts_ecr) = timestamps.split_at(4);
+
+    Some((
+        u32::from_be_bytes(ts_val.try_into().ok()?),
+        u32::from_be_bytes(ts_ecr.try_into().ok()?),
+    ))
+}
+
+/// Whether a SYN's options include SACK permitted, meaning the peer accepts SACK blocks.
+/// RFC 2018 Section 2
+pub fn sack_permitted(options: &[u8]) -> bool {
+    parse_options(options).any(|option| option.kind == KIND_SACK_PERMITTED)
+}
+
+/// The Fast Open cookie on a SYN, empty if the peer is asking for one.
+/// RFC 7413 Section 4.1.1
+pub fn fast_open(options: &[u8]) -> Option<&[u8]> {
+    parse_options(options)
+        .find(|option| option.kind == KIND_FAST_OPEN)
+        .map(|option| option.data)
+}
+
+/// Whether the stack handles this option kind itself
+pub fn is_known_kind(kind: u8) -> bool {
+    KNOWN_KINDS.contains(&kind)
+}
+
+/// An experimental option using a shared experiment kind and an experiment identifier.
+/// RFC 6994
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExperimentalOption {
+    /// Either [`KIND_EXPERIMENT_1`] or [`KIND_EXPERIMENT_2`]
+    pub kind: u8,
+    /// Experiment identifier (ExID)
+    pub exid: u16,
+    pub data: Vec<u8>,
+}
+
+impl ExperimentalOption {
+    /// Interpret a received option as an experimental option, if it is one
+    pub fn from_raw(option: RawOption) -> Option<Self> {
+        if option.kind != KIND_EXPERIMENT_1 && option.kind != KIND_EXPERIMENT_2 {
+            return None;
+        }
+
+        let (exid, data) = option.data.split_first_chunk::<2>()?;
+
+        Some(ExperimentalOption {
+            kind: option.kind,
+            exid: u16::from_be_bytes(*exid),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Number of bytes the option takes up on the wire
+    pub fn encoded_len(&self) -> usize {
+        4 + self.data.len()
+    }
+
+    /// Append the wire encoding of the option to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.kind);
+        buf.push(self.encoded_len() as u8);
+        buf.extend_from_slice(&self.exid.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+/// Per-connection hook letting an embedder add experimental options to outgoing
+/// segments and observe options the stack doesn't understand on incoming ones.
+pub trait OptionHook: Send {
+    /// Options to add to `header` before it is sent.
+    /// Options which don't fit in the remaining option space are dropped.
+    fn outgoing_options(&mut self, header: &TcpHeader) -> Vec<ExperimentalOption>;
+
+    /// Called for every option on an incoming segment which the stack doesn't handle
+    fn on_unknown_option(&mut self, option: RawOption);
+}
+
+/// Options requested for an outgoing segment, before it's known whether they all fit.
+///
+/// [`OutgoingOptions::encode`] lays them out in priority order, each padded to a
+/// 4 byte boundary with NOPs as Linux does:
+/// MSS, window scale, SACK permitted, timestamps, Fast Open cookie, SACK blocks, then
+/// experimental options.
+/// When space runs out, SACK blocks are dropped oldest first. RFC 2018 Section 4 requires
+/// the first block to be the most recently received, so the oldest are at the end.
+/// Anything else which doesn't fit is dropped whole.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutgoingOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// TSval and TSecr
+    pub timestamps: Option<(u32, u32)>,
+    /// Cookie for the peer to open its next connection with, RFC 7413 Section 4.1.1
+    pub fast_open_cookie: Option<[u8; FAST_OPEN_COOKIE_LEN]>,
+    /// Left and right edges of each block, most recent first
+    pub sack_blocks: Vec<(u32, u32)>,
+    pub experimental: Vec<ExperimentalOption>,
+}
+
+impl OutgoingOptions {
+    /// Encode as many of the options as fit in `max_len` bytes, which is clamped to
+    /// [`MAX_OPTIONS_LEN`]. The result is always a multiple of 4 bytes long, so it can
+    /// be described by the data offset field.
+    pub fn encode(&self, max_len: usize) -> Vec<u8> {
+        // Rounded down to a whole number of 32 bit words
+        let max_len: usize = max_len.min(MAX_OPTIONS_LEN) & !3;
+        let mut buf: Vec<u8> = Vec::with_capacity(max_len);
+
+        let push = |buf: &mut Vec<u8>, option: &[u8]| -> bool {
+            if buf.len() + option.len() > max_len {
+                return false;
+            }
+            buf.extend_from_slice(option);
+            true
+        };
+
+        if let Some(mss) = self.mss {
+            let [hi, lo] = mss.to_be_bytes();
+            push(&mut buf, &[KIND_MSS, 4, hi, lo]);
+        }
+
+        if let Some(shift) = self.window_scale {
+            push(&mut buf, &[KIND_NOOP, KIND_WINDOW_SCALE, 3, shift]);
+        }
+
+        // SACK permitted takes the place of the timestamp option's padding when both are sent
+        let mut timestamps: Vec<u8> = match self.timestamps {
+            Some((ts_val, ts_ecr)) => {
+                let mut option: Vec<u8> = vec![KIND_NOOP, KIND_NOOP, KIND_TIMESTAMPS, 10];
+                option.extend_from_slice(&ts_val.to_be_bytes());
+                option.extend_from_slice(&ts_ecr.to_be_bytes());
+                option
+            }
+            None => Vec::new(),
+        };
+
+        if self.sack_permitted {
+            if !timestamps.is_empty() && buf.len() + timestamps.len() <= max_len {
+                timestamps[..2].copy_from_slice(&[KIND_SACK_PERMITTED, 2]);
+            } else {
+                push(&mut buf, &[KIND_NOOP, KIND_NOOP, KIND_SACK_PERMITTED, 2]);
+            }
+        }
+
+        if !timestamps.is_empty() {
+            push(&mut buf, &timestamps);
+        }
+
+        if let Some(cookie) = self.fast_open_cookie {
+            let mut option: Vec<u8> =
+                vec![KIND_NOOP, KIND_NOOP, KIND_FAST_OPEN, 2 + cookie.len() as u8];
+            option.extend_from_slice(&cookie);
+            push(&mut buf, &option);
+        }
+
+        let sack_space: usize = max_len.saturating_sub(buf.len() + 4) / 8;
+        let n_blocks: usize = self.sack_blocks.len().min(sack_space);
+        if n_blocks < self.sack_blocks.len() {
+            log!(
+                "Dropping {} oldest SACK blocks. Not enough option space",
+                self.sack_blocks.len() - n_blocks
+            );
+        }
+
+        if n_blocks > 0 {
+            let mut option: Vec<u8> = vec![KIND_NOOP, KIND_NOOP, KIND_SACK, 2 + 8 * n_blocks as u8];
+            for (left, right) in &self.sack_blocks[..n_blocks] {
+                option.extend_from_slice(&left.to_be_bytes());
+                option.extend_from_slice(&right.to_be_bytes());
+            }
+            push(&mut buf, &option);
+        }
+
+        for option in &self.experimental {
+            let mut encoded: Vec<u8> = Vec::with_capacity(option.encoded_len() + 3);
+            option.encode(&mut encoded);
+            while !encoded.len().is_multiple_of(4) {
+                encoded.push(KIND_NOOP);
+            }
+
+            if option.encoded_len() > u8::MAX as usize || !push(&mut buf, &encoded) {
+                log!(
+                    "Dropping experimental option {}/{:#06x}. Not enough option space",
+                    option.kind,
+                    option.exid
+                );
+            }
+        }
+
+        buf
+    }
+}
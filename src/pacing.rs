@@ -0,0 +1,66 @@
+use core::time::Duration;
+
+use crate::clock::Instant;
+
+/// How much faster than cwnd per round trip segments are paced in slow start, in percent,
+/// so the window can still double each round trip. Linux uses the same gains.
+pub const SLOW_START_GAIN: u32 = 200;
+
+/// How much faster than cwnd per round trip segments are paced in congestion avoidance,
+/// in percent, leaving room for the window to grow
+pub const CONGESTION_AVOIDANCE_GAIN: u32 = 120;
+
+/// Spreads the segments of a window across the round trip rather than sending them back
+/// to back, so a whole window never lands on the device queue at once. Segments are sent
+/// at `gain * cwnd / SRTT`, with nothing paced until a round trip has been measured.
+///
+/// Time spent with nothing to send isn't saved up, so a connection resuming after being
+/// idle starts from one segment rather than bursting what it could have sent meanwhile.
+/// RFC 7661 Section 4.4.2 suggests pacing for just that.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pacer {
+    /// When the next segment can be sent, if it has to wait
+    next_send: Option<Instant>,
+}
+
+impl Pacer {
+    /// Time to leave after sending `len` bytes with `cwnd` bytes sent per `srtt`, faster
+    /// by `gain` percent
+    pub fn interval(len: usize, cwnd: u32, srtt: Duration, gain: u32) -> Duration {
+        let rate: u128 = cwnd.max(1) as u128 * gain as u128;
+        Duration::from_nanos((srtt.as_nanos() * len as u128 * 100 / rate) as u64)
+    }
+
+    /// When the next segment can be sent, if that's still to come
+    pub fn next_send(&self) -> Option<Instant> {
+        self.next_send
+    }
+
+    pub fn can_send(&self, now: Instant) -> bool {
+        self.next_send.is_none_or(|next_send| now >= next_send)
+    }
+
+    /// Note a segment of `len` bytes was sent at `now`, holding the next one back
+    pub fn on_send(
+        &mut self,
+        len: usize,
+        cwnd: u32,
+        srtt: Option<Duration>,
+        gain: u32,
+        now: Instant,
+    ) {
+        let Some(srtt) = srtt else {
+            self.next_send = None;
+            return;
+        };
+
+        let start: Instant = self.next_send.map_or(now, |next_send| next_send.max(now));
+        self.next_send = Some(start + Pacer::interval(len, cwnd, srtt, gain));
+    }
+
+    /// Let the next segment go straight away, such as a retransmission, or once the time
+    /// has come for it
+    pub fn release(&mut self) {
+        self.next_send = None;
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    ffi::CString,
+    io::{self, IoSlice},
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use crate::{
+    device::{self, NetworkDevice},
+    ethernet::MacAddr,
+    span::log,
+    EtherType, ETH_MTU,
+};
+
+/// packet(7), stop the socket seeing frames sent from this host, ours included.
+/// Linux 4.20 and later, not yet in `libc`.
+const PACKET_IGNORE_OUTGOING: libc::c_int = 23;
+
+/// Accept a whole frame, rather than truncating it
+const BPF_ACCEPT: u32 = u32::MAX;
+
+/// Ports a [`PacketFilter`] can check before its jumps no longer fit in a BPF instruction
+pub const MAX_FILTER_PORTS: usize = 200;
+
+/// Which frames a [`PacketSocket`] passes up, so the stack doesn't see every frame on the
+/// interface: ARP, and IPv4 TCP and ICMP to `addr`. With `ports`, TCP segments must also
+/// be for one of them. Fragments after the first can't be told apart by port and are
+/// always passed up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketFilter {
+    pub addr: Ipv4Addr,
+    /// Destination ports to accept, or empty for every port
+    pub ports: Vec<u16>,
+}
+
+impl PacketFilter {
+    /// The filter as a classic BPF program, see `SO_ATTACH_FILTER` in socket(7)
+    pub fn program(&self) -> io::Result<Vec<libc::sock_filter>> {
+        if self.ports.len() > MAX_FILTER_PORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can filter at most {MAX_FILTER_PORTS} ports"),
+            ));
+        }
+
+        let checks_ports: bool = !self.ports.is_empty();
+        let reject: usize = if checks_ports {
+            12 + self.ports.len()
+        } else {
+            8
+        };
+        let accept: usize = reject + 1;
+
+        // Offsets are from the start of the Ethernet header, IP fields start at 14
+        let mut program: Vec<libc::sock_filter> = vec![
+            stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 12),
+            jump_to(1, EtherType::Arp as u32, accept, 2),
+            jump_to(2, EtherType::Ipv4 as u32, 3, reject),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 30),
+            jump_to(4, u32::from(self.addr), 5, reject),
+            stmt(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 23),
+            jump_to(6, etherparse::IpNumber::ICMP.0 as u32, accept, 7),
+            jump_to(
+                7,
+                etherparse::IpNumber::TCP.0 as u32,
+                if checks_ports { 8 } else { accept },
+                reject,
+            ),
+        ];
+
+        if checks_ports {
+            // Non-zero fragment offset
+            program.push(stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 20));
+            program.push(filter(
+                libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+                0x1fff,
+                (accept - 10) as u8,
+                0,
+            ));
+            // X = IP header length, then the destination port follows it by 2 bytes
+            program.push(stmt(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 14));
+            program.push(stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, 16));
+            for (i, &port) in self.ports.iter().enumerate() {
+                let pc: usize = 12 + i;
+                program.push(jump_to(pc, port as u32, accept, pc + 1));
+            }
+        }
+
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, 0));
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, BPF_ACCEPT));
+        Ok(program)
+    }
+}
+
+fn filter(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    filter(code, k, 0, 0)
+}
+
+/// The instruction at `pc` comparing the accumulator with `k`, going to `if_equal` or
+/// `otherwise`. BPF jumps are relative to the next instruction.
+fn jump_to(pc: usize, k: u32, if_equal: usize, otherwise: usize) -> libc::sock_filter {
+    filter(
+        libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+        k,
+        (if_equal - pc - 1) as u8,
+        (otherwise - pc - 1) as u8,
+    )
+}
+
+/// An `AF_PACKET` socket sending and receiving Ethernet frames on a physical interface,
+/// packet(7), for running the stack on a real NIC without a tun device. Wrap it in an
+/// [`EthernetDevice`](crate::ethernet::EthernetDevice) at [`PacketSocket::mac`].
+///
+/// The stack should have an address of its own which the host doesn't use, otherwise the
+/// kernel answers the same segments too. Frames sent from this host aren't seen.
+/// On a virtual link such as veth, the peer's kernel may leave checksums for the NIC to
+/// fill in, so turn off its transmit checksum offload or those segments are dropped.
+pub struct PacketSocket {
+    fd: OwnedFd,
+    name: String,
+    mac: MacAddr,
+}
+
+impl PacketSocket {
+    /// Open a socket on the interface `name`, passing up frames accepted by `filter`.
+    /// Needs CAP_NET_RAW.
+    pub fn open(name: &str, filter: &PacketFilter) -> io::Result<Self> {
+        let c_name = CString::new(name).map_err(|_| io::ErrorKind::InvalidInput)?;
+        // SAFETY: `c_name` is a valid C string
+        let ifindex: libc::c_uint = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Protocol 0 receives nothing until it's bound, so no frame gets past before the
+        // filter is attached
+        // SAFETY: no pointers are passed
+        let fd: RawFd =
+            unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut program: Vec<libc::sock_filter> = filter.program()?;
+        let fprog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_mut_ptr(),
+        };
+        setsockopt(&fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)?;
+
+        if let Err(err) = setsockopt(&fd, libc::SOL_PACKET, PACKET_IGNORE_OUTGOING, &1) {
+            log!("Couldn't ignore outgoing frames on {name}, relying on the filter: {err}");
+        }
+
+        // SAFETY: an all zero sockaddr_ll is valid
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as libc::c_int;
+        // SAFETY: `addr` is a valid sockaddr_ll and its size is given
+        let result: libc::c_int = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&addr as *const libc::sockaddr_ll).cast::<libc::sockaddr>(),
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let request: libc::ifreq = device::interface_ioctl(name, libc::SIOCGIFHWADDR)?;
+        // SAFETY: SIOCGIFHWADDR fills in the hardware address member
+        let hwaddr: libc::sockaddr = unsafe { request.ifr_ifru.ifru_hwaddr };
+        let mut mac: [u8; 6] = [0; 6];
+        for (dst, src) in mac.iter_mut().zip(hwaddr.sa_data) {
+            *dst = src as u8;
+        }
+
+        Ok(PacketSocket {
+            fd,
+            name: name.to_string(),
+            mac: MacAddr(mac),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The interface's hardware address, which the stack should answer ARP with
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn set_non_blocking(&self) -> io::Result<()> {
+        // SAFETY: no pointers are passed
+        let flags: libc::c_int = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: no pointers are passed
+        let result: libc::c_int =
+            unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` is valid for reads of its size for the length of the call
+    let result: libc::c_int = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            (value as *const T).cast::<libc::c_void>(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl NetworkDevice for PacketSocket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: `buf` is valid for writes of its length
+        let n_read: isize = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr().cast::<libc::c_void>(),
+                buf.len(),
+                0,
+            )
+        };
+        if n_read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n_read as usize)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// Each write to a bound packet socket is one frame out of its interface
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n_bufs: libc::c_int = bufs
+            .len()
+            .try_into()
+            .map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on Unix, and every
+        // slice outlives the call
+        let n_written: isize = unsafe {
+            libc::writev(
+                self.fd.as_raw_fd(),
+                bufs.as_ptr().cast::<libc::iovec>(),
+                n_bufs,
+            )
+        };
+        if n_written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n_written as usize)
+    }
+
+    /// The interface's MTU, which is what fits in a frame after the Ethernet header
+    fn mtu(&self) -> usize {
+        match device::interface_mtu(&self.name) {
+            Ok(mtu) => mtu,
+            Err(err) => {
+                log!(
+                    "Couldn't read the MTU of {}, assuming {ETH_MTU}: {err}",
+                    self.name
+                );
+                ETH_MTU
+            }
+        }
+    }
+}
+
+impl AsRawFd for PacketSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Result, TcpError},
+    EtherType,
+};
+
+/// Magic number at the start of a pcap file with microsecond timestamps
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Magic number at the start of a pcap file with nanosecond timestamps
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Magic number at the start of a pcapng file, which isn't supported
+const MAGIC_PCAPNG: u32 = 0x0a0d_0d0a;
+
+/// Link type code for bare IP packets, <https://www.tcpdump.org/linktypes.html>
+const LINKTYPE_RAW: u32 = 101;
+
+/// Snap length written to new files, enough for any IPv4 packet
+const SNAP_LEN: u32 = u16::MAX as u32;
+
+/// Largest record accepted, well above any real snap length, so a corrupt
+/// length can't trigger a huge allocation
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+/// Link layer of the frames in a capture.
+/// <https://www.tcpdump.org/linktypes.html>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    /// BSD loopback, a 4 byte address family in host byte order
+    Null,
+    Ethernet,
+    /// Bare IP packets, as captured from a tun device
+    Raw,
+    /// Linux "cooked" capture, used for `tcpdump -i any`
+    LinuxSll,
+}
+
+impl LinkType {
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(LinkType::Null),
+            1 => Some(LinkType::Ethernet),
+            101 | 228 => Some(LinkType::Raw),
+            113 => Some(LinkType::LinuxSll),
+            _ => None,
+        }
+    }
+
+    /// The IPv4 packet carried by `frame`, if it is one
+    pub fn ipv4_packet<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let ipv4: u16 = EtherType::Ipv4 as u16;
+
+        match self {
+            LinkType::Null => {
+                let (family, packet) = frame.split_first_chunk::<4>()?;
+                // AF_INET is 2 on every platform, stored in the capturing host's byte order
+                let is_inet: bool =
+                    u32::from_le_bytes(*family) == 2 || u32::from_be_bytes(*family) == 2;
+                is_inet.then_some(packet)
+            }
+            LinkType::Ethernet => {
+                let (header, packet) = frame.split_first_chunk::<14>()?;
+                (u16::from_be_bytes([header[12], header[13]]) == ipv4).then_some(packet)
+            }
+            LinkType::Raw => (frame.first()? >> 4 == 4).then_some(frame),
+            LinkType::LinuxSll => {
+                let (header, packet) = frame.split_first_chunk::<16>()?;
+                (u16::from_be_bytes([header[14], header[15]]) == ipv4).then_some(packet)
+            }
+        }
+    }
+}
+
+/// A single captured frame
+pub struct PcapRecord {
+    /// Capture time, since the Unix epoch
+    pub timestamp: Duration,
+    /// Frame as captured, starting at the link layer header
+    pub data: Vec<u8>,
+}
+
+/// Reads records from a classic (not pcapng) pcap file.
+/// <https://datatracker.ietf.org/doc/draft-ietf-opsawg-pcap/>
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: LinkType,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the file header, failing if the file isn't a pcap with a supported link type
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header: [u8; 24] = [0; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic: [u8; 4] = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos): (bool, bool) =
+            match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (MAGIC_MICROS, _) => (false, false),
+                (MAGIC_NANOS, _) => (false, true),
+                (_, MAGIC_MICROS) => (true, false),
+                (_, MAGIC_NANOS) => (true, true),
+                (_, MAGIC_PCAPNG) => {
+                    return Err(TcpError::Parse(
+                        "pcapng files aren't supported, convert with `editcap -F pcap`".to_string(),
+                    ))
+                }
+                _ => {
+                    return Err(TcpError::Parse(format!(
+                        "Not a pcap file, bad magic number {magic:02x?}"
+                    )))
+                }
+            };
+
+        let mut pcap = PcapReader {
+            reader,
+            big_endian,
+            nanos,
+            link_type: LinkType::Raw,
+        };
+
+        let link_type_code: u32 = pcap.u32_at(&header, 20) & 0x0fff_ffff;
+        pcap.link_type = match LinkType::from_code(link_type_code) {
+            Some(link_type) => link_type,
+            None => {
+                return Err(TcpError::Parse(format!(
+                    "Unsupported pcap link type {link_type_code}"
+                )))
+            }
+        };
+
+        Ok(pcap)
+    }
+
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    /// The next record, or `None` at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<PcapRecord>> {
+        let mut header: [u8; 16] = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+
+        let secs: u32 = self.u32_at(&header, 0);
+        let fraction: u32 = self.u32_at(&header, 4);
+        let captured_len: usize = self.u32_at(&header, 8) as usize;
+
+        if captured_len > MAX_RECORD_LEN {
+            return Err(TcpError::Parse(format!(
+                "pcap record of {captured_len} bytes is too large, the file is likely corrupt"
+            )));
+        }
+
+        let mut data: Vec<u8> = vec![0; captured_len];
+        self.reader.read_exact(&mut data)?;
+
+        let nanos: u32 = if self.nanos {
+            fraction
+        } else {
+            fraction.saturating_mul(1000)
+        };
+
+        Ok(Some(PcapRecord {
+            timestamp: Duration::new(secs as u64, nanos),
+            data,
+        }))
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let bytes: [u8; 4] = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+/// Writes bare IP packets to a classic pcap file with microsecond timestamps, which
+/// [`PcapReader`] and Wireshark can both open
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header: Vec<u8> = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        // Version 2.4
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Time zone offset and timestamp accuracy, both unused
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAP_LEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(PcapWriter { writer })
+    }
+
+    /// Append one packet, captured at `time`
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch: Duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len: u32 = packet.len() as u32;
+
+        let mut header: Vec<u8> = Vec::with_capacity(16);
+        header.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        header.extend_from_slice(&len.min(SNAP_LEN).to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer
+            .write_all(&packet[..packet.len().min(SNAP_LEN as usize)])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,120 @@
+use core::time::Duration;
+
+use crate::{clock::Instant, span::log};
+
+/// Smallest MTU every IPv4 link must support, RFC 791
+pub const MIN_PATH_MTU: usize = 68;
+
+/// Common MTUs to fall back through when the real one isn't known.
+/// RFC 1191 Section 7, Table 7-1
+pub const PLATEAUS: [usize; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+/// How long a lowered path MTU is kept before the link MTU is tried again, in case the
+/// route has changed. RFC 1191 Section 6.3
+pub const PATH_MTU_AGING: Duration = Duration::from_secs(10 * 60);
+
+/// Consecutive timeouts of a segment too large for the next plateau before the path is
+/// assumed to be a black hole dropping it without sending an ICMP error.
+/// RFC 2923 Section 2.1
+pub const BLACK_HOLE_RETRANSMISSIONS: u32 = 2;
+
+/// Bytes of IPv4 and TCP header, without options, in every segment
+const HEADERS_LEN: usize = 40;
+
+/// The largest plateau below `mtu`, never less than [`MIN_PATH_MTU`]
+pub fn plateau_below(mtu: usize) -> usize {
+    PLATEAUS
+        .into_iter()
+        .find(|&plateau| plateau < mtu)
+        .unwrap_or(MIN_PATH_MTU)
+}
+
+/// Path MTU discovery state for one connection.
+/// RFC 1191
+///
+/// Every segment is sent with DF set. The path MTU starts at the link MTU and only
+/// drops when a router reports Fragmentation Needed, or when full sized segments
+/// keep going unacknowledged without any report. After [`PATH_MTU_AGING`] the link
+/// MTU is tried again.
+#[derive(Clone, Copy, Debug)]
+pub struct PathMtu {
+    link_mtu: usize,
+    mtu: usize,
+    /// When the path MTU was last lowered below the link MTU
+    lowered_at: Option<Instant>,
+}
+
+impl PathMtu {
+    pub fn new(link_mtu: usize) -> Self {
+        PathMtu {
+            link_mtu,
+            mtu: link_mtu,
+            lowered_at: None,
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Largest payload which fits in a segment without options
+    pub fn mss(&self) -> usize {
+        self.mtu - HEADERS_LEN
+    }
+
+    /// Largest payload which fits in a segment without options once the path MTU drops
+    /// to the next plateau
+    pub fn plateau_mss(&self) -> usize {
+        plateau_below(self.mtu) - HEADERS_LEN
+    }
+
+    /// Take the MTU reported by a router in a Fragmentation Needed message.
+    /// Routers predating RFC 1191 report zero, in which case the next plateau is used.
+    /// The path MTU only ever drops in response, reports of larger or impossible MTUs
+    /// are ignored. Returns whether it dropped.
+    pub fn on_fragmentation_needed(&mut self, next_hop_mtu: u16, now: Instant) -> bool {
+        let next_hop_mtu: usize = match next_hop_mtu {
+            0 => plateau_below(self.mtu),
+            mtu => mtu.into(),
+        };
+
+        if next_hop_mtu < MIN_PATH_MTU || next_hop_mtu >= self.mtu {
+            return false;
+        }
+
+        self.lower(next_hop_mtu, now);
+        true
+    }
+
+    /// Drop to the next plateau after full sized segments keep being lost without
+    /// any ICMP error. Returns whether it dropped.
+    pub fn on_black_hole(&mut self, now: Instant) -> bool {
+        if self.mtu <= MIN_PATH_MTU {
+            return false;
+        }
+
+        self.lower(plateau_below(self.mtu), now);
+        true
+    }
+
+    /// Go back to the link MTU once a lowered path MTU has aged out, so a larger MTU
+    /// is discovered if the path now allows it
+    pub fn expire(&mut self, now: Instant) {
+        if self
+            .lowered_at
+            .is_some_and(|lowered_at| now.saturating_duration_since(lowered_at) >= PATH_MTU_AGING)
+        {
+            log!("Path MTU aged out, trying {} again", self.link_mtu);
+            self.mtu = self.link_mtu;
+            self.lowered_at = None;
+        }
+    }
+
+    fn lower(&mut self, mtu: usize, now: Instant) {
+        log!("Path MTU lowered from {} to {mtu}", self.mtu);
+        self.mtu = mtu;
+        self.lowered_at = Some(now);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use crate::tcp::{ConnectInfo, State, Tcb};
+
+/// Something which happened on a connection, see [`Stack::poll`](crate::stack::Stack::poll)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub connection: ConnectInfo,
+    /// There's data to read, or the peer has closed and reading returns the end of the
+    /// stream
+    pub readable: bool,
+    /// [`Tcb::send`] will take at least some data
+    pub writable: bool,
+    /// A listener accepted the connection, and it's established
+    pub accepted: bool,
+    /// The connection has been deleted from the stack
+    pub closed: bool,
+    /// It was deleted because the peer reset it
+    pub reset: bool,
+}
+
+impl Event {
+    fn new(connection: ConnectInfo) -> Self {
+        Event {
+            connection,
+            readable: false,
+            writable: false,
+            accepted: false,
+            closed: false,
+            reset: false,
+        }
+    }
+}
+
+/// Whether a read would return data or the end of the stream
+fn is_readable(tcb: &Tcb) -> bool {
+    let peer_finished: bool = matches!(
+        tcb.state(),
+        State::CloseWait | State::Closing | State::LastAck | State::TimeWait
+    );
+    tcb.readable_len() > 0 || peer_finished
+}
+
+fn is_writable(tcb: &Tcb) -> bool {
+    matches!(tcb.state(), State::Estab | State::CloseWait) && tcb.send_space() > 0
+}
+
+/// What's been reported on each connection, so each change is only reported once.
+/// Only kept once the stack has been polled.
+#[derive(Default)]
+pub(crate) struct Poller {
+    /// Whether each connection was readable and writable when last polled
+    reported: HashMap<ConnectInfo, (bool, bool)>,
+    /// Connections deleted since the last poll, and whether the peer reset them
+    deleted: Vec<(ConnectInfo, bool)>,
+}
+
+impl Poller {
+    /// Follow a connection we opened from the start, so it's reported even if it's
+    /// refused before the next poll
+    pub(crate) fn on_connect(&mut self, info: ConnectInfo) {
+        self.reported.insert(info, (false, false));
+    }
+
+    /// Note that a connection is being deleted, to be reported by the next poll if it's
+    /// been followed
+    pub(crate) fn on_delete(&mut self, info: &ConnectInfo, tcb: &Tcb) {
+        if self.reported.remove(info).is_some() {
+            self.deleted.push((*info, tcb.was_reset()));
+        }
+    }
+
+    /// Stop following a connection the application deleted itself
+    pub(crate) fn forget(&mut self, info: &ConnectInfo) {
+        self.reported.remove(info);
+    }
+
+    /// Add an event to `events` for every connection which has changed since the last poll
+    pub(crate) fn poll<'a>(
+        &mut self,
+        connections: impl Iterator<Item = (&'a ConnectInfo, &'a Tcb)>,
+        events: &mut Vec<Event>,
+    ) {
+        for (info, reset) in self.deleted.drain(..) {
+            events.push(Event {
+                closed: true,
+                reset,
+                ..Event::new(info)
+            });
+        }
+
+        for (info, tcb) in connections {
+            let (accepted, (was_readable, was_writable)) = match self.reported.get(info) {
+                Some(&reported) => (false, reported),
+                None if !tcb.passive_open() => (false, (false, false)),
+                None if tcb.state().is_synchronised() => (true, (false, false)),
+                // Still opening, so not yet accepted
+                None => continue,
+            };
+
+            let readable: bool = is_readable(tcb);
+            let writable: bool = is_writable(tcb);
+            self.reported.insert(*info, (readable, writable));
+
+            let event = Event {
+                readable: readable && !was_readable,
+                writable: writable && !was_writable,
+                accepted,
+                ..Event::new(*info)
+            };
+            if event.readable || event.writable || event.accepted {
+                events.push(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    io::IoSlice,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use crate::PACKET_BUF_SIZE;
+
+/// Most free buffers a pool made with [`PacketPool::default`] keeps hold of
+pub const DEFAULT_MAX_IDLE: usize = 1024;
+
+/// Fixed size packet buffers, reused rather than allocated for every packet.
+///
+/// Buffers are handed out as [`PacketBuf`]s, which go back to the pool when dropped.
+/// Packets too large for the pool's buffers still get one, allocated to fit and freed
+/// once dropped. Clones share the same buffers.
+#[derive(Clone)]
+pub struct PacketPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    buf_len: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+    n_allocated: AtomicUsize,
+}
+
+impl PacketPool {
+    /// A pool of `buf_len` byte buffers, keeping at most `max_idle` of them once freed
+    pub fn new(buf_len: usize, max_idle: usize) -> Self {
+        PacketPool {
+            inner: Arc::new(PoolInner {
+                buf_len,
+                max_idle,
+                idle: Mutex::new(Vec::new()),
+                n_allocated: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// The pool shared by everything in the process which doesn't bring its own
+    pub fn shared() -> PacketPool {
+        static SHARED: OnceLock<PacketPool> = OnceLock::new();
+        SHARED.get_or_init(PacketPool::default).clone()
+    }
+
+    /// Size of each pooled buffer
+    pub fn buf_len(&self) -> usize {
+        self.inner.buf_len
+    }
+
+    /// Free buffers waiting to be reused
+    pub fn idle(&self) -> usize {
+        self.lock_idle().len()
+    }
+
+    /// Buffers allocated by the pool so far, including those too large to be pooled
+    pub fn allocated(&self) -> usize {
+        self.inner.n_allocated.load(Ordering::Relaxed)
+    }
+
+    /// An empty buffer with room for at least `len` bytes
+    pub fn take(&self, len: usize) -> PacketBuf {
+        let reused: Option<Box<[u8]>> = if len <= self.inner.buf_len {
+            self.lock_idle().pop()
+        } else {
+            None
+        };
+        let buf: Box<[u8]> = reused.unwrap_or_else(|| {
+            self.inner.n_allocated.fetch_add(1, Ordering::Relaxed);
+            vec![0; len.max(self.inner.buf_len)].into_boxed_slice()
+        });
+
+        PacketBuf {
+            buf,
+            len: 0,
+            pool: Arc::clone(&self.inner),
+        }
+    }
+
+    /// A copy of `packet`
+    pub fn copy(&self, packet: &[u8]) -> PacketBuf {
+        self.gather(&[IoSlice::new(packet)])
+    }
+
+    /// The pieces of a packet copied one after another into a single buffer
+    pub fn gather(&self, bufs: &[IoSlice<'_>]) -> PacketBuf {
+        let mut packet: PacketBuf = self.take(bufs.iter().map(|buf| buf.len()).sum());
+        for buf in bufs {
+            packet.buf[packet.len..packet.len + buf.len()].copy_from_slice(buf);
+            packet.len += buf.len();
+        }
+        packet
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<Box<[u8]>>> {
+        self.inner.lock_idle()
+    }
+}
+
+impl Default for PacketPool {
+    /// Buffers large enough for an Ethernet sized packet and its header
+    fn default() -> Self {
+        PacketPool::new(PACKET_BUF_SIZE, DEFAULT_MAX_IDLE)
+    }
+}
+
+impl PoolInner {
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<Box<[u8]>>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A packet in a buffer from a [`PacketPool`], which it goes back to when dropped.
+/// Derefs to the bytes filled so far.
+pub struct PacketBuf {
+    buf: Box<[u8]>,
+    len: usize,
+    pool: Arc<PoolInner>,
+}
+
+impl PacketBuf {
+    /// Most bytes the buffer can hold
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The whole buffer, for a device to receive into before [`PacketBuf::set_len`]
+    pub fn spare_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Keep the first `len` bytes of the buffer as the packet
+    ///
+    /// # Panics
+    ///
+    /// If `len` is larger than the buffer
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.buf.len(), "{len} bytes won't fit in the buffer");
+        self.len = len;
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        // Oversized buffers aren't worth keeping
+        if self.buf.len() != self.pool.buf_len {
+            return;
+        }
+
+        let mut idle = self.pool.lock_idle();
+        if idle.len() < self.pool.max_idle {
+            idle.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
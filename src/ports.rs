@@ -0,0 +1,101 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+/// Dynamic ports, which active opens are given when they don't ask for one.
+/// RFC 6335 Section 6
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// What a local port is being used for, see [`Stack::port_state`](crate::stack::Stack::port_state)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortState {
+    /// A listener accepts connections on it
+    Listening,
+    /// Connections opened with `connect` are using it, and segments for any other
+    /// connection are answered as if it were closed
+    Connected,
+    /// Nothing uses it, segments to it are answered by the closed port policy
+    Free,
+}
+
+/// Local ports held by connections we opened, and the ephemeral ports handed out to them.
+///
+/// Listening ports live in [`Listeners`](crate::listener::Listeners). A port here belongs
+/// to its connections alone, so a SYN to it from anywhere else isn't accepted even when
+/// listening on every port.
+pub struct PortTable {
+    /// Connections opened on each port
+    connected: HashMap<u16, usize>,
+    ephemeral: RangeInclusive<u16>,
+    /// Where the search for a free ephemeral port starts, so ports aren't reused
+    /// any sooner than they have to be
+    next_ephemeral: u16,
+}
+
+impl PortTable {
+    /// Hand out ephemeral ports from `ephemeral`
+    pub fn new(ephemeral: RangeInclusive<u16>) -> Self {
+        PortTable {
+            connected: HashMap::new(),
+            next_ephemeral: *ephemeral.start(),
+            ephemeral,
+        }
+    }
+
+    pub fn ephemeral(&self) -> RangeInclusive<u16> {
+        self.ephemeral.clone()
+    }
+
+    /// Whether connections we opened are using `port`
+    pub fn is_connected(&self, port: u16) -> bool {
+        self.connected.contains_key(&port)
+    }
+
+    /// Connections we opened on `port`
+    pub fn connections(&self, port: u16) -> usize {
+        self.connected.get(&port).copied().unwrap_or(0)
+    }
+
+    /// Record a connection opened on `port`
+    pub fn bind(&mut self, port: u16) {
+        *self.connected.entry(port).or_insert(0) += 1;
+    }
+
+    /// Record a connection on `port` going away, freeing the port after its last one
+    pub fn release(&mut self, port: u16) {
+        if let Some(n_connections) = self.connected.get_mut(&port) {
+            *n_connections -= 1;
+            if *n_connections == 0 {
+                self.connected.remove(&port);
+            }
+        }
+    }
+
+    /// The next ephemeral port which isn't connected and for which `is_listening` is false,
+    /// or `None` if every one is taken. The port isn't bound until [`PortTable::bind`].
+    pub fn allocate(&mut self, is_listening: impl Fn(u16) -> bool) -> Option<u16> {
+        if self.ephemeral.is_empty() {
+            return None;
+        }
+
+        let (first, last) = (*self.ephemeral.start(), *self.ephemeral.end());
+        let n_ports: usize = self.ephemeral.len();
+
+        let mut port: u16 = self.next_ephemeral.clamp(first, last);
+        for _ in 0..n_ports {
+            let candidate: u16 = port;
+            port = if port == last { first } else { port + 1 };
+
+            if !self.is_connected(candidate) && !is_listening(candidate) {
+                self.next_ephemeral = port;
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for PortTable {
+    fn default() -> Self {
+        PortTable::new(EPHEMERAL_PORTS)
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+    os::fd::AsRawFd,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    async_stack::{block_on, AsyncStack, AsyncTcpStream},
+    cli::{Forward, Reverse},
+    device::NetworkDevice,
+    error::{Result, TcpError},
+    listener::ListenerLimits,
+    span::log,
+};
+
+/// Bytes read from one side before they're written to the other
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How long an acceptor waits after running out of descriptors or memory, so it doesn't
+/// spin until some are freed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How many bytes a bridged connection carried each way, see [`bridge`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bridged {
+    /// From the stack's peer to the host connection
+    pub to_upstream: u64,
+    /// From the host connection to the stack's peer
+    pub from_upstream: u64,
+}
+
+/// Relays connections between the stack and host sockets, for each [`Forward`] and
+/// [`Reverse`] rule. Every rule has a thread accepting connections, and every bridged
+/// connection a pair of threads, one for each direction.
+///
+/// The stack runs for as long as the proxy does. The threads blocked on host listeners
+/// can't be interrupted though, so the proxy is meant to run until the process exits.
+pub struct Proxy<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    stack: Arc<AsyncStack<D>>,
+    reverse_addrs: Vec<SocketAddr>,
+    acceptors: Vec<JoinHandle<Result<()>>>,
+}
+
+impl<D> Proxy<D>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    /// Start listening for every rule. Host listeners are bound before this returns, so
+    /// failing to bind is reported here.
+    pub fn spawn(stack: AsyncStack<D>, forwards: &[Forward], reverses: &[Reverse]) -> Result<Self> {
+        let stack = Arc::new(stack);
+        let mut acceptors: Vec<JoinHandle<Result<()>>> = Vec::new();
+
+        for &forward in forwards {
+            let listener = stack.listen(forward.port, ListenerLimits::default());
+            acceptors.push(thread::spawn(move || loop {
+                match block_on(listener.accept()) {
+                    Ok(stream) => {
+                        thread::spawn(move || forward_connection(stream, forward));
+                    }
+                    Err(err) => recover_accept(err, forward.port)?,
+                }
+            }));
+        }
+
+        let mut reverse_addrs: Vec<SocketAddr> = Vec::with_capacity(reverses.len());
+        for &reverse in reverses {
+            let listener = TcpListener::bind(reverse.listen).map_err(|err| {
+                TcpError::Failed(format!("Failed to listen on {}: {err}", reverse.listen))
+            })?;
+            reverse_addrs.push(listener.local_addr()?);
+
+            let stack: Arc<AsyncStack<D>> = Arc::clone(&stack);
+            acceptors.push(thread::spawn(move || loop {
+                match listener.accept() {
+                    Ok((upstream, _)) => {
+                        let stack: Arc<AsyncStack<D>> = Arc::clone(&stack);
+                        thread::spawn(move || reverse_connection(&stack, upstream, reverse));
+                    }
+                    Err(err) => recover_accept(err, reverse.listen.port())?,
+                }
+            }));
+        }
+
+        Ok(Proxy {
+            stack,
+            reverse_addrs,
+            acceptors,
+        })
+    }
+
+    /// The stack connections are relayed through
+    pub fn stack(&self) -> &AsyncStack<D> {
+        &self.stack
+    }
+
+    /// Where each [`Reverse`] rule is listening, in order, which tells the port picked for
+    /// a rule listening on port 0
+    pub fn reverse_addrs(&self) -> &[SocketAddr] {
+        &self.reverse_addrs
+    }
+
+    /// Wait for the threads accepting connections, which only return if a listener can no
+    /// longer accept. Failures for a single connection are logged and skipped.
+    pub fn join(self) -> Result<()> {
+        for acceptor in self.acceptors {
+            acceptor
+                .join()
+                .map_err(|_| TcpError::Failed("Proxy thread panicked".into()))??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Log a failure to accept a connection on `port` and carry on, unless the listener can't
+/// accept any more, such as when the stack has stopped
+fn recover_accept(err: io::Error, port: u16) -> io::Result<()> {
+    match err.kind() {
+        // The connection was gone before it was accepted
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => {
+            log!("Failed to accept a connection on port {port}: {err}");
+            Ok(())
+        }
+        _ => match err.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => {
+                log!("Failed to accept a connection on port {port}, backing off: {err}");
+                thread::sleep(ACCEPT_BACKOFF);
+                Ok(())
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+/// Bridge a connection the stack accepted to a new host connection to the rule's upstream,
+/// resetting it if the upstream can't be reached
+fn forward_connection<D>(stream: AsyncTcpStream<D>, forward: Forward)
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    let info = stream.info();
+    match TcpStream::connect(forward.upstream) {
+        Ok(upstream) => match bridge(&stream, &upstream) {
+            Ok(bridged) => log!("Bridged {info} to {}: {bridged:?}", forward.upstream),
+            Err(err) => log!("Bridging {info} to {} failed: {err}", forward.upstream),
+        },
+        Err(err) => {
+            log!(
+                "Failed to connect to {} for {info}: {err}",
+                forward.upstream
+            );
+            let _ = stream.abort();
+        }
+    }
+}
+
+/// Bridge a connection the host listener accepted to a new connection from the stack.
+/// The host connection is closed if the stack's can't be opened.
+fn reverse_connection<D>(stack: &AsyncStack<D>, upstream: TcpStream, reverse: Reverse)
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    let local = SocketAddrV4::new(reverse.local, 0);
+    match block_on(stack.connect(local, reverse.remote)) {
+        Ok(stream) => match bridge(&stream, &upstream) {
+            Ok(bridged) => log!(
+                "Bridged {} to {}: {bridged:?}",
+                reverse.listen,
+                stream.info()
+            ),
+            Err(err) => log!(
+                "Bridging {} to {} failed: {err}",
+                reverse.listen,
+                stream.info()
+            ),
+        },
+        Err(err) => log!(
+            "Failed to connect to {} for {}: {err}",
+            reverse.remote,
+            reverse.listen
+        ),
+    }
+}
+
+/// Copy data both ways between `stream` and `upstream` until both directions have closed.
+///
+/// A FIN from one side shuts down writing on the other, so half closes pass through, and
+/// a reset or failure on one side tears down the other. Writes wait for room, so a slow
+/// reader on either side holds the sender back through the windows in between.
+pub fn bridge<D>(stream: &AsyncTcpStream<D>, upstream: &TcpStream) -> io::Result<Bridged>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    thread::scope(|scope| {
+        let inbound = scope.spawn(|| copy_to_stream(upstream, stream));
+        let to_upstream: io::Result<u64> = copy_to_upstream(stream, upstream);
+        let from_upstream: io::Result<u64> = inbound
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("bridge thread panicked")));
+
+        Ok(Bridged {
+            to_upstream: to_upstream?,
+            from_upstream: from_upstream?,
+        })
+    })
+}
+
+/// Copy from the stack's connection to the host's until the peer closes
+fn copy_to_upstream<D>(stream: &AsyncTcpStream<D>, mut upstream: &TcpStream) -> io::Result<u64>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    let mut buf: Vec<u8> = vec![0; CHUNK_SIZE];
+    let mut n_copied: u64 = 0;
+
+    loop {
+        let n_read: usize = match block_on(stream.read(&mut buf)) {
+            Ok(n_read) => n_read,
+            // Reset by the peer, which the host connection should see as well
+            Err(err) => {
+                let _ = upstream.shutdown(Shutdown::Both);
+                return Err(err);
+            }
+        };
+        if n_read == 0 {
+            // The connection is only gone, rather than half closed, if it timed out
+            let how = match stream.option(|_| ()) {
+                Ok(()) => Shutdown::Write,
+                Err(_) => Shutdown::Both,
+            };
+            let _ = upstream.shutdown(how);
+            return Ok(n_copied);
+        }
+
+        if let Err(err) = upstream.write_all(&buf[..n_read]) {
+            let _ = stream.abort();
+            return Err(err);
+        }
+        n_copied += n_read as u64;
+    }
+}
+
+/// Copy from the host's connection to the stack's until the host closes
+fn copy_to_stream<D>(mut upstream: &TcpStream, stream: &AsyncTcpStream<D>) -> io::Result<u64>
+where
+    D: NetworkDevice + AsRawFd + Send + Sync + 'static,
+{
+    let mut buf: Vec<u8> = vec![0; CHUNK_SIZE];
+    let mut n_copied: u64 = 0;
+
+    loop {
+        let n_read: usize = match upstream.read(&mut buf) {
+            Ok(n_read) => n_read,
+            Err(err) => {
+                let _ = stream.abort();
+                return Err(err);
+            }
+        };
+        if n_read == 0 {
+            block_on(stream.shutdown())?;
+            return Ok(n_copied);
+        }
+
+        if let Err(err) = block_on(stream.write_all(&buf[..n_read])) {
+            let _ = upstream.shutdown(Shutdown::Both);
+            return Err(err);
+        }
+        n_copied += n_read as u64;
+    }
+}
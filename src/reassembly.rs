@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpFragOffset, IpNumber, Ipv4Header, Ipv4HeaderSlice};
+
+/// How long the fragments of a datagram are held waiting for the rest, matching Linux.
+/// RFC 1122 Section 3.3.2
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most datagrams reassembled at once, so a flood of first fragments can't exhaust memory
+pub const MAX_PENDING_DATAGRAMS: usize = 64;
+
+/// Largest payload an IPv4 datagram can carry
+const MAX_PAYLOAD_LEN: usize = u16::MAX as usize - Ipv4Header::MIN_LEN;
+
+/// Identifies the fragments belonging to one datagram.
+/// RFC 791 Section 3.2
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub id: u16,
+    pub protocol: IpNumber,
+}
+
+impl FragmentKey {
+    pub fn of(ip_header: &Ipv4HeaderSlice) -> Self {
+        FragmentKey {
+            src: ip_header.source_addr(),
+            dst: ip_header.destination_addr(),
+            id: ip_header.identification(),
+            protocol: ip_header.protocol(),
+        }
+    }
+}
+
+/// Why a datagram was abandoned before it was complete
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    /// A fragment overlapped part of the datagram already received with different data,
+    /// or disagreed about where it ends. Overlaps are never resolved, as they are
+    /// only ever used to slip data past filters.
+    Overlap,
+    /// The fragments would make a datagram longer than IPv4 allows
+    TooLong,
+    /// Too many other datagrams are already being reassembled
+    TooManyDatagrams,
+}
+
+/// The fragments of one datagram received so far
+struct PartialDatagram {
+    /// Header of the fragment at offset zero, once it has arrived
+    header: Option<Ipv4Header>,
+    data: Vec<u8>,
+    /// Ranges of `data` received, sorted and merged where they touch
+    received: Vec<Range<usize>>,
+    /// Length of the whole payload, known once the last fragment arrives
+    total_len: Option<usize>,
+    deadline: Instant,
+}
+
+impl PartialDatagram {
+    /// Add a fragment, returning `Ok(false)` for an exact duplicate
+    fn insert(&mut self, range: Range<usize>, payload: &[u8]) -> Result<bool, ReassemblyError> {
+        for received in &self.received {
+            if received.start < range.end && range.start < received.end {
+                let is_duplicate: bool = received.start <= range.start
+                    && range.end <= received.end
+                    && self.data[range.clone()] == *payload;
+
+                return match is_duplicate {
+                    true => Ok(false),
+                    false => Err(ReassemblyError::Overlap),
+                };
+            }
+        }
+
+        if self.data.len() < range.end {
+            self.data.resize(range.end, 0);
+        }
+        self.data[range.clone()].copy_from_slice(payload);
+
+        let position: usize = self
+            .received
+            .partition_point(|received| received.start < range.start);
+        self.received.insert(position, range);
+
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.received.len());
+        for range in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        self.received = merged;
+
+        Ok(true)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self.total_len.is_some_and(|total_len| {
+                self.received.len() == 1 && self.received[0] == (0..total_len)
+            })
+    }
+
+    /// Serialise the whole datagram, using the first fragment's header.
+    /// RFC 791 Section 3.2
+    fn assemble(mut self) -> Vec<u8> {
+        let mut header: Ipv4Header = self.header.take().unwrap_or_default();
+        header.more_fragments = false;
+        header.fragment_offset = IpFragOffset::ZERO;
+        header.total_len = (header.header_len() + self.data.len()) as u16;
+        header.header_checksum = header.calc_header_checksum();
+
+        let mut packet: Vec<u8> = Vec::with_capacity(header.total_len as usize);
+        packet.extend_from_slice(&header.to_bytes());
+        packet.extend_from_slice(&self.data);
+        packet
+    }
+}
+
+/// Reassembles fragmented IPv4 datagrams before they reach TCP.
+/// RFC 791 Section 3.2, RFC 1122 Section 3.3.2
+pub struct Reassembler {
+    datagrams: HashMap<FragmentKey, PartialDatagram>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            datagrams: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Number of datagrams waiting for more fragments
+    pub fn pending(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Add a fragment, with `payload` being exactly its data.
+    /// Returns the whole datagram, header included, once every fragment has arrived.
+    /// On an error the datagram is dropped along with every fragment received for it.
+    pub fn push(
+        &mut self,
+        ip_header: &Ipv4HeaderSlice,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        let key = FragmentKey::of(ip_header);
+        let offset: usize = ip_header.fragments_offset().value() as usize * 8;
+        let range: Range<usize> = offset..offset + payload.len();
+        let is_last: bool = !ip_header.more_fragments();
+
+        if !self.datagrams.contains_key(&key) && self.datagrams.len() >= MAX_PENDING_DATAGRAMS {
+            return Err(ReassemblyError::TooManyDatagrams);
+        }
+
+        let timeout: Duration = self.timeout;
+        let datagram: &mut PartialDatagram =
+            self.datagrams
+                .entry(key)
+                .or_insert_with(|| PartialDatagram {
+                    header: None,
+                    data: Vec::new(),
+                    received: Vec::new(),
+                    total_len: None,
+                    deadline: now + timeout,
+                });
+
+        // The last fragment fixes where the datagram ends, which every other has to agree with
+        let conflicts_with_end: bool = match datagram.total_len {
+            Some(total_len) => range.end > total_len || (is_last && range.end != total_len),
+            None => is_last && datagram.data.len() > range.end,
+        };
+
+        let result: Result<bool, ReassemblyError> = if range.end > MAX_PAYLOAD_LEN {
+            Err(ReassemblyError::TooLong)
+        } else if conflicts_with_end {
+            Err(ReassemblyError::Overlap)
+        } else {
+            datagram.insert(range.clone(), payload)
+        };
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(err) => {
+                self.datagrams.remove(&key);
+                return Err(err);
+            }
+        }
+
+        if is_last {
+            datagram.total_len = Some(range.end);
+        }
+        if offset == 0 {
+            datagram.header = Some(ip_header.to_header());
+        }
+
+        if !datagram.is_complete() {
+            return Ok(None);
+        }
+
+        Ok(self.datagrams.remove(&key).map(PartialDatagram::assemble))
+    }
+
+    /// When the oldest incomplete datagram expires, if there is one
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.datagrams
+            .values()
+            .map(|datagram| datagram.deadline)
+            .min()
+    }
+
+    /// Drop every datagram which hasn't been completed by `now`, returning how many
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let n_pending: usize = self.datagrams.len();
+        self.datagrams.retain(|_, datagram| datagram.deadline > now);
+        n_pending - self.datagrams.len()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(REASSEMBLY_TIMEOUT)
+    }
+}
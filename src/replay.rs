@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Read},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeader, TcpOptionElement};
+
+use crate::{
+    analyze,
+    clock::{Clock, SimulatedClock},
+    device::CaptureDevice,
+    error::{Result, TcpError},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    pcap::PcapReader,
+    stack::Stack,
+    stats::StackStats,
+    tcp::{self, ConnectInfo, State},
+};
+
+/// One of the peer's packets from a capture, fed through the stack
+pub struct ReplayStep {
+    /// Position of the packet in the capture, starting from 1
+    pub packet_number: usize,
+    /// Time since the first packet in the capture
+    pub elapsed: Duration,
+    /// The peer's IPv4 packet
+    pub inbound: Vec<u8>,
+    /// Packets the stack sent, including any sent by timers which expired since the
+    /// previous packet
+    pub sent: Vec<Vec<u8>>,
+}
+
+/// Result of replaying a peer's side of a capture through a [`Stack`]
+pub struct Replay {
+    /// The local address the peer was talking to, taken from the first SYN
+    pub local_addr: Option<Ipv4Addr>,
+    pub steps: Vec<ReplayStep>,
+    /// Packets which weren't IPv4 packets sent to `local_addr`,
+    /// including the original endpoint's own responses
+    pub skipped: usize,
+    /// The stack's counters once the whole capture has been replayed
+    pub stats: StackStats,
+}
+
+/// Feed every packet sent to the local endpoint in a capture through a [`Stack`] as if it
+/// had been read from the tun device, recording what the stack sends back. Nothing is
+/// sent on the network.
+///
+/// Unlike [`analyze::analyze`], this goes through the whole stack, so reassembly, ICMP,
+/// listeners and closed ports are covered too. The stack accepts connections on any port
+/// and serves them like the daemon, reading everything and closing once the peer has.
+/// Timers run on the capture's clock.
+pub fn replay(reader: impl Read) -> Result<Replay> {
+    let mut pcap = PcapReader::new(reader)?;
+    let link_type = pcap.link_type();
+
+    let mut packets: Vec<(Duration, Vec<u8>)> = Vec::new();
+    let mut skipped: usize = 0;
+
+    while let Some(record) = pcap.next_record()? {
+        match link_type.ipv4_packet(&record.data) {
+            Some(packet) => packets.push((record.timestamp, packet.to_vec())),
+            None => {
+                skipped += 1;
+                packets.push((record.timestamp, Vec::new()));
+            }
+        }
+    }
+
+    let local_addr: Option<Ipv4Addr> = analyze::find_local_addr(&packets);
+
+    // Connections run on the capture's clock
+    let clock = Arc::new(SimulatedClock::new());
+    let start: Instant = clock.now();
+    let device = CaptureDevice::default();
+    // A fixed secret, sequence numbers in the transcript are relative anyway
+    let mut stack = Stack::new(
+        Listeners::accept_any(ListenerLimits::default()),
+        IsnGenerator::new([0; 16]),
+        start,
+    );
+    stack.set_clock(clock.clone());
+    let mut translations = HashMap::<ConnectInfo, Translation>::new();
+    let mut steps: Vec<ReplayStep> = Vec::new();
+
+    let first_timestamp: Duration = packets.first().map_or(Duration::ZERO, |(ts, _)| *ts);
+
+    for (i, (timestamp, packet)) in packets.iter().enumerate() {
+        if packet.is_empty() {
+            continue;
+        }
+
+        let to_local: bool = Ipv4HeaderSlice::from_slice(packet)
+            .is_ok_and(|ip_header| Some(ip_header.destination_addr()) == local_addr);
+        if !to_local {
+            // The captured endpoint's SYN-ACK gives the ISN the peer's ACKs are relative to
+            if let Some((info, true, seq)) = connection_from_peer(packet) {
+                translations.entry(info).or_default().captured_isn = Some(seq);
+            }
+            skipped += 1;
+            continue;
+        }
+
+        let elapsed: Duration = timestamp.saturating_sub(first_timestamp);
+        let now: Instant = start + elapsed;
+        clock.advance_to(now);
+
+        if stack
+            .next_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            stack.on_tick(&device, now)?;
+        }
+
+        let inbound: Vec<u8> = translate(&translations, packet);
+        stack.on_packet(&device, &inbound, now)?;
+        serve_connections(&device, &mut stack)?;
+
+        let sent: Vec<Vec<u8>> = device.take_sent();
+        for packet in &sent {
+            record_sent(&mut translations, packet);
+        }
+
+        steps.push(ReplayStep {
+            packet_number: i + 1,
+            elapsed,
+            inbound,
+            sent,
+        });
+    }
+
+    Ok(Replay {
+        local_addr,
+        steps,
+        skipped,
+        stats: stack.stats(),
+    })
+}
+
+/// How to rewrite the peer's segments on one connection, which acknowledge and echo
+/// what the captured endpoint sent rather than what the stack sends
+#[derive(Default)]
+struct Translation {
+    /// ISN of the endpoint which was originally captured
+    captured_isn: Option<u32>,
+    /// ISN the stack chose
+    isn: Option<u32>,
+    /// Latest timestamp the stack sent, echoed in place of the captured endpoint's
+    ts_val: Option<u32>,
+}
+
+/// The connection a segment is on as seen from the peer, whether it's a SYN and its
+/// sequence number
+fn connection_from_peer(packet: &[u8]) -> Option<(ConnectInfo, bool, u32)> {
+    let (ip_header, tcp_header, _) = analyze::decode(packet)?;
+
+    let info = ConnectInfo {
+        src_addr: ip_header.destination_addr(),
+        src_port: tcp_header.destination_port(),
+        dst_addr: ip_header.source_addr(),
+        dst_port: tcp_header.source_port(),
+    };
+
+    Some((info, tcp_header.syn(), tcp_header.sequence_number()))
+}
+
+/// Note the ISN and latest timestamp of a segment the stack sent
+fn record_sent(translations: &mut HashMap<ConnectInfo, Translation>, packet: &[u8]) {
+    let Some((info, syn, seq)) = connection_from_peer(packet) else {
+        return;
+    };
+    let translation: &mut Translation = translations.entry(info).or_default();
+
+    if syn {
+        translation.isn = Some(seq);
+    }
+
+    let Some((_, tcp_header, _)) = analyze::decode(packet) else {
+        return;
+    };
+    for option in tcp_header.options_iterator().flatten() {
+        if let TcpOptionElement::Timestamp(ts_val, _) = option {
+            translation.ts_val = Some(ts_val);
+        }
+    }
+}
+
+/// The peer's packet with its acknowledgment number, SACK blocks and timestamp echo moved
+/// from the captured endpoint's sequence space to the stack's. Packets on connections
+/// whose ISNs aren't both known yet, and fragments, are passed through unchanged.
+fn translate(translations: &HashMap<ConnectInfo, Translation>, packet: &[u8]) -> Vec<u8> {
+    let Ok(ip_header) = Ipv4HeaderSlice::from_slice(packet) else {
+        return packet.to_vec();
+    };
+    if ip_header.is_fragmenting_payload() {
+        return packet.to_vec();
+    }
+
+    let Some((ip_header, tcp_header, data)) = analyze::decode(packet) else {
+        return packet.to_vec();
+    };
+
+    let info = ConnectInfo {
+        src_addr: ip_header.source_addr(),
+        src_port: tcp_header.source_port(),
+        dst_addr: ip_header.destination_addr(),
+        dst_port: tcp_header.destination_port(),
+    };
+    let Some(Translation {
+        captured_isn: Some(captured_isn),
+        isn: Some(isn),
+        ts_val,
+    }) = translations.get(&info)
+    else {
+        return packet.to_vec();
+    };
+
+    let offset: u32 = isn.wrapping_sub(*captured_isn);
+    let shift = |(left, right): (u32, u32)| (left.wrapping_add(offset), right.wrapping_add(offset));
+
+    let mut header: TcpHeader = tcp_header.to_header();
+    if header.ack {
+        header.acknowledgment_number = header.acknowledgment_number.wrapping_add(offset);
+    }
+
+    let options: Vec<TcpOptionElement> = header
+        .options_iterator()
+        .flatten()
+        .map(|option| match option {
+            TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                TcpOptionElement::SelectiveAcknowledgement(
+                    shift(first),
+                    rest.map(|block| block.map(shift)),
+                )
+            }
+            TcpOptionElement::Timestamp(peer_ts_val, ts_ecr) => {
+                TcpOptionElement::Timestamp(peer_ts_val, ts_val.unwrap_or(ts_ecr))
+            }
+            option => option,
+        })
+        .collect();
+    // Same options in the same order, so they fit in the same space
+    if header.set_options(&options).is_err() {
+        return packet.to_vec();
+    }
+
+    let Ok(checksum) =
+        header.calc_checksum_ipv4_raw(ip_header.source(), ip_header.destination(), data)
+    else {
+        return packet.to_vec();
+    };
+    header.checksum = checksum;
+
+    let mut translated: Vec<u8> = ip_header.slice().to_vec();
+    header
+        .write(&mut translated)
+        .expect("writing to a Vec doesn't fail");
+    translated.extend_from_slice(data);
+    translated
+}
+
+/// Matches the daemon, which reads everything it receives straight away and closes as
+/// soon as the peer has finished
+fn serve_connections(device: &CaptureDevice, stack: &mut Stack) -> Result<()> {
+    let mut finished: Vec<ConnectInfo> = Vec::new();
+
+    for (info, tcb) in stack.connections_mut() {
+        let mut received: Vec<u8> = vec![0; tcb.unread_len()];
+        tcb.read(&mut received);
+
+        if tcb.state() == State::CloseWait {
+            finished.push(*info);
+        }
+    }
+
+    for info in finished {
+        stack.close(device, &info)?;
+    }
+
+    Ok(())
+}
+
+impl Replay {
+    /// One line per packet, the peer's prefixed with its packet number and the stack's
+    /// responses indented below it.
+    ///
+    /// Sequence and acknowledgment numbers are relative to the first sequence number
+    /// seen in each direction, as in Wireshark, so the transcript doesn't depend on the
+    /// ISN the stack chose and can be checked into a regression test.
+    pub fn transcript(&self) -> String {
+        let mut isns = HashMap::<(SocketAddrV4, SocketAddrV4), u32>::new();
+        let mut lines: Vec<String> = Vec::new();
+
+        for step in &self.steps {
+            lines.push(format!(
+                "#{} {}",
+                step.packet_number,
+                describe(&mut isns, &step.inbound)
+            ));
+            for packet in &step.sent {
+                lines.push(format!("    {}", describe(&mut isns, packet)));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    /// Compare the transcript with `expected`, failing on the first line which differs.
+    /// Trailing whitespace and blank lines at the end are ignored.
+    pub fn check(&self, expected: &str) -> Result<()> {
+        let transcript: String = self.transcript();
+        let actual: Vec<&str> = transcript.lines().map(str::trim_end).collect();
+        let expected: Vec<&str> = expected.trim_end().lines().map(str::trim_end).collect();
+
+        for (i, (actual, expected)) in actual.iter().zip(&expected).enumerate() {
+            if actual != expected {
+                return Err(TcpError::Failed(format!(
+                    "transcript differs at line {}\nexpected: {expected}\n  actual: {actual}",
+                    i + 1
+                )));
+            }
+        }
+
+        if actual.len() != expected.len() {
+            return Err(TcpError::Failed(format!(
+                "transcript has {} lines, expected {}",
+                actual.len(),
+                expected.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// One line summary of a packet, with sequence numbers made relative to `isns`, which
+/// is updated with the first sequence number of each new direction and of every SYN
+fn describe(isns: &mut HashMap<(SocketAddrV4, SocketAddrV4), u32>, packet: &[u8]) -> String {
+    let Some((ip_header, tcp_header, data)) = analyze::decode(packet) else {
+        return format!("{} bytes, not a TCP segment", packet.len());
+    };
+
+    let src = SocketAddrV4::new(ip_header.source_addr(), tcp_header.source_port());
+    let dst = SocketAddrV4::new(ip_header.destination_addr(), tcp_header.destination_port());
+
+    let seq: u32 = tcp_header.sequence_number();
+    if tcp_header.syn() {
+        isns.insert((src, dst), seq);
+    }
+    let isn: u32 = *isns.entry((src, dst)).or_insert(seq);
+
+    let flags: String = [
+        (tcp_header.syn(), 'S'),
+        (tcp_header.fin(), 'F'),
+        (tcp_header.rst(), 'R'),
+        (tcp_header.psh(), 'P'),
+        (tcp_header.urg(), 'U'),
+        (tcp_header.ack(), '.'),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+
+    let ack: String = match (tcp_header.ack(), isns.get(&(dst, src))) {
+        (false, _) => String::new(),
+        (true, Some(peer_isn)) => format!(
+            " ack={}",
+            tcp_header.acknowledgment_number().wrapping_sub(*peer_isn)
+        ),
+        (true, None) => format!(" ack={}", tcp_header.acknowledgment_number()),
+    };
+
+    format!(
+        "{src} > {dst} [{flags}] seq={}{ack} win={} len={}",
+        seq.wrapping_sub(isn),
+        tcp_header.window_size(),
+        tcp::segment_len(&tcp_header, data),
+    )
+}
+
+/// Replay the capture at `pcap_path`. With `expected_path`, check the transcript against
+/// the one in that file, otherwise print it.
+pub fn run_and_report(pcap_path: &str, expected_path: Option<&str>) -> Result<()> {
+    let file = File::open(pcap_path)?;
+    let replay = replay(BufReader::new(file))?;
+
+    match expected_path {
+        Some(expected_path) => {
+            replay.check(&fs::read_to_string(expected_path)?)?;
+            println!(
+                "{} packets replayed, transcript matches {expected_path}",
+                replay.steps.len()
+            );
+        }
+        None => println!("{}", replay.transcript()),
+    }
+
+    Ok(())
+}
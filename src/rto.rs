@@ -0,0 +1,160 @@
+use core::time::Duration;
+
+/// Retransmission timeout used before any round trip time has been measured.
+/// RFC 6298 Section 2.1
+pub const DEFAULT_INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Smallest initial timeout which can be configured, for labs with sub-millisecond paths
+pub const MIN_INITIAL_RTO: Duration = Duration::from_millis(200);
+
+/// Upper bound on the timeout however far it has backed off, unless a higher one is
+/// configured. RFC 6298 Section 2.5
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Timeout to fall back to once the handshake completes if the SYN had to be
+/// retransmitted with an initial timeout shorter than this.
+/// RFC 6298 Section 5.7
+pub const SYN_TIMEOUT_FALLBACK_RTO: Duration = Duration::from_secs(3);
+
+/// Granularity of the clock used to measure round trips, G in RFC 6298
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Retransmission timeout estimator.
+/// RFC 6298
+///
+/// ```text
+/// First measurement R:
+///     SRTT <- R
+///     RTTVAR <- R/2
+/// Subsequent measurements R':
+///     RTTVAR <- (1 - 1/4) * RTTVAR + 1/4 * |SRTT - R'|
+///     SRTT <- (1 - 1/8) * SRTT + 1/8 * R'
+/// Then:
+///     RTO <- SRTT + max (G, 4*RTTVAR)
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RtoEstimator {
+    initial: Duration,
+    max: Duration,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    /// Times the timer has expired since the last measurement
+    backoffs: u32,
+}
+
+impl RtoEstimator {
+    /// An estimator starting from `initial`, clamped to [`MIN_INITIAL_RTO`]..=[`MAX_RTO`].
+    /// RFC 6298 Section 2.4 puts a floor of 1 second under every timeout, which is
+    /// lowered to match a shorter initial timeout.
+    pub fn new(initial: Duration) -> Self {
+        RtoEstimator::with_max(initial, MAX_RTO)
+    }
+
+    /// An estimator starting from `initial` which backs off no further than `max`.
+    /// RFC 6298 Section 2.5 only allows a maximum of at least [`MAX_RTO`], so `max` is
+    /// raised to it if lower.
+    pub fn with_max(initial: Duration, max: Duration) -> Self {
+        let max: Duration = max.max(MAX_RTO);
+        let initial: Duration = initial.clamp(MIN_INITIAL_RTO, max);
+
+        RtoEstimator {
+            initial,
+            max,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: initial,
+            backoffs: 0,
+        }
+    }
+
+    /// The current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn initial(&self) -> Duration {
+        self.initial
+    }
+
+    /// Longest the timeout backs off to
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Smoothed round trip time, once one has been measured
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    pub fn backoffs(&self) -> u32 {
+        self.backoffs
+    }
+
+    fn min_rto(&self) -> Duration {
+        self.initial.min(DEFAULT_INITIAL_RTO)
+    }
+
+    /// Take a round trip time measured from a segment which was never retransmitted.
+    /// RFC 6298 Section 2.2 and 2.3
+    ///
+    /// The first measurement after backing off replaces the backed off timeout,
+    /// as Karn's algorithm only holds on to it until a valid sample arrives.
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        let srtt: Duration = self.srtt.unwrap_or(rtt);
+        self.rto = (srtt + CLOCK_GRANULARITY.max(self.rttvar * 4)).clamp(self.min_rto(), self.max);
+        self.backoffs = 0;
+    }
+
+    /// Carry on from an estimate made elsewhere, such as by the process a connection was
+    /// handed over from, as if its samples had been taken here
+    pub fn resume(&mut self, srtt: Duration, rttvar: Duration) {
+        self.srtt = Some(srtt);
+        self.rttvar = rttvar;
+        self.rto = (srtt + CLOCK_GRANULARITY.max(rttvar * 4)).clamp(self.min_rto(), self.max);
+        self.backoffs = 0;
+    }
+
+    /// Back off after the retransmission timer expires.
+    /// RFC 6298 Section 5.5, RTO <- RTO * 2
+    pub fn on_timeout(&mut self) {
+        self.rto = (self.rto * 2).min(self.max);
+        self.backoffs += 1;
+    }
+
+    /// Called once the handshake completes, with whether the SYN was retransmitted.
+    /// RFC 6298 Section 5.7
+    /// ```text
+    /// If the timer expires awaiting the ACK of a SYN segment and the
+    /// TCP implementation is using an RTO less than 3 seconds, the RTO
+    /// MUST be re-initialized to 3 seconds when data transmission
+    /// begins (i.e., after the three-way handshake completes).
+    /// ```
+    pub fn on_established(&mut self, syn_retransmitted: bool) {
+        if syn_retransmitted && self.srtt.is_none() && self.initial < SYN_TIMEOUT_FALLBACK_RTO {
+            self.rto = SYN_TIMEOUT_FALLBACK_RTO;
+            self.backoffs = 0;
+        }
+    }
+}
+
+impl Default for RtoEstimator {
+    fn default() -> Self {
+        RtoEstimator::new(DEFAULT_INITIAL_RTO)
+    }
+}
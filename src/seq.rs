@@ -0,0 +1,121 @@
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+
+/// A TCP sequence number, which lives in a space of 2^32 numbers that wraps around.
+///
+/// Arithmetic wraps, and ordering is serial number arithmetic from RFC 1982: a number is
+/// before another if it's less than 2^31 behind it going around the space. Two numbers
+/// exactly 2^31 apart are neither before nor after each other. There's no `Ord`, as the
+/// order isn't transitive across the whole space.
+/// RFC 9293 Section 3.4, RFC 1982 Section 3
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SeqNum(u32);
+
+impl SeqNum {
+    pub const fn new(value: u32) -> Self {
+        SeqNum(value)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// How far `self` is ahead of `other`, negative if it's behind
+    fn offset_from(self, other: SeqNum) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+
+    /// Before `other`, RFC 1982's `s1 < s2`
+    pub fn lt(self, other: SeqNum) -> bool {
+        other.offset_from(self) > 0
+    }
+
+    pub fn le(self, other: SeqNum) -> bool {
+        self == other || self.lt(other)
+    }
+
+    /// After `other`, RFC 1982's `s1 > s2`
+    pub fn gt(self, other: SeqNum) -> bool {
+        self.offset_from(other) > 0
+    }
+
+    pub fn ge(self, other: SeqNum) -> bool {
+        self == other || self.gt(other)
+    }
+
+    /// Whether `start < self < end` going around the space from `start`. Unlike the
+    /// comparisons this works for ranges of any size, and is empty if `start == end`.
+    pub fn is_between(self, start: SeqNum, end: SeqNum) -> bool {
+        let offset: u32 = self - start;
+        offset > 0 && offset < end - start
+    }
+
+    /// Whether `self` is one of the `len` numbers starting at `start`, as with a segment
+    /// or window, `start <= self < start + len`
+    pub fn is_in_window(self, start: SeqNum, len: u32) -> bool {
+        self - start < len
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(value: u32) -> Self {
+        SeqNum(value)
+    }
+}
+
+impl From<SeqNum> for u32 {
+    fn from(seq: SeqNum) -> Self {
+        seq.0
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(n))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, n: u32) {
+        *self = *self + n;
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(n))
+    }
+}
+
+impl SubAssign<u32> for SeqNum {
+    fn sub_assign(&mut self, n: u32) {
+        *self = *self - n;
+    }
+}
+
+/// The distance going forward from `other` to `self`, which wraps if `self` is behind
+impl Sub for SeqNum {
+    type Output = u32;
+
+    fn sub(self, other: SeqNum) -> u32 {
+        self.0.wrapping_sub(other.0)
+    }
+}
+
+impl fmt::Debug for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{
+    device::NetworkDevice,
+    error::{Result, TcpError},
+    health::{Health, HealthLimits, HealthUsage},
+    icmp,
+    reassembly::Reassembler,
+    span::log,
+    stack::{self, Stack},
+    stats::{ConnectionSummary, StatsRecorder, StatsSnapshot},
+    tcp::ConnectInfo,
+};
+
+/// Serves a [`Stack`]'s connections from a pool of worker threads.
+///
+/// Each shard is a [`Stack`] of its own, run by one worker and owning the connections
+/// whose 4-tuple hashes to it. The thread reading the device reassembles fragments, then
+/// hands each datagram to its connection's shard, so segments for one connection are
+/// always processed in order. ICMP errors go to the shard of the connection they quote.
+/// Anything which doesn't belong to a connection goes to the first shard.
+///
+/// Workers send on the device directly, which is shared between them.
+/// Listener limits and the challenge ACK limit apply to each shard separately.
+pub struct ShardedStack<D>
+where
+    D: NetworkDevice + Send + Sync + 'static,
+{
+    nic: Arc<D>,
+    shards: Vec<Shard>,
+    hasher: RandomState,
+    reassembler: Reassembler,
+    /// Counters for packets before they reach a shard, the reads and reassembly
+    stats: StatsRecorder,
+    /// Limits for the shards together, rather than each shard's own
+    health_limits: HealthLimits,
+}
+
+struct Shard {
+    stack: Arc<Mutex<Stack>>,
+    /// Datagrams for the worker, dropped to stop it
+    packets: Option<Sender<Vec<u8>>>,
+    worker: Option<JoinHandle<Result<()>>>,
+}
+
+impl<D> ShardedStack<D>
+where
+    D: NetworkDevice + Send + Sync + 'static,
+{
+    /// Start `n_shards` workers, each with a stack from `new_stack`.
+    /// After every datagram or timer a worker calls `serve` on its stack, which is where
+    /// the application reads and writes the shard's connections.
+    pub fn spawn<F>(
+        nic: Arc<D>,
+        n_shards: usize,
+        mut new_stack: impl FnMut() -> Result<Stack>,
+        serve: F,
+    ) -> Result<Self>
+    where
+        F: Fn(&D, &mut Stack) -> Result<()> + Clone + Send + 'static,
+    {
+        if n_shards == 0 {
+            return Err(TcpError::InvalidInput(
+                "a sharded stack needs at least one shard",
+            ));
+        }
+
+        let mut shards: Vec<Shard> = Vec::with_capacity(n_shards);
+        for i in 0..n_shards {
+            let stack = Arc::new(Mutex::new(new_stack()?));
+            let (packets, worker_packets) = mpsc::channel();
+
+            let worker_nic: Arc<D> = Arc::clone(&nic);
+            let worker_stack: Arc<Mutex<Stack>> = Arc::clone(&stack);
+            let serve: F = serve.clone();
+            let worker: JoinHandle<Result<()>> = thread::Builder::new()
+                .name(format!("shard-{i}"))
+                .spawn(move || run_shard(&*worker_nic, &worker_stack, worker_packets, serve))?;
+
+            shards.push(Shard {
+                stack,
+                packets: Some(packets),
+                worker: Some(worker),
+            });
+        }
+
+        Ok(ShardedStack {
+            nic,
+            shards,
+            hasher: RandomState::new(),
+            reassembler: Reassembler::default(),
+            stats: StatsRecorder::new(Instant::now()),
+            health_limits: HealthLimits::default(),
+        })
+    }
+
+    pub fn n_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard owning the connection, as seen on segments from the peer
+    pub fn shard_of(&self, info: &ConnectInfo) -> usize {
+        (self.hasher.hash_one(info) % self.shards.len() as u64) as usize
+    }
+
+    /// Lock the stack of one shard. Its worker waits until the guard is dropped.
+    pub fn shard(&self, index: usize) -> MutexGuard<'_, Stack> {
+        lock(&self.shards[index].stack)
+    }
+
+    /// Pass one packet read from the device to the shard it belongs to
+    pub fn on_packet(&mut self, packet: &[u8], now: Instant) -> Result<()> {
+        self.stats.stack.packets_in += 1;
+
+        if stack::is_truncated(&mut self.stats.stack, packet) {
+            return Ok(());
+        }
+
+        let Some(datagram) =
+            stack::reassemble(&mut self.reassembler, &mut self.stats.stack, packet, now)
+        else {
+            return Ok(());
+        };
+
+        let index: usize = connection_of(&datagram).map_or(0, |info| self.shard_of(&info));
+        let shard: &mut Shard = &mut self.shards[index];
+
+        let delivered: bool = shard
+            .packets
+            .as_ref()
+            .is_some_and(|packets| packets.send(datagram.into_owned()).is_ok());
+        if delivered {
+            return Ok(());
+        }
+
+        // The worker only hangs up if it failed
+        match shard.worker.take().map(JoinHandle::join) {
+            Some(Ok(Err(err))) => Err(TcpError::WorkerStopped {
+                index,
+                error: Some(Box::new(err)),
+            }),
+            _ => Err(TcpError::WorkerStopped { index, error: None }),
+        }
+    }
+
+    /// The next time `on_tick` has work to do, if any. Shards run their own timers.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.reassembler.next_deadline()
+    }
+
+    /// Drop fragmented datagrams which weren't completed in time
+    pub fn on_tick(&mut self, now: Instant) {
+        let n_expired: usize = self.reassembler.expire(now);
+        if n_expired > 0 {
+            log!("Dropping {n_expired} fragmented datagrams which weren't completed in time");
+            self.stats.stack.reassembly_failures += n_expired as u64;
+        }
+    }
+
+    /// Read every counter across the shards, resetting them to zero if `reset` is set.
+    /// Shards are read one after another, so the intervals only roughly line up.
+    pub fn snapshot_stats(&mut self, now: Instant, reset: bool) -> StatsSnapshot {
+        let mut snapshot: StatsSnapshot = self.stats.snapshot(&mut HashMap::new(), now, reset);
+
+        for shard in &self.shards {
+            snapshot.merge(lock(&shard.stack).snapshot_stats(now, reset));
+        }
+
+        snapshot
+    }
+
+    /// Every connection across the shards, see [`Stack::connection_summaries`]
+    pub fn connection_summaries(&self) -> Vec<ConnectionSummary> {
+        let mut summaries: Vec<ConnectionSummary> = self
+            .shards
+            .iter()
+            .flat_map(|shard| lock(&shard.stack).connection_summaries())
+            .collect();
+        summaries.sort_by_key(|summary| (summary.connection.src_addr, summary.connection.src_port));
+        summaries
+    }
+
+    /// Abort a connection in whichever shard owns it, see [`Stack::abort`]
+    pub fn abort(&self, info: &ConnectInfo) -> Result<()> {
+        self.shard(self.shard_of(info)).abort(&*self.nic, info)
+    }
+
+    pub fn set_health_limits(&mut self, limits: HealthLimits) {
+        self.health_limits = limits;
+    }
+
+    /// Resource usage summed across the shards, against the limits for all of them
+    pub fn health(&self) -> Health {
+        let mut usage = HealthUsage {
+            timers: self.reassembler.pending(),
+            device_queue: self.nic.queued_packets(),
+            ..HealthUsage::default()
+        };
+
+        for shard in &self.shards {
+            usage.merge(lock(&shard.stack).health_usage());
+        }
+
+        Health::new(usage, self.health_limits)
+    }
+
+    pub fn nic(&self) -> &D {
+        &self.nic
+    }
+}
+
+impl<D> Drop for ShardedStack<D>
+where
+    D: NetworkDevice + Send + Sync + 'static,
+{
+    /// Stop every worker. Connections which are still open go with them.
+    fn drop(&mut self) {
+        for shard in &mut self.shards {
+            shard.packets = None;
+        }
+
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            if let Some(Ok(Err(err))) = shard.worker.take().map(JoinHandle::join) {
+                log!("Shard {index} failed: {err}");
+            }
+        }
+    }
+}
+
+/// Process datagrams for one shard until the dispatcher hangs up, running its timers in
+/// between
+fn run_shard<D, F>(
+    nic: &D,
+    stack: &Mutex<Stack>,
+    packets: Receiver<Vec<u8>>,
+    serve: F,
+) -> Result<()>
+where
+    D: NetworkDevice,
+    F: Fn(&D, &mut Stack) -> Result<()>,
+{
+    loop {
+        let deadline: Option<Instant> = lock(stack).next_deadline();
+
+        let datagram: Option<Vec<u8>> = match deadline {
+            Some(deadline) => {
+                match packets.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(datagram) => Some(datagram),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+            None => match packets.recv() {
+                Ok(datagram) => Some(datagram),
+                Err(_) => return Ok(()),
+            },
+        };
+
+        let mut stack = lock(stack);
+        if let Some(datagram) = datagram {
+            stack.handle_packet(nic, &datagram)?;
+        }
+        serve(nic, &mut stack)?;
+        stack.on_tick(nic, Instant::now())?;
+    }
+}
+
+/// The connection a datagram is about, as seen on segments from the peer, if any
+pub(crate) fn connection_of(datagram: &[u8]) -> Option<ConnectInfo> {
+    let ip_header = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+
+    // Anything past the IP total length is padding
+    let header_len: usize = ip_header.slice().len();
+    let payload: &[u8] =
+        &datagram[header_len..(ip_header.total_len() as usize).clamp(header_len, datagram.len())];
+
+    match ip_header.protocol() {
+        IpNumber::TCP => {
+            let tcp_header = TcpHeaderSlice::from_slice(payload).ok()?;
+            Some(ConnectInfo {
+                src_addr: ip_header.source_addr(),
+                src_port: tcp_header.source_port(),
+                dst_addr: ip_header.destination_addr(),
+                dst_port: tcp_header.destination_port(),
+            })
+        }
+        IpNumber::ICMP => icmp::parse_error(&ip_header, payload).map(|message| message.connection),
+        _ => None,
+    }
+}
+
+pub(crate) fn lock(stack: &Mutex<Stack>) -> MutexGuard<'_, Stack> {
+    stack
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
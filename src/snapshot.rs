@@ -0,0 +1,448 @@
+use alloc::{format, vec::Vec};
+use core::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use crate::{
+    error::{Result, TcpError},
+    tcp::{KeepaliveConfig, State, UnreadDataPolicy},
+};
+
+/// Bytes at the start of an encoded [`StackSnapshot`]
+const MAGIC: [u8; 4] = *b"TCPS";
+
+/// Version of the encoding, bumped whenever [`ConnectionState`] changes
+const VERSION: u16 = 1;
+
+/// What a connection needs to carry on in another process, from [`Tcb::save_state`].
+/// Sequence numbers are as in [`ConnectionSummary`](crate::stats::ConnectionSummary).
+///
+/// Timers aren't kept as instants, which mean nothing to another process, only what was
+/// left of the ones with a fixed length. [`Tcb::restore`] re-arms the rest from the time
+/// it's restored. Counters, hooks and soft errors the application hadn't taken are left
+/// behind.
+///
+/// [`Tcb::save_state`]: crate::tcp::Tcb::save_state
+/// [`Tcb::restore`]: crate::tcp::Tcb::restore
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionState {
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub state: State,
+    pub passive_open: bool,
+
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    /// SND.MAX, the highest sequence number sent
+    pub snd_max: u32,
+    pub snd_wnd: u32,
+    pub snd_wl1: u32,
+    pub snd_wl2: u32,
+    pub iss: u32,
+    /// Shift applied to the window field of the peer's segments
+    pub snd_scale: u8,
+    pub rcv_nxt: u32,
+    pub rcv_wnd: u32,
+    pub irs: u32,
+    /// Shift applied to the window field of our segments
+    pub rcv_scale: u8,
+
+    /// Largest segment the peer accepts
+    pub send_mss: u16,
+    /// MSS option sent on our SYN, if any
+    pub recv_mss: Option<u16>,
+    /// Our next TSval and TS.Recent, if timestamps were negotiated
+    pub timestamps: Option<(u32, u32)>,
+    pub sack_permitted: bool,
+
+    /// Data from SND.UNA on, sent and unacknowledged and then still to be sent
+    pub send_buffer: Vec<u8>,
+    /// Offsets into `send_buffer` just after the end of each `send`, where segments are
+    /// pushed
+    pub push_points: Vec<u64>,
+    /// Whether our FIN is waiting for the data before it to be sent
+    pub fin_queued: bool,
+    /// Data received in order and not yet read
+    pub recv_buffer: Vec<u8>,
+    /// Bytes at the front of `recv_buffer` up to the last segment with PSH set
+    pub recv_pushed: usize,
+    pub recv_shutdown: bool,
+
+    pub recv_buffer_size: usize,
+    pub send_buffer_size: usize,
+    pub recv_low_water: usize,
+    pub nodelay: bool,
+    pub pacing: bool,
+    pub dsack_enabled: bool,
+    pub unread_data_policy: UnreadDataPolicy,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub linger: Option<Duration>,
+    pub user_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub time_wait: Duration,
+
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub cwnd: u32,
+    pub ssthresh: u32,
+
+    /// What was left of TIME-WAIT
+    pub time_wait_remaining: Option<Duration>,
+    /// What was left of a lingering close before it resets the connection
+    pub linger_remaining: Option<Duration>,
+}
+
+/// Every connection a stack is carrying, from [`Stack::snapshot`], to be handed to
+/// [`Stack::restore`] in a new process. [`StackSnapshot::to_bytes`] gives something to
+/// write to a file or pass over a socket.
+///
+/// [`Stack::snapshot`]: crate::stack::Stack::snapshot
+/// [`Stack::restore`]: crate::stack::Stack::restore
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackSnapshot {
+    pub connections: Vec<ConnectionState>,
+}
+
+impl StackSnapshot {
+    /// Encode the snapshot. Integers are big endian, durations are seconds and
+    /// nanoseconds, and anything optional or variable in length is prefixed with whether
+    /// it's there or how long it is.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.buf.extend_from_slice(&MAGIC);
+        encoder.u16(VERSION);
+        encoder.u32(self.connections.len() as u32);
+        for connection in &self.connections {
+            encoder.connection(connection);
+        }
+
+        encoder.buf
+    }
+
+    /// Decode a snapshot from [`StackSnapshot::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder { bytes };
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(TcpError::Parse("Not a connection snapshot".into()));
+        }
+        let version: u16 = decoder.u16()?;
+        if version != VERSION {
+            return Err(TcpError::Parse(format!(
+                "Unsupported snapshot version {version}, expected {VERSION}"
+            )));
+        }
+
+        let n_connections: u32 = decoder.u32()?;
+        let mut connections: Vec<ConnectionState> = Vec::new();
+        for _ in 0..n_connections {
+            connections.push(decoder.connection()?);
+        }
+        if !decoder.bytes.is_empty() {
+            return Err(TcpError::Parse(format!(
+                "{} bytes left over after the snapshot",
+                decoder.bytes.len()
+            )));
+        }
+
+        Ok(StackSnapshot { connections })
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn connection(&mut self, connection: &ConnectionState) {
+        self.addr(connection.local);
+        self.addr(connection.remote);
+        self.u8(state_code(connection.state));
+        self.bool(connection.passive_open);
+
+        self.u32(connection.snd_una);
+        self.u32(connection.snd_nxt);
+        self.u32(connection.snd_max);
+        self.u32(connection.snd_wnd);
+        self.u32(connection.snd_wl1);
+        self.u32(connection.snd_wl2);
+        self.u32(connection.iss);
+        self.u8(connection.snd_scale);
+        self.u32(connection.rcv_nxt);
+        self.u32(connection.rcv_wnd);
+        self.u32(connection.irs);
+        self.u8(connection.rcv_scale);
+
+        self.u16(connection.send_mss);
+        self.option(connection.recv_mss, Encoder::u16);
+        self.option(connection.timestamps, |encoder, (ts_val, recent)| {
+            encoder.u32(ts_val);
+            encoder.u32(recent);
+        });
+        self.bool(connection.sack_permitted);
+
+        self.bytes(&connection.send_buffer);
+        self.u32(connection.push_points.len() as u32);
+        for &point in &connection.push_points {
+            self.u64(point);
+        }
+        self.bool(connection.fin_queued);
+        self.bytes(&connection.recv_buffer);
+        self.u64(connection.recv_pushed as u64);
+        self.bool(connection.recv_shutdown);
+
+        self.u64(connection.recv_buffer_size as u64);
+        self.u64(connection.send_buffer_size as u64);
+        self.u64(connection.recv_low_water as u64);
+        self.bool(connection.nodelay);
+        self.bool(connection.pacing);
+        self.bool(connection.dsack_enabled);
+        self.bool(connection.unread_data_policy == UnreadDataPolicy::Fin);
+        self.option(connection.keepalive, |encoder, keepalive| {
+            encoder.duration(keepalive.idle);
+            encoder.duration(keepalive.interval);
+            encoder.u32(keepalive.probes);
+        });
+        self.option(connection.linger, Encoder::duration);
+        self.option(connection.user_timeout, Encoder::duration);
+        self.option(connection.idle_timeout, Encoder::duration);
+        self.duration(connection.time_wait);
+
+        self.option(connection.srtt, Encoder::duration);
+        self.duration(connection.rttvar);
+        self.u32(connection.cwnd);
+        self.u32(connection.ssthresh);
+
+        self.option(connection.time_wait_remaining, Encoder::duration);
+        self.option(connection.linger_remaining, Encoder::duration);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn addr(&mut self, addr: SocketAddrV4) {
+        self.buf.extend_from_slice(&addr.ip().octets());
+        self.u16(addr.port());
+    }
+
+    fn duration(&mut self, duration: Duration) {
+        self.u64(duration.as_secs());
+        self.u32(duration.subsec_nanos());
+    }
+
+    fn option<T>(&mut self, value: Option<T>, encode: impl FnOnce(&mut Encoder, T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            encode(self, value);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn connection(&mut self) -> Result<ConnectionState> {
+        Ok(ConnectionState {
+            local: self.addr()?,
+            remote: self.addr()?,
+            state: state_from_code(self.u8()?)?,
+            passive_open: self.bool()?,
+
+            snd_una: self.u32()?,
+            snd_nxt: self.u32()?,
+            snd_max: self.u32()?,
+            snd_wnd: self.u32()?,
+            snd_wl1: self.u32()?,
+            snd_wl2: self.u32()?,
+            iss: self.u32()?,
+            snd_scale: self.u8()?,
+            rcv_nxt: self.u32()?,
+            rcv_wnd: self.u32()?,
+            irs: self.u32()?,
+            rcv_scale: self.u8()?,
+
+            send_mss: self.u16()?,
+            recv_mss: self.option(Decoder::u16)?,
+            timestamps: self.option(|decoder| Ok((decoder.u32()?, decoder.u32()?)))?,
+            sack_permitted: self.bool()?,
+
+            send_buffer: self.bytes()?.to_vec(),
+            push_points: {
+                let n_points: u32 = self.u32()?;
+                (0..n_points)
+                    .map(|_| self.u64())
+                    .collect::<Result<Vec<u64>>>()?
+            },
+            fin_queued: self.bool()?,
+            recv_buffer: self.bytes()?.to_vec(),
+            recv_pushed: self.usize()?,
+            recv_shutdown: self.bool()?,
+
+            recv_buffer_size: self.usize()?,
+            send_buffer_size: self.usize()?,
+            recv_low_water: self.usize()?,
+            nodelay: self.bool()?,
+            pacing: self.bool()?,
+            dsack_enabled: self.bool()?,
+            unread_data_policy: match self.bool()? {
+                true => UnreadDataPolicy::Fin,
+                false => UnreadDataPolicy::Reset,
+            },
+            keepalive: self.option(|decoder| {
+                Ok(KeepaliveConfig {
+                    idle: decoder.duration()?,
+                    interval: decoder.duration()?,
+                    probes: decoder.u32()?,
+                })
+            })?,
+            linger: self.option(Decoder::duration)?,
+            user_timeout: self.option(Decoder::duration)?,
+            idle_timeout: self.option(Decoder::duration)?,
+            time_wait: self.duration()?,
+
+            srtt: self.option(Decoder::duration)?,
+            rttvar: self.duration()?,
+            cwnd: self.u32()?,
+            ssthresh: self.u32()?,
+
+            time_wait_remaining: self.option(Decoder::duration)?,
+            linger_remaining: self.option(Decoder::duration)?,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(TcpError::Parse("Snapshot cut short".into()));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take_array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take_array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let value: u64 = self.u64()?;
+        value
+            .try_into()
+            .map_err(|_| TcpError::Parse(format!("Snapshot size {value} too large")))
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(TcpError::Parse(format!(
+                "Snapshot flag {value} isn't 0 or 1"
+            ))),
+        }
+    }
+
+    /// A length prefixed run of bytes, which can't be longer than what's left
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len: u32 = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn addr(&mut self) -> Result<SocketAddrV4> {
+        let ip: [u8; 4] = self.take_array()?;
+        Ok(SocketAddrV4::new(Ipv4Addr::from(ip), self.u16()?))
+    }
+
+    fn duration(&mut self) -> Result<Duration> {
+        let secs: u64 = self.u64()?;
+        let nanos: u32 = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(TcpError::Parse(format!(
+                "Snapshot duration with {nanos} nanoseconds"
+            )));
+        }
+
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn option<T>(
+        &mut self,
+        decode: impl FnOnce(&mut Decoder<'a>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        match self.bool()? {
+            true => decode(self).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+fn state_code(state: State) -> u8 {
+    match state {
+        State::SynSent => 0,
+        State::SynRcvd => 1,
+        State::Estab => 2,
+        State::FinWait1 => 3,
+        State::FinWait2 => 4,
+        State::CloseWait => 5,
+        State::Closing => 6,
+        State::LastAck => 7,
+        State::TimeWait => 8,
+        State::Closed => 9,
+    }
+}
+
+fn state_from_code(code: u8) -> Result<State> {
+    match code {
+        0 => Ok(State::SynSent),
+        1 => Ok(State::SynRcvd),
+        2 => Ok(State::Estab),
+        3 => Ok(State::FinWait1),
+        4 => Ok(State::FinWait2),
+        5 => Ok(State::CloseWait),
+        6 => Ok(State::Closing),
+        7 => Ok(State::LastAck),
+        8 => Ok(State::TimeWait),
+        9 => Ok(State::Closed),
+        _ => Err(TcpError::Parse(format!("Unknown connection state {code}"))),
+    }
+}
@@ -0,0 +1,215 @@
+#[cfg(feature = "std")]
+use std::cell::Cell;
+
+use alloc::format;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::{
+    error::{Result, TcpError},
+    tcp::ConnectInfo,
+};
+
+/// Source of incarnation numbers, shared by every stack and thread in the process
+static NEXT_INCARNATION: AtomicU64 = AtomicU64::new(1);
+
+/// The [`LogLevel`] for the whole process. A library shouldn't write to stderr unless asked
+/// to, so nothing is logged until [`set_level`] raises it.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Off as u8);
+
+/// The port set by [`set_port_filter`], or 0 to log every connection
+static PORT_FILTER: AtomicU16 = AtomicU16::new(0);
+
+#[cfg(feature = "std")]
+thread_local! {
+    /// The connection this thread is doing work for, if any
+    static CURRENT: Cell<Option<ConnectionId>> = const { Cell::new(None) };
+}
+
+/// Identifies one connection for logging. A 4-tuple can be reused once a connection has
+/// closed, so each connection also gets an incarnation number unique within the process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId {
+    /// The 4-tuple as seen on segments from the peer
+    pub info: ConnectInfo,
+    pub incarnation: u64,
+}
+
+impl ConnectionId {
+    /// An id for a new connection, with the next incarnation number
+    pub fn new(info: ConnectInfo) -> Self {
+        ConnectionId {
+            info,
+            incarnation: NEXT_INCARNATION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} #{}", self.info, self.incarnation)
+    }
+}
+
+/// Mark the current thread as working for the connection `id` until the guard is dropped,
+/// when whatever was entered before is restored. Every log line in between is tagged with
+/// `id`.
+///
+/// [`Tcb`](crate::tcp::Tcb) enters its own span in each of its entry points, so timers,
+/// retransmissions and work done from a worker thread or the async front end are all
+/// tagged without the caller doing anything.
+#[must_use = "the span is exited as soon as the guard is dropped"]
+#[cfg(feature = "std")]
+pub fn enter(id: ConnectionId) -> Entered {
+    Entered {
+        previous: CURRENT.replace(Some(id)),
+    }
+}
+
+/// Without std there are no thread locals to keep the span in, so nothing is entered
+#[must_use = "the span is exited as soon as the guard is dropped"]
+#[cfg(not(feature = "std"))]
+pub fn enter(_id: ConnectionId) -> Entered {
+    Entered {}
+}
+
+/// The connection the current thread is working for, if any
+#[cfg(feature = "std")]
+pub fn current() -> Option<ConnectionId> {
+    CURRENT.get()
+}
+
+/// Without std log lines are never tagged with a connection
+#[cfg(not(feature = "std"))]
+pub fn current() -> Option<ConnectionId> {
+    None
+}
+
+/// Guard returned by [`enter`]
+pub struct Entered {
+    #[cfg(feature = "std")]
+    previous: Option<ConnectionId>,
+}
+
+#[cfg(feature = "std")]
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(self.previous);
+    }
+}
+
+/// How much is logged, see [`set_level`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    /// What happens to connections and why packets are dropped
+    Info,
+    /// Also each connection's state transitions
+    Debug,
+    /// Also hex dumps of every packet received and sent, and of data read
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "off" => Ok(LogLevel::Off),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(TcpError::Parse(format!(
+                "Unknown log level {name}, expected off, info, debug or trace"
+            ))),
+        }
+    }
+}
+
+/// Log everything at `level` and below from now on, across every thread. Defaults to
+/// [`LogLevel::Off`].
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Only log lines for connections to or from `port`, along with lines not about any one
+/// connection, to follow a single connection's lifecycle. `None` logs every connection.
+pub fn set_port_filter(port: Option<u16>) {
+    PORT_FILTER.store(port.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Whether lines at `level` are being logged from the current span
+pub fn enabled(level: LogLevel) -> bool {
+    if level == LogLevel::Off || level as u8 > LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let port: u16 = PORT_FILTER.load(Ordering::Relaxed);
+    match current() {
+        Some(id) if port != 0 => id.info.src_port == port || id.info.dst_port == port,
+        _ => true,
+    }
+}
+
+/// Write a line to stderr, prefixed with the current connection if there is one
+#[cfg(feature = "std")]
+pub fn write_log(args: fmt::Arguments) {
+    match current() {
+        Some(id) => eprintln!("[{id}] {args}"),
+        None => eprintln!("{args}"),
+    }
+}
+
+/// Without std there's no stderr, so lines go to the sink given to [`set_sink`], if any
+#[cfg(not(feature = "std"))]
+pub fn write_log(args: fmt::Arguments) {
+    let sink: usize = SINK.load(Ordering::Relaxed);
+    if sink != 0 {
+        // SAFETY: SINK only ever holds 0 or a `fn(fmt::Arguments)`, stored by `set_sink`
+        let sink: fn(fmt::Arguments) = unsafe { core::mem::transmute(sink) };
+        sink(args);
+    }
+}
+
+/// Where [`write_log`] sends lines without std, a `fn(fmt::Arguments)` or 0 for nowhere
+#[cfg(not(feature = "std"))]
+static SINK: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Send every line logged to `sink`, such as a UART or RTT channel, for builds without
+/// std. Nothing is logged until a sink is set.
+#[cfg(not(feature = "std"))]
+pub fn set_sink(sink: fn(fmt::Arguments)) {
+    SINK.store(sink as usize, Ordering::Relaxed);
+}
+
+/// Log a line at [`LogLevel::Info`] through [`write_log`], which tags it with the
+/// connection being worked on and writes it to stderr, or to the sink without std
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if $crate::span::enabled($crate::span::LogLevel::Info) {
+            $crate::span::write_log(format_args!($($arg)*))
+        }
+    };
+}
+
+/// [`log`] at [`LogLevel::Debug`]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::span::enabled($crate::span::LogLevel::Debug) {
+            $crate::span::write_log(format_args!($($arg)*))
+        }
+    };
+}
+
+/// [`log`] at [`LogLevel::Trace`]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::span::enabled($crate::span::LogLevel::Trace) {
+            $crate::span::write_log(format_args!($($arg)*))
+        }
+    };
+}
+
+pub(crate) use debug;
+pub(crate) use log;
+pub(crate) use trace;
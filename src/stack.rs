@@ -0,0 +1,1033 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    net::{Shutdown, SocketAddrV4},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Instant,
+};
+#[cfg(unix)]
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+
+#[cfg(unix)]
+use crate::device::RecvBatch;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{ConnectionState, StackSnapshot};
+use crate::{
+    challenge::ChallengeAckLimiter,
+    checksum::{self, ChecksumError},
+    clock::{self, Clock},
+    config::StackConfig,
+    device::NetworkDevice,
+    error::{Result, TcpError},
+    health::{Health, HealthLimits, HealthUsage},
+    icmp,
+    isn::IsnGenerator,
+    listener::{ClosedPortPolicy, Listeners, TimeWaitPolicy},
+    poll::{Event, Poller},
+    ports::{PortState, PortTable},
+    reassembly::Reassembler,
+    span::{self, log},
+    stats::{ConnectionSummary, StackStats, StatsRecorder, StatsSnapshot},
+    tcp::{self, ConnectInfo, State, Tcb},
+    timer::TimerQueue,
+};
+
+/// Every connection and listener, along with the state they share.
+///
+/// Nothing here blocks. The owner waits for the device to have a packet or for
+/// [`Stack::next_deadline`], whichever comes first, then passes packets to
+/// [`Stack::on_packet`] and lets [`Stack::on_tick`] run any timers which have expired.
+///
+/// Each connection's next deadline is kept in a [`TimerQueue`], so a tick only looks at
+/// connections which are due or have changed since the last one.
+pub struct Stack {
+    connections: HashMap<ConnectInfo, Tcb>,
+    timers: TimerQueue,
+    /// Connections which may have changed since the last tick, whose timers haven't been
+    /// rescheduled yet
+    changed: HashSet<ConnectInfo>,
+    listeners: Listeners,
+    ports: PortTable,
+    challenge_acks: ChallengeAckLimiter,
+    isn: IsnGenerator,
+    stats: StatsRecorder,
+    reassembler: Reassembler,
+    clock: Arc<dyn Clock>,
+    config: StackConfig,
+    /// What's been reported to [`Stack::poll`], once it's been called
+    poller: Option<Poller>,
+    /// Connections which had segments in the batch being handled, see [`Stack::on_packets`]
+    batch: Option<Vec<ConnectInfo>>,
+}
+
+impl Stack {
+    pub fn new(listeners: Listeners, isn: IsnGenerator, now: Instant) -> Self {
+        Stack::with_config(listeners, isn, now, StackConfig::DEFAULT)
+    }
+
+    /// A stack opening connections with the settings in `config`
+    pub fn with_config(
+        listeners: Listeners,
+        isn: IsnGenerator,
+        now: Instant,
+        config: StackConfig,
+    ) -> Self {
+        Stack {
+            connections: HashMap::new(),
+            timers: TimerQueue::default(),
+            changed: HashSet::new(),
+            listeners,
+            ports: PortTable::default(),
+            challenge_acks: ChallengeAckLimiter::default(),
+            isn,
+            stats: StatsRecorder::new(now),
+            reassembler: Reassembler::default(),
+            clock: clock::system(),
+            config,
+            poller: None,
+            batch: None,
+        }
+    }
+
+    /// Use `clock` for the timers of connections opened from now on, such as a
+    /// [`SimulatedClock`](crate::clock::SimulatedClock) in tests. Existing connections
+    /// keep the clock they were opened with.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn listeners(&self) -> &Listeners {
+        &self.listeners
+    }
+
+    pub fn listeners_mut(&mut self) -> &mut Listeners {
+        &mut self.listeners
+    }
+
+    /// Local ports used by connections we opened
+    pub fn ports(&self) -> &PortTable {
+        &self.ports
+    }
+
+    /// Give active opens which don't ask for a local port one from `ephemeral`, rather
+    /// than from [`EPHEMERAL_PORTS`](crate::ports::EPHEMERAL_PORTS)
+    pub fn set_ephemeral_ports(&mut self, ephemeral: RangeInclusive<u16>) {
+        let mut ports = PortTable::new(ephemeral);
+        for tcb in self.connections.values().filter(|tcb| !tcb.passive_open()) {
+            ports.bind(tcb.local().port());
+        }
+        self.ports = ports;
+    }
+
+    /// What segments arriving at local `port` for a connection which doesn't exist yet
+    /// are delivered to. A port connections were opened from is theirs alone, even when
+    /// listening on every port.
+    pub fn port_state(&self, port: u16) -> PortState {
+        port_state(&self.listeners, &self.ports, port)
+    }
+
+    pub fn connection(&self, info: &ConnectInfo) -> Option<&Tcb> {
+        self.connections.get(info)
+    }
+
+    pub fn connection_mut(&mut self, info: &ConnectInfo) -> Option<&mut Tcb> {
+        let tcb: &mut Tcb = self.connections.get_mut(info)?;
+        self.changed.insert(*info);
+        Some(tcb)
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = (&ConnectInfo, &Tcb)> {
+        self.connections.iter()
+    }
+
+    pub fn connections_mut(&mut self) -> impl Iterator<Item = (&ConnectInfo, &mut Tcb)> {
+        self.changed.extend(self.connections.keys());
+        self.connections.iter_mut()
+    }
+
+    /// The stack's counters so far
+    pub fn stats(&self) -> StackStats {
+        self.stats.stack
+    }
+
+    /// Read every counter, stack and connection, resetting them to zero if `reset` is set
+    pub fn snapshot_stats(&mut self, now: Instant, reset: bool) -> StatsSnapshot {
+        self.stats.snapshot(&mut self.connections, now, reset)
+    }
+
+    /// Every connection's state, sequence variables and queues, ordered by the peer's
+    /// address
+    pub fn connection_summaries(&self) -> Vec<ConnectionSummary> {
+        let mut summaries: Vec<ConnectionSummary> =
+            self.connections.values().map(Tcb::summary).collect();
+        summaries.sort_by_key(|summary| (summary.connection.src_addr, summary.connection.src_port));
+        summaries
+    }
+
+    /// The state of every synchronised connection, to carry them on in a new process with
+    /// [`Stack::restore`]. Connections still in the handshake are left out, as their peers
+    /// send their SYNs again. Nothing here changes, so once the new process has the
+    /// connections this stack should stop without closing them.
+    #[cfg(feature = "snapshot")]
+    pub fn snapshot(&self) -> StackSnapshot {
+        let mut connections: Vec<ConnectionState> = self
+            .connections
+            .values()
+            .filter_map(|tcb| tcb.save_state().ok())
+            .collect();
+        connections.sort_by_key(|connection| (connection.local, connection.remote));
+
+        StackSnapshot { connections }
+    }
+
+    /// Carry on the connections from [`Stack::snapshot`], each as [`Tcb::restore`] does
+    /// with the stack's settings, returning their 4-tuples as seen on segments from the
+    /// peer.
+    ///
+    /// Restored connections are left out of the listeners' accept queues, as they were
+    /// accepted long ago, so they're found through [`Stack::connections`], or reported by
+    /// [`Stack::poll`] once it's started. Nothing is restored if any connection can't be,
+    /// such as when its 4-tuple is already taken, [`TcpError::AddrInUse`], or it's still
+    /// in the handshake.
+    #[cfg(feature = "snapshot")]
+    pub fn restore(
+        &mut self,
+        nic: &impl NetworkDevice,
+        snapshot: StackSnapshot,
+    ) -> Result<Vec<ConnectInfo>> {
+        let infos: Vec<ConnectInfo> = snapshot
+            .connections
+            .iter()
+            .map(|connection| ConnectInfo {
+                src_addr: *connection.remote.ip(),
+                src_port: connection.remote.port(),
+                dst_addr: *connection.local.ip(),
+                dst_port: connection.local.port(),
+            })
+            .collect();
+        for (i, (info, saved)) in infos.iter().zip(&snapshot.connections).enumerate() {
+            if self.connections.contains_key(info) || infos[..i].contains(info) {
+                return Err(TcpError::AddrInUse(info.dst_port));
+            }
+            if !saved.state.is_synchronised() {
+                return Err(TcpError::InvalidState(saved.state));
+            }
+        }
+
+        // Every connection is restored and sent on before any is added, so one which fails
+        // leaves the stack as it was
+        let mut tcbs: Vec<Tcb> = snapshot
+            .connections
+            .into_iter()
+            .map(|saved| Tcb::restore_unsent(nic, saved, &self.clock, &self.config.tcb))
+            .collect::<Result<_>>()?;
+        for tcb in &mut tcbs {
+            tcb.send_restored(nic)?;
+        }
+
+        for (info, tcb) in infos.iter().zip(tcbs) {
+            if !tcb.passive_open() {
+                self.ports.bind(tcb.local().port());
+            }
+            self.connections.insert(*info, tcb);
+            self.changed.insert(*info);
+            if let Some(poller) = &mut self.poller {
+                poller.on_connect(*info);
+            }
+        }
+
+        Ok(infos)
+    }
+
+    pub fn config(&self) -> &StackConfig {
+        &self.config
+    }
+
+    /// Use `config` for connections opened from now on and for the health limits, such
+    /// as one of the profiles on [`StackConfig`]. Existing connections keep the settings
+    /// they were opened with.
+    pub fn set_config(&mut self, config: StackConfig) {
+        self.config = config;
+    }
+
+    pub fn health_limits(&self) -> HealthLimits {
+        self.config.health_limits
+    }
+
+    pub fn set_health_limits(&mut self, limits: HealthLimits) {
+        self.config.health_limits = limits;
+    }
+
+    /// Resources held by the stack itself, leaving out the device
+    pub fn health_usage(&self) -> HealthUsage {
+        HealthUsage {
+            buffered_bytes: self
+                .connections
+                .values()
+                .map(|tcb| tcb.unread_len() + tcb.unacked_len())
+                .sum(),
+            connections: self.connections.len(),
+            // Each datagram being reassembled has a timer of its own
+            timers: self
+                .connections
+                .values()
+                .map(Tcb::running_timers)
+                .sum::<usize>()
+                + self.reassembler.pending(),
+            device_queue: None,
+        }
+    }
+
+    /// Resource usage, including packets queued on `nic`, against the stack's limits
+    pub fn health(&self, nic: &impl NetworkDevice) -> Health {
+        let mut usage: HealthUsage = self.health_usage();
+        usage.device_queue = nic.queued_packets();
+        Health::new(usage, self.config.health_limits)
+    }
+
+    /// The next time `on_tick` has work to do, if any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        // The timers of changed connections may be out of date, so theirs are asked for
+        let scheduled: Option<Instant> = self
+            .timers
+            .iter()
+            .find(|(_, info)| !self.changed.contains(info))
+            .map(|(deadline, _)| deadline);
+        let changed = self
+            .changed
+            .iter()
+            .filter_map(|info| self.connections.get(info))
+            .filter_map(Tcb::next_deadline);
+
+        scheduled
+            .into_iter()
+            .chain(changed)
+            .chain(self.reassembler.next_deadline())
+            .min()
+    }
+
+    /// Actively open a connection from `local` to `remote`, see [`Tcb::connect`].
+    /// Returns the connection's 4-tuple as seen on segments from the peer.
+    ///
+    /// A local port of 0 is replaced with a free ephemeral port. Any other port can be
+    /// shared by connections to different peers, but not with a listener.
+    pub fn connect(
+        &mut self,
+        nic: &impl NetworkDevice,
+        mut local: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> Result<ConnectInfo> {
+        if local.port() == 0 {
+            let listeners: &Listeners = &self.listeners;
+            let Some(port) = self.ports.allocate(|port| listeners.get(port).is_some()) else {
+                return Err(TcpError::InsufficientResources(
+                    "no free ephemeral ports".to_string(),
+                ));
+            };
+            local.set_port(port);
+        } else if self.listeners.get(local.port()).is_some() {
+            return Err(TcpError::AddrInUse(local.port()));
+        }
+
+        if let Some(max_connections) = at_connection_limit(&self.config, self.connections.len()) {
+            return Err(TcpError::InsufficientResources(format!(
+                "at the limit of {max_connections} connections"
+            )));
+        }
+
+        let info = ConnectInfo {
+            src_addr: *remote.ip(),
+            src_port: remote.port(),
+            dst_addr: *local.ip(),
+            dst_port: local.port(),
+        };
+
+        // Chosen once, so a new incarnation uses the same ISS it was checked with
+        let iss: u32 = self.isn.generate(local, remote, self.clock.now());
+        if let Some(old) = self.connections.get(&info) {
+            if self.listeners.time_wait_policy() != TimeWaitPolicy::Reopen
+                || !old.can_reconnect_with(iss)
+            {
+                return Err(TcpError::AddrInUse(local.port()));
+            }
+
+            if let Some(tcb) = self.connections.remove(&info) {
+                let _span = span::enter(tcb.id());
+                log!("Reconnecting from TIME-WAIT as a new incarnation");
+                release_port(&mut self.ports, &tcb);
+                cancel_timer(&mut self.timers, &tcb);
+            }
+            if let Some(poller) = &mut self.poller {
+                poller.forget(&info);
+            }
+            self.stats.stack.connections_closed += 1;
+            self.stats.stack.time_wait_reopened += 1;
+        }
+
+        let tcb: Tcb =
+            Tcb::connect_with_iss(nic, local, remote, iss, &self.clock, &self.config.tcb)?;
+        self.connections.insert(info, tcb);
+        self.changed.insert(info);
+        self.ports.bind(local.port());
+        if let Some(poller) = &mut self.poller {
+            poller.on_connect(info);
+        }
+
+        Ok(info)
+    }
+
+    /// Close our side of a connection, see [`Tcb::close`]
+    pub fn close(&mut self, nic: &impl NetworkDevice, info: &ConnectInfo) -> Result<()> {
+        let Some(tcb) = self.connections.get_mut(info) else {
+            return Err(TcpError::NotConnected);
+        };
+
+        self.changed.insert(*info);
+        let was_finished: bool = tcb.state().is_finished();
+        tcb.close(nic)?;
+        on_state_change(&mut self.listeners, info, tcb, was_finished);
+
+        Ok(())
+    }
+
+    /// Abort a connection, see [`Tcb::abort`], and delete it straight away
+    pub fn abort(&mut self, nic: &impl NetworkDevice, info: &ConnectInfo) -> Result<()> {
+        let Some(tcb) = self.connections.get_mut(info) else {
+            return Err(TcpError::NotConnected);
+        };
+
+        let was_finished: bool = tcb.state().is_finished();
+        let result: Result<()> = tcb.abort(nic);
+        on_state_change(&mut self.listeners, info, tcb, was_finished);
+
+        if let Some(tcb) = self.connections.remove(info) {
+            release_port(&mut self.ports, &tcb);
+            cancel_timer(&mut self.timers, &tcb);
+            self.stats.stack.connections_closed += 1;
+        }
+        if let Some(poller) = &mut self.poller {
+            poller.forget(info);
+        }
+
+        result
+    }
+
+    /// Shut down one or both directions of a connection, see [`Tcb::shutdown`]
+    pub fn shutdown(
+        &mut self,
+        nic: &impl NetworkDevice,
+        info: &ConnectInfo,
+        how: Shutdown,
+    ) -> Result<()> {
+        let Some(tcb) = self.connections.get_mut(info) else {
+            return Err(TcpError::NotConnected);
+        };
+
+        self.changed.insert(*info);
+        let was_finished: bool = tcb.state().is_finished();
+        tcb.shutdown(nic, how)?;
+        on_state_change(&mut self.listeners, info, tcb, was_finished);
+
+        Ok(())
+    }
+
+    /// Process one packet read from the device
+    pub fn on_packet(
+        &mut self,
+        nic: &impl NetworkDevice,
+        packet: &[u8],
+        now: Instant,
+    ) -> Result<()> {
+        self.stats.stack.packets_in += 1;
+
+        if is_truncated(&mut self.stats.stack, packet) {
+            return Ok(());
+        }
+
+        let Some(packet) = reassemble(&mut self.reassembler, &mut self.stats.stack, packet, now)
+        else {
+            return Ok(());
+        };
+
+        self.handle_packet(nic, &packet)
+    }
+
+    /// Process packets read from the device together, such as by a [`RecvBatch`](crate::device::RecvBatch). Each is
+    /// handled as by [`Stack::on_packet`], except that a connection only acknowledges every
+    /// second segment of in-order data straight away, and the rest once the whole batch
+    /// has been handled, so a burst of data costs fewer ACKs.
+    pub fn on_packets<'a>(
+        &mut self,
+        nic: &impl NetworkDevice,
+        packets: impl IntoIterator<Item = &'a [u8]>,
+        now: Instant,
+    ) -> Result<()> {
+        self.batch = Some(Vec::new());
+        let mut result: Result<()> = Ok(());
+        for packet in packets {
+            result = self.on_packet(nic, packet, now);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // The ACKs are still owed if a packet failed
+        for info in self.batch.take().unwrap_or_default() {
+            if let Some(tcb) = self.connections.get_mut(&info) {
+                tcb.end_batch(nic)?;
+            }
+        }
+
+        result
+    }
+
+    /// Run every timer which has expired by `now`, then delete closed connections
+    pub fn on_tick(&mut self, nic: &impl NetworkDevice, now: Instant) -> Result<()> {
+        let n_expired: usize = self.reassembler.expire(now);
+        if n_expired > 0 {
+            log!("Dropping {n_expired} fragmented datagrams which weren't completed in time");
+            self.stats.stack.reassembly_failures += n_expired as u64;
+        }
+
+        // A changed connection's timer may be later than its next deadline, so it's moved
+        // before looking for what's expired
+        for info in &self.changed {
+            if let Some(tcb) = self.connections.get_mut(info) {
+                reschedule(&mut self.timers, info, tcb);
+            }
+        }
+
+        while let Some(info) = self.timers.pop_expired(now) {
+            let Some(tcb) = self.connections.get_mut(&info) else {
+                continue;
+            };
+            tcb.set_timer(None);
+            self.changed.insert(info);
+
+            if tcb.idle_deadline().is_some_and(|deadline| deadline <= now) {
+                self.stats.stack.connections_evicted += 1;
+            }
+
+            let was_finished: bool = tcb.state().is_finished();
+            tcb.on_tick(nic, now)?;
+            on_state_change(&mut self.listeners, &info, tcb, was_finished);
+        }
+
+        // Only connections which changed or whose timers ran can have sent data or closed
+        for info in std::mem::take(&mut self.changed) {
+            let Some(tcb) = self.connections.get_mut(&info) else {
+                continue;
+            };
+            count_bytes_out(&mut self.listeners, &info, tcb);
+
+            if tcb.state() != State::Closed {
+                reschedule(&mut self.timers, &info, tcb);
+                continue;
+            }
+
+            if let Some(tcb) = self.connections.remove(&info) {
+                release_port(&mut self.ports, &tcb);
+                cancel_timer(&mut self.timers, &tcb);
+                if let Some(poller) = &mut self.poller {
+                    poller.on_delete(&info, &tcb);
+                }
+                self.stats.stack.connections_closed += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for something to happen on the connections, filling `events` with what did.
+    /// Packets from `nic` are received and timers run in the meantime, until there's at
+    /// least one event or `timeout` has passed. `None` waits for as long as it takes.
+    ///
+    /// Events are edge triggered, like epoll's `EPOLLET`. A connection is only reported
+    /// readable or writable again once it's stopped being so, so read until nothing's
+    /// left and send until the buffer is full before polling again. Connections a listener
+    /// accepts are reported once established. Those from [`Stack::connect`] are reported
+    /// writable once established, or closed if they're refused, as long as polling had
+    /// started when they were opened. Either kind is reported once more when it's deleted,
+    /// unless that was by [`Stack::abort`].
+    #[cfg(unix)]
+    pub fn poll<D: NetworkDevice + AsRawFd>(
+        &mut self,
+        nic: &D,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let give_up: Option<Instant> = timeout.map(|timeout| Instant::now() + timeout);
+        let mut batch = RecvBatch::for_device(nic);
+
+        loop {
+            self.poll_events(events);
+            if !events.is_empty() || give_up.is_some_and(|give_up| Instant::now() >= give_up) {
+                return Ok(());
+            }
+
+            let deadline: Option<Instant> =
+                [self.next_deadline(), give_up].into_iter().flatten().min();
+            let [packet_ready] = wait_for_input([Some(nic.as_raw_fd())], deadline)?;
+
+            if packet_ready {
+                batch.recv(nic)?;
+                self.on_packets(nic, batch.packets(), Instant::now())?;
+            }
+            self.on_tick(nic, Instant::now())?;
+        }
+    }
+
+    /// Fill `events` with what's happened since the last poll without waiting, for owners
+    /// which receive packets and run timers themselves. See [`Stack::poll`].
+    pub fn poll_events(&mut self, events: &mut Vec<Event>) {
+        events.clear();
+        self.poller
+            .get_or_insert_with(Poller::default)
+            .poll(self.connections.iter(), events);
+    }
+
+    /// Process a whole datagram, which has already been counted and reassembled
+    pub(crate) fn handle_packet(&mut self, nic: &impl NetworkDevice, buf: &[u8]) -> Result<()> {
+        let stats: &mut StackStats = &mut self.stats.stack;
+
+        let ipv4_header = match Ipv4HeaderSlice::from_slice(buf) {
+            Ok(ipv4_header) => ipv4_header,
+            Err(err) => {
+                stats.packets_invalid += 1;
+                log!("Skipping packet. Failed to decode Ipv4 packet: {err}");
+                return Ok(());
+            }
+        };
+
+        let tcp_header_offset: usize = ipv4_header.slice().len();
+
+        // Anything past the IP total length is padding
+        let ip_payload: &[u8] = &buf[tcp_header_offset
+            ..(ipv4_header.total_len() as usize).clamp(tcp_header_offset, buf.len())];
+
+        match ipv4_header.protocol() {
+            IpNumber::TCP => {}
+            IpNumber::ICMP => {
+                stats.icmp_in += 1;
+                self.handle_icmp(&ipv4_header, ip_payload);
+                return Ok(());
+            }
+            // There are no UDP sockets, so every UDP port is closed.
+            // RFC 1122 Section 3.2.2.1
+            IpNumber::UDP => {
+                if self.listeners.closed_port_policy() != ClosedPortPolicy::Silent
+                    && icmp::send_port_unreachable(nic, &ipv4_header, ip_payload)?
+                {
+                    stats.icmp_out += 1;
+                }
+                return Ok(());
+            }
+            _ => {
+                stats.packets_invalid += 1;
+                return Ok(());
+            }
+        }
+
+        let tcp_header = match TcpHeaderSlice::from_slice(&buf[tcp_header_offset..]) {
+            Ok(tcp_header) => tcp_header,
+            Err(err) => {
+                stats.packets_invalid += 1;
+                log!("Skipping packet. Failed to decode TCP packet: {err}");
+                return Ok(());
+            }
+        };
+
+        // Port 0 is reserved, RFC 6335 Section 6, so it's never one end of a connection.
+        // Nothing is sent back, as a reply to port 0 would be just as invalid.
+        if tcp_header.source_port() == 0 || tcp_header.destination_port() == 0 {
+            stats.zero_port_segments += 1;
+            log!("Skipping packet. Port 0 is reserved");
+            return Ok(());
+        }
+
+        let data: &[u8] = ip_payload.get(tcp_header.slice().len()..).unwrap_or(&[]);
+
+        let info = ConnectInfo {
+            src_addr: ipv4_header.source_addr(),
+            src_port: tcp_header.source_port(),
+            dst_addr: ipv4_header.destination_addr(),
+            dst_port: tcp_header.destination_port(),
+        };
+
+        if let Err(err) = checksum::verify(&ipv4_header, &tcp_header, data) {
+            match err {
+                ChecksumError::Ipv4Header => stats.ip_checksum_errors += 1,
+                ChecksumError::Tcp => stats.tcp_checksum_errors += 1,
+            }
+            let _span = self.connections.get_mut(&info).map(|tcb| {
+                tcb.on_checksum_error();
+                span::enter(tcb.id())
+            });
+            log!("Skipping packet. {err:?} checksum doesn't match");
+            return Ok(());
+        }
+
+        // Only a listener can take the new incarnation, so a connection we opened
+        // ourselves, or one whose listener has gone, stays in TIME-WAIT
+        let listening: bool =
+            port_state(&self.listeners, &self.ports, info.dst_port) == PortState::Listening;
+        if self.listeners.time_wait_policy() == TimeWaitPolicy::Reopen
+            && listening
+            && self
+                .connections
+                .get(&info)
+                .is_some_and(|tcb| tcb.passive_open() && tcb.is_new_incarnation(&tcp_header))
+        {
+            if let Some(tcb) = self.connections.remove(&info) {
+                let _span = span::enter(tcb.id());
+                log!("Reopening from TIME-WAIT for a new incarnation");
+                release_port(&mut self.ports, &tcb);
+                cancel_timer(&mut self.timers, &tcb);
+                if let Some(poller) = &mut self.poller {
+                    poller.on_delete(&info, &tcb);
+                }
+            }
+            stats.connections_closed += 1;
+            stats.time_wait_reopened += 1;
+        }
+
+        let closed_port_policy: ClosedPortPolicy = self.listeners.closed_port_policy();
+        let connection_limit: Option<usize> =
+            at_connection_limit(&self.config, self.connections.len());
+        let listener = match port_state(&self.listeners, &self.ports, info.dst_port) {
+            PortState::Listening => self.listeners.get_mut(info.dst_port),
+            PortState::Connected | PortState::Free => None,
+        };
+
+        match self.connections.entry(info) {
+            Entry::Occupied(mut entry) => {
+                let _span = span::enter(entry.get().id());
+
+                // Only connections accepted by the listener count against its byte rate.
+                // Over it, the payload and the FIN after it are dropped for the peer to
+                // retransmit, but the rest of the segment is still processed.
+                let listener = listener.filter(|_| entry.get().passive_open());
+                let throttled: bool = match listener {
+                    Some(listener) => !listener.admit_bytes_in(data.len(), self.clock.now()),
+                    None => false,
+                };
+                let mut header_buf: [u8; TcpHeader::MAX_LEN] = [0; TcpHeader::MAX_LEN];
+                let (tcp_header, data): (TcpHeaderSlice, &[u8]) = match throttled {
+                    true => {
+                        log!(
+                            "Dropping payload. Listener on port {} is over its byte rate",
+                            info.dst_port
+                        );
+                        (without_fin(&tcp_header, &mut header_buf), &[])
+                    }
+                    false => (tcp_header, data),
+                };
+
+                self.changed.insert(info);
+                let tcb: &mut Tcb = entry.get_mut();
+                let was_finished: bool = tcb.state().is_finished();
+                if let Some(batch) = &mut self.batch {
+                    tcb.begin_batch();
+                    batch.push(info);
+                }
+
+                tcb.on_packet(nic, ipv4_header, tcp_header, data, &mut self.challenge_acks)?;
+
+                on_state_change(&mut self.listeners, &info, tcb, was_finished);
+            }
+            Entry::Vacant(entry) => {
+                let Some(listener) = listener else {
+                    // Nothing is listening, so this port is in the CLOSED state. That
+                    // includes ports only our own connections use.
+                    stats.segments_to_closed_ports += 1;
+                    match closed_port_policy {
+                        ClosedPortPolicy::Reset => {
+                            tcp::send_reset(
+                                nic,
+                                &ipv4_header,
+                                &tcp_header,
+                                data,
+                                self.config.tcb.ttl,
+                            )?;
+                            stats.resets_out += !tcp_header.rst() as u64;
+                        }
+                        ClosedPortPolicy::PortUnreachable => {
+                            if icmp::send_port_unreachable(nic, &ipv4_header, ip_payload)? {
+                                stats.icmp_out += 1;
+                            }
+                        }
+                        ClosedPortPolicy::Silent => {}
+                    }
+                    return Ok(());
+                };
+
+                if let (true, Some(max_connections)) = (tcp_header.syn(), connection_limit) {
+                    stats.connections_refused += 1;
+                    log!("Skipping packet. At the limit of {max_connections} connections");
+                    return Ok(());
+                }
+
+                if tcp_header.syn() && !listener.try_reserve() {
+                    log!(
+                        "Skipping packet. Listener on port {} is at its connection limit",
+                        listener.port()
+                    );
+                    return Ok(());
+                }
+
+                let accepted: Option<Tcb> = if listener.limits().fast_open {
+                    Tcb::accept_fast_open_connection(
+                        nic,
+                        ipv4_header,
+                        tcp_header,
+                        data,
+                        &self.isn,
+                        &self.clock,
+                        &self.config.tcb,
+                    )?
+                } else {
+                    Tcb::accept_connection_with_config(
+                        nic,
+                        ipv4_header,
+                        tcp_header,
+                        data,
+                        &self.isn,
+                        &self.clock,
+                        &self.config.tcb,
+                    )?
+                };
+                if let Some(tcb) = accepted {
+                    listener.on_accept();
+                    stats.connections_accepted += 1;
+                    entry.insert(tcb);
+                    self.changed.insert(info);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pass an ICMP error on to the connection whose segment caused it, if there is one
+    fn handle_icmp(&mut self, ip_header: &Ipv4HeaderSlice, payload: &[u8]) {
+        let Some(message) = icmp::parse_error(ip_header, payload) else {
+            log!("Skipping ICMP message. Not a Destination Unreachable about a TCP segment");
+            return;
+        };
+
+        let Some(tcb) = self.connections.get_mut(&message.connection) else {
+            log!(
+                "Skipping ICMP message. {:?} for unknown connection {}",
+                message.error,
+                message.connection
+            );
+            return;
+        };
+
+        self.changed.insert(message.connection);
+        let was_finished: bool = tcb.state().is_finished();
+        tcb.on_icmp_error(message.seq, message.error);
+        on_state_change(&mut self.listeners, &message.connection, tcb, was_finished);
+    }
+}
+
+/// Count what a connection has sent against its listener, and give its slot back once it
+/// has finished
+fn on_state_change(
+    listeners: &mut Listeners,
+    info: &ConnectInfo,
+    tcb: &mut Tcb,
+    was_finished: bool,
+) {
+    count_bytes_out(listeners, info, tcb);
+    if tcb.passive_open() && !was_finished && tcb.state().is_finished() {
+        if let Some(listener) = listeners.get_mut(info.dst_port) {
+            listener.on_close();
+        }
+    }
+}
+
+/// Add the payload a connection accepted by a listener has sent since last time to the
+/// listener's `bytes_out`
+fn count_bytes_out(listeners: &mut Listeners, info: &ConnectInfo, tcb: &mut Tcb) {
+    let n_bytes: u64 = tcb.take_bytes_out();
+    if n_bytes == 0 || !tcb.passive_open() {
+        return;
+    }
+
+    if let Some(listener) = listeners.get_mut(info.dst_port) {
+        listener.on_bytes_out(n_bytes as usize);
+    }
+}
+
+/// `tcp_header` copied into `buf` with its FIN flag cleared
+fn without_fin<'a>(
+    tcp_header: &TcpHeaderSlice,
+    buf: &'a mut [u8; TcpHeader::MAX_LEN],
+) -> TcpHeaderSlice<'a> {
+    let header: &'a mut [u8] = &mut buf[..tcp_header.slice().len()];
+    header.copy_from_slice(tcp_header.slice());
+    // FIN is the lowest bit of the flags byte
+    header[13] &= !0x01;
+    TcpHeaderSlice::from_slice(header).expect("the header was already parsed")
+}
+
+fn port_state(listeners: &Listeners, ports: &PortTable, port: u16) -> PortState {
+    if listeners.get(port).is_some() {
+        PortState::Listening
+    } else if ports.is_connected(port) {
+        PortState::Connected
+    } else if listeners.accepts_any() {
+        PortState::Listening
+    } else {
+        PortState::Free
+    }
+}
+
+/// The configured connection limit, if `n_connections` has reached it
+fn at_connection_limit(config: &StackConfig, n_connections: usize) -> Option<usize> {
+    config
+        .max_connections
+        .filter(|&max_connections| n_connections >= max_connections)
+}
+
+/// Move a connection's timer to its next deadline, if that's changed
+fn reschedule(timers: &mut TimerQueue, info: &ConnectInfo, tcb: &mut Tcb) {
+    let deadline: Option<Instant> = tcb.next_deadline();
+    if tcb.timer().map(|timer| timer.deadline()) == deadline {
+        return;
+    }
+
+    cancel_timer(timers, tcb);
+    tcb.set_timer(deadline.map(|deadline| timers.schedule(deadline, *info)));
+}
+
+/// Stop a connection's timer, once it's been deleted or before it's moved
+fn cancel_timer(timers: &mut TimerQueue, tcb: &Tcb) {
+    if let Some(timer) = tcb.timer() {
+        timers.cancel(timer);
+    }
+}
+
+/// Free the local port of a connection we opened once it's been deleted
+fn release_port(ports: &mut PortTable, tcb: &Tcb) {
+    if !tcb.passive_open() {
+        ports.release(tcb.local().port());
+    }
+}
+
+/// Whether the device handed over less of the packet than its IP header declares, in
+/// which case it's counted and should be dropped rather than parsed
+pub(crate) fn is_truncated(stats: &mut StackStats, packet: &[u8]) -> bool {
+    let Ok(ip_header) = Ipv4HeaderSlice::from_slice(packet) else {
+        return false;
+    };
+
+    let total_len: usize = ip_header.total_len() as usize;
+    if total_len <= packet.len() {
+        return false;
+    }
+
+    stats.truncated_packets += 1;
+    log!(
+        "Skipping packet. Read {}b of a {total_len}b packet",
+        packet.len()
+    );
+    true
+}
+
+/// Hold on to fragments until their datagram is complete, returning the packet to process
+/// once there is one. Anything which isn't a fragment is passed straight through.
+/// RFC 791 Section 3.2
+pub(crate) fn reassemble<'a>(
+    reassembler: &mut Reassembler,
+    stats: &mut StackStats,
+    packet: &'a [u8],
+    now: Instant,
+) -> Option<Cow<'a, [u8]>> {
+    let Ok(ip_header) = Ipv4HeaderSlice::from_slice(packet) else {
+        return Some(Cow::Borrowed(packet));
+    };
+
+    if !ip_header.is_fragmenting_payload() {
+        return Some(Cow::Borrowed(packet));
+    }
+
+    stats.fragments_in += 1;
+
+    if let Err(err) = checksum::verify_ipv4_header(&ip_header) {
+        stats.ip_checksum_errors += 1;
+        log!("Skipping fragment. {err:?} checksum doesn't match");
+        return None;
+    }
+
+    // Anything past the IP total length is padding
+    let header_len: usize = ip_header.slice().len();
+    let payload: &[u8] =
+        &packet[header_len..(ip_header.total_len() as usize).clamp(header_len, packet.len())];
+
+    match reassembler.push(&ip_header, payload, now) {
+        Ok(datagram) => datagram.map(Cow::Owned),
+        Err(err) => {
+            stats.reassembly_failures += 1;
+            log!("Dropping fragmented datagram. {err:?}");
+            None
+        }
+    }
+}
+
+/// Block until one of `fds` can be read without blocking or `deadline` has passed,
+/// returning which are ready. `None` entries are skipped. A hang up counts as ready,
+/// as reading then returns the end of the file rather than blocking.
+#[cfg(unix)]
+pub fn wait_for_input<const N: usize>(
+    fds: [Option<RawFd>; N],
+    deadline: Option<Instant>,
+) -> Result<[bool; N]> {
+    let timeout_ms: libc::c_int = match deadline {
+        Some(deadline) => deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .saturating_add(1)
+            .try_into()
+            .unwrap_or(libc::c_int::MAX),
+        None => -1,
+    };
+
+    // A negative fd is ignored by poll
+    let mut poll_fds: [libc::pollfd; N] = fds.map(|fd| libc::pollfd {
+        fd: fd.unwrap_or(-1),
+        events: libc::POLLIN,
+        revents: 0,
+    });
+
+    // SAFETY: `poll_fds` is an array of valid pollfds which outlives the call
+    let n_ready: libc::c_int = unsafe {
+        libc::poll(
+            poll_fds.as_mut_ptr(),
+            poll_fds.len() as libc::nfds_t,
+            timeout_ms,
+        )
+    };
+
+    if n_ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok([false; N]);
+        }
+        return Err(err.into());
+    }
+
+    Ok(poll_fds.map(|poll_fd| poll_fd.revents & (libc::POLLIN | libc::POLLHUP) != 0))
+}
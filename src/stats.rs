@@ -0,0 +1,265 @@
+use alloc::vec::Vec;
+use core::{fmt, time::Duration};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::tcp::{ConnectInfo, State};
+#[cfg(feature = "std")]
+use crate::{clock::Instant, tcp::Tcb};
+
+/// Counters kept by each connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub segments_in: u64,
+    pub segments_out: u64,
+    /// Payload bytes received, excluding headers
+    pub bytes_in: u64,
+    /// Payload bytes sent, excluding headers
+    pub bytes_out: u64,
+    /// Segments for this connection dropped because a checksum didn't match
+    pub checksum_errors: u64,
+    /// Segments carrying data which had already been received
+    pub duplicate_segments: u64,
+    /// Segments carrying data which arrived ahead of RCV.NXT, and were dropped
+    pub out_of_order_segments: u64,
+    /// Segments sent again after the retransmission timer expired
+    pub retransmissions: u64,
+    /// RFC 5681 Section 2, ACKs which don't advance SND.UNA, carry no data and don't
+    /// change the window, while data is outstanding
+    pub duplicate_acks: u64,
+    /// Segments which took the header prediction fast path, see [`Tcb::on_packet`]
+    pub predicted_segments: u64,
+    /// Smoothed round trip time, once one has been measured. Unlike the counters this
+    /// isn't reset by [`Tcb::take_stats`].
+    pub rtt: Option<Duration>,
+}
+
+/// One connection's state, sequence variables and queues alongside its counters, as
+/// `ss` or `netstat` would list it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    pub connection: ConnectInfo,
+    pub state: State,
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub snd_wnd: u32,
+    pub rcv_nxt: u32,
+    pub rcv_wnd: u32,
+    /// Bytes sent or waiting to be sent which the peer hasn't acknowledged
+    pub send_queue: usize,
+    /// Bytes received which haven't been read
+    pub recv_queue: usize,
+    pub stats: ConnectionStats,
+}
+
+/// Counters kept for the whole stack
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StackStats {
+    /// Packets read from the device
+    pub packets_in: u64,
+    /// Packets which couldn't be decoded as IPv4 TCP segments or ICMP messages
+    pub packets_invalid: u64,
+    /// Packets dropped because the device returned fewer bytes than the IP header declares
+    pub truncated_packets: u64,
+    /// Packets dropped because the IPv4 header checksum didn't match
+    pub ip_checksum_errors: u64,
+    /// Fragments of IPv4 datagrams received
+    pub fragments_in: u64,
+    /// Datagrams whose fragments were dropped because they overlapped, didn't all arrive
+    /// in time or there were too many datagrams to reassemble
+    pub reassembly_failures: u64,
+    /// Segments dropped because the TCP checksum didn't match
+    pub tcp_checksum_errors: u64,
+    /// Segments for a port with nothing listening
+    pub segments_to_closed_ports: u64,
+    /// Segments dropped because the source or destination port was 0
+    pub zero_port_segments: u64,
+    /// ICMP messages received
+    pub icmp_in: u64,
+    /// ICMP Port Unreachable messages sent
+    pub icmp_out: u64,
+    /// RSTs sent in answer to segments for ports with nothing listening
+    pub resets_out: u64,
+    pub connections_accepted: u64,
+    pub connections_closed: u64,
+    /// Connections in TIME-WAIT replaced by a new incarnation, see
+    /// [`TimeWaitPolicy::Reopen`](crate::listener::TimeWaitPolicy::Reopen)
+    pub time_wait_reopened: u64,
+    /// SYNs dropped because the stack already held
+    /// [`StackConfig::max_connections`](crate::config::StackConfig::max_connections)
+    pub connections_refused: u64,
+    /// Connections aborted after seeing nothing from the peer for
+    /// [`StackConfig::idle_timeout`](crate::config::StackConfig::idle_timeout)
+    pub connections_evicted: u64,
+}
+
+/// Stack counters along with those of every open connection
+pub struct StatsSnapshot {
+    /// Time covered by the counters, since they were last reset or the stack started
+    pub interval: Duration,
+    pub stack: StackStats,
+    pub connections: Vec<(ConnectInfo, State, ConnectionStats)>,
+}
+
+impl StatsSnapshot {
+    /// Fold in the counters of another part of the same stack, such as another shard.
+    /// The interval is the longer of the two.
+    pub fn merge(&mut self, other: StatsSnapshot) {
+        let (ours, theirs): (&mut StackStats, StackStats) = (&mut self.stack, other.stack);
+        ours.packets_in += theirs.packets_in;
+        ours.packets_invalid += theirs.packets_invalid;
+        ours.truncated_packets += theirs.truncated_packets;
+        ours.ip_checksum_errors += theirs.ip_checksum_errors;
+        ours.fragments_in += theirs.fragments_in;
+        ours.reassembly_failures += theirs.reassembly_failures;
+        ours.tcp_checksum_errors += theirs.tcp_checksum_errors;
+        ours.segments_to_closed_ports += theirs.segments_to_closed_ports;
+        ours.zero_port_segments += theirs.zero_port_segments;
+        ours.icmp_in += theirs.icmp_in;
+        ours.icmp_out += theirs.icmp_out;
+        ours.resets_out += theirs.resets_out;
+        ours.connections_accepted += theirs.connections_accepted;
+        ours.connections_closed += theirs.connections_closed;
+        ours.time_wait_reopened += theirs.time_wait_reopened;
+        ours.connections_refused += theirs.connections_refused;
+        ours.connections_evicted += theirs.connections_evicted;
+
+        self.interval = self.interval.max(other.interval);
+        self.connections.extend(other.connections);
+        self.connections
+            .sort_by_key(|(info, _, _)| (info.src_addr, info.src_port));
+    }
+}
+
+/// Collects the stack's counters and when they were last reset
+#[cfg(feature = "std")]
+pub struct StatsRecorder {
+    pub stack: StackStats,
+    since: Instant,
+}
+
+#[cfg(feature = "std")]
+impl StatsRecorder {
+    pub fn new(now: Instant) -> Self {
+        StatsRecorder {
+            stack: StackStats::default(),
+            since: now,
+        }
+    }
+
+    /// Read every counter, resetting them to zero if `reset` is set.
+    /// The stack is single threaded, so nothing is counted between the read and
+    /// the reset and consecutive intervals add up exactly.
+    pub fn snapshot(
+        &mut self,
+        connections: &mut HashMap<ConnectInfo, Tcb>,
+        now: Instant,
+        reset: bool,
+    ) -> StatsSnapshot {
+        let interval: Duration = now.saturating_duration_since(self.since);
+
+        let mut connection_stats: Vec<(ConnectInfo, State, ConnectionStats)> = connections
+            .iter_mut()
+            .map(|(info, tcb)| {
+                let stats: ConnectionStats = if reset { tcb.take_stats() } else { tcb.stats() };
+                (*info, tcb.state(), stats)
+            })
+            .collect();
+        connection_stats.sort_by_key(|(info, _, _)| (info.src_addr, info.src_port));
+
+        let stack: StackStats = if reset {
+            self.since = now;
+            core::mem::take(&mut self.stack)
+        } else {
+            self.stack
+        };
+
+        StatsSnapshot {
+            interval,
+            stack,
+            connections: connection_stats,
+        }
+    }
+}
+
+impl fmt::Display for StackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packets_in={} packets_invalid={} truncated_packets={} ip_checksum_errors={} fragments_in={} reassembly_failures={} tcp_checksum_errors={} segments_to_closed_ports={} zero_port_segments={} icmp_in={} icmp_out={} resets_out={} connections_accepted={} connections_closed={} time_wait_reopened={} connections_refused={} connections_evicted={}",
+            self.packets_in,
+            self.packets_invalid,
+            self.truncated_packets,
+            self.ip_checksum_errors,
+            self.fragments_in,
+            self.reassembly_failures,
+            self.tcp_checksum_errors,
+            self.segments_to_closed_ports,
+            self.zero_port_segments,
+            self.icmp_in,
+            self.icmp_out,
+            self.resets_out,
+            self.connections_accepted,
+            self.connections_closed,
+            self.time_wait_reopened,
+            self.connections_refused,
+            self.connections_evicted
+        )
+    }
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "segments_in={} segments_out={} bytes_in={} bytes_out={} checksum_errors={} duplicate_segments={} out_of_order_segments={} retransmissions={} duplicate_acks={} predicted_segments={}",
+            self.segments_in,
+            self.segments_out,
+            self.bytes_in,
+            self.bytes_out,
+            self.checksum_errors,
+            self.duplicate_segments,
+            self.out_of_order_segments,
+            self.retransmissions,
+            self.duplicate_acks,
+            self.predicted_segments
+        )?;
+
+        if let Some(rtt) = self.rtt {
+            write!(f, " rtt={:.6}s", rtt.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ConnectionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} snd_una={} snd_nxt={} snd_wnd={} rcv_nxt={} rcv_wnd={} send_queue={} recv_queue={} {}",
+            self.connection,
+            self.state,
+            self.snd_una,
+            self.snd_nxt,
+            self.snd_wnd,
+            self.rcv_nxt,
+            self.rcv_wnd,
+            self.send_queue,
+            self.recv_queue,
+            self.stats
+        )
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "interval {:.6}s", self.interval.as_secs_f64())?;
+        write!(f, "stack {}", self.stack)?;
+
+        for (info, state, stats) in &self.connections {
+            write!(f, "\nconnection {info} {state:?} {stats}")?;
+        }
+
+        Ok(())
+    }
+}
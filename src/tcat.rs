@@ -0,0 +1,300 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    net::Shutdown,
+    os::fd::AsRawFd,
+    time::Instant,
+};
+
+#[cfg(unix)]
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::error::{Result, TcpError};
+#[cfg(unix)]
+use crate::{
+    challenge::ChallengeAckLimiter,
+    checksum, clock,
+    config::TcbConfig,
+    device::{NetworkDevice, RecvBuffer},
+    icmp,
+    isn::IsnGenerator,
+    span::log,
+    stack,
+    tcp::{self, State, Tcb},
+    PACKET_BUF_SIZE,
+};
+
+/// Address of the stack on the far side of `tun0`, see `scripts/run.sh`
+pub const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+/// Start of the range local ports are picked from when connecting.
+/// RFC 6335 Section 6
+#[cfg(unix)]
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Most bytes read from stdin at once
+#[cfg(unix)]
+const STDIN_CHUNK_SIZE: usize = 8 * 1024;
+
+const USAGE: &str = "Usage: tcp_rs tcat <host> <port> | tcp_rs tcat -l <port>";
+
+/// How `tcat` opens its connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcatMode {
+    /// Connect to a remote address, like `nc <host> <port>`
+    Connect(SocketAddrV4),
+    /// Wait for a single connection to a local port, like `nc -l <port>`
+    Listen(u16),
+}
+
+impl TcatMode {
+    /// Parse the arguments following `tcat`
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mode = match (args.next(), args.next()) {
+            (Some(flag), Some(port)) if flag == "-l" => TcatMode::Listen(port.parse()?),
+            (Some(host), Some(port)) => {
+                TcatMode::Connect(SocketAddrV4::new(host.parse()?, port.parse()?))
+            }
+            _ => return Err(TcpError::Parse(USAGE.into())),
+        };
+
+        if args.next().is_some() {
+            return Err(TcpError::Parse(USAGE.into()));
+        }
+
+        Ok(mode)
+    }
+}
+
+/// Pipe stdin and stdout through a single connection over `nic`, like netcat.
+///
+/// Received data is written to stdout as it arrives. Stdin is only read while the send
+/// buffer has room, so a slow peer holds up the writer rather than the data piling up.
+/// At the end of stdin our side is closed with a FIN, and data from the peer is still
+/// received until it closes too, RFC 9293 Section 3.6. Once both sides have closed,
+/// or the connection is reset, `tcat` exits.
+#[cfg(unix)]
+pub fn run<D: NetworkDevice + AsRawFd>(nic: &D, mode: TcatMode) -> Result<()> {
+    let isn = IsnGenerator::from_os_random()?;
+    let mut challenge_acks = ChallengeAckLimiter::default();
+    let mut stdout = io::stdout().lock();
+
+    let mut tcb: Option<Tcb> = match mode {
+        TcatMode::Connect(remote) => {
+            let local = SocketAddrV4::new(LOCAL_ADDR, ephemeral_port());
+            Some(Tcb::connect(nic, local, remote, &isn, &clock::system())?)
+        }
+        TcatMode::Listen(port) => {
+            log!("Listening on {LOCAL_ADDR}:{port}");
+            None
+        }
+    };
+
+    let mut stdin_open: bool = true;
+    let mut closed: bool = false;
+    let mut established: bool = false;
+    let mut packet_buf = RecvBuffer::for_device(nic);
+    let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+
+    loop {
+        if let Some(tcb) = &mut tcb {
+            established |= tcb.state().is_synchronised();
+
+            match tcb.state() {
+                State::Closed if established => return Ok(()),
+                State::Closed => return Err(TcpError::Failed("Connection failed".into())),
+                // Both sides have closed, the peer's FIN has been acknowledged
+                State::TimeWait => return Ok(()),
+                _ => {}
+            }
+
+            // Shutting down writing before the handshake completes would drop the data
+            // queued so far. Reading carries on until the peer closes too.
+            if !stdin_open && !closed && tcb.state() != State::SynSent {
+                tcb.shutdown(nic, Shutdown::Write)?;
+                closed = true;
+            }
+        }
+
+        let read_stdin: bool = stdin_open
+            && tcb.as_ref().is_some_and(|tcb| {
+                tcb.send_space() > 0
+                    && matches!(
+                        tcb.state(),
+                        State::SynSent | State::SynRcvd | State::Estab | State::CloseWait
+                    )
+            });
+        let deadline: Option<Instant> = tcb.as_ref().and_then(Tcb::next_deadline);
+
+        let [packet_ready, stdin_ready] = stack::wait_for_input(
+            [
+                Some(nic.as_raw_fd()),
+                read_stdin.then_some(libc::STDIN_FILENO),
+            ],
+            deadline,
+        )?;
+
+        if packet_ready {
+            if let Some(packet) = packet_buf.recv(nic)? {
+                handle_packet(nic, &mut tcb, mode, &mut challenge_acks, &isn, packet)?;
+            }
+        }
+
+        let Some(tcb) = &mut tcb else {
+            continue;
+        };
+
+        if stdin_ready {
+            let mut chunk: [u8; STDIN_CHUNK_SIZE] = [0; STDIN_CHUNK_SIZE];
+            let n_wanted: usize = chunk.len().min(tcb.send_space());
+            let n_read: usize = read_stdin_chunk(&mut chunk[..n_wanted])?;
+
+            if n_read == 0 {
+                stdin_open = false;
+            } else {
+                tcb.send(nic, &chunk[..n_read])?;
+            }
+        }
+
+        let now = Instant::now();
+        if tcb.next_deadline().is_some_and(|deadline| deadline <= now) {
+            tcb.on_tick(nic, now)?;
+        }
+
+        // Trouble on the path is worth knowing about, but doesn't end the connection
+        for error in tcb.take_soft_errors() {
+            log!("Warning: {error:?}");
+        }
+
+        let n_read: usize = tcb.read(&mut buf);
+        if n_read > 0 {
+            stdout.write_all(&buf[..n_read])?;
+            stdout.flush()?;
+        }
+    }
+}
+
+/// Pass a packet to the connection it belongs to. While listening, a SYN to the port
+/// opens the connection. Segments for anything else are answered as a closed port.
+#[cfg(unix)]
+fn handle_packet(
+    nic: &impl NetworkDevice,
+    tcb: &mut Option<Tcb>,
+    mode: TcatMode,
+    challenge_acks: &mut ChallengeAckLimiter,
+    isn: &IsnGenerator,
+    packet: &[u8],
+) -> Result<()> {
+    let Ok(ip_header) = Ipv4HeaderSlice::from_slice(packet) else {
+        return Ok(());
+    };
+
+    if ip_header.total_len() as usize > packet.len() {
+        log!("Skipping truncated packet");
+        return Ok(());
+    }
+
+    // Anything past the IP total length is padding
+    let header_len: usize = ip_header.slice().len();
+    let ip_payload: &[u8] =
+        &packet[header_len..(ip_header.total_len() as usize).clamp(header_len, packet.len())];
+
+    if ip_header.is_fragmenting_payload() {
+        log!("Skipping fragment. tcat doesn't reassemble datagrams");
+        return Ok(());
+    }
+
+    if ip_header.protocol() == IpNumber::ICMP {
+        if let (Some(message), Some(tcb)) = (icmp::parse_error(&ip_header, ip_payload), tcb) {
+            if message.connection == connection_of(tcb) {
+                tcb.on_icmp_error(message.seq, message.error);
+            }
+        }
+        return Ok(());
+    }
+
+    if ip_header.protocol() != IpNumber::TCP {
+        return Ok(());
+    }
+
+    let Ok(tcp_header) = TcpHeaderSlice::from_slice(ip_payload) else {
+        return Ok(());
+    };
+    // Port 0 is reserved, and a reset sent to it would be just as invalid
+    if tcp_header.source_port() == 0 || tcp_header.destination_port() == 0 {
+        return Ok(());
+    }
+
+    let data: &[u8] = &ip_payload[tcp_header.slice().len()..];
+
+    if let Err(err) = checksum::verify(&ip_header, &tcp_header, data) {
+        log!("Skipping packet. {err:?} checksum doesn't match");
+        return Ok(());
+    }
+
+    let info = tcp::ConnectInfo {
+        src_addr: ip_header.source_addr(),
+        src_port: tcp_header.source_port(),
+        dst_addr: ip_header.destination_addr(),
+        dst_port: tcp_header.destination_port(),
+    };
+
+    match (tcb.as_mut(), mode) {
+        (Some(tcb), _) if info == connection_of(tcb) => {
+            tcb.on_packet(nic, ip_header, tcp_header, data, challenge_acks)?;
+        }
+        (None, TcatMode::Listen(port)) if info.dst_port == port => {
+            *tcb = Tcb::accept_connection(nic, ip_header, tcp_header, data, isn, &clock::system())?;
+            if let Some(tcb) = tcb {
+                log!("Connection from {}", tcb.remote());
+            }
+        }
+        _ => tcp::send_reset(nic, &ip_header, &tcp_header, data, TcbConfig::DEFAULT.ttl)?,
+    }
+
+    Ok(())
+}
+
+/// The connection's 4-tuple as seen on segments from the peer
+#[cfg(unix)]
+fn connection_of(tcb: &Tcb) -> tcp::ConnectInfo {
+    tcp::ConnectInfo {
+        src_addr: *tcb.remote().ip(),
+        src_port: tcb.remote().port(),
+        dst_addr: *tcb.local().ip(),
+        dst_port: tcb.local().port(),
+    }
+}
+
+/// A random port from the ephemeral range
+#[cfg(unix)]
+fn ephemeral_port() -> u16 {
+    let random: u64 = RandomState::new().build_hasher().finish();
+    let n_ports: u64 = (u16::MAX - EPHEMERAL_PORT_START) as u64 + 1;
+
+    EPHEMERAL_PORT_START + (random % n_ports) as u16
+}
+
+/// Read whatever stdin has, at most `buf.len()` bytes. Zero means the end of stdin.
+/// Stdin is read directly rather than through [`io::Stdin`], whose buffering would hide
+/// data from `poll`.
+#[cfg(unix)]
+fn read_stdin_chunk(buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes for the length of the call
+        let n_read: isize =
+            unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+
+        if n_read >= 0 {
+            return Ok(n_read as usize);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
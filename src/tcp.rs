@@ -1,13 +1,42 @@
-use std::{cmp::Ordering, io::Write, net::Ipv4Addr};
+use alloc::{borrow::Cow, boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+pub use std::net::Shutdown;
 
-use anyhow::Result;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::ConnectionState;
+use crate::{
+    challenge::ChallengeAckLimiter,
+    checksum,
+    clock::{Clock, Instant},
+    config::TcbConfig,
+    congestion::CongestionWindow,
+    device::NetworkDevice,
+    error::{Result, TcpError},
+    hooks::{SegmentHook, SegmentInfo, Verdict},
+    icmp::IcmpError,
+    io::IoSlice,
+    isn::IsnGenerator,
+    options::{self, OptionHook, OutgoingOptions, FAST_OPEN_COOKIE_LEN},
+    pacing::{self, Pacer},
+    pmtu::{self, PathMtu},
+    rto::RtoEstimator,
+    seq::SeqNum,
+    span::{self, debug, log, trace, ConnectionId},
+    stats::{ConnectionStats, ConnectionSummary},
+    timer::TimerHandle,
+    timestamps::Timestamps,
+    window::WindowScale,
+    ETH_MTU,
+};
 use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
-use tun_tap::Iface;
-
-use crate::ETH_MTU;
 
 /// Variables relating tracking which bytes can be sent and whether they are acknowledged by the reciever
-/// ```
+/// ```text
 /// Send Sequence Space
 /// RFC 793 Section 3.2 Figure 4.
 ///      1         2          3          4
@@ -20,24 +49,30 @@ use crate::ETH_MTU;
 /// 3 - sequence numbers allowed for new data transmission
 /// 4 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 struct SendSequenceVariables {
     /// Send unacknowledged
-    pub una: u32,
+    pub una: SeqNum,
     /// Send next
-    pub nxt: u32,
-    /// Send window
-    pub wnd: u16,
+    pub nxt: SeqNum,
+    /// Highest sequence number sent, which SND.NXT falls back behind when retransmitting.
+    /// ACKs up to here are for data the peer really was sent.
+    pub max: SeqNum,
+    /// Send window in bytes, as advertised by the peer
+    pub wnd: u32,
+    /// Scale applied to the window field of the peer's segments
+    pub scale: WindowScale,
     /// Send urgent pointer
     pub up: bool,
     /// Segment sequence number used for last window update
-    pub wl1: u32,
+    pub wl1: SeqNum,
     /// Segment acknowledgement number used for last window update
-    pub wl2: u32,
+    pub wl2: SeqNum,
     /// Initial send sequence number
-    pub iss: u32,
+    pub iss: SeqNum,
 }
 
-/// ```
+/// ```text
 /// Receive Sequence Space
 /// RFC 793 Section 3.2 Figure 5.
 ///      1          2          3
@@ -49,18 +84,21 @@ struct SendSequenceVariables {
 /// 2 - sequence numbers allowed for new reception
 /// 3 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 struct RecvSequenceVariables {
     /// receive next
-    pub nxt: u32,
-    /// receive window
-    pub wnd: u16,
+    pub nxt: SeqNum,
+    /// receive window in bytes
+    pub wnd: u32,
+    /// scale applied to the window field of our segments
+    pub scale: WindowScale,
     /// receive urgent pointer
     pub up: bool,
     /// initial receive sequence number
-    pub irs: u32,
+    pub irs: SeqNum,
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct ConnectInfo {
     pub src_addr: Ipv4Addr,
     pub src_port: u16,
@@ -68,330 +106,2786 @@ pub struct ConnectInfo {
     pub dst_port: u16,
 }
 
+impl fmt::Display for ConnectInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} -> {}:{}",
+            self.src_addr, self.src_port, self.dst_addr, self.dst_port
+        )
+    }
+}
+
+/// Maximum segment lifetime, the longest a segment is assumed to survive in the network.
+/// Twice this is spent in TIME-WAIT, giving the 60 seconds Linux uses.
+pub const MSL: Duration = Duration::from_secs(30);
+
+/// Retransmissions of a SYN before giving up on opening the connection, matching Linux
+pub const MAX_SYN_RETRANSMISSIONS: u32 = 6;
+
+/// Retransmissions of any other segment before giving up on the connection, matching Linux.
+/// With the timeout backing off to its 60 second limit this is over 15 minutes.
+pub const MAX_RETRANSMISSIONS: u32 = 15;
+
+/// Retransmissions of one segment after which the application is told the connection
+/// may be failing. RFC 1122 Section 4.2.3.5, R1 of at least 3 retransmissions.
+pub const R1_RETRANSMISSIONS: u32 = 3;
+
+/// Soft errors kept for the application to read, the oldest being dropped past this
+const MAX_SOFT_ERRORS: usize = 16;
+
+/// Room for the largest IPv4 and TCP headers, 60 bytes each
+const MAX_HEADERS_LEN: usize = 120;
+
+/// Offset of the checksum within a TCP header
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+/// MSS assumed when the peer's SYN has no MSS option.
+/// RFC 9293 Section 3.7.1
+pub const DEFAULT_MSS: u16 = 536;
+
+/// Smallest MSS taken from the peer's SYN, as Linux's `TCP_MIN_MSS`. Anything less
+/// leaves little or no room for data once the headers' options are taken out.
+pub const MIN_MSS: u16 = 88;
+
+/// Most data `send` will hold which hasn't been acknowledged yet
+pub const SEND_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Keep-alive settings for a connection.
+/// RFC 1122 Section 4.2.3.6
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// How long the connection must be idle before the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection is torn down
+    pub probes: u32,
+}
+
+impl KeepaliveConfig {
+    /// Defaults recommended by RFC 1122, matching Linux
+    pub const DEFAULT: KeepaliveConfig = KeepaliveConfig {
+        idle: Duration::from_secs(2 * 60 * 60),
+        interval: Duration::from_secs(75),
+        probes: 9,
+    };
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig::DEFAULT
+    }
+}
+
+/// What closing a connection does while received data is still waiting to be read.
+/// RFC 1122 Section 4.2.2.13
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnreadDataPolicy {
+    /// Send a RST instead of a FIN, as RFC 1122 recommends and Linux does, so the peer
+    /// knows its data was never consumed. Data arriving after the close is also answered
+    /// with a RST.
+    #[default]
+    Reset,
+    /// Close normally with a FIN, leaving the data unread
+    Fin,
+}
+
+/// Which halves of a connection [`Tcb::shutdown`] closes, the same as std's
+/// `std::net::Shutdown`, which it is with the `std` feature
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    Read,
+    Write,
+    Both,
+}
+
+/// A setting which can be changed on a live connection, see [`Tcb::set_option`]. Each
+/// has a getter of its own on [`Tcb`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketOption {
+    /// Send data as soon as it's queued, rather than holding small segments back while
+    /// anything is unacknowledged. On by default.
+    /// RFC 1122 Section 4.2.3.4, turning it off enables the Nagle algorithm.
+    NoDelay(bool),
+    /// Keep-alive probe settings, or `None` to send no probes, see [`Tcb::set_keepalive`]
+    Keepalive(Option<KeepaliveConfig>),
+    /// What [`Tcb::close`] does about data still to be sent. `None` closes gracefully in
+    /// the background, `Some(Duration::ZERO)` resets the connection instead, and any
+    /// other time resets it if our FIN still hasn't been acknowledged by then.
+    Linger(Option<Duration>),
+    /// Most received bytes held for reading, which bounds the receive window
+    RecvBufferSize(usize),
+    /// Most bytes [`Tcb::send`] holds before they're acknowledged
+    SendBufferSize(usize),
+    /// How long sent data can go unacknowledged before the connection is dropped, in place
+    /// of the retransmission limit, or `None` to keep the limit.
+    /// RFC 5482 Section 2 and RFC 9293 Section 3.8.3
+    UserTimeout(Option<Duration>),
+    /// Spread segments across the round trip rather than sending a window back to back,
+    /// see [`Pacer`]. Off by default.
+    Pacing(bool),
+}
+
+/// A sign the connection is in trouble which doesn't end it.
+/// RFC 1122 Section 4.2.3.5 and 4.2.3.9, the connection carries on and the application
+/// is told, see [`Tcb::take_soft_errors`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoftError {
+    /// An ICMP error which doesn't abort the connection in its current state
+    Icmp(IcmpError),
+    /// A segment has been retransmitted [`R1_RETRANSMISSIONS`] times without being
+    /// acknowledged
+    RetransmissionThreshold,
+}
+
 /// Transmission Control Block.
 /// A record of all the variables needed for a TCP conenction.
 pub struct Tcb {
     state: State,
+    /// Whether the connection was opened by a SYN from the peer rather than by `connect`
+    passive_open: bool,
     recv: RecvSequenceVariables,
     send: SendSequenceVariables,
     send_ip_header: Ipv4Header,
     send_tcp_header: TcpHeader,
+    keepalive: Option<KeepaliveConfig>,
+    /// When the last acceptable segment arrived
+    last_recv: Instant,
+    /// Keep-alive probes sent since `last_recv`
+    keepalive_probes_sent: u32,
+    /// How long after `last_recv` the connection is aborted
+    idle_timeout: Option<Duration>,
+    /// How long TIME-WAIT lasts
+    time_wait: Duration,
+    /// When the connection leaves TIME-WAIT and can be deleted
+    time_wait_deadline: Option<Instant>,
+    /// Option space used on outgoing segments, at most [`options::MAX_OPTIONS_LEN`]
+    max_options_len: usize,
+    option_hook: Option<Box<dyn OptionHook>>,
+    segment_hook: Option<Box<dyn SegmentHook>>,
+    stats: ConnectionStats,
+    /// Payload bytes sent which the stack hasn't counted against a listener yet, see
+    /// [`Tcb::take_bytes_out`]
+    uncounted_bytes_out: u64,
+    /// In-order data received but not yet read, which shrinks the receive window
+    recv_buffer: VecDeque<u8>,
+    /// Most bytes `recv_buffer` holds, which the receive window never goes past
+    recv_buffer_size: usize,
+    /// When reading opened the receive window, until a segment has told the peer
+    window_update_due: Option<Instant>,
+    /// Whether ACKs of in-order data wait for the end of a batch, see [`Tcb::end_batch`]
+    coalesce_acks: bool,
+    /// An ACK of in-order data is waiting for the end of the batch
+    ack_deferred: bool,
+    /// Bytes at the front of `recv_buffer` up to the end of the last segment with PSH set
+    recv_pushed: usize,
+    /// Unpushed bytes `read` waits for before returning any, see [`Tcb::set_recv_low_water`]
+    recv_low_water: usize,
+    /// Data from `send` which hasn't been acknowledged, starting at SND.UNA
+    send_buffer: VecDeque<u8>,
+    /// Most bytes `send_buffer` holds
+    send_buffer_size: usize,
+    /// Bytes ever removed from the front of `send_buffer`, so positions in the stream of
+    /// data sent hold across acknowledgements
+    send_buffer_start: u64,
+    /// Stream positions just after the end of each `send` not yet acknowledged, where a
+    /// segment ending at or past one is pushed. RFC 9293 Section 3.9.1.2
+    push_points: VecDeque<u64>,
+    /// Whether writing has been shut down but the FIN is waiting for data before it to be
+    /// sent. The state moves on as soon as it's queued.
+    fin_queued: bool,
+    /// Whether reading has been shut down, so received data will never be read
+    recv_shutdown: bool,
+    /// Whether the peer closed the connection with a reset
+    reset_by_peer: bool,
+    /// Whether that reset answered our SYN, so the connection was never established
+    refused_by_peer: bool,
+    unread_data_policy: UnreadDataPolicy,
+    /// Whether small segments go out while data is unacknowledged, see [`SocketOption::NoDelay`]
+    nodelay: bool,
+    /// Whether segments are spread across the round trip, see [`SocketOption::Pacing`]
+    pacing: bool,
+    pacer: Pacer,
+    congestion: CongestionWindow,
+    /// See [`SocketOption::Linger`]
+    linger: Option<Duration>,
+    /// When a lingering close gives up and resets the connection
+    linger_deadline: Option<Instant>,
+    /// See [`SocketOption::UserTimeout`]
+    user_timeout: Option<Duration>,
+    /// When the oldest unacknowledged data was sent, or last saw progress
+    unacked_since: Option<Instant>,
+    path_mtu: PathMtu,
+    /// Largest segment the peer will accept, from the MSS option on its SYN
+    send_mss: u16,
+    /// MSS option sent on our SYN, if any
+    recv_mss: Option<u16>,
+    rto: RtoEstimator,
+    /// When the retransmission timer was last started, while anything is unacknowledged
+    retransmit_timer: Option<Instant>,
+    /// Times the oldest unacknowledged segment has been retransmitted
+    retransmissions: u32,
+    /// Whether our SYN or SYN,ACK was ever retransmitted
+    syn_retransmitted: bool,
+    /// The SND.NXT which acknowledges the segment being timed for an RTT measurement,
+    /// and when it was sent
+    rtt_timed: Option<(SeqNum, Instant)>,
+    /// Set once both sides have agreed to send timestamps, RFC 7323 Section 3.2
+    timestamps: Option<Timestamps>,
+    /// Fast Open cookie to send on our SYN,ACK, RFC 7413 Section 4.2.2
+    fast_open_cookie: Option<[u8; FAST_OPEN_COOKIE_LEN]>,
+    /// Whether the peer's SYN offered SACK permitted, so it accepts SACK blocks from us
+    sack_permitted: bool,
+    /// Whether to report duplicate data with D-SACK blocks when the peer accepts them
+    dsack_enabled: bool,
+    /// Duplicate data to report on the next ACK, RFC 2883 Section 4
+    dsack: Option<(u32, u32)>,
+    /// Soft errors the application hasn't taken yet, oldest first
+    soft_errors: VecDeque<SoftError>,
+    /// Outgoing segments still to be discarded, see [`Tcb::drop_next_segments`]
+    #[cfg(feature = "fault-injection")]
+    segments_to_drop: u32,
+    /// Source of the time for every timer, see [`Stack::set_clock`](crate::stack::Stack::set_clock)
+    clock: Arc<dyn Clock>,
+    /// Where the stack has scheduled `next_deadline`, if it has
+    timer: Option<TimerHandle>,
+    /// Tags everything logged while working on this connection, see [`span::enter`]
+    id: ConnectionId,
 }
 
 impl Tcb {
+    /// Answer a SYN from the peer, with the [default](TcbConfig::DEFAULT) settings
     pub fn accept_connection(
-        nic: &Iface,
+        nic: &impl NetworkDevice,
+        ip_header: Ipv4HeaderSlice,
+        tcp_header: TcpHeaderSlice,
+        data: &[u8],
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+    ) -> Result<Option<Self>> {
+        Tcb::accept_connection_with_config(
+            nic,
+            ip_header,
+            tcp_header,
+            data,
+            isn,
+            clock,
+            &TcbConfig::DEFAULT,
+        )
+    }
+
+    /// Answer a SYN from the peer, with buffers and options set by `config`
+    pub fn accept_connection_with_config(
+        nic: &impl NetworkDevice,
+        ip_header: Ipv4HeaderSlice,
+        tcp_header: TcpHeaderSlice,
+        data: &[u8],
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Option<Self>> {
+        let Some(mut tcb) = Tcb::accept(nic, &ip_header, &tcp_header, data, isn, clock, config)?
+        else {
+            return Ok(None);
+        };
+
+        let _span = span::enter(tcb.id);
+        tcb.write(nic, Payload::EMPTY)?;
+        Ok(Some(tcb))
+    }
+
+    /// Answer a SYN from the peer as [`Tcb::accept_connection_with_config`] does, taking
+    /// part in TCP Fast Open.
+    /// RFC 7413 Section 4.2.2, data on a SYN with a valid cookie is accepted straight away
+    /// and acknowledged by the SYN,ACK. A SYN asking for a cookie, or carrying one which
+    /// isn't valid, gets one on the SYN,ACK and its data is left for the peer to send again
+    /// once the handshake is done.
+    pub fn accept_fast_open_connection(
+        nic: &impl NetworkDevice,
         ip_header: Ipv4HeaderSlice,
         tcp_header: TcpHeaderSlice,
         data: &[u8],
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Option<Self>> {
+        let Some(mut tcb) = Tcb::accept(nic, &ip_header, &tcp_header, data, isn, clock, config)?
+        else {
+            return Ok(None);
+        };
+
+        let _span = span::enter(tcb.id);
+        if let Some(offered) = options::fast_open(tcp_header.options()) {
+            let cookie: [u8; FAST_OPEN_COOKIE_LEN] =
+                isn.fast_open_cookie(Ipv4Addr::from(ip_header.source()));
+
+            if offered == cookie {
+                let n_new: usize = tcb.receive_data(tcb.recv.nxt, data);
+                if n_new > 0 && tcp_header.psh() && n_new == data.len() {
+                    tcb.recv_pushed = tcb.recv_buffer.len();
+                }
+                log!("Fast Open: accepted {n_new}b with the SYN");
+            } else {
+                log!("Fast Open: issuing a cookie");
+                tcb.fast_open_cookie = Some(cookie);
+            }
+        }
+
+        tcb.write(nic, Payload::EMPTY)?;
+        Ok(Some(tcb))
+    }
+
+    /// RFC 9293 Section 3.10.7.2, check a segment arriving in the LISTEN state and set up
+    /// the connection if it's a SYN. The SYN,ACK is left to the caller.
+    fn accept(
+        nic: &impl NetworkDevice,
+        ip_header: &Ipv4HeaderSlice,
+        tcp_header: &TcpHeaderSlice,
+        data: &[u8],
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
     ) -> Result<Option<Self>> {
-        println!(
+        log!(
             "{} -> {}:{} {}b of TCP",
-            ip_header.source_addr(),
-            ip_header.destination_addr(),
+            Ipv4Addr::from(ip_header.source()),
+            Ipv4Addr::from(ip_header.destination()),
             tcp_header.destination_port(),
             data.len(),
         );
 
+        // An incoming RST has nothing to reset
+        if tcp_header.rst() {
+            return Ok(None);
+        }
+
+        // Nothing has been sent yet so any acknowledgement is unacceptable
+        if tcp_header.ack() {
+            send_reset(nic, ip_header, tcp_header, data, config.ttl)?;
+            return Ok(None);
+        }
+
         // Packet must be SYN
         if !tcp_header.syn() {
             return Ok(None);
         }
 
-        let iss = 0;
-        let wnd = 1024;
+        if tcp_header.source_port() == 0 || tcp_header.destination_port() == 0 {
+            return Ok(None);
+        }
+
+        trace!("Received ip header: \n{:02x?}", ip_header.slice());
+        trace!("Received tcp header: \n{:02x?}", tcp_header.slice());
+
+        let local = SocketAddrV4::new(
+            Ipv4Addr::from(ip_header.destination()),
+            tcp_header.destination_port(),
+        );
+        let remote =
+            SocketAddrV4::new(Ipv4Addr::from(ip_header.source()), tcp_header.source_port());
+        let iss: u32 = isn.generate(local, remote, clock.now());
+
+        let mut tcb = Tcb::new(State::SynRcvd, local, remote, iss, nic.mtu(), clock, config)?;
+        let _span = span::enter(tcb.id);
+
+        tcb.passive_open = true;
+        tcb.send_mss = peer_mss(tcp_header.options());
+        // RFC 7323 Section 3.2, timestamps are only sent if the peer's SYN offered them
+        tcb.timestamps = options::timestamps(tcp_header.options())
+            .filter(|_| config.timestamps)
+            .map(|(ts_val, _)| Timestamps::new(iss, ts_val, clock.now()));
+        tcb.sack_permitted = options::sack_permitted(tcp_header.options());
+        tcb.recv.irs = SeqNum::new(tcp_header.sequence_number());
+        tcb.recv.nxt = tcb.recv.irs + 1;
+        tcb.update_send_window(tcp_header);
+        tcb.send_tcp_header.syn = true;
+        tcb.send_tcp_header.ack = true;
+
+        Ok(Some(tcb))
+    }
+
+    /// Actively open a connection from `local` to `remote`, sending a SYN, with the
+    /// [default](TcbConfig::DEFAULT) settings.
+    /// RFC 9293 Section 3.10.1
+    pub fn connect(
+        nic: &impl NetworkDevice,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+    ) -> Result<Self> {
+        Tcb::connect_with_config(nic, local, remote, isn, clock, &TcbConfig::DEFAULT)
+    }
+
+    /// Actively open a connection from `local` to `remote`, with buffers and options set
+    /// by `config`
+    pub fn connect_with_config(
+        nic: &impl NetworkDevice,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        isn: &IsnGenerator,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Self> {
+        let iss: u32 = isn.generate(local, remote, clock.now());
+        Tcb::connect_with_iss(nic, local, remote, iss, clock, config)
+    }
+
+    /// Actively open a connection from `local` to `remote` with initial sequence number
+    /// `iss` already chosen, as when the caller has checked it against an old incarnation
+    pub fn connect_with_iss(
+        nic: &impl NetworkDevice,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        iss: u32,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Self> {
+        if local.port() == 0 || remote.port() == 0 {
+            return Err(TcpError::InvalidInput("port 0 is reserved"));
+        }
 
+        let mut tcb = Tcb::new(State::SynSent, local, remote, iss, nic.mtu(), clock, config)?;
+        let _span = span::enter(tcb.id);
+
+        // Offered on the SYN, then kept only if the peer's SYN offers them too
+        if config.timestamps {
+            tcb.timestamps = Some(Timestamps::new(iss, 0, clock.now()));
+        }
+        tcb.send_tcp_header.syn = true;
+        tcb.write(nic, Payload::EMPTY)?;
+
+        Ok(tcb)
+    }
+
+    /// A TCB in `state` with nothing sent or received yet
+    fn new(
+        state: State,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        iss: u32,
+        link_mtu: usize,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Self> {
         let recv = RecvSequenceVariables {
-            irs: tcp_header.sequence_number(),
-            nxt: tcp_header.sequence_number() + 1,
-            wnd: tcp_header.window_size(),
+            irs: SeqNum::default(),
+            nxt: SeqNum::default(),
+            wnd: config.recv_window,
+            scale: WindowScale::NONE,
             up: false,
         };
 
+        let iss = SeqNum::new(iss);
         let send = SendSequenceVariables {
             iss,
             una: iss,
             nxt: iss,
-            wnd,
+            max: iss,
+            wnd: 0,
+            scale: WindowScale::NONE,
             up: false,
-            wl1: 0,
-            wl2: 0,
+            wl1: SeqNum::default(),
+            wl2: SeqNum::default(),
         };
 
         let send_tcp_header = TcpHeader {
-            source_port: tcp_header.destination_port(),
-            destination_port: tcp_header.source_port(),
-            acknowledgment_number: recv.nxt,
-            sequence_number: send.iss,
-            window_size: send.wnd,
-            syn: true,
-            ack: true,
+            source_port: local.port(),
+            destination_port: remote.port(),
+            sequence_number: send.iss.get(),
             ..Default::default()
         };
 
         let send_ip_header_payload_len: u16 = send_tcp_header.header_len_u16();
-        let send_ip_header_ttl: u8 = 64;
+        let send_ip_header_ttl: u8 = config.ttl;
         let send_ip_header_protocol: IpNumber = IpNumber::TCP;
-        let send_ip_header_source: [u8; 4] = ip_header.destination();
-        let send_ip_header_destination: [u8; 4] = ip_header.source();
 
-        let send_ip_header = Ipv4Header::new(
+        let mut send_ip_header = Ipv4Header::new(
             send_ip_header_payload_len,
             send_ip_header_ttl,
             send_ip_header_protocol,
-            send_ip_header_source,
-            send_ip_header_destination,
+            local.ip().octets(),
+            remote.ip().octets(),
         )?;
+        // RFC 1191 Section 3, path MTU discovery relies on routers refusing to fragment
+        send_ip_header.dont_fragment = true;
 
-        println!("Received ip header: \n{:02x?}", ip_header.slice());
-        println!("Received tcp header: \n{:02x?}", tcp_header.slice());
-
-        let mut tcb = Tcb {
-            state: State::SynRcvd,
+        Ok(Tcb {
+            state,
+            passive_open: false,
             send,
             recv,
             send_ip_header,
             send_tcp_header,
-        };
+            keepalive: config.keepalive,
+            last_recv: clock.now(),
+            keepalive_probes_sent: 0,
+            idle_timeout: config.idle_timeout,
+            time_wait: config.time_wait,
+            time_wait_deadline: None,
+            max_options_len: options::MAX_OPTIONS_LEN,
+            option_hook: None,
+            segment_hook: None,
+            stats: ConnectionStats::default(),
+            uncounted_bytes_out: 0,
+            recv_buffer: VecDeque::new(),
+            recv_buffer_size: config.recv_window as usize,
+            window_update_due: None,
+            coalesce_acks: false,
+            ack_deferred: false,
+            recv_pushed: 0,
+            recv_low_water: 1,
+            send_buffer: VecDeque::new(),
+            send_buffer_size: config.send_buffer_size,
+            send_buffer_start: 0,
+            push_points: VecDeque::new(),
+            fin_queued: false,
+            recv_shutdown: false,
+            reset_by_peer: false,
+            refused_by_peer: false,
+            unread_data_policy: UnreadDataPolicy::default(),
+            nodelay: true,
+            pacing: config.pacing,
+            pacer: Pacer::default(),
+            congestion: CongestionWindow::new(DEFAULT_MSS.into(), clock.now()),
+            linger: None,
+            linger_deadline: None,
+            user_timeout: None,
+            unacked_since: None,
+            path_mtu: PathMtu::new(link_mtu.min(ETH_MTU)),
+            send_mss: DEFAULT_MSS,
+            recv_mss: config.mss,
+            rto: RtoEstimator::with_max(config.initial_rto, config.max_rto),
+            retransmit_timer: None,
+            retransmissions: 0,
+            syn_retransmitted: false,
+            rtt_timed: None,
+            timestamps: None,
+            fast_open_cookie: None,
+            sack_permitted: false,
+            dsack_enabled: config.sack,
+            dsack: None,
+            soft_errors: VecDeque::new(),
+            #[cfg(feature = "fault-injection")]
+            segments_to_drop: 0,
+            clock: Arc::clone(clock),
+            timer: None,
+            id: ConnectionId::new(ConnectInfo {
+                src_addr: *remote.ip(),
+                src_port: remote.port(),
+                dst_addr: *local.ip(),
+                dst_port: local.port(),
+            }),
+        })
+    }
 
-        tcb.write(nic, &[])?;
+    /// Identifies this connection in logs, unlike its 4-tuple it's never reused
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
 
-        return Ok(Some(tcb));
+    /// The stack's timer for this connection, see [`TimerQueue`](crate::timer::TimerQueue)
+    pub fn timer(&self) -> Option<TimerHandle> {
+        self.timer
     }
 
-    pub fn on_packet(
-        &mut self,
-        nic: &Iface,
-        ip_header: Ipv4HeaderSlice,
-        tcp_header: TcpHeaderSlice,
-        data: &[u8],
-    ) -> Result<()> {
-        if !self.is_segment_valid(&tcp_header, data) {
-            // https://youtu.be/OCpt1I0MWXE?feature=shared&t=329
-            self.write(nic, &[])?;
-            return Ok(());
-        }
+    #[cfg(feature = "std")]
+    pub(crate) fn set_timer(&mut self, timer: Option<TimerHandle>) {
+        self.timer = timer;
+    }
 
-        if !tcp_header.ack() {
-            return Ok(());
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Whether the connection was opened by a SYN from the peer rather than by `connect`
+    pub fn passive_open(&self) -> bool {
+        self.passive_open
+    }
+
+    /// Our end of the connection
+    pub fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(
+            self.send_ip_header.source.into(),
+            self.send_tcp_header.source_port,
+        )
+    }
+
+    /// The peer's end of the connection
+    pub fn remote(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(
+            self.send_ip_header.destination.into(),
+            self.send_tcp_header.destination_port,
+        )
+    }
+
+    pub fn keepalive(&self) -> Option<KeepaliveConfig> {
+        self.keepalive
+    }
+
+    /// Enable keep-alive probes with the given settings, or disable them with `None`
+    pub fn set_keepalive(&mut self, keepalive: Option<KeepaliveConfig>) {
+        self.keepalive = keepalive;
+        self.keepalive_probes_sent = 0;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Abort the connection once nothing has arrived from the peer for `idle_timeout`,
+    /// or never with `None`
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// When the connection is aborted for being idle, unless something arrives first
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        if self.state.is_finished() {
+            return None;
         }
 
-        let ackn = tcp_header.acknowledgment_number();
+        self.idle_timeout.map(|timeout| self.last_recv + timeout)
+    }
 
-        if let State::SynRcvd = self.state {
-            if is_between_values_wrapped(
-                ackn,
-                self.send.una.wrapping_sub(1),
-                self.send.nxt.wrapping_add(1),
-            ) {
-                self.state = State::Estab;
-            } else {
-                // TODO: reset
-            }
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.rto.srtt(),
+            ..self.stats
         }
+    }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            // Check ack is valid. una < ack <= nxt (but with wrapping arithmatic)
-            if !is_between_values_wrapped(ackn, self.send.una, self.send.nxt.wrapping_add(1)) {
-                return Ok(());
-            }
+    /// Payload bytes sent since this was last called, for the stack to add to the
+    /// listener's [`ListenerStats`](crate::listener::ListenerStats). Unlike `stats`, these
+    /// aren't reset by taking a snapshot of the counters.
+    #[cfg(feature = "std")]
+    pub(crate) fn take_bytes_out(&mut self) -> u64 {
+        core::mem::take(&mut self.uncounted_bytes_out)
+    }
 
-            self.send.una = ackn;
+    /// The connection's state, sequence variables, queues and counters in one go
+    pub fn summary(&self) -> ConnectionSummary {
+        ConnectionSummary {
+            connection: ConnectInfo {
+                src_addr: *self.remote().ip(),
+                src_port: self.remote().port(),
+                dst_addr: *self.local().ip(),
+                dst_port: self.local().port(),
+            },
+            state: self.state,
+            snd_una: self.send.una.get(),
+            snd_nxt: self.send.nxt.get(),
+            snd_wnd: self.send.wnd,
+            rcv_nxt: self.recv.nxt.get(),
+            rcv_wnd: self.recv.wnd,
+            send_queue: self.unacked_len(),
+            recv_queue: self.unread_len(),
+            stats: self.stats(),
+        }
+    }
 
-            assert!(data.is_empty());
+    /// The connection's state, to carry it on elsewhere with [`Tcb::restore`]. Only a
+    /// synchronised connection can be carried on, one still in the handshake is
+    /// [`TcpError::InvalidState`]. Nothing about the connection changes, so it can carry on
+    /// here too if the handover falls through.
+    #[cfg(feature = "snapshot")]
+    pub fn save_state(&self) -> Result<ConnectionState> {
+        if !self.state.is_synchronised() {
+            return Err(TcpError::InvalidState(self.state));
         }
 
-        if let State::Estab = self.state {
-            self.send_tcp_header.fin = true; //TODO: store in retransmission queue
-            self.write(nic, &[])?;
-            self.state = State::FinWait1;
+        let now = self.clock.now();
+        let remaining = |deadline: Option<Instant>| {
+            deadline.map(|deadline| deadline.saturating_duration_since(now))
+        };
+
+        Ok(ConnectionState {
+            local: self.local(),
+            remote: self.remote(),
+            state: self.state,
+            passive_open: self.passive_open,
+            snd_una: self.send.una.get(),
+            snd_nxt: self.send.nxt.get(),
+            snd_max: self.send.max.get(),
+            snd_wnd: self.send.wnd,
+            snd_wl1: self.send.wl1.get(),
+            snd_wl2: self.send.wl2.get(),
+            iss: self.send.iss.get(),
+            snd_scale: self.send.scale.shift(),
+            rcv_nxt: self.recv.nxt.get(),
+            rcv_wnd: self.recv.wnd,
+            irs: self.recv.irs.get(),
+            rcv_scale: self.recv.scale.shift(),
+            send_mss: self.send_mss,
+            recv_mss: self.recv_mss,
+            timestamps: self
+                .timestamps
+                .map(|timestamps| (timestamps.ts_val(now), timestamps.recent())),
+            sack_permitted: self.sack_permitted,
+            send_buffer: self.send_buffer.iter().copied().collect(),
+            push_points: self
+                .push_points
+                .iter()
+                .map(|&point| point - self.send_buffer_start)
+                .collect(),
+            fin_queued: self.fin_queued,
+            recv_buffer: self.recv_buffer.iter().copied().collect(),
+            recv_pushed: self.recv_pushed,
+            recv_shutdown: self.recv_shutdown,
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            recv_low_water: self.recv_low_water,
+            nodelay: self.nodelay,
+            pacing: self.pacing,
+            dsack_enabled: self.dsack_enabled,
+            unread_data_policy: self.unread_data_policy,
+            keepalive: self.keepalive,
+            linger: self.linger,
+            user_timeout: self.user_timeout,
+            idle_timeout: self.idle_timeout,
+            time_wait: self.time_wait,
+            srtt: self.rto.srtt(),
+            rttvar: self.rto.rttvar(),
+            cwnd: self.congestion.cwnd(),
+            ssthresh: self.congestion.ssthresh(),
+            time_wait_remaining: remaining(self.time_wait_deadline),
+            linger_remaining: remaining(self.linger_deadline),
+        })
+    }
+
+    /// Carry on a connection saved by [`Tcb::save_state`], perhaps in another process,
+    /// sending on `nic`. What the state doesn't hold, such as the TTL and the bounds on
+    /// the retransmission timeout, comes from `config`.
+    ///
+    /// Timers are re-armed from now. The retransmission timer runs if anything is
+    /// unacknowledged, so what was in flight during the handover is sent again, keep-alive
+    /// and the idle timeout start over as if a segment had just arrived, and TIME-WAIT and
+    /// a lingering close run for what was left of them. Anything queued which the windows
+    /// allow is sent straight away.
+    #[cfg(feature = "snapshot")]
+    pub fn restore(
+        nic: &impl NetworkDevice,
+        saved: ConnectionState,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Self> {
+        let mut tcb: Tcb = Tcb::restore_unsent(nic, saved, clock, config)?;
+        tcb.send_restored(nic)?;
+        Ok(tcb)
+    }
+
+    /// [`Tcb::restore`], without sending anything yet, see [`Tcb::send_restored`]
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn restore_unsent(
+        nic: &impl NetworkDevice,
+        saved: ConnectionState,
+        clock: &Arc<dyn Clock>,
+        config: &TcbConfig,
+    ) -> Result<Self> {
+        if !saved.state.is_synchronised() {
+            return Err(TcpError::InvalidState(saved.state));
+        }
+        if saved.recv_pushed > saved.recv_buffer.len()
+            || saved
+                .push_points
+                .iter()
+                .any(|&point| point > saved.send_buffer.len() as u64)
+        {
+            return Err(TcpError::InvalidInput(
+                "saved push points past the end of the buffers",
+            ));
         }
 
-        if let State::FinWait1 = self.state {
-            // Checking ack for both the SYN initially sent and the FIN
-            if self.send.una == self.send.iss + 2 {
-                self.state = State::FinWait2
-            }
+        let mut tcb = Tcb::new(
+            saved.state,
+            saved.local,
+            saved.remote,
+            saved.iss,
+            nic.mtu(),
+            clock,
+            config,
+        )?;
+        let _span = span::enter(tcb.id);
+        let now = clock.now();
+
+        tcb.passive_open = saved.passive_open;
+        tcb.send.una = SeqNum::new(saved.snd_una);
+        tcb.send.nxt = SeqNum::new(saved.snd_nxt);
+        tcb.send.max = SeqNum::new(saved.snd_max);
+        tcb.send.wnd = saved.snd_wnd;
+        tcb.send.wl1 = SeqNum::new(saved.snd_wl1);
+        tcb.send.wl2 = SeqNum::new(saved.snd_wl2);
+        tcb.send.scale = WindowScale::new(saved.snd_scale);
+        tcb.recv.nxt = SeqNum::new(saved.rcv_nxt);
+        tcb.recv.wnd = saved.rcv_wnd;
+        tcb.recv.irs = SeqNum::new(saved.irs);
+        tcb.recv.scale = WindowScale::new(saved.rcv_scale);
+        tcb.send_tcp_header.ack = true;
+
+        tcb.send_mss = saved.send_mss.max(MIN_MSS);
+        tcb.recv_mss = saved.recv_mss;
+        // TSval carries on from where it was, so the peer's PAWS check keeps passing
+        tcb.timestamps = saved.timestamps.map(|(ts_val, recent)| {
+            let mut timestamps = Timestamps::new(ts_val, recent, now);
+            timestamps.on_ack_sent(tcb.recv.nxt);
+            timestamps
+        });
+        tcb.sack_permitted = saved.sack_permitted;
+
+        tcb.send_buffer = saved.send_buffer.into();
+        tcb.push_points = saved.push_points.into();
+        tcb.fin_queued = saved.fin_queued;
+        tcb.recv_buffer = saved.recv_buffer.into();
+        tcb.recv_pushed = saved.recv_pushed;
+        tcb.recv_shutdown = saved.recv_shutdown;
+
+        tcb.recv_buffer_size = saved.recv_buffer_size;
+        tcb.send_buffer_size = saved.send_buffer_size;
+        tcb.recv_low_water = saved.recv_low_water;
+        tcb.nodelay = saved.nodelay;
+        tcb.pacing = saved.pacing;
+        tcb.dsack_enabled = saved.dsack_enabled;
+        tcb.unread_data_policy = saved.unread_data_policy;
+        tcb.keepalive = saved.keepalive;
+        tcb.linger = saved.linger;
+        tcb.user_timeout = saved.user_timeout;
+        tcb.idle_timeout = saved.idle_timeout;
+        tcb.time_wait = saved.time_wait;
+
+        if let Some(srtt) = saved.srtt {
+            tcb.rto.resume(srtt, saved.rttvar);
         }
+        tcb.congestion.resume(saved.cwnd, saved.ssthresh, now);
 
-        if tcp_header.fin() {
-            if let State::FinWait2 = self.state {
-                self.write(nic, &[])?;
-                self.state = State::TimeWait;
-            }
+        tcb.time_wait_deadline = saved.time_wait_remaining.map(|remaining| now + remaining);
+        tcb.linger_deadline = saved.linger_remaining.map(|remaining| now + remaining);
+        if tcb.send.una != tcb.send.nxt {
+            tcb.retransmit_timer = Some(now);
+            tcb.unacked_since = Some(now);
         }
 
-        Ok(())
+        log!(
+            "Restored in {:?} with {}b to send and {}b to read",
+            tcb.state,
+            tcb.send_buffer.len(),
+            tcb.recv_buffer.len()
+        );
+
+        Ok(tcb)
     }
 
-    fn write(&mut self, nic: &Iface, payload: &[u8]) -> Result<usize> {
-        let mut buf: [u8; ETH_MTU] = [0; ETH_MTU];
+    /// Send what a connection from [`Tcb::restore_unsent`] has queued which the windows
+    /// allow
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn send_restored(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        if self.state != State::TimeWait {
+            self.flush(nic)?;
+        }
+        Ok(())
+    }
 
-        self.send_tcp_header.sequence_number = self.send.nxt;
-        self.send_tcp_header.acknowledgment_number = self.recv.nxt;
+    /// Count a segment for this connection which was dropped for a bad checksum
+    pub fn on_checksum_error(&mut self) {
+        self.stats.checksum_errors += 1;
+    }
 
-        let size = std::cmp::min(
-            buf.len(),
-            self.send_tcp_header.header_len() + self.send_ip_header.header_len() + payload.len(),
-        );
+    /// Read the connection's counters and reset them to zero
+    pub fn take_stats(&mut self) -> ConnectionStats {
+        let stats: ConnectionStats = self.stats();
+        self.stats = ConnectionStats::default();
+        stats
+    }
 
-        self.send_ip_header
-            .set_payload_len(size - self.send_ip_header.header_len())?;
+    pub fn unread_data_policy(&self) -> UnreadDataPolicy {
+        self.unread_data_policy
+    }
 
-        self.send_tcp_header.checksum = self
-            .send_tcp_header
-            .calc_checksum_ipv4(&self.send_ip_header, &[])?;
+    /// Choose what happens when the connection is closed before all received data is read
+    pub fn set_unread_data_policy(&mut self, policy: UnreadDataPolicy) {
+        self.unread_data_policy = policy;
+    }
 
-        let buf_len: usize = buf.len();
+    /// Change a setting on the connection, which takes effect straight away.
+    /// Buffer sizes must be at least one byte.
+    pub fn set_option(&mut self, option: SocketOption) -> Result<()> {
+        match option {
+            SocketOption::NoDelay(nodelay) => self.nodelay = nodelay,
+            SocketOption::Keepalive(keepalive) => self.set_keepalive(keepalive),
+            SocketOption::Linger(linger) => self.linger = linger,
+            SocketOption::RecvBufferSize(0) | SocketOption::SendBufferSize(0) => {
+                return Err(TcpError::InvalidInput(
+                    "buffer size must be at least one byte",
+                ))
+            }
+            SocketOption::RecvBufferSize(size) => {
+                // The window already offered is never taken back, RFC 9293 Section 3.8.6.2.2,
+                // so a smaller buffer only takes effect as the window closes
+                self.recv_buffer_size = size;
+                self.open_recv_window();
+            }
+            SocketOption::SendBufferSize(size) => self.send_buffer_size = size,
+            SocketOption::UserTimeout(user_timeout) => self.user_timeout = user_timeout,
+            SocketOption::Pacing(pacing) => {
+                self.pacing = pacing;
+                self.pacer.release();
+            }
+        }
 
-        let mut unwritten_bytes: &mut [u8] = &mut buf[..];
+        Ok(())
+    }
 
-        self.send_ip_header.write(&mut unwritten_bytes)?;
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
 
-        self.send_tcp_header.write(&mut unwritten_bytes)?;
+    pub fn linger(&self) -> Option<Duration> {
+        self.linger
+    }
 
-        let payload_bytes: usize = unwritten_bytes.write(payload)?;
+    pub fn pacing(&self) -> bool {
+        self.pacing
+    }
 
-        let num_written_bytes: usize = buf_len - unwritten_bytes.len();
+    pub fn recv_buffer_size(&self) -> usize {
+        self.recv_buffer_size
+    }
 
-        let response: &[u8] = &buf[..num_written_bytes];
+    pub fn send_buffer_size(&self) -> usize {
+        self.send_buffer_size
+    }
 
-        self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
+    pub fn user_timeout(&self) -> Option<Duration> {
+        self.user_timeout
+    }
 
-        if self.send_tcp_header.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.send_tcp_header.syn = false;
+    /// When the connection is dropped for the user timeout, unless the peer acknowledges
+    /// something first
+    pub fn user_timeout_deadline(&self) -> Option<Instant> {
+        if self.state == State::Closed {
+            return None;
         }
 
-        if self.send_tcp_header.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.send_tcp_header.fin = false;
+        Some(self.unacked_since? + self.user_timeout?)
+    }
+
+    /// When a lingering close resets the connection, unless our FIN is acknowledged first
+    fn linger_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::FinWait1 | State::Closing | State::LastAck => self.linger_deadline,
+            _ => None,
         }
+    }
 
-        nic.send(response)?;
+    /// Largest IP packet the path to the peer is known to carry
+    pub fn path_mtu(&self) -> usize {
+        self.path_mtu.mtu()
+    }
 
-        println!("Response ({num_written_bytes}b): \n{:02x?}", response);
+    /// Largest payload to send in one segment, the smaller of the peer's MSS and what
+    /// fits in the path MTU, less the options sent on every segment.
+    /// RFC 1122 Section 4.2.2.6
+    pub fn effective_mss(&self) -> usize {
+        let options_len: usize = match self.timestamps {
+            Some(_) => options::TIMESTAMPS_LEN,
+            None => 0,
+        };
 
-        Ok(payload_bytes)
+        // A path MTU as small as RFC 791's 68 bytes still leaves room for some data
+        self.path_mtu
+            .mss()
+            .min(self.send_mss.into())
+            .saturating_sub(options_len)
+            .max(1)
     }
 
-    fn send_rst(&mut self, nic: &Iface) -> Result<()> {
-        // TODO fix sequence numbers
-        // TODO handle synchronised reset
-        self.send_tcp_header.rst = true;
-        self.send_tcp_header.sequence_number = 0;
-        self.send_tcp_header.acknowledgment_number = 0;
-        self.write(nic, &[])?;
+    pub fn rto(&self) -> RtoEstimator {
+        self.rto
+    }
 
-        Ok(())
+    pub fn congestion(&self) -> &CongestionWindow {
+        &self.congestion
     }
 
-    /// RFC 793 Section 3.3
-    /// The first part of this test checks to see if the beginning of the
-    /// segment falls in the window, the second part of the test checks to see
-    /// if the end of the segment falls in the window; if the segment passes
-    /// either part of the test it contains data in the window.
-    ///
-    /// Actually, it is a little more complicated than this.  Due to zero
-    /// windows and zero length segments, we have four cases for the
-    /// acceptability of an incoming segment:
-    ///```
-    ///   Segment Receive  Test
-    ///   Length  Window
-    ///   ------- -------  -------------------------------------------
-    ///
-    ///      0       0     SEG.SEQ = RCV.NXT
-    ///
-    ///      0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-    ///
-    ///     >0       0     not acceptable
-    ///
-    ///     >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-    ///                 or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-    /// ```
-    fn is_segment_valid(&mut self, tcp_header: &TcpHeaderSlice, data: &[u8]) -> bool {
-        let seqn = tcp_header.sequence_number();
+    /// Set the retransmission timeout used until a round trip has been measured,
+    /// see [`RtoEstimator::new`]. Has no effect once one has.
+    pub fn set_initial_rto(&mut self, initial: Duration) {
+        if self.rto.srtt().is_none() {
+            self.rto = RtoEstimator::with_max(initial, self.rto.max());
+        }
+    }
 
-        let seg_len: u32 = {
-            let mut slen = data.len();
-            if tcp_header.fin() {
-                slen += 1;
-            }
+    /// Limit the option space used on outgoing segments, for paths with middleboxes which
+    /// mishandle long headers. Options which don't fit are dropped in priority order,
+    /// see [`OutgoingOptions::encode`].
+    pub fn set_max_options_len(&mut self, max_len: usize) {
+        self.max_options_len = max_len.min(options::MAX_OPTIONS_LEN);
+    }
 
-            if tcp_header.syn() {
-                slen += 1;
-            }
-            slen as u32
-        };
+    /// Install a hook to add experimental options to outgoing segments and
+    /// receive unrecognised options from incoming ones, replacing any existing hook.
+    pub fn set_option_hook(&mut self, hook: Option<Box<dyn OptionHook>>) {
+        self.option_hook = hook;
+    }
 
-        let window = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+    /// Install a hook to inspect or drop segments sent and received on this connection,
+    /// replacing any existing hook.
+    pub fn set_segment_hook(&mut self, hook: Option<Box<dyn SegmentHook>>) {
+        self.segment_hook = hook;
+    }
 
-        let is_valid = if seg_len == 0 {
-            if self.recv.wnd == 0 {
-                seqn == self.recv.nxt
-            } else {
-                is_between_values_wrapped(seqn, self.recv.nxt.wrapping_sub(1), window)
-            }
-        } else {
-            if self.recv.wnd == 0 {
-                false
-            } else {
-                is_between_values_wrapped(seqn, self.recv.nxt.wrapping_sub(1), window)
-                    || is_between_values_wrapped(
-                        seqn.wrapping_add(seg_len - 1),
-                        self.recv.nxt.wrapping_sub(1),
-                        window,
-                    )
-            }
-        };
+    /// Discard the next `n` segments this connection sends, as if they were lost on the
+    /// wire, replacing any count still outstanding.
+    #[cfg(feature = "fault-injection")]
+    pub fn drop_next_segments(&mut self, n: u32) {
+        self.segments_to_drop = n;
+    }
+
+    /// Act as if the retransmission timer had just expired, retransmitting the oldest
+    /// unacknowledged segment and backing off the timer. Does nothing if everything sent
+    /// has been acknowledged.
+    #[cfg(feature = "fault-injection")]
+    pub fn force_retransmit(&mut self, nic: &impl NetworkDevice, now: Instant) -> Result<()> {
+        let _span = span::enter(self.id);
+        if self.retransmit_timer.is_none() {
+            return Ok(());
+        }
 
-        self.recv.nxt = seqn.wrapping_add(seg_len);
-        // TODO ensure this is acked
+        self.on_retransmit_timeout(nic, now)
+    }
 
-        // TODO if the received sequence number is not acceptable we need to send an ack back.
+    /// The next time `on_tick` has work to do, if any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.window_update_due,
+            self.pacing_deadline(),
+            self.time_wait_deadline,
+            self.retransmit_deadline(),
+            self.keepalive_deadline(),
+            self.idle_deadline(),
+            self.user_timeout_deadline(),
+            self.linger_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
 
-        is_valid
+    /// Number of timers running: retransmission, TIME-WAIT, keep-alive, idle, user
+    /// timeout and linger
+    pub fn running_timers(&self) -> usize {
+        [
+            self.time_wait_deadline,
+            self.retransmit_deadline(),
+            self.keepalive_deadline(),
+            self.idle_deadline(),
+            self.user_timeout_deadline(),
+            self.linger_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .count()
     }
-}
 
-/// lower < value < upper
-/// but with wrapping arithmatic
-/// TODO: without branching
-fn is_between_values_wrapped(value: u32, start: u32, end: u32) -> bool {
-    match start.cmp(&value) {
-        Ordering::Equal => return false,
-        Ordering::Less => {
-            if end >= start && end <= value {
-                return false;
-            }
+    /// Run any timers which have expired by `now`
+    pub fn on_tick(&mut self, nic: &impl NetworkDevice, now: Instant) -> Result<()> {
+        let _span = span::enter(self.id);
+
+        if self
+            .time_wait_deadline
+            .is_some_and(|deadline| now >= deadline)
+        {
+            self.time_wait_deadline = None;
+            self.set_state(State::Closed);
+            return Ok(());
         }
-        Ordering::Greater => {
-            if end > value && end < start {
-            } else {
-                return false;
-            }
+
+        if self.idle_deadline().is_some_and(|deadline| now >= deadline) {
+            log!(
+                "Idle: nothing received for {:?}, aborting connection",
+                now - self.last_recv
+            );
+            return self.abort(nic);
         }
-    }
 
-    return true;
-}
+        if self
+            .linger_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            log!("Linger: FIN unacknowledged when lingering ran out, aborting connection");
+            return self.abort(nic);
+        }
 
-#[derive(Clone, Copy)]
-pub enum State {
-    SynRcvd,
-    Estab,
-    FinWait1,
-    FinWait2,
-    TimeWait,
-}
+        // RFC 9293 Section 3.10.8, the user timeout drops the connection without a reset
+        if self
+            .user_timeout_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            log!(
+                "User timeout: nothing acknowledged for {:?}, closing connection",
+                self.user_timeout.unwrap_or_default()
+            );
+            self.delete();
+            return Ok(());
+        }
 
-impl State {
-    pub fn is_synchronised(&self) -> bool {
-        use State::*;
+        if self
+            .retransmit_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            self.on_retransmit_timeout(nic, now)?;
+            if self.state == State::Closed {
+                return Ok(());
+            }
+        }
 
-        match self {
-            SynRcvd => false,
-            Estab | FinWait1 | FinWait2 | TimeWait => true,
+        if self
+            .pacing_deadline()
+            .is_some_and(|deadline| now >= deadline)
+        {
+            self.pacer.release();
+            self.flush(nic)?;
+        }
+
+        // Any segment sent above carried the window already
+        if self.window_update_due.is_some_and(|due| now >= due) {
+            self.write(nic, Payload::EMPTY)?;
         }
+
+        let (Some(keepalive), Some(deadline)) = (self.keepalive, self.keepalive_deadline()) else {
+            return Ok(());
+        };
+
+        if now < deadline {
+            return Ok(());
+        }
+
+        if self.keepalive_probes_sent >= keepalive.probes {
+            log!(
+                "Keep-alive: no response after {} probes, closing connection",
+                self.keepalive_probes_sent
+            );
+            self.send_rst(nic)?;
+            self.delete();
+            return Ok(());
+        }
+
+        self.send_keepalive_probe(nic)?;
+        self.keepalive_probes_sent += 1;
+
+        Ok(())
+    }
+
+    /// When the pacer lets the next segment go, while one is waiting for it
+    fn pacing_deadline(&self) -> Option<Instant> {
+        let n_in_flight: usize =
+            ((self.send.nxt - self.send.una) as usize).min(self.send_buffer.len());
+        if !self.pacing || self.state == State::Closed || self.send_buffer.len() == n_in_flight {
+            return None;
+        }
+
+        self.pacer.next_send()
+    }
+
+    fn retransmit_deadline(&self) -> Option<Instant> {
+        if self.state == State::Closed {
+            return None;
+        }
+
+        self.retransmit_timer
+            .map(|started| started + self.rto.rto())
+    }
+
+    /// RFC 6298 Section 5.4 to 5.6, retransmit the oldest unacknowledged segment,
+    /// back off the timer and restart it.
+    /// Retransmitted segments are never timed, following Karn's algorithm.
+    fn on_retransmit_timeout(&mut self, nic: &impl NetworkDevice, now: Instant) -> Result<()> {
+        let max_retransmissions: u32 = if self.state.is_synchronised() {
+            MAX_RETRANSMISSIONS
+        } else {
+            MAX_SYN_RETRANSMISSIONS
+        };
+
+        // The user timeout replaces the retransmission limit, RFC 5482 Section 2
+        if self.user_timeout.is_none() && self.retransmissions >= max_retransmissions {
+            log!(
+                "Retransmission: no acknowledgement after {} retransmissions, closing connection",
+                self.retransmissions
+            );
+            self.delete();
+            return Ok(());
+        }
+
+        if !self.state.is_synchronised() {
+            self.syn_retransmitted = true;
+        }
+
+        let outstanding: usize = (self.send.nxt - self.send.una) as usize;
+        if self.retransmissions + 1 >= pmtu::BLACK_HOLE_RETRANSMISSIONS
+            && outstanding > self.path_mtu.plateau_mss()
+        {
+            self.path_mtu.on_black_hole(now);
+        }
+
+        if self.state.is_synchronised() {
+            self.congestion.on_timeout(
+                outstanding as u32,
+                self.effective_mss() as u32,
+                self.retransmissions == 0,
+            );
+        }
+
+        self.retransmit(nic)?;
+        self.rtt_timed = None;
+        self.retransmissions += 1;
+        if self.retransmissions == R1_RETRANSMISSIONS {
+            log!("Retransmission: {R1_RETRANSMISSIONS} retransmissions without an acknowledgement");
+            self.push_soft_error(SoftError::RetransmissionThreshold);
+        }
+        self.rto.on_timeout();
+        self.retransmit_timer = Some(now);
+
+        Ok(())
+    }
+
+    /// Send the oldest unacknowledged segment again, starting from SND.UNA.
+    /// Anything sent after it follows again as the retransmission is acknowledged.
+    fn retransmit(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        let fin_sent: bool = !self.fin_queued
+            && matches!(
+                self.state,
+                State::FinWait1 | State::Closing | State::LastAck
+            );
+        self.send.nxt = self.send.una;
+
+        // No data is sent until the SYN is acknowledged, so it can only be followed by our FIN
+        if self.send.una == self.send.iss {
+            self.send_tcp_header.syn = true;
+            self.send_tcp_header.fin = fin_sent;
+            self.write(nic, Payload::EMPTY)?;
+            self.stats.retransmissions += 1;
+            return Ok(());
+        }
+
+        self.stats.retransmissions += 1;
+        self.fin_queued |= fin_sent;
+        // The retransmission isn't held back by the pacer
+        self.pacer.release();
+        self.send_next_segment(nic)?;
+
+        Ok(())
+    }
+
+    /// Advance SND.UNA to `ackn`, measuring the round trip if it covers the timed segment.
+    /// RFC 6298 Section 5.2 and 5.3, the retransmission timer stops once everything is
+    /// acknowledged, otherwise it restarts.
+    fn acknowledge(&mut self, ackn: SeqNum) {
+        let now = self.clock.now();
+
+        // Neither our SYN nor our FIN is in the send buffer
+        let syn_acked: u32 = (self.send.una == self.send.iss) as u32;
+        let seq_acked: usize = (ackn - self.send.una).saturating_sub(syn_acked) as usize;
+        let n_acked: usize = seq_acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..n_acked);
+        self.send_buffer_start += n_acked as u64;
+        while self
+            .push_points
+            .front()
+            .is_some_and(|&point| point <= self.send_buffer_start)
+        {
+            self.push_points.pop_front();
+        }
+        self.send.una = ackn;
+
+        // After going back to retransmit, the ACK can cover more than has been sent again,
+        // including a FIN that was queued to go again
+        if ackn.gt(self.send.nxt) {
+            self.send.nxt = ackn;
+            if seq_acked > n_acked {
+                self.fin_queued = false;
+            }
+        }
+
+        if let Some((timed_seq, sent)) = self.rtt_timed {
+            if ackn.ge(timed_seq) {
+                self.rto.on_rtt_sample(now.saturating_duration_since(sent));
+                self.rtt_timed = None;
+            }
+        }
+
+        if self.state.is_synchronised() {
+            self.congestion.on_ack(
+                n_acked as u32,
+                ackn,
+                self.send.nxt,
+                self.effective_mss() as u32,
+                self.rto.srtt(),
+                now,
+            );
+        }
+
+        self.retransmissions = 0;
+        self.retransmit_timer = if self.send.una == self.send.nxt {
+            None
+        } else {
+            Some(now)
+        };
+        self.unacked_since = self.retransmit_timer;
+    }
+
+    /// Every state transition goes through here, so each is logged in the connection's span
+    fn set_state(&mut self, state: State) {
+        if state != self.state {
+            debug!("State: {:?} -> {state:?}", self.state);
+        }
+        self.state = state;
+    }
+
+    /// Move to ESTABLISHED once the handshake completes
+    fn establish(&mut self) {
+        self.set_state(State::Estab);
+        self.rto.on_established(self.syn_retransmitted);
+        self.congestion.on_established(
+            self.effective_mss() as u32,
+            self.syn_retransmitted,
+            self.clock.now(),
+        );
+    }
+
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+
+        if !matches!(self.state, State::Estab | State::CloseWait) {
+            return None;
+        }
+
+        Some(self.last_recv + keepalive.idle + keepalive.interval * self.keepalive_probes_sent)
+    }
+
+    /// Move to TIME-WAIT, (re)starting the timer after which the TCB is deleted, 2MSL by default
+    fn enter_time_wait(&mut self) {
+        self.set_state(State::TimeWait);
+        self.time_wait_deadline = Some(self.clock.now() + self.time_wait);
+    }
+
+    pub fn on_packet(
+        &mut self,
+        nic: &impl NetworkDevice,
+        ip_header: Ipv4HeaderSlice,
+        tcp_header: TcpHeaderSlice,
+        data: &[u8],
+        challenge_acks: &mut ChallengeAckLimiter,
+    ) -> Result<()> {
+        let _span = span::enter(self.id);
+
+        if let Some(hook) = &mut self.segment_hook {
+            let segment = SegmentInfo {
+                state: self.state,
+                header: &tcp_header.to_header(),
+                payload: data,
+            };
+
+            if hook.on_receive(&segment) == Verdict::Drop {
+                return Ok(());
+            }
+        }
+
+        self.stats.segments_in += 1;
+        self.stats.bytes_in += data.len() as u64;
+
+        if self.state == State::SynSent {
+            return self.on_packet_syn_sent(nic, ip_header, tcp_header, data);
+        }
+
+        let seq = SeqNum::new(tcp_header.sequence_number());
+        let ackn = SeqNum::new(tcp_header.acknowledgment_number());
+
+        // RFC 9293 Section 3.5 Figure 7, simultaneous open.
+        // Both sides answer the other's SYN with a SYN,ACK, which repeats the SYN so sits just
+        // before RCV.NXT. It still acknowledges our SYN, completing the handshake.
+        if self.state == State::SynRcvd
+            && !self.passive_open
+            && tcp_header.syn()
+            && tcp_header.ack()
+            && !tcp_header.rst()
+            && seq + 1 == self.recv.nxt
+            && ackn == self.send.nxt
+        {
+            self.last_recv = self.clock.now();
+            self.acknowledge(ackn);
+            self.establish();
+            return Ok(());
+        }
+
+        let seg_ts_val: Option<u32> =
+            options::timestamps(tcp_header.options()).map(|(ts_val, _)| ts_val);
+        let now = self.clock.now();
+
+        // RFC 7323 Section 5.3 R1, PAWS. A segment carrying an older timestamp than one
+        // already received is a duplicate from earlier in the connection, so is dropped.
+        // Segments without a timestamp are accepted, as Linux does.
+        if let (Some(timestamps), Some(ts_val)) = (&self.timestamps, seg_ts_val) {
+            if !tcp_header.rst() && timestamps.is_old(ts_val, now) {
+                self.write(nic, Payload::EMPTY)?;
+                return Ok(());
+            }
+        }
+
+        if self.receive_predicted(nic, &tcp_header, data, seg_ts_val)? {
+            return Ok(());
+        }
+
+        self.note_duplicate(&tcp_header, data);
+
+        if !self.is_segment_valid(&tcp_header, data) {
+            // A keep-alive probe sits just before RCV.NXT so is never acceptable, but its
+            // ACK still echoes the probe's timestamp so the peer sees we're there.
+            // RFC 1122 Section 4.2.3.6
+            let is_keepalive: bool = data.len() <= 1
+                && !tcp_header.syn()
+                && !tcp_header.fin()
+                && !tcp_header.rst()
+                && seq + 1 == self.recv.nxt;
+            if let (Some(timestamps), Some(ts_val), true) =
+                (&mut self.timestamps, seg_ts_val, is_keepalive)
+            {
+                timestamps.update_recent(ts_val, seq, now);
+            }
+
+            // https://youtu.be/OCpt1I0MWXE?feature=shared&t=329
+            // Unacceptable resets are dropped without a reply
+            if !tcp_header.rst() {
+                self.write(nic, Payload::EMPTY)?;
+            }
+
+            // The peer's retransmitted FIN sits just before RCV.NXT, so lands here.
+            // It means our last ACK was lost, so the 2MSL wait starts again.
+            if self.state == State::TimeWait && tcp_header.fin() {
+                self.enter_time_wait();
+            }
+            return Ok(());
+        }
+
+        self.last_recv = now;
+        self.keepalive_probes_sent = 0;
+        if let (Some(timestamps), Some(ts_val)) = (&mut self.timestamps, seg_ts_val) {
+            timestamps.update_recent(ts_val, seq, now);
+        }
+
+        if let Some(hook) = &mut self.option_hook {
+            options::parse_options(tcp_header.options())
+                .filter(|option| !options::is_known_kind(option.kind))
+                .for_each(|option| hook.on_unknown_option(option));
+        }
+
+        // RFC 9293 Section 3.10.7.4, second check the RST bit.
+        // In SYN-RECEIVED following a passive open the connection returns to LISTEN,
+        // which for us means dropping the TCB as the listener still exists.
+        // In every other state the connection is reset and the TCB deleted.
+        if tcp_header.rst() {
+            // RFC 1337, a reset in TIME-WAIT would cut the 2MSL wait short and let old
+            // duplicates through to the next incarnation, so it's ignored
+            if self.state == State::TimeWait {
+                log!("Ignoring reset in TIME-WAIT");
+                return Ok(());
+            }
+
+            // RFC 5961 Section 3.2
+            // A reset inside the window but not exactly at RCV.NXT may be a blind reset
+            // attack. A genuine peer will answer the challenge with a correctly sequenced reset.
+            if seq != self.recv.nxt {
+                self.send_challenge_ack(nic, challenge_acks)?;
+                return Ok(());
+            }
+
+            log!("Connection reset by peer in state {:?}", self.state);
+            self.reset_by_peer = true;
+            self.set_state(State::Closed);
+            return Ok(());
+        }
+
+        // RFC 9293 Section 3.10.7.4, fourth check the SYN bit.
+        if tcp_header.syn() {
+            // Following a passive open the connection returns to LISTEN
+            if self.state == State::SynRcvd && self.passive_open {
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+
+            // RFC 5961 Section 4.2
+            // A SYN in a synchronised state is always challenged rather than
+            // resetting the connection, whatever its sequence number.
+            self.send_challenge_ack(nic, challenge_acks)?;
+            return Ok(());
+        }
+
+        if !tcp_header.ack() {
+            return Ok(());
+        }
+
+        // RFC 9293 Section 3.10.7.4, fifth check the ACK field.
+        // In SYN-RECEIVED the ACK must cover our SYN, SND.UNA < SEG.ACK =< SND.NXT.
+        // Clients often send their first data along with this ACK, so once established
+        // the rest of the segment carries on through the usual processing below.
+        if let State::SynRcvd = self.state {
+            if ackn.is_between(self.send.una, self.send.nxt + 1) {
+                self.establish();
+            } else {
+                // The ACK is for something we haven't sent
+                send_reset(
+                    nic,
+                    &ip_header,
+                    &tcp_header,
+                    data,
+                    self.send_ip_header.time_to_live,
+                )?;
+                return Ok(());
+            }
+        }
+
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            if ackn.gt(self.send.max) {
+                // Acknowledges something not yet sent
+                self.write(nic, Payload::EMPTY)?;
+                return Ok(());
+            }
+
+            // Check ack is valid. una < ack <= max (but with wrapping arithmatic)
+            if ackn.is_between(self.send.una, self.send.max + 1) {
+                self.acknowledge(ackn);
+            } else if self.is_duplicate_ack(&tcp_header, data) {
+                // Ignored while the rest of the segment is processed
+                self.stats.duplicate_acks += 1;
+            }
+
+            // RFC 9293 Section 3.10.7.4, fifth check the ACK field.
+            // If SND.UNA =< SEG.ACK =< SND.NXT the send window is updated, unless the
+            // segment is older than the one it was last updated from.
+            if self.is_newer_segment(&tcp_header)
+                && ackn.is_between(self.send.una - 1, self.send.max + 1)
+            {
+                self.take_acked_window(&tcp_header);
+            }
+        }
+
+        match self.state {
+            State::FinWait1 if self.is_fin_acked() => self.set_state(State::FinWait2),
+            State::Closing if self.is_fin_acked() => self.enter_time_wait(),
+            State::LastAck if self.is_fin_acked() => {
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // RFC 9293 Section 3.10.7.4, seventh process the segment text.
+        // Once the peer has sent its FIN there can't be any more, so it's ignored.
+        let mut needs_ack: bool = false;
+
+        if !data.is_empty() {
+            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                let n_new: usize = self.receive_data(seq, data);
+
+                // RFC 9293 Section 3.9.1.2, once everything up to a PSH has arrived it's
+                // handed to the reader without waiting for more
+                let data_end: SeqNum = seq + data.len() as u32;
+                if n_new > 0 && tcp_header.psh() && data_end == self.recv.nxt {
+                    self.recv_pushed = self.recv_buffer.len();
+                }
+
+                // RFC 1122 Section 4.2.2.13
+                // We've stopped reading so nothing will read this data. Resetting tells the
+                // peer it was lost. After only shutting down writing it's still delivered.
+                if n_new > 0
+                    && self.recv_shutdown
+                    && self.unread_data_policy == UnreadDataPolicy::Reset
+                {
+                    log!("Received {n_new}b after closing, resetting the connection");
+                    self.recv_buffer.clear();
+                    self.send_rst(nic)?;
+                    self.set_state(State::Closed);
+                    return Ok(());
+                }
+
+                needs_ack = true;
+            }
+        }
+
+        // RFC 9293 Section 3.10.7.4, eighth check the FIN bit.
+        // The FIN is only processed once everything before it has been received,
+        // then RCV.NXT is advanced over it, it's acknowledged and the state moves on.
+        let fin_seq: SeqNum = seq + data.len() as u32;
+
+        if tcp_header.fin() && fin_seq != self.recv.nxt {
+            needs_ack = true;
+        } else if tcp_header.fin() {
+            self.recv.nxt += 1;
+            self.write(nic, Payload::EMPTY)?;
+            needs_ack = false;
+
+            match self.state {
+                State::SynRcvd | State::Estab => self.set_state(State::CloseWait),
+                // FIN-WAIT-1 only remains if our FIN is unacknowledged, so both sides closed at once
+                State::FinWait1 => self.set_state(State::Closing),
+                State::FinWait2 => self.enter_time_wait(),
+                // Only a retransmission of the peer's FIN can arrive in TIME-WAIT
+                State::TimeWait => self.enter_time_wait(),
+                State::SynSent
+                | State::CloseWait
+                | State::Closing
+                | State::LastAck
+                | State::Closed => {}
+            }
+        }
+
+        // Anything the ACK made room for in the peer's window goes now, and carries the ACK
+        let n_sent: usize = self.flush(nic)?;
+        if needs_ack && n_sent == 0 {
+            self.write(nic, Payload::EMPTY)?;
+        }
+
+        Ok(())
+    }
+
+    /// Header prediction, as in 4.4BSD's `tcp_input`. During a bulk transfer almost every
+    /// segment is either a pure ACK of new data or the next data in order acknowledging
+    /// nothing new. Those pass every check in RFC 9293 Section 3.10.7.4 anyway, so they're
+    /// handled here without going through them.
+    /// Returns whether the segment was handled.
+    fn receive_predicted(
+        &mut self,
+        nic: &impl NetworkDevice,
+        tcp_header: &TcpHeaderSlice,
+        data: &[u8],
+        seg_ts_val: Option<u32>,
+    ) -> Result<bool> {
+        let seq = SeqNum::new(tcp_header.sequence_number());
+        let ackn = SeqNum::new(tcp_header.acknowledgment_number());
+
+        let is_predictable: bool = self.state == State::Estab
+            && tcp_header.ack()
+            && !tcp_header.syn()
+            && !tcp_header.fin()
+            && !tcp_header.rst()
+            && !tcp_header.urg()
+            && seq == self.recv.nxt
+            && self.option_hook.is_none();
+        if !is_predictable {
+            return Ok(false);
+        }
+
+        let is_new_ack: bool = data.is_empty() && ackn.is_between(self.send.una, self.send.max + 1);
+        let is_next_data: bool = !data.is_empty()
+            && ackn == self.send.una
+            && !self.recv_shutdown
+            && data.len() as u32 <= self.recv.scale.advertised(self.recv.wnd, false);
+        if !is_new_ack && !is_next_data {
+            return Ok(false);
+        }
+
+        let now = self.clock.now();
+        self.stats.predicted_segments += 1;
+        self.last_recv = now;
+        self.keepalive_probes_sent = 0;
+        if let (Some(timestamps), Some(ts_val)) = (&mut self.timestamps, seg_ts_val) {
+            timestamps.update_recent(ts_val, seq, now);
+        }
+        if self.is_newer_segment(tcp_header) {
+            self.take_acked_window(tcp_header);
+        }
+
+        if is_new_ack {
+            self.acknowledge(ackn);
+            self.flush(nic)?;
+            return Ok(true);
+        }
+
+        self.receive_data(seq, data);
+        if tcp_header.psh() {
+            self.recv_pushed = self.recv_buffer.len();
+        }
+        if self.flush(nic)? > 0 {
+            return Ok(true);
+        }
+
+        // RFC 5681 Section 4.2, within a batch only every second segment is acknowledged
+        // straight away. The end of the batch acknowledges the rest.
+        if self.coalesce_acks && !self.ack_deferred {
+            self.ack_deferred = true;
+        } else {
+            self.write(nic, Payload::EMPTY)?;
+        }
+
+        Ok(true)
+    }
+
+    #[cfg(feature = "std")]
+    /// Leave ACKs of in-order data until [`Tcb::end_batch`], while the stack works through
+    /// a batch of packets
+    pub(crate) fn begin_batch(&mut self) {
+        self.coalesce_acks = true;
+    }
+
+    #[cfg(feature = "std")]
+    /// Send the ACK left from the batch, if nothing since has carried it
+    pub(crate) fn end_batch(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        self.coalesce_acks = false;
+        if self.ack_deferred {
+            self.write(nic, Payload::EMPTY)?;
+        }
+
+        Ok(())
+    }
+
+    /// RFC 5681 Section 2, an ACK of SND.UNA with data outstanding, carrying no data, SYN
+    /// or FIN and advertising the same window
+    fn is_duplicate_ack(&self, tcp_header: &TcpHeaderSlice, data: &[u8]) -> bool {
+        let wnd: u32 = self.send.scale.from_field(tcp_header.window_size(), false);
+
+        SeqNum::new(tcp_header.acknowledgment_number()) == self.send.una
+            && self.send.una != self.send.nxt
+            && data.is_empty()
+            && !tcp_header.syn()
+            && !tcp_header.fin()
+            && wnd == self.send.wnd
+    }
+
+    /// Count data in the segment which was already received, and if the peer accepts SACK
+    /// blocks, report it with a D-SACK block on the next ACK. RFC 2883 Section 4, the
+    /// block covers the duplicate part of the segment.
+    fn note_duplicate(&mut self, tcp_header: &TcpHeaderSlice, data: &[u8]) {
+        if data.is_empty() || tcp_header.syn() || tcp_header.rst() || !self.state.is_synchronised()
+        {
+            return;
+        }
+
+        let seq = SeqNum::new(tcp_header.sequence_number());
+        if !seq.lt(self.recv.nxt) {
+            return;
+        }
+        let already_received: u32 = self.recv.nxt - seq;
+
+        // A keep-alive probe repeats the byte before RCV.NXT but isn't a retransmission
+        if data.len() == 1 && already_received == 1 {
+            return;
+        }
+
+        let end: SeqNum = seq + already_received.min(data.len() as u32);
+        self.stats.duplicate_segments += 1;
+        if self.sack_permitted && self.dsack_enabled {
+            self.dsack = Some((seq.get(), end.get()));
+        }
+    }
+
+    /// Take the in-order part of `data`, which starts at `seq`, into the receive buffer as far
+    /// as the window allows. Returns the number of new bytes taken.
+    fn receive_data(&mut self, seq: SeqNum, data: &[u8]) -> usize {
+        // Data ahead of RCV.NXT is dropped, the peer will retransmit it after our ACK
+        if seq.gt(self.recv.nxt) {
+            self.stats.out_of_order_segments += 1;
+            return 0;
+        }
+
+        // Skip anything already received at the front of a retransmission
+        let already_received: usize = (self.recv.nxt - seq) as usize;
+        let new_data: &[u8] = data.get(already_received..).unwrap_or(&[]);
+        let n_new: usize = new_data.len().min(self.recv.wnd as usize);
+
+        self.recv_buffer.extend(&new_data[..n_new]);
+        self.recv.nxt += n_new as u32;
+        self.recv.wnd -= n_new as u32;
+
+        n_new
+    }
+
+    /// Copy received data into `buf`, returning the number of bytes read.
+    /// Reading opens the receive window back up, see [`Tcb::open_recv_window`].
+    ///
+    /// Nothing is read until [`Tcb::readable_len`] is more than 0.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n_read: usize = buf.len().min(self.readable_len());
+
+        for (dst, src) in buf.iter_mut().zip(self.recv_buffer.drain(..n_read)) {
+            *dst = src;
+        }
+        self.recv_pushed = self.recv_pushed.saturating_sub(n_read);
+        self.open_recv_window();
+
+        n_read
+    }
+
+    /// RFC 9293 Section 3.8.6.2.2, receive side silly window syndrome avoidance.
+    /// The window only opens to the free space in the receive buffer once that's grown by
+    /// a full segment or half the buffer, whichever is less, so the peer is never offered
+    /// a sliver it would fill with a tiny segment. The peer hears about it from the next
+    /// segment sent, or a window update ACK from `on_tick` if nothing else goes first.
+    fn open_recv_window(&mut self) {
+        let free: usize = self.recv_buffer_size.saturating_sub(self.recv_buffer.len());
+        let threshold: usize = self.effective_mss().min(self.recv_buffer_size / 2).max(1);
+        if free < self.recv.wnd as usize + threshold {
+            return;
+        }
+
+        self.recv.wnd = free as u32;
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            self.window_update_due = Some(self.clock.now());
+        }
+    }
+
+    /// Number of received bytes waiting to be read
+    pub fn unread_len(&self) -> usize {
+        self.recv_buffer.len()
+    }
+
+    /// Number of received bytes `read` will hand over now. Data waits in the buffer until
+    /// there's at least the low water mark of it, unless the peer pushed it, the receive
+    /// window has closed or the peer has finished sending.
+    pub fn readable_len(&self) -> usize {
+        let peer_finished: bool = matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        );
+
+        if self.recv_pushed > 0
+            || self.recv_buffer.len() >= self.recv_low_water
+            || self.recv.wnd == 0
+            || peer_finished
+        {
+            self.recv_buffer.len()
+        } else {
+            0
+        }
+    }
+
+    pub fn recv_low_water(&self) -> usize {
+        self.recv_low_water
+    }
+
+    /// Hold received data back from `read` until `bytes` of it have arrived, to read in
+    /// fewer, larger pieces. Data the peer pushes is read straight away. Defaults to 1, so
+    /// anything received can be read.
+    pub fn set_recv_low_water(&mut self, bytes: usize) {
+        self.recv_low_water = bytes.max(1);
+    }
+
+    /// RFC 9293 Section 3.10.7.3, segments arriving in the SYN-SENT state
+    fn on_packet_syn_sent(
+        &mut self,
+        nic: &impl NetworkDevice,
+        ip_header: Ipv4HeaderSlice,
+        tcp_header: TcpHeaderSlice,
+        data: &[u8],
+    ) -> Result<()> {
+        // First check the ACK bit. Only an acknowledgement of our SYN is acceptable.
+        let ackn = SeqNum::new(tcp_header.acknowledgment_number());
+        let ack_acceptable: bool =
+            tcp_header.ack() && ackn.is_between(self.send.iss, self.send.nxt + 1);
+
+        if tcp_header.ack() && !ack_acceptable {
+            send_reset(
+                nic,
+                &ip_header,
+                &tcp_header,
+                data,
+                self.send_ip_header.time_to_live,
+            )?;
+            return Ok(());
+        }
+
+        // Second check the RST bit. Without an acceptable ACK it could be a blind reset.
+        if tcp_header.rst() {
+            if ack_acceptable {
+                log!("Connection refused by peer");
+                self.reset_by_peer = true;
+                self.refused_by_peer = true;
+                self.set_state(State::Closed);
+            }
+            return Ok(());
+        }
+
+        // Fourth check the SYN bit
+        if !tcp_header.syn() {
+            return Ok(());
+        }
+
+        self.last_recv = self.clock.now();
+        self.send_mss = peer_mss(tcp_header.options());
+        self.sack_permitted = options::sack_permitted(tcp_header.options());
+        self.timestamps = match (self.timestamps, options::timestamps(tcp_header.options())) {
+            (Some(mut timestamps), Some((ts_val, _))) => {
+                timestamps.on_syn(ts_val, self.clock.now());
+                Some(timestamps)
+            }
+            _ => None,
+        };
+        self.recv.irs = SeqNum::new(tcp_header.sequence_number());
+        self.recv.nxt = self.recv.irs + 1;
+        self.update_send_window(&tcp_header);
+        self.send_tcp_header.ack = true;
+
+        if ack_acceptable {
+            self.acknowledge(ackn);
+            self.establish();
+            if self.flush(nic)? == 0 {
+                self.write(nic, Payload::EMPTY)?;
+            }
+        } else {
+            // Simultaneous open, the peer's SYN crossed ours.
+            // Send <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>, repeating our SYN.
+            self.set_state(State::SynRcvd);
+            self.send.nxt = self.send.iss;
+            self.send_tcp_header.syn = true;
+            self.write(nic, Payload::EMPTY)?;
+        }
+
+        Ok(())
+    }
+
+    /// Close the connection, sending a FIN once everything before it has been sent, and
+    /// stop reading. The same as [`Tcb::shutdown`] with [`Shutdown::Both`].
+    /// RFC 9293 Section 3.10.4
+    ///
+    /// If received data hasn't been read, the [`UnreadDataPolicy`] decides whether to
+    /// finish with a FIN as usual or reset the connection. A [`SocketOption::Linger`] time
+    /// bounds how long the close can take.
+    pub fn close(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        if self.state != State::Closed && self.recv_shutdown && self.is_send_shutdown() {
+            return Err(TcpError::InvalidState(self.state));
+        }
+
+        match self.linger {
+            Some(Duration::ZERO) => self.abort(nic),
+            Some(linger) => {
+                self.linger_deadline = Some(self.clock.now() + linger);
+                self.shutdown(nic, Shutdown::Both)
+            }
+            None => self.shutdown(nic, Shutdown::Both),
+        }
+    }
+
+    /// Close one or both directions of the connection.
+    ///
+    /// Shutting down writing sends a FIN once everything before it has been sent, after
+    /// which nothing more can be sent. Data from the peer is still delivered until the
+    /// peer closes too, a half-close. RFC 9293 Section 3.6
+    ///
+    /// Shutting down reading says nothing more will be read, so data arriving from then
+    /// on, and any already waiting, is handled by the [`UnreadDataPolicy`].
+    pub fn shutdown(&mut self, nic: &impl NetworkDevice, how: Shutdown) -> Result<()> {
+        let _span = span::enter(self.id);
+
+        if self.state == State::Closed {
+            return Err(self.closed_error());
+        }
+
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.recv_shutdown = true;
+
+            if !self.recv_buffer.is_empty()
+                && self.unread_data_policy == UnreadDataPolicy::Reset
+                && self.state.is_synchronised()
+                && !self.state.is_finished()
+            {
+                log!(
+                    "Closing with {}b unread, resetting the connection",
+                    self.recv_buffer.len()
+                );
+                self.recv_buffer.clear();
+                self.send_rst(nic)?;
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+        }
+
+        // Closing both ways after writing has already been shut down only stops reading
+        let shutdown_write: bool = match how {
+            Shutdown::Read => false,
+            Shutdown::Write => true,
+            Shutdown::Both => !self.is_send_shutdown(),
+        };
+        if !shutdown_write {
+            return Ok(());
+        }
+
+        let next_state = match self.state {
+            // Nothing has been received so there is nothing to finish, the TCB is just deleted
+            State::SynSent => {
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+            State::SynRcvd | State::Estab => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait => return Err(TcpError::InvalidState(self.state)),
+            State::Closed => return Err(self.closed_error()),
+        };
+
+        self.fin_queued = true;
+        self.flush(nic)?;
+        self.set_state(next_state);
+
+        Ok(())
+    }
+
+    /// Abort the connection straight away, dropping anything waiting to be sent or read,
+    /// and leave it CLOSED for the stack to delete.
+    /// RFC 9293 Section 3.10.5, the peer is only sent a reset if it could still think the
+    /// connection is open: not before our SYN, nor once it has acknowledged our FIN.
+    pub fn abort(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        let _span = span::enter(self.id);
+
+        match self.state {
+            State::SynRcvd
+            | State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait => self.send_rst(nic)?,
+            State::SynSent | State::Closing | State::LastAck | State::TimeWait => {}
+            State::Closed => return Err(self.closed_error()),
+        }
+
+        self.delete();
+
+        Ok(())
+    }
+
+    /// Drop everything waiting to be sent or read, stop the timers and leave the
+    /// connection CLOSED
+    fn delete(&mut self) {
+        self.send_buffer.clear();
+        self.push_points.clear();
+        self.fin_queued = false;
+        self.recv_buffer.clear();
+        self.recv_pushed = 0;
+        self.retransmit_timer = None;
+        self.unacked_since = None;
+        self.time_wait_deadline = None;
+        self.linger_deadline = None;
+        self.window_update_due = None;
+        self.set_state(State::Closed);
+    }
+
+    /// Whether writing has been shut down, so our FIN has been queued or sent
+    fn is_send_shutdown(&self) -> bool {
+        !matches!(
+            self.state,
+            State::SynSent | State::SynRcvd | State::Estab | State::CloseWait
+        )
+    }
+
+    /// Whether the peer reset the connection, or refused it while we were opening it
+    pub fn was_reset(&self) -> bool {
+        self.reset_by_peer
+    }
+
+    /// Whether the peer answered our SYN with a reset, RFC 9293's "connection refused".
+    /// A reset once the connection is established is only [`Tcb::was_reset`].
+    pub fn was_refused(&self) -> bool {
+        self.refused_by_peer
+    }
+
+    /// What calls on a CLOSED connection fail with
+    fn closed_error(&self) -> TcpError {
+        if self.reset_by_peer {
+            TcpError::ConnectionReset
+        } else {
+            TcpError::NotConnected
+        }
+    }
+
+    /// Whether reading has been shut down, by [`Tcb::close`] or [`Tcb::shutdown`]
+    pub fn is_recv_shutdown(&self) -> bool {
+        self.recv_shutdown
+    }
+
+    /// Queue `data` to be sent, returning how much of it was taken.
+    /// Only as much as fits in the send buffer, [`SEND_BUFFER_SIZE`] bytes by default, is
+    /// taken, and room is made as the peer acknowledges what has been sent.
+    /// Data queued before the connection is established is sent once it is.
+    /// RFC 9293 Section 3.10.2
+    pub fn send(&mut self, nic: &impl NetworkDevice, data: &[u8]) -> Result<usize> {
+        let _span = span::enter(self.id);
+
+        match self.state {
+            State::SynSent | State::SynRcvd | State::Estab | State::CloseWait => {}
+            State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait => return Err(TcpError::InvalidState(self.state)),
+            State::Closed => return Err(self.closed_error()),
+        }
+
+        let n_taken: usize = data.len().min(self.send_space());
+        self.send_buffer.extend(&data[..n_taken]);
+        if n_taken > 0 {
+            self.push_points
+                .push_back(self.send_buffer_start + self.send_buffer.len() as u64);
+        }
+        self.flush(nic)?;
+
+        Ok(n_taken)
+    }
+
+    /// Bytes `send` can take before the send buffer is full. None while it holds more
+    /// than [`SocketOption::SendBufferSize`] after being made smaller.
+    pub fn send_space(&self) -> usize {
+        self.send_buffer_size.saturating_sub(self.send_buffer.len())
+    }
+
+    /// Bytes sent or waiting to be sent which the peer hasn't acknowledged yet
+    pub fn unacked_len(&self) -> usize {
+        self.send_buffer.len()
+    }
+
+    /// The peer's window in bytes, SND.WND, from the latest segment allowed to update it
+    pub fn send_window(&self) -> u32 {
+        self.send.wnd
+    }
+
+    /// Send as much queued data as the peer's window allows, followed by our FIN once
+    /// `close` has been called and everything before it has gone.
+    /// Returns the number of segments sent.
+    fn flush(&mut self, nic: &impl NetworkDevice) -> Result<usize> {
+        let mut n_sent: usize = 0;
+        while self.send_next_segment(nic)? {
+            n_sent += 1;
+        }
+
+        Ok(n_sent)
+    }
+
+    /// Send one segment from SND.NXT, with as much queued data as the MSS, the peer's
+    /// window and the congestion window allow, carrying our FIN if it's the last thing left
+    /// to send. Returns whether a segment was sent.
+    ///
+    /// RFC 9293 Section 3.8.6.1, while the peer's window is closed one byte at a time is
+    /// still sent once everything else has been acknowledged. The retransmission timer
+    /// keeps sending it until the peer opens the window again.
+    ///
+    /// Without [`SocketOption::NoDelay`], a segment smaller than the MSS waits while
+    /// anything is unacknowledged, unless it carries our FIN. RFC 1122 Section 4.2.3.4
+    ///
+    /// With [`SocketOption::Pacing`], data waits for the pacer, and `on_tick` sends it.
+    fn send_next_segment(&mut self, nic: &impl NetworkDevice) -> Result<bool> {
+        // Data waits for the SYN to be acknowledged
+        if self.send.una == self.send.iss {
+            return Ok(false);
+        }
+
+        let n_in_flight: usize =
+            ((self.send.nxt - self.send.una) as usize).min(self.send_buffer.len());
+        let n_unsent: usize = self.send_buffer.len() - n_in_flight;
+
+        let now = self.clock.now();
+        let mss: u32 = self.effective_mss() as u32;
+        self.congestion.on_send(mss, self.rto.srtt(), now);
+
+        let window_end: SeqNum = self.send.una + self.send.wnd.min(self.congestion.cwnd());
+        let mut usable_window: usize = if self.send.nxt.gt(window_end) {
+            0
+        } else {
+            (window_end - self.send.nxt) as usize
+        };
+        if self.send.wnd == 0 && self.send.una == self.send.nxt {
+            usable_window = 1;
+        }
+
+        let n_bytes: usize = n_unsent.min(usable_window).min(self.effective_mss());
+        let fin: bool = self.fin_queued && n_bytes == n_unsent;
+
+        if n_bytes == 0 && !fin {
+            return Ok(false);
+        }
+
+        if !self.nodelay && !fin && n_bytes < self.effective_mss() && n_in_flight > 0 {
+            return Ok(false);
+        }
+
+        if self.pacing && n_bytes > 0 && !self.pacer.can_send(now) {
+            return Ok(false);
+        }
+
+        // The segment holding the end of a `send` is pushed, RFC 9293 Section 3.9.1.2
+        let start: u64 = self.send_buffer_start + n_in_flight as u64;
+        let end: u64 = start + n_bytes as u64;
+        let next_push: usize = self.push_points.partition_point(|&point| point <= start);
+        self.send_tcp_header.psh = self
+            .push_points
+            .get(next_push)
+            .is_some_and(|&point| point <= end);
+        self.send_tcp_header.fin = fin;
+        self.fin_queued &= !fin;
+
+        // The payload goes to the device straight from the send buffer, which is put back
+        // once the segment has been sent
+        let send_buffer: VecDeque<u8> = core::mem::take(&mut self.send_buffer);
+        let payload = Payload::from_deque(&send_buffer, n_in_flight, n_bytes);
+        let written: Result<usize> = self.write(nic, payload);
+        self.send_buffer = send_buffer;
+        written?;
+        self.send_tcp_header.psh = false;
+
+        if self.pacing && n_bytes > 0 {
+            let gain: u32 = if self.congestion.in_slow_start() {
+                pacing::SLOW_START_GAIN
+            } else {
+                pacing::CONGESTION_AVOIDANCE_GAIN
+            };
+            self.pacer
+                .on_send(n_bytes, self.congestion.cwnd(), self.rto.srtt(), gain, now);
+        }
+
+        Ok(true)
+    }
+
+    /// Take the peer's window from `tcp_header`, recording the segment it came from.
+    /// SND.WND = SEG.WND, SND.WL1 = SEG.SEQ, SND.WL2 = SEG.ACK
+    fn update_send_window(&mut self, tcp_header: &TcpHeaderSlice) {
+        self.send.wnd = self
+            .send
+            .scale
+            .from_field(tcp_header.window_size(), tcp_header.syn());
+        self.send.wl1 = SeqNum::new(tcp_header.sequence_number());
+        self.send.wl2 = SeqNum::new(tcp_header.acknowledgment_number());
+    }
+
+    /// Take the peer's window from an acknowledgement, see [`Tcb::update_send_window`].
+    ///
+    /// RFC 9293 Section 3.8.6.1, the peer drops what's sent into its closed window, so when
+    /// the window opens without the probe being acknowledged, sending goes back to SND.UNA.
+    /// Otherwise what follows the probe arrives out of order, and waits for the
+    /// retransmission timer to fill the gap.
+    fn take_acked_window(&mut self, tcp_header: &TcpHeaderSlice) {
+        let window_was_closed: bool = self.send.wnd == 0;
+        self.update_send_window(tcp_header);
+
+        let ackn = SeqNum::new(tcp_header.acknowledgment_number());
+        if !window_was_closed
+            || self.send.wnd == 0
+            || ackn != self.send.una
+            || self.send.nxt == self.send.una
+        {
+            return;
+        }
+
+        let fin_sent: bool = !self.fin_queued
+            && matches!(
+                self.state,
+                State::FinWait1 | State::Closing | State::LastAck
+            );
+        self.send.nxt = self.send.una;
+        self.fin_queued |= fin_sent;
+        self.rtt_timed = None;
+    }
+
+    /// Whether the segment is no older than the one the send window was last taken from,
+    /// SND.WL1 < SEG.SEQ or (SND.WL1 = SEG.SEQ and SND.WL2 =< SEG.ACK)
+    fn is_newer_segment(&self, tcp_header: &TcpHeaderSlice) -> bool {
+        let seq = SeqNum::new(tcp_header.sequence_number());
+        let ackn = SeqNum::new(tcp_header.acknowledgment_number());
+
+        self.send.wl1.lt(seq) || (self.send.wl1 == seq && self.send.wl2.le(ackn))
+    }
+
+    /// Whether everything we've sent, including our FIN, has been acknowledged.
+    /// Only meaningful once `close` has been called, as the FIN is the last thing sent.
+    fn is_fin_acked(&self) -> bool {
+        !self.fin_queued && self.send.una == self.send.nxt
+    }
+
+    fn write(&mut self, nic: &impl NetworkDevice, payload: Payload) -> Result<usize> {
+        self.path_mtu.expire(self.clock.now());
+
+        self.send_tcp_header.sequence_number = self.send.nxt.get();
+        self.send_tcp_header.acknowledgment_number = self.recv.nxt.get();
+        self.send_tcp_header.window_size = self
+            .recv
+            .scale
+            .to_field(self.recv.wnd, self.send_tcp_header.syn);
+        self.window_update_due = None;
+        self.ack_deferred = false;
+
+        let payload_bytes: usize = self.transmit(nic, payload)?;
+        let occupies_sequence_space: bool =
+            payload_bytes > 0 || self.send_tcp_header.syn || self.send_tcp_header.fin;
+
+        self.send.nxt += payload_bytes as u32;
+
+        if self.send_tcp_header.syn {
+            self.send.nxt += 1;
+            self.send_tcp_header.syn = false;
+        }
+
+        if self.send_tcp_header.fin {
+            self.send.nxt += 1;
+            self.send_tcp_header.fin = false;
+        }
+
+        if self.send.nxt.gt(self.send.max) {
+            self.send.max = self.send.nxt;
+        }
+
+        // RFC 6298 Section 5.1, start the timer if it isn't already running
+        let now = self.clock.now();
+        if occupies_sequence_space && self.rtt_timed.is_none() {
+            self.rtt_timed = Some((self.send.nxt, now));
+        }
+        if self.send.una != self.send.nxt && self.retransmit_timer.is_none() {
+            self.retransmit_timer = Some(now);
+        }
+        if self.send.una != self.send.nxt && self.unacked_since.is_none() {
+            self.unacked_since = Some(now);
+        }
+
+        Ok(payload_bytes)
+    }
+
+    /// Send a keep-alive probe.
+    /// RFC 1122 Section 4.2.3.6
+    /// The probe carries SEG.SEQ = SND.NXT-1 and no data, which is outside the
+    /// peer's window and so provokes an ACK without advancing either sequence space.
+    fn send_keepalive_probe(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        self.send_tcp_header.sequence_number = (self.send.nxt - 1).get();
+        self.send_tcp_header.acknowledgment_number = self.recv.nxt.get();
+
+        self.transmit(nic, Payload::EMPTY)?;
+
+        Ok(())
+    }
+
+    /// Serialise the current headers and payload into a packet and send it,
+    /// without touching any sequence variables.
+    fn transmit(&mut self, nic: &impl NetworkDevice, payload: Payload) -> Result<usize> {
+        let mut outgoing = OutgoingOptions::default();
+        // RFC 9293 Section 3.7.1, the MSS option is only sent on SYNs
+        if self.send_tcp_header.syn {
+            outgoing.mss = self.recv_mss;
+            outgoing.fast_open_cookie = self.fast_open_cookie;
+        }
+        if let Some(timestamps) = &mut self.timestamps {
+            outgoing.timestamps = Some((timestamps.ts_val(self.clock.now()), timestamps.recent()));
+            if self.send_tcp_header.ack {
+                timestamps.on_ack_sent(SeqNum::new(self.send_tcp_header.acknowledgment_number));
+            }
+        }
+        if self.send_tcp_header.ack {
+            outgoing.sack_blocks.extend(self.dsack.take());
+        }
+        if let Some(hook) = &mut self.option_hook {
+            outgoing.experimental = hook.outgoing_options(&self.send_tcp_header);
+        }
+
+        let header_options: Vec<u8> = outgoing.encode(self.max_options_len);
+        self.send_tcp_header.set_options_raw(&header_options)?;
+
+        #[cfg(feature = "fault-injection")]
+        if self.segments_to_drop > 0 {
+            self.segments_to_drop -= 1;
+            return Ok(payload.len());
+        }
+
+        if let Some(hook) = &mut self.segment_hook {
+            let segment = SegmentInfo {
+                state: self.state,
+                header: &self.send_tcp_header,
+                payload: &payload.contiguous(),
+            };
+
+            if hook.on_transmit(&segment) == Verdict::Drop {
+                return Ok(payload.len());
+            }
+        }
+
+        let payload_bytes: usize = send_segment(
+            nic,
+            &mut self.send_ip_header,
+            &mut self.send_tcp_header,
+            payload,
+        )?;
+
+        self.stats.segments_out += 1;
+        self.stats.bytes_out += payload_bytes as u64;
+        self.uncounted_bytes_out += payload_bytes as u64;
+
+        Ok(payload_bytes)
+    }
+
+    /// Whether `tcp_header` is a SYN opening a new incarnation of this connection while
+    /// it's in TIME-WAIT, rather than an old duplicate.
+    /// RFC 6191 Section 2, if both the old connection and the SYN use timestamps the SYN's
+    /// must be newer than TS.Recent. Otherwise, as in RFC 1122 Section 4.2.2.13, its
+    /// sequence number must be beyond anything received on the old connection.
+    pub fn is_new_incarnation(&self, tcp_header: &TcpHeaderSlice) -> bool {
+        if self.state != State::TimeWait
+            || !tcp_header.syn()
+            || tcp_header.ack()
+            || tcp_header.rst()
+        {
+            return false;
+        }
+
+        match (&self.timestamps, options::timestamps(tcp_header.options())) {
+            (Some(timestamps), Some((ts_val, _))) => {
+                (ts_val.wrapping_sub(timestamps.recent()) as i32) > 0
+            }
+            _ => SeqNum::new(tcp_header.sequence_number()).gt(self.recv.nxt),
+        }
+    }
+
+    /// Whether we can open a new incarnation of this connection with initial sequence
+    /// number `iss` while it's in TIME-WAIT. As for [`Tcb::is_new_incarnation`] on the
+    /// other end, the new sequence numbers must start beyond anything sent on this one,
+    /// so old duplicates can't be mistaken for them.
+    pub fn can_reconnect_with(&self, iss: u32) -> bool {
+        self.state == State::TimeWait && SeqNum::new(iss).gt(self.send.nxt)
+    }
+
+    /// Act on an ICMP error about the segment we sent with sequence number `seq`.
+    /// RFC 5927 Section 4.1, errors quoting a sequence number outside SND.UNA..SND.NXT
+    /// are ignored, so a blind attacker has to guess it to affect the connection.
+    /// RFC 5461 Section 4, hard errors only abort a connection which is still opening.
+    /// Once synchronised they're treated as soft errors, as Linux does, since a route
+    /// change can make them transient.
+    pub fn on_icmp_error(&mut self, seq: u32, error: IcmpError) {
+        let _span = span::enter(self.id);
+
+        let in_flight: u32 = self.send.nxt - self.send.una;
+        if !SeqNum::new(seq).is_in_window(self.send.una, in_flight) {
+            log!("Ignoring {error:?} for unsent or acknowledged sequence number {seq}");
+            return;
+        }
+
+        match error {
+            IcmpError::FragmentationNeeded { next_hop_mtu } => {
+                self.path_mtu
+                    .on_fragmentation_needed(next_hop_mtu, self.clock.now());
+            }
+            error if error.is_hard() && !self.state.is_synchronised() => {
+                log!("Connection aborted by {error:?} in state {:?}", self.state);
+                self.set_state(State::Closed);
+            }
+            error => {
+                log!("Soft error {error:?} in state {:?}", self.state);
+                self.push_soft_error(SoftError::Icmp(error));
+            }
+        }
+    }
+
+    /// Take the soft errors reported since the last call, oldest first.
+    /// Only the latest 16 are kept.
+    pub fn take_soft_errors(&mut self) -> Vec<SoftError> {
+        self.soft_errors.drain(..).collect()
+    }
+
+    fn push_soft_error(&mut self, error: SoftError) {
+        if self.soft_errors.len() == MAX_SOFT_ERRORS {
+            self.soft_errors.pop_front();
+        }
+        self.soft_errors.push_back(error);
+    }
+
+    /// Reset the connection from our side.
+    /// RFC 9293 Section 3.10.5, <SEQ=SND.NXT><CTL=RST>
+    fn send_rst(&mut self, nic: &impl NetworkDevice) -> Result<()> {
+        self.send_tcp_header.rst = true;
+        self.send_tcp_header.ack = false;
+        self.send_tcp_header.syn = false;
+        self.send_tcp_header.fin = false;
+        self.send_tcp_header.sequence_number = self.send.nxt.get();
+        self.send_tcp_header.acknowledgment_number = 0;
+
+        let result = self.transmit(nic, Payload::EMPTY);
+
+        self.send_tcp_header.rst = false;
+        self.send_tcp_header.ack = true;
+
+        result.map(|_| ())
+    }
+
+    /// RFC 793 Section 3.3
+    /// The first part of this test checks to see if the beginning of the
+    /// segment falls in the window, the second part of the test checks to see
+    /// if the end of the segment falls in the window; if the segment passes
+    /// either part of the test it contains data in the window.
+    ///
+    /// Actually, it is a little more complicated than this.  Due to zero
+    /// windows and zero length segments, we have four cases for the
+    /// acceptability of an incoming segment:
+    ///```text
+    ///   Segment Receive  Test
+    ///   Length  Window
+    ///   ------- -------  -------------------------------------------
+    ///
+    ///      0       0     SEG.SEQ = RCV.NXT
+    ///
+    ///      0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+    ///
+    ///     >0       0     not acceptable
+    ///
+    ///     >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+    ///                 or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+    /// ```
+    fn is_segment_valid(&self, tcp_header: &TcpHeaderSlice, data: &[u8]) -> bool {
+        let seqn = SeqNum::new(tcp_header.sequence_number());
+
+        let seg_len: u32 = segment_len(tcp_header, data);
+
+        let wnd: u32 = self.recv.scale.advertised(self.recv.wnd, false);
+
+        if seg_len == 0 {
+            if wnd == 0 {
+                seqn == self.recv.nxt
+            } else {
+                seqn.is_in_window(self.recv.nxt, wnd)
+            }
+        } else {
+            if wnd == 0 {
+                false
+            } else {
+                seqn.is_in_window(self.recv.nxt, wnd)
+                    || (seqn + (seg_len - 1)).is_in_window(self.recv.nxt, wnd)
+            }
+        }
+    }
+
+    /// <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>, unless the shared limit has been reached.
+    /// RFC 5961 Section 3.2
+    fn send_challenge_ack(
+        &mut self,
+        nic: &impl NetworkDevice,
+        challenge_acks: &mut ChallengeAckLimiter,
+    ) -> Result<()> {
+        if challenge_acks.try_acquire(self.clock.now()) {
+            self.write(nic, Payload::EMPTY)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Length of a segment in sequence space, counting SYN and FIN
+pub fn segment_len(tcp_header: &TcpHeaderSlice, data: &[u8]) -> u32 {
+    let mut slen = data.len();
+    if tcp_header.fin() {
+        slen += 1;
+    }
+
+    if tcp_header.syn() {
+        slen += 1;
+    }
+    slen as u32
+}
+
+/// Largest segment the peer will accept, from the MSS option on its SYN, no smaller than
+/// [`MIN_MSS`] so a bogus option can't stall the connection
+fn peer_mss(options: &[u8]) -> u16 {
+    options::mss(options).unwrap_or(DEFAULT_MSS).max(MIN_MSS)
+}
+
+/// Reply to a segment which doesn't belong to a synchronised connection with a reset.
+/// RFC 9293 Section 3.10.7.1
+/// ```text
+/// If the incoming segment has an ACK field, the reset takes its
+/// sequence number from the ACK field of the segment, otherwise the
+/// reset has sequence number zero and the ACK field is set to the sum
+/// of the sequence number and segment length of the incoming segment.
+///
+///     <SEQ=SEG.ACK><CTL=RST>
+///     <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+/// ```
+/// Incoming resets are never answered. The reset is sent with time to live `ttl`, usually
+/// [`TcbConfig::ttl`].
+pub fn send_reset(
+    nic: &impl NetworkDevice,
+    ip_header: &Ipv4HeaderSlice,
+    tcp_header: &TcpHeaderSlice,
+    data: &[u8],
+    ttl: u8,
+) -> Result<()> {
+    if tcp_header.rst() {
+        return Ok(());
+    }
+
+    let mut reset_tcp_header = TcpHeader {
+        source_port: tcp_header.destination_port(),
+        destination_port: tcp_header.source_port(),
+        rst: true,
+        ..Default::default()
+    };
+
+    if tcp_header.ack() {
+        reset_tcp_header.sequence_number = tcp_header.acknowledgment_number();
+    } else {
+        reset_tcp_header.ack = true;
+        reset_tcp_header.acknowledgment_number =
+            (SeqNum::new(tcp_header.sequence_number()) + segment_len(tcp_header, data)).get();
+    }
+
+    let mut reset_ip_header = Ipv4Header::new(
+        reset_tcp_header.header_len_u16(),
+        ttl,
+        IpNumber::TCP,
+        ip_header.destination(),
+        ip_header.source(),
+    )?;
+
+    log!(
+        "Resetting {}:{} -> {}:{}",
+        Ipv4Addr::from(ip_header.source()),
+        tcp_header.source_port(),
+        Ipv4Addr::from(ip_header.destination()),
+        tcp_header.destination_port(),
+    );
+
+    send_segment(
+        nic,
+        &mut reset_ip_header,
+        &mut reset_tcp_header,
+        Payload::EMPTY,
+    )?;
+
+    Ok(())
+}
+
+/// Serialise the headers and send them along with the payload as one packet. The payload
+/// goes to the device from wherever it is, rather than being copied in after the headers.
+/// Fills in the IP payload length and TCP checksum, returning the number of payload bytes sent.
+fn send_segment(
+    nic: &impl NetworkDevice,
+    ip_header: &mut Ipv4Header,
+    tcp_header: &mut TcpHeader,
+    payload: Payload,
+) -> Result<usize> {
+    let ip_header_len: usize = ip_header.header_len();
+    let headers_len: usize = ip_header_len + tcp_header.header_len();
+
+    // Only as much of the payload as fits in a packet is sent
+    let payload: Payload = payload.truncate(ETH_MTU.saturating_sub(headers_len));
+    ip_header.set_payload_len(tcp_header.header_len() + payload.len())?;
+
+    let mut buf: [u8; MAX_HEADERS_LEN] = [0; MAX_HEADERS_LEN];
+    ip_header.header_checksum = ip_header.calc_header_checksum();
+    buf[..ip_header_len].copy_from_slice(&ip_header.to_bytes());
+    tcp_header.checksum = 0;
+    buf[ip_header_len..headers_len].copy_from_slice(&tcp_header.to_bytes());
+    let headers: &mut [u8] = &mut buf[..headers_len];
+
+    tcp_header.checksum = checksum::tcp_ipv4(
+        ip_header.source,
+        ip_header.destination,
+        &[&headers[ip_header_len..], payload.front, payload.back],
+    );
+    let checksum_offset: usize = ip_header_len + TCP_CHECKSUM_OFFSET;
+    headers[checksum_offset..checksum_offset + 2]
+        .copy_from_slice(&tcp_header.checksum.to_be_bytes());
+
+    let num_written_bytes: usize = nic.send_vectored(&[
+        IoSlice::new(headers),
+        IoSlice::new(payload.front),
+        IoSlice::new(payload.back),
+    ])?;
+
+    trace!(
+        "Response ({num_written_bytes}b), headers: \n{:02x?}",
+        headers
+    );
+
+    Ok(payload.len())
+}
+
+/// Data for one segment, in up to two pieces as it's taken straight from the send buffer,
+/// which can wrap around the end of its ring
+#[derive(Clone, Copy, Debug, Default)]
+struct Payload<'a> {
+    front: &'a [u8],
+    back: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    const EMPTY: Payload<'static> = Payload {
+        front: &[],
+        back: &[],
+    };
+
+    /// `len` bytes of `buffer`, starting `start` bytes in
+    fn from_deque(buffer: &'a VecDeque<u8>, start: usize, len: usize) -> Self {
+        let (first, second) = buffer.as_slices();
+        let end: usize = start + len;
+
+        Payload {
+            front: &first[start.min(first.len())..end.min(first.len())],
+            back: &second[start.saturating_sub(first.len())..end.saturating_sub(first.len())],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.front.len() + self.back.len()
+    }
+
+    /// At most the first `len` bytes
+    fn truncate(self, len: usize) -> Self {
+        let front: &[u8] = &self.front[..len.min(self.front.len())];
+        let back: &[u8] = &self.back[..(len - front.len()).min(self.back.len())];
+
+        Payload { front, back }
+    }
+
+    /// The payload in one piece, which is only copied if it's split
+    fn contiguous(&self) -> Cow<'a, [u8]> {
+        if self.back.is_empty() {
+            Cow::Borrowed(self.front)
+        } else {
+            Cow::Owned([self.front, self.back].concat())
+        }
+    }
+}
+
+/// Connection states from RFC 9293 Section 3.3.2.
+/// LISTEN is represented by a listener with no TCB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// We've sent a SYN from `Tcb::connect` and are waiting for the peer's
+    SynSent,
+    SynRcvd,
+    Estab,
+    /// We've sent a FIN which hasn't been acknowledged
+    FinWait1,
+    /// Our FIN has been acknowledged, waiting for the peer's
+    FinWait2,
+    /// The peer has closed, waiting for the application to close
+    CloseWait,
+    /// Both sides sent a FIN at the same time, waiting for ours to be acknowledged
+    Closing,
+    /// The peer closed first and we've sent our FIN, waiting for it to be acknowledged
+    LastAck,
+    TimeWait,
+    /// The connection has ended and the TCB can be deleted
+    Closed,
+}
+
+impl State {
+    pub fn is_synchronised(&self) -> bool {
+        use State::*;
+
+        match self {
+            SynSent | SynRcvd | Closed => false,
+            Estab | FinWait1 | FinWait2 | CloseWait | Closing | LastAck | TimeWait => true,
+        }
+    }
+
+    /// Whether both sides have finished sending, so the connection no longer counts as open
+    pub fn is_finished(&self) -> bool {
+        matches!(self, State::TimeWait | State::Closed)
     }
 }
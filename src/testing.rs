@@ -0,0 +1,336 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    clock::{Clock, SimulatedClock},
+    device::{LoopbackDevice, NetworkDevice},
+    error::{Result, TcpError},
+    impair::{ImpairedDevice, Impairment},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    PACKET_BUF_SIZE,
+};
+
+/// Address of the client end of a [`Wan`]
+pub const CLIENT_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+/// Address of the server end of a [`Wan`]
+pub const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+/// Port the client connects from in [`Wan::established`]
+pub const CLIENT_PORT: u16 = 40000;
+
+/// Most packets [`Wan::run_until_idle`] delivers before deciding the link will never go quiet
+const MAX_PACKETS: usize = 100_000;
+
+/// One end of a [`Wan`]
+pub struct Host {
+    /// Impairs the packets this host sends
+    pub device: ImpairedDevice<LoopbackDevice>,
+    pub stack: Stack,
+}
+
+impl Host {
+    fn new(
+        device: LoopbackDevice,
+        impairment: Impairment,
+        seed: u64,
+        clock: &Arc<SimulatedClock>,
+    ) -> Self {
+        let mut device = ImpairedDevice::new(device, impairment, seed);
+        device.set_clock(clock.clone());
+
+        let mut stack = Stack::new(
+            Listeners::default(),
+            IsnGenerator::new([1; 16]),
+            clock.now(),
+        );
+        stack.set_clock(clock.clone());
+
+        Host { device, stack }
+    }
+
+    /// Process every packet waiting on this end, returning how many there were
+    pub fn receive(&mut self, now: Instant) -> Result<usize> {
+        let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+        let mut n_packets: usize = 0;
+
+        while let Ok(n_bytes) = self.device.recv(&mut buf) {
+            self.stack.on_packet(&self.device, &buf[..n_bytes], now)?;
+            n_packets += 1;
+        }
+
+        Ok(n_packets)
+    }
+
+    pub fn state(&self, info: &ConnectInfo) -> Option<State> {
+        self.stack.connection(info).map(|tcb| tcb.state())
+    }
+
+    /// Read everything received on the connection so far
+    pub fn read_all(&mut self, info: &ConnectInfo) -> Vec<u8> {
+        let mut received: Vec<u8> = Vec::new();
+        let Some(tcb) = self.stack.connection_mut(info) else {
+            return received;
+        };
+
+        let mut buf: [u8; 4096] = [0; 4096];
+        loop {
+            let n_read: usize = tcb.read(&mut buf);
+            if n_read == 0 {
+                return received;
+            }
+            received.extend_from_slice(&buf[..n_read]);
+        }
+    }
+
+    /// The next time the stack has a timer to run or the device a delayed packet to send
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [self.stack.next_deadline(), self.device.next_release()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+/// Which end of a [`Wan`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// A connection across a [`Wan`], keyed as each end sees it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Connection {
+    pub client: ConnectInfo,
+    pub server: ConnectInfo,
+}
+
+impl Connection {
+    pub fn info(&self, side: Side) -> ConnectInfo {
+        match side {
+            Side::Client => self.client,
+            Side::Server => self.server,
+        }
+    }
+}
+
+/// Two stacks joined by an emulated WAN, for end to end tests without a tun device.
+///
+/// Each host sends through an [`ImpairedDevice`], so packets are lost, delayed,
+/// duplicated and reordered both ways, and everything runs on one [`SimulatedClock`].
+/// Time only moves when nothing is left to deliver, straight to the next timer or delayed
+/// packet, so tests are fast and a seed always plays out the same way.
+pub struct Wan {
+    pub clock: Arc<SimulatedClock>,
+    pub client: Host,
+    pub server: Host,
+}
+
+impl Wan {
+    /// A client at [`CLIENT_ADDR`] and a server at [`SERVER_ADDR`], neither listening on
+    /// anything yet, with `impairment` applied both ways. `seed` picks what's impaired.
+    pub fn new(impairment: Impairment, seed: u64) -> Self {
+        let clock = Arc::new(SimulatedClock::new());
+        let (client_device, server_device) = LoopbackDevice::pair();
+
+        Wan {
+            client: Host::new(client_device, impairment, seed, &clock),
+            server: Host::new(server_device, impairment, seed.wrapping_add(1), &clock),
+            clock,
+        }
+    }
+
+    /// Two stacks over a perfect link
+    pub fn lossless() -> Self {
+        Wan::new(Impairment::default(), 0)
+    }
+
+    pub fn host(&self, side: Side) -> &Host {
+        match side {
+            Side::Client => &self.client,
+            Side::Server => &self.server,
+        }
+    }
+
+    pub fn host_mut(&mut self, side: Side) -> &mut Host {
+        match side {
+            Side::Client => &mut self.client,
+            Side::Server => &mut self.server,
+        }
+    }
+
+    /// Accept connections to `port` on the server
+    pub fn listen(&mut self, port: u16, limits: ListenerLimits) {
+        self.server.stack.listeners_mut().insert(port, limits);
+    }
+
+    /// Deliver packets both ways until neither side has anything more to send, without
+    /// moving the clock. Returns how many packets were delivered.
+    pub fn run_until_idle(&mut self) -> Result<usize> {
+        let mut n_packets: usize = 0;
+
+        loop {
+            self.client.device.release_due()?;
+            self.server.device.release_due()?;
+
+            let now: Instant = self.clock.now();
+            let n_delivered: usize = self.client.receive(now)? + self.server.receive(now)?;
+            if n_delivered == 0 {
+                return Ok(n_packets);
+            }
+
+            n_packets += n_delivered;
+            if n_packets >= MAX_PACKETS {
+                return Err(TcpError::Failed("the link never went quiet".into()));
+            }
+        }
+    }
+
+    /// Move the clock on to the next timer or delayed packet on either side, then run
+    /// everything due. Returns false if there was nothing to wait for.
+    pub fn step(&mut self) -> Result<bool> {
+        let next: Option<Instant> = [self.client.next_deadline(), self.server.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min();
+        let Some(next) = next else {
+            return Ok(false);
+        };
+
+        self.advance_to(next)?;
+        Ok(true)
+    }
+
+    /// Move the clock on to `when`, then run every timer due on either side and deliver
+    /// whatever they send. Timers due before `when` run late rather than in order.
+    pub fn advance_to(&mut self, when: Instant) -> Result<()> {
+        self.clock.advance_to(when);
+        let now: Instant = self.clock.now();
+        self.client.stack.on_tick(&self.client.device, now)?;
+        self.server.stack.on_tick(&self.server.device, now)?;
+        self.run_until_idle()?;
+
+        Ok(())
+    }
+
+    /// Deliver packets and step the clock until `done` holds, checking it whenever the
+    /// link goes quiet. Fails if it doesn't hold within `max_steps` steps, or if nothing
+    /// is left to wait for.
+    pub fn run_until(
+        &mut self,
+        max_steps: usize,
+        mut done: impl FnMut(&mut Wan) -> bool,
+    ) -> Result<()> {
+        for _ in 0..max_steps {
+            self.run_until_idle()?;
+            if done(self) {
+                return Ok(());
+            }
+
+            // Whatever `done` sent goes before the clock moves
+            if self.run_until_idle()? > 0 {
+                continue;
+            }
+            if !self.step()? {
+                break;
+            }
+        }
+
+        if done(self) {
+            return Ok(());
+        }
+        Err(TcpError::Failed(format!(
+            "the condition didn't hold after {max_steps} steps"
+        )))
+    }
+
+    /// Start opening a connection from `client_port` on the client to `server_port` on
+    /// the server, without waiting for the handshake. A `client_port` of 0 picks an
+    /// ephemeral port.
+    pub fn open(&mut self, client_port: u16, server_port: u16) -> Result<Connection> {
+        let local = SocketAddrV4::new(CLIENT_ADDR, client_port);
+        let remote = SocketAddrV4::new(SERVER_ADDR, server_port);
+
+        let client: ConnectInfo = self
+            .client
+            .stack
+            .connect(&self.client.device, local, remote)?;
+
+        Ok(Connection {
+            client,
+            server: ConnectInfo {
+                src_addr: CLIENT_ADDR,
+                src_port: client.dst_port,
+                dst_addr: SERVER_ADDR,
+                dst_port: server_port,
+            },
+        })
+    }
+
+    /// Open a connection from `client_port` on the client to `server_port` on the server,
+    /// which must be listening, returning once both ends are established
+    pub fn connect(&mut self, client_port: u16, server_port: u16) -> Result<Connection> {
+        let connection: Connection = self.open(client_port, server_port)?;
+
+        self.run_until(1000, |wan| {
+            wan.client.state(&connection.client) == Some(State::Estab)
+                && wan.server.state(&connection.server) == Some(State::Estab)
+        })?;
+
+        Ok(connection)
+    }
+
+    /// Listen on `server_port` with the default limits and connect to it from
+    /// [`CLIENT_PORT`], returning once both ends are established
+    pub fn established(mut self, server_port: u16) -> Result<(Wan, Connection)> {
+        self.listen(server_port, ListenerLimits::default());
+        let connection: Connection = self.connect(CLIENT_PORT, server_port)?;
+        Ok((self, connection))
+    }
+
+    /// Send `data` from one end of the connection, returning everything the other end
+    /// read once it's all arrived
+    pub fn transfer(&mut self, connection: Connection, from: Side, data: &[u8]) -> Result<Vec<u8>> {
+        let to: Side = match from {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        };
+        let mut n_sent: usize = 0;
+        let mut received: Vec<u8> = Vec::new();
+        let mut send_error: Option<TcpError> = None;
+
+        self.run_until(100_000, |wan| {
+            let sender: &mut Host = wan.host_mut(from);
+            if let Some(tcb) = sender.stack.connection_mut(&connection.info(from)) {
+                if n_sent < data.len() {
+                    match tcb.send(&sender.device, &data[n_sent..]) {
+                        Ok(n_taken) => n_sent += n_taken,
+                        Err(err) => {
+                            send_error = Some(err);
+                            return true;
+                        }
+                    }
+                }
+            }
+
+            received.extend(wan.host_mut(to).read_all(&connection.info(to)));
+            received.len() >= data.len()
+        })?;
+
+        match send_error {
+            Some(err) => Err(err),
+            None => Ok(received),
+        }
+    }
+
+    /// Close one end of the connection, without waiting for anything
+    pub fn close(&mut self, connection: Connection, side: Side) -> Result<()> {
+        let host: &mut Host = self.host_mut(side);
+        host.stack.close(&host.device, &connection.info(side))
+    }
+}
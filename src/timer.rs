@@ -0,0 +1,72 @@
+use alloc::collections::BTreeMap;
+
+use crate::{clock::Instant, tcp::ConnectInfo};
+
+/// A connection's place in a [`TimerQueue`], which the connection keeps so its timer can
+/// be moved or cancelled without searching for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerHandle {
+    deadline: Instant,
+    /// Tells apart timers with the same deadline
+    id: u64,
+}
+
+impl TimerHandle {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+/// Each connection's next deadline, ordered by when it expires, so finding what's due
+/// doesn't mean looking at every connection. Scheduling and cancelling are O(log n).
+#[derive(Debug, Default)]
+pub struct TimerQueue {
+    timers: BTreeMap<TimerHandle, ConnectInfo>,
+    next_id: u64,
+}
+
+impl TimerQueue {
+    /// Expire a timer for `connection` at `deadline`
+    pub fn schedule(&mut self, deadline: Instant, connection: ConnectInfo) -> TimerHandle {
+        let handle = TimerHandle {
+            deadline,
+            id: self.next_id,
+        };
+        self.next_id += 1;
+        self.timers.insert(handle, connection);
+        handle
+    }
+
+    /// Stop a timer, returning the connection it was for if it hadn't expired
+    pub fn cancel(&mut self, handle: TimerHandle) -> Option<ConnectInfo> {
+        self.timers.remove(&handle)
+    }
+
+    /// Take the earliest timer if it's expired by `now`
+    pub fn pop_expired(&mut self, now: Instant) -> Option<ConnectInfo> {
+        let entry = self.timers.first_entry()?;
+        if entry.key().deadline > now {
+            return None;
+        }
+        Some(entry.remove())
+    }
+
+    /// Every timer, earliest first
+    pub fn iter(&self) -> impl Iterator<Item = (Instant, &ConnectInfo)> {
+        self.timers
+            .iter()
+            .map(|(handle, connection)| (handle.deadline, connection))
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.keys().next().map(TimerHandle::deadline)
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+}
@@ -0,0 +1,93 @@
+use core::time::Duration;
+
+use crate::{clock::Instant, seq::SeqNum};
+
+/// Longest a connection can be idle before TS.Recent is no longer trusted, as the peer's
+/// millisecond clock may have wrapped far enough to look older than it.
+/// RFC 7323 Section 5.5
+pub const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Timestamp option state for a connection which negotiated it.
+/// RFC 7323 Section 3 and 4
+///
+/// Every segment we send carries TSval from a millisecond clock and echoes TS.Recent in
+/// TSecr, zero length segments such as ACKs and keep-alive probes included, so the peer's
+/// PAWS check keeps accepting them however long the connection has been idle.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamps {
+    /// Added to the clock so TSval doesn't give away how long the stack has been up.
+    /// RFC 7323 Section 7.1
+    offset: u32,
+    epoch: Instant,
+    /// The peer's TSval to echo, TS.Recent
+    recent: u32,
+    /// When `recent` was last updated
+    recent_age: Instant,
+    /// The acknowledgement number of the last ACK we sent, Last.ACK.sent
+    last_ack_sent: SeqNum,
+}
+
+impl Timestamps {
+    /// Start a clock at `offset`, echoing `recent` until the peer sends something newer
+    pub fn new(offset: u32, recent: u32, now: Instant) -> Self {
+        Timestamps {
+            offset,
+            epoch: now,
+            recent,
+            recent_age: now,
+            last_ack_sent: SeqNum::default(),
+        }
+    }
+
+    /// Our TSval at `now`
+    pub fn ts_val(&self, now: Instant) -> u32 {
+        let elapsed_ms: u128 = now.saturating_duration_since(self.epoch).as_millis();
+        self.offset.wrapping_add(elapsed_ms as u32)
+    }
+
+    /// TS.Recent, echoed in TSecr
+    pub fn recent(&self) -> u32 {
+        self.recent
+    }
+
+    /// Take TS.Recent from the peer's SYN,ACK. Nothing has been acknowledged before it,
+    /// so it's always echoed. RFC 7323 Section 3.2
+    pub fn on_syn(&mut self, ts_val: u32, now: Instant) {
+        self.recent = ts_val;
+        self.recent_age = now;
+    }
+
+    /// Record the acknowledgement number of a segment we sent
+    pub fn on_ack_sent(&mut self, ack: SeqNum) {
+        self.last_ack_sent = ack;
+    }
+
+    /// Whether TS.Recent is still meaningful, having been updated within [`PAWS_IDLE_LIMIT`]
+    fn is_recent_valid(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.recent_age) <= PAWS_IDLE_LIMIT
+    }
+
+    /// PAWS, whether a segment carrying `ts_val` is older than one already received and
+    /// should be dropped. RFC 7323 Section 5.3 R1
+    pub fn is_old(&self, ts_val: u32, now: Instant) -> bool {
+        (ts_val.wrapping_sub(self.recent) as i32) < 0 && self.is_recent_valid(now)
+    }
+
+    /// Remember `ts_val` to echo if the segment it came on starting at `seq` covers
+    /// everything we've acknowledged, so a delayed ACK echoes the oldest unacknowledged
+    /// segment rather than the newest. RFC 7323 Section 4.3
+    /// ```text
+    /// If SEG.TSval >= TS.Recent and SEG.SEQ <= Last.ACK.sent
+    /// then SEG.TSval is copied to TS.Recent; otherwise, it is ignored.
+    /// ```
+    pub fn update_recent(&mut self, ts_val: u32, seq: SeqNum, now: Instant) {
+        let is_newer: bool =
+            (ts_val.wrapping_sub(self.recent) as i32) >= 0 || !self.is_recent_valid(now);
+        let is_acknowledged: bool = seq.le(self.last_ack_sent);
+
+        if is_newer && is_acknowledged {
+            self.recent = ts_val;
+            self.recent_age = now;
+        }
+    }
+}
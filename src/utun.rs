@@ -0,0 +1,228 @@
+use std::{
+    ffi::CStr,
+    io::{self, IoSlice, IoSliceMut},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use crate::{
+    device::{self, NetworkDevice},
+    span::log,
+    ETH_MTU,
+};
+
+/// Kernel control which hands out utun interfaces
+const UTUN_CONTROL_NAME: &CStr = c"com.apple.net.utun_control";
+
+/// Length of the protocol family which starts every packet read from or written to a utun
+/// device, in network byte order
+const FAMILY_PREFIX_SIZE: usize = 4;
+
+/// A macOS utun interface moving IP packets in and out of the stack, the counterpart of a
+/// Linux tun device. macOS has no tap devices, so there is no Ethernet mode.
+///
+/// Packets are prefixed with their protocol family, `AF_INET` or `AF_INET6`, which is
+/// added on send and stripped on receive so the stack only ever sees the IP packet.
+pub struct Utun {
+    fd: OwnedFd,
+    name: String,
+}
+
+impl Utun {
+    /// Open the interface `name`, which must be `utun<n>`, or `utun` for the next free
+    /// unit. The kernel picks the name either way, see [`Utun::name`]. Needs root.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let unit: u32 = match name.strip_prefix("utun") {
+            Some("") => 0,
+            // sc_unit counts from 1, 0 asking for the next free unit
+            Some(n) => n
+                .parse::<u32>()
+                .ok()
+                .and_then(|n| n.checked_add(1))
+                .ok_or(io::ErrorKind::InvalidInput)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("utun interfaces are named utun<n>, got {name}"),
+                ))
+            }
+        };
+
+        // SAFETY: no pointers are passed
+        let fd: RawFd =
+            unsafe { libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        set_cloexec(&fd)?;
+
+        // SAFETY: an all zero ctl_info is valid, the name is copied in below
+        let mut info: libc::ctl_info = unsafe { std::mem::zeroed() };
+        for (dst, &src) in info.ctl_name.iter_mut().zip(UTUN_CONTROL_NAME.to_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        // SAFETY: `info` is a valid ctl_info which outlives the call
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::CTLIOCGINFO, &mut info) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let addr = libc::sockaddr_ctl {
+            sc_len: std::mem::size_of::<libc::sockaddr_ctl>() as libc::c_uchar,
+            sc_family: libc::AF_SYSTEM as libc::c_uchar,
+            ss_sysaddr: libc::AF_SYS_CONTROL as u16,
+            sc_id: info.ctl_id,
+            sc_unit: unit,
+            sc_reserved: [0; 5],
+        };
+        // SAFETY: `addr` is a valid sockaddr_ctl and its size is given
+        let result: libc::c_int = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                (&addr as *const libc::sockaddr_ctl).cast::<libc::sockaddr>(),
+                std::mem::size_of::<libc::sockaddr_ctl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name: [u8; libc::IFNAMSIZ] = [0; libc::IFNAMSIZ];
+        let mut name_len = name.len() as libc::socklen_t;
+        // SAFETY: `name` is valid for writes of `name_len` bytes
+        let result: libc::c_int = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SYSPROTO_CONTROL,
+                libc::UTUN_OPT_IFNAME,
+                name.as_mut_ptr().cast::<libc::c_void>(),
+                &mut name_len,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let name: String = CStr::from_bytes_until_nul(&name)
+            .map_err(|_| io::ErrorKind::InvalidData)?
+            .to_string_lossy()
+            .into_owned();
+
+        Ok(Utun { fd, name })
+    }
+
+    /// The interface's name, as chosen by the kernel
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_non_blocking(&self) -> io::Result<()> {
+        // SAFETY: no pointers are passed
+        let flags: libc::c_int = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: no pointers are passed
+        let result: libc::c_int =
+            unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// macOS has no SOCK_CLOEXEC, so the flag is set after the socket is opened
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: no pointers are passed
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The family prefix for an IP packet, by its version
+fn family_prefix(packet: &[u8]) -> [u8; FAMILY_PREFIX_SIZE] {
+    let family: libc::c_int = match packet.first().map(|byte| byte >> 4) {
+        Some(6) => libc::AF_INET6,
+        _ => libc::AF_INET,
+    };
+    (family as u32).to_be_bytes()
+}
+
+impl NetworkDevice for Utun {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut prefix: [u8; FAMILY_PREFIX_SIZE] = [0; FAMILY_PREFIX_SIZE];
+        let mut bufs: [IoSliceMut<'_>; 2] = [IoSliceMut::new(&mut prefix), IoSliceMut::new(buf)];
+
+        // SAFETY: IoSliceMut is guaranteed to be ABI compatible with iovec on Unix, and
+        // every slice outlives the call
+        let n_read: isize = unsafe {
+            libc::readv(
+                self.fd.as_raw_fd(),
+                bufs.as_mut_ptr().cast::<libc::iovec>(),
+                bufs.len() as libc::c_int,
+            )
+        };
+        if n_read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((n_read as usize).saturating_sub(FAMILY_PREFIX_SIZE))
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// Each write to a utun device is one packet, so the prefix and the pieces go to the
+    /// kernel as they are
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let first: &[u8] = bufs.first().map(|buf| &**buf).unwrap_or_default();
+        let prefix: [u8; FAMILY_PREFIX_SIZE] = family_prefix(first);
+        let mut iovecs: Vec<IoSlice<'_>> = Vec::with_capacity(bufs.len() + 1);
+        iovecs.push(IoSlice::new(&prefix));
+        iovecs.extend_from_slice(bufs);
+
+        let n_bufs: libc::c_int = iovecs
+            .len()
+            .try_into()
+            .map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on Unix, and every
+        // slice outlives the call
+        let n_written: isize = unsafe {
+            libc::writev(
+                self.fd.as_raw_fd(),
+                iovecs.as_ptr().cast::<libc::iovec>(),
+                n_bufs,
+            )
+        };
+        if n_written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((n_written as usize).saturating_sub(FAMILY_PREFIX_SIZE))
+    }
+
+    /// The interface's configured MTU, or [`ETH_MTU`] if it can't be read
+    fn mtu(&self) -> usize {
+        match device::interface_mtu(&self.name) {
+            Ok(mtu) => mtu,
+            Err(err) => {
+                log!(
+                    "Couldn't read the MTU of {}, assuming {ETH_MTU}: {err}",
+                    self.name
+                );
+                ETH_MTU
+            }
+        }
+    }
+}
+
+impl AsRawFd for Utun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
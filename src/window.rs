@@ -0,0 +1,65 @@
+use crate::span::log;
+
+/// Receive window advertised on new connections, in bytes
+pub const DEFAULT_RECV_WINDOW: u32 = 1024;
+
+/// Largest shift allowed by the window scale option.
+/// RFC 7323 Section 2.3
+pub const MAX_SHIFT: u8 = 14;
+
+/// How a window in bytes maps to the 16 bit window field of a segment.
+/// RFC 7323 Section 2.2
+///
+/// Sequence variables always hold windows in bytes. Scaling only happens when
+/// a window is read from or written to a header, and never on SYN segments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WindowScale {
+    shift: u8,
+}
+
+impl WindowScale {
+    /// No scaling, used until the option has been negotiated
+    pub const NONE: WindowScale = WindowScale { shift: 0 };
+
+    /// A scale with the given shift count, clamped to [`MAX_SHIFT`].
+    /// RFC 7323 Section 2.3
+    pub fn new(shift: u8) -> Self {
+        if shift > MAX_SHIFT {
+            log!("Window scale shift {shift} is too large, using {MAX_SHIFT}");
+        }
+
+        WindowScale {
+            shift: shift.min(MAX_SHIFT),
+        }
+    }
+
+    pub fn shift(&self) -> u8 {
+        self.shift
+    }
+
+    /// Largest window in bytes which can be advertised with this scale
+    pub fn max_window(&self) -> u32 {
+        (u16::MAX as u32) << self.shift
+    }
+
+    /// Window in bytes from the window field of a received segment
+    pub fn from_field(&self, field: u16, syn: bool) -> u32 {
+        let shift: u8 = if syn { 0 } else { self.shift };
+        (field as u32) << shift
+    }
+
+    /// Window field advertising `window` bytes. Clamped to the largest representable
+    /// window and rounded down, so never more than `window` is offered.
+    pub fn to_field(&self, window: u32, syn: bool) -> u16 {
+        let shift: u8 = if syn { 0 } else { self.shift };
+        (window >> shift).min(u16::MAX as u32) as u16
+    }
+
+    /// The window in bytes the peer will see when `window` is advertised.
+    /// Acceptance checks have to use this rather than `window` itself, as the
+    /// peer can only send what it was told about.
+    pub fn advertised(&self, window: u32, syn: bool) -> u32 {
+        let shift: u8 = if syn { 0 } else { self.shift };
+        (self.to_field(window, syn) as u32) << shift
+    }
+}
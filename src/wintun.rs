@@ -0,0 +1,292 @@
+use std::{
+    ffi::{c_void, CStr},
+    io::{self, IoSlice},
+    time::Instant,
+};
+
+/// The driver's library, looked up next to the executable and then on the usual DLL path.
+/// Download it from <https://www.wintun.net>.
+const WINTUN_DLL: &str = "wintun.dll";
+
+/// Tunnel type given to adapters the stack creates, shown in the adapter's properties
+const TUNNEL_TYPE: &str = "tcp_rs";
+
+/// Size of the rings shared with the driver in each direction. A power of two between
+/// 128 KiB and 64 MiB.
+const RING_CAPACITY: u32 = 0x40_0000;
+
+/// `GetLastError` codes the Wintun functions report
+const ERROR_HANDLE_EOF: i32 = 38;
+const ERROR_BUFFER_OVERFLOW: i32 = 111;
+const ERROR_NO_MORE_ITEMS: i32 = 259;
+
+/// `WaitForSingleObject` results and the timeout which never expires
+const WAIT_OBJECT_0: u32 = 0;
+const WAIT_TIMEOUT: u32 = 0x102;
+const INFINITE: u32 = u32::MAX;
+
+type Handle = *mut c_void;
+
+/// Signatures of the functions in `wintun.h` the device uses
+type CreateAdapterFn = unsafe extern "system" fn(*const u16, *const u16, *const c_void) -> Handle;
+type OpenAdapterFn = unsafe extern "system" fn(*const u16) -> Handle;
+type CloseFn = unsafe extern "system" fn(Handle);
+type StartSessionFn = unsafe extern "system" fn(Handle, u32) -> Handle;
+type GetReadWaitEventFn = unsafe extern "system" fn(Handle) -> Handle;
+type ReceivePacketFn = unsafe extern "system" fn(Handle, *mut u32) -> *mut u8;
+type AllocateSendPacketFn = unsafe extern "system" fn(Handle, u32) -> *mut u8;
+type PacketFn = unsafe extern "system" fn(Handle, *const u8);
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn LoadLibraryW(name: *const u16) -> Handle;
+    fn GetProcAddress(module: Handle, name: *const u8) -> *mut c_void;
+    fn FreeLibrary(module: Handle) -> i32;
+    fn WaitForSingleObject(handle: Handle, timeout_ms: u32) -> u32;
+}
+
+/// The functions of `wintun.h` the device uses, looked up in [`WINTUN_DLL`] when it's
+/// opened, so the crate builds without the driver installed
+struct Api {
+    library: Handle,
+    create_adapter: CreateAdapterFn,
+    open_adapter: OpenAdapterFn,
+    close_adapter: CloseFn,
+    start_session: StartSessionFn,
+    end_session: CloseFn,
+    get_read_wait_event: GetReadWaitEventFn,
+    receive_packet: ReceivePacketFn,
+    release_receive_packet: PacketFn,
+    allocate_send_packet: AllocateSendPacketFn,
+    send_packet: PacketFn,
+}
+
+impl Api {
+    fn load() -> io::Result<Self> {
+        let name: Vec<u16> = wide(WINTUN_DLL);
+        // SAFETY: `name` is a nul terminated wide string
+        let library: Handle = unsafe { LoadLibraryW(name.as_ptr()) };
+        if library.is_null() {
+            let err = io::Error::last_os_error();
+            return Err(io::Error::new(
+                err.kind(),
+                format!("couldn't load {WINTUN_DLL}: {err}"),
+            ));
+        }
+
+        /// Look up a function, and give it the type `wintun.h` declares it with
+        macro_rules! function {
+            ($name:literal as $type:ty) => {{
+                let name: &CStr = $name;
+                // SAFETY: `library` was loaded above and `name` is a C string
+                let address: *mut c_void =
+                    unsafe { GetProcAddress(library, name.as_ptr().cast::<u8>()) };
+                if address.is_null() {
+                    let err = io::Error::last_os_error();
+                    // SAFETY: nothing looked up from the library has been called
+                    unsafe { FreeLibrary(library) };
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("{WINTUN_DLL} has no {name:?}: {err}"),
+                    ));
+                }
+                // SAFETY: the function has the signature of the field it's assigned to,
+                // as declared in wintun.h
+                unsafe { std::mem::transmute::<*mut c_void, $type>(address) }
+            }};
+        }
+
+        Ok(Api {
+            library,
+            create_adapter: function!(c"WintunCreateAdapter" as CreateAdapterFn),
+            open_adapter: function!(c"WintunOpenAdapter" as OpenAdapterFn),
+            close_adapter: function!(c"WintunCloseAdapter" as CloseFn),
+            start_session: function!(c"WintunStartSession" as StartSessionFn),
+            end_session: function!(c"WintunEndSession" as CloseFn),
+            get_read_wait_event: function!(c"WintunGetReadWaitEvent" as GetReadWaitEventFn),
+            receive_packet: function!(c"WintunReceivePacket" as ReceivePacketFn),
+            release_receive_packet: function!(c"WintunReleaseReceivePacket" as PacketFn),
+            allocate_send_packet: function!(c"WintunAllocateSendPacket" as AllocateSendPacketFn),
+            send_packet: function!(c"WintunSendPacket" as PacketFn),
+        })
+    }
+}
+
+impl Drop for Api {
+    fn drop(&mut self) {
+        // SAFETY: the library was loaded by `load`, and its functions go with it
+        unsafe { FreeLibrary(self.library) };
+    }
+}
+
+/// `value` as a nul terminated UTF-16 string
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain([0]).collect()
+}
+
+/// A Wintun adapter moving IP packets in and out of the stack, the Windows counterpart of
+/// a Linux tun device. Packets are exchanged through rings shared with the driver, see
+/// <https://www.wintun.net>. There are no file descriptors to poll, so the daemon
+/// sleeps in [`Wintun::wait_readable`] instead.
+pub struct Wintun {
+    api: Api,
+    adapter: Handle,
+    session: Handle,
+    read_event: Handle,
+    name: String,
+}
+
+// SAFETY: Wintun's session functions are safe to call from any thread, and the handles are
+// only closed on drop
+unsafe impl Send for Wintun {}
+unsafe impl Sync for Wintun {}
+
+impl Wintun {
+    /// Open the adapter called `name`, creating it if there isn't one. Needs administrator
+    /// rights, and `wintun.dll` next to the executable or on the DLL search path.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let api = Api::load()?;
+        let wide_name: Vec<u16> = wide(name);
+        let tunnel_type: Vec<u16> = wide(TUNNEL_TYPE);
+
+        // SAFETY: both names are nul terminated wide strings, and a null GUID lets the
+        // driver pick one
+        let adapter: Handle = unsafe {
+            let adapter: Handle = (api.open_adapter)(wide_name.as_ptr());
+            if adapter.is_null() {
+                (api.create_adapter)(wide_name.as_ptr(), tunnel_type.as_ptr(), std::ptr::null())
+            } else {
+                adapter
+            }
+        };
+        if adapter.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: `adapter` was opened above
+        let session: Handle = unsafe { (api.start_session)(adapter, RING_CAPACITY) };
+        if session.is_null() {
+            let err = io::Error::last_os_error();
+            // SAFETY: `adapter` was opened above and isn't used again
+            unsafe { (api.close_adapter)(adapter) };
+            return Err(err);
+        }
+
+        // SAFETY: `session` was started above. The event belongs to the session.
+        let read_event: Handle = unsafe { (api.get_read_wait_event)(session) };
+
+        Ok(Wintun {
+            api,
+            adapter,
+            session,
+            read_event,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receiving never blocks, so there's nothing to change. Here to match the other
+    /// devices.
+    pub fn set_non_blocking(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Block until a packet can be received or `deadline` has passed, returning whether
+    /// there's a packet. Receiving may still find nothing, as the wakeup can be spurious.
+    pub fn wait_readable(&self, deadline: Option<Instant>) -> io::Result<bool> {
+        let timeout_ms: u32 = match deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis()
+                .saturating_add(1)
+                .try_into()
+                .unwrap_or(INFINITE - 1),
+            None => INFINITE,
+        };
+
+        // SAFETY: `read_event` is valid until the session ends on drop
+        match unsafe { WaitForSingleObject(self.read_event, timeout_ms) } {
+            WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl crate::device::NetworkDevice for Wintun {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut packet_len: u32 = 0;
+        // SAFETY: `session` is valid until drop
+        let packet: *mut u8 = unsafe { (self.api.receive_packet)(self.session, &mut packet_len) };
+        if packet.is_null() {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(ERROR_NO_MORE_ITEMS) => Err(io::ErrorKind::WouldBlock.into()),
+                Some(ERROR_HANDLE_EOF) => Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    format!("{} is shutting down", self.name),
+                )),
+                _ => Err(err),
+            };
+        }
+
+        // A packet larger than `buf` fills it, which is how receive buffers spot one cut
+        // short
+        let n_bytes: usize = (packet_len as usize).min(buf.len());
+        // SAFETY: the driver hands over `packet_len` readable bytes, which stay valid until
+        // the packet is released
+        unsafe {
+            std::ptr::copy_nonoverlapping(packet, buf.as_mut_ptr(), n_bytes);
+            (self.api.release_receive_packet)(self.session, packet);
+        }
+
+        Ok(n_bytes)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    /// The pieces are copied straight into the send ring, so they're never gathered into
+    /// a buffer of their own first
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let packet_len: u32 = len.try_into().map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: `session` is valid until drop
+        let packet: *mut u8 = unsafe { (self.api.allocate_send_packet)(self.session, packet_len) };
+        if packet.is_null() {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // The ring is full, as a tun device's queue would be
+                Some(ERROR_BUFFER_OVERFLOW) => Err(io::ErrorKind::WouldBlock.into()),
+                _ => Err(err),
+            };
+        }
+
+        let mut offset: usize = 0;
+        for buf in bufs {
+            // SAFETY: the driver allocated `len` writable bytes, which the pieces add up to
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), packet.add(offset), buf.len()) };
+            offset += buf.len();
+        }
+        // SAFETY: `packet` was allocated above and is handed back to the driver exactly once
+        unsafe { (self.api.send_packet)(self.session, packet) };
+
+        Ok(len)
+    }
+}
+
+impl Drop for Wintun {
+    fn drop(&mut self) {
+        // SAFETY: the session and adapter were opened by `open` and aren't used again.
+        // Ending the session closes its read event. The library is freed after this.
+        unsafe {
+            (self.api.end_session)(self.session);
+            (self.api.close_adapter)(self.adapter);
+        }
+    }
+}
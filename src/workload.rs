@@ -0,0 +1,397 @@
+use std::{
+    fmt, fs,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::Clock,
+    error::{Result, TcpError},
+    impair::{Impairment, ImpairmentStats},
+    listener::ListenerLimits,
+    tcp::State,
+    testing::{Connection, Side, Wan},
+};
+
+/// Port the server listens on for every flow
+const SERVER_PORT: u16 = 80;
+/// Client port of the first flow, each flow after it gets the next one
+const FIRST_CLIENT_PORT: u16 = 10000;
+/// Most bytes handed to a connection at once
+const SEND_CHUNK: [u8; 16 * 1024] = [0x5a; 16 * 1024];
+
+/// Which way a flow's data goes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to server, as in an upload
+    Up,
+    /// Server to client, as in a download
+    Down,
+}
+
+impl Direction {
+    fn sender(self) -> Side {
+        match self {
+            Direction::Up => Side::Client,
+            Direction::Down => Side::Server,
+        }
+    }
+
+    fn receiver(self) -> Side {
+        match self {
+            Direction::Up => Side::Server,
+            Direction::Down => Side::Client,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// One connection in a workload, opened `start` into the run to move `bytes` one way
+/// and then closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flow {
+    pub start: Duration,
+    pub direction: Direction,
+    pub bytes: usize,
+}
+
+/// A mix of flows and the link they run over, read from a flow description file.
+///
+/// Each line is either a flow, `<start ms> <up|down> <bytes> [count]`, or the link,
+/// `link [loss=<p>] [duplicate=<p>] [reorder=<p>] [delay=<ms>] [jitter=<ms>] [seed=<n>]`.
+/// Sizes can end in `k` or `M` for KiB and MiB, and `count` opens that many identical
+/// flows at once. Everything after a `#` is a comment.
+/// ```text
+/// link delay=20 jitter=5 loss=0.01
+/// # A page load, then a download alongside a stream of small requests
+/// 0    up   400
+/// 0    down 2M
+/// 100  up   200  50
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Workload {
+    pub impairment: Impairment,
+    pub seed: u64,
+    pub flows: Vec<Flow>,
+}
+
+impl Workload {
+    pub fn parse(description: &str) -> Result<Self> {
+        let mut workload = Workload::default();
+
+        for (i, line) in description.lines().enumerate() {
+            let line: &str = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            let parsed: Result<()> = match fields.as_slice() {
+                [] => Ok(()),
+                ["link", settings @ ..] => workload.parse_link(settings),
+                fields => workload.parse_flows(fields),
+            };
+            parsed.map_err(|err| {
+                TcpError::Parse(format!("line {}: {}: {err}", i + 1, line.trim()))
+            })?;
+        }
+
+        Ok(workload)
+    }
+
+    fn parse_link(&mut self, settings: &[&str]) -> Result<()> {
+        for setting in settings {
+            let Some((key, value)) = setting.split_once('=') else {
+                return Err(TcpError::Parse(format!(
+                    "expected key=value, got {setting}"
+                )));
+            };
+
+            match key {
+                "loss" => self.impairment.loss = parse_probability(value)?,
+                "duplicate" => self.impairment.duplicate = parse_probability(value)?,
+                "reorder" => self.impairment.reorder = parse_probability(value)?,
+                "delay" => self.impairment.delay = Duration::from_millis(value.parse()?),
+                "jitter" => self.impairment.jitter = Duration::from_millis(value.parse()?),
+                "seed" => self.seed = value.parse()?,
+                _ => return Err(TcpError::Parse(format!("unknown link setting {key}"))),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_flows(&mut self, fields: &[&str]) -> Result<()> {
+        let (start, direction, bytes, count) = match fields {
+            [start, direction, bytes] => (start, direction, bytes, "1"),
+            [start, direction, bytes, count] => (start, direction, bytes, *count),
+            _ => {
+                return Err(TcpError::Parse(
+                    "expected <start ms> <up|down> <bytes> [count]".into(),
+                ))
+            }
+        };
+
+        let flow = Flow {
+            start: Duration::from_millis(start.parse()?),
+            direction: match *direction {
+                "up" => Direction::Up,
+                "down" => Direction::Down,
+                _ => {
+                    return Err(TcpError::Parse(format!(
+                        "direction must be up or down, got {direction}"
+                    )))
+                }
+            },
+            bytes: parse_size(bytes)?,
+        };
+        let count: usize = count.parse()?;
+
+        self.flows.extend(std::iter::repeat_n(flow, count));
+        Ok(())
+    }
+}
+
+fn parse_probability(value: &str) -> Result<f64> {
+    let probability: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&probability) {
+        return Err(TcpError::Parse(format!(
+            "probability must be from 0 to 1, got {value}"
+        )));
+    }
+    Ok(probability)
+}
+
+/// A number of bytes, optionally in KiB or MiB
+fn parse_size(value: &str) -> Result<usize> {
+    let (digits, multiplier): (&str, usize) = match value.strip_suffix('k') {
+        Some(digits) => (digits, 1024),
+        None => match value.strip_suffix('M') {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (value, 1),
+        },
+    };
+
+    let n: usize = digits.parse()?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| TcpError::Parse(format!("{value} is too large")))
+}
+
+/// How one flow of a workload went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowResult {
+    pub flow: Flow,
+    /// From the flow starting until the receiver had read everything
+    pub completion_time: Duration,
+}
+
+/// Result of running a [`Workload`], with flows in the order they were described
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkloadReport {
+    pub flows: Vec<FlowResult>,
+    /// From the start of the run until the last flow completed
+    pub duration: Duration,
+    /// What the link did to packets going each way
+    pub upstream: ImpairmentStats,
+    pub downstream: ImpairmentStats,
+}
+
+impl WorkloadReport {
+    /// The completion time which `percent` of flows finished within, by nearest rank
+    pub fn completion_percentile(&self, percent: usize) -> Duration {
+        let mut times: Vec<Duration> = self
+            .flows
+            .iter()
+            .map(|result| result.completion_time)
+            .collect();
+        times.sort();
+
+        let rank: usize = (times.len() * percent).div_ceil(100);
+        times
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for WorkloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, result) in self.flows.iter().enumerate() {
+            writeln!(
+                f,
+                "flow {i} {} bytes={} start={} completion={}",
+                result.flow.direction,
+                result.flow.bytes,
+                millis(result.flow.start),
+                millis(result.completion_time)
+            )?;
+        }
+
+        write!(
+            f,
+            "flows={} bytes={} duration={} completion_p50={} completion_p99={} completion_max={} lost={} duplicated={} reordered={}",
+            self.flows.len(),
+            self.flows.iter().map(|result| result.flow.bytes).sum::<usize>(),
+            millis(self.duration),
+            millis(self.completion_percentile(50)),
+            millis(self.completion_percentile(99)),
+            millis(self.completion_percentile(100)),
+            self.upstream.lost + self.downstream.lost,
+            self.upstream.duplicated + self.downstream.duplicated,
+            self.upstream.reordered + self.downstream.reordered
+        )
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// A flow which has been opened
+struct RunningFlow {
+    index: usize,
+    connection: Connection,
+    n_sent: usize,
+    n_received: usize,
+}
+
+/// Run every flow of `workload` between two stacks over an emulated link, on simulated
+/// time, so the same description and seed always play out the same way.
+///
+/// Each flow is a new connection from the client to the server. Once the receiver has
+/// read everything both ends close, without waiting for the close to finish.
+pub fn run(workload: &Workload) -> Result<WorkloadReport> {
+    if workload.flows.len() > usize::from(u16::MAX - FIRST_CLIENT_PORT) {
+        return Err(TcpError::Failed(format!(
+            "at most {} flows",
+            u16::MAX - FIRST_CLIENT_PORT
+        )));
+    }
+
+    let mut wan = Wan::new(workload.impairment, workload.seed);
+    wan.listen(SERVER_PORT, ListenerLimits::default());
+    let start: Instant = wan.clock.now();
+
+    let mut by_start: Vec<usize> = (0..workload.flows.len()).collect();
+    by_start.sort_by_key(|&i| workload.flows[i].start);
+    let mut waiting = by_start.into_iter().peekable();
+
+    let mut running: Vec<RunningFlow> = Vec::new();
+    let mut completion_times: Vec<Option<Duration>> = vec![None; workload.flows.len()];
+
+    loop {
+        let elapsed: Duration = wan.clock.now() - start;
+        while let Some(index) = waiting.next_if(|&i| workload.flows[i].start <= elapsed) {
+            let client_port: u16 = FIRST_CLIENT_PORT + index as u16;
+            running.push(RunningFlow {
+                index,
+                connection: wan.open(client_port, SERVER_PORT)?,
+                n_sent: 0,
+                n_received: 0,
+            });
+        }
+
+        for flow in running.iter_mut() {
+            if drive(&mut wan, &workload.flows[flow.index], flow)? {
+                completion_times[flow.index] = Some(elapsed - workload.flows[flow.index].start);
+                wan.close(flow.connection, Side::Client)?;
+                wan.close(flow.connection, Side::Server)?;
+            }
+        }
+        running.retain(|flow| completion_times[flow.index].is_none());
+
+        if wan.run_until_idle()? > 0 {
+            continue;
+        }
+        if running.is_empty() && waiting.peek().is_none() {
+            break;
+        }
+
+        let next_start: Option<Instant> = waiting.peek().map(|&i| start + workload.flows[i].start);
+        let next: Option<Instant> = [
+            next_start,
+            wan.client.next_deadline(),
+            wan.server.next_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let Some(next) = next else {
+            return Err(TcpError::Failed(format!(
+                "{} flows can never complete",
+                running.len()
+            )));
+        };
+        wan.advance_to(next)?;
+    }
+
+    let flows: Vec<FlowResult> = workload
+        .flows
+        .iter()
+        .zip(completion_times)
+        .map(|(flow, completion_time)| FlowResult {
+            flow: *flow,
+            completion_time: completion_time.unwrap_or_default(),
+        })
+        .collect();
+    let duration: Duration = flows
+        .iter()
+        .map(|result| result.flow.start + result.completion_time)
+        .max()
+        .unwrap_or_default();
+
+    Ok(WorkloadReport {
+        flows,
+        duration,
+        upstream: wan.client.device.stats(),
+        downstream: wan.server.device.stats(),
+    })
+}
+
+/// Hand the sender as much of the flow as it will take and read whatever has arrived,
+/// returning whether the flow has completed
+fn drive(wan: &mut Wan, flow: &Flow, running: &mut RunningFlow) -> Result<bool> {
+    let connection: Connection = running.connection;
+    if wan.client.state(&connection.client).is_none() {
+        return Err(TcpError::Failed(format!(
+            "flow {} was reset before completing",
+            running.index
+        )));
+    }
+
+    let sender = wan.host_mut(flow.direction.sender());
+    if let Some(tcb) = sender
+        .stack
+        .connection_mut(&connection.info(flow.direction.sender()))
+    {
+        while running.n_sent < flow.bytes {
+            let len: usize = (flow.bytes - running.n_sent).min(SEND_CHUNK.len());
+            let n_taken: usize = tcb.send(&sender.device, &SEND_CHUNK[..len])?;
+            if n_taken == 0 {
+                break;
+            }
+            running.n_sent += n_taken;
+        }
+    }
+
+    let receiver: Side = flow.direction.receiver();
+    running.n_received += wan
+        .host_mut(receiver)
+        .read_all(&connection.info(receiver))
+        .len();
+
+    Ok(running.n_received >= flow.bytes
+        && wan.client.state(&connection.client) == Some(State::Estab)
+        && wan.server.state(&connection.server) == Some(State::Estab))
+}
+
+/// Run the flow description at `path` and print how each flow went
+pub fn run_and_report(path: &str) -> Result<()> {
+    let workload = Workload::parse(&fs::read_to_string(path)?)?;
+    println!("{}", run(&workload)?);
+    Ok(())
+}
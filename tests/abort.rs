@@ -0,0 +1,86 @@
+//! Aborting a connection from our side. RFC 9293 Section 3.10.5
+
+use tcp_rs::{
+    error::TcpError,
+    tcp::State,
+    testing::{Side, Wan},
+};
+
+#[test]
+fn abort_resets_the_peer_and_deletes_the_connection() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    // Data from the server which is never read, and from the client which is never
+    // acknowledged
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"unread").unwrap();
+    wan.run_until_idle().unwrap();
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, b"unsent").unwrap();
+
+    wan.client
+        .stack
+        .abort(&wan.client.device, &connection.client)
+        .unwrap();
+
+    assert!(wan.client.stack.connection(&connection.client).is_none());
+    assert!(!wan
+        .client
+        .stack
+        .ports()
+        .is_connected(connection.client.dst_port));
+    assert_eq!(wan.client.stack.stats().connections_closed, 1);
+
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.server.state(&connection.server), Some(State::Closed));
+
+    // The server's calls report the reset, the client's find nothing there
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    assert!(matches!(
+        server.send(&wan.server.device, b"late"),
+        Err(TcpError::ConnectionReset)
+    ));
+    assert!(matches!(
+        wan.client
+            .stack
+            .abort(&wan.client.device, &connection.client),
+        Err(TcpError::NotConnected)
+    ));
+}
+
+#[test]
+fn abort_before_the_handshake_sends_nothing() {
+    let mut wan = Wan::lossless();
+    let connection = wan.open(0, 443).unwrap();
+    assert_eq!(wan.server.device.inner().take_pending().len(), 1);
+
+    wan.client
+        .stack
+        .abort(&wan.client.device, &connection.client)
+        .unwrap();
+
+    assert!(wan.server.device.inner().take_pending().is_empty());
+    assert!(wan.client.stack.connection(&connection.client).is_none());
+}
+
+#[test]
+fn abort_after_both_sides_close_sends_nothing() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    wan.close(connection, Side::Client).unwrap();
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::TimeWait));
+
+    wan.client
+        .stack
+        .abort(&wan.client.device, &connection.client)
+        .unwrap();
+
+    assert!(wan.server.device.inner().take_pending().is_empty());
+    assert!(wan.client.stack.connection(&connection.client).is_none());
+    assert!(wan
+        .client
+        .stack
+        .abort(&wan.client.device, &connection.client)
+        .is_err());
+}
@@ -0,0 +1,518 @@
+//! The runtime-agnostic async front end, driven over a socket pair standing in for the
+//! tun device. Futures are run on the test thread with `async_stack::block_on`.
+#![cfg(unix)]
+
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    pin::pin,
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use tcp_rs::{
+    async_stack::{block_on, AsyncStack, AsyncTcpListener, AsyncTcpStream},
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::{SocketOption, Tcb, SEND_BUFFER_SIZE},
+};
+
+const PORT: u16 = 443;
+
+/// Where the stack connects from
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), PORT);
+
+/// Where the peer accepts connections, and connects from
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+
+/// How long the operations which are expected to time out wait
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+/// One end of a datagram socket pair, each datagram being one IP packet
+struct SocketDevice(UnixDatagram);
+
+impl NetworkDevice for SocketDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+}
+
+impl AsRawFd for SocketDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Poll `future` once, then drop it
+fn poll_once<T>(future: impl Future<Output = T>) -> Poll<T> {
+    let mut cx = Context::from_waker(Waker::noop());
+    pin!(future).poll(&mut cx)
+}
+
+/// The peer on the other end of the device, at 192.168.0.1:40000
+struct Peer {
+    socket: UnixDatagram,
+    seq: u32,
+    ack: u32,
+}
+
+impl Peer {
+    fn send(&mut self, modify: impl FnOnce(&mut TcpHeader), payload: &[u8]) {
+        let mut tcp_header = TcpHeader::new(40000, PORT, self.seq, 8192);
+        tcp_header.ack = self.ack != 0;
+        tcp_header.acknowledgment_number = self.ack;
+        modify(&mut tcp_header);
+
+        let ip_header = Ipv4Header::new(
+            (tcp_header.header_len() + payload.len()) as u16,
+            64,
+            IpNumber::TCP,
+            [192, 168, 0, 1],
+            [192, 168, 0, 2],
+        )
+        .unwrap();
+        tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, payload).unwrap();
+
+        let mut packet: Vec<u8> = Vec::new();
+        ip_header.write(&mut packet).unwrap();
+        tcp_header.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        self.socket.send(&packet).unwrap();
+
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+        if tcp_header.syn || tcp_header.fin {
+            self.seq = self.seq.wrapping_add(1);
+        }
+    }
+
+    /// Receive segments until one matches `wanted`, returning its header and payload
+    fn recv_until(
+        &mut self,
+        wanted: impl Fn(&TcpHeaderSlice, &[u8]) -> bool,
+    ) -> (TcpHeader, Vec<u8>) {
+        let mut buf: [u8; 2048] = [0; 2048];
+
+        loop {
+            let n_bytes: usize = self.socket.recv(&mut buf).unwrap();
+            let ip_header = Ipv4HeaderSlice::from_slice(&buf[..n_bytes]).unwrap();
+            let tcp_header =
+                TcpHeaderSlice::from_slice(&buf[ip_header.slice().len()..n_bytes]).unwrap();
+            let payload: &[u8] = &buf[ip_header.slice().len() + tcp_header.slice().len()..n_bytes];
+
+            if wanted(&tcp_header, payload) {
+                return (tcp_header.to_header(), payload.to_vec());
+            }
+        }
+    }
+
+    /// Open a connection, returning once the handshake-completing ACK has been sent
+    fn connect(&mut self, before_ack: impl FnOnce()) {
+        self.send(|header| header.syn = true, &[]);
+
+        let (syn_ack, _) = self.recv_until(|header, _| header.syn() && header.ack());
+        self.ack = syn_ack.sequence_number.wrapping_add(1);
+
+        before_ack();
+        self.send(|_| {}, &[]);
+    }
+
+    /// Answer the stack's SYN with a SYN,ACK offering a window of `window` bytes
+    fn accept(&mut self, window: u16) {
+        let (syn, _) = self.recv_until(|header, _| header.syn());
+        self.ack = syn.sequence_number.wrapping_add(1);
+
+        self.send(
+            |header| {
+                header.syn = true;
+                header.window_size = window;
+            },
+            &[],
+        );
+    }
+}
+
+fn setup() -> (AsyncStack<SocketDevice>, Peer) {
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    ours.set_nonblocking(true).unwrap();
+    theirs
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        Instant::now(),
+    );
+    let stack = AsyncStack::spawn(SocketDevice(ours), stack).unwrap();
+
+    let peer = Peer {
+        socket: theirs,
+        seq: 100,
+        ack: 0,
+    };
+
+    (stack, peer)
+}
+
+/// Connect to the peer, which accepts with a window of `window` bytes
+fn connect(
+    stack: &AsyncStack<SocketDevice>,
+    peer: &mut Peer,
+    window: u16,
+) -> AsyncTcpStream<SocketDevice> {
+    let mut connecting = pin!(stack.connect(LOCAL, REMOTE));
+    assert!(poll_once(connecting.as_mut()).is_pending());
+
+    peer.accept(window);
+    block_on(connecting).unwrap()
+}
+
+#[test]
+fn accepted_stream_reads_writes_and_shuts_down() {
+    let (stack, mut peer) = setup();
+    let listener: AsyncTcpListener<SocketDevice> = stack.listen(PORT, ListenerLimits::default());
+
+    peer.connect(|| {});
+    let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+
+    peer.send(|header| header.psh = true, b"hello");
+    let mut buf: [u8; 16] = [0; 16];
+    let n_read: usize = block_on(stream.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n_read], b"hello");
+
+    block_on(stream.write_all(b"world")).unwrap();
+    let (_, payload) = peer.recv_until(|_, payload| !payload.is_empty());
+    assert_eq!(payload, b"world");
+
+    block_on(stream.shutdown()).unwrap();
+    peer.recv_until(|header, _| header.fin());
+}
+
+/// An accept dropped before the handshake completes doesn't lose the connection
+#[test]
+fn accept_cancelled_mid_handshake() {
+    let (stack, mut peer) = setup();
+    let listener: AsyncTcpListener<SocketDevice> = stack.listen(PORT, ListenerLimits::default());
+
+    peer.connect(|| assert!(poll_once(listener.accept()).is_pending()));
+
+    let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+    assert_eq!(stream.info().src_port, REMOTE.port());
+}
+
+#[test]
+fn connected_stream_reads_writes_and_shuts_down() {
+    let (stack, mut peer) = setup();
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 8192);
+
+    peer.send(|header| header.psh = true, b"hello");
+    let mut buf: [u8; 16] = [0; 16];
+    let n_read: usize = block_on(stream.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n_read], b"hello");
+
+    block_on(stream.write_all(b"world")).unwrap();
+    let (_, payload) = peer.recv_until(|_, payload| !payload.is_empty());
+    assert_eq!(payload, b"world");
+
+    block_on(stream.shutdown()).unwrap();
+    peer.recv_until(|header, _| header.fin());
+}
+
+/// A connect dropped before the handshake completes deletes its connection, so the late
+/// SYN,ACK is refused and the same 4-tuple can be connected again
+#[test]
+fn connect_cancelled_mid_handshake() {
+    let (stack, mut peer) = setup();
+
+    assert!(poll_once(stack.connect(LOCAL, REMOTE)).is_pending());
+    peer.accept(8192);
+    peer.recv_until(|header, _| header.rst());
+
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 8192);
+    assert_eq!(stream.info().src_port, REMOTE.port());
+}
+
+/// A read dropped while waiting doesn't lose the data which arrives after it
+#[test]
+fn read_cancelled_mid_read() {
+    let (stack, mut peer) = setup();
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 8192);
+
+    let mut buf: [u8; 16] = [0; 16];
+    assert!(poll_once(stream.read(&mut buf)).is_pending());
+
+    peer.send(|header| header.psh = true, b"hello");
+    let n_read: usize = block_on(stream.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n_read], b"hello");
+}
+
+/// Once the peer closes, reads return the end of the stream
+#[test]
+fn read_returns_zero_after_peer_closes() {
+    let (stack, mut peer) = setup();
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 8192);
+
+    peer.send(|header| header.fin = true, b"bye");
+
+    let mut received: Vec<u8> = Vec::new();
+    let mut buf: [u8; 16] = [0; 16];
+    loop {
+        let n_read: usize = block_on(stream.read(&mut buf)).unwrap();
+        if n_read == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..n_read]);
+    }
+    assert_eq!(received, b"bye");
+}
+
+#[test]
+fn connect_times_out_without_an_answer() {
+    let (stack, mut peer) = setup();
+
+    let start = Instant::now();
+    let result = block_on(stack.connect_timeout(LOCAL, REMOTE, TIMEOUT));
+    assert_eq!(
+        result.err().map(|err| err.kind()),
+        Some(io::ErrorKind::TimedOut)
+    );
+    assert!(start.elapsed() >= TIMEOUT);
+
+    // The connection was deleted, so a late SYN,ACK is refused with a reset
+    peer.accept(8192);
+    peer.recv_until(|header, _| header.rst());
+}
+
+/// A read which times out takes nothing, so the data which arrives later is all there
+#[test]
+fn read_times_out_without_losing_later_data() {
+    let (stack, mut peer) = setup();
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 8192);
+
+    let mut buf: [u8; 16] = [0; 16];
+    let start = Instant::now();
+    let err = block_on(stream.read_timeout(&mut buf, TIMEOUT)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= TIMEOUT);
+
+    peer.send(|header| header.psh = true, b"hello");
+    let n_read: usize = block_on(stream.read_timeout(&mut buf, Duration::from_secs(5))).unwrap();
+    assert_eq!(&buf[..n_read], b"hello");
+}
+
+/// A write which times out on a full send buffer queues none of its data, so once the
+/// peer has taken everything before it the next write follows straight on
+#[test]
+fn write_times_out_on_a_full_send_buffer() {
+    let (stack, mut peer) = setup();
+    let stream: AsyncTcpStream<SocketDevice> = connect(&stack, &mut peer, 0);
+
+    let full: Vec<u8> = vec![b'a'; SEND_BUFFER_SIZE];
+    assert_eq!(block_on(stream.write(&full)).unwrap(), SEND_BUFFER_SIZE);
+
+    let err = block_on(stream.write_timeout(b"more", TIMEOUT)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    // Open the window a little at a time, so the socket pair's queue never fills
+    let (ack, _) = peer.recv_until(|header, _| header.ack());
+    let start: u32 = ack.sequence_number;
+    let mut received: u32 = 0;
+    while received < SEND_BUFFER_SIZE as u32 {
+        peer.send(|header| header.window_size = 2048, &[]);
+        let (segment, payload) = peer.recv_until(|header, payload| {
+            header.sequence_number() == start.wrapping_add(received) && !payload.is_empty()
+        });
+        assert!(payload.iter().all(|&byte| byte == b'a'));
+        received += payload.len() as u32;
+        peer.ack = segment.sequence_number.wrapping_add(payload.len() as u32);
+    }
+    peer.send(|header| header.window_size = 2048, &[]);
+
+    block_on(stream.write(b"next")).unwrap();
+    let (segment, payload) = peer.recv_until(|_, payload| !payload.is_empty());
+    assert_eq!(segment.sequence_number, start.wrapping_add(received));
+    assert!(b"next".starts_with(&payload));
+}
+
+/// Writes take no more than the send buffer holds until the peer acknowledges some of it
+#[test]
+fn full_send_buffer_pushes_back_on_writes() {
+    let (stack, mut peer) = setup();
+    let listener: AsyncTcpListener<SocketDevice> = stack.listen(PORT, ListenerLimits::default());
+
+    peer.connect(|| {});
+    let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+
+    let data: Vec<u8> = vec![1; 2 * SEND_BUFFER_SIZE];
+    let mut n_written: usize = 0;
+    let err = loop {
+        match stream.try_write(&data[n_written..]) {
+            Ok(n_taken) => n_written += n_taken,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(n_written, SEND_BUFFER_SIZE);
+    assert!(poll_once(stream.write(b"more")).is_pending());
+
+    // Acknowledging the first flight makes room for that much more. The congestion window
+    // starts smaller than the peer's window, so only that much is sent straight away.
+    let flight: u32 = stream
+        .option(|tcb| tcb.congestion().cwnd())
+        .unwrap()
+        .min(8192);
+    let start: u32 = peer.ack;
+    let mut end: u32 = start;
+    while end.wrapping_sub(start) < flight {
+        let (header, payload) = peer.recv_until(|_, payload| !payload.is_empty());
+        let segment_end: u32 = header.sequence_number.wrapping_add(payload.len() as u32);
+        if segment_end.wrapping_sub(end) as i32 > 0 {
+            end = segment_end;
+        }
+    }
+    peer.ack = end;
+    peer.send(|_| {}, &[]);
+
+    assert_eq!(block_on(stream.write(&data)).unwrap(), flight as usize);
+}
+
+#[test]
+fn abort_resets_the_connection() {
+    let (stack, mut peer) = setup();
+    let listener: AsyncTcpListener<SocketDevice> = stack.listen(PORT, ListenerLimits::default());
+
+    peer.connect(|| {});
+    let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+    peer.send(|header| header.psh = true, b"unread");
+
+    stream.abort().unwrap();
+    let (rst, _) = peer.recv_until(|header, _| header.rst());
+    assert_eq!(rst.sequence_number, peer.ack);
+
+    assert_eq!(
+        stream.abort().unwrap_err().kind(),
+        io::ErrorKind::NotConnected
+    );
+    assert_eq!(
+        block_on(stream.write(b"late")).unwrap_err().kind(),
+        io::ErrorKind::NotConnected
+    );
+}
+
+#[test]
+fn options_are_changed_on_the_live_connection() {
+    let (stack, mut peer) = setup();
+    let listener: AsyncTcpListener<SocketDevice> = stack.listen(PORT, ListenerLimits::default());
+
+    peer.connect(|| {});
+    let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+    assert!(stream.option(Tcb::nodelay).unwrap());
+
+    stream.set_option(SocketOption::NoDelay(false)).unwrap();
+    stream.set_option(SocketOption::SendBufferSize(4)).unwrap();
+    assert!(!stream.option(Tcb::nodelay).unwrap());
+    assert_eq!(stream.try_write(b"hello").unwrap(), 4);
+
+    let err = stream
+        .set_option(SocketOption::SendBufferSize(0))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+/// Open a connection from the stack to the peer, which answers the SYN with `answer`
+fn connect_to_peer(
+    stack: &AsyncStack<SocketDevice>,
+    mut peer: Peer,
+    answer: fn(&mut TcpHeader),
+) -> io::Result<AsyncTcpStream<SocketDevice>> {
+    let peer = thread::spawn(move || {
+        let (syn, _) = peer.recv_until(|header, _| header.syn());
+        peer.ack = syn.sequence_number.wrapping_add(1);
+        peer.send(
+            |header| {
+                header.destination_port = syn.source_port;
+                answer(header);
+            },
+            &[],
+        );
+    });
+
+    let stream = block_on(stack.connect(SocketAddrV4::new(*LOCAL.ip(), 0), REMOTE));
+    peer.join().unwrap();
+    stream
+}
+
+#[test]
+fn connect_waits_for_the_handshake() {
+    let (stack, peer) = setup();
+
+    let stream = connect_to_peer(&stack, peer, |header| header.syn = true).unwrap();
+
+    assert_eq!(stream.info().src_port, REMOTE.port());
+    assert!(stream.option(|tcb| tcb.state().is_synchronised()).unwrap());
+}
+
+#[test]
+fn connect_is_refused_by_a_reset() {
+    let (stack, peer) = setup();
+
+    let result = connect_to_peer(&stack, peer, |header| header.rst = true);
+
+    assert_eq!(
+        result.err().map(|err| err.kind()),
+        Some(io::ErrorKind::ConnectionRefused)
+    );
+}
+
+/// A reset after the handshake is for the stream, even if the peer sends it before the
+/// connect future sees the connection established
+#[test]
+fn reset_after_the_handshake_is_not_a_refusal() {
+    let (stack, mut peer) = setup();
+
+    let mut connecting = pin!(stack.connect(SocketAddrV4::new(*LOCAL.ip(), 0), REMOTE));
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(connecting.as_mut().poll(&mut cx).is_pending());
+
+    let (syn, _) = peer.recv_until(|header, _| header.syn());
+    peer.ack = syn.sequence_number.wrapping_add(1);
+    peer.send(
+        |header| {
+            header.destination_port = syn.source_port;
+            header.syn = true;
+        },
+        &[],
+    );
+    peer.recv_until(|header, _| header.ack() && !header.syn());
+    peer.send(
+        |header| {
+            header.destination_port = syn.source_port;
+            header.rst = true;
+        },
+        &[],
+    );
+
+    let stream: AsyncTcpStream<SocketDevice> = block_on(connecting).unwrap();
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(
+        block_on(stream.read(&mut buf)).unwrap_err().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+    assert_eq!(
+        block_on(stream.write(b"late")).unwrap_err().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+}
@@ -0,0 +1,165 @@
+//! Two instances of the stack connected back-to-back, each delivering what it sends
+//! straight into the other.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    challenge::ChallengeAckLimiter,
+    clock,
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    tcp::{State, Tcb},
+};
+
+const A: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const B: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+/// One end of the link
+struct Endpoint {
+    device: CaptureDevice,
+    tcb: Option<Tcb>,
+    challenge_acks: ChallengeAckLimiter,
+    isn: IsnGenerator,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            device: CaptureDevice::default(),
+            tcb: None,
+            challenge_acks: ChallengeAckLimiter::default(),
+            isn: IsnGenerator::from_os_random().unwrap(),
+        }
+    }
+}
+
+impl Endpoint {
+    fn state(&self) -> Option<State> {
+        self.tcb.as_ref().map(Tcb::state)
+    }
+
+    /// Process a packet from the other end, opening a connection passively if there isn't one
+    fn receive(&mut self, packet: &[u8]) {
+        let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+        let data: &[u8] = &packet[ip_header.slice().len() + tcp_header.slice().len()..];
+
+        match &mut self.tcb {
+            Some(tcb) => tcb
+                .on_packet(
+                    &self.device,
+                    ip_header,
+                    tcp_header,
+                    data,
+                    &mut self.challenge_acks,
+                )
+                .unwrap(),
+            None => {
+                self.tcb = Tcb::accept_connection(
+                    &self.device,
+                    ip_header,
+                    tcp_header,
+                    data,
+                    &self.isn,
+                    &clock::system(),
+                )
+                .unwrap()
+            }
+        }
+    }
+}
+
+/// Deliver everything `from` has sent so far to `to`, returning how many packets were delivered
+fn deliver(from: &Endpoint, to: &mut Endpoint) -> usize {
+    let packets: Vec<Vec<u8>> = from.device.take_sent();
+    for packet in &packets {
+        to.receive(packet);
+    }
+    packets.len()
+}
+
+fn flags(packet: &[u8]) -> (bool, bool) {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+    (tcp_header.syn(), tcp_header.ack())
+}
+
+#[test]
+fn active_open_to_passive_open() {
+    let mut a = Endpoint::default();
+    let mut b = Endpoint::default();
+
+    a.tcb = Some(Tcb::connect(&a.device, A, B, &a.isn, &clock::system()).unwrap());
+    assert_eq!(a.state(), Some(State::SynSent));
+
+    assert_eq!(deliver(&a, &mut b), 1);
+    assert_eq!(b.state(), Some(State::SynRcvd));
+
+    assert_eq!(deliver(&b, &mut a), 1);
+    assert_eq!(a.state(), Some(State::Estab));
+
+    assert_eq!(deliver(&a, &mut b), 1);
+    assert_eq!(b.state(), Some(State::Estab));
+
+    assert_eq!(deliver(&b, &mut a), 0);
+}
+
+/// RFC 9293 Section 3.5 Figure 7
+#[test]
+fn simultaneous_open() {
+    let mut a = Endpoint::default();
+    let mut b = Endpoint::default();
+
+    a.tcb = Some(Tcb::connect(&a.device, A, B, &a.isn, &clock::system()).unwrap());
+    b.tcb = Some(Tcb::connect(&b.device, B, A, &b.isn, &clock::system()).unwrap());
+
+    // The SYNs cross on the wire
+    let a_syn: Vec<Vec<u8>> = a.device.take_sent();
+    let b_syn: Vec<Vec<u8>> = b.device.take_sent();
+    a.receive(&b_syn[0]);
+    b.receive(&a_syn[0]);
+
+    assert_eq!(a.state(), Some(State::SynRcvd));
+    assert_eq!(b.state(), Some(State::SynRcvd));
+
+    // Each answers with a SYN,ACK, which completes the handshake at the other end
+    let a_syn_ack: Vec<Vec<u8>> = a.device.take_sent();
+    let b_syn_ack: Vec<Vec<u8>> = b.device.take_sent();
+    assert_eq!(a_syn_ack.len(), 1);
+    assert_eq!(b_syn_ack.len(), 1);
+    assert_eq!(flags(&a_syn_ack[0]), (true, true));
+    assert_eq!(flags(&b_syn_ack[0]), (true, true));
+
+    a.receive(&b_syn_ack[0]);
+    b.receive(&a_syn_ack[0]);
+
+    assert_eq!(a.state(), Some(State::Estab));
+    assert_eq!(b.state(), Some(State::Estab));
+
+    // Nothing else is needed
+    assert_eq!(deliver(&a, &mut b), 0);
+    assert_eq!(deliver(&b, &mut a), 0);
+}
+
+#[test]
+fn closing_in_syn_sent_deletes_the_connection() {
+    let a = Endpoint::default();
+    let mut tcb = Tcb::connect(&a.device, A, B, &a.isn, &clock::system()).unwrap();
+    a.device.take_sent();
+
+    tcb.close(&a.device).unwrap();
+
+    assert_eq!(tcb.state(), State::Closed);
+    assert!(a.device.take_sent().is_empty());
+}
+
+#[test]
+fn connecting_with_port_zero_fails() {
+    let a = Endpoint::default();
+    let zero = SocketAddrV4::new(*B.ip(), 0);
+
+    assert!(Tcb::connect(&a.device, A, zero, &a.isn, &clock::system()).is_err());
+    assert!(Tcb::connect(&a.device, zero, B, &a.isn, &clock::system()).is_err());
+    assert!(a.device.take_sent().is_empty());
+}
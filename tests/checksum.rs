@@ -0,0 +1,115 @@
+//! Checksum validation of incoming packets, and checksums of the segments we send
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    checksum::{self, ChecksumError},
+    impair::Impairment,
+    listener::ListenerLimits,
+    tcp::SEND_BUFFER_SIZE,
+    testing::{Side, Wan},
+};
+
+/// <SEQ=101><ACK=301><CTL=PSH,ACK> carrying "hello"
+const DATA_SEGMENT: &str =
+    "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f";
+
+fn verify(packet: &[u8]) -> Result<(), ChecksumError> {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+    let data: &[u8] = &packet[ip_header.slice().len() + tcp_header.slice().len()..];
+
+    checksum::verify(&ip_header, &tcp_header, data)
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn intact_segment_is_accepted() {
+    assert_eq!(verify(&decode_hex(DATA_SEGMENT)), Ok(()));
+}
+
+#[test]
+fn corrupt_ip_header_is_rejected() {
+    let mut packet: Vec<u8> = decode_hex(DATA_SEGMENT);
+    // TTL
+    packet[8] ^= 0x01;
+
+    assert_eq!(verify(&packet), Err(ChecksumError::Ipv4Header));
+}
+
+#[test]
+fn corrupt_tcp_header_is_rejected() {
+    let mut packet: Vec<u8> = decode_hex(DATA_SEGMENT);
+    // Window
+    packet[34] ^= 0x80;
+
+    assert_eq!(verify(&packet), Err(ChecksumError::Tcp));
+}
+
+#[test]
+fn corrupt_payload_is_rejected() {
+    let mut packet: Vec<u8> = decode_hex(DATA_SEGMENT);
+    *packet.last_mut().unwrap() ^= 0x20;
+
+    assert_eq!(verify(&packet), Err(ChecksumError::Tcp));
+}
+
+/// The pseudo-header covers the addresses, so a segment delivered to the wrong host fails
+#[test]
+fn pseudo_header_is_covered() {
+    let mut packet: Vec<u8> = decode_hex(DATA_SEGMENT);
+    // Move the change from the destination address into the IP checksum so only the
+    // TCP checksum is wrong
+    packet[19] = 0x03;
+    packet[11] = packet[11].wrapping_sub(1);
+
+    assert_eq!(verify(&packet), Err(ChecksumError::Tcp));
+}
+
+/// The checksum of outgoing segments is summed over the header and payload where they
+/// lie, which may split a 16 bit word between two pieces
+#[test]
+fn checksum_of_split_segment_matches() {
+    let mut packet: Vec<u8> = decode_hex(DATA_SEGMENT);
+    let expected = u16::from_be_bytes([packet[36], packet[37]]);
+    packet[36..38].fill(0);
+
+    let source: [u8; 4] = packet[12..16].try_into().unwrap();
+    let destination: [u8; 4] = packet[16..20].try_into().unwrap();
+    let segment: &[u8] = &packet[20..];
+
+    for split in [0, 1, 20, 21, 23, segment.len()] {
+        let (first, second) = segment.split_at(split);
+        assert_eq!(
+            checksum::tcp_ipv4(source, destination, &[first, &[], second]),
+            expected,
+            "split at {split}"
+        );
+    }
+}
+
+/// The send buffer is a ring, so segments are sent from either side of its end
+#[test]
+fn segments_from_a_wrapped_send_buffer_have_valid_checksums() {
+    // Losses move segment boundaries around, so some straddle the end of the ring
+    let impairment = Impairment {
+        loss: 0.05,
+        ..Impairment::default()
+    };
+    let mut wan = Wan::new(impairment, 1);
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    // Several times the size of the send buffer, which is topped up as it drains
+    let data: Vec<u8> = (0..5 * SEND_BUFFER_SIZE + 7)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+
+    assert_eq!(wan.server.stack.stats().tcp_checksum_errors, 0);
+}
@@ -0,0 +1,180 @@
+//! Parsing the daemon's command line and config file
+
+use std::{fs, net::Ipv4Addr, time::Duration};
+
+use tcp_rs::{
+    cli::{self, Command, DaemonOptions, Forward, LinkMode, Reverse},
+    config::StackConfig,
+    span::LogLevel,
+    tcat::{self, TcatMode},
+};
+
+fn parse(args: &[&str]) -> tcp_rs::error::Result<Command> {
+    Command::parse(args.iter().map(|arg| arg.to_string()))
+}
+
+fn daemon(args: &[&str]) -> DaemonOptions {
+    match parse(args).unwrap() {
+        Command::Daemon(options) => *options,
+        command => panic!("expected the daemon, got {command:?}"),
+    }
+}
+
+#[test]
+fn no_arguments_runs_the_daemon_on_tun0() {
+    assert_eq!(daemon(&[]), DaemonOptions::default());
+    #[cfg(target_os = "linux")]
+    assert_eq!(DaemonOptions::default().iface, "tun0");
+}
+
+#[test]
+fn daemon_options_are_parsed() {
+    let options = daemon(&[
+        "--iface",
+        "tap3",
+        "--tap",
+        "10.0.0.2",
+        "--address",
+        "10.0.0.1/24",
+        "--listen",
+        "80,443",
+        "--workers",
+        "4",
+        "--queues",
+        "2",
+        "--log",
+        "trace",
+        "--log-port",
+        "443",
+        "--pcap",
+        "/tmp/out.pcap",
+    ]);
+
+    assert_eq!(options.iface, "tap3");
+    assert_eq!(options.link, LinkMode::Tap(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(options.address, Some((Ipv4Addr::new(10, 0, 0, 1), 24)));
+    assert_eq!(options.listen, [80, 443]);
+    assert_eq!(options.workers, Some(4));
+    assert_eq!(options.queues, Some(2));
+    assert_eq!(options.log_level, LogLevel::Trace);
+    assert_eq!(options.log_port, Some(443));
+    assert_eq!(options.pcap.unwrap().to_str(), Some("/tmp/out.pcap"));
+}
+
+#[test]
+fn proxy_rules_are_parsed() {
+    let options = daemon(&[
+        "--forward",
+        "80=127.0.0.1:8080, 443=[::1]:8443",
+        "--reverse",
+        "127.0.0.1:2222=192.168.0.1:22",
+    ]);
+
+    assert_eq!(
+        options.forward,
+        [
+            Forward {
+                port: 80,
+                upstream: "127.0.0.1:8080".parse().unwrap(),
+            },
+            Forward {
+                port: 443,
+                upstream: "[::1]:8443".parse().unwrap(),
+            },
+        ]
+    );
+    assert_eq!(
+        options.reverse,
+        [Reverse {
+            listen: "127.0.0.1:2222".parse().unwrap(),
+            local: tcat::LOCAL_ADDR,
+            remote: "192.168.0.1:22".parse().unwrap(),
+        }]
+    );
+
+    assert!(parse(&["--forward", "80"]).is_err());
+    assert!(parse(&["--reverse", "127.0.0.1:2222=localhost:22"]).is_err());
+}
+
+#[test]
+fn connection_settings_tune_the_profile_wherever_it_is_given() {
+    let options = daemon(&[
+        "--ttl",
+        "32",
+        "--idle-timeout",
+        "60",
+        "--pacing",
+        "on",
+        "--profile",
+        "server",
+    ]);
+
+    assert_eq!(options.config.tcb.ttl, 32);
+    assert!(options.config.tcb.pacing);
+    assert_eq!(
+        options.config.tcb.idle_timeout,
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        options.config.tcb.send_buffer_size,
+        StackConfig::SERVER.tcb.send_buffer_size
+    );
+}
+
+#[test]
+fn command_line_overrides_the_config_file() {
+    let path = std::env::temp_dir().join(format!("tcp_rs_cli_{}.conf", std::process::id()));
+    fs::write(
+        &path,
+        "# Tuned for a small board\nprofile = embedded\nlisten = 22, 80\nkeepalive = 30\nmss = 1400\n",
+    )
+    .unwrap();
+
+    let options = daemon(&["--config", path.to_str().unwrap(), "--mss", "1200"]);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(options.listen, [22, 80]);
+    assert_eq!(
+        options.config.max_connections,
+        StackConfig::EMBEDDED.max_connections
+    );
+    assert_eq!(
+        options.config.tcb.keepalive.unwrap().idle,
+        Duration::from_secs(30)
+    );
+    assert_eq!(options.config.tcb.mss, Some(1200));
+}
+
+#[test]
+fn bad_options_are_rejected() {
+    assert!(parse(&["--iface"]).is_err());
+    assert!(parse(&["--colour", "blue"]).is_err());
+    assert!(parse(&["--address", "10.0.0.1"]).is_err());
+    assert!(parse(&["--raw", "eth0"]).is_err());
+    assert!(parse(&["--timestamps", "maybe"]).is_err());
+    assert!(parse(&["serve"]).is_err());
+    assert!(cli::parse_config_file("mss 1400").is_err());
+}
+
+#[test]
+fn other_commands_are_parsed() {
+    assert_eq!(parse(&["--help"]).unwrap(), Command::Help);
+    assert_eq!(parse(&["--isn-audit"]).unwrap(), Command::IsnAudit(1000));
+    assert_eq!(
+        parse(&["--replay", "a.pcap", "expected.txt"]).unwrap(),
+        Command::Replay {
+            pcap: "a.pcap".to_string(),
+            expected: Some("expected.txt".to_string()),
+        }
+    );
+    assert_eq!(
+        parse(&["--iface", "tun1", "tcat", "-l", "8080"]).unwrap(),
+        Command::Tcat {
+            iface: "tun1".to_string(),
+            mode: TcatMode::Listen(8080),
+        }
+    );
+
+    // Options only apply to the daemon and tcat
+    assert!(parse(&["--log", "off", "--analyze", "a.pcap"]).is_err());
+}
@@ -0,0 +1,135 @@
+//! Timers driven by a simulated clock, so timeouts run at exactly the instants they're
+//! due without the tests waiting for them
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    clock::{Clock, SimulatedClock},
+    device::{CaptureDevice, LoopbackDevice, NetworkDevice},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::{ConnectInfo, State, MAX_SYN_RETRANSMISSIONS, MSL},
+    PACKET_BUF_SIZE,
+};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+fn stack(clock: &Arc<SimulatedClock>, listeners: Listeners) -> Stack {
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), clock.now());
+    stack.set_clock(clock.clone());
+    stack
+}
+
+/// Advance the clock to the stack's next deadline and run it, returning how long after
+/// `start` that was
+fn tick(
+    clock: &SimulatedClock,
+    stack: &mut Stack,
+    nic: &impl NetworkDevice,
+    start: Instant,
+) -> Duration {
+    let deadline: Instant = stack.next_deadline().unwrap();
+    clock.advance_to(deadline);
+    stack.on_tick(nic, deadline).unwrap();
+    deadline - start
+}
+
+/// RFC 6298 Section 5.5, an unanswered SYN is retransmitted with the RTO doubling from
+/// one second each time, then the connection gives up
+#[test]
+fn unanswered_syn_backs_off_then_aborts() {
+    let clock = Arc::new(SimulatedClock::new());
+    let device = CaptureDevice::default();
+    let mut stack = stack(&clock, Listeners::default());
+    let start: Instant = clock.now();
+
+    let info: ConnectInfo = stack.connect(&device, CLIENT, SERVER).unwrap();
+    assert_eq!(device.take_sent().len(), 1);
+
+    let mut retransmitted_at: Vec<Duration> = Vec::new();
+    for _ in 0..MAX_SYN_RETRANSMISSIONS {
+        retransmitted_at.push(tick(&clock, &mut stack, &device, start));
+        assert_eq!(device.take_sent().len(), 1);
+    }
+    assert_eq!(
+        retransmitted_at,
+        [1, 3, 7, 15, 31, 63].map(Duration::from_secs)
+    );
+
+    // The last retransmission waits out the largest RTO, capped at a minute
+    assert_eq!(
+        tick(&clock, &mut stack, &device, start),
+        Duration::from_secs(123)
+    );
+    assert!(device.take_sent().is_empty());
+    assert!(stack.connection(&info).is_none());
+    assert_eq!(stack.next_deadline(), None);
+}
+
+/// RFC 9293 Section 3.6, the side closing first lingers in TIME-WAIT for exactly 2 MSL
+#[test]
+fn time_wait_lasts_two_msl() {
+    let clock = Arc::new(SimulatedClock::new());
+    let (client_device, server_device) = LoopbackDevice::pair();
+    let mut listeners = Listeners::default();
+    listeners.insert(SERVER.port(), ListenerLimits::default());
+    let mut client = stack(&clock, Listeners::default());
+    let mut server = stack(&clock, listeners);
+
+    let deliver = |client: &mut Stack, server: &mut Stack| {
+        let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+        loop {
+            let mut n_packets: usize = 0;
+            while let Ok(n_bytes) = client_device.recv(&mut buf) {
+                client
+                    .on_packet(&client_device, &buf[..n_bytes], clock.now())
+                    .unwrap();
+                n_packets += 1;
+            }
+            while let Ok(n_bytes) = server_device.recv(&mut buf) {
+                server
+                    .on_packet(&server_device, &buf[..n_bytes], clock.now())
+                    .unwrap();
+                n_packets += 1;
+            }
+            if n_packets == 0 {
+                return;
+            }
+        }
+    };
+
+    let client_info: ConnectInfo = client.connect(&client_device, CLIENT, SERVER).unwrap();
+    deliver(&mut client, &mut server);
+    let server_info = ConnectInfo {
+        src_addr: *CLIENT.ip(),
+        src_port: CLIENT.port(),
+        dst_addr: *SERVER.ip(),
+        dst_port: SERVER.port(),
+    };
+
+    client.close(&client_device, &client_info).unwrap();
+    deliver(&mut client, &mut server);
+    server.close(&server_device, &server_info).unwrap();
+    deliver(&mut client, &mut server);
+    assert_eq!(
+        client.connection(&client_info).map(|tcb| tcb.state()),
+        Some(State::TimeWait)
+    );
+
+    let closed_at: Instant = clock.now();
+    clock.advance(2 * MSL - Duration::from_millis(1));
+    client.on_tick(&client_device, clock.now()).unwrap();
+    assert!(client.connection(&client_info).is_some());
+
+    assert_eq!(
+        tick(&clock, &mut client, &client_device, closed_at),
+        2 * MSL
+    );
+    assert!(client.connection(&client_info).is_none());
+}
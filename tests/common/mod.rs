@@ -0,0 +1,66 @@
+//! Packets and connections shared by the tests which drive a stack with hand-built
+//! segments, from a client at 192.168.0.1 to the stack at 192.168.0.2
+
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use tcp_rs::{
+    tcp::ConnectInfo,
+    testing::{CLIENT_ADDR, SERVER_ADDR},
+};
+
+/// Sequence number of the client's segments, before `modify` changes it
+pub const CLIENT_ISN: u32 = 100;
+
+/// A segment from the client's `src_port` to the stack's `dst_port`, carrying `payload`
+pub fn data_segment(
+    src_port: u16,
+    dst_port: u16,
+    modify: impl FnOnce(&mut TcpHeader),
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp_header = TcpHeader::new(src_port, dst_port, CLIENT_ISN, 8192);
+    modify(&mut tcp_header);
+
+    let ip_header = Ipv4Header::new(
+        tcp_header.header_len_u16() + payload.len() as u16,
+        64,
+        IpNumber::TCP,
+        CLIENT_ADDR.octets(),
+        SERVER_ADDR.octets(),
+    )
+    .unwrap();
+    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, payload).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    tcp_header.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A segment without a payload from the client's `src_port` to the stack's `dst_port`
+pub fn segment(src_port: u16, dst_port: u16, modify: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+    data_segment(src_port, dst_port, modify, &[])
+}
+
+/// A SYN from the client's `src_port` to the stack's `dst_port`
+pub fn syn(src_port: u16, dst_port: u16) -> Vec<u8> {
+    segment(src_port, dst_port, |header| header.syn = true)
+}
+
+pub fn tcp_header(packet: &[u8]) -> TcpHeaderSlice<'_> {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap()
+}
+
+/// The connection from the client's `src_port` to the stack's `dst_port`
+pub fn connection(src_port: u16, dst_port: u16) -> ConnectInfo {
+    ConnectInfo {
+        src_addr: CLIENT_ADDR,
+        src_port,
+        dst_addr: SERVER_ADDR,
+        dst_port,
+    }
+}
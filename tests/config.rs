@@ -0,0 +1,191 @@
+//! Preset stack configurations and their effect on new connections
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    clock,
+    config::{StackConfig, TcbConfig},
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    options,
+    stack::Stack,
+    tcp::{KeepaliveConfig, Tcb},
+    testing::{Side, Wan},
+};
+
+mod common;
+
+use common::syn;
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+fn profiles() -> Vec<StackConfig> {
+    StackConfig::PROFILES
+        .iter()
+        .map(|name| StackConfig::profile(name).unwrap())
+        .collect()
+}
+
+#[test]
+fn profiles_are_chosen_by_name() {
+    assert_eq!(
+        StackConfig::profile("embedded").unwrap(),
+        StackConfig::EMBEDDED
+    );
+    assert_eq!(StackConfig::profile("server").unwrap(), StackConfig::SERVER);
+    assert_eq!(
+        StackConfig::profile("interactive").unwrap(),
+        StackConfig::INTERACTIVE
+    );
+    assert_eq!(
+        StackConfig::profile("default").unwrap(),
+        StackConfig::default()
+    );
+    assert!(StackConfig::profile("fast").is_err());
+}
+
+#[test]
+fn every_profile_is_usable() {
+    for config in profiles() {
+        // Window scaling isn't offered, so nothing larger can be advertised
+        assert!(config.tcb.recv_window > 0 && config.tcb.recv_window <= u16::MAX as u32);
+        assert!(config.tcb.send_buffer_size > 0);
+        // A retransmission, keep-alive and TIME-WAIT timer for every connection
+        assert!(config.health_limits.max_timers >= 3 * config.health_limits.max_connections);
+    }
+}
+
+#[test]
+fn profiles_are_tuned_for_their_use() {
+    let default = StackConfig::DEFAULT;
+
+    let embedded = StackConfig::EMBEDDED;
+    assert!(embedded.tcb.send_buffer_size < default.tcb.send_buffer_size);
+    assert!(embedded.tcb.recv_window <= default.tcb.recv_window);
+    assert!(embedded.health_limits.max_connections < default.health_limits.max_connections);
+    assert!(!embedded.tcb.timestamps && !embedded.tcb.sack);
+    assert!(embedded.tcb.idle_timeout.is_some());
+
+    let server = StackConfig::SERVER;
+    assert!(server.tcb.send_buffer_size > default.tcb.send_buffer_size);
+    assert!(server.tcb.recv_window > default.tcb.recv_window);
+    assert!(server.health_limits.max_connections > default.health_limits.max_connections);
+    assert!(server.tcb.keepalive.is_some());
+    assert!(server.max_connections.is_some());
+
+    let interactive = StackConfig::INTERACTIVE;
+    let keepalive: KeepaliveConfig = interactive.tcb.keepalive.unwrap();
+    assert!(keepalive.idle < KeepaliveConfig::DEFAULT.idle);
+}
+
+#[test]
+fn new_connections_take_their_settings_from_the_config() {
+    let device = CaptureDevice::default();
+    let mut stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        Instant::now(),
+    );
+    stack.set_config(StackConfig::EMBEDDED);
+
+    let info = stack.connect(&device, CLIENT, SERVER).unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    let ip_header = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+    let syn = TcpHeaderSlice::from_slice(&sent[0][ip_header.slice().len()..]).unwrap();
+    assert_eq!(syn.window_size(), 1024);
+    assert_eq!(options::timestamps(syn.options()), None);
+
+    let tcb = stack.connection_mut(&info).unwrap();
+    assert_eq!(tcb.send(&device, &[0; 4096]).unwrap(), 2048);
+    assert_eq!(tcb.keepalive(), None);
+    assert_eq!(stack.health_limits(), StackConfig::EMBEDDED.health_limits);
+}
+
+#[test]
+fn connections_take_ttl_mss_and_timers_from_their_config() {
+    let device = CaptureDevice::default();
+    let config = TcbConfig {
+        ttl: 32,
+        mss: Some(1460),
+        initial_rto: Duration::from_millis(500),
+        ..TcbConfig::DEFAULT
+    };
+
+    let tcb = Tcb::connect_with_config(
+        &device,
+        CLIENT,
+        SERVER,
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+        &config,
+    )
+    .unwrap();
+    assert_eq!(tcb.rto().initial(), Duration::from_millis(500));
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    let ip_header = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+    let syn = TcpHeaderSlice::from_slice(&sent[0][ip_header.slice().len()..]).unwrap();
+    assert_eq!(ip_header.ttl(), 32);
+    assert_eq!(options::mss(syn.options()), Some(1460));
+}
+
+#[test]
+fn resets_take_ttl_from_the_config() {
+    let device = CaptureDevice::default();
+    let mut stack = Stack::with_config(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        Instant::now(),
+        StackConfig {
+            tcb: TcbConfig {
+                ttl: 32,
+                ..TcbConfig::DEFAULT
+            },
+            ..StackConfig::DEFAULT
+        },
+    );
+
+    // Nothing is listening, so the SYN is reset
+    stack
+        .on_packet(&device, &syn(40000, 443), Instant::now())
+        .unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    let ip_header = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+    let rst = TcpHeaderSlice::from_slice(&sent[0][ip_header.slice().len()..]).unwrap();
+    assert!(rst.rst());
+    assert_eq!(ip_header.ttl(), 32);
+}
+
+#[test]
+fn stack_can_be_built_with_a_config() {
+    let stack = Stack::with_config(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        Instant::now(),
+        StackConfig::SERVER,
+    );
+    assert_eq!(*stack.config(), StackConfig::SERVER);
+}
+
+#[test]
+fn server_profile_transfers_between_two_stacks() {
+    let mut wan = Wan::lossless();
+    wan.client.stack.set_config(StackConfig::SERVER);
+    wan.server.stack.set_config(StackConfig::SERVER);
+    wan.listen(443, ListenerLimits::default());
+
+    let connection = wan.connect(40000, 443).unwrap();
+    let tcb = wan.server.stack.connection(&connection.server).unwrap();
+    assert_eq!(tcb.keepalive(), Some(KeepaliveConfig::DEFAULT));
+
+    let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+}
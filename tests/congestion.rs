@@ -0,0 +1,224 @@
+//! The congestion window, RFC 7661 validation of it, and pacing segments across the
+//! round trip
+
+use std::time::{Duration, Instant};
+
+use tcp_rs::{
+    clock::Clock,
+    config::StackConfig,
+    congestion::{initial_window, CongestionWindow, NON_VALIDATED_PERIOD},
+    impair::Impairment,
+    pacing::Pacer,
+    seq::SeqNum,
+    tcp::{SocketOption, Tcb},
+    testing::{Connection, Wan},
+};
+
+const MSS: u32 = 1000;
+const SRTT: Option<Duration> = Some(Duration::from_millis(100));
+
+/// Acknowledge `len` bytes sent from `start` one segment at a time, returning where they end
+fn ack_flight(cwnd: &mut CongestionWindow, start: SeqNum, len: u32, now: Instant) -> SeqNum {
+    let end: SeqNum = start + len;
+    let mut ackn: SeqNum = start;
+    while ackn != end {
+        let n_acked: u32 = (end - ackn).min(MSS);
+        ackn += n_acked;
+        cwnd.on_ack(n_acked, ackn, end, MSS, SRTT, now);
+    }
+    end
+}
+
+fn established(now: Instant) -> CongestionWindow {
+    let mut cwnd = CongestionWindow::new(MSS, now);
+    cwnd.on_established(MSS, false, now);
+    cwnd
+}
+
+#[test]
+fn initial_window_follows_rfc_6928() {
+    assert_eq!(initial_window(536), 5360);
+    assert_eq!(initial_window(1460), 14600);
+    assert_eq!(initial_window(4000), 14600);
+    assert_eq!(initial_window(9000), 18000);
+
+    let now = Instant::now();
+    let mut cwnd = CongestionWindow::new(MSS, now);
+    cwnd.on_established(MSS, true, now);
+    assert_eq!(cwnd.cwnd(), MSS);
+}
+
+#[test]
+fn slow_start_doubles_the_window_each_flight() {
+    let now = Instant::now();
+    let mut cwnd: CongestionWindow = established(now);
+    assert!(cwnd.in_slow_start());
+
+    let end: SeqNum = ack_flight(&mut cwnd, SeqNum::new(1), 10 * MSS, now);
+    assert_eq!(cwnd.cwnd(), 20 * MSS);
+    ack_flight(&mut cwnd, end, 20 * MSS, now);
+    assert_eq!(cwnd.cwnd(), 40 * MSS);
+    assert!(cwnd.is_validated());
+}
+
+#[test]
+fn an_application_limited_window_stops_growing() {
+    let mut now = Instant::now();
+    let mut cwnd: CongestionWindow = established(now);
+
+    // One small segment a round trip uses a sliver of the window
+    let mut seq = SeqNum::new(1);
+    for _ in 0..3 {
+        seq = ack_flight(&mut cwnd, seq, 100, now);
+        now += SRTT.unwrap();
+    }
+    assert!(!cwnd.is_validated());
+    assert_eq!(cwnd.pipe_ack(), Some(100));
+
+    let before: u32 = cwnd.cwnd();
+    for _ in 0..10 {
+        seq = ack_flight(&mut cwnd, seq, 100, now);
+        now += SRTT.unwrap();
+    }
+    assert_eq!(cwnd.cwnd(), before);
+}
+
+#[test]
+fn an_idle_window_is_halved_every_non_validated_period() {
+    let now = Instant::now();
+    let mut cwnd: CongestionWindow = established(now);
+    let end: SeqNum = ack_flight(&mut cwnd, SeqNum::new(1), 10 * MSS, now);
+    ack_flight(&mut cwnd, end, 20 * MSS, now);
+    assert_eq!(cwnd.cwnd(), 40 * MSS);
+
+    // The samples age out, but the window is kept until a whole period has passed
+    cwnd.on_send(MSS, SRTT, now + Duration::from_secs(2));
+    assert!(!cwnd.is_validated());
+    assert_eq!(cwnd.cwnd(), 40 * MSS);
+
+    cwnd.on_send(MSS, SRTT, now + NON_VALIDATED_PERIOD);
+    assert_eq!(cwnd.cwnd(), 20 * MSS);
+    assert_eq!(cwnd.ssthresh(), u32::MAX);
+
+    // Never below the initial window, however long it's idle
+    cwnd.on_send(MSS, SRTT, now + NON_VALIDATED_PERIOD * 4);
+    assert_eq!(cwnd.cwnd(), initial_window(MSS));
+}
+
+#[test]
+fn a_timeout_drops_to_one_segment() {
+    let now = Instant::now();
+    let mut cwnd: CongestionWindow = established(now);
+
+    cwnd.on_timeout(8 * MSS, MSS, true);
+    assert_eq!(cwnd.cwnd(), MSS);
+    assert_eq!(cwnd.ssthresh(), 4 * MSS);
+
+    // Backing off again for the same data leaves ssthresh alone
+    cwnd.on_timeout(MSS, MSS, false);
+    assert_eq!(cwnd.ssthresh(), 4 * MSS);
+
+    // Slow start up to ssthresh, then a segment a window
+    let end: SeqNum = ack_flight(&mut cwnd, SeqNum::new(1), MSS, now);
+    let end: SeqNum = ack_flight(&mut cwnd, end, 2 * MSS, now);
+    assert_eq!(cwnd.cwnd(), 4 * MSS);
+    assert!(!cwnd.in_slow_start());
+    ack_flight(&mut cwnd, end, 4 * MSS, now);
+    assert_eq!(cwnd.cwnd(), 5 * MSS);
+}
+
+#[test]
+fn pacing_interval_spreads_the_window_over_the_round_trip() {
+    let srtt = Duration::from_millis(100);
+    assert_eq!(
+        Pacer::interval(1000, 10_000, srtt, 100),
+        Duration::from_millis(10)
+    );
+    assert_eq!(
+        Pacer::interval(1000, 10_000, srtt, 200),
+        Duration::from_millis(5)
+    );
+
+    let now = Instant::now();
+    let mut pacer = Pacer::default();
+    pacer.on_send(1000, 10_000, None, 100, now);
+    assert!(pacer.can_send(now));
+
+    pacer.on_send(1000, 10_000, Some(srtt), 100, now);
+    assert!(!pacer.can_send(now));
+    assert!(pacer.can_send(now + Duration::from_millis(10)));
+
+    // Time spent idle isn't saved up for a burst
+    let later: Instant = now + Duration::from_secs(1);
+    pacer.on_send(1000, 10_000, Some(srtt), 100, later);
+    assert_eq!(pacer.next_send(), Some(later + Duration::from_millis(10)));
+}
+
+/// A connection over a link with some delay, so there's a round trip to pace across,
+/// and a server window larger than the initial congestion window
+fn connected() -> (Wan, Connection) {
+    let impairment = Impairment {
+        delay: Duration::from_millis(10),
+        ..Impairment::default()
+    };
+    let mut wan = Wan::new(impairment, 1);
+    wan.server.stack.set_config(StackConfig::SERVER);
+    wan.established(443).unwrap()
+}
+
+fn client(wan: &mut Wan, connection: Connection) -> &mut Tcb {
+    wan.client.stack.connection_mut(&connection.client).unwrap()
+}
+
+#[test]
+fn the_congestion_window_limits_what_is_in_flight() {
+    let (mut wan, connection) = connected();
+    let data: Vec<u8> = vec![7; 100_000];
+
+    let tcb: &mut Tcb = wan.client.stack.connection_mut(&connection.client).unwrap();
+    let n_sent: usize = tcb.send(&wan.client.device, &data).unwrap();
+    let summary = tcb.summary();
+    let in_flight: u32 = summary.snd_nxt.wrapping_sub(summary.snd_una);
+    assert_eq!(
+        tcb.congestion().cwnd(),
+        initial_window(tcb.effective_mss() as u32)
+    );
+    assert_eq!(in_flight, tcb.congestion().cwnd());
+
+    // The window opens as the data is acknowledged
+    let mut received: Vec<u8> = Vec::new();
+    wan.run_until(1000, |wan| {
+        received.extend(wan.server.read_all(&connection.server));
+        received.len() == n_sent
+    })
+    .unwrap();
+    assert!(client(&mut wan, connection).congestion().cwnd() > in_flight);
+}
+
+#[test]
+fn pacing_spreads_segments_across_the_round_trip() {
+    let (mut wan, connection) = connected();
+    client(&mut wan, connection)
+        .set_option(SocketOption::Pacing(true))
+        .unwrap();
+    let data: Vec<u8> = vec![7; 20_000];
+
+    let tcb: &mut Tcb = wan.client.stack.connection_mut(&connection.client).unwrap();
+    assert!(tcb.pacing());
+    let before: u64 = tcb.stats().segments_out;
+    let n_sent: usize = tcb.send(&wan.client.device, &data).unwrap();
+
+    // Only the first segment goes straight out, the next waits its turn
+    assert_eq!(tcb.stats().segments_out - before, 1);
+    let next: Instant = tcb.next_deadline().unwrap();
+    assert!(next > wan.clock.now());
+    assert!(next - wan.clock.now() < Duration::from_millis(10));
+
+    let mut received: Vec<u8> = Vec::new();
+    wan.run_until(1000, |wan| {
+        received.extend(wan.server.read_all(&connection.server));
+        received.len() == n_sent
+    })
+    .unwrap();
+    assert_eq!(received, data[..n_sent]);
+}
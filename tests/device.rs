@@ -0,0 +1,158 @@
+//! Receive buffers sized from the device MTU, receiving in batches, and capturing packets
+//! through a device
+
+use std::{
+    io::{self, IoSlice, Write},
+    sync::{Arc, Mutex},
+};
+
+use tcp_rs::{
+    device::{LoopbackDevice, NetworkDevice, PcapTap, RecvBatch, RecvBuffer, MAX_PACKET_LEN},
+    pcap::{LinkType, PcapReader},
+    ETH_HEADER_SIZE, ETH_MTU,
+};
+
+/// A link with jumbo frames
+struct JumboDevice(LoopbackDevice);
+
+impl NetworkDevice for JumboDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        9000
+    }
+}
+
+#[test]
+fn buffer_is_sized_from_the_device_mtu() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let device = JumboDevice(ours);
+    let mut buf = RecvBuffer::for_device(&device);
+    assert_eq!(buf.max_packet_len(), 9000 + ETH_HEADER_SIZE);
+
+    let jumbo: Vec<u8> = vec![7; 9000];
+    theirs.send(&jumbo).unwrap();
+    assert_eq!(buf.recv(&device).unwrap(), Some(&jumbo[..]));
+
+    // Never smaller than Ethernet's, nor larger than the largest IPv4 packet
+    assert_eq!(
+        RecvBuffer::for_device(&theirs).max_packet_len(),
+        ETH_MTU + ETH_HEADER_SIZE
+    );
+    assert_eq!(
+        RecvBuffer::new(usize::MAX).max_packet_len(),
+        MAX_PACKET_LEN + ETH_HEADER_SIZE
+    );
+}
+
+#[test]
+fn oversized_packet_is_dropped() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let mut buf = RecvBuffer::for_device(&ours);
+
+    theirs.send(&vec![1; 2 * ETH_MTU]).unwrap();
+    theirs.send(&[2; 40]).unwrap();
+
+    assert_eq!(buf.recv(&ours).unwrap(), None);
+    assert_eq!(buf.recv(&ours).unwrap(), Some(&[2; 40][..]));
+    assert_eq!(
+        buf.recv(&ours).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn batch_drains_the_device_until_full() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let mut batch = RecvBatch::new(ETH_MTU, 4);
+
+    for n in 0..6 {
+        theirs.send(&[n; 40]).unwrap();
+    }
+    // Dropped without taking a place in the batch
+    theirs.send(&vec![9; 2 * ETH_MTU]).unwrap();
+
+    assert_eq!(batch.recv(&ours).unwrap(), 4);
+    let packets: Vec<&[u8]> = batch.packets().collect();
+    assert_eq!(packets, [&[0; 40], &[1; 40], &[2; 40], &[3; 40]]);
+
+    assert_eq!(batch.recv(&ours).unwrap(), 2);
+    assert_eq!(batch.packets().count(), 2);
+    assert_eq!(batch.recv(&ours).unwrap(), 0);
+    assert_eq!(batch.packets().count(), 0);
+}
+
+/// A file in memory which can be read back after it's been handed to the tap
+#[derive(Clone, Default)]
+struct SharedFile(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn capture_records_both_directions_while_enabled() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let tap = PcapTap::new(ours);
+    let file = SharedFile::default();
+    let mut buf: [u8; 64] = [0; 64];
+
+    tap.send(&[1; 20]).unwrap();
+    assert!(!tap.is_capturing());
+
+    tap.start_capture(file.clone()).unwrap();
+    assert!(tap.is_capturing());
+    tap.send(&[2; 30]).unwrap();
+    theirs.send(&[3; 40]).unwrap();
+    assert_eq!(tap.recv(&mut buf).unwrap(), 40);
+
+    tap.stop_capture().unwrap();
+    tap.send(&[4; 50]).unwrap();
+    // Capturing doesn't get in the way of the packets themselves
+    assert_eq!(theirs.take_pending().len(), 3);
+
+    let contents: Vec<u8> = file.0.lock().unwrap().clone();
+    let mut pcap = PcapReader::new(&contents[..]).unwrap();
+    assert_eq!(pcap.link_type(), LinkType::Raw);
+    assert_eq!(pcap.next_record().unwrap().unwrap().data, [2; 30]);
+    assert_eq!(pcap.next_record().unwrap().unwrap().data, [3; 40]);
+    assert!(pcap.next_record().unwrap().is_none());
+}
+
+/// A packet sent in pieces arrives, and is captured, as one
+#[test]
+fn vectored_send_is_one_packet() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let tap = PcapTap::new(ours);
+    let file = SharedFile::default();
+    tap.start_capture(file.clone()).unwrap();
+
+    let n_sent: usize = tap
+        .send_vectored(&[
+            IoSlice::new(&[1; 20]),
+            IoSlice::new(&[]),
+            IoSlice::new(&[2; 10]),
+        ])
+        .unwrap();
+    assert_eq!(n_sent, 30);
+
+    let expected: Vec<u8> = [[1; 20].as_slice(), &[2; 10]].concat();
+    assert_eq!(theirs.take_pending(), std::slice::from_ref(&expected));
+
+    tap.stop_capture().unwrap();
+    let contents: Vec<u8> = file.0.lock().unwrap().clone();
+    let mut pcap = PcapReader::new(&contents[..]).unwrap();
+    assert_eq!(pcap.next_record().unwrap().unwrap().data, expected);
+}
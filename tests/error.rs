@@ -0,0 +1,69 @@
+//! Errors the library's calls fail with, and how they carry over to `io::Error`
+
+use std::io;
+
+use tcp_rs::{error::TcpError, pcap::PcapReader, tcp::State};
+
+#[test]
+fn errors_map_to_the_closest_io_error_kind() {
+    let cases: [(TcpError, io::ErrorKind); 6] = [
+        (TcpError::NotConnected, io::ErrorKind::NotConnected),
+        (TcpError::ConnectionReset, io::ErrorKind::ConnectionReset),
+        (
+            TcpError::InvalidState(State::FinWait1),
+            io::ErrorKind::BrokenPipe,
+        ),
+        (TcpError::AddrInUse(443), io::ErrorKind::AddrInUse),
+        (
+            TcpError::Parse("bad".to_string()),
+            io::ErrorKind::InvalidData,
+        ),
+        (
+            TcpError::InvalidInput("port 0"),
+            io::ErrorKind::InvalidInput,
+        ),
+    ];
+
+    for (err, kind) in cases {
+        assert_eq!(io::Error::from(err).kind(), kind);
+    }
+}
+
+#[test]
+fn device_errors_keep_their_io_error() {
+    let err = TcpError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+    assert!(matches!(&err, TcpError::Device(_)));
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+
+    // A device which is only busy isn't failing
+    let err = TcpError::from(io::Error::from(io::ErrorKind::WouldBlock));
+    assert!(matches!(err, TcpError::WouldBlock));
+}
+
+#[test]
+fn messages_follow_rfc_9293() {
+    assert_eq!(
+        TcpError::InvalidState(State::LastAck).to_string(),
+        "connection closing"
+    );
+    assert_eq!(
+        TcpError::InvalidState(State::Closed).to_string(),
+        "connection does not exist"
+    );
+    assert_eq!(TcpError::ConnectionReset.to_string(), "connection reset");
+}
+
+#[test]
+fn malformed_captures_are_parse_errors() {
+    let file: [u8; 24] = [0; 24];
+    assert!(matches!(
+        PcapReader::new(&file[..]),
+        Err(TcpError::Parse(_))
+    ));
+
+    // Running out of file is the reader failing rather than the contents
+    assert!(matches!(
+        PcapReader::new(&file[..4]),
+        Err(TcpError::Device(_))
+    ));
+}
@@ -0,0 +1,254 @@
+//! Ethernet framing and ARP for running on a tap interface
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4Header};
+use tcp_rs::{
+    clock::{Clock, SimulatedClock},
+    device::{LoopbackDevice, NetworkDevice},
+    ethernet::{ArpCache, ArpOperation, ArpPacket, EthernetDevice, MacAddr, ETH_FRAME_HEADER_SIZE},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::State,
+    EtherType,
+};
+
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const PEER_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const PEER_MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x01]);
+
+/// The stack's end of the link, and the peer's end which speaks raw frames
+fn link() -> (EthernetDevice<LoopbackDevice>, LoopbackDevice) {
+    let (wire, peer) = LoopbackDevice::pair();
+    (
+        EthernetDevice::new(wire, MacAddr::for_ipv4(ADDR), ADDR),
+        peer,
+    )
+}
+
+fn frame(dst: MacAddr, src: MacAddr, ether_type: EtherType, payload: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::new();
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&src.0);
+    frame.extend_from_slice(&(ether_type as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Split a frame into its destination, EtherType and payload
+fn unframe(frame: &[u8]) -> (MacAddr, u16, &[u8]) {
+    let dst = MacAddr(frame[0..6].try_into().unwrap());
+    let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+    (dst, ether_type, &frame[ETH_FRAME_HEADER_SIZE..])
+}
+
+fn arp(operation: ArpOperation, target_addr: Ipv4Addr, target_mac: MacAddr) -> Vec<u8> {
+    let arp = ArpPacket {
+        operation,
+        sender_mac: PEER_MAC,
+        sender_addr: PEER_ADDR,
+        target_mac,
+        target_addr,
+    };
+    frame(
+        match operation {
+            ArpOperation::Request => MacAddr::BROADCAST,
+            ArpOperation::Reply => target_mac,
+        },
+        PEER_MAC,
+        EtherType::Arp,
+        &arp.to_bytes(),
+    )
+}
+
+/// An IPv4 packet from us to `dst` with a few bytes of payload
+fn ip_packet(dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let ip_header = Ipv4Header::new(
+        payload.len() as u16,
+        64,
+        IpNumber::TCP,
+        ADDR.octets(),
+        dst.octets(),
+    )
+    .unwrap();
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Receive on `nic` until nothing is left, returning the IP packets
+fn recv_all(nic: &impl NetworkDevice) -> Vec<Vec<u8>> {
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut buf: [u8; 2048] = [0; 2048];
+
+    loop {
+        match nic.recv(&mut buf) {
+            Ok(n_bytes) => packets.push(buf[..n_bytes].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return packets,
+            Err(err) => panic!("{err}"),
+        }
+    }
+}
+
+#[test]
+fn arp_requests_for_our_address_are_answered() {
+    let (nic, peer) = link();
+
+    peer.send(&arp(ArpOperation::Request, ADDR, MacAddr([0; 6])))
+        .unwrap();
+    peer.send(&arp(
+        ArpOperation::Request,
+        Ipv4Addr::new(192, 168, 0, 3),
+        MacAddr([0; 6]),
+    ))
+    .unwrap();
+    assert!(recv_all(&nic).is_empty());
+
+    let replies: Vec<Vec<u8>> = peer.take_pending();
+    assert_eq!(replies.len(), 1);
+    let (dst, ether_type, payload) = unframe(&replies[0]);
+    assert_eq!(dst, PEER_MAC);
+    assert_eq!(ether_type, EtherType::Arp as u16);
+    assert_eq!(
+        ArpPacket::parse(payload),
+        Some(ArpPacket {
+            operation: ArpOperation::Reply,
+            sender_mac: MacAddr::for_ipv4(ADDR),
+            sender_addr: ADDR,
+            target_mac: PEER_MAC,
+            target_addr: PEER_ADDR,
+        })
+    );
+
+    // Asking taught us the peer's address
+    assert_eq!(nic.neighbour(PEER_ADDR), Some(PEER_MAC));
+}
+
+#[test]
+fn packets_wait_for_their_destination_to_be_resolved() {
+    let (nic, peer) = link();
+    let first: Vec<u8> = ip_packet(PEER_ADDR, b"first");
+    let second: Vec<u8> = ip_packet(PEER_ADDR, b"second");
+    nic.send(&first).unwrap();
+    nic.send(&second).unwrap();
+
+    // Only one request goes out for both
+    let sent: Vec<Vec<u8>> = peer.take_pending();
+    assert_eq!(sent.len(), 1);
+    let (dst, ether_type, payload) = unframe(&sent[0]);
+    assert_eq!(dst, MacAddr::BROADCAST);
+    assert_eq!(ether_type, EtherType::Arp as u16);
+    let request: ArpPacket = ArpPacket::parse(payload).unwrap();
+    assert_eq!(request.operation, ArpOperation::Request);
+    assert_eq!(request.target_addr, PEER_ADDR);
+
+    peer.send(&arp(ArpOperation::Reply, ADDR, MacAddr::for_ipv4(ADDR)))
+        .unwrap();
+    assert!(recv_all(&nic).is_empty());
+
+    let third: Vec<u8> = ip_packet(PEER_ADDR, b"third");
+    nic.send(&third).unwrap();
+
+    let sent: Vec<Vec<u8>> = peer.take_pending();
+    let expected: Vec<u8> = frame(PEER_MAC, MacAddr::for_ipv4(ADDR), EtherType::Ipv4, &[]);
+    for (frame, packet) in sent.iter().zip([&first, &second, &third]) {
+        assert_eq!(&frame[..ETH_FRAME_HEADER_SIZE], &expected[..]);
+        assert_eq!(&frame[ETH_FRAME_HEADER_SIZE..], &packet[..]);
+    }
+    assert_eq!(sent.len(), 3);
+}
+
+#[test]
+fn only_ipv4_frames_for_us_are_received() {
+    let (nic, peer) = link();
+    let packet: Vec<u8> = ip_packet(ADDR, b"hello");
+    let ours = MacAddr::for_ipv4(ADDR);
+
+    peer.send(&frame(ours, PEER_MAC, EtherType::Ipv4, &packet))
+        .unwrap();
+    peer.send(&frame(PEER_MAC, PEER_MAC, EtherType::Ipv4, &packet))
+        .unwrap();
+    peer.send(&frame(ours, PEER_MAC, EtherType::Ipv6, &packet))
+        .unwrap();
+
+    assert_eq!(recv_all(&nic), [packet]);
+}
+
+#[test]
+fn arp_cache_expires_entries_and_replaces_the_oldest() {
+    let start = Instant::now();
+    let mut cache = ArpCache::new(2, Duration::from_secs(60));
+    let addr = |i: u8| Ipv4Addr::new(10, 0, 0, i);
+
+    cache.insert(addr(1), MacAddr([1; 6]), start);
+    cache.insert(addr(2), MacAddr([2; 6]), start + Duration::from_secs(1));
+    cache.insert(addr(3), MacAddr([3; 6]), start + Duration::from_secs(2));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(addr(1), start), None);
+
+    let later: Instant = start + Duration::from_secs(61);
+    assert_eq!(cache.get(addr(2), later), None);
+    assert_eq!(cache.get(addr(3), later), Some(MacAddr([3; 6])));
+
+    assert!(cache.update(addr(3), MacAddr([4; 6]), later));
+    assert!(!cache.update(addr(1), MacAddr([1; 6]), later));
+    assert_eq!(cache.get(addr(3), later), Some(MacAddr([4; 6])));
+}
+
+#[test]
+fn stacks_connect_over_ethernet() {
+    let clock = Arc::new(SimulatedClock::new());
+    let (client_wire, server_wire) = LoopbackDevice::pair();
+    let client_nic = EthernetDevice::with_clock(
+        client_wire,
+        MacAddr::for_ipv4(PEER_ADDR),
+        PEER_ADDR,
+        clock.clone(),
+    );
+    let server_nic =
+        EthernetDevice::with_clock(server_wire, MacAddr::for_ipv4(ADDR), ADDR, clock.clone());
+
+    let mut client = Stack::new(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        clock.now(),
+    );
+    let mut listeners = Listeners::default();
+    listeners.insert(443, ListenerLimits::default());
+    let mut server = Stack::new(listeners, IsnGenerator::new([2; 16]), clock.now());
+
+    let info = client
+        .connect(
+            &client_nic,
+            SocketAddrV4::new(PEER_ADDR, 40000),
+            SocketAddrV4::new(ADDR, 443),
+        )
+        .unwrap();
+
+    loop {
+        let to_server: Vec<Vec<u8>> = recv_all(&server_nic);
+        for packet in &to_server {
+            server.on_packet(&server_nic, packet, clock.now()).unwrap();
+        }
+        let to_client: Vec<Vec<u8>> = recv_all(&client_nic);
+        for packet in &to_client {
+            client.on_packet(&client_nic, packet, clock.now()).unwrap();
+        }
+
+        // ARP is answered without the stacks seeing anything, so look at the wire
+        if server_nic.inner().pending() == 0 && client_nic.inner().pending() == 0 {
+            break;
+        }
+    }
+
+    assert_eq!(client.connection(&info).unwrap().state(), State::Estab);
+    assert_eq!(server.connections().count(), 1);
+    assert_eq!(client_nic.neighbour(ADDR), Some(MacAddr::for_ipv4(ADDR)));
+}
@@ -0,0 +1,172 @@
+//! TCP Fast Open on the passive side, RFC 7413
+
+use std::time::Instant;
+
+use etherparse::TcpHeaderSlice;
+use tcp_rs::{
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    options::{self, FAST_OPEN_COOKIE_LEN, KIND_FAST_OPEN},
+    stack::Stack,
+    tcp::State,
+    testing::CLIENT_PORT,
+};
+
+mod common;
+
+use common::{connection, data_segment, tcp_header, CLIENT_ISN};
+
+const ISN_SECRET: [u8; 16] = [1; 16];
+
+fn stack(fast_open: bool) -> Stack {
+    let mut listeners = Listeners::default();
+    let limits = ListenerLimits {
+        fast_open,
+        ..ListenerLimits::default()
+    };
+    listeners.insert(443, limits);
+
+    Stack::new(listeners, IsnGenerator::new(ISN_SECRET), Instant::now())
+}
+
+/// A SYN carrying `payload` and a Fast Open option holding `cookie`, a request for one if
+/// it's empty
+fn syn(cookie: &[u8], payload: &[u8]) -> Vec<u8> {
+    data_segment(
+        CLIENT_PORT,
+        443,
+        |header| {
+            header.syn = true;
+            header.psh = true;
+            let mut options: Vec<u8> = vec![KIND_FAST_OPEN, 2 + cookie.len() as u8];
+            options.extend_from_slice(cookie);
+            while !options.len().is_multiple_of(4) {
+                options.push(options::KIND_NOOP);
+            }
+            header.set_options_raw(&options).unwrap();
+        },
+        payload,
+    )
+}
+
+/// Send `packet` to `stack`, returning the SYN,ACK
+fn syn_ack(stack: &mut Stack, packet: &[u8]) -> Vec<u8> {
+    let device = CaptureDevice::default();
+    stack.on_packet(&device, packet, Instant::now()).unwrap();
+
+    let mut sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    let syn_ack: Vec<u8> = sent.remove(0);
+    assert!(tcp_header(&syn_ack).syn() && tcp_header(&syn_ack).ack());
+    syn_ack
+}
+
+fn valid_cookie() -> [u8; FAST_OPEN_COOKIE_LEN] {
+    IsnGenerator::new(ISN_SECRET).fast_open_cookie([192, 168, 0, 1].into())
+}
+
+#[test]
+fn cookie_requests_are_answered_with_a_cookie() {
+    let mut stack = stack(true);
+
+    let syn_ack: Vec<u8> = syn_ack(&mut stack, &syn(&[], b"early"));
+
+    let syn_ack: TcpHeaderSlice = tcp_header(&syn_ack);
+    assert_eq!(
+        options::fast_open(syn_ack.options()),
+        Some(&valid_cookie()[..])
+    );
+    // Only the SYN is acknowledged, the data comes again after the handshake
+    assert_eq!(syn_ack.acknowledgment_number(), CLIENT_ISN + 1);
+    let tcb = stack.connection(&connection(CLIENT_PORT, 443)).unwrap();
+    assert_eq!(tcb.unread_len(), 0);
+}
+
+#[test]
+fn data_with_a_valid_cookie_is_accepted() {
+    let mut stack = stack(true);
+
+    let syn_ack: Vec<u8> = syn_ack(&mut stack, &syn(&valid_cookie(), b"early"));
+
+    let syn_ack: TcpHeaderSlice = tcp_header(&syn_ack);
+    assert_eq!(syn_ack.acknowledgment_number(), CLIENT_ISN + 1 + 5);
+    assert_eq!(options::fast_open(syn_ack.options()), None);
+
+    // Delivered once the handshake completes
+    let ack: Vec<u8> = data_segment(
+        CLIENT_PORT,
+        443,
+        |header| {
+            header.sequence_number = CLIENT_ISN + 1 + 5;
+            header.ack = true;
+            header.acknowledgment_number = syn_ack.sequence_number().wrapping_add(1);
+        },
+        &[],
+    );
+    let device = CaptureDevice::default();
+    stack.on_packet(&device, &ack, Instant::now()).unwrap();
+
+    let tcb = stack.connection_mut(&connection(CLIENT_PORT, 443)).unwrap();
+    assert_eq!(tcb.state(), State::Estab);
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(tcb.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"early");
+}
+
+#[test]
+fn invalid_cookies_are_replaced_and_their_data_dropped() {
+    let mut stack = stack(true);
+
+    let syn_ack: Vec<u8> = syn_ack(&mut stack, &syn(&[0xaa; FAST_OPEN_COOKIE_LEN], b"early"));
+
+    let syn_ack: TcpHeaderSlice = tcp_header(&syn_ack);
+    assert_eq!(syn_ack.acknowledgment_number(), CLIENT_ISN + 1);
+    assert_eq!(
+        options::fast_open(syn_ack.options()),
+        Some(&valid_cookie()[..])
+    );
+    assert_eq!(
+        stack
+            .connection(&connection(CLIENT_PORT, 443))
+            .unwrap()
+            .unread_len(),
+        0
+    );
+}
+
+#[test]
+fn listeners_without_fast_open_ignore_the_option() {
+    let mut stack = stack(false);
+
+    let syn_ack: Vec<u8> = syn_ack(&mut stack, &syn(&valid_cookie(), b"early"));
+
+    let syn_ack: TcpHeaderSlice = tcp_header(&syn_ack);
+    assert_eq!(syn_ack.acknowledgment_number(), CLIENT_ISN + 1);
+    assert_eq!(options::fast_open(syn_ack.options()), None);
+    assert_eq!(
+        stack
+            .connection(&connection(CLIENT_PORT, 443))
+            .unwrap()
+            .unread_len(),
+        0
+    );
+}
+
+#[test]
+fn cookies_are_tied_to_the_client_address() {
+    let isn = IsnGenerator::new(ISN_SECRET);
+
+    assert_eq!(
+        isn.fast_open_cookie([192, 168, 0, 1].into()),
+        valid_cookie()
+    );
+    assert_ne!(
+        isn.fast_open_cookie([192, 168, 0, 3].into()),
+        valid_cookie()
+    );
+    assert_ne!(
+        IsnGenerator::new([2; 16]).fast_open_cookie([192, 168, 0, 1].into()),
+        valid_cookie()
+    );
+}
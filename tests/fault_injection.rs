@@ -0,0 +1,109 @@
+//! Dropping segments and forcing retransmissions on demand. Both ends run on a simulated
+//! clock which never moves, so no timer fires behind the test's back.
+//! Run with `cargo test --features fault-injection`.
+#![cfg(feature = "fault-injection")]
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    challenge::ChallengeAckLimiter,
+    clock::{Clock, SimulatedClock},
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    tcp::{State, Tcb},
+};
+
+const A: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const B: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+/// Deliver everything `from` has sent so far to `to`, returning how many packets were delivered
+fn deliver(from: &CaptureDevice, to: &mut Tcb, to_device: &CaptureDevice) -> usize {
+    let packets: Vec<Vec<u8>> = from.take_sent();
+    for packet in &packets {
+        let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+        let data: &[u8] = &packet[ip_header.slice().len() + tcp_header.slice().len()..];
+
+        to.on_packet(
+            to_device,
+            ip_header,
+            tcp_header,
+            data,
+            &mut ChallengeAckLimiter::default(),
+        )
+        .unwrap();
+    }
+    packets.len()
+}
+
+/// Open a connection from A to B, returning each end and its device along with their clock
+fn establish() -> (CaptureDevice, Tcb, CaptureDevice, Tcb, Arc<SimulatedClock>) {
+    let isn = IsnGenerator::new([1; 16]);
+    let clock = Arc::new(SimulatedClock::new());
+    let shared: Arc<dyn Clock> = clock.clone();
+    let a_device = CaptureDevice::default();
+    let b_device = CaptureDevice::default();
+
+    let mut a = Tcb::connect(&a_device, A, B, &isn, &shared).unwrap();
+
+    let syn: Vec<u8> = a_device.take_sent().remove(0);
+    let ip_header = Ipv4HeaderSlice::from_slice(&syn).unwrap();
+    let tcp_header = TcpHeaderSlice::from_slice(&syn[ip_header.slice().len()..]).unwrap();
+    let mut b = Tcb::accept_connection(&b_device, ip_header, tcp_header, &[], &isn, &shared)
+        .unwrap()
+        .unwrap();
+
+    deliver(&b_device, &mut a, &a_device);
+    deliver(&a_device, &mut b, &b_device);
+    assert_eq!(a.state(), State::Estab);
+    assert_eq!(b.state(), State::Estab);
+
+    (a_device, a, b_device, b, clock)
+}
+
+#[test]
+fn dropped_segment_is_recovered_by_forced_retransmission() {
+    let (a_device, mut a, b_device, mut b, clock) = establish();
+
+    a.drop_next_segments(1);
+    a.send(&a_device, b"hello").unwrap();
+    assert_eq!(deliver(&a_device, &mut b, &b_device), 0);
+    assert_eq!(a.unacked_len(), 5);
+
+    a.force_retransmit(&a_device, clock.now()).unwrap();
+    assert_eq!(deliver(&a_device, &mut b, &b_device), 1);
+
+    let mut buf: [u8; 16] = [0; 16];
+    let n_read: usize = b.read(&mut buf);
+    assert_eq!(&buf[..n_read], b"hello");
+
+    deliver(&b_device, &mut a, &a_device);
+    assert_eq!(a.unacked_len(), 0);
+}
+
+#[test]
+fn only_the_requested_number_of_segments_are_dropped() {
+    let (a_device, mut a, b_device, mut b, clock) = establish();
+
+    a.drop_next_segments(2);
+    a.send(&a_device, b"one").unwrap();
+    a.force_retransmit(&a_device, clock.now()).unwrap();
+    assert_eq!(deliver(&a_device, &mut b, &b_device), 0);
+
+    a.force_retransmit(&a_device, clock.now()).unwrap();
+    assert_eq!(deliver(&a_device, &mut b, &b_device), 1);
+}
+
+#[test]
+fn forcing_a_retransmission_with_nothing_outstanding_sends_nothing() {
+    let (a_device, mut a, _b_device, _b, clock) = establish();
+
+    a.force_retransmit(&a_device, clock.now()).unwrap();
+
+    assert!(a_device.take_sent().is_empty());
+    assert_eq!(a.state(), State::Estab);
+}
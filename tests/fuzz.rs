@@ -0,0 +1,85 @@
+//! The fuzzing entry points, run over a few fixed and pseudo-random inputs
+
+use etherparse::{IpNumber, Ipv4Header, TcpHeader};
+use tcp_rs::{fuzz, tcp::State};
+
+/// Deterministic bytes for inputs, xorshift64
+struct Bytes(u64);
+
+impl Bytes {
+    fn take(&mut self, n: usize) -> Vec<u8> {
+        (0..n)
+            .map(|_| {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0 as u8
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn process_packet_answers_a_syn() {
+    let mut tcp_header = TcpHeader::new(40000, 443, 100, 8192);
+    tcp_header.syn = true;
+    let ip_header = Ipv4Header::new(
+        tcp_header.header_len_u16(),
+        64,
+        IpNumber::TCP,
+        [192, 168, 0, 1],
+        [192, 168, 0, 2],
+    )
+    .unwrap();
+    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    tcp_header.write(&mut packet).unwrap();
+
+    let mut stack = fuzz::stack();
+    let sent: Vec<Vec<u8>> = fuzz::process_packet(&mut stack, &packet).unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(stack.stats().connections_accepted, 1);
+}
+
+#[test]
+fn run_segments_completes_a_session() {
+    let segment = |flags: u8, actions: u8, seq: i32, ack: i32, payload: &[u8]| {
+        let mut input: Vec<u8> = vec![flags, actions];
+        input.extend_from_slice(&seq.to_be_bytes());
+        input.extend_from_slice(&ack.to_be_bytes());
+        input.extend_from_slice(&u16::MAX.to_be_bytes());
+        input.push(0);
+        input.push(payload.len() as u8);
+        input.extend_from_slice(payload);
+        input
+    };
+
+    let input: Vec<u8> = [
+        segment(0x10, 0, 0, 1, &[]),
+        segment(0x18, 0b0110, 0, 1, b"hello"),
+        segment(0x11, 0b1010, 0, 6, &[]),
+        segment(0x10, 0b0001, 0, 7, &[]),
+    ]
+    .concat();
+
+    let stack = fuzz::run_segments(&input).unwrap();
+    let (_, tcb) = stack.connections().next().unwrap();
+    assert_eq!(tcb.state(), State::Closed);
+    assert_eq!(tcb.stats().bytes_in, 5);
+    assert_eq!(tcb.stats().bytes_out, 5);
+}
+
+#[test]
+fn arbitrary_input_does_not_panic() {
+    let mut bytes = Bytes(0x1234_5678_9abc_def1);
+
+    for len in (0..2000).map(|i| i % 300) {
+        let input: Vec<u8> = bytes.take(len);
+
+        let mut stack = fuzz::stack();
+        let _ = fuzz::process_packet(&mut stack, &input);
+        let _ = fuzz::run_segments(&input);
+    }
+}
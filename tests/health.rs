@@ -0,0 +1,122 @@
+//! Resource usage reported against the stack's limits
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Instant,
+};
+
+use tcp_rs::{
+    admin::AdminCommand,
+    device::{CaptureDevice, LoopbackDevice, NetworkDevice},
+    health::{Health, HealthLimits, HealthStatus, HealthUsage},
+    isn::IsnGenerator,
+    listener::Listeners,
+    stack::Stack,
+};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+const LIMITS: HealthLimits = HealthLimits {
+    max_buffered_bytes: 1000,
+    max_connections: 10,
+    max_timers: 10,
+    max_device_queue: 10,
+};
+
+fn stack() -> Stack {
+    let mut stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::new([1; 16]),
+        Instant::now(),
+    );
+    stack.set_health_limits(LIMITS);
+    stack
+}
+
+#[test]
+fn status_is_the_worst_of_any_resource() {
+    let health = |usage: HealthUsage| Health::new(usage, LIMITS).status();
+
+    assert_eq!(health(HealthUsage::default()), HealthStatus::Ok);
+    assert_eq!(
+        health(HealthUsage {
+            buffered_bytes: 799,
+            connections: 7,
+            ..HealthUsage::default()
+        }),
+        HealthStatus::Ok
+    );
+    assert_eq!(
+        health(HealthUsage {
+            timers: 8,
+            ..HealthUsage::default()
+        }),
+        HealthStatus::Degraded
+    );
+    assert_eq!(
+        health(HealthUsage {
+            connections: 8,
+            device_queue: Some(10),
+            ..HealthUsage::default()
+        }),
+        HealthStatus::Overloaded
+    );
+}
+
+#[test]
+fn connections_their_buffers_and_timers_are_counted() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+
+    let info = stack.connect(&device, CLIENT, SERVER).unwrap();
+    stack
+        .connection_mut(&info)
+        .unwrap()
+        .send(&device, b"queued until the handshake completes")
+        .unwrap();
+
+    let health: Health = stack.health(&device);
+    assert_eq!(
+        health.usage,
+        HealthUsage {
+            buffered_bytes: 36,
+            connections: 1,
+            // The SYN's retransmission timer
+            timers: 1,
+            device_queue: None,
+        }
+    );
+    assert_eq!(health.status(), HealthStatus::Ok);
+
+    for port in 40001..40010 {
+        stack
+            .connect(&device, SocketAddrV4::new(*CLIENT.ip(), port), SERVER)
+            .unwrap();
+    }
+    assert_eq!(stack.health(&device).status(), HealthStatus::Overloaded);
+}
+
+#[test]
+fn packets_queued_on_the_device_are_counted() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let stack = stack();
+
+    for _ in 0..8 {
+        theirs.send(&[0x45; 20]).unwrap();
+    }
+
+    let health: Health = stack.health(&ours);
+    assert_eq!(health.usage.device_queue, Some(8));
+    assert_eq!(health.status(), HealthStatus::Degraded);
+    assert_eq!(
+        health.to_string(),
+        "health degraded buffered_bytes=0/1000 connections=0/10 timers=0/10 device_queue=8/10"
+    );
+}
+
+#[test]
+fn health_command_is_parsed() {
+    assert_eq!(AdminCommand::parse("health\n"), Some(AdminCommand::Health));
+    assert_eq!(AdminCommand::parse("health now"), None);
+}
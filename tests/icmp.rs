@@ -0,0 +1,285 @@
+//! ICMP errors received about our segments, and Destination Unreachable sent for closed ports
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use etherparse::{
+    icmpv4::{DestUnreachableHeader, ParameterProblemHeader, TimeExceededCode},
+    Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4Header, Ipv4HeaderSlice, UdpHeader,
+};
+use tcp_rs::{
+    clock,
+    device::CaptureDevice,
+    icmp::{self, IcmpError, IcmpErrorMessage},
+    isn::IsnGenerator,
+    tcp::{ConnectInfo, SoftError, State, Tcb, R1_RETRANSMISSIONS},
+    ETH_MTU,
+};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 443);
+
+/// A connection in SYN-SENT along with the SYN it sent
+fn connecting() -> (Tcb, Vec<u8>) {
+    let device = CaptureDevice::default();
+    let tcb = Tcb::connect(
+        &device,
+        LOCAL,
+        REMOTE,
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap();
+    let syn: Vec<u8> = device.take_sent().remove(0);
+
+    (tcb, syn)
+}
+
+/// An ICMP Destination Unreachable from the peer quoting the start of `packet`
+fn unreachable(header: DestUnreachableHeader, packet: &[u8]) -> Vec<u8> {
+    icmp_error(Icmpv4Type::DestinationUnreachable(header), packet)
+}
+
+/// An ICMP error from the peer quoting the start of `packet`
+fn icmp_error(icmp_type: Icmpv4Type, packet: &[u8]) -> Vec<u8> {
+    let quoted: &[u8] = &packet[..28];
+    let icmp_header = Icmpv4Header::with_checksum(icmp_type, quoted);
+
+    let ip_header = Ipv4Header::new(
+        (icmp_header.header_len() + quoted.len()) as u16,
+        64,
+        IpNumber::ICMP,
+        REMOTE.ip().octets(),
+        LOCAL.ip().octets(),
+    )
+    .unwrap();
+
+    let mut icmp_packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut icmp_packet).unwrap();
+    icmp_header.write(&mut icmp_packet).unwrap();
+    icmp_packet.extend_from_slice(quoted);
+    icmp_packet
+}
+
+fn parse(packet: &[u8]) -> Option<IcmpErrorMessage> {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    icmp::parse_error(&ip_header, &packet[ip_header.slice().len()..])
+}
+
+fn sequence_number(packet: &[u8]) -> u32 {
+    u32::from_be_bytes(packet[24..28].try_into().unwrap())
+}
+
+#[test]
+fn error_is_matched_to_the_connection_which_sent_the_segment() {
+    let (_, syn) = connecting();
+
+    let message = parse(&unreachable(DestUnreachableHeader::Port, &syn)).unwrap();
+
+    assert_eq!(
+        message,
+        IcmpErrorMessage {
+            connection: ConnectInfo {
+                src_addr: *REMOTE.ip(),
+                src_port: REMOTE.port(),
+                dst_addr: *LOCAL.ip(),
+                dst_port: LOCAL.port(),
+            },
+            seq: sequence_number(&syn),
+            error: IcmpError::PortUnreachable,
+        }
+    );
+}
+
+#[test]
+fn corrupt_message_is_ignored() {
+    let (_, syn) = connecting();
+    let mut packet: Vec<u8> = unreachable(DestUnreachableHeader::Port, &syn);
+    *packet.last_mut().unwrap() ^= 0x01;
+
+    assert_eq!(parse(&packet), None);
+}
+
+#[test]
+fn hard_error_aborts_opening_connection() {
+    let (mut tcb, syn) = connecting();
+    let message = parse(&unreachable(DestUnreachableHeader::Port, &syn)).unwrap();
+
+    tcb.on_icmp_error(message.seq, message.error);
+
+    assert_eq!(tcb.state(), State::Closed);
+}
+
+#[test]
+fn soft_error_leaves_connection_open() {
+    let (mut tcb, syn) = connecting();
+    let message = parse(&unreachable(DestUnreachableHeader::Host, &syn)).unwrap();
+    assert_eq!(message.error, IcmpError::Unreachable { code: 1 });
+
+    tcb.on_icmp_error(message.seq, message.error);
+
+    assert_eq!(tcb.state(), State::SynSent);
+}
+
+#[test]
+fn other_errors_are_parsed_as_soft() {
+    let (_, syn) = connecting();
+    let source_quench = Icmpv4Type::Unknown {
+        type_u8: 4,
+        code_u8: 0,
+        bytes5to8: [0; 4],
+    };
+
+    let errors: Vec<IcmpError> = [
+        source_quench,
+        Icmpv4Type::TimeExceeded(TimeExceededCode::TtlExceededInTransit),
+        Icmpv4Type::ParameterProblem(ParameterProblemHeader::PointerIndicatesError(0)),
+    ]
+    .into_iter()
+    .map(|icmp_type| parse(&icmp_error(icmp_type, &syn)).unwrap().error)
+    .collect();
+
+    assert_eq!(
+        errors,
+        [
+            IcmpError::SourceQuench,
+            IcmpError::TimeExceeded { code: 0 },
+            IcmpError::ParameterProblem,
+        ]
+    );
+    assert!(errors.iter().all(|error| !error.is_hard()));
+}
+
+/// RFC 1122 Section 4.2.3.9, soft errors are passed on without aborting the connection
+#[test]
+fn soft_errors_are_reported_to_the_application() {
+    let (mut tcb, syn) = connecting();
+    let seq: u32 = sequence_number(&syn);
+
+    tcb.on_icmp_error(seq, IcmpError::Unreachable { code: 1 });
+    tcb.on_icmp_error(seq, IcmpError::SourceQuench);
+
+    assert_eq!(
+        tcb.take_soft_errors(),
+        [
+            SoftError::Icmp(IcmpError::Unreachable { code: 1 }),
+            SoftError::Icmp(IcmpError::SourceQuench),
+        ]
+    );
+    assert_eq!(tcb.take_soft_errors(), []);
+    assert_eq!(tcb.state(), State::SynSent);
+}
+
+/// RFC 1122 Section 4.2.3.5, the application hears about R1 retransmissions while the
+/// connection carries on to R2
+#[test]
+fn reaching_r1_retransmissions_is_reported() {
+    let (mut tcb, _) = connecting();
+    let device = CaptureDevice::default();
+
+    for n_retransmissions in 1..=R1_RETRANSMISSIONS {
+        let deadline = tcb.next_deadline().unwrap();
+        tcb.on_tick(&device, deadline).unwrap();
+
+        let expected: &[SoftError] = if n_retransmissions == R1_RETRANSMISSIONS {
+            &[SoftError::RetransmissionThreshold]
+        } else {
+            &[]
+        };
+        assert_eq!(tcb.take_soft_errors(), expected);
+    }
+
+    assert_eq!(device.take_sent().len(), R1_RETRANSMISSIONS as usize);
+    assert_eq!(tcb.state(), State::SynSent);
+}
+
+/// RFC 5927 Section 4.1
+#[test]
+fn error_quoting_unsent_sequence_number_is_ignored() {
+    let (mut tcb, syn) = connecting();
+
+    tcb.on_icmp_error(
+        sequence_number(&syn).wrapping_add(1),
+        IcmpError::PortUnreachable,
+    );
+
+    assert_eq!(tcb.state(), State::SynSent);
+}
+
+#[test]
+fn fragmentation_needed_only_lowers_path_mtu() {
+    let (mut tcb, syn) = connecting();
+    let seq: u32 = sequence_number(&syn);
+    assert_eq!(tcb.path_mtu(), ETH_MTU);
+
+    for next_hop_mtu in [1400, 1450, 20] {
+        tcb.on_icmp_error(seq, IcmpError::FragmentationNeeded { next_hop_mtu });
+    }
+
+    assert_eq!(tcb.path_mtu(), 1400);
+    assert_eq!(tcb.state(), State::SynSent);
+}
+
+fn udp_datagram(destination: Ipv4Addr) -> Vec<u8> {
+    let payload: &[u8] = b"are you there?";
+    let udp_header = UdpHeader::without_ipv4_checksum(5353, 53, payload.len()).unwrap();
+    let ip_header = Ipv4Header::new(
+        (udp_header.header_len() + payload.len()) as u16,
+        64,
+        IpNumber::UDP,
+        REMOTE.ip().octets(),
+        destination.octets(),
+    )
+    .unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    udp_header.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[test]
+fn port_unreachable_quotes_ip_header_and_eight_bytes() {
+    let datagram: Vec<u8> = udp_datagram(*LOCAL.ip());
+    let ip_header = Ipv4HeaderSlice::from_slice(&datagram).unwrap();
+    let device = CaptureDevice::default();
+
+    let sent: bool =
+        icmp::send_port_unreachable(&device, &ip_header, &datagram[ip_header.slice().len()..])
+            .unwrap();
+    assert!(sent);
+
+    let packets: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(packets.len(), 1);
+
+    let reply_ip_header = Ipv4HeaderSlice::from_slice(&packets[0]).unwrap();
+    assert_eq!(reply_ip_header.protocol(), IpNumber::ICMP);
+    assert_eq!(reply_ip_header.source_addr(), *LOCAL.ip());
+    assert_eq!(reply_ip_header.destination_addr(), *REMOTE.ip());
+
+    let reply = Icmpv4Slice::from_slice(&packets[0][reply_ip_header.slice().len()..]).unwrap();
+    assert_eq!(
+        reply.icmp_type(),
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port)
+    );
+    assert_eq!(
+        reply.checksum(),
+        Icmpv4Header::with_checksum(reply.icmp_type(), reply.payload()).checksum
+    );
+    assert_eq!(reply.payload(), &datagram[..28]);
+}
+
+/// RFC 1122 Section 3.2.2
+#[test]
+fn no_port_unreachable_for_broadcast() {
+    let datagram: Vec<u8> = udp_datagram(Ipv4Addr::BROADCAST);
+    let ip_header = Ipv4HeaderSlice::from_slice(&datagram).unwrap();
+    let device = CaptureDevice::default();
+
+    let sent: bool =
+        icmp::send_port_unreachable(&device, &ip_header, &datagram[ip_header.slice().len()..])
+            .unwrap();
+
+    assert!(!sent);
+    assert!(device.take_sent().is_empty());
+}
@@ -0,0 +1,118 @@
+//! Packets lost, delayed, duplicated and reordered on their way through a device, and
+//! connections carrying on regardless
+
+use std::{sync::Arc, time::Duration};
+
+use tcp_rs::{
+    clock::{Clock, SimulatedClock},
+    device::{LoopbackDevice, NetworkDevice},
+    impair::{ImpairedDevice, Impairment, ImpairmentStats},
+    listener::ListenerLimits,
+    testing::{Side, Wan},
+};
+
+/// Send one packet for each byte, returning the bytes in the order they arrived
+fn send_through(device: &ImpairedDevice<LoopbackDevice>, peer: &LoopbackDevice) -> Vec<u8> {
+    for byte in 0..4 {
+        device.send(&[byte]).unwrap();
+    }
+
+    peer.take_pending().into_iter().flatten().collect()
+}
+
+#[test]
+fn lost_packets_never_arrive() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let impairment = Impairment {
+        loss: 1.0,
+        ..Impairment::default()
+    };
+    let device = ImpairedDevice::new(ours, impairment, 1);
+
+    assert_eq!(send_through(&device, &theirs), []);
+    assert_eq!(
+        device.stats(),
+        ImpairmentStats {
+            packets: 4,
+            lost: 4,
+            ..ImpairmentStats::default()
+        }
+    );
+}
+
+#[test]
+fn duplicated_packets_arrive_twice() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let impairment = Impairment {
+        duplicate: 1.0,
+        ..Impairment::default()
+    };
+    let device = ImpairedDevice::new(ours, impairment, 1);
+
+    assert_eq!(send_through(&device, &theirs), [0, 0, 1, 1, 2, 2, 3, 3]);
+}
+
+#[test]
+fn reordered_packets_arrive_after_the_next_one() {
+    let (ours, theirs) = LoopbackDevice::pair();
+    let impairment = Impairment {
+        reorder: 1.0,
+        ..Impairment::default()
+    };
+    let device = ImpairedDevice::new(ours, impairment, 1);
+
+    assert_eq!(send_through(&device, &theirs), [1, 0, 3, 2]);
+
+    device.send(&[4]).unwrap();
+    assert_eq!(theirs.pending(), 0);
+    device.flush().unwrap();
+    assert_eq!(theirs.take_pending(), [vec![4]]);
+}
+
+#[test]
+fn delayed_packets_arrive_once_due() {
+    let clock = Arc::new(SimulatedClock::new());
+    let (ours, theirs) = LoopbackDevice::pair();
+    let impairment = Impairment {
+        delay: Duration::from_millis(50),
+        ..Impairment::default()
+    };
+    let mut device = ImpairedDevice::new(ours, impairment, 1);
+    device.set_clock(clock.clone());
+
+    device.send(&[0]).unwrap();
+    assert_eq!(theirs.pending(), 0);
+    assert_eq!(
+        device.next_release(),
+        Some(clock.now() + Duration::from_millis(50))
+    );
+
+    clock.advance(Duration::from_millis(49));
+    assert_eq!(device.release_due().unwrap(), 0);
+
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(device.release_due().unwrap(), 1);
+    assert_eq!(theirs.take_pending(), [vec![0]]);
+    assert_eq!(device.next_release(), None);
+}
+
+/// Connections get their data through intact, however badly the link treats it
+#[test]
+fn transfer_completes_over_an_impaired_link() {
+    let impairment = Impairment {
+        loss: 0.1,
+        duplicate: 0.05,
+        reorder: 0.1,
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(20),
+    };
+    let mut wan = Wan::new(impairment, 1);
+    wan.listen(443, ListenerLimits::default());
+
+    let connection = wan.connect(40000, 443).unwrap();
+    let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+
+    let stats: ImpairmentStats = wan.client.device.stats();
+    assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0);
+}
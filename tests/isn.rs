@@ -0,0 +1,36 @@
+//! Initial sequence number selection, RFC 6528
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{isn::IsnGenerator, isn_audit};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+
+#[test]
+fn audit_passes() {
+    let report = isn_audit::run(1000).unwrap();
+    assert!(report.passed(), "{report}");
+}
+
+#[test]
+fn isns_depend_on_the_secret_quad_and_clock() {
+    let isn = IsnGenerator::new([7; 16]);
+    let now = Instant::now();
+
+    let first: u32 = isn.generate(LOCAL, REMOTE, now);
+    assert_eq!(isn.generate(LOCAL, REMOTE, now), first);
+
+    let other_port = SocketAddrV4::new(*REMOTE.ip(), REMOTE.port() + 1);
+    assert_ne!(isn.generate(LOCAL, other_port, now), first);
+
+    // The clock ticks every 4 microseconds
+    let later: u32 = isn.generate(LOCAL, REMOTE, now + Duration::from_millis(1));
+    assert_eq!(later.wrapping_sub(first), 250);
+
+    let other_secret = IsnGenerator::new([8; 16]);
+    assert_ne!(other_secret.generate(LOCAL, REMOTE, now), first);
+}
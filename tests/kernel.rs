@@ -0,0 +1,357 @@
+//! The stack against the kernel's own TCP, over a real tun device. The kernel side uses
+//! `std::net`, the stack side the async front end.
+//! Needs CAP_NET_ADMIN, run with `sudo -E cargo test --features kernel-tests --test kernel`.
+#![cfg(all(feature = "kernel-tests", target_os = "linux"))]
+
+use std::{
+    future::Future,
+    io::{Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream},
+    os::fd::AsRawFd,
+    pin::pin,
+    process::Command,
+    task::Poll,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    async_stack::{block_on, AsyncStack, AsyncTcpStream},
+    device::NetworkDevice,
+    ethernet::{EthernetDevice, MacAddr},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    packet_socket::{PacketFilter, PacketSocket},
+    stack::Stack,
+};
+use tun_tap::{Iface, Mode};
+
+const PORT: u16 = 443;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the async front end needs from a device
+trait Link: NetworkDevice + AsRawFd + Send + Sync + 'static {}
+
+impl<D: NetworkDevice + AsRawFd + Send + Sync + 'static> Link for D {}
+
+/// An interface of its own with the kernel at 10.99.`subnet`.1, so tests can run side by
+/// side. The interface goes away when it's dropped.
+fn open(subnet: u8, mode: Mode) -> Iface {
+    let name: String = format!("tcprs{subnet}");
+    let nic = Iface::without_packet_info(&name, mode).unwrap();
+    nic.set_non_blocking().unwrap();
+
+    let host: String = format!("10.99.{subnet}.1/24");
+    for args in [
+        ["addr", "add", &host, "dev", &name],
+        ["link", "set", "up", "dev", &name],
+    ] {
+        let status = Command::new("ip").args(args).status().unwrap();
+        assert!(status.success(), "ip {} failed: {status}", args.join(" "));
+    }
+
+    nic
+}
+
+/// Where the stack answers on `subnet`
+fn stack_addr(subnet: u8) -> Ipv4Addr {
+    Ipv4Addr::new(10, 99, subnet, 2)
+}
+
+fn spawn<D: Link>(nic: D) -> AsyncStack<D> {
+    let stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::from_os_random().unwrap(),
+        Instant::now(),
+    );
+    AsyncStack::spawn(nic, stack).unwrap()
+}
+
+/// The stack on a tun interface, and the address to connect to it at
+fn start(subnet: u8) -> (AsyncStack, SocketAddr) {
+    let stack = spawn(open(subnet, Mode::Tun));
+    (stack, SocketAddrV4::new(stack_addr(subnet), PORT).into())
+}
+
+/// The stack on a tap interface, answering ARP for its address
+fn start_tap(subnet: u8) -> (AsyncStack<EthernetDevice<Iface>>, SocketAddr) {
+    let addr: Ipv4Addr = stack_addr(subnet);
+    let nic = EthernetDevice::new(open(subnet, Mode::Tap), MacAddr::for_ipv4(addr), addr);
+    (spawn(nic), SocketAddrV4::new(addr, PORT).into())
+}
+
+/// A veth pair, removed when dropped
+struct Veth {
+    kernel: String,
+}
+
+impl Drop for Veth {
+    fn drop(&mut self) {
+        let _ = Command::new("ip")
+            .args(["link", "del", &self.kernel])
+            .status();
+    }
+}
+
+/// Turn off transmit checksum offload on `name`, as `ethtool -K <name> tx off` does.
+/// Otherwise the kernel leaves checksums for the veth peer to fill in, and the packet
+/// socket reads segments whose checksums don't match.
+fn disable_tx_checksums(name: &str) {
+    /// ETHTOOL_STXCSUM from linux/ethtool.h
+    const ETHTOOL_STXCSUM: u32 = 0x17;
+
+    #[repr(C)]
+    struct EthtoolValue {
+        cmd: u32,
+        data: u32,
+    }
+
+    let mut value = EthtoolValue {
+        cmd: ETHTOOL_STXCSUM,
+        data: 0,
+    };
+    // SAFETY: an all zero ifreq is valid
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    request.ifr_ifru.ifru_data = (&mut value as *mut EthtoolValue).cast();
+
+    // SAFETY: `request` points at `value`, and both outlive the calls
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+        let result = libc::ioctl(fd, libc::SIOCETHTOOL, &mut request);
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+        assert!(result >= 0, "SIOCETHTOOL on {name} failed: {err}");
+    }
+}
+
+/// The stack on one end of a veth pair through a packet socket, as it would be on a
+/// physical NIC, with the kernel on the other end
+fn start_packet_socket(subnet: u8) -> (Veth, AsyncStack<EthernetDevice<PacketSocket>>, SocketAddr) {
+    let veth = Veth {
+        kernel: format!("tcprs{subnet}k"),
+    };
+    let stack_side: String = format!("tcprs{subnet}s");
+    let host: String = format!("10.99.{subnet}.1/24");
+    for args in [
+        vec![
+            "link",
+            "add",
+            &veth.kernel,
+            "type",
+            "veth",
+            "peer",
+            "name",
+            &stack_side,
+        ],
+        vec!["addr", "add", &host, "dev", &veth.kernel],
+        vec!["link", "set", "up", "dev", &veth.kernel],
+        vec!["link", "set", "up", "dev", &stack_side],
+    ] {
+        let status = Command::new("ip").args(&args).status().unwrap();
+        assert!(status.success(), "ip {} failed: {status}", args.join(" "));
+    }
+
+    disable_tx_checksums(&veth.kernel);
+
+    let addr: Ipv4Addr = stack_addr(subnet);
+    let filter = PacketFilter {
+        addr,
+        ports: vec![PORT],
+    };
+    let socket = PacketSocket::open(&stack_side, &filter).unwrap();
+    socket.set_non_blocking().unwrap();
+    let mac: MacAddr = socket.mac();
+
+    let stack = spawn(EthernetDevice::new(socket, mac, addr));
+    (veth, stack, SocketAddrV4::new(addr, PORT).into())
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.set_write_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+/// Read from the stack until the kernel closes its side
+async fn read_to_end<D: Link>(stream: &AsyncTcpStream<D>) -> Vec<u8> {
+    let mut received: Vec<u8> = Vec::new();
+    let mut buf: [u8; 4096] = [0; 4096];
+
+    loop {
+        let n_read: usize = stream.read(&mut buf).await.unwrap();
+        if n_read == 0 {
+            return received;
+        }
+        received.extend_from_slice(&buf[..n_read]);
+    }
+}
+
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+}
+
+#[test]
+fn kernel_connects_and_closes() {
+    let (stack, addr) = start(1);
+    let listener = stack.listen(PORT, ListenerLimits::default());
+
+    let kernel = thread::spawn(move || {
+        let mut stream = connect(addr);
+        stream.write_all(b"hello").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut reply: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        reply
+    });
+
+    block_on(async {
+        let stream = listener.accept().await.unwrap();
+        assert_eq!(read_to_end(&stream).await, b"hello");
+        stream.write_all(b"bye").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    assert_eq!(kernel.join().unwrap(), b"bye");
+}
+
+#[test]
+fn bulk_transfer_in_both_directions() {
+    let (stack, addr) = start(2);
+    let listener = stack.listen(PORT, ListenerLimits::default());
+    let upload: Vec<u8> = pattern(256 * 1024, 1);
+    let download: Vec<u8> = pattern(256 * 1024, 2);
+
+    let kernel = {
+        let (upload, download) = (upload.clone(), download.clone());
+        thread::spawn(move || {
+            let mut stream = connect(addr);
+            let mut reader = stream.try_clone().unwrap();
+            let receiving = thread::spawn(move || {
+                let mut received: Vec<u8> = Vec::new();
+                reader.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            stream.write_all(&upload).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            assert!(receiving.join().unwrap() == download);
+        })
+    };
+
+    block_on(async {
+        let stream = listener.accept().await.unwrap();
+
+        // Both directions at once, so neither side's window fills while the other waits
+        let sending = async {
+            stream.write_all(&download).await.unwrap();
+            stream.shutdown().await.unwrap();
+        };
+        let mut sending = pin!(sending);
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf: [u8; 4096] = [0; 4096];
+        let mut sent: bool = false;
+
+        std::future::poll_fn(|cx| {
+            if !sent {
+                sent = sending.as_mut().poll(cx).is_ready();
+            }
+            loop {
+                match stream.poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(0)) if sent => return Poll::Ready(()),
+                    Poll::Ready(Ok(0)) => return Poll::Pending,
+                    Poll::Ready(Ok(n_read)) => received.extend_from_slice(&buf[..n_read]),
+                    Poll::Ready(Err(err)) => panic!("{err}"),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        })
+        .await;
+
+        assert!(received == upload);
+    });
+
+    kernel.join().unwrap();
+}
+
+#[test]
+fn stack_closes_first() {
+    let (stack, addr) = start(3);
+    let listener = stack.listen(PORT, ListenerLimits::default());
+
+    let kernel = thread::spawn(move || {
+        let mut stream = connect(addr);
+        let mut received: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        drop(stream);
+        received
+    });
+
+    block_on(async {
+        let stream = listener.accept().await.unwrap();
+        stream.write_all(&pattern(10_000, 3)).await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert!(read_to_end(&stream).await.is_empty());
+    });
+
+    assert!(kernel.join().unwrap() == pattern(10_000, 3));
+}
+
+#[test]
+fn kernel_connects_over_tap() {
+    let (stack, addr) = start_tap(4);
+    let listener = stack.listen(PORT, ListenerLimits::default());
+
+    let kernel = thread::spawn(move || {
+        let mut stream = connect(addr);
+        stream.write_all(b"over ethernet").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut reply: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        reply
+    });
+
+    block_on(async {
+        let stream = listener.accept().await.unwrap();
+        let received: Vec<u8> = read_to_end(&stream).await;
+        stream.write_all(&received).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    assert_eq!(kernel.join().unwrap(), b"over ethernet");
+}
+
+#[test]
+fn kernel_connects_through_a_packet_socket() {
+    let (_veth, stack, addr) = start_packet_socket(5);
+    let listener = stack.listen(PORT, ListenerLimits::default());
+    let data: Vec<u8> = pattern(8 * 1024, 5);
+
+    let kernel = {
+        let data = data.clone();
+        thread::spawn(move || {
+            let mut stream = connect(addr);
+            stream.write_all(&data).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            let mut reply: Vec<u8> = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        })
+    };
+
+    block_on(async {
+        let stream = listener.accept().await.unwrap();
+        let received: Vec<u8> = read_to_end(&stream).await;
+        stream.write_all(&received).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    assert!(kernel.join().unwrap() == data);
+}
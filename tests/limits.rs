@@ -0,0 +1,192 @@
+//! Capping how many connections the stack holds and evicting idle ones
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use etherparse::TcpHeader;
+use tcp_rs::{
+    clock::{Clock, SimulatedClock},
+    config::{StackConfig, TcbConfig},
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    testing::{Side, Wan, CLIENT_PORT},
+};
+
+mod common;
+
+use common::{connection, data_segment, segment, syn, tcp_header, CLIENT_ISN};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn syns_past_the_connection_limit_are_dropped() {
+    let device = CaptureDevice::default();
+    let mut listeners = Listeners::default();
+    listeners.insert(443, ListenerLimits::default());
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now());
+    stack.set_config(StackConfig {
+        max_connections: Some(2),
+        ..StackConfig::DEFAULT
+    });
+
+    for src_port in 40000..40003 {
+        stack
+            .on_packet(&device, &syn(src_port, 443), Instant::now())
+            .unwrap();
+    }
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert!(sent.iter().all(|packet| tcp_header(packet).syn()));
+    assert_eq!(stack.connections().count(), 2);
+    assert_eq!(stack.stats().connections_refused, 1);
+
+    let local = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 0);
+    let remote = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 9), 80);
+    assert!(stack.connect(&device, local, remote).is_err());
+}
+
+#[test]
+fn idle_connections_are_reset_and_deleted() {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig {
+        tcb: TcbConfig {
+            idle_timeout: Some(IDLE_TIMEOUT),
+            ..TcbConfig::DEFAULT
+        },
+        ..StackConfig::DEFAULT
+    });
+    wan.listen(443, ListenerLimits::default());
+
+    let connection = wan.connect(40000, 443).unwrap();
+    let opened: Instant = wan.clock.now();
+    assert_eq!(wan.server.next_deadline(), Some(opened + IDLE_TIMEOUT));
+
+    wan.run_until(10, |wan| wan.server.stack.connections().count() == 0)
+        .unwrap();
+    assert_eq!(wan.clock.now(), opened + IDLE_TIMEOUT);
+    assert_eq!(wan.server.stack.stats().connections_evicted, 1);
+
+    // The client is told with a RST rather than left holding its end
+    assert_eq!(wan.client.state(&connection.client), Some(State::Closed));
+}
+
+#[test]
+fn traffic_from_the_peer_keeps_a_connection() {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig {
+        tcb: TcbConfig {
+            idle_timeout: Some(IDLE_TIMEOUT),
+            ..TcbConfig::DEFAULT
+        },
+        ..StackConfig::DEFAULT
+    });
+    wan.listen(443, ListenerLimits::default());
+
+    let connection = wan.connect(40000, 443).unwrap();
+    wan.advance_to(wan.clock.now() + IDLE_TIMEOUT / 2).unwrap();
+    wan.transfer(connection, Side::Client, b"still here")
+        .unwrap();
+
+    wan.advance_to(wan.clock.now() + IDLE_TIMEOUT * 3 / 4)
+        .unwrap();
+    assert!(wan.server.state(&connection.server).is_some());
+    assert_eq!(wan.server.stack.stats().connections_evicted, 0);
+}
+
+#[test]
+fn listeners_count_payload_both_ways() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let request: Vec<u8> = vec![1; 3000];
+    let response: Vec<u8> = vec![2; 50_000];
+    wan.transfer(connection, Side::Client, &request).unwrap();
+    wan.transfer(connection, Side::Server, &response).unwrap();
+    wan.run_until_idle().unwrap();
+
+    // Both count retransmitted payload, like the connection's own counters
+    let sent: u64 = wan
+        .server
+        .stack
+        .connection(&connection.server)
+        .unwrap()
+        .stats()
+        .bytes_out;
+    let stats = wan.server.stack.listeners().get(443).unwrap().stats();
+    assert_eq!(stats.accepted, 1);
+    assert!(stats.bytes_in >= request.len() as u64);
+    assert!(sent >= response.len() as u64);
+    assert_eq!(stats.bytes_out, sent);
+}
+
+/// Over the byte rate only the payload is dropped, so an ACK riding on it still gets
+/// through. The bucket runs on the stack's clock, so a second later there's room again.
+#[test]
+fn throttled_listeners_only_drop_payload() {
+    let device = CaptureDevice::default();
+    let clock = Arc::new(SimulatedClock::new());
+    // The listener is made a little after the stack's clock starts
+    thread::sleep(Duration::from_millis(10));
+    let mut listeners = Listeners::default();
+    let limits = ListenerLimits {
+        byte_rate: Some(10),
+        ..ListenerLimits::default()
+    };
+    listeners.insert(443, limits);
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), clock.now());
+    stack.set_clock(clock.clone());
+    let info: ConnectInfo = connection(CLIENT_PORT, 443);
+
+    stack
+        .on_packet(&device, &syn(CLIENT_PORT, 443), clock.now())
+        .unwrap();
+    let iss: u32 = tcp_header(&device.take_sent()[0]).sequence_number();
+    let ack = |n_acked: u32| {
+        move |header: &mut TcpHeader| {
+            header.sequence_number = CLIENT_ISN + 1;
+            header.ack = true;
+            header.acknowledgment_number = iss.wrapping_add(1 + n_acked);
+        }
+    };
+    stack
+        .on_packet(&device, &segment(CLIENT_PORT, 443, ack(0)), clock.now())
+        .unwrap();
+    stack
+        .connection_mut(&info)
+        .unwrap()
+        .send(&device, b"response")
+        .unwrap();
+
+    let request: Vec<u8> = data_segment(CLIENT_PORT, 443, ack(8), &[1; 20]);
+    stack.on_packet(&device, &request, clock.now()).unwrap();
+    let tcb = stack.connection(&info).unwrap();
+    assert_eq!(tcb.unacked_len(), 0);
+    assert_eq!(tcb.readable_len(), 0);
+    let stats = stack.listeners().get(443).unwrap().stats();
+    assert_eq!(stats.bytes_in, 0);
+    assert_eq!(stats.bytes_throttled, 20);
+
+    let request: Vec<u8> = data_segment(CLIENT_PORT, 443, ack(8), &[1; 10]);
+    stack.on_packet(&device, &request, clock.now()).unwrap();
+    clock.advance(Duration::from_secs(1));
+    let request: Vec<u8> = data_segment(
+        CLIENT_PORT,
+        443,
+        |header| {
+            ack(8)(header);
+            header.sequence_number += 10;
+        },
+        &[2; 10],
+    );
+    stack.on_packet(&device, &request, clock.now()).unwrap();
+    assert_eq!(stack.connection(&info).unwrap().readable_len(), 20);
+}
@@ -0,0 +1,238 @@
+//! Two stacks talking over an in-memory link, covering the handshake, data transfer
+//! and teardown end to end.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Instant,
+};
+
+use tcp_rs::{
+    device::{LoopbackDevice, NetworkDevice},
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    PACKET_BUF_SIZE,
+};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 40000);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 443);
+
+/// Most packets exchanged by `run` before it gives up on the link going quiet
+const MAX_PACKETS: usize = 10_000;
+
+/// A stack and its end of the link
+struct Host {
+    device: LoopbackDevice,
+    stack: Stack,
+}
+
+impl Host {
+    fn new(device: LoopbackDevice, listeners: Listeners) -> Self {
+        Host {
+            device,
+            stack: Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now()),
+        }
+    }
+
+    /// Process every packet waiting on this end, returning how many there were
+    fn receive(&mut self) -> usize {
+        let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+        let mut n_packets: usize = 0;
+
+        while let Ok(n_bytes) = self.device.recv(&mut buf) {
+            self.stack
+                .on_packet(&self.device, &buf[..n_bytes], Instant::now())
+                .unwrap();
+            n_packets += 1;
+        }
+
+        n_packets
+    }
+
+    fn state(&self, info: &ConnectInfo) -> Option<State> {
+        self.stack.connection(info).map(|tcb| tcb.state())
+    }
+
+    /// Read everything received on the connection so far
+    fn read_all(&mut self, info: &ConnectInfo) -> Vec<u8> {
+        let tcb = self.stack.connection_mut(info).unwrap();
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf: [u8; 4096] = [0; 4096];
+
+        loop {
+            let n_read: usize = tcb.read(&mut buf);
+            if n_read == 0 {
+                return received;
+            }
+            received.extend_from_slice(&buf[..n_read]);
+        }
+    }
+}
+
+/// A client and a server listening on [`SERVER`], before anything has been sent
+fn hosts() -> (Host, Host) {
+    let (client_device, server_device) = LoopbackDevice::pair();
+
+    let mut listeners = Listeners::default();
+    listeners.insert(SERVER.port(), ListenerLimits::default());
+
+    (
+        Host::new(client_device, Listeners::default()),
+        Host::new(server_device, listeners),
+    )
+}
+
+/// Deliver packets both ways until neither side has anything more to send
+fn run(a: &mut Host, b: &mut Host) {
+    let mut n_packets: usize = 0;
+
+    loop {
+        let n_delivered: usize = a.receive() + b.receive();
+        if n_delivered == 0 {
+            return;
+        }
+
+        n_packets += n_delivered;
+        assert!(n_packets < MAX_PACKETS, "the link never went quiet");
+    }
+}
+
+/// Open a connection from the client to the server, returning its 4-tuple as seen by
+/// each side
+fn establish(client: &mut Host, server: &mut Host) -> (ConnectInfo, ConnectInfo) {
+    let client_info: ConnectInfo = client
+        .stack
+        .connect(&client.device, CLIENT, SERVER)
+        .unwrap();
+    run(client, server);
+
+    let server_info = ConnectInfo {
+        src_addr: *CLIENT.ip(),
+        src_port: CLIENT.port(),
+        dst_addr: *SERVER.ip(),
+        dst_port: SERVER.port(),
+    };
+
+    assert_eq!(client.state(&client_info), Some(State::Estab));
+    assert_eq!(server.state(&server_info), Some(State::Estab));
+
+    (client_info, server_info)
+}
+
+#[test]
+fn handshake_completes() {
+    let (mut client, mut server) = hosts();
+
+    establish(&mut client, &mut server);
+
+    assert_eq!(server.stack.stats().connections_accepted, 1);
+}
+
+#[test]
+fn data_flows_both_ways() {
+    let (mut client, mut server) = hosts();
+    let (client_info, server_info) = establish(&mut client, &mut server);
+
+    let tcb = client.stack.connection_mut(&client_info).unwrap();
+    tcb.send(&client.device, b"ping").unwrap();
+    run(&mut client, &mut server);
+    assert_eq!(server.read_all(&server_info), b"ping");
+
+    let tcb = server.stack.connection_mut(&server_info).unwrap();
+    tcb.send(&server.device, b"pong").unwrap();
+    run(&mut client, &mut server);
+    assert_eq!(client.read_all(&client_info), b"pong");
+
+    let tcb = client.stack.connection(&client_info).unwrap();
+    assert_eq!(tcb.unacked_len(), 0);
+}
+
+/// More data than fits in either the send buffer or the receive window, so it only
+/// gets through as the receiver reads and the sender's zero window probes find the
+/// window open again
+#[test]
+fn bulk_transfer_through_small_windows() {
+    let (mut client, mut server) = hosts();
+    let (client_info, server_info) = establish(&mut client, &mut server);
+
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let mut n_sent: usize = 0;
+    let mut received: Vec<u8> = Vec::new();
+
+    for _ in 0..MAX_PACKETS {
+        if received.len() == data.len() {
+            break;
+        }
+
+        let tcb = client.stack.connection_mut(&client_info).unwrap();
+        n_sent += tcb.send(&client.device, &data[n_sent..]).unwrap();
+        run(&mut client, &mut server);
+        received.extend(server.read_all(&server_info));
+
+        // The next probe is answered with the window the read opened
+        if let Some(deadline) = client.stack.next_deadline() {
+            client.stack.on_tick(&client.device, deadline).unwrap();
+            run(&mut client, &mut server);
+        }
+    }
+
+    assert_eq!(received, data);
+}
+
+#[test]
+fn lost_segment_is_retransmitted() {
+    let (mut client, mut server) = hosts();
+    let (client_info, server_info) = establish(&mut client, &mut server);
+
+    let tcb = client.stack.connection_mut(&client_info).unwrap();
+    tcb.send(&client.device, b"hello").unwrap();
+    assert_eq!(server.device.take_pending().len(), 1);
+
+    let deadline: Instant = client.stack.next_deadline().unwrap();
+    client.stack.on_tick(&client.device, deadline).unwrap();
+    run(&mut client, &mut server);
+
+    assert_eq!(server.read_all(&server_info), b"hello");
+    let tcb = client.stack.connection(&client_info).unwrap();
+    assert_eq!(tcb.unacked_len(), 0);
+}
+
+/// RFC 9293 Section 3.6, the side closing first waits in TIME-WAIT while the other
+/// side's connection is deleted as soon as its FIN is acknowledged
+#[test]
+fn both_sides_close() {
+    let (mut client, mut server) = hosts();
+    let (client_info, server_info) = establish(&mut client, &mut server);
+
+    client.stack.close(&client.device, &client_info).unwrap();
+    run(&mut client, &mut server);
+    assert_eq!(client.state(&client_info), Some(State::FinWait2));
+    assert_eq!(server.state(&server_info), Some(State::CloseWait));
+
+    server.stack.close(&server.device, &server_info).unwrap();
+    run(&mut client, &mut server);
+    assert_eq!(client.state(&client_info), Some(State::TimeWait));
+    assert_eq!(server.state(&server_info), Some(State::Closed));
+
+    server
+        .stack
+        .on_tick(&server.device, Instant::now())
+        .unwrap();
+    assert_eq!(server.stack.connections().count(), 0);
+}
+
+#[test]
+fn connecting_to_a_closed_port_is_reset() {
+    let (mut client, mut server) = hosts();
+    let closed_port = SocketAddrV4::new(*SERVER.ip(), 80);
+
+    let info: ConnectInfo = client
+        .stack
+        .connect(&client.device, CLIENT, closed_port)
+        .unwrap();
+    run(&mut client, &mut server);
+
+    assert_eq!(client.state(&info), Some(State::Closed));
+    assert_eq!(server.stack.stats().segments_to_closed_ports, 1);
+}
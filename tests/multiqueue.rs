@@ -0,0 +1,162 @@
+//! A stack for each queue of a multi-queue device, driven over socket pairs standing in for
+//! the queues
+#![cfg(unix)]
+
+use std::{
+    io,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    multiqueue::MultiQueueStack,
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+};
+
+mod common;
+
+use common::{connection, segment, tcp_header};
+
+const LISTEN_PORT: u16 = 443;
+const N_QUEUES: usize = 2;
+
+/// One end of a datagram socket pair, each datagram being one IP packet
+struct SocketDevice(UnixDatagram);
+
+impl NetworkDevice for SocketDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+}
+
+impl AsRawFd for SocketDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// The stack, and the peer's end of each queue
+fn multiqueue_stack() -> (MultiQueueStack<SocketDevice>, Vec<UnixDatagram>) {
+    let mut queues: Vec<SocketDevice> = Vec::new();
+    let mut peers: Vec<UnixDatagram> = Vec::new();
+    for _ in 0..N_QUEUES {
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        queues.push(SocketDevice(ours));
+        peers.push(theirs);
+    }
+
+    let stack = MultiQueueStack::spawn(
+        queues,
+        || {
+            let mut listeners = Listeners::default();
+            listeners.insert(LISTEN_PORT, ListenerLimits::default());
+            Ok(Stack::new(
+                listeners,
+                IsnGenerator::new([1; 16]),
+                Instant::now(),
+            ))
+        },
+        |_: &SocketDevice, _: &mut Stack| Ok(()),
+    )
+    .unwrap();
+
+    (stack, peers)
+}
+
+/// Send a SYN from `src_port` on `peer`'s queue, returning the SYN,ACK's sequence number
+fn open(peer: &UnixDatagram, src_port: u16) -> u32 {
+    peer.send(&segment(src_port, LISTEN_PORT, |header| header.syn = true))
+        .unwrap();
+
+    let mut buf: [u8; 2048] = [0; 2048];
+    let n_bytes: usize = peer.recv(&mut buf).unwrap();
+    let tcp_header = tcp_header(&buf[..n_bytes]);
+    assert!(tcp_header.syn() && tcp_header.ack());
+    tcp_header.sequence_number()
+}
+
+/// Wait for the connection to reach `state` in the stack of queue `index`
+fn wait_for_state(
+    stack: &MultiQueueStack<SocketDevice>,
+    index: usize,
+    info: &ConnectInfo,
+    state: State,
+) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while stack.queue(index).connection(info).map(|tcb| tcb.state()) != Some(state) {
+        assert!(Instant::now() < deadline, "never reached {state:?}");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn connections_live_in_the_queue_their_syn_arrived_on() {
+    let (stack, peers) = multiqueue_stack();
+
+    open(&peers[1], 40000);
+    open(&peers[0], 40001);
+
+    assert_eq!(stack.queue_of(&connection(40000, LISTEN_PORT)), Some(1));
+    assert_eq!(stack.queue_of(&connection(40001, LISTEN_PORT)), Some(0));
+    assert_eq!(stack.connection_summaries().len(), 2);
+}
+
+#[test]
+fn segments_on_another_queue_reach_the_owning_stack() {
+    let (stack, peers) = multiqueue_stack();
+    let iss: u32 = open(&peers[0], 40000);
+
+    // The handshake is completed through the other queue
+    peers[1]
+        .send(&segment(40000, LISTEN_PORT, |header| {
+            header.sequence_number = 101;
+            header.ack = true;
+            header.acknowledgment_number = iss.wrapping_add(1);
+        }))
+        .unwrap();
+
+    let info: ConnectInfo = connection(40000, LISTEN_PORT);
+    wait_for_state(&stack, 0, &info, State::Estab);
+    assert!(stack.queue(1).connection(&info).is_none());
+}
+
+#[test]
+fn connections_are_aborted_on_their_own_queue() {
+    let (stack, peers) = multiqueue_stack();
+    open(&peers[1], 40000);
+
+    stack.abort(&connection(40000, LISTEN_PORT)).unwrap();
+
+    let mut buf: [u8; 2048] = [0; 2048];
+    let n_bytes: usize = peers[1].recv(&mut buf).unwrap();
+    let tcp_header = tcp_header(&buf[..n_bytes]);
+    assert!(tcp_header.rst());
+    assert!(stack.abort(&connection(40000, LISTEN_PORT)).is_err());
+}
+
+#[test]
+fn zero_queues_is_an_error() {
+    let result = MultiQueueStack::spawn(
+        Vec::<SocketDevice>::new(),
+        || unreachable!(),
+        |_: &SocketDevice, _: &mut Stack| Ok(()),
+    );
+
+    assert!(result.is_err());
+}
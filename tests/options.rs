@@ -0,0 +1,140 @@
+//! Laying out outgoing options within the 40 byte option space
+
+use tcp_rs::options::{
+    self, parse_options, ExperimentalOption, OutgoingOptions, RawOption, KIND_EXPERIMENT_2,
+    KIND_FAST_OPEN, KIND_SACK, KIND_SACK_PERMITTED, KIND_TIMESTAMPS, MAX_OPTIONS_LEN,
+};
+
+fn blocks(n: u32) -> Vec<(u32, u32)> {
+    (0..n).map(|i| (1000 * i, 1000 * i + 500)).collect()
+}
+
+/// Left edges of the SACK blocks in `encoded`
+fn sack_left_edges(encoded: &[u8]) -> Vec<u32> {
+    let sack: RawOption = parse_options(encoded)
+        .find(|option| option.kind == KIND_SACK)
+        .unwrap();
+
+    sack.data
+        .chunks_exact(8)
+        .map(|block| u32::from_be_bytes(block[..4].try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn syn_options_fit() {
+    let options = OutgoingOptions {
+        mss: Some(1460),
+        window_scale: Some(7),
+        sack_permitted: true,
+        timestamps: Some((1, 0)),
+        ..Default::default()
+    };
+
+    let encoded: Vec<u8> = options.encode(MAX_OPTIONS_LEN);
+
+    assert_eq!(encoded.len(), 20);
+    let kinds: Vec<u8> = parse_options(&encoded).map(|option| option.kind).collect();
+    assert_eq!(kinds, [2, 3, KIND_SACK_PERMITTED, KIND_TIMESTAMPS]);
+}
+
+#[test]
+fn syn_ack_fits_a_fast_open_cookie() {
+    let options = OutgoingOptions {
+        mss: Some(1460),
+        sack_permitted: true,
+        timestamps: Some((1, 2)),
+        fast_open_cookie: Some([7; 8]),
+        ..Default::default()
+    };
+
+    let encoded: Vec<u8> = options.encode(MAX_OPTIONS_LEN);
+
+    assert_eq!(encoded.len(), 28);
+    let kinds: Vec<u8> = parse_options(&encoded).map(|option| option.kind).collect();
+    assert_eq!(
+        kinds,
+        [2, KIND_SACK_PERMITTED, KIND_TIMESTAMPS, KIND_FAST_OPEN]
+    );
+    assert_eq!(options::fast_open(&encoded), Some(&[7; 8][..]));
+}
+
+#[test]
+fn four_sack_blocks_fill_the_option_space() {
+    let options = OutgoingOptions {
+        sack_blocks: blocks(4),
+        ..Default::default()
+    };
+
+    let encoded: Vec<u8> = options.encode(MAX_OPTIONS_LEN);
+
+    assert_eq!(encoded.len(), 36);
+    assert_eq!(sack_left_edges(&encoded), [0, 1000, 2000, 3000]);
+}
+
+#[test]
+fn oldest_sack_blocks_are_dropped_first() {
+    let options = OutgoingOptions {
+        sack_blocks: blocks(5),
+        ..Default::default()
+    };
+    assert_eq!(
+        sack_left_edges(&options.encode(MAX_OPTIONS_LEN)),
+        [0, 1000, 2000, 3000]
+    );
+
+    // Timestamps take 12 bytes, leaving room for 3 blocks in the remaining 28
+    let options = OutgoingOptions {
+        timestamps: Some((1, 2)),
+        sack_blocks: blocks(4),
+        ..Default::default()
+    };
+    let encoded: Vec<u8> = options.encode(MAX_OPTIONS_LEN);
+
+    assert_eq!(encoded.len(), MAX_OPTIONS_LEN);
+    assert_eq!(sack_left_edges(&encoded), [0, 1000, 2000]);
+    assert!(parse_options(&encoded).any(|option| option.kind == KIND_TIMESTAMPS));
+}
+
+#[test]
+fn experimental_options_only_use_what_is_left() {
+    let options = OutgoingOptions {
+        timestamps: Some((1, 2)),
+        sack_blocks: blocks(3),
+        experimental: vec![ExperimentalOption {
+            kind: KIND_EXPERIMENT_2,
+            exid: 0x1234,
+            data: Vec::new(),
+        }],
+        ..Default::default()
+    };
+    assert!(!parse_options(&options.encode(MAX_OPTIONS_LEN))
+        .any(|option| option.kind == KIND_EXPERIMENT_2));
+
+    let options = OutgoingOptions {
+        timestamps: Some((1, 2)),
+        sack_blocks: blocks(2),
+        ..options
+    };
+    let encoded: Vec<u8> = options.encode(MAX_OPTIONS_LEN);
+    assert_eq!(encoded.len(), 36);
+    assert!(parse_options(&encoded).any(|option| option.kind == KIND_EXPERIMENT_2));
+}
+
+#[test]
+fn smaller_limit_is_respected_and_word_aligned() {
+    let options = OutgoingOptions {
+        mss: Some(1460),
+        timestamps: Some((1, 2)),
+        sack_blocks: blocks(4),
+        ..Default::default()
+    };
+
+    let encoded: Vec<u8> = options.encode(30);
+
+    // 30 rounds down to 28, MSS and timestamps take 16 leaving room for one block
+    assert_eq!(encoded.len(), 28);
+    assert_eq!(sack_left_edges(&encoded), [0]);
+
+    assert_eq!(options.encode(100), options.encode(MAX_OPTIONS_LEN));
+}
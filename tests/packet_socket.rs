@@ -0,0 +1,164 @@
+//! The BPF filter choosing which frames a packet socket passes to the stack, run with a
+//! small interpreter for the instructions it uses
+#![cfg(target_os = "linux")]
+
+use std::net::Ipv4Addr;
+
+use etherparse::{IpNumber, Ipv4Header, TcpHeader};
+use tcp_rs::{
+    packet_socket::{PacketFilter, MAX_FILTER_PORTS},
+    EtherType,
+};
+
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+
+/// Run a classic BPF program over `frame`, returning how many bytes it accepts
+fn run(program: &[libc::sock_filter], frame: &[u8]) -> u32 {
+    let load = |offset: usize, len: usize| -> Option<u32> {
+        let bytes: &[u8] = frame.get(offset..offset + len)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |value, &byte| value << 8 | byte as u32),
+        )
+    };
+
+    let (mut a, mut x, mut pc): (u32, u32, usize) = (0, 0, 0);
+    loop {
+        let insn = program[pc];
+        let code = insn.code as u32;
+        let k: u32 = insn.k;
+        pc += 1;
+
+        let size: usize = match code & 0x18 {
+            libc::BPF_W => 4,
+            libc::BPF_H => 2,
+            _ => 1,
+        };
+        match code {
+            _ if code == libc::BPF_RET | libc::BPF_K => return k,
+            _ if code & 0x07 == libc::BPF_LD && code & 0xe0 == libc::BPF_ABS => {
+                let Some(value) = load(k as usize, size) else {
+                    return 0;
+                };
+                a = value;
+            }
+            _ if code & 0x07 == libc::BPF_LD && code & 0xe0 == libc::BPF_IND => {
+                let Some(value) = load((x + k) as usize, size) else {
+                    return 0;
+                };
+                a = value;
+            }
+            _ if code == libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH => {
+                let Some(value) = load(k as usize, 1) else {
+                    return 0;
+                };
+                x = (value & 0xf) * 4;
+            }
+            _ if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                pc += if a == k { insn.jt } else { insn.jf } as usize;
+            }
+            _ if code == libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K => {
+                pc += if a & k != 0 { insn.jt } else { insn.jf } as usize;
+            }
+            _ => panic!("unexpected instruction {code:#x}"),
+        }
+    }
+}
+
+fn frame(ether_type: EtherType, payload: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = vec![0; 12];
+    frame.extend_from_slice(&(ether_type as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A TCP segment to `dst`:`port` in an Ethernet frame, with IP options if `long_header`
+fn tcp_frame(dst: Ipv4Addr, port: u16, long_header: bool) -> Vec<u8> {
+    let tcp_header = TcpHeader::new(40000, port, 1, 1024);
+    let mut ip_header = Ipv4Header::new(
+        tcp_header.header_len_u16(),
+        64,
+        IpNumber::TCP,
+        [192, 168, 1, 1],
+        dst.octets(),
+    )
+    .unwrap();
+    if long_header {
+        ip_header.options = [1, 1, 1, 0][..].try_into().unwrap();
+    }
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    tcp_header.write(&mut packet).unwrap();
+    frame(EtherType::Ipv4, &packet)
+}
+
+#[test]
+fn arp_and_our_tcp_and_icmp_are_accepted() {
+    let program = PacketFilter {
+        addr: ADDR,
+        ports: Vec::new(),
+    }
+    .program()
+    .unwrap();
+
+    assert_ne!(run(&program, &frame(EtherType::Arp, &[0; 28])), 0);
+    assert_ne!(run(&program, &tcp_frame(ADDR, 443, false)), 0);
+    assert_eq!(
+        run(
+            &program,
+            &tcp_frame(Ipv4Addr::new(192, 168, 1, 51), 443, false)
+        ),
+        0
+    );
+    assert_eq!(run(&program, &frame(EtherType::Ipv6, &[0; 40])), 0);
+
+    let mut icmp: Vec<u8> = tcp_frame(ADDR, 443, false);
+    icmp[23] = IpNumber::ICMP.0;
+    assert_ne!(run(&program, &icmp), 0);
+    let mut udp: Vec<u8> = tcp_frame(ADDR, 443, false);
+    udp[23] = IpNumber::UDP.0;
+    assert_eq!(run(&program, &udp), 0);
+}
+
+#[test]
+fn ports_are_checked_after_any_ip_options() {
+    let program = PacketFilter {
+        addr: ADDR,
+        ports: vec![80, 443],
+    }
+    .program()
+    .unwrap();
+
+    for long_header in [false, true] {
+        assert_ne!(run(&program, &tcp_frame(ADDR, 80, long_header)), 0);
+        assert_ne!(run(&program, &tcp_frame(ADDR, 443, long_header)), 0);
+        assert_eq!(run(&program, &tcp_frame(ADDR, 22, long_header)), 0);
+    }
+
+    // Later fragments have no TCP header to check
+    let mut fragment: Vec<u8> = tcp_frame(ADDR, 22, false);
+    fragment[14 + 6..14 + 8].copy_from_slice(&100u16.to_be_bytes());
+    assert_ne!(run(&program, &fragment), 0);
+    assert_ne!(run(&program, &frame(EtherType::Arp, &[0; 28])), 0);
+}
+
+#[test]
+fn too_many_ports_are_rejected() {
+    let filter = PacketFilter {
+        addr: ADDR,
+        ports: (1..=MAX_FILTER_PORTS as u16 + 1).collect(),
+    };
+    assert!(filter.program().is_err());
+
+    let filter = PacketFilter {
+        addr: ADDR,
+        ports: (1..=MAX_FILTER_PORTS as u16).collect(),
+    };
+    let program = filter.program().unwrap();
+    assert_ne!(
+        run(&program, &tcp_frame(ADDR, MAX_FILTER_PORTS as u16, false)),
+        0
+    );
+}
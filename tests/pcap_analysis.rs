@@ -0,0 +1,121 @@
+//! Offline handshake analysis of a capture built in memory
+
+use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use tcp_rs::{analyze, tcp::State};
+
+const PEER: [u8; 4] = [192, 168, 0, 1];
+const LOCAL: [u8; 4] = [192, 168, 0, 2];
+const PEER_ISN: u32 = 100;
+
+/// An Ethernet frame carrying a TCP segment from `src` to `dst`
+fn frame(src: [u8; 4], dst: [u8; 4], configure: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+    let (src_port, dst_port): (u16, u16) = if src == PEER {
+        (40000, 443)
+    } else {
+        (443, 40000)
+    };
+
+    let mut tcp_header = TcpHeader::new(src_port, dst_port, 0, 8192);
+    configure(&mut tcp_header);
+
+    let ip_header = Ipv4Header::new(tcp_header.header_len_u16(), 64, 6.into(), src, dst).unwrap();
+    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+
+    let mut frame: Vec<u8> = vec![0; 12];
+    frame.extend_from_slice(&0x0800u16.to_be_bytes());
+    ip_header.write(&mut frame).unwrap();
+    tcp_header.write(&mut frame).unwrap();
+    frame
+}
+
+/// A little endian, microsecond pcap file with an Ethernet link type
+fn pcap(frames: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut file: Vec<u8> = Vec::new();
+    file.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&4u16.to_le_bytes());
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65535u32.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+
+    for (micros, frame) in frames {
+        file.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        file.extend_from_slice(&micros.to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(frame);
+    }
+
+    file
+}
+
+#[test]
+fn handshake_then_reset_is_replayed() {
+    let mut arp: Vec<u8> = vec![0; 12];
+    arp.extend_from_slice(&0x0806u16.to_be_bytes());
+    arp.extend_from_slice(&[0; 28]);
+
+    let file: Vec<u8> = pcap(&[
+        (0, arp),
+        (
+            10,
+            frame(PEER, LOCAL, |tcp| {
+                tcp.sequence_number = PEER_ISN;
+                tcp.syn = true;
+            }),
+        ),
+        // The captured endpoint's own SYN-ACK, which the analysis ignores
+        (
+            20,
+            frame(LOCAL, PEER, |tcp| {
+                tcp.sequence_number = 5000;
+                tcp.acknowledgment_number = PEER_ISN + 1;
+                tcp.syn = true;
+                tcp.ack = true;
+            }),
+        ),
+        (
+            30,
+            frame(PEER, LOCAL, |tcp| {
+                tcp.sequence_number = PEER_ISN + 1;
+                tcp.rst = true;
+            }),
+        ),
+    ]);
+
+    let analysis = analyze::analyze(&file[..]).unwrap();
+
+    assert_eq!(analysis.local_addr, Some(LOCAL.into()));
+    assert_eq!(analysis.skipped, 2);
+    assert_eq!(analysis.steps.len(), 2);
+
+    let syn = &analysis.steps[0];
+    assert_eq!(syn.packet_number, 2);
+    assert_eq!(syn.state_before, None);
+    assert_eq!(syn.state_after, Some(State::SynRcvd));
+    assert_eq!(syn.sent.len(), 1);
+
+    let syn_ack = Ipv4HeaderSlice::from_slice(&syn.sent[0]).unwrap();
+    assert_eq!(syn_ack.destination(), PEER);
+    let syn_ack = TcpHeaderSlice::from_slice(&syn.sent[0][syn_ack.slice().len()..]).unwrap();
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.acknowledgment_number(), PEER_ISN + 1);
+
+    let rst = &analysis.steps[1];
+    assert_eq!(rst.packet_number, 4);
+    assert_eq!(rst.state_before, Some(State::SynRcvd));
+    assert_eq!(rst.state_after, Some(State::Closed));
+    assert!(rst.sent.is_empty());
+
+    let report: String = analysis.to_string();
+    assert!(report.contains("would send 192.168.0.2:443 > 192.168.0.1:40000 [S.]"));
+}
+
+#[test]
+fn pcapng_is_rejected() {
+    let mut file: Vec<u8> = 0x0a0d_0d0au32.to_le_bytes().to_vec();
+    file.extend_from_slice(&[0; 20]);
+
+    let err = analyze::analyze(&file[..]).err().unwrap();
+    assert!(err.to_string().contains("pcapng"));
+}
@@ -0,0 +1,248 @@
+//! Path MTU discovery, RFC 1191
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{
+    IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice, TcpOptionElement,
+};
+use tcp_rs::{
+    clock,
+    device::{CaptureDevice, NetworkDevice},
+    icmp::IcmpError,
+    isn::IsnGenerator,
+    listener::Listeners,
+    options,
+    pmtu::{self, PathMtu, MIN_PATH_MTU, PATH_MTU_AGING},
+    stack::Stack,
+    tcp::{State, Tcb, DEFAULT_MSS, MIN_MSS},
+    ETH_MTU,
+};
+
+/// A connection accepted from a SYN carrying `mss`, along with its SYN,ACK
+fn accepted_connection(mss: Option<u16>) -> (Tcb, Vec<u8>) {
+    let mut syn = TcpHeader::new(40000, 443, 100, 8192);
+    syn.syn = true;
+    if let Some(mss) = mss {
+        syn.set_options(&[TcpOptionElement::MaximumSegmentSize(mss)])
+            .unwrap();
+    }
+
+    let ip_header = Ipv4Header::new(
+        syn.header_len_u16(),
+        64,
+        IpNumber::TCP,
+        [192, 168, 0, 1],
+        [192, 168, 0, 2],
+    )
+    .unwrap();
+    syn.checksum = syn.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    syn.write(&mut packet).unwrap();
+
+    let ip_slice = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+    let tcp_slice = TcpHeaderSlice::from_slice(&packet[ip_slice.slice().len()..]).unwrap();
+
+    let device = CaptureDevice::default();
+    let tcb = Tcb::accept_connection(
+        &device,
+        ip_slice,
+        tcp_slice,
+        &[],
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap()
+    .unwrap();
+
+    (tcb, device.take_sent().remove(0))
+}
+
+fn sequence_number(packet: &[u8]) -> u32 {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..])
+        .unwrap()
+        .sequence_number()
+}
+
+#[test]
+fn segments_are_sent_with_dont_fragment() {
+    let (_, syn_ack) = accepted_connection(None);
+
+    assert!(Ipv4HeaderSlice::from_slice(&syn_ack)
+        .unwrap()
+        .dont_fragment());
+}
+
+/// RFC 1122 Section 4.2.2.6
+#[test]
+fn effective_mss_is_limited_by_peer_and_path() {
+    let (tcb, _) = accepted_connection(None);
+    assert_eq!(tcb.effective_mss(), DEFAULT_MSS as usize);
+
+    let (mut tcb, syn_ack) = accepted_connection(Some(1460));
+    assert_eq!(tcb.effective_mss(), 1460);
+
+    tcb.on_icmp_error(
+        sequence_number(&syn_ack),
+        IcmpError::FragmentationNeeded { next_hop_mtu: 1400 },
+    );
+
+    assert_eq!(tcb.path_mtu(), 1400);
+    assert_eq!(tcb.effective_mss(), 1360);
+}
+
+/// A SYN,ACK with an MSS option too small for the timestamps option used to leave no room
+/// for data, or less than none
+#[test]
+fn tiny_mss_from_the_peer_is_raised_to_the_minimum() {
+    for mss in [0, 1, 12] {
+        let device = CaptureDevice::default();
+        let mut stack = Stack::new(
+            Listeners::default(),
+            IsnGenerator::new([1; 16]),
+            Instant::now(),
+        );
+        let local = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 40000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 443);
+        let info = stack.connect(&device, local, remote).unwrap();
+
+        let syn: Vec<u8> = device.take_sent().remove(0);
+        let syn_ip = Ipv4HeaderSlice::from_slice(&syn).unwrap();
+        let syn_tcp = TcpHeaderSlice::from_slice(&syn[syn_ip.slice().len()..]).unwrap();
+        let (ts_val, _) = options::timestamps(syn_tcp.options()).unwrap();
+
+        let mut syn_ack = TcpHeader::new(443, 40000, 5000, 8192);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        syn_ack.acknowledgment_number = syn_tcp.sequence_number().wrapping_add(1);
+        syn_ack
+            .set_options(&[
+                TcpOptionElement::MaximumSegmentSize(mss),
+                TcpOptionElement::Noop,
+                TcpOptionElement::Noop,
+                TcpOptionElement::Timestamp(1, ts_val),
+            ])
+            .unwrap();
+        let ip_header = Ipv4Header::new(
+            syn_ack.header_len_u16(),
+            64,
+            IpNumber::TCP,
+            [192, 168, 0, 1],
+            [192, 168, 0, 2],
+        )
+        .unwrap();
+        syn_ack.checksum = syn_ack.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+        let mut packet: Vec<u8> = Vec::new();
+        ip_header.write(&mut packet).unwrap();
+        syn_ack.write(&mut packet).unwrap();
+
+        stack.on_packet(&device, &packet, Instant::now()).unwrap();
+        device.take_sent();
+
+        let tcb = stack.connection_mut(&info).unwrap();
+        assert_eq!(tcb.state(), State::Estab);
+        assert_eq!(
+            tcb.effective_mss(),
+            MIN_MSS as usize - options::TIMESTAMPS_LEN
+        );
+
+        tcb.send(&device, &[0; 200]).unwrap();
+        let sent: Vec<Vec<u8>> = device.take_sent();
+        assert!(!sent.is_empty());
+        for packet in sent {
+            let ip_header = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+            let tcp_header =
+                TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+            let payload: usize = packet.len() - ip_header.slice().len() - tcp_header.slice().len();
+            assert!(payload > 0 && payload <= tcb.effective_mss());
+        }
+    }
+}
+
+#[test]
+fn plateaus_are_strictly_below() {
+    assert_eq!(pmtu::plateau_below(ETH_MTU), 1492);
+    assert_eq!(pmtu::plateau_below(1492), 1006);
+    assert_eq!(pmtu::plateau_below(100), MIN_PATH_MTU);
+    assert_eq!(pmtu::plateau_below(MIN_PATH_MTU), MIN_PATH_MTU);
+}
+
+/// RFC 1191 Section 5, routers which don't report an MTU
+#[test]
+fn missing_next_hop_mtu_drops_to_next_plateau() {
+    let now = Instant::now();
+    let mut path_mtu = PathMtu::new(ETH_MTU);
+
+    assert!(path_mtu.on_fragmentation_needed(0, now));
+    assert_eq!(path_mtu.mtu(), 1492);
+
+    assert!(path_mtu.on_fragmentation_needed(0, now));
+    assert_eq!(path_mtu.mtu(), 1006);
+}
+
+#[test]
+fn black_hole_walks_down_the_plateaus() {
+    let now = Instant::now();
+    let mut path_mtu = PathMtu::new(ETH_MTU);
+
+    let mut mtus: Vec<usize> = Vec::new();
+    while path_mtu.on_black_hole(now) {
+        mtus.push(path_mtu.mtu());
+    }
+
+    assert_eq!(mtus, [1492, 1006, 508, 296, 68]);
+    assert_eq!(path_mtu.mss(), 28);
+}
+
+/// RFC 1191 Section 6.3
+#[test]
+fn lowered_path_mtu_ages_out() {
+    let now = Instant::now();
+    let mut path_mtu = PathMtu::new(ETH_MTU);
+    path_mtu.on_fragmentation_needed(1280, now);
+
+    path_mtu.expire(now + PATH_MTU_AGING - Duration::from_secs(1));
+    assert_eq!(path_mtu.mtu(), 1280);
+
+    path_mtu.expire(now + PATH_MTU_AGING);
+    assert_eq!(path_mtu.mtu(), ETH_MTU);
+}
+
+/// A device with a smaller MTU than Ethernet, as a tunnel might have
+struct TunnelDevice(CaptureDevice);
+
+impl NetworkDevice for TunnelDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        1280
+    }
+}
+
+#[test]
+fn path_mtu_starts_from_the_device_mtu() {
+    let device = TunnelDevice(CaptureDevice::default());
+    let tcb = Tcb::connect(
+        &device,
+        "192.168.0.2:50000".parse().unwrap(),
+        "192.168.0.1:443".parse().unwrap(),
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap();
+
+    assert_eq!(tcb.path_mtu(), 1280);
+    assert_eq!(CaptureDevice::default().mtu(), ETH_MTU);
+}
@@ -0,0 +1,240 @@
+//! Waiting for readiness across every connection
+
+use tcp_rs::{
+    clock::Clock,
+    listener::ListenerLimits,
+    poll::Event,
+    stack::Stack,
+    testing::{Connection, Side, Wan},
+};
+
+/// Both stacks have started polling, and nothing has happened yet
+fn polling() -> Wan {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    assert_eq!(events(&mut wan.client.stack), []);
+    assert_eq!(events(&mut wan.server.stack), []);
+    wan
+}
+
+fn events(stack: &mut Stack) -> Vec<Event> {
+    let mut events: Vec<Event> = Vec::new();
+    stack.poll_events(&mut events);
+    events
+}
+
+fn event(connection: Connection, side: Side) -> Event {
+    Event {
+        connection: connection.info(side),
+        readable: false,
+        writable: false,
+        accepted: false,
+        closed: false,
+        reset: false,
+    }
+}
+
+#[test]
+fn connections_are_reported_once_established() {
+    let mut wan = polling();
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let accepted = Event {
+        accepted: true,
+        writable: true,
+        ..event(connection, Side::Server)
+    };
+    assert_eq!(events(&mut wan.server.stack), [accepted]);
+    let connected = Event {
+        writable: true,
+        ..event(connection, Side::Client)
+    };
+    assert_eq!(events(&mut wan.client.stack), [connected]);
+
+    assert_eq!(events(&mut wan.server.stack), []);
+    assert_eq!(events(&mut wan.client.stack), []);
+}
+
+#[test]
+fn readable_is_reported_again_once_everything_was_read() {
+    let mut wan = polling();
+    let connection = wan.connect(40000, 443).unwrap();
+    events(&mut wan.server.stack);
+
+    let send = |wan: &mut Wan| {
+        let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+        client.send(&wan.client.device, b"hello").unwrap();
+        wan.run_until_idle().unwrap();
+    };
+    let readable = Event {
+        readable: true,
+        ..event(connection, Side::Server)
+    };
+
+    send(&mut wan);
+    assert_eq!(events(&mut wan.server.stack), [readable]);
+    send(&mut wan);
+    assert_eq!(events(&mut wan.server.stack), []);
+
+    assert_eq!(wan.server.read_all(&connection.server), b"hellohello");
+    assert_eq!(events(&mut wan.server.stack), []);
+    send(&mut wan);
+    assert_eq!(events(&mut wan.server.stack), [readable]);
+}
+
+#[test]
+fn writable_is_reported_again_once_the_send_buffer_drains() {
+    let mut wan = polling();
+    let connection = wan.connect(40000, 443).unwrap();
+    events(&mut wan.client.stack);
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    while client.send(&wan.client.device, &[1; 4096]).unwrap() > 0 {}
+    assert_eq!(events(&mut wan.client.stack), []);
+
+    wan.run_until_idle().unwrap();
+    wan.server.read_all(&connection.server);
+    wan.run_until_idle().unwrap();
+
+    let writable = Event {
+        writable: true,
+        ..event(connection, Side::Client)
+    };
+    assert_eq!(events(&mut wan.client.stack), [writable]);
+}
+
+#[test]
+fn peer_closing_is_readable_then_deletion_is_reported() {
+    let mut wan = polling();
+    let connection = wan.connect(40000, 443).unwrap();
+    events(&mut wan.server.stack);
+
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+    let end_of_stream = Event {
+        readable: true,
+        ..event(connection, Side::Server)
+    };
+    assert_eq!(events(&mut wan.server.stack), [end_of_stream]);
+
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    wan.server
+        .stack
+        .on_tick(&wan.server.device, wan.clock.now())
+        .unwrap();
+    let closed = Event {
+        closed: true,
+        ..event(connection, Side::Server)
+    };
+    assert_eq!(events(&mut wan.server.stack), [closed]);
+}
+
+#[test]
+fn refused_connections_are_reported_as_reset() {
+    let mut wan = polling();
+    let connection = wan.open(40000, 80).unwrap();
+    wan.run_until_idle().unwrap();
+    wan.client
+        .stack
+        .on_tick(&wan.client.device, wan.clock.now())
+        .unwrap();
+
+    let refused = Event {
+        closed: true,
+        reset: true,
+        ..event(connection, Side::Client)
+    };
+    assert_eq!(events(&mut wan.client.stack), [refused]);
+}
+
+#[cfg(unix)]
+mod blocking {
+    use std::{
+        io,
+        os::{
+            fd::{AsRawFd, RawFd},
+            unix::net::UnixDatagram,
+        },
+        time::{Duration, Instant},
+    };
+
+    use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+    use tcp_rs::{
+        device::NetworkDevice,
+        isn::IsnGenerator,
+        listener::{ListenerLimits, Listeners},
+        poll::Event,
+        stack::Stack,
+    };
+
+    /// One end of a datagram socket pair, each datagram being one IP packet
+    struct SocketDevice(UnixDatagram);
+
+    impl NetworkDevice for SocketDevice {
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+    }
+
+    impl AsRawFd for SocketDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    fn syn() -> Vec<u8> {
+        let mut tcp_header = TcpHeader::new(40000, 443, 100, 8192);
+        tcp_header.syn = true;
+        let ip_header = Ipv4Header::new(
+            tcp_header.header_len_u16(),
+            64,
+            IpNumber::TCP,
+            [192, 168, 0, 1],
+            [192, 168, 0, 2],
+        )
+        .unwrap();
+        tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+
+        let mut packet: Vec<u8> = Vec::new();
+        ip_header.write(&mut packet).unwrap();
+        tcp_header.write(&mut packet).unwrap();
+        packet
+    }
+
+    /// Packets are handled while waiting, and nothing to report waits out the timeout
+    #[test]
+    fn poll_receives_packets_until_the_timeout() {
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let nic = SocketDevice(ours);
+
+        let mut listeners = Listeners::default();
+        listeners.insert(443, ListenerLimits::default());
+        let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now());
+
+        theirs.send(&syn()).unwrap();
+        let start = Instant::now();
+        let mut events: Vec<Event> = Vec::new();
+        stack
+            .poll(&nic, &mut events, Some(Duration::from_millis(50)))
+            .unwrap();
+
+        assert!(events.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut buf: [u8; 2048] = [0; 2048];
+        let n_bytes: usize = theirs.recv(&mut buf).unwrap();
+        let ip_header = Ipv4HeaderSlice::from_slice(&buf[..n_bytes]).unwrap();
+        let tcp_header =
+            TcpHeaderSlice::from_slice(&buf[ip_header.slice().len()..n_bytes]).unwrap();
+        assert!(tcp_header.syn() && tcp_header.ack());
+    }
+}
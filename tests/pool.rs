@@ -0,0 +1,74 @@
+//! Packet buffers reused rather than allocated for every packet
+
+use std::io::IoSlice;
+
+use tcp_rs::{
+    listener::ListenerLimits,
+    pool::{PacketBuf, PacketPool},
+    testing::{Side, Wan},
+};
+
+#[test]
+fn dropped_buffers_are_reused() {
+    let pool = PacketPool::new(64, 8);
+
+    let packet: PacketBuf = pool.copy(&[1; 10]);
+    assert_eq!(&packet[..], &[1; 10]);
+    assert_eq!(packet.capacity(), 64);
+    drop(packet);
+    assert_eq!(pool.idle(), 1);
+
+    let packet: PacketBuf = pool.copy(&[2; 20]);
+    assert_eq!(&packet[..], &[2; 20]);
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(pool.allocated(), 1);
+}
+
+#[test]
+fn pieces_are_gathered_in_order() {
+    let pool = PacketPool::new(64, 8);
+
+    let packet: PacketBuf = pool.gather(&[
+        IoSlice::new(&[1; 20]),
+        IoSlice::new(&[]),
+        IoSlice::new(&[2; 10]),
+    ]);
+
+    assert_eq!(&packet[..], [[1; 20].as_slice(), &[2; 10]].concat());
+}
+
+#[test]
+fn oversized_packets_are_not_pooled() {
+    let pool = PacketPool::new(64, 8);
+
+    let packet: PacketBuf = pool.copy(&[1; 100]);
+    assert_eq!(packet.len(), 100);
+    drop(packet);
+
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(pool.allocated(), 1);
+}
+
+#[test]
+fn idle_buffers_are_capped() {
+    let pool = PacketPool::new(64, 2);
+
+    let packets: Vec<PacketBuf> = (0..3).map(|_| pool.take(64)).collect();
+    drop(packets);
+
+    assert_eq!(pool.idle(), 2);
+    assert_eq!(pool.allocated(), 3);
+}
+
+/// Only packets in flight hold a buffer, however much is sent
+#[test]
+fn transfer_allocates_for_packets_in_flight_only() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+
+    assert!(PacketPool::shared().allocated() < 64);
+}
@@ -0,0 +1,152 @@
+//! Local ports owned by listeners and by the connections we open
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    clock::Clock,
+    device::CaptureDevice,
+    error::TcpError,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners, TimeWaitPolicy},
+    ports::{PortState, PortTable},
+    stack::Stack,
+    tcp::State,
+    testing::{Side, Wan, CLIENT_PORT},
+};
+
+mod common;
+
+use common::{syn, tcp_header};
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 9), 80);
+
+#[test]
+fn ports_are_freed_after_their_last_connection() {
+    let mut ports = PortTable::default();
+    ports.bind(50000);
+    ports.bind(50000);
+
+    ports.release(50000);
+    assert_eq!(ports.connections(50000), 1);
+    ports.release(50000);
+    assert!(!ports.is_connected(50000));
+}
+
+#[test]
+fn ephemeral_ports_skip_those_in_use_and_run_out() {
+    let device = CaptureDevice::default();
+    let mut listeners = Listeners::default();
+    listeners.insert(50001, ListenerLimits::default());
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now());
+    stack.set_ephemeral_ports(50000..=50002);
+
+    let any_port = SocketAddrV4::new(LOCAL_ADDR, 0);
+    let first = stack.connect(&device, any_port, REMOTE).unwrap();
+    let second = stack.connect(&device, any_port, REMOTE).unwrap();
+    assert_eq!((first.dst_port, second.dst_port), (50000, 50002));
+    assert!(matches!(
+        stack.connect(&device, any_port, REMOTE),
+        Err(TcpError::InsufficientResources(_))
+    ));
+
+    assert_eq!(stack.port_state(50000), PortState::Connected);
+    assert_eq!(stack.port_state(50001), PortState::Listening);
+    assert_eq!(stack.port_state(50003), PortState::Free);
+}
+
+#[test]
+fn listening_ports_cant_be_connected_from() {
+    let device = CaptureDevice::default();
+    let mut listeners = Listeners::default();
+    listeners.insert(443, ListenerLimits::default());
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now());
+
+    assert!(matches!(
+        stack.connect(&device, SocketAddrV4::new(LOCAL_ADDR, 443), REMOTE),
+        Err(TcpError::AddrInUse(443))
+    ));
+}
+
+/// Listening on every port doesn't include those our own connections are using
+#[test]
+fn syn_to_a_connected_port_is_reset() {
+    let device = CaptureDevice::default();
+    let listeners = Listeners::accept_any(ListenerLimits::default());
+    let mut stack = Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now());
+
+    let info = stack
+        .connect(&device, SocketAddrV4::new(LOCAL_ADDR, 0), REMOTE)
+        .unwrap();
+    device.take_sent();
+
+    stack
+        .on_packet(&device, &syn(CLIENT_PORT, info.dst_port), Instant::now())
+        .unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert!(tcp_header(&sent[0]).rst());
+    assert_eq!(stack.connections().count(), 1);
+    assert_eq!(stack.stats().segments_to_closed_ports, 1);
+
+    // Any other port is listening
+    stack
+        .on_packet(&device, &syn(CLIENT_PORT, 8080), Instant::now())
+        .unwrap();
+    assert!(tcp_header(&device.take_sent()[0]).syn());
+}
+
+#[test]
+fn ephemeral_port_is_released_once_the_connection_is_deleted() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+
+    let connection = wan.connect(0, 443).unwrap();
+    let port: u16 = connection.client.dst_port;
+    assert!(wan.client.stack.ports().ephemeral().contains(&port));
+
+    wan.close(connection, Side::Client).unwrap();
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    assert!(wan.client.stack.ports().is_connected(port));
+
+    // Once through TIME-WAIT
+    wan.run_until(10, |wan| wan.client.stack.connections().count() == 0)
+        .unwrap();
+    assert!(!wan.client.stack.ports().is_connected(port));
+}
+
+/// A 4-tuple in TIME-WAIT can be connected from again once our initial sequence number
+/// has moved past the old connection's, unless that's refused
+#[test]
+fn reconnecting_from_a_port_in_time_wait() {
+    for (policy, reconnects) in [
+        (TimeWaitPolicy::Reopen, true),
+        (TimeWaitPolicy::Refuse, false),
+    ] {
+        let mut wan = Wan::lossless();
+        wan.client
+            .stack
+            .listeners_mut()
+            .set_time_wait_policy(policy);
+        wan.listen(443, ListenerLimits::default());
+
+        let connection = wan.connect(40000, 443).unwrap();
+        wan.close(connection, Side::Client).unwrap();
+        wan.close(connection, Side::Server).unwrap();
+        wan.run_until_idle().unwrap();
+        wan.advance_to(wan.clock.now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(wan.client.state(&connection.client), Some(State::TimeWait));
+
+        assert_eq!(wan.connect(40000, 443).is_ok(), reconnects, "{policy:?}");
+        assert_eq!(
+            wan.client.stack.stats().time_wait_reopened,
+            reconnects as u64
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Relaying between the stack and host sockets. A second stack on the other end of a
+//! socket pair stands in for the peer, and the host side is real sockets on loopback.
+#![cfg(unix)]
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    async_stack::{block_on, AsyncStack, AsyncTcpStream},
+    cli::{Forward, Reverse},
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    proxy::Proxy,
+    stack::Stack,
+    tcat::LOCAL_ADDR,
+};
+
+const PEER_ADDR: [u8; 4] = [192, 168, 0, 1];
+
+/// More than fits in the send buffer, so the relay has to wait for the windows to open
+const N_BYTES: usize = 200_000;
+
+/// Longest any read waits, so a relay which stops moving fails the test instead of hanging it
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One end of a datagram socket pair, each datagram being one IP packet
+struct SocketDevice(UnixDatagram);
+
+impl NetworkDevice for SocketDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+}
+
+impl AsRawFd for SocketDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// The stack the proxy runs on, at [`LOCAL_ADDR`], and the peer's stack at [`PEER_ADDR`]
+fn stacks() -> (AsyncStack<SocketDevice>, AsyncStack<SocketDevice>) {
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    let spawn = |socket: UnixDatagram, key: u8| {
+        socket.set_nonblocking(true).unwrap();
+        let stack = Stack::new(
+            Listeners::default(),
+            IsnGenerator::new([key; 16]),
+            Instant::now(),
+        );
+        AsyncStack::spawn(SocketDevice(socket), stack).unwrap()
+    };
+
+    (spawn(ours, 1), spawn(theirs, 2))
+}
+
+/// Bytes which don't repeat every chunk, so misordering shows up
+fn pattern() -> Vec<u8> {
+    (0..N_BYTES).map(|i| (i % 251) as u8).collect()
+}
+
+/// A host listener echoing back what its first connection sends, closing once it's closed
+fn host_echo_server() -> (SocketAddr, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        io::copy(&mut stream.try_clone().unwrap(), &mut stream).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+    });
+
+    (addr, server)
+}
+
+/// An upstream which refuses connections, the port of one end of a connected pair. Nothing
+/// listens on it, and it can't be taken by anything else while the pair is open.
+fn refusing_upstream() -> ((TcpStream, TcpStream), SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let addr: SocketAddr = client.local_addr().unwrap();
+
+    ((client, server), addr)
+}
+
+/// Open a connection from the peer's stack to `port` on the proxy's
+fn connect(peer_stack: &AsyncStack<SocketDevice>, port: u16) -> AsyncTcpStream<SocketDevice> {
+    block_on(peer_stack.connect_timeout(
+        SocketAddrV4::new(PEER_ADDR.into(), 0),
+        SocketAddrV4::new(LOCAL_ADDR, port),
+        TIMEOUT,
+    ))
+    .unwrap()
+}
+
+/// Write `data` to `stream` and close it, reading everything that comes back meanwhile
+fn exchange(stream: &AsyncTcpStream<SocketDevice>, data: &[u8]) -> Vec<u8> {
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            block_on(stream.write_all(data)).unwrap();
+            block_on(stream.shutdown()).unwrap();
+        });
+
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf: [u8; 4096] = [0; 4096];
+        loop {
+            let n_read: usize = block_on(stream.read_timeout(&mut buf, TIMEOUT)).unwrap();
+            if n_read == 0 {
+                return received;
+            }
+            received.extend_from_slice(&buf[..n_read]);
+        }
+    })
+}
+
+#[test]
+fn forwarded_connections_relay_both_ways_until_both_close() {
+    let (proxy_stack, peer_stack) = stacks();
+    let (upstream, server) = host_echo_server();
+    let _proxy = Proxy::spawn(proxy_stack, &[Forward { port: 80, upstream }], &[]).unwrap();
+    let stream = connect(&peer_stack, 80);
+
+    // The echo server only closes after the peer's FIN has been passed on
+    assert_eq!(exchange(&stream, &pattern()), pattern());
+    server.join().unwrap();
+}
+
+#[test]
+fn forwarded_connections_are_reset_if_the_upstream_refuses() {
+    let (proxy_stack, peer_stack) = stacks();
+    let (_pair, upstream) = refusing_upstream();
+    let _proxy = Proxy::spawn(proxy_stack, &[Forward { port: 80, upstream }], &[]).unwrap();
+
+    // The proxy only tries the upstream once it's accepted the connection, so the
+    // handshake completes and the refusal shows up as a reset afterwards
+    let stream = connect(&peer_stack, 80);
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(
+        block_on(stream.read_timeout(&mut buf, TIMEOUT))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::ConnectionReset
+    );
+    assert!(block_on(stream.write(b"late")).is_err());
+}
+
+#[test]
+fn reverse_connections_are_opened_through_the_stack() {
+    let (proxy_stack, peer_stack) = stacks();
+
+    // The peer echoes like the host server does
+    let listener = peer_stack.listen(22, ListenerLimits::default());
+    let server = thread::spawn(move || {
+        let stream: AsyncTcpStream<SocketDevice> = block_on(listener.accept()).unwrap();
+        let mut buf: [u8; 4096] = [0; 4096];
+        loop {
+            let n_read: usize = block_on(stream.read_timeout(&mut buf, TIMEOUT)).unwrap();
+            if n_read == 0 {
+                block_on(stream.shutdown()).unwrap();
+                return stream.info();
+            }
+            block_on(stream.write_all(&buf[..n_read])).unwrap();
+        }
+    });
+
+    let reverse = Reverse {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        local: LOCAL_ADDR,
+        remote: SocketAddrV4::new(PEER_ADDR.into(), 22),
+    };
+    let proxy = Proxy::spawn(proxy_stack, &[], &[reverse]).unwrap();
+
+    let host = TcpStream::connect(proxy.reverse_addrs()[0]).unwrap();
+    host.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let received = thread::scope(|scope| {
+        scope.spawn(|| {
+            (&host).write_all(&pattern()).unwrap();
+            host.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let mut received: Vec<u8> = Vec::new();
+        (&host).read_to_end(&mut received).unwrap();
+        received
+    });
+
+    assert_eq!(received, pattern());
+    assert_eq!(server.join().unwrap().src_addr, LOCAL_ADDR);
+}
@@ -0,0 +1,99 @@
+//! Setting PSH at the end of each write and delivering pushed data straight away
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{clock::Clock, device::NetworkDevice, listener::ListenerLimits, testing::Wan};
+
+/// Packets the server hasn't received yet, taken off the link
+fn take_in_flight(wan: &Wan) -> Vec<Vec<u8>> {
+    wan.server.device.inner().take_pending()
+}
+
+/// The TCP header and payload length of a packet
+fn segment(packet: &[u8]) -> (TcpHeaderSlice<'_>, usize) {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+    let payload_len: usize = packet.len() - ip_header.slice().len() - tcp_header.slice().len();
+    (tcp_header, payload_len)
+}
+
+/// The length and PSH flag of each segment carrying data
+fn data_segments(packets: &[Vec<u8>]) -> Vec<(usize, bool)> {
+    packets
+        .iter()
+        .map(|packet| segment(packet))
+        .filter(|(_, payload_len)| *payload_len > 0)
+        .map(|(tcp_header, payload_len)| (payload_len, tcp_header.psh()))
+        .collect()
+}
+
+#[test]
+fn segment_holding_the_end_of_a_write_is_pushed() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.open(40000, 443).unwrap();
+
+    // Both writes wait for the handshake, then go out together
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 300]).unwrap();
+    client.send(&wan.client.device, &[2; 300]).unwrap();
+
+    let now = wan.clock.now();
+    wan.server.receive(now).unwrap();
+    wan.client.receive(now).unwrap();
+
+    let mss: usize = wan
+        .client
+        .stack
+        .connection(&connection.client)
+        .unwrap()
+        .effective_mss();
+    assert_eq!(
+        data_segments(&take_in_flight(&wan)),
+        [(mss, true), (600 - mss, true)]
+    );
+}
+
+#[test]
+fn only_the_last_segment_of_a_write_is_pushed() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 1000]).unwrap();
+    let mss: usize = client.effective_mss();
+
+    assert_eq!(
+        data_segments(&take_in_flight(&wan)),
+        [(mss, false), (1000 - mss, true)]
+    );
+}
+
+#[test]
+fn data_waits_for_the_low_water_mark_unless_pushed() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.set_recv_low_water(800);
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 1000]).unwrap();
+    let mss: usize = client.effective_mss();
+
+    // Only the first segment arrives, without PSH
+    let in_flight: Vec<Vec<u8>> = take_in_flight(&wan);
+    wan.client.device.inner().send(&in_flight[0]).unwrap();
+    wan.server.receive(wan.clock.now()).unwrap();
+
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    assert_eq!(server.unread_len(), mss);
+    assert_eq!(server.readable_len(), 0);
+    assert_eq!(server.read(&mut [0; 8000]), 0);
+
+    // The rest of the write ends with PSH, so all of it can be read
+    for packet in &in_flight[1..] {
+        wan.client.device.inner().send(packet).unwrap();
+    }
+    wan.server.receive(wan.clock.now()).unwrap();
+
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    assert_eq!(server.readable_len(), 1000);
+    assert_eq!(server.read(&mut [0; 8000]), 1000);
+}
@@ -0,0 +1,196 @@
+//! IPv4 fragment reassembly, RFC 791 Section 3.2
+
+use std::time::{Duration, Instant};
+
+use etherparse::{IpFragOffset, IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use tcp_rs::{
+    checksum,
+    reassembly::{Reassembler, ReassemblyError, MAX_PENDING_DATAGRAMS, REASSEMBLY_TIMEOUT},
+};
+
+/// A data segment carrying 100 bytes, with IP identification `id`
+fn segment(id: u16) -> Vec<u8> {
+    let payload: Vec<u8> = (0..100).collect();
+
+    let mut tcp_header = TcpHeader::new(40000, 443, 101, 8192);
+    tcp_header.ack = true;
+    tcp_header.acknowledgment_number = 301;
+
+    let mut ip_header = Ipv4Header::new(
+        (tcp_header.header_len() + payload.len()) as u16,
+        64,
+        IpNumber::TCP,
+        [192, 168, 0, 1],
+        [192, 168, 0, 2],
+    )
+    .unwrap();
+    ip_header.identification = id;
+    ip_header.dont_fragment = false;
+    ip_header.header_checksum = ip_header.calc_header_checksum();
+    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, &payload).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    tcp_header.write(&mut packet).unwrap();
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+/// Split `packet` into fragments, each starting at one of `offsets` bytes into its payload
+fn fragment(packet: &[u8], offsets: &[usize]) -> Vec<Vec<u8>> {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let payload: &[u8] = &packet[ip_header.slice().len()..];
+
+    offsets
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end: usize = offsets.get(i + 1).copied().unwrap_or(payload.len());
+
+            let mut header: Ipv4Header = ip_header.to_header();
+            header.fragment_offset = IpFragOffset::try_new((start / 8) as u16).unwrap();
+            header.more_fragments = end < payload.len();
+            header.set_payload_len(end - start).unwrap();
+            header.header_checksum = header.calc_header_checksum();
+
+            let mut fragment: Vec<u8> = Vec::new();
+            header.write(&mut fragment).unwrap();
+            fragment.extend_from_slice(&payload[start..end]);
+            fragment
+        })
+        .collect()
+}
+
+fn push(
+    reassembler: &mut Reassembler,
+    fragment: &[u8],
+    now: Instant,
+) -> Result<Option<Vec<u8>>, ReassemblyError> {
+    let ip_header = Ipv4HeaderSlice::from_slice(fragment).unwrap();
+    reassembler.push(&ip_header, &fragment[ip_header.slice().len()..], now)
+}
+
+fn assert_is_whole_segment(packet: &[u8], original: &[u8]) {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    assert!(!ip_header.is_fragmenting_payload());
+
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+    let data: &[u8] = &packet[ip_header.slice().len() + tcp_header.slice().len()..];
+    assert_eq!(checksum::verify(&ip_header, &tcp_header, data), Ok(()));
+    assert_eq!(packet, original);
+}
+
+#[test]
+fn fragments_are_reassembled_in_any_order() {
+    let original: Vec<u8> = segment(1);
+    let fragments: Vec<Vec<u8>> = fragment(&original, &[0, 40, 80]);
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    assert_eq!(push(&mut reassembler, &fragments[2], now), Ok(None));
+    assert_eq!(push(&mut reassembler, &fragments[0], now), Ok(None));
+    let packet: Vec<u8> = push(&mut reassembler, &fragments[1], now).unwrap().unwrap();
+
+    assert_is_whole_segment(&packet, &original);
+    assert_eq!(reassembler.pending(), 0);
+}
+
+#[test]
+fn interleaved_datagrams_are_kept_apart() {
+    let first: Vec<u8> = segment(1);
+    let second: Vec<u8> = segment(2);
+    let first_fragments: Vec<Vec<u8>> = fragment(&first, &[0, 64]);
+    let second_fragments: Vec<Vec<u8>> = fragment(&second, &[0, 64]);
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    assert_eq!(push(&mut reassembler, &first_fragments[0], now), Ok(None));
+    assert_eq!(push(&mut reassembler, &second_fragments[1], now), Ok(None));
+    assert_eq!(reassembler.pending(), 2);
+
+    let packet: Vec<u8> = push(&mut reassembler, &second_fragments[0], now)
+        .unwrap()
+        .unwrap();
+    assert_is_whole_segment(&packet, &second);
+    assert_eq!(reassembler.pending(), 1);
+}
+
+#[test]
+fn duplicate_fragments_are_ignored() {
+    let original: Vec<u8> = segment(1);
+    let fragments: Vec<Vec<u8>> = fragment(&original, &[0, 64]);
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    assert_eq!(push(&mut reassembler, &fragments[0], now), Ok(None));
+    assert_eq!(push(&mut reassembler, &fragments[0], now), Ok(None));
+    let packet: Vec<u8> = push(&mut reassembler, &fragments[1], now).unwrap().unwrap();
+
+    assert_is_whole_segment(&packet, &original);
+}
+
+#[test]
+fn overlapping_fragments_drop_the_datagram() {
+    let original: Vec<u8> = segment(1);
+    let mut altered: Vec<u8> = original.clone();
+    *altered.last_mut().unwrap() ^= 0xff;
+
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    assert_eq!(
+        push(&mut reassembler, &fragment(&original, &[0, 64])[1], now),
+        Ok(None)
+    );
+    assert_eq!(
+        push(&mut reassembler, &fragment(&altered, &[0, 56])[1], now),
+        Err(ReassemblyError::Overlap)
+    );
+    assert_eq!(reassembler.pending(), 0);
+}
+
+#[test]
+fn incomplete_datagrams_expire() {
+    let fragments: Vec<Vec<u8>> = fragment(&segment(1), &[0, 64]);
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    assert_eq!(push(&mut reassembler, &fragments[0], now), Ok(None));
+    assert_eq!(reassembler.next_deadline(), Some(now + REASSEMBLY_TIMEOUT));
+
+    assert_eq!(
+        reassembler.expire(now + REASSEMBLY_TIMEOUT - Duration::from_millis(1)),
+        0
+    );
+    assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 1);
+    assert_eq!(reassembler.next_deadline(), None);
+
+    // The rest of the datagram turning up late starts again rather than completing it
+    assert_eq!(
+        push(&mut reassembler, &fragments[1], now + REASSEMBLY_TIMEOUT),
+        Ok(None)
+    );
+}
+
+#[test]
+fn pending_datagrams_are_limited() {
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+
+    for id in 0..MAX_PENDING_DATAGRAMS as u16 {
+        let fragments: Vec<Vec<u8>> = fragment(&segment(id), &[0, 64]);
+        assert_eq!(push(&mut reassembler, &fragments[0], now), Ok(None));
+    }
+
+    let fragments: Vec<Vec<u8>> = fragment(&segment(u16::MAX), &[0, 64]);
+    assert_eq!(
+        push(&mut reassembler, &fragments[0], now),
+        Err(ReassemblyError::TooManyDatagrams)
+    );
+
+    // Datagrams already under way can still complete
+    let fragments: Vec<Vec<u8>> = fragment(&segment(0), &[0, 64]);
+    assert!(push(&mut reassembler, &fragments[1], now)
+        .unwrap()
+        .is_some());
+}
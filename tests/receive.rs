@@ -0,0 +1,144 @@
+//! Taking segment text on established connections, RFC 9293 Section 3.10.7.4, along with
+//! the header prediction fast path for it and ACKs coalesced across a batch
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    clock::Clock,
+    testing::{Connection, Side, Wan},
+};
+
+/// Send each of `writes` from the client, taking the segments off the link before the
+/// server sees them
+fn client_segments(wan: &mut Wan, connection: Connection, writes: &[&[u8]]) -> Vec<Vec<u8>> {
+    for data in writes {
+        let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+        client.send(&wan.client.device, data).unwrap();
+    }
+    wan.server.device.inner().take_pending()
+}
+
+/// Hand `packet` to the server, returning the acknowledgement numbers of what it sends back
+fn deliver(wan: &mut Wan, packet: &[u8]) -> Vec<u32> {
+    wan.server
+        .stack
+        .on_packet(&wan.server.device, packet, wan.clock.now())
+        .unwrap();
+    server_acks(wan)
+}
+
+/// The acknowledgement numbers of what the server has sent, taken off the link
+fn server_acks(wan: &Wan) -> Vec<u32> {
+    wan.client
+        .device
+        .inner()
+        .take_pending()
+        .iter()
+        .map(|packet| {
+            let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+            TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..])
+                .unwrap()
+                .acknowledgment_number()
+        })
+        .collect()
+}
+
+fn rcv_nxt(wan: &Wan, connection: Connection) -> u32 {
+    let server = wan.server.stack.connection(&connection.server).unwrap();
+    server.summary().rcv_nxt
+}
+
+#[test]
+fn in_order_data_advances_rcv_nxt_and_is_acknowledged() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let start: u32 = rcv_nxt(&wan, connection);
+
+    let segments: Vec<Vec<u8>> = client_segments(&mut wan, connection, &[b"hello"]);
+    assert_eq!(deliver(&mut wan, &segments[0]), [start.wrapping_add(5)]);
+
+    assert_eq!(rcv_nxt(&wan, connection), start.wrapping_add(5));
+    assert_eq!(wan.server.read_all(&connection.server), b"hello");
+}
+
+#[test]
+fn data_ahead_of_rcv_nxt_is_not_taken() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let start: u32 = rcv_nxt(&wan, connection);
+
+    let segments: Vec<Vec<u8>> = client_segments(&mut wan, connection, &[b"hello", b"world"]);
+    assert_eq!(segments.len(), 2);
+
+    // The gap is reported with an ACK of RCV.NXT, which stays where it was
+    assert_eq!(deliver(&mut wan, &segments[1]), [start]);
+    assert_eq!(rcv_nxt(&wan, connection), start);
+    assert_eq!(wan.server.read_all(&connection.server), b"");
+
+    assert_eq!(deliver(&mut wan, &segments[0]), [start.wrapping_add(5)]);
+    assert_eq!(wan.server.read_all(&connection.server), b"hello");
+}
+
+#[test]
+fn retransmitted_data_is_only_taken_once() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let start: u32 = rcv_nxt(&wan, connection);
+
+    let segments: Vec<Vec<u8>> = client_segments(&mut wan, connection, &[b"hello"]);
+    deliver(&mut wan, &segments[0]);
+    assert_eq!(deliver(&mut wan, &segments[0]), [start.wrapping_add(5)]);
+
+    assert_eq!(rcv_nxt(&wan, connection), start.wrapping_add(5));
+    assert_eq!(wan.server.read_all(&connection.server), b"hello");
+}
+
+#[test]
+fn in_order_data_and_new_acks_are_predicted() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let predicted = |wan: &Wan, side: Side| {
+        let tcb = wan.host(side).stack.connection(&connection.info(side));
+        tcb.unwrap().stats().predicted_segments
+    };
+    // Neither handshake segment arrived in ESTABLISHED
+    assert_eq!(predicted(&wan, Side::Client), 0);
+    assert_eq!(predicted(&wan, Side::Server), 0);
+
+    let segments: Vec<Vec<u8>> = client_segments(&mut wan, connection, &[b"hello", b"world"]);
+    deliver(&mut wan, &segments[1]);
+    assert_eq!(predicted(&wan, Side::Server), 0);
+    deliver(&mut wan, &segments[0]);
+    assert_eq!(predicted(&wan, Side::Server), 1);
+
+    // The retransmission starts before RCV.NXT so takes the full path, the ACK of it doesn't
+    wan.run_until(20, |wan| {
+        let client = wan.client.stack.connection(&connection.client).unwrap();
+        client.unacked_len() == 0
+    })
+    .unwrap();
+    assert_eq!(wan.server.read_all(&connection.server), b"helloworld");
+    assert_eq!(predicted(&wan, Side::Server), 1);
+    assert_eq!(predicted(&wan, Side::Client), 1);
+}
+
+#[test]
+fn batches_acknowledge_every_second_segment() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let start: u32 = rcv_nxt(&wan, connection);
+
+    let segments: Vec<Vec<u8>> =
+        client_segments(&mut wan, connection, &[b"a", b"b", b"c", b"d", b"e"]);
+    assert_eq!(segments.len(), 5);
+    wan.server
+        .stack
+        .on_packets(
+            &wan.server.device,
+            segments.iter().map(Vec::as_slice),
+            wan.clock.now(),
+        )
+        .unwrap();
+
+    // The last one is acknowledged once the batch has been handled
+    let acks: Vec<u32> = server_acks(&wan)
+        .into_iter()
+        .map(|ackn| ackn.wrapping_sub(start))
+        .collect();
+    assert_eq!(acks, [2, 4, 5]);
+    assert_eq!(wan.server.read_all(&connection.server), b"abcde");
+}
@@ -0,0 +1,103 @@
+//! Advertising the free space in the receive buffer, without silly window syndrome.
+//! RFC 9293 Section 3.8.6.2.2
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    clock::Clock,
+    config::{StackConfig, TcbConfig},
+    listener::ListenerLimits,
+    testing::{Connection, Wan},
+};
+
+const RECV_WINDOW: u32 = 2000;
+
+/// A connection to a server advertising [`RECV_WINDOW`], which the client has filled
+fn filled() -> (Wan, Connection) {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig {
+        tcb: TcbConfig {
+            recv_window: RECV_WINDOW,
+            ..TcbConfig::DEFAULT
+        },
+        ..StackConfig::DEFAULT
+    });
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 5000]).unwrap();
+    wan.run_until_idle().unwrap();
+    (wan, connection)
+}
+
+/// Run the server's timers, returning the window and payload length of everything it sent
+fn server_tick(wan: &mut Wan) -> Vec<(u16, usize)> {
+    wan.server
+        .stack
+        .on_tick(&wan.server.device, wan.clock.now())
+        .unwrap();
+
+    wan.client
+        .device
+        .inner()
+        .take_pending()
+        .iter()
+        .map(|packet| {
+            let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+            let tcp_header =
+                TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+            let payload_len: usize =
+                packet.len() - ip_header.slice().len() - tcp_header.slice().len();
+            (tcp_header.window_size(), payload_len)
+        })
+        .collect()
+}
+
+#[test]
+fn window_closes_as_data_is_buffered() {
+    let (wan, connection) = filled();
+
+    let server = wan.server.stack.connection(&connection.server).unwrap();
+    assert_eq!(server.unread_len(), RECV_WINDOW as usize);
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.send_window(), 0);
+}
+
+#[test]
+fn small_reads_leave_the_window_shut() {
+    let (mut wan, connection) = filled();
+
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    assert_eq!(server.read(&mut [0; 100]), 100);
+
+    assert_eq!(server_tick(&mut wan), []);
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.send_window(), 0);
+}
+
+#[test]
+fn reading_from_a_closed_window_sends_a_window_update() {
+    let (mut wan, connection) = filled();
+
+    let received: Vec<u8> = wan.server.read_all(&connection.server);
+    assert_eq!(received.len(), RECV_WINDOW as usize);
+    assert_eq!(server_tick(&mut wan), [(RECV_WINDOW as u16, 0)]);
+
+    // Nothing more goes out until there's something new to say
+    assert_eq!(server_tick(&mut wan), []);
+}
+
+#[test]
+fn window_updates_let_the_sender_finish() {
+    let (mut wan, connection) = filled();
+    let mut received: Vec<u8> = Vec::new();
+
+    wan.run_until(100, |wan| {
+        received.extend(wan.server.read_all(&connection.server));
+        received.len() == 5000
+    })
+    .unwrap();
+
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.unacked_len(), 0);
+}
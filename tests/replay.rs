@@ -0,0 +1,158 @@
+//! Replaying a capture through the whole stack and checking the transcript
+
+use etherparse::{Ipv4Header, TcpHeader};
+use tcp_rs::replay;
+
+const PEER: [u8; 4] = [192, 168, 0, 1];
+const LOCAL: [u8; 4] = [192, 168, 0, 2];
+const PEER_ISN: u32 = 100;
+
+/// A bare IPv4 packet carrying a TCP segment from `src` to `dst`
+fn packet(
+    src: [u8; 4],
+    dst: [u8; 4],
+    payload: &[u8],
+    configure: impl FnOnce(&mut TcpHeader),
+) -> Vec<u8> {
+    let (src_port, dst_port): (u16, u16) = if src == PEER {
+        (40000, 443)
+    } else {
+        (443, 40000)
+    };
+
+    let mut tcp_header = TcpHeader::new(src_port, dst_port, 0, 8192);
+    configure(&mut tcp_header);
+
+    let ip_header = Ipv4Header::new(
+        (tcp_header.header_len() + payload.len()) as u16,
+        64,
+        6.into(),
+        src,
+        dst,
+    )
+    .unwrap();
+    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, payload).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    tcp_header.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A little endian, microsecond pcap file of bare IP packets, as captured on a tun device
+fn pcap(packets: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut file: Vec<u8> = Vec::new();
+    file.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&4u16.to_le_bytes());
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65535u32.to_le_bytes());
+    file.extend_from_slice(&101u32.to_le_bytes());
+
+    for (micros, packet) in packets {
+        file.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        file.extend_from_slice(&micros.to_le_bytes());
+        file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        file.extend_from_slice(packet);
+    }
+
+    file
+}
+
+/// The peer opens a connection, sends "hello" and closes. The captured endpoint's
+/// responses are in the file too, with its own ISN, but only the peer's side is replayed.
+fn session() -> Vec<u8> {
+    let peer_ack: u32 = 5001;
+
+    pcap(&[
+        (
+            0,
+            packet(PEER, LOCAL, &[], |tcp| {
+                tcp.sequence_number = PEER_ISN;
+                tcp.syn = true;
+            }),
+        ),
+        (
+            10,
+            packet(LOCAL, PEER, &[], |tcp| {
+                tcp.sequence_number = 5000;
+                tcp.acknowledgment_number = PEER_ISN + 1;
+                tcp.syn = true;
+                tcp.ack = true;
+            }),
+        ),
+        (
+            20,
+            packet(PEER, LOCAL, &[], |tcp| {
+                tcp.sequence_number = PEER_ISN + 1;
+                tcp.acknowledgment_number = peer_ack;
+                tcp.ack = true;
+            }),
+        ),
+        (
+            30,
+            packet(PEER, LOCAL, b"hello", |tcp| {
+                tcp.sequence_number = PEER_ISN + 1;
+                tcp.acknowledgment_number = peer_ack;
+                tcp.ack = true;
+                tcp.psh = true;
+            }),
+        ),
+        (
+            40,
+            packet(PEER, LOCAL, &[], |tcp| {
+                tcp.sequence_number = PEER_ISN + 6;
+                tcp.acknowledgment_number = peer_ack;
+                tcp.ack = true;
+                tcp.fin = true;
+            }),
+        ),
+    ])
+}
+
+/// What the stack sends in response to [`session`]. Reading "hello" frees too little to
+/// open the window again.
+const SESSION_TRANSCRIPT: &str = "\
+#1 192.168.0.1:40000 > 192.168.0.2:443 [S] seq=0 win=8192 len=1
+    192.168.0.2:443 > 192.168.0.1:40000 [S.] seq=0 ack=1 win=1024 len=1
+#3 192.168.0.1:40000 > 192.168.0.2:443 [.] seq=1 ack=1 win=8192 len=0
+#4 192.168.0.1:40000 > 192.168.0.2:443 [P.] seq=1 ack=1 win=8192 len=5
+    192.168.0.2:443 > 192.168.0.1:40000 [.] seq=1 ack=6 win=1019 len=0
+#5 192.168.0.1:40000 > 192.168.0.2:443 [F.] seq=6 ack=1 win=8192 len=1
+    192.168.0.2:443 > 192.168.0.1:40000 [.] seq=1 ack=7 win=1019 len=0
+    192.168.0.2:443 > 192.168.0.1:40000 [F.] seq=1 ack=7 win=1019 len=1
+";
+
+/// The peer's ACKs are for the captured endpoint's ISN, so they only establish the
+/// connection if they're moved onto the stack's
+#[test]
+fn session_is_replayed_through_the_stack() {
+    let replay = replay::replay(&session()[..]).unwrap();
+
+    assert_eq!(replay.local_addr, Some(LOCAL.into()));
+    assert_eq!(replay.skipped, 1);
+    assert_eq!(replay.steps.len(), 4);
+    assert_eq!(replay.stats.connections_accepted, 1);
+    assert_eq!(replay.stats.segments_to_closed_ports, 0);
+
+    replay.check(SESSION_TRANSCRIPT).unwrap();
+}
+
+#[test]
+fn differing_transcript_is_reported() {
+    let replay = replay::replay(&session()[..]).unwrap();
+
+    let expected: String = SESSION_TRANSCRIPT.replace("ack=6 win=1019", "ack=6 win=1024");
+    let err = replay.check(&expected).err().unwrap();
+    assert!(err.to_string().contains("line 5"));
+
+    let expected: String = SESSION_TRANSCRIPT
+        .lines()
+        .take(3)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let err = replay.check(&expected).err().unwrap();
+    assert!(err.to_string().contains("8 lines, expected 3"));
+}
@@ -0,0 +1,152 @@
+//! Retransmission timeout estimation, RFC 6298
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    clock,
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    rto::{RtoEstimator, DEFAULT_INITIAL_RTO, MAX_RTO, MIN_INITIAL_RTO, SYN_TIMEOUT_FALLBACK_RTO},
+    tcp::{State, Tcb, MAX_SYN_RETRANSMISSIONS},
+};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 443);
+
+#[test]
+fn initial_rto_is_one_second() {
+    assert_eq!(RtoEstimator::default().rto(), DEFAULT_INITIAL_RTO);
+    assert_eq!(DEFAULT_INITIAL_RTO, Duration::from_secs(1));
+}
+
+#[test]
+fn initial_rto_is_clamped() {
+    assert_eq!(
+        RtoEstimator::new(Duration::from_millis(10)).rto(),
+        MIN_INITIAL_RTO
+    );
+    assert_eq!(RtoEstimator::new(Duration::from_secs(600)).rto(), MAX_RTO);
+}
+
+/// RFC 6298 Section 2.2 and 2.3
+#[test]
+fn samples_are_smoothed() {
+    let mut rto = RtoEstimator::default();
+
+    rto.on_rtt_sample(Duration::from_millis(400));
+    assert_eq!(rto.srtt(), Some(Duration::from_millis(400)));
+    assert_eq!(rto.rttvar(), Duration::from_millis(200));
+    assert_eq!(rto.rto(), Duration::from_millis(1200));
+
+    rto.on_rtt_sample(Duration::from_millis(800));
+    assert_eq!(rto.rttvar(), Duration::from_millis(250));
+    assert_eq!(rto.srtt(), Some(Duration::from_millis(450)));
+    assert_eq!(rto.rto(), Duration::from_millis(1450));
+}
+
+/// RFC 6298 Section 2.4, the timeout never drops below a second unless a shorter
+/// initial timeout was configured
+#[test]
+fn rto_has_a_floor() {
+    let mut rto = RtoEstimator::default();
+    rto.on_rtt_sample(Duration::from_millis(10));
+    assert_eq!(rto.rto(), Duration::from_secs(1));
+
+    let mut lab = RtoEstimator::new(MIN_INITIAL_RTO);
+    lab.on_rtt_sample(Duration::from_millis(10));
+    assert_eq!(lab.rto(), MIN_INITIAL_RTO);
+}
+
+/// RFC 6298 Section 5.5
+#[test]
+fn timeouts_back_off_up_to_the_limit() {
+    let mut rto = RtoEstimator::default();
+
+    for expected_secs in [2, 4, 8, 16, 32, 60, 60] {
+        rto.on_timeout();
+        assert_eq!(rto.rto(), Duration::from_secs(expected_secs));
+    }
+    assert_eq!(rto.backoffs(), 7);
+
+    // Karn's algorithm keeps the backed off timeout until a valid sample arrives
+    rto.on_rtt_sample(Duration::from_millis(400));
+    assert_eq!(rto.rto(), Duration::from_millis(1200));
+    assert_eq!(rto.backoffs(), 0);
+}
+
+/// RFC 6298 Section 2.5, a maximum lower than 60 seconds isn't allowed
+#[test]
+fn higher_limit_can_be_configured() {
+    let mut rto = RtoEstimator::with_max(DEFAULT_INITIAL_RTO, Duration::from_secs(120));
+    for _ in 0..8 {
+        rto.on_timeout();
+    }
+    assert_eq!(rto.rto(), Duration::from_secs(120));
+
+    let rto = RtoEstimator::with_max(DEFAULT_INITIAL_RTO, Duration::from_secs(10));
+    assert_eq!(rto.max(), MAX_RTO);
+}
+
+/// RFC 6298 Section 5.7
+#[test]
+fn syn_timeout_falls_back_to_three_seconds() {
+    let mut rto = RtoEstimator::default();
+    rto.on_timeout();
+    rto.on_established(true);
+    assert_eq!(rto.rto(), SYN_TIMEOUT_FALLBACK_RTO);
+
+    let mut rto = RtoEstimator::default();
+    rto.on_established(false);
+    assert_eq!(rto.rto(), DEFAULT_INITIAL_RTO);
+}
+
+fn sequence_number(packet: &[u8]) -> u32 {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..])
+        .unwrap()
+        .sequence_number()
+}
+
+#[test]
+fn syn_is_retransmitted_with_backoff_until_giving_up() {
+    let device = CaptureDevice::default();
+    let mut tcb = Tcb::connect(
+        &device,
+        LOCAL,
+        REMOTE,
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap();
+    tcb.set_initial_rto(MIN_INITIAL_RTO);
+    let iss: u32 = sequence_number(&device.take_sent()[0]);
+
+    let mut now: Instant = Instant::now();
+    let mut expected_rto: Duration = MIN_INITIAL_RTO;
+
+    for _ in 0..MAX_SYN_RETRANSMISSIONS {
+        let deadline: Instant = tcb.next_deadline().unwrap();
+        assert!(deadline <= now + expected_rto);
+
+        now = deadline;
+        tcb.on_tick(&device, now).unwrap();
+
+        let sent: Vec<Vec<u8>> = device.take_sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sequence_number(&sent[0]), iss);
+
+        expected_rto *= 2;
+        assert_eq!(tcb.rto().rto(), expected_rto);
+    }
+
+    let deadline: Instant = tcb.next_deadline().unwrap();
+    tcb.on_tick(&device, deadline).unwrap();
+
+    assert!(device.take_sent().is_empty());
+    assert_eq!(tcb.state(), State::Closed);
+    assert_eq!(tcb.next_deadline(), None);
+}
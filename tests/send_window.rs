@@ -0,0 +1,91 @@
+//! Keeping to the window the peer advertises, and only taking it from the newest segments.
+//! RFC 9293 Section 3.8.6 and 3.10.7.4
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcp_rs::{
+    clock::Clock,
+    config::{StackConfig, TcbConfig},
+    device::NetworkDevice,
+    testing::{Connection, Wan},
+};
+
+const RECV_WINDOW: u32 = 2000;
+
+/// A connection to a server advertising [`RECV_WINDOW`]
+fn connected() -> (Wan, Connection) {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig {
+        tcb: TcbConfig {
+            recv_window: RECV_WINDOW,
+            ..TcbConfig::DEFAULT
+        },
+        ..StackConfig::DEFAULT
+    });
+    wan.established(443).unwrap()
+}
+
+/// The TCP header and payload length of a packet
+fn segment(packet: &[u8]) -> (TcpHeaderSlice<'_>, usize) {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp_header = TcpHeaderSlice::from_slice(&packet[ip_header.slice().len()..]).unwrap();
+    let payload_len: usize = packet.len() - ip_header.slice().len() - tcp_header.slice().len();
+    (tcp_header, payload_len)
+}
+
+#[test]
+fn data_in_flight_stays_within_the_peers_window() {
+    let (mut wan, connection) = connected();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    assert_eq!(client.send_window(), RECV_WINDOW);
+    client.send(&wan.client.device, &[1; 5000]).unwrap();
+
+    let in_flight: usize = wan
+        .server
+        .device
+        .inner()
+        .take_pending()
+        .iter()
+        .map(|packet| segment(packet).1)
+        .sum();
+    assert_eq!(in_flight, RECV_WINDOW as usize);
+    assert_eq!(client.unacked_len(), 5000);
+}
+
+#[test]
+fn window_is_only_taken_from_newer_segments() {
+    let (mut wan, connection) = connected();
+
+    // The server acknowledges half its window it hasn't read yet, then replies with data
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client
+        .send(&wan.client.device, &[1; RECV_WINDOW as usize / 2])
+        .unwrap();
+    wan.run_until_idle().unwrap();
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"a").unwrap();
+    let older: Vec<u8> = wan.client.device.inner().take_pending().pop().unwrap();
+
+    // Once it's read them the window is open again for its next segment
+    wan.server.read_all(&connection.server);
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"b").unwrap();
+    let newer: Vec<u8> = wan.client.device.inner().take_pending().pop().unwrap();
+
+    let (older_header, _) = segment(&older);
+    let (newer_header, _) = segment(&newer);
+    assert_eq!(
+        older_header.acknowledgment_number(),
+        newer_header.acknowledgment_number()
+    );
+    assert!(older_header.window_size() < newer_header.window_size());
+
+    // Reordered on the way, so the older window arrives last and is ignored
+    for packet in [&newer, &older] {
+        wan.server.device.inner().send(packet).unwrap();
+        wan.client.receive(wan.clock.now()).unwrap();
+    }
+
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.send_window(), newer_header.window_size() as u32);
+}
@@ -0,0 +1,129 @@
+//! Sequence number arithmetic and RFC 1982 comparisons, checked over pseudo-random numbers
+//! clustered around the wrap point
+
+use tcp_rs::seq::SeqNum;
+
+/// Deterministic numbers for inputs, xorshift64
+struct Numbers(u64);
+
+impl Numbers {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as u32
+    }
+
+    /// A sequence number, half of them within a small distance of the wrap point
+    fn seq(&mut self) -> SeqNum {
+        let n: u32 = self.next();
+        if n.is_multiple_of(2) {
+            SeqNum::new(n)
+        } else {
+            SeqNum::new((n % 2048).wrapping_sub(1024))
+        }
+    }
+
+    /// A distance less than 2^31, mostly small
+    fn distance(&mut self) -> u32 {
+        match self.next() % 3 {
+            0 => self.next() % 16,
+            1 => self.next() % 65536,
+            _ => self.next() % (1 << 31),
+        }
+    }
+}
+
+const N_CASES: usize = 10_000;
+
+#[test]
+fn arithmetic_wraps_around_the_space() {
+    assert_eq!(SeqNum::new(u32::MAX) + 1, SeqNum::new(0));
+    assert_eq!(SeqNum::new(0) - 1, SeqNum::new(u32::MAX));
+    assert_eq!(SeqNum::new(5) - SeqNum::new(u32::MAX - 4), 10);
+
+    let mut numbers = Numbers(0x2545_f491_4f6c_dd1d);
+    for _ in 0..N_CASES {
+        let seq: SeqNum = numbers.seq();
+        let n: u32 = numbers.next();
+
+        assert_eq!((seq + n) - seq, n);
+        assert_eq!((seq + n) - n, seq);
+
+        let mut moved: SeqNum = seq;
+        moved += n;
+        moved -= n;
+        assert_eq!(moved, seq);
+    }
+}
+
+#[test]
+fn numbers_ahead_by_less_than_half_the_space_are_after() {
+    assert!(SeqNum::new(u32::MAX).lt(SeqNum::new(0)));
+    assert!(SeqNum::new(0).gt(SeqNum::new(u32::MAX)));
+
+    let mut numbers = Numbers(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..N_CASES {
+        let seq: SeqNum = numbers.seq();
+        let ahead: SeqNum = seq + numbers.distance().max(1);
+
+        assert!(seq.lt(ahead) && seq.le(ahead), "{seq:?} < {ahead:?}");
+        assert!(ahead.gt(seq) && ahead.ge(seq), "{ahead:?} > {seq:?}");
+        assert!(!seq.gt(ahead) && !seq.ge(ahead));
+        assert!(!ahead.lt(seq) && !ahead.le(seq));
+
+        assert!(seq.le(seq) && seq.ge(seq));
+        assert!(!seq.lt(seq) && !seq.gt(seq));
+    }
+}
+
+#[test]
+fn numbers_half_the_space_apart_are_unordered() {
+    let mut numbers = Numbers(0xdead_beef_cafe_f00d);
+    for _ in 0..N_CASES {
+        let seq: SeqNum = numbers.seq();
+        let opposite: SeqNum = seq + (1 << 31);
+
+        assert!(!seq.lt(opposite) && !seq.gt(opposite));
+        assert!(!opposite.lt(seq) && !opposite.gt(seq));
+    }
+}
+
+#[test]
+fn is_between_excludes_both_ends() {
+    let mut numbers = Numbers(0x0123_4567_89ab_cdef);
+    for _ in 0..N_CASES {
+        let start: SeqNum = numbers.seq();
+        let len: u32 = numbers.distance();
+        let end: SeqNum = start + len;
+
+        assert!(!start.is_between(start, end));
+        assert!(!end.is_between(start, end));
+        if len > 1 {
+            assert!((start + 1).is_between(start, end));
+            assert!((end - 1).is_between(start, end));
+            assert!((start + numbers.next() % (len - 1) + 1).is_between(start, end));
+        }
+        assert!(!(end + 1 + numbers.next() % (u32::MAX - len)).is_between(start, end));
+    }
+}
+
+#[test]
+fn is_in_window_covers_len_numbers_from_start() {
+    let mut numbers = Numbers(0xfeed_face_0bad_f00d);
+    for _ in 0..N_CASES {
+        let start: SeqNum = numbers.seq();
+        let len: u32 = numbers.distance();
+
+        assert!(!(start - 1).is_in_window(start, len));
+        assert!(!(start + len).is_in_window(start, len));
+        if len > 0 {
+            assert!(start.is_in_window(start, len));
+            assert!((start + len - 1).is_in_window(start, len));
+            assert!((start + numbers.next() % len).is_in_window(start, len));
+        }
+    }
+
+    // Nothing is in an empty window
+    assert!(!SeqNum::new(0).is_in_window(SeqNum::new(0), 0));
+}
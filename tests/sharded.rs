@@ -0,0 +1,172 @@
+//! Connections spread across worker threads by their 4-tuple
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tcp_rs::{
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    sharded::ShardedStack,
+    stack::Stack,
+    stats::StatsSnapshot,
+    tcp::State,
+};
+
+mod common;
+
+use common::{connection, syn, tcp_header};
+
+const LISTEN_PORT: u16 = 443;
+const N_SHARDS: usize = 4;
+
+/// Keeps every packet sent to it, from any thread
+#[derive(Default)]
+struct SharedCapture {
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+impl SharedCapture {
+    /// Wait for at least `n` packets to have been sent, then take them all
+    fn wait_for(&self, n: usize) -> Vec<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.sent.lock().unwrap().len() < n {
+            assert!(
+                Instant::now() < deadline,
+                "fewer than {n} packets were sent"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        std::mem::take(&mut *self.sent.lock().unwrap())
+    }
+}
+
+impl NetworkDevice for SharedCapture {
+    fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.lock().unwrap().push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+fn sharded_stack() -> (Arc<SharedCapture>, ShardedStack<SharedCapture>) {
+    let device = Arc::new(SharedCapture::default());
+    let stack = ShardedStack::spawn(
+        Arc::clone(&device),
+        N_SHARDS,
+        || {
+            let mut listeners = Listeners::default();
+            listeners.insert(LISTEN_PORT, ListenerLimits::default());
+            Ok(Stack::new(
+                listeners,
+                IsnGenerator::new([1; 16]),
+                Instant::now(),
+            ))
+        },
+        |_: &SharedCapture, _: &mut Stack| Ok(()),
+    )
+    .unwrap();
+
+    (device, stack)
+}
+
+#[test]
+fn connections_are_owned_by_the_shard_they_hash_to() {
+    let (device, mut stack) = sharded_stack();
+    let ports: Vec<u16> = (40000..40032).collect();
+
+    for &port in &ports {
+        stack
+            .on_packet(&syn(port, LISTEN_PORT), Instant::now())
+            .unwrap();
+    }
+
+    let sent: Vec<Vec<u8>> = device.wait_for(ports.len());
+    assert_eq!(sent.len(), ports.len());
+    for packet in &sent {
+        assert!(tcp_header(packet).syn() && tcp_header(packet).ack());
+    }
+
+    let mut shards_used: Vec<usize> = Vec::new();
+    for &port in &ports {
+        let info = connection(port, LISTEN_PORT);
+        let index: usize = stack.shard_of(&info);
+        let shard = stack.shard(index);
+        assert_eq!(
+            shard.connection(&info).map(|tcb| tcb.state()),
+            Some(State::SynRcvd)
+        );
+        shards_used.push(index);
+    }
+
+    shards_used.sort();
+    shards_used.dedup();
+    assert!(
+        shards_used.len() > 1,
+        "every connection hashed to one shard"
+    );
+}
+
+#[test]
+fn stats_are_merged_across_shards() {
+    let (device, mut stack) = sharded_stack();
+
+    for port in 40000..40008 {
+        stack
+            .on_packet(&syn(port, LISTEN_PORT), Instant::now())
+            .unwrap();
+    }
+    device.wait_for(8);
+
+    let snapshot: StatsSnapshot = stack.snapshot_stats(Instant::now(), true);
+    assert_eq!(snapshot.stack.packets_in, 8);
+    assert_eq!(snapshot.stack.connections_accepted, 8);
+    assert_eq!(snapshot.connections.len(), 8);
+
+    let snapshot: StatsSnapshot = stack.snapshot_stats(Instant::now(), false);
+    assert_eq!(snapshot.stack.packets_in, 0);
+    assert_eq!(snapshot.stack.connections_accepted, 0);
+}
+
+#[test]
+fn zero_shards_is_an_error() {
+    let result = ShardedStack::spawn(
+        Arc::new(SharedCapture::default()),
+        0,
+        || unreachable!(),
+        |_: &SharedCapture, _: &mut Stack| Ok(()),
+    );
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn connections_are_listed_and_aborted_across_shards() {
+    let (device, mut stack) = sharded_stack();
+
+    for port in 40000..40008 {
+        stack
+            .on_packet(&syn(port, LISTEN_PORT), Instant::now())
+            .unwrap();
+    }
+    device.wait_for(8);
+
+    let ports: Vec<u16> = stack
+        .connection_summaries()
+        .iter()
+        .map(|summary| summary.connection.src_port)
+        .collect();
+    assert_eq!(ports, (40000..40008).collect::<Vec<u16>>());
+
+    stack.abort(&connection(40003, LISTEN_PORT)).unwrap();
+    assert_eq!(device.wait_for(1).len(), 1);
+    assert_eq!(stack.connection_summaries().len(), 7);
+    assert!(stack.abort(&connection(40003, LISTEN_PORT)).is_err());
+}
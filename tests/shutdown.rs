@@ -0,0 +1,76 @@
+//! Shutting down one direction of a connection
+
+use std::net::Shutdown;
+
+use tcp_rs::{
+    error::TcpError,
+    tcp::State,
+    testing::{Connection, Side, Wan},
+};
+
+fn shutdown(wan: &mut Wan, connection: Connection, side: Side, how: Shutdown) {
+    let host = wan.host_mut(side);
+    host.stack
+        .shutdown(&host.device, &connection.info(side), how)
+        .unwrap();
+}
+
+#[test]
+fn half_closed_connection_still_receives() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    shutdown(&mut wan, connection, Side::Client, Shutdown::Write);
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::FinWait2));
+    assert_eq!(wan.server.state(&connection.server), Some(State::CloseWait));
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    assert!(matches!(
+        client.send(&wan.client.device, b"more"),
+        Err(TcpError::InvalidState(State::FinWait2))
+    ));
+
+    let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(wan.transfer(connection, Side::Server, &data).unwrap(), data);
+
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::TimeWait));
+    assert_eq!(wan.server.state(&connection.server), Some(State::Closed));
+}
+
+#[test]
+fn closing_after_a_half_close_stops_reading() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    shutdown(&mut wan, connection, Side::Client, Shutdown::Write);
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+    assert!(wan
+        .client
+        .stack
+        .connection(&connection.client)
+        .unwrap()
+        .is_recv_shutdown());
+
+    // Nothing will read it, so the peer is told with a RST
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"unread").unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::Closed));
+    assert_eq!(wan.server.state(&connection.server), Some(State::Closed));
+
+    assert!(wan.close(connection, Side::Client).is_err());
+}
+
+#[test]
+fn writing_can_only_be_shut_down_once() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    shutdown(&mut wan, connection, Side::Client, Shutdown::Write);
+    let client = &mut wan.client;
+    assert!(client
+        .stack
+        .shutdown(&client.device, &connection.client, Shutdown::Write)
+        .is_err());
+}
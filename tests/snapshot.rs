@@ -0,0 +1,176 @@
+//! Saving connections and carrying them on in a new stack.
+//! Run with `cargo test --features snapshot`.
+#![cfg(feature = "snapshot")]
+
+use std::{net::SocketAddrV4, time::Duration};
+
+use tcp_rs::{
+    clock::Clock,
+    device::NetworkDevice,
+    error::TcpError,
+    isn::IsnGenerator,
+    listener::Listeners,
+    snapshot::StackSnapshot,
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    testing::{Side, Wan},
+    PACKET_BUF_SIZE,
+};
+
+/// A new stack on the same clock, as a restarted daemon would start with
+fn fresh_stack(wan: &Wan) -> Stack {
+    let mut stack = Stack::new(
+        Listeners::default(),
+        IsnGenerator::new([2; 16]),
+        wan.clock.now(),
+    );
+    stack.set_clock(wan.clock.clone());
+    stack
+}
+
+/// Throw away every packet waiting for `side`, as if the link lost them
+fn drop_in_flight(wan: &mut Wan, side: Side) {
+    let mut buf: [u8; PACKET_BUF_SIZE] = [0; PACKET_BUF_SIZE];
+    while wan.host(side).device.recv(&mut buf).is_ok() {}
+}
+
+#[test]
+fn a_snapshot_survives_encoding() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, b"unread").unwrap();
+    wan.run_until_idle().unwrap();
+    // A connection still in the handshake is left out
+    wan.open(40001, 443).unwrap();
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"unacked").unwrap();
+
+    let snapshot: StackSnapshot = wan.server.stack.snapshot();
+    assert_eq!(snapshot.connections.len(), 1);
+    let saved = &snapshot.connections[0];
+    assert_eq!(saved.state, State::Estab);
+    assert_eq!(saved.recv_buffer, b"unread");
+    assert_eq!(saved.send_buffer, b"unacked");
+    assert_eq!(saved.snd_nxt.wrapping_sub(saved.snd_una), 7);
+
+    let bytes: Vec<u8> = snapshot.to_bytes();
+    assert_eq!(StackSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+    assert!(matches!(
+        StackSnapshot::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(TcpError::Parse(_))
+    ));
+    assert!(matches!(
+        StackSnapshot::from_bytes(b"pcap"),
+        Err(TcpError::Parse(_))
+    ));
+}
+
+#[test]
+fn a_restored_stack_carries_on_the_connection() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, b"hello").unwrap();
+    wan.run_until_idle().unwrap();
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"in flight").unwrap();
+
+    // The daemon restarts, and what it had just sent is lost on the way
+    let bytes: Vec<u8> = wan.server.stack.snapshot().to_bytes();
+    drop_in_flight(&mut wan, Side::Client);
+    let mut stack: Stack = fresh_stack(&wan);
+    let restored: Vec<ConnectInfo> = stack
+        .restore(
+            &wan.server.device,
+            StackSnapshot::from_bytes(&bytes).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(restored, [connection.server]);
+    wan.server.stack = stack;
+
+    let tcb = wan.server.stack.connection(&connection.server).unwrap();
+    assert_eq!(tcb.state(), State::Estab);
+    assert!(tcb.next_deadline().is_some());
+    assert_eq!(wan.server.read_all(&connection.server), b"hello");
+
+    // The retransmission timer was re-armed, so the lost data arrives
+    let mut received: Vec<u8> = Vec::new();
+    wan.run_until(100, |wan| {
+        received.extend(wan.client.read_all(&connection.client));
+        received.len() == 9
+    })
+    .unwrap();
+    assert_eq!(received, b"in flight");
+
+    let data: Vec<u8> = (0..50_000).map(|i| i as u8).collect();
+    assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+    assert_eq!(wan.transfer(connection, Side::Server, &data).unwrap(), data);
+}
+
+#[test]
+fn time_wait_runs_for_what_was_left_of_it() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::TimeWait));
+
+    wan.clock.advance(Duration::from_secs(20));
+    let snapshot: StackSnapshot = wan.client.stack.snapshot();
+    assert_eq!(
+        snapshot.connections[0].time_wait_remaining,
+        Some(Duration::from_secs(40))
+    );
+
+    let mut stack: Stack = fresh_stack(&wan);
+    stack.restore(&wan.client.device, snapshot).unwrap();
+    wan.client.stack = stack;
+    assert_eq!(
+        wan.client.stack.next_deadline(),
+        Some(wan.clock.now() + Duration::from_secs(40))
+    );
+
+    let now = wan.clock.now();
+    wan.advance_to(now + Duration::from_secs(40)).unwrap();
+    assert_eq!(wan.client.state(&connection.client), None);
+}
+
+#[test]
+fn restore_refuses_a_connection_already_open() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let snapshot: StackSnapshot = wan.server.stack.snapshot();
+
+    let err = wan
+        .server
+        .stack
+        .restore(&wan.server.device, snapshot)
+        .unwrap_err();
+    assert!(matches!(err, TcpError::AddrInUse(443)));
+    assert_eq!(wan.server.stack.connections().count(), 1);
+    assert!(wan.server.stack.connection(&connection.server).is_some());
+}
+
+/// A connection which can't be restored leaves the stack as it was, even after one which
+/// could
+#[test]
+fn restore_adds_nothing_if_a_later_connection_is_corrupt() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    wan.connect(40001, 443).unwrap();
+    let mut snapshot: StackSnapshot = wan.client.stack.snapshot();
+    assert_eq!(snapshot.connections.len(), 2);
+    let corrupt = &mut snapshot.connections[1];
+    corrupt.push_points = vec![corrupt.send_buffer.len() as u64 + 1];
+
+    let mut stack: Stack = fresh_stack(&wan);
+    let err = stack.restore(&wan.client.device, snapshot).unwrap_err();
+    assert!(matches!(err, TcpError::InvalidInput(_)));
+    assert_eq!(stack.connections().count(), 0);
+
+    // The first connection's port wasn't bound either
+    let client = connection.client;
+    let local = SocketAddrV4::new(client.dst_addr, client.dst_port);
+    let remote = SocketAddrV4::new(client.src_addr, 80);
+    stack.connect(&wan.client.device, local, remote).unwrap();
+}
@@ -0,0 +1,303 @@
+//! Changing settings on live connections with `set_option`
+
+use std::time::{Duration, Instant};
+
+use tcp_rs::{
+    clock::Clock,
+    config::{StackConfig, TcbConfig},
+    listener::ListenerLimits,
+    tcp::{KeepaliveConfig, SocketOption, State, Tcb, SEND_BUFFER_SIZE},
+    testing::{Connection, Side, Wan},
+};
+
+mod common;
+
+use common::tcp_header;
+
+fn client(wan: &mut Wan, connection: Connection) -> &mut Tcb {
+    wan.client.stack.connection_mut(&connection.client).unwrap()
+}
+
+/// Send each of `writes` from the client, returning how many segments went out
+fn send_writes(wan: &mut Wan, connection: Connection, writes: &[&[u8]]) -> u64 {
+    let before: u64 = client(wan, connection).stats().segments_out;
+    for data in writes {
+        let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+        client.send(&wan.client.device, data).unwrap();
+    }
+    client(wan, connection).stats().segments_out - before
+}
+
+#[test]
+fn defaults_are_reported_by_the_getters() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let client = client(&mut wan, connection);
+
+    assert!(client.nodelay());
+    assert_eq!(client.keepalive(), None);
+    assert_eq!(client.linger(), None);
+    assert_eq!(client.send_buffer_size(), SEND_BUFFER_SIZE);
+    assert_eq!(
+        client.recv_buffer_size(),
+        TcbConfig::DEFAULT.recv_window as usize
+    );
+    assert_eq!(client.user_timeout(), None);
+
+    client
+        .set_option(SocketOption::Keepalive(Some(KeepaliveConfig::DEFAULT)))
+        .unwrap();
+    assert_eq!(client.keepalive(), Some(KeepaliveConfig::DEFAULT));
+}
+
+#[test]
+fn small_writes_go_straight_out_by_default() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    assert_eq!(send_writes(&mut wan, connection, &[b"a", b"b", b"c"]), 3);
+}
+
+#[test]
+fn nagle_holds_small_segments_until_the_ack() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    client(&mut wan, connection)
+        .set_option(SocketOption::NoDelay(false))
+        .unwrap();
+
+    assert_eq!(send_writes(&mut wan, connection, &[b"a", b"b", b"c"]), 1);
+
+    // The ACK of the first lets the rest go together
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.server.read_all(&connection.server), b"abc");
+    assert_eq!(client(&mut wan, connection).unacked_len(), 0);
+    assert_eq!(client(&mut wan, connection).stats().segments_out, 4);
+}
+
+#[test]
+fn nagle_still_sends_full_segments_and_the_fin() {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig::SERVER);
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+    let client = client(&mut wan, connection);
+    client.set_option(SocketOption::NoDelay(false)).unwrap();
+    let mss: usize = client.effective_mss();
+
+    // Full segments go out while the first byte is unacknowledged
+    let data: Vec<u8> = vec![1; 2 * mss];
+    assert_eq!(send_writes(&mut wan, connection, &[b"a", &data]), 3);
+
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.server.read_all(&connection.server).len(), 2 * mss + 1);
+    assert_eq!(wan.server.state(&connection.server), Some(State::CloseWait));
+}
+
+#[test]
+fn zero_linger_resets_on_close() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    client(&mut wan, connection)
+        .set_option(SocketOption::Linger(Some(Duration::ZERO)))
+        .unwrap();
+
+    wan.close(connection, Side::Client).unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::Closed));
+
+    wan.run_until_idle().unwrap();
+    let server = wan.server.stack.connection(&connection.server).unwrap();
+    assert_eq!(server.state(), State::Closed);
+    assert!(server.was_reset());
+}
+
+#[test]
+fn lingering_close_resets_if_the_fin_goes_unacknowledged() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let linger = Duration::from_millis(500);
+    client(&mut wan, connection)
+        .set_option(SocketOption::Linger(Some(linger)))
+        .unwrap();
+
+    // The FIN is lost
+    wan.close(connection, Side::Client).unwrap();
+    assert_eq!(wan.server.device.inner().take_pending().len(), 1);
+    let deadline: Instant = wan.clock.now() + linger;
+    assert_eq!(wan.client.next_deadline(), Some(deadline));
+
+    wan.advance_to(deadline).unwrap();
+    assert!(wan.client.stack.connection(&connection.client).is_none());
+    assert!(wan
+        .server
+        .stack
+        .connection(&connection.server)
+        .unwrap()
+        .was_reset());
+}
+
+#[test]
+fn lingering_close_finishes_normally_when_acknowledged() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    client(&mut wan, connection)
+        .set_option(SocketOption::Linger(Some(Duration::from_millis(500))))
+        .unwrap();
+
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+
+    assert_eq!(wan.client.state(&connection.client), Some(State::FinWait2));
+    assert_eq!(client(&mut wan, connection).running_timers(), 0);
+}
+
+#[test]
+fn user_timeout_drops_unacknowledged_connections() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let user_timeout = Duration::from_secs(3);
+    client(&mut wan, connection)
+        .set_option(SocketOption::UserTimeout(Some(user_timeout)))
+        .unwrap();
+
+    // Everything the client sends from here on is lost
+    let start: Instant = wan.clock.now();
+    send_writes(&mut wan, connection, &[b"lost"]);
+    assert_eq!(
+        client(&mut wan, connection).user_timeout_deadline(),
+        Some(start + user_timeout)
+    );
+
+    while let Some(deadline) = wan.client.next_deadline() {
+        wan.clock.advance_to(deadline);
+        wan.client
+            .stack
+            .on_tick(&wan.client.device, wan.clock.now())
+            .unwrap();
+        let sent: Vec<Vec<u8>> = wan.server.device.inner().take_pending();
+        if wan.client.stack.connection(&connection.client).is_none() {
+            // Dropped quietly, without a reset
+            assert!(sent.is_empty());
+            break;
+        }
+    }
+
+    assert_eq!(wan.clock.now(), start + user_timeout);
+}
+
+/// Run the client's own timers until it gives up on the lost server, returning what it
+/// sent last
+fn tick_until_closed(wan: &mut Wan, connection: Connection) -> Vec<u8> {
+    let mut last_sent: Vec<u8> = Vec::new();
+    while client(wan, connection).state() != State::Closed {
+        let deadline: Instant = client(wan, connection).next_deadline().unwrap();
+        wan.clock.advance_to(deadline);
+        let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+        client.on_tick(&wan.client.device, deadline).unwrap();
+        if let Some(packet) = wan.server.device.inner().take_pending().pop() {
+            last_sent = packet;
+        }
+    }
+    last_sent
+}
+
+#[test]
+fn keepalive_exhaustion_resets_and_drops_the_connection() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server.send(&wan.server.device, b"unread").unwrap();
+    wan.run_until_idle().unwrap();
+    client(&mut wan, connection)
+        .set_option(SocketOption::Keepalive(Some(KeepaliveConfig {
+            idle: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            probes: 2,
+        })))
+        .unwrap();
+
+    let last_sent: Vec<u8> = tick_until_closed(&mut wan, connection);
+    assert!(tcp_header(&last_sent).rst());
+    let client = client(&mut wan, connection);
+    assert_eq!(client.unread_len(), 0);
+    assert_eq!(client.next_deadline(), None);
+}
+
+#[test]
+fn retransmission_limit_drops_the_connection() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    send_writes(&mut wan, connection, &[b"lost"]);
+
+    let last_sent: Vec<u8> = tick_until_closed(&mut wan, connection);
+    assert!(!tcp_header(&last_sent).rst());
+    let client = client(&mut wan, connection);
+    assert_eq!(client.unacked_len(), 0);
+    assert_eq!(client.next_deadline(), None);
+}
+
+#[test]
+fn acknowledgements_push_the_user_timeout_back() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    client(&mut wan, connection)
+        .set_option(SocketOption::UserTimeout(Some(Duration::from_secs(3))))
+        .unwrap();
+
+    send_writes(&mut wan, connection, &[b"hello"]);
+    wan.run_until_idle().unwrap();
+    assert_eq!(client(&mut wan, connection).user_timeout_deadline(), None);
+
+    wan.advance_to(wan.clock.now() + Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::Estab));
+}
+
+#[test]
+fn growing_the_receive_buffer_opens_the_window() {
+    let mut wan = Wan::lossless();
+    wan.server.stack.set_config(StackConfig {
+        tcb: TcbConfig {
+            recv_window: 2000,
+            ..TcbConfig::DEFAULT
+        },
+        ..StackConfig::DEFAULT
+    });
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+    send_writes(&mut wan, connection, &[&[1; 5000]]);
+    wan.run_until_idle().unwrap();
+    assert_eq!(client(&mut wan, connection).send_window(), 0);
+
+    let server = wan.server.stack.connection_mut(&connection.server).unwrap();
+    server
+        .set_option(SocketOption::RecvBufferSize(8000))
+        .unwrap();
+    assert_eq!(server.recv_buffer_size(), 8000);
+
+    // The window update lets the rest of the data in without anything being read
+    wan.run_until(20, |wan| {
+        let client = wan.client.stack.connection(&connection.client).unwrap();
+        client.unacked_len() == 0
+    })
+    .unwrap();
+    assert_eq!(wan.server.read_all(&connection.server).len(), 5000);
+}
+
+#[test]
+fn shrinking_the_send_buffer_takes_no_more_until_it_drains() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    send_writes(&mut wan, connection, &[&[1; 1000]]);
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client
+        .set_option(SocketOption::SendBufferSize(500))
+        .unwrap();
+    assert_eq!(client.send_space(), 0);
+    assert_eq!(client.send(&wan.client.device, b"more").unwrap(), 0);
+
+    wan.run_until_idle().unwrap();
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.send_space(), 500);
+}
+
+#[test]
+fn empty_buffers_are_refused() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let client = client(&mut wan, connection);
+
+    assert!(client.set_option(SocketOption::RecvBufferSize(0)).is_err());
+    assert!(client.set_option(SocketOption::SendBufferSize(0)).is_err());
+    assert_eq!(client.send_buffer_size(), SEND_BUFFER_SIZE);
+}
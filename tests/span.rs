@@ -0,0 +1,131 @@
+//! Connection ids and the spans tagging work done for a connection
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tcp_rs::{
+    clock,
+    device::CaptureDevice,
+    hooks::{SegmentHook, SegmentInfo, Verdict},
+    isn::IsnGenerator,
+    span::{self, ConnectionId, LogLevel},
+    tcp::{ConnectInfo, Tcb},
+};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 443);
+
+/// Records the span each segment was sent in
+struct SpanRecorder(Arc<Mutex<Vec<Option<ConnectionId>>>>);
+
+impl SegmentHook for SpanRecorder {
+    fn on_transmit(&mut self, _segment: &SegmentInfo) -> Verdict {
+        self.0.lock().unwrap().push(span::current());
+        Verdict::Accept
+    }
+}
+
+#[test]
+fn reused_tuple_gets_a_new_incarnation() {
+    let device = CaptureDevice::default();
+    let isn = IsnGenerator::new([1; 16]);
+
+    let first = Tcb::connect(&device, LOCAL, REMOTE, &isn, &clock::system()).unwrap();
+    let second = Tcb::connect(&device, LOCAL, REMOTE, &isn, &clock::system()).unwrap();
+
+    let info = ConnectInfo {
+        src_addr: *REMOTE.ip(),
+        src_port: REMOTE.port(),
+        dst_addr: *LOCAL.ip(),
+        dst_port: LOCAL.port(),
+    };
+    assert_eq!(first.id().info, info);
+    assert_eq!(second.id().info, info);
+    assert!(second.id().incarnation > first.id().incarnation);
+}
+
+#[test]
+fn retransmission_runs_in_the_connection_span() {
+    let device = CaptureDevice::default();
+    let mut tcb = Tcb::connect(
+        &device,
+        LOCAL,
+        REMOTE,
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap();
+
+    let spans = Arc::new(Mutex::new(Vec::new()));
+    tcb.set_segment_hook(Some(Box::new(SpanRecorder(Arc::clone(&spans)))));
+
+    let deadline: Instant = tcb.next_deadline().unwrap();
+    tcb.on_tick(&device, deadline).unwrap();
+
+    assert_eq!(*spans.lock().unwrap(), vec![Some(tcb.id())]);
+    assert_eq!(span::current(), None);
+}
+
+#[test]
+fn spans_nest_and_restore() {
+    let device = CaptureDevice::default();
+    let isn = IsnGenerator::new([1; 16]);
+    let outer: ConnectionId = Tcb::connect(&device, LOCAL, REMOTE, &isn, &clock::system())
+        .unwrap()
+        .id();
+    let inner: ConnectionId = Tcb::connect(&device, LOCAL, REMOTE, &isn, &clock::system())
+        .unwrap()
+        .id();
+
+    {
+        let _outer = span::enter(outer);
+        {
+            let _inner = span::enter(inner);
+            assert_eq!(span::current(), Some(inner));
+        }
+        assert_eq!(span::current(), Some(outer));
+    }
+    assert_eq!(span::current(), None);
+}
+
+/// The only test here changing the process wide level and filter
+#[test]
+fn levels_and_port_filter_choose_what_is_logged() {
+    let device = CaptureDevice::default();
+    let id: ConnectionId = Tcb::connect(
+        &device,
+        LOCAL,
+        REMOTE,
+        &IsnGenerator::new([1; 16]),
+        &clock::system(),
+    )
+    .unwrap()
+    .id();
+
+    // Nothing is logged until the level is raised
+    assert!(!span::enabled(LogLevel::Info));
+
+    span::set_level(LogLevel::Info);
+    assert!(span::enabled(LogLevel::Info));
+    assert!(!span::enabled(LogLevel::Debug));
+
+    span::set_level(LogLevel::Trace);
+    span::set_port_filter(Some(80));
+    assert!(span::enabled(LogLevel::Trace));
+    {
+        let _span = span::enter(id);
+        assert!(!span::enabled(LogLevel::Info));
+
+        span::set_port_filter(Some(REMOTE.port()));
+        assert!(span::enabled(LogLevel::Trace));
+    }
+
+    span::set_level(LogLevel::Off);
+    assert!(!span::enabled(LogLevel::Info));
+    assert!(!span::enabled(LogLevel::Off));
+
+    span::set_port_filter(None);
+}
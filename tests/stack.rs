@@ -0,0 +1,458 @@
+//! Packet demultiplexing and timers across the whole stack
+
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use etherparse::{TcpHeader, TcpOptionElement};
+use tcp_rs::{
+    device::CaptureDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners, TimeWaitPolicy},
+    stack::Stack,
+    tcp::{ConnectInfo, State},
+    testing::CLIENT_PORT,
+};
+
+mod common;
+
+use common::{connection, segment, tcp_header};
+
+const LISTEN_PORT: u16 = 443;
+
+fn stack() -> Stack {
+    let mut listeners = Listeners::default();
+    listeners.insert(LISTEN_PORT, ListenerLimits::default());
+
+    Stack::new(listeners, IsnGenerator::new([1; 16]), Instant::now())
+}
+
+#[test]
+fn syn_to_listening_port_opens_connection() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| header.syn = true);
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert!(tcp_header(&sent[0]).syn() && tcp_header(&sent[0]).ack());
+
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::SynRcvd);
+    assert_eq!(stack.stats().packets_in, 1);
+    assert_eq!(stack.stats().connections_accepted, 1);
+}
+
+#[test]
+fn syn_to_closed_port_is_reset() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, 80, |header| header.syn = true);
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert!(tcp_header(&sent[0]).rst());
+    assert_eq!(stack.connections().count(), 0);
+    assert_eq!(stack.stats().segments_to_closed_ports, 1);
+}
+
+/// The deadline reported covers the connection's retransmission timer, which fires
+/// when the stack is ticked at that time
+#[test]
+fn timers_run_at_next_deadline() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    assert_eq!(stack.next_deadline(), None);
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| header.syn = true);
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+    device.take_sent();
+
+    let deadline: Instant = stack.next_deadline().unwrap();
+    stack
+        .on_tick(&device, deadline - Duration::from_millis(1))
+        .unwrap();
+    assert!(device.take_sent().is_empty());
+
+    stack.on_tick(&device, deadline).unwrap();
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert!(tcp_header(&sent[0]).syn());
+}
+
+#[test]
+fn closed_connections_are_deleted() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| header.syn = true);
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+    let rst: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| {
+        header.sequence_number = 101;
+        header.rst = true;
+    });
+    stack.on_packet(&device, &rst, Instant::now()).unwrap();
+    assert_eq!(
+        stack
+            .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+            .unwrap()
+            .state(),
+        State::Closed
+    );
+
+    stack.on_tick(&device, Instant::now()).unwrap();
+    assert_eq!(stack.connections().count(), 0);
+    assert_eq!(stack.stats().connections_closed, 1);
+    assert_eq!(stack.next_deadline(), None);
+}
+
+#[test]
+fn zero_ports_are_dropped_and_counted() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+
+    let zero_source: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| {
+        header.source_port = 0;
+        header.syn = true;
+    });
+    let zero_destination: Vec<u8> = segment(CLIENT_PORT, 0, |header| header.syn = true);
+
+    for packet in [zero_source, zero_destination] {
+        stack.on_packet(&device, &packet, Instant::now()).unwrap();
+    }
+
+    assert!(device.take_sent().is_empty());
+    assert_eq!(stack.connections().count(), 0);
+    assert_eq!(stack.stats().zero_port_segments, 2);
+    assert_eq!(stack.stats().segments_to_closed_ports, 0);
+}
+
+/// Malformed and degenerate packets found by fuzzing the packet path. None of them may
+/// panic, get a reply or open a connection.
+#[test]
+fn degenerate_headers_are_dropped() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| header.syn = true);
+
+    let with_byte = |index: usize, value: u8| -> Vec<u8> {
+        let mut packet: Vec<u8> = syn.clone();
+        packet[index] = value;
+        packet
+    };
+
+    let packets: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        // IHL below the minimum of 5 words
+        with_byte(0, 0x44),
+        // IHL past the end of the packet
+        with_byte(0, 0x4f),
+        // Total length shorter than the IP header
+        with_byte(3, 10),
+        // TCP data offset below the minimum of 5 words
+        with_byte(32, 0x40),
+        // TCP data offset past the end of the packet
+        with_byte(32, 0xf0),
+        // TCP header cut short
+        syn[..30].to_vec(),
+        // A SYN with RST set is a reset, which has nothing to reset
+        segment(CLIENT_PORT, LISTEN_PORT, |header| {
+            header.syn = true;
+            header.rst = true;
+        }),
+        // No flags at all
+        segment(CLIENT_PORT, LISTEN_PORT, |_| {}),
+    ];
+
+    for packet in &packets {
+        stack.on_packet(&device, packet, Instant::now()).unwrap();
+    }
+
+    assert!(device.take_sent().is_empty());
+    assert_eq!(stack.connections().count(), 0);
+    assert_eq!(stack.stats().packets_in, packets.len() as u64);
+}
+
+/// A read which stops short of the lengths in the IP header is counted rather than
+/// parsed as a segment
+#[test]
+fn truncated_reads_are_dropped_and_counted() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| header.syn = true);
+
+    // Cut into the TCP header, then cut off just the last byte
+    for len in [30, syn.len() - 1] {
+        stack
+            .on_packet(&device, &syn[..len], Instant::now())
+            .unwrap();
+    }
+
+    assert!(device.take_sent().is_empty());
+    assert_eq!(stack.connections().count(), 0);
+    assert_eq!(stack.stats().truncated_packets, 2);
+    assert_eq!(stack.stats().packets_invalid, 0);
+
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+    assert_eq!(stack.connections().count(), 1);
+}
+
+/// Open a connection from the peer and close it from our end first, leaving it in
+/// TIME-WAIT with RCV.NXT at 102. With `ts_val` every segment from the peer carries
+/// that timestamp.
+fn time_wait(device: &CaptureDevice, stack: &mut Stack, ts_val: Option<u32>) {
+    let with_timestamp = |header: &mut TcpHeader| {
+        if let Some(ts_val) = ts_val {
+            header
+                .set_options(&[TcpOptionElement::Timestamp(ts_val, 0)])
+                .unwrap();
+        }
+    };
+
+    stack
+        .on_packet(
+            device,
+            &segment(CLIENT_PORT, LISTEN_PORT, |header| {
+                header.syn = true;
+                with_timestamp(header);
+            }),
+            Instant::now(),
+        )
+        .unwrap();
+    let iss: u32 = tcp_header(&device.take_sent()[0]).sequence_number();
+
+    let ack = |header: &mut TcpHeader, seq: u32, ack: u32| {
+        header.sequence_number = seq;
+        header.acknowledgment_number = ack;
+        header.ack = true;
+        with_timestamp(header);
+    };
+
+    stack
+        .on_packet(
+            device,
+            &segment(CLIENT_PORT, LISTEN_PORT, |header| ack(header, 101, iss + 1)),
+            Instant::now(),
+        )
+        .unwrap();
+    stack
+        .close(device, &connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    stack
+        .on_packet(
+            device,
+            &segment(CLIENT_PORT, LISTEN_PORT, |header| {
+                ack(header, 101, iss + 2);
+                header.fin = true;
+            }),
+            Instant::now(),
+        )
+        .unwrap();
+
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::TimeWait);
+    device.take_sent();
+}
+
+#[test]
+fn new_incarnation_reopens_time_wait_when_allowed() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    stack
+        .listeners_mut()
+        .set_time_wait_policy(TimeWaitPolicy::Reopen);
+    time_wait(&device, &mut stack, None);
+    let old_id = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap()
+        .id();
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| {
+        header.sequence_number = 1000;
+        header.syn = true;
+    });
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+    let sent: Vec<Vec<u8>> = device.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert!(tcp_header(&sent[0]).syn() && tcp_header(&sent[0]).ack());
+    assert_eq!(tcp_header(&sent[0]).acknowledgment_number(), 1001);
+
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::SynRcvd);
+    assert_ne!(tcb.id(), old_id);
+    assert_eq!(stack.stats().time_wait_reopened, 1);
+    assert_eq!(stack.stats().connections_accepted, 2);
+}
+
+/// A SYN which isn't clearly from a new incarnation, or any SYN when they're refused,
+/// leaves the old connection in TIME-WAIT
+#[test]
+fn old_syn_or_default_policy_keeps_time_wait() {
+    let syn = |seq: u32, ts_val: Option<u32>| {
+        segment(CLIENT_PORT, LISTEN_PORT, |header| {
+            header.sequence_number = seq;
+            header.syn = true;
+            if let Some(ts_val) = ts_val {
+                header
+                    .set_options(&[TcpOptionElement::Timestamp(ts_val, 0)])
+                    .unwrap();
+            }
+        })
+    };
+
+    let cases: [(TimeWaitPolicy, Option<u32>, Vec<u8>); 3] = [
+        (TimeWaitPolicy::Refuse, None, syn(1000, None)),
+        (TimeWaitPolicy::Reopen, None, syn(50, None)),
+        // Beyond RCV.NXT, but its timestamp is no newer than the old connection's
+        (TimeWaitPolicy::Reopen, Some(10), syn(1000, Some(10))),
+    ];
+
+    for (policy, ts_val, syn) in cases {
+        let device = CaptureDevice::default();
+        let mut stack = stack();
+        stack.listeners_mut().set_time_wait_policy(policy);
+        time_wait(&device, &mut stack, ts_val);
+
+        stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+        let tcb = stack
+            .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+            .unwrap();
+        assert_eq!(tcb.state(), State::TimeWait, "{policy:?} {ts_val:?}");
+        assert_eq!(stack.stats().time_wait_reopened, 0);
+    }
+}
+
+/// Only a listener takes a new incarnation. A connection we opened ourselves, or one
+/// whose listener has gone, stays in TIME-WAIT and the SYN isn't answered with a reset.
+#[test]
+fn time_wait_only_reopens_for_a_listener() {
+    const ACTIVE_PORT: u16 = 5000;
+    let new_syn = |port: u16| {
+        segment(CLIENT_PORT, port, |header| {
+            header.sequence_number = 1000;
+            header.syn = true;
+        })
+    };
+
+    // Opened by us, to the peer's port 40000, and closed from our end first
+    let device = CaptureDevice::default();
+    let mut active = stack();
+    active
+        .listeners_mut()
+        .set_time_wait_policy(TimeWaitPolicy::Reopen);
+    let local = SocketAddrV4::new([192, 168, 0, 2].into(), ACTIVE_PORT);
+    let remote = SocketAddrV4::new([192, 168, 0, 1].into(), 40000);
+    let info: ConnectInfo = active.connect(&device, local, remote).unwrap();
+    let iss: u32 = tcp_header(&device.take_sent()[0]).sequence_number();
+    let syn_ack: Vec<u8> = segment(CLIENT_PORT, ACTIVE_PORT, |header| {
+        header.acknowledgment_number = iss + 1;
+        header.syn = true;
+        header.ack = true;
+    });
+    active.on_packet(&device, &syn_ack, Instant::now()).unwrap();
+    active.close(&device, &info).unwrap();
+    let fin: Vec<u8> = segment(CLIENT_PORT, ACTIVE_PORT, |header| {
+        header.sequence_number = 101;
+        header.acknowledgment_number = iss + 2;
+        header.ack = true;
+        header.fin = true;
+    });
+    active.on_packet(&device, &fin, Instant::now()).unwrap();
+    assert_eq!(active.connection(&info).unwrap().state(), State::TimeWait);
+    device.take_sent();
+
+    active
+        .on_packet(&device, &new_syn(ACTIVE_PORT), Instant::now())
+        .unwrap();
+
+    assert!(device
+        .take_sent()
+        .iter()
+        .all(|packet| !tcp_header(packet).rst()));
+    assert_eq!(active.connection(&info).unwrap().state(), State::TimeWait);
+    assert_eq!(active.stats().time_wait_reopened, 0);
+
+    // Accepted, but the listener has since been removed
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    stack
+        .listeners_mut()
+        .set_time_wait_policy(TimeWaitPolicy::Reopen);
+    time_wait(&device, &mut stack, None);
+    stack.listeners_mut().remove(LISTEN_PORT);
+
+    stack
+        .on_packet(&device, &new_syn(LISTEN_PORT), Instant::now())
+        .unwrap();
+
+    assert!(device
+        .take_sent()
+        .iter()
+        .all(|packet| !tcp_header(packet).rst()));
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::TimeWait);
+    assert_eq!(stack.stats().time_wait_reopened, 0);
+}
+
+/// RFC 1337, a reset can't cut TIME-WAIT short, even exactly at RCV.NXT
+#[test]
+fn reset_is_ignored_in_time_wait() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    time_wait(&device, &mut stack, None);
+
+    let rst: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| {
+        header.sequence_number = 102;
+        header.rst = true;
+    });
+    stack.on_packet(&device, &rst, Instant::now()).unwrap();
+    stack.on_tick(&device, Instant::now()).unwrap();
+
+    assert!(device.take_sent().is_empty());
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::TimeWait);
+}
+
+/// With timestamps the SYN's timestamp decides, whatever its sequence number
+#[test]
+fn newer_timestamp_reopens_time_wait() {
+    let device = CaptureDevice::default();
+    let mut stack = stack();
+    stack
+        .listeners_mut()
+        .set_time_wait_policy(TimeWaitPolicy::Reopen);
+    time_wait(&device, &mut stack, Some(10));
+
+    let syn: Vec<u8> = segment(CLIENT_PORT, LISTEN_PORT, |header| {
+        header.sequence_number = 50;
+        header.syn = true;
+        header
+            .set_options(&[TcpOptionElement::Timestamp(20, 0)])
+            .unwrap();
+    });
+    stack.on_packet(&device, &syn, Instant::now()).unwrap();
+
+    let tcb = stack
+        .connection(&connection(CLIENT_PORT, LISTEN_PORT))
+        .unwrap();
+    assert_eq!(tcb.state(), State::SynRcvd);
+    assert_eq!(stack.stats().time_wait_reopened, 1);
+}
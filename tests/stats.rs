@@ -0,0 +1,275 @@
+//! Snapshotting and resetting counters, locally and over the admin socket
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+#[cfg(unix)]
+use tcp_rs::admin::{AdminCommand, AdminSocket};
+use tcp_rs::{
+    clock::{self, Clock},
+    device::CaptureDevice,
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::ListenerLimits,
+    stats::{ConnectionStats, ConnectionSummary, StatsRecorder},
+    tcp::{ConnectInfo, State, Tcb},
+    testing::Wan,
+};
+
+/// A connection in SYN-RECEIVED, having received one SYN and sent one SYN-ACK
+fn accepted_connection() -> (ConnectInfo, Tcb) {
+    let mut syn = TcpHeader::new(40000, 443, 100, 8192);
+    syn.syn = true;
+
+    let ip_header = Ipv4Header::new(
+        syn.header_len_u16(),
+        64,
+        IpNumber::TCP,
+        [192, 168, 0, 1],
+        [192, 168, 0, 2],
+    )
+    .unwrap();
+    syn.checksum = syn.calc_checksum_ipv4(&ip_header, &[]).unwrap();
+
+    let mut packet: Vec<u8> = Vec::new();
+    ip_header.write(&mut packet).unwrap();
+    syn.write(&mut packet).unwrap();
+
+    let ip_slice = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+    let tcp_slice = TcpHeaderSlice::from_slice(&packet[ip_slice.slice().len()..]).unwrap();
+
+    let device = CaptureDevice::default();
+    let isn = IsnGenerator::new([1; 16]);
+    let tcb = Tcb::accept_connection(&device, ip_slice, tcp_slice, &[], &isn, &clock::system())
+        .unwrap()
+        .unwrap();
+
+    let info = ConnectInfo {
+        src_addr: Ipv4Addr::new(192, 168, 0, 1),
+        src_port: 40000,
+        dst_addr: Ipv4Addr::new(192, 168, 0, 2),
+        dst_port: 443,
+    };
+
+    (info, tcb)
+}
+
+#[test]
+fn snapshot_and_reset_starts_a_new_interval() {
+    let start = Instant::now();
+    let mut recorder = StatsRecorder::new(start);
+    let mut connections: HashMap<ConnectInfo, Tcb> = HashMap::from([accepted_connection()]);
+
+    recorder.stack.packets_in = 5;
+
+    let snapshot = recorder.snapshot(&mut connections, start + Duration::from_secs(2), false);
+    assert_eq!(snapshot.interval, Duration::from_secs(2));
+    assert_eq!(snapshot.stack.packets_in, 5);
+    assert_eq!(snapshot.connections[0].2.segments_out, 1);
+
+    let snapshot = recorder.snapshot(&mut connections, start + Duration::from_secs(3), true);
+    assert_eq!(snapshot.interval, Duration::from_secs(3));
+    assert_eq!(snapshot.stack.packets_in, 5);
+    assert_eq!(snapshot.connections[0].2.segments_out, 1);
+
+    let snapshot = recorder.snapshot(&mut connections, start + Duration::from_secs(4), false);
+    assert_eq!(snapshot.interval, Duration::from_secs(1));
+    assert_eq!(snapshot.stack.packets_in, 0);
+    assert_eq!(snapshot.connections[0].2, ConnectionStats::default());
+}
+
+#[test]
+#[cfg(unix)]
+fn admin_socket_answers_commands() {
+    let path = std::env::temp_dir().join(format!("tcp_rs_admin_{}.sock", std::process::id()));
+    let admin = AdminSocket::bind(&path).unwrap();
+
+    let mut recorder = StatsRecorder::new(Instant::now());
+    let mut connections: HashMap<ConnectInfo, Tcb> = HashMap::from([accepted_connection()]);
+    recorder.stack.connections_accepted = 1;
+
+    let mut stats_client = UnixStream::connect(&path).unwrap();
+    stats_client.write_all(b"stats reset\n").unwrap();
+    let mut bad_client = UnixStream::connect(&path).unwrap();
+    bad_client.write_all(b"bogus\n").unwrap();
+
+    let mut commands: Vec<AdminCommand> = Vec::new();
+    admin
+        .serve(|command| {
+            commands.push(command.clone());
+            match command {
+                AdminCommand::Stats { reset } => recorder
+                    .snapshot(&mut connections, Instant::now(), reset)
+                    .to_string(),
+                command => panic!("unexpected command {command:?}"),
+            }
+        })
+        .unwrap();
+
+    assert_eq!(commands, [AdminCommand::Stats { reset: true }]);
+    assert_eq!(recorder.stack.connections_accepted, 0);
+
+    let mut response = String::new();
+    stats_client.read_to_string(&mut response).unwrap();
+    assert!(response.contains("connections_accepted=1"), "{response}");
+    assert!(
+        response.contains(
+            "connection 192.168.0.1:40000 -> 192.168.0.2:443 SynRcvd segments_in=0 segments_out=1"
+        ),
+        "{response}"
+    );
+
+    let mut response = String::new();
+    bad_client.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("unknown command \"bogus\""),
+        "{response}"
+    );
+
+    drop(admin);
+    assert!(!path.exists());
+}
+
+#[test]
+fn capture_commands_are_parsed() {
+    assert_eq!(
+        AdminCommand::parse("capture start /tmp/tun0.pcap\n"),
+        Some(AdminCommand::StartCapture {
+            path: "/tmp/tun0.pcap".into()
+        })
+    );
+    assert_eq!(
+        AdminCommand::parse("capture stop"),
+        Some(AdminCommand::StopCapture)
+    );
+    assert_eq!(AdminCommand::parse("capture start"), None);
+}
+
+#[test]
+#[cfg(unix)]
+fn connection_commands_are_parsed() {
+    let connection = ConnectInfo {
+        src_addr: Ipv4Addr::new(192, 168, 0, 1),
+        src_port: 40000,
+        dst_addr: Ipv4Addr::new(192, 168, 0, 2),
+        dst_port: 443,
+    };
+
+    assert_eq!(
+        AdminCommand::parse("connections\n"),
+        Some(AdminCommand::Connections)
+    );
+    assert_eq!(
+        AdminCommand::parse("abort 192.168.0.1:40000 192.168.0.2:443"),
+        Some(AdminCommand::Abort { connection })
+    );
+    // As the connection is listed
+    assert_eq!(
+        AdminCommand::parse(&format!("abort {connection}")),
+        Some(AdminCommand::Abort { connection })
+    );
+    assert_eq!(
+        AdminCommand::parse("abort 192.168.0.1 192.168.0.2:443"),
+        None
+    );
+}
+
+#[test]
+fn connections_are_summarised_with_their_sequence_variables() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 100]).unwrap();
+    wan.run_until_idle().unwrap();
+
+    let summaries: Vec<ConnectionSummary> = wan.server.stack.connection_summaries();
+    assert_eq!(summaries.len(), 1);
+    let server: ConnectionSummary = summaries[0];
+    assert_eq!(server.connection, connection.server);
+    assert_eq!(server.state, State::Estab);
+    assert_eq!(server.recv_queue, 100);
+    assert_eq!(server.send_queue, 0);
+    assert_eq!(server.stats.bytes_in, 100);
+
+    let client: ConnectionSummary = wan
+        .client
+        .stack
+        .connection(&connection.client)
+        .unwrap()
+        .summary();
+    assert_eq!(client.connection, connection.client);
+    assert_eq!(client.snd_una, client.snd_nxt);
+    assert_eq!(client.snd_nxt, server.rcv_nxt);
+    assert_eq!(client.snd_wnd, server.rcv_wnd);
+    assert!(server.to_string().contains(" Estab snd_una="));
+}
+
+#[test]
+fn losses_are_counted_on_each_side() {
+    let mut wan = Wan::lossless();
+    wan.listen(443, ListenerLimits::default());
+    let connection = wan.connect(40000, 443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, &[1; 1000]).unwrap();
+
+    // The first segment is lost, so the second arrives out of order
+    let in_flight: Vec<Vec<u8>> = wan.server.device.inner().take_pending();
+    assert_eq!(in_flight.len(), 2);
+    wan.client.device.inner().send(&in_flight[1]).unwrap();
+    wan.server.receive(wan.clock.now()).unwrap();
+    wan.client.receive(wan.clock.now()).unwrap();
+
+    let server: ConnectionStats = wan
+        .server
+        .stack
+        .connection(&connection.server)
+        .unwrap()
+        .stats();
+    assert_eq!(server.out_of_order_segments, 1);
+    let client: ConnectionStats = wan
+        .client
+        .stack
+        .connection(&connection.client)
+        .unwrap()
+        .stats();
+    assert_eq!(client.duplicate_acks, 1);
+    assert_eq!(client.retransmissions, 0);
+
+    // It's sent again once the retransmission timer expires
+    wan.run_until(10, |wan| {
+        let server = wan.server.stack.connection(&connection.server).unwrap();
+        server.unread_len() == 1000
+    })
+    .unwrap();
+
+    let client: ConnectionStats = wan
+        .client
+        .stack
+        .connection(&connection.client)
+        .unwrap()
+        .stats();
+    assert_eq!(client.retransmissions, 1);
+    assert!(client.rtt.is_some());
+}
+
+#[test]
+fn resets_for_closed_ports_are_counted() {
+    let mut wan = Wan::lossless();
+    wan.open(40000, 443).unwrap();
+    wan.run_until_idle().unwrap();
+
+    assert_eq!(wan.server.stack.stats().resets_out, 1);
+    assert_eq!(wan.server.stack.stats().segments_to_closed_ports, 1);
+}
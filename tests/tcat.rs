@@ -0,0 +1,30 @@
+//! Argument handling for the netcat-like `tcat` subcommand
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tcp_rs::tcat::TcatMode;
+
+fn parse(args: &[&str]) -> Option<TcatMode> {
+    TcatMode::from_args(args.iter().map(|arg| arg.to_string())).ok()
+}
+
+#[test]
+fn modes_are_parsed() {
+    assert_eq!(
+        parse(&["192.168.0.1", "8080"]),
+        Some(TcatMode::Connect(SocketAddrV4::new(
+            Ipv4Addr::new(192, 168, 0, 1),
+            8080
+        )))
+    );
+    assert_eq!(parse(&["-l", "443"]), Some(TcatMode::Listen(443)));
+}
+
+#[test]
+fn bad_arguments_are_rejected() {
+    assert_eq!(parse(&[]), None);
+    assert_eq!(parse(&["-l"]), None);
+    assert_eq!(parse(&["-l", "70000"]), None);
+    assert_eq!(parse(&["localhost", "80"]), None);
+    assert_eq!(parse(&["192.168.0.1", "80", "extra"]), None);
+}
@@ -0,0 +1,85 @@
+//! The two stack test fixture, as a downstream crate would use it
+
+use std::time::{Duration, Instant};
+
+use tcp_rs::{
+    clock::Clock,
+    impair::{Impairment, ImpairmentStats},
+    listener::ListenerLimits,
+    tcp::State,
+    testing::{Side, Wan},
+};
+
+const PORT: u16 = 443;
+
+fn lossy() -> Impairment {
+    Impairment {
+        loss: 0.1,
+        duplicate: 0.05,
+        reorder: 0.1,
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(20),
+    }
+}
+
+#[test]
+fn lossless_link_connects_transfers_and_closes() {
+    let mut wan = Wan::lossless();
+    wan.listen(PORT, ListenerLimits::default());
+
+    let connection = wan.connect(40000, PORT).unwrap();
+    assert_eq!(
+        wan.transfer(connection, Side::Client, b"ping").unwrap(),
+        b"ping"
+    );
+    assert_eq!(
+        wan.transfer(connection, Side::Server, b"pong").unwrap(),
+        b"pong"
+    );
+
+    wan.close(connection, Side::Client).unwrap();
+    wan.run_until_idle().unwrap();
+    wan.close(connection, Side::Server).unwrap();
+    wan.run_until_idle().unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::TimeWait));
+    assert_eq!(wan.server.state(&connection.server), Some(State::Closed));
+
+    wan.run_until(10, |wan| wan.client.stack.connections().count() == 0)
+        .unwrap();
+    assert_eq!(wan.server.stack.connections().count(), 0);
+}
+
+/// A handshake over a link with a fixed delay takes one and a half round trips
+#[test]
+fn delay_sets_the_round_trip_time() {
+    let impairment = Impairment {
+        delay: Duration::from_millis(50),
+        ..Impairment::default()
+    };
+    let mut wan = Wan::new(impairment, 0);
+    wan.listen(PORT, ListenerLimits::default());
+    let start: Instant = wan.clock.now();
+
+    wan.connect(40000, PORT).unwrap();
+
+    assert_eq!(wan.clock.now() - start, Duration::from_millis(150));
+}
+
+#[test]
+fn same_seed_plays_out_the_same() {
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+    let run = |seed: u64| -> (Duration, ImpairmentStats) {
+        let mut wan = Wan::new(lossy(), seed);
+        wan.listen(PORT, ListenerLimits::default());
+        let start: Instant = wan.clock.now();
+
+        let connection = wan.connect(40000, PORT).unwrap();
+        assert_eq!(wan.transfer(connection, Side::Client, &data).unwrap(), data);
+
+        (wan.clock.now() - start, wan.client.device.stats())
+    };
+
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
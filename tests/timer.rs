@@ -0,0 +1,113 @@
+//! Connection timers kept in order by the stack
+
+use std::time::{Duration, Instant};
+
+use tcp_rs::{
+    clock::Clock,
+    tcp::{KeepaliveConfig, SocketOption, State},
+    testing::Wan,
+    timer::TimerQueue,
+};
+
+mod common;
+
+use common::connection;
+
+#[test]
+fn timers_expire_in_order_of_deadline() {
+    let start = Instant::now();
+    let mut timers = TimerQueue::default();
+    timers.schedule(start + Duration::from_secs(3), connection(3, 443));
+    timers.schedule(start + Duration::from_secs(1), connection(1, 443));
+    timers.schedule(start + Duration::from_secs(2), connection(2, 443));
+    assert_eq!(timers.next_deadline(), Some(start + Duration::from_secs(1)));
+
+    let now = start + Duration::from_secs(2);
+    assert_eq!(timers.pop_expired(now), Some(connection(1, 443)));
+    assert_eq!(timers.pop_expired(now), Some(connection(2, 443)));
+    assert_eq!(timers.pop_expired(now), None);
+    assert_eq!(timers.len(), 1);
+}
+
+#[test]
+fn cancelled_timers_never_expire() {
+    let start = Instant::now();
+    let mut timers = TimerQueue::default();
+    let first = timers.schedule(start, connection(1, 443));
+    // The same deadline twice is two timers
+    timers.schedule(start, connection(2, 443));
+
+    assert_eq!(timers.cancel(first), Some(connection(1, 443)));
+    assert_eq!(timers.cancel(first), None);
+    assert_eq!(timers.pop_expired(start), Some(connection(2, 443)));
+    assert!(timers.is_empty());
+}
+
+#[test]
+fn each_connection_is_scheduled_at_its_next_deadline() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    wan.client
+        .stack
+        .on_tick(&wan.client.device, wan.clock.now())
+        .unwrap();
+
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.next_deadline(), None);
+    assert_eq!(client.timer(), None);
+
+    // The retransmission timer starts with the data, and stops with its ACK
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.send(&wan.client.device, b"hello").unwrap();
+    let retransmit: Option<Instant> = client.next_deadline();
+    assert!(retransmit.is_some());
+    wan.client
+        .stack
+        .on_tick(&wan.client.device, wan.clock.now())
+        .unwrap();
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.timer().map(|timer| timer.deadline()), retransmit);
+
+    wan.run_until_idle().unwrap();
+    wan.client
+        .stack
+        .on_tick(&wan.client.device, wan.clock.now())
+        .unwrap();
+    let client = wan.client.stack.connection(&connection.client).unwrap();
+    assert_eq!(client.timer(), None);
+}
+
+#[test]
+fn changes_are_seen_before_the_next_tick() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+    let keepalive = KeepaliveConfig {
+        idle: Duration::from_secs(10),
+        ..KeepaliveConfig::DEFAULT
+    };
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client
+        .set_option(SocketOption::Keepalive(Some(keepalive)))
+        .unwrap();
+    let deadline: Instant = wan.clock.now() + Duration::from_secs(10);
+    assert_eq!(wan.client.stack.next_deadline(), Some(deadline));
+
+    // Turning it off again before the tick leaves nothing to wait for
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.set_option(SocketOption::Keepalive(None)).unwrap();
+    assert_eq!(wan.client.stack.next_deadline(), None);
+}
+
+#[test]
+fn connections_closed_between_ticks_are_deleted() {
+    let (mut wan, connection) = Wan::lossless().established(443).unwrap();
+
+    let client = wan.client.stack.connection_mut(&connection.client).unwrap();
+    client.abort(&wan.client.device).unwrap();
+    assert_eq!(wan.client.state(&connection.client), Some(State::Closed));
+
+    wan.client
+        .stack
+        .on_tick(&wan.client.device, wan.clock.now())
+        .unwrap();
+    assert_eq!(wan.client.state(&connection.client), None);
+}
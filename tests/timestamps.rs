@@ -0,0 +1,89 @@
+//! Timestamps option and PAWS, RFC 7323
+
+use std::time::{Duration, Instant};
+
+use tcp_rs::{
+    options,
+    seq::SeqNum,
+    timestamps::{Timestamps, PAWS_IDLE_LIMIT},
+};
+
+#[test]
+fn timestamps_option_is_parsed() {
+    let raw: [u8; 12] = [1, 1, 8, 10, 0, 0, 0, 7, 0, 0, 1, 44];
+    assert_eq!(options::timestamps(&raw), Some((7, 300)));
+
+    // Wrong length
+    assert_eq!(options::timestamps(&[8, 6, 0, 0, 0, 7]), None);
+    assert_eq!(options::timestamps(&[2, 4, 5, 180]), None);
+}
+
+#[test]
+fn ts_val_counts_milliseconds_from_offset() {
+    let now = Instant::now();
+    let timestamps = Timestamps::new(u32::MAX - 1, 0, now);
+
+    assert_eq!(timestamps.ts_val(now), u32::MAX - 1);
+    assert_eq!(timestamps.ts_val(now + Duration::from_millis(5)), 3);
+}
+
+/// RFC 7323 Section 5.3 R1, compared modulo 2^32
+#[test]
+fn older_ts_val_is_rejected() {
+    let now = Instant::now();
+    let timestamps = Timestamps::new(0, 1000, now);
+
+    assert!(timestamps.is_old(999, now));
+    assert!(!timestamps.is_old(1000, now));
+    assert!(!timestamps.is_old(1001, now));
+    assert!(timestamps.is_old(1000u32.wrapping_add(1 << 31), now));
+}
+
+/// RFC 7323 Section 4.3, only segments covering Last.ACK.sent update TS.Recent
+#[test]
+fn recent_follows_last_ack_sent() {
+    let now = Instant::now();
+    let mut timestamps = Timestamps::new(0, 1000, now);
+    timestamps.on_ack_sent(SeqNum::new(101));
+
+    timestamps.update_recent(1010, SeqNum::new(101), now);
+    assert_eq!(timestamps.recent(), 1010);
+
+    // Past what we've acknowledged, so the delayed ACK keeps echoing the earlier segment
+    timestamps.update_recent(1020, SeqNum::new(111), now);
+    assert_eq!(timestamps.recent(), 1010);
+
+    // Older than TS.Recent
+    timestamps.update_recent(1005, SeqNum::new(101), now);
+    assert_eq!(timestamps.recent(), 1010);
+}
+
+/// RFC 7323 Section 5.5, after 24 days idle the peer's clock may have wrapped
+#[test]
+fn recent_is_invalidated_after_idle_limit() {
+    let now = Instant::now();
+    let mut timestamps = Timestamps::new(0, 1000, now);
+    timestamps.on_ack_sent(SeqNum::new(101));
+
+    assert!(timestamps.is_old(10, now + PAWS_IDLE_LIMIT));
+    assert!(!timestamps.is_old(10, now + PAWS_IDLE_LIMIT + Duration::from_secs(1)));
+
+    timestamps.update_recent(
+        10,
+        SeqNum::new(101),
+        now + PAWS_IDLE_LIMIT + Duration::from_secs(1),
+    );
+    assert_eq!(timestamps.recent(), 10);
+}
+
+/// The SYN,ACK's TSval is echoed whatever it is, as nothing was acknowledged before it
+#[test]
+fn recent_is_taken_from_syn_ack() {
+    let now = Instant::now();
+    let mut timestamps = Timestamps::new(0, 0, now);
+
+    timestamps.on_syn(0x8211_093f, now);
+
+    assert_eq!(timestamps.recent(), 0x8211_093f);
+    assert!(!timestamps.is_old(0x8211_0940, now));
+}
@@ -0,0 +1,121 @@
+//! The async front end on a tokio runtime, with the packet pump as a task and streams
+//! used through tokio's `AsyncRead` and `AsyncWrite`. The peer is a second stack on the
+//! other end of a socket pair, pumped by a thread of its own.
+#![cfg(all(unix, feature = "tokio"))]
+
+use std::{
+    io,
+    net::SocketAddrV4,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    thread,
+    time::Instant,
+};
+
+use tcp_rs::{
+    async_stack::{block_on, compat, AsyncStack, AsyncTcpStream},
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ListenerLimits, Listeners},
+    stack::Stack,
+};
+use tokio::{prelude::future, runtime::current_thread::Runtime};
+
+const LOCAL_ADDR: [u8; 4] = [192, 168, 0, 2];
+const PEER_ADDR: [u8; 4] = [192, 168, 0, 1];
+
+/// One end of a datagram socket pair, each datagram being one IP packet
+struct SocketDevice(UnixDatagram);
+
+impl NetworkDevice for SocketDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+}
+
+impl AsRawFd for SocketDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Our stack, pumped by a task on `runtime`, and the peer's
+fn stacks(runtime: &mut Runtime) -> (AsyncStack<SocketDevice>, AsyncStack<SocketDevice>) {
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    ours.set_nonblocking(true).unwrap();
+    theirs.set_nonblocking(true).unwrap();
+    let stack = |key: u8| {
+        Stack::new(
+            Listeners::default(),
+            IsnGenerator::new([key; 16]),
+            Instant::now(),
+        )
+    };
+
+    let ours = runtime
+        .block_on(future::lazy(|| {
+            AsyncStack::spawn_tokio(SocketDevice(ours), stack(1))
+        }))
+        .unwrap();
+    let theirs = AsyncStack::spawn(SocketDevice(theirs), stack(2)).unwrap();
+    (ours, theirs)
+}
+
+#[test]
+fn accepted_stream_is_read_and_written_with_tokio_io() {
+    let mut runtime = Runtime::new().unwrap();
+    let (stack, peer_stack) = stacks(&mut runtime);
+    let listener = stack.listen(80, ListenerLimits::default());
+
+    let peer = thread::spawn(move || {
+        let stream = block_on(peer_stack.connect(
+            SocketAddrV4::new(PEER_ADDR.into(), 0),
+            SocketAddrV4::new(LOCAL_ADDR.into(), 80),
+        ))
+        .unwrap();
+        block_on(stream.write_all(b"hello")).unwrap();
+        block_on(stream.shutdown()).unwrap();
+
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf: [u8; 64] = [0; 64];
+        loop {
+            let n_read: usize = block_on(stream.read(&mut buf)).unwrap();
+            if n_read == 0 {
+                return received;
+            }
+            received.extend_from_slice(&buf[..n_read]);
+        }
+    });
+
+    let stream: AsyncTcpStream<SocketDevice> = runtime
+        .block_on(compat(async move { listener.accept().await }))
+        .unwrap()
+        .unwrap();
+    let (stream, request) = runtime
+        .block_on(tokio::io::read_to_end(stream, Vec::new()))
+        .unwrap();
+    assert_eq!(request, b"hello");
+
+    let (stream, _) = runtime
+        .block_on(tokio::io::write_all(stream, b"world"))
+        .unwrap();
+    let stream = runtime.block_on(tokio::io::shutdown(stream)).unwrap();
+    assert_eq!(peer.join().unwrap(), b"world");
+    drop(stream);
+}
+
+/// The pump task finishes once the stack is dropped, so the runtime can too
+#[test]
+fn pump_task_ends_with_the_stack() {
+    let mut runtime = Runtime::new().unwrap();
+    let (stack, _peer_stack) = stacks(&mut runtime);
+
+    drop(stack);
+    runtime.run().unwrap();
+}
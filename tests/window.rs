@@ -0,0 +1,31 @@
+//! Window scaling, RFC 7323
+
+use tcp_rs::window::{WindowScale, MAX_SHIFT};
+
+#[test]
+fn shift_is_clamped() {
+    assert_eq!(WindowScale::new(15).shift(), MAX_SHIFT);
+    assert_eq!(WindowScale::new(15).max_window(), 0xffff << 14);
+}
+
+#[test]
+fn advertised_window_rounds_down_and_clamps() {
+    let scale = WindowScale::new(7);
+
+    assert_eq!(scale.to_field(1000, false), 7);
+    assert_eq!(scale.advertised(1000, false), 896);
+
+    assert_eq!(scale.to_field(u32::MAX, false), u16::MAX);
+    assert_eq!(scale.advertised(u32::MAX, false), scale.max_window());
+
+    assert_eq!(WindowScale::NONE.to_field(100_000, false), u16::MAX);
+}
+
+#[test]
+fn syn_windows_are_never_scaled() {
+    let scale = WindowScale::new(7);
+
+    assert_eq!(scale.from_field(1000, true), 1000);
+    assert_eq!(scale.from_field(1000, false), 128_000);
+    assert_eq!(scale.to_field(1000, true), 1000);
+}
@@ -0,0 +1,902 @@
+//! Byte-for-byte wire vectors for the handshake, close and reset paths.
+//!
+//! Each step feeds one inbound IPv4 packet into the core and asserts the exact packets
+//! sent in response. Vectors are written with a fixed initial send sequence number,
+//! so the harness shifts our sequence space (and the peer's acknowledgements of it)
+//! by the ISS actually chosen, recomputing the TCP checksum after each shift.
+//! Our TSvals come from a clock, so they're checked to never go backwards and then
+//! replaced with the ones the vectors were written with.
+
+use std::{
+    cell::RefCell,
+    io,
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use tcp_rs::{
+    challenge::ChallengeAckLimiter,
+    clock,
+    config::TcbConfig,
+    device::NetworkDevice,
+    isn::IsnGenerator,
+    listener::{ClosedPortPolicy, ListenerLimits, Listeners},
+    options,
+    rto::DEFAULT_INITIAL_RTO,
+    tcp::{self, KeepaliveConfig, State, Tcb, UnreadDataPolicy, MSL},
+};
+
+mod common;
+
+use common::tcp_header;
+
+/// Port the harness listens on. Everything else is closed.
+const LISTEN_PORT: u16 = 443;
+
+#[derive(Default)]
+struct RecordingDevice {
+    sent: RefCell<Vec<Vec<u8>>>,
+}
+
+impl NetworkDevice for RecordingDevice {
+    fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+struct Harness {
+    device: RecordingDevice,
+    listeners: Listeners,
+    tcb: Option<Tcb>,
+    challenge_acks: ChallengeAckLimiter,
+    isn: IsnGenerator,
+    /// Difference between our actual ISS and the one the vectors were written with, once known
+    iss_offset: Option<u32>,
+    /// The last TSval we sent
+    last_ts_val: Option<u32>,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        let mut listeners = Listeners::default();
+        listeners.insert(LISTEN_PORT, ListenerLimits::default());
+
+        Harness {
+            device: RecordingDevice::default(),
+            listeners,
+            tcb: None,
+            challenge_acks: ChallengeAckLimiter::default(),
+            isn: IsnGenerator::from_os_random().unwrap(),
+            iss_offset: None,
+            last_ts_val: None,
+        }
+    }
+}
+
+impl Harness {
+    /// Feed `inbound` and assert the packets sent in response are exactly `expected`
+    fn step(&mut self, inbound: &str, expected: &[&str]) {
+        let mut packet: Vec<u8> = decode_hex(inbound);
+        let offset: u32 = self.iss_offset.unwrap_or(0);
+
+        rewrite_tcp(&mut packet, |header| {
+            if header.ack {
+                header.acknowledgment_number = header.acknowledgment_number.wrapping_add(offset);
+            }
+        });
+
+        self.dispatch(&packet);
+        self.expect_sent(expected, &format!("response to {inbound}"));
+    }
+
+    /// Close the connection from our side and assert exactly `expected` is sent
+    fn close(&mut self, expected: &[&str]) {
+        self.tcb.as_mut().unwrap().close(&self.device).unwrap();
+        self.expect_sent(expected, "close");
+    }
+
+    /// Queue `data` to send and assert exactly `expected` is sent
+    fn send(&mut self, data: &[u8], expected: &[&str]) {
+        let n_taken: usize = self.tcb.as_mut().unwrap().send(&self.device, data).unwrap();
+        assert_eq!(n_taken, data.len());
+        self.expect_sent(expected, "send");
+    }
+
+    /// Run the connection's timers as if it were `now` and assert exactly `expected` is sent
+    fn tick(&mut self, now: Instant, expected: &[&str]) {
+        self.tcb
+            .as_mut()
+            .unwrap()
+            .on_tick(&self.device, now)
+            .unwrap();
+        self.expect_sent(expected, "tick");
+    }
+
+    fn expect_sent(&mut self, expected: &[&str], context: &str) {
+        let sent: Vec<Vec<u8>> = self.device.sent.take();
+        let actual: Vec<String> = sent
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut packet)| {
+                if self.iss_offset.is_none() && tcp_header(&packet).syn() {
+                    if let Some(expected) = expected.get(i) {
+                        let actual_seq = tcp_header(&packet).sequence_number();
+                        let expected_seq = tcp_header(&decode_hex(expected)).sequence_number();
+                        self.iss_offset = Some(actual_seq.wrapping_sub(expected_seq));
+                    }
+                }
+
+                let actual_ts_val: Option<u32> = ts_val(&packet);
+                if let Some(ts_val) = actual_ts_val {
+                    if let Some(last_ts_val) = self.last_ts_val {
+                        assert!(
+                            (ts_val.wrapping_sub(last_ts_val) as i32) >= 0,
+                            "{context}: TSval went backwards"
+                        );
+                    }
+                    self.last_ts_val = Some(ts_val);
+                }
+                let expected_ts_val: Option<u32> = expected
+                    .get(i)
+                    .and_then(|expected| ts_val(&decode_hex(expected)));
+
+                let offset: u32 = self.iss_offset.unwrap_or(0);
+                rewrite_tcp(&mut packet, |header| {
+                    header.sequence_number = header.sequence_number.wrapping_sub(offset);
+                    if let (Some(_), Some(ts_val)) = (actual_ts_val, expected_ts_val) {
+                        set_ts_val(header, ts_val);
+                    }
+                });
+
+                encode_hex(&packet)
+            })
+            .collect();
+
+        assert_eq!(actual, expected, "{context}");
+    }
+
+    fn dispatch(&mut self, packet: &[u8]) {
+        let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcp_offset: usize = ip_header.slice().len();
+        let tcp_header = TcpHeaderSlice::from_slice(&packet[tcp_offset..]).unwrap();
+        let data: &[u8] = &packet[tcp_offset + tcp_header.slice().len()..];
+
+        match &mut self.tcb {
+            Some(tcb) => tcb
+                .on_packet(
+                    &self.device,
+                    ip_header,
+                    tcp_header,
+                    data,
+                    &mut self.challenge_acks,
+                )
+                .unwrap(),
+            None if self.listeners.get(tcp_header.destination_port()).is_some() => {
+                self.tcb = Tcb::accept_connection(
+                    &self.device,
+                    ip_header,
+                    tcp_header,
+                    data,
+                    &self.isn,
+                    &clock::system(),
+                )
+                .unwrap();
+            }
+            None => {
+                if self.listeners.closed_port_policy() == ClosedPortPolicy::Reset {
+                    tcp::send_reset(
+                        &self.device,
+                        &ip_header,
+                        &tcp_header,
+                        data,
+                        TcbConfig::DEFAULT.ttl,
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+
+    fn state(&self) -> Option<State> {
+        self.tcb.as_ref().map(Tcb::state)
+    }
+}
+
+/// Modify the TCP header of an IPv4 packet in place and recompute its checksum,
+/// leaving every other byte untouched
+fn rewrite_tcp(packet: &mut [u8], modify: impl FnOnce(&mut TcpHeader)) {
+    let ip_header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let (source, destination) = (ip_header.source(), ip_header.destination());
+    let tcp_offset: usize = ip_header.slice().len();
+
+    let (mut header, payload) = TcpHeader::from_slice(&packet[tcp_offset..]).unwrap();
+    modify(&mut header);
+    header.checksum = header
+        .calc_checksum_ipv4_raw(source, destination, payload)
+        .unwrap();
+
+    let header_bytes = header.to_bytes();
+    packet[tcp_offset..tcp_offset + header_bytes.len()].copy_from_slice(&header_bytes);
+}
+
+fn ts_val(packet: &[u8]) -> Option<u32> {
+    options::timestamps(tcp_header(packet).options()).map(|(ts_val, _)| ts_val)
+}
+
+/// Overwrite the TSval of the timestamps option in `header`
+fn set_ts_val(header: &mut TcpHeader, ts_val: u32) {
+    let mut raw: Vec<u8> = header.options.as_slice().to_vec();
+    let mut i: usize = 0;
+    while i < raw.len() {
+        match raw[i] {
+            options::KIND_END => break,
+            options::KIND_NOOP => i += 1,
+            options::KIND_TIMESTAMPS => {
+                raw[i + 2..i + 6].copy_from_slice(&ts_val.to_be_bytes());
+                break;
+            }
+            _ => i += raw[i + 1] as usize,
+        }
+    }
+    header.set_options_raw(&raw).unwrap();
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// RFC 9293 Section 3.5 Figure 6, basic three-way handshake.
+/// Peer ISN 100, our ISN 300, no options.
+fn established_harness(challenge_acks: ChallengeAckLimiter) -> Harness {
+    let mut harness = Harness {
+        challenge_acks,
+        ..Default::default()
+    };
+
+    // 1. SYN-SENT --> <SEQ=100><CTL=SYN> --> SYN-RECEIVED
+    // 2. <-- <SEQ=300><ACK=101><CTL=SYN,ACK> <-- SYN-RECEIVED
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000640000000050022000702f0000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012c00000065501204008af20000"],
+    );
+    assert_eq!(harness.state(), Some(State::SynRcvd));
+
+    // 3. ESTABLISHED --> <SEQ=101><ACK=301><CTL=ACK> --> ESTABLISHED
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501020006ef30000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+
+    harness
+}
+
+/// RFC 9293 Section 3.6 Figure 12, normal close, with us as the side closing first
+#[test]
+fn rfc_active_close() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    // <-- <SEQ=301><ACK=101><CTL=FIN,ACK>
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501104008af20000",
+    ]);
+    assert_eq!(harness.state(), Some(State::FinWait1));
+
+    // --> <SEQ=101><ACK=302><CTL=ACK> --> FIN-WAIT-2
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012e501020006ef20000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::FinWait2));
+
+    // --> <SEQ=101><ACK=302><CTL=FIN,ACK> --> TIME-WAIT
+    // <-- <SEQ=302><ACK=102><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012e501120006ef10000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012e00000066501004008af10000"],
+    );
+    assert_eq!(harness.state(), Some(State::TimeWait));
+}
+
+/// RFC 9293 Section 3.6, TIME-WAIT lasts 2MSL, restarting when the peer's FIN is retransmitted
+#[test]
+fn time_wait_expires_after_2msl() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501104008af20000",
+    ]);
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012e501120006ef10000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012e00000066501004008af10000"],
+    );
+    assert_eq!(harness.state(), Some(State::TimeWait));
+
+    let entered_time_wait = Instant::now();
+
+    // Our ACK was lost and the FIN is retransmitted, so it's acknowledged again
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012e501120006ef10000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012e00000066501004008af10000"],
+    );
+
+    harness.tick(entered_time_wait + MSL, &[]);
+    assert_eq!(harness.state(), Some(State::TimeWait));
+
+    let deadline: Instant = harness.tcb.as_ref().unwrap().next_deadline().unwrap();
+    assert!(deadline >= entered_time_wait + 2 * MSL);
+
+    harness.tick(deadline + Duration::from_millis(1), &[]);
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// RFC 9293 Section 3.6 Figure 12, normal close, with the peer closing first
+#[test]
+fn rfc_passive_close() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    // --> <SEQ=101><ACK=301><CTL=FIN,ACK> --> CLOSE-WAIT
+    // <-- <SEQ=301><ACK=102><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501120006ef20000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000066501004008af20000"],
+    );
+    assert_eq!(harness.state(), Some(State::CloseWait));
+
+    // <-- <SEQ=301><ACK=102><CTL=FIN,ACK> <-- LAST-ACK
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000066501104008af10000",
+    ]);
+    assert_eq!(harness.state(), Some(State::LastAck));
+
+    // --> <SEQ=102><ACK=302><CTL=ACK> --> CLOSED
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000660000012e501020006ef10000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// RFC 9293 Section 3.6 Figure 13, simultaneous close
+#[test]
+fn rfc_simultaneous_close() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    // <-- <SEQ=301><ACK=101><CTL=FIN,ACK> <-- FIN-WAIT-1
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501104008af20000",
+    ]);
+
+    // --> <SEQ=101><ACK=301><CTL=FIN,ACK> --> CLOSING
+    // <-- <SEQ=302><ACK=102><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501120006ef20000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012e00000066501004008af10000"],
+    );
+    assert_eq!(harness.state(), Some(State::Closing));
+
+    // --> <SEQ=102><ACK=302><CTL=ACK> --> TIME-WAIT
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000660000012e501020006ef10000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::TimeWait));
+}
+
+/// A session with a Linux 6.x client (`nc`), whose SYN carries
+/// MSS, SACK permitted, timestamps and window scale options. The client closes first.
+#[test]
+fn linux_client_session() {
+    let mut harness = Harness::default();
+
+    // The SYN offers timestamps, so every segment after carries them with TSecr echoing
+    // the client's latest TSval
+    harness.step(
+        "4500003c8a1c400040062f4cc0a80001c0a80002c82201bb6f1e2a9000000000a002faf0b1c60000020405b40402080a9a3b1c2d0000000001030307",
+        &["45000034000040004006b970c0a80002c0a8000101bbc8220000012c6f1e2a9180120400d51900000101080a0000012c9a3b1c2d"],
+    );
+
+    harness.step(
+        "450000348a1d400040062f53c0a80001c0a80002c82201bb6f1e2a910000012d8010faf0de2800000101080a9a3b1c2e0000012c",
+        &[],
+    );
+
+    harness.step(
+        "450000348a1e400040062f52c0a80001c0a80002c82201bb6f1e2a910000012d8011faf0de2300000101080a9a3b1c320000012c",
+        &["45000034000040004006b970c0a80002c0a8000101bbc8220000012d6f1e2a9280100400d51400000101080a0000012c9a3b1c32"],
+    );
+
+    harness.close(&[
+        "45000034000040004006b970c0a80002c0a8000101bbc8220000012d6f1e2a9280110400d51300000101080a0000012c9a3b1c32",
+    ]);
+
+    harness.step(
+        "450000348a1f400040062f51c0a80001c0a80002c82201bb6f1e2a920000012e8010faf0de2100000101080a9a3b1c330000012c",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// RFC 7323 Section 3.2 and RFC 1122 Section 4.2.3.6. After a long idle period our
+/// keep-alive probe still echoes the peer's latest TSval, and the ACK of the peer's own
+/// probe echoes the probe's TSval, so neither side's PAWS check discards the other.
+#[test]
+fn keepalive_echoes_timestamps() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "45000034000040004006b970c0a80001c0a800029c4001bb000000640000000080022000333000000101080a000003e800000000",
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012c00000065801204004cc700000101080a0000012c000003e8"],
+    );
+    harness.step(
+        "45000034000040004006b970c0a80001c0a800029c4001bb000000650000012d8010200030be00000101080a000003f20000012c",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+
+    let keepalive = KeepaliveConfig::default();
+    harness.tcb.as_mut().unwrap().set_keepalive(Some(keepalive));
+
+    // <SEQ=SND.NXT-1><ACK=RCV.NXT><CTL=ACK> with TSecr still the last TSval received
+    harness.tick(
+        Instant::now() + keepalive.idle,
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012c00000065801004006f5100000101080a006dde2c000003f2"],
+    );
+
+    // The peer's probe sits before RCV.NXT, but its TSval is still echoed
+    harness.step(
+        "45000034000040004006b970c0a80001c0a800029c4001bb000000640000012d8010200043bb00000101080a00001388006dde2c",
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012d00000065801004005fba00000101080a006dde2c00001388"],
+    );
+
+    // RFC 7323 Section 5.3, PAWS. Data with an older TSval is dropped with an ACK.
+    harness.step(
+        "45000037000040004006b96dc0a80001c0a800029c4001bb000000650000012d80182000742a00000101080a00000fa0006dde2c6f6c64",
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012d00000065801004005fba00000101080a006dde2c00001388"],
+    );
+    assert_eq!(harness.tcb.as_ref().unwrap().unread_len(), 0);
+}
+
+/// RFC 9293 Section 3.10.7.1, a SYN to a closed port is answered with
+/// <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+#[test]
+fn syn_to_closed_port() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "4500003c1f00400040069a68c0a80001c0a80002c8241f900c4d5e6f00000000a002faf0c2e10000020405b40402080a9a3b1c2d0000000001030307",
+        &["45000028000040004006b97cc0a80002c0a800011f90c824000000000c4d5e7050140000dc0a0000"],
+    );
+    assert_eq!(harness.state(), None);
+}
+
+/// RFC 9293 Section 3.10.7.1, an ACK to a closed port is answered with <SEQ=SEG.ACK><CTL=RST>
+#[test]
+fn ack_to_closed_port() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "450000281f01400040069a7bc0a80001c0a80002c8261f9011111111222222225010faf0e5720000",
+        &["45000028000040004006b97cc0a80002c0a800011f90c82622222222000000005004000002920000"],
+    );
+}
+
+/// With the silent policy, segments to closed ports get no reply at all
+#[test]
+fn closed_port_stays_silent() {
+    let mut harness = Harness::default();
+    harness
+        .listeners
+        .set_closed_port_policy(ClosedPortPolicy::Silent);
+
+    // SYN
+    harness.step(
+        "4500003c1f00400040069a68c0a80001c0a80002c8241f900c4d5e6f00000000a002faf0c2e10000020405b40402080a9a3b1c2d0000000001030307",
+        &[],
+    );
+
+    // ACK
+    harness.step(
+        "450000281f01400040069a7bc0a80001c0a80002c8261f9011111111222222225010faf0e5720000",
+        &[],
+    );
+    assert_eq!(harness.state(), None);
+}
+
+/// RFC 9293 Section 3.10.7.2, an ACK in LISTEN is answered with <SEQ=SEG.ACK><CTL=RST>
+/// and no connection is created
+#[test]
+fn ack_to_listening_port() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "450000281f02400040069a7ac0a80001c0a80002c82801bb33333333444444445010faf07abd0000",
+        &["45000028000040004006b97cc0a80002c0a8000101bbc828444444440000000050040000dc200000"],
+    );
+    assert_eq!(harness.state(), None);
+}
+
+/// RFC 9293 Section 3.10.7.4, an in-window RST in SYN-RECEIVED removes the connection
+/// without a reply
+#[test]
+fn reset_in_syn_received() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000640000000050022000702f0000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012c00000065501204008af20000"],
+    );
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000000050040000902c0000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// RFC 5961 Section 3.2, a RST in the window but not at RCV.NXT gets a challenge ACK
+/// <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK> and doesn't reset the connection,
+/// while one exactly at RCV.NXT does
+#[test]
+fn in_window_reset_is_challenged() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    // --> <SEQ=105><CTL=RST>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb00000069000000005004000090280000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501004008af30000"],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+
+    // --> <SEQ=101><CTL=RST>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000000050040000902c0000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// RFC 5961 Section 4.2, a SYN on a synchronised connection gets a challenge ACK
+#[test]
+fn syn_on_synchronised_connection_is_challenged() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    // --> <SEQ=5000><CTL=SYN>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb0000138800000000500220005d0b0000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501004008af30000"],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+}
+
+/// RFC 5961 Section 7, challenge ACKs beyond the limit are not sent
+#[test]
+fn challenge_acks_are_rate_limited() {
+    let mut harness = established_harness(ChallengeAckLimiter::new(1));
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb00000069000000005004000090280000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501004008af30000"],
+    );
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb00000069000000005004000090280000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+}
+
+/// In-order data is buffered and acknowledged, shrinking the advertised window
+/// until it's read. Data past RCV.NXT is dropped with a duplicate ACK.
+#[test]
+fn data_is_acknowledged_and_read() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    harness.step(
+        "4500002b000040004006b979c0a80001c0a800029c4001bb0000006e0000012d501820007c65000078797a",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501004008af30000"],
+    );
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000"],
+    );
+
+    let tcb: &mut Tcb = harness.tcb.as_mut().unwrap();
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(tcb.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(tcb.unread_len(), 0);
+
+    // Reading 5 bytes isn't enough to open the window again, so the FIN keeps it shut
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501103fb8af20000",
+    ]);
+    assert_eq!(harness.state(), Some(State::FinWait1));
+}
+
+/// RFC 2883 Section 4, retransmitted data we already have is reported with a D-SACK
+/// block on the ACK, when the peer's SYN offered SACK permitted
+#[test]
+fn duplicate_data_is_reported_with_dsack() {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "4500002c000040004006b978c0a80001c0a800029c4001bb0000006400000000600220005b28000001010402",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012c00000065501204008af20000"],
+    );
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501020006ef30000",
+        &[],
+    );
+
+    let hello: &str =
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f";
+    harness.step(
+        hello,
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000"],
+    );
+
+    // The whole segment is a duplicate
+    harness.step(
+        hello,
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012d0000006a801003fb540d00000101050a000000650000006a"],
+    );
+
+    // Only the first two bytes are, "lo" of "hello"
+    harness.step(
+        "45000030000040004006b974c0a80001c0a800029c4001bb000000680000012d50182000062300006c6f20776f726c64",
+        &["45000034000040004006b970c0a80002c0a8000101bb9c400000012d00000070801003f5540a00000101050a000000680000006a"],
+    );
+
+    let tcb: &mut Tcb = harness.tcb.as_mut().unwrap();
+    assert_eq!(tcb.stats().duplicate_segments, 2);
+    let mut buf: [u8; 16] = [0; 16];
+    let n_read: usize = tcb.read(&mut buf);
+    assert_eq!(&buf[..n_read], b"hello world");
+}
+
+/// Without SACK permitted the duplicate is still counted, but acknowledged plainly
+#[test]
+fn duplicate_data_without_sack_is_counted() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    let hello: &str =
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f";
+    let ack: &str =
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000";
+
+    harness.step(hello, &[ack]);
+    harness.step(hello, &[ack]);
+
+    assert_eq!(harness.tcb.as_ref().unwrap().stats().duplicate_segments, 1);
+}
+
+/// Data is sent with PSH on the segment emptying the send buffer, and dropped from the
+/// buffer once acknowledged
+#[test]
+fn data_is_sent_and_acknowledged() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    harness.send(
+        b"hello",
+        &["4500002d000040004006b977c0a80002c0a8000101bb9c400000012d00000065501804004714000068656c6c6f"],
+    );
+    assert_eq!(harness.tcb.as_ref().unwrap().unacked_len(), 5);
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb0000006500000132501020006eee0000",
+        &[],
+    );
+    assert_eq!(harness.tcb.as_ref().unwrap().unacked_len(), 0);
+}
+
+/// RFC 6298 Section 5.4, unacknowledged data is sent again when the timer expires
+#[test]
+fn data_is_retransmitted() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    let hello: &str =
+        "4500002d000040004006b977c0a80002c0a8000101bb9c400000012d00000065501804004714000068656c6c6f";
+
+    harness.send(b"hello", &[hello]);
+    harness.tick(Instant::now() + DEFAULT_INITIAL_RTO, &[hello]);
+}
+
+/// Only as much as the peer's window allows is sent, and a FIN queued by `close`
+/// follows the last of the data
+#[test]
+fn send_is_limited_by_peer_window() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    let data: Vec<u8> = (0..=255).chain(0..=43).collect();
+
+    // <SEQ=101><ACK=301><WND=100><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501000648e8f0000",
+        &[],
+    );
+
+    harness.send(
+        &data,
+        &["4500008c000040004006b918c0a80002c0a8000101bb9c400000012d0000006550100400eec10000000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60616263"],
+    );
+
+    harness.close(&[]);
+    assert_eq!(harness.state(), Some(State::FinWait1));
+
+    // <SEQ=101><ACK=401><WND=300><CTL=ACK>
+    // <-- <SEQ=401><ACK=101><CTL=FIN,PSH,ACK><DATA=200b>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb00000065000001915010012c8d630000",
+        &["450000f0000040004006b8b4c0a80002c0a8000101bb9c40000001910000006550190400956600006465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9fa0a1a2a3a4a5a6a7a8a9aaabacadaeafb0b1b2b3b4b5b6b7b8b9babbbcbdbebfc0c1c2c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d7d8d9dadbdcdddedfe0e1e2e3e4e5e6e7e8e9eaebecedeeeff0f1f2f3f4f5f6f7f8f9fafbfcfdfeff000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b"],
+    );
+    assert_eq!(harness.state(), Some(State::FinWait1));
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000025a5010012c8c9a0000",
+        &[],
+    );
+    assert_eq!(harness.state(), Some(State::FinWait2));
+}
+
+/// RFC 9293 Section 3.8.6.1, a closed window is probed with one byte of data
+#[test]
+fn zero_window_is_probed() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    let window_closed: &str =
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501000008ef30000";
+
+    harness.step(window_closed, &[]);
+
+    harness.send(
+        b"hello",
+        &["45000029000040004006b97bc0a80002c0a8000101bb9c400000012d000000655010040022f2000068"],
+    );
+
+    // The probe isn't taken, and nothing more is sent until the window opens
+    harness.step(window_closed, &[]);
+
+    // <SEQ=101><ACK=302><WND=8192><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012e501020006ef20000",
+        &["4500002c000040004006b978c0a80002c0a8000101bb9c400000012e0000006550180400b90a0000656c6c6f"],
+    );
+}
+
+/// RFC 9293 Section 3.8.6.1, a probe the peer doesn't take is sent again with the rest
+/// once the window opens, rather than leaving a hole for the retransmission timer
+#[test]
+fn refused_probe_is_resent_when_the_window_opens() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    let window_closed: &str =
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501000008ef30000";
+
+    harness.step(window_closed, &[]);
+
+    harness.send(
+        b"hello",
+        &["45000029000040004006b97bc0a80002c0a8000101bb9c400000012d000000655010040022f2000068"],
+    );
+
+    // <SEQ=101><ACK=301><WND=8192><CTL=ACK>
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000650000012d501020006ef30000",
+        &["4500002d000040004006b977c0a80002c0a8000101bb9c400000012d00000065501804004714000068656c6c6f"],
+    );
+}
+
+/// RFC 1122 Section 4.2.2.13, closing with unread data resets the connection
+#[test]
+fn close_with_unread_data_resets() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000"],
+    );
+
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000000500403fb8b690000",
+    ]);
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+#[test]
+fn close_with_unread_data_can_send_fin() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+    harness
+        .tcb
+        .as_mut()
+        .unwrap()
+        .set_unread_data_policy(UnreadDataPolicy::Fin);
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d501820002b14000068656c6c6f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000"],
+    );
+
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501103fb8af20000",
+    ]);
+    assert_eq!(harness.state(), Some(State::FinWait1));
+}
+
+/// Data arriving after we've closed can never be read, so it's answered with a reset
+#[test]
+fn data_after_close_resets() {
+    let mut harness = established_harness(ChallengeAckLimiter::default());
+
+    harness.close(&[
+        "45000028000040004006b97cc0a80002c0a8000101bb9c400000012d00000065501104008af20000",
+    ]);
+
+    harness.step(
+        "4500002c000040004006b978c0a80001c0a800029c4001bb000000650000012e501820008e1f00006c617465",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012e00000000500404008b630000"],
+    );
+    assert_eq!(harness.state(), Some(State::Closed));
+}
+
+/// Harness which has received the peer's SYN and answered it, in SYN-RECEIVED
+fn syn_received_harness() -> Harness {
+    let mut harness = Harness::default();
+
+    harness.step(
+        "45000028000040004006b97cc0a80001c0a800029c4001bb000000640000000050022000702f0000",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012c00000065501204008af20000"],
+    );
+    assert_eq!(harness.state(), Some(State::SynRcvd));
+
+    harness
+}
+
+/// The handshake completing ACK carries the first data, as curl sends it
+#[test]
+fn data_with_handshake_ack() {
+    let mut harness = syn_received_harness();
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d50182000a4800000474554202f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006a501003fb8af30000"],
+    );
+    assert_eq!(harness.state(), Some(State::Estab));
+
+    let mut buf: [u8; 16] = [0; 16];
+    let n_read: usize = harness.tcb.as_mut().unwrap().read(&mut buf);
+    assert_eq!(&buf[..n_read], b"GET /");
+}
+
+/// The whole request arrives with the handshake completing ACK, data and FIN together
+#[test]
+fn data_and_fin_with_handshake_ack() {
+    let mut harness = syn_received_harness();
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012d50192000a47f0000474554202f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012d0000006b501003fb8af20000"],
+    );
+    assert_eq!(harness.state(), Some(State::CloseWait));
+    assert_eq!(harness.tcb.as_ref().unwrap().unread_len(), 5);
+}
+
+/// An ACK which doesn't cover our SYN can't complete the handshake, so the data isn't taken
+#[test]
+fn data_without_acknowledging_syn_is_reset() {
+    let mut harness = syn_received_harness();
+
+    harness.step(
+        "4500002d000040004006b977c0a80001c0a800029c4001bb000000650000012c50182000a4810000474554202f",
+        &["45000028000040004006b97cc0a80002c0a8000101bb9c400000012c00000000500400008f650000"],
+    );
+    assert_eq!(harness.state(), Some(State::SynRcvd));
+    assert_eq!(harness.tcb.as_ref().unwrap().unread_len(), 0);
+}
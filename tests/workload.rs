@@ -0,0 +1,98 @@
+//! Mixes of flows described in a file and replayed between two stacks
+
+use std::time::Duration;
+
+use tcp_rs::{
+    impair::Impairment,
+    workload::{self, Direction, Flow, Workload, WorkloadReport},
+};
+
+const MIX: &str = "
+link delay=10 jitter=5 loss=0.02 seed=3
+# A bulk download alongside a stream of small requests
+0    down 256k
+0    up   300
+20   up   200  10   # a burst
+";
+
+#[test]
+fn description_is_parsed() {
+    let workload = Workload::parse(MIX).unwrap();
+
+    assert_eq!(
+        workload.impairment,
+        Impairment {
+            loss: 0.02,
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            ..Impairment::default()
+        }
+    );
+    assert_eq!(workload.seed, 3);
+    assert_eq!(workload.flows.len(), 12);
+    assert_eq!(
+        workload.flows[0],
+        Flow {
+            start: Duration::ZERO,
+            direction: Direction::Down,
+            bytes: 256 * 1024,
+        }
+    );
+    assert_eq!(
+        workload.flows[11],
+        Flow {
+            start: Duration::from_millis(20),
+            direction: Direction::Up,
+            bytes: 200,
+        }
+    );
+}
+
+#[test]
+fn bad_lines_are_reported_by_number() {
+    let err = Workload::parse("0 up 10\n0 sideways 10").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "line 2: 0 sideways 10: direction must be up or down, got sideways"
+    );
+
+    assert!(Workload::parse("link loss=2").is_err());
+    assert!(Workload::parse("link bandwidth=10").is_err());
+    assert!(Workload::parse("0 up").is_err());
+}
+
+#[test]
+fn every_flow_completes_over_an_impaired_link() {
+    let workload = Workload::parse(MIX).unwrap();
+    let report: WorkloadReport = workload::run(&workload).unwrap();
+
+    assert_eq!(report.flows.len(), 12);
+    for result in &report.flows {
+        // At least the handshake and the data's way across
+        assert!(result.completion_time >= Duration::from_millis(15));
+    }
+    assert!(report.upstream.lost + report.downstream.lost > 0);
+
+    // The bulk flow takes longest, and the same description always plays out the same way
+    assert_eq!(
+        report.completion_percentile(100),
+        report.flows[0].completion_time
+    );
+    assert_eq!(workload::run(&workload).unwrap(), report);
+}
+
+#[test]
+fn flows_start_on_time() {
+    let workload = Workload::parse("link delay=5\n0 up 100\n50 down 100").unwrap();
+    let report: WorkloadReport = workload::run(&workload).unwrap();
+
+    // An upload goes with the last segment of the handshake, so arrives after one and a
+    // half round trips. The server can't send a download until that segment arrives.
+    assert_eq!(report.flows[0].completion_time, Duration::from_millis(15));
+    assert_eq!(report.flows[1].completion_time, Duration::from_millis(20));
+    assert_eq!(report.duration, Duration::from_millis(50 + 20));
+    assert_eq!(
+        report.to_string().lines().last().unwrap(),
+        "flows=2 bytes=200 duration=70.0ms completion_p50=15.0ms completion_p99=20.0ms completion_max=20.0ms lost=0 duplicated=0 reordered=0"
+    );
+}